@@ -14,18 +14,29 @@
 //! Implements the [HashWriter] interface for the Blake2 hash function.
 //! Requires the `blake2` feature to be enabled.
 
+use std::fmt;
+use std::marker::PhantomData;
+
 use crate::hash::*;
-use blake2::digest::Digest;
+use blake2::digest::{Digest, FixedOutput, KeyInit, VariableOutput};
 pub use blake2::{Blake2b512, Blake2s256};
+use blake2::{Blake2bMac512, Blake2bVar};
 
 macro_rules! hash_writer_impl {
-    ($tname:ident) => {
+    ($tname:ident, $id:expr, $len:expr) => {
         impl HashWriter for $tname {
+            const OUTPUT_LEN: usize = $len;
+            const ALGORITHM_ID: &'static str = $id;
+
             fn record<I: Iterator<Item = u8>>(&mut self, bs: I) {
                 let vec: Vec<u8> = bs.collect();
                 self.update(&vec);
             }
 
+            fn record_bytes(&mut self, bs: &[u8]) {
+                self.update(bs);
+            }
+
             fn hash(&self) -> Vec<u8> {
                 let mut out = Vec::new();
                 // This is gross but Blake2 doesn't support the
@@ -38,5 +49,110 @@ macro_rules! hash_writer_impl {
     };
 }
 
-hash_writer_impl!(Blake2b512);
-hash_writer_impl!(Blake2s256);
+hash_writer_impl!(Blake2b512, "blake2b", 64);
+hash_writer_impl!(Blake2s256, "blake2s", 32);
+
+/// A Blake2b [HashWriter] with `N` byte ids for `N` from 1 to 64. Shorter ids make for smaller
+/// indexes than [Blake2b512] while keeping Blake2b's speed on 64 bit machines. Blake2b records
+/// the output length in its parameters so each `N` assigns unrelated ids and `Blake2bN<64>`
+/// assigns the same ids as [Blake2b512].
+#[derive(Clone)]
+pub struct Blake2bN<const N: usize> {
+    hasher: Blake2bVar,
+}
+
+impl<const N: usize> Blake2bN<N> {
+    const VALID_LEN: () = assert!(N >= 1 && N <= 64, "Blake2b ids are 1 to 64 bytes long");
+}
+
+impl<const N: usize> Default for Blake2bN<N> {
+    fn default() -> Self {
+        let () = Self::VALID_LEN;
+        Self {
+            hasher: Blake2bVar::new(N).expect("The output length was checked"),
+        }
+    }
+}
+
+impl<const N: usize> fmt::Debug for Blake2bN<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Blake2bN")
+            .field("output_len", &N)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<const N: usize> HashWriter for Blake2bN<N> {
+    const OUTPUT_LEN: usize = N;
+    // Blake2bN<64> assigns the same ids as Blake2b512.
+    const ALGORITHM_ID: &'static str = "blake2b";
+
+    fn record<I: Iterator<Item = u8>>(&mut self, bs: I) {
+        let vec: Vec<u8> = bs.collect();
+        blake2::digest::Update::update(&mut self.hasher, &vec);
+    }
+
+    fn record_bytes(&mut self, bs: &[u8]) {
+        blake2::digest::Update::update(&mut self.hasher, bs);
+    }
+
+    fn hash(&self) -> Vec<u8> {
+        let mut out = vec![0; N];
+        self.hasher
+            .clone()
+            .finalize_variable(&mut out)
+            .expect("The buffer is N bytes long");
+        out
+    }
+}
+
+/// A keyed Blake2b [HashWriter] using the key of `K`. DAGs hashed with different keys assign
+/// different ids to the same content, so ids can't be computed without the key.
+pub struct KeyedBlake2b<K> {
+    mac: Blake2bMac512,
+    _key: PhantomData<K>,
+}
+
+impl<K: HashKey> Default for KeyedBlake2b<K> {
+    fn default() -> Self {
+        Self {
+            mac: <Blake2bMac512 as KeyInit>::new_from_slice(&K::key())
+                .expect("A 32 byte key fits Blake2b"),
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K> Clone for KeyedBlake2b<K> {
+    fn clone(&self) -> Self {
+        Self {
+            mac: self.mac.clone(),
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K> fmt::Debug for KeyedBlake2b<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The MAC state would give the key away.
+        f.debug_struct("KeyedBlake2b").finish_non_exhaustive()
+    }
+}
+
+impl<K: HashKey> HashWriter for KeyedBlake2b<K> {
+    const OUTPUT_LEN: usize = 64;
+    const ALGORITHM_ID: &'static str = "blake2b-keyed";
+
+    fn record<I: Iterator<Item = u8>>(&mut self, bs: I) {
+        let vec: Vec<u8> = bs.collect();
+        blake2::digest::Update::update(&mut self.mac, &vec);
+    }
+
+    fn record_bytes(&mut self, bs: &[u8]) {
+        blake2::digest::Update::update(&mut self.mac, bs);
+    }
+
+    fn hash(&self) -> Vec<u8> {
+        self.mac.clone().finalize_fixed().to_vec()
+    }
+}
@@ -0,0 +1,165 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Implements the [HashWriter] interface for the BLAKE3 hash function in its plain, keyed and
+//! key derivation modes. Requires the `blake3` feature to be enabled.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::hash::*;
+
+/// A BLAKE3 [HashWriter]. The [Default] hasher is the plain hash function.
+///
+/// Nodes build their hashers with [Default] so the hashers of [Blake3::keyed] and
+/// [Blake3::derive_key] are for hashing outside a DAG. Use [KeyedBlake3] and [DerivedBlake3]
+/// for keyed node ids.
+#[derive(Clone, Debug, Default)]
+pub struct Blake3(blake3::Hasher);
+
+impl Blake3 {
+    /// A hasher in the keyed mode of BLAKE3.
+    pub fn keyed(key: &[u8; 32]) -> Self {
+        Self(blake3::Hasher::new_keyed(key))
+    }
+
+    /// A hasher in the key derivation mode of BLAKE3. The `context` should be hardcoded,
+    /// globally unique and specific to the application.
+    pub fn derive_key(context: &str) -> Self {
+        Self(blake3::Hasher::new_derive_key(context))
+    }
+}
+
+impl HashWriter for Blake3 {
+    const OUTPUT_LEN: usize = blake3::OUT_LEN;
+    const ALGORITHM_ID: &'static str = "blake3";
+
+    fn record<I: Iterator<Item = u8>>(&mut self, bs: I) {
+        let vec: Vec<u8> = bs.collect();
+        self.0.update(&vec);
+    }
+
+    fn record_bytes(&mut self, bs: &[u8]) {
+        self.0.update(bs);
+    }
+
+    fn hash(&self) -> Vec<u8> {
+        self.0.finalize().as_bytes().to_vec()
+    }
+}
+
+/// A BLAKE3 [HashWriter] in the keyed mode using the key of `K`. DAGs hashed with different
+/// keys assign different ids to the same content.
+pub struct KeyedBlake3<K> {
+    hasher: Blake3,
+    _key: PhantomData<K>,
+}
+
+impl<K: HashKey> Default for KeyedBlake3<K> {
+    fn default() -> Self {
+        Self {
+            hasher: Blake3::keyed(&K::key()),
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K> Clone for KeyedBlake3<K> {
+    fn clone(&self) -> Self {
+        Self {
+            hasher: self.hasher.clone(),
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K> fmt::Debug for KeyedBlake3<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The hasher state would give the key away.
+        f.debug_struct("KeyedBlake3").finish_non_exhaustive()
+    }
+}
+
+impl<K: HashKey> HashWriter for KeyedBlake3<K> {
+    const OUTPUT_LEN: usize = Blake3::OUTPUT_LEN;
+    const ALGORITHM_ID: &'static str = "blake3-keyed";
+
+    fn record<I: Iterator<Item = u8>>(&mut self, bs: I) {
+        self.hasher.record(bs)
+    }
+
+    fn record_bytes(&mut self, bs: &[u8]) {
+        self.hasher.record_bytes(bs)
+    }
+
+    fn hash(&self) -> Vec<u8> {
+        self.hasher.hash()
+    }
+}
+
+/// Supplies the context of a [DerivedBlake3].
+pub trait KeyContext {
+    /// The context string. It should be hardcoded, globally unique and specific to the
+    /// application.
+    const CONTEXT: &'static str;
+}
+
+/// A BLAKE3 [HashWriter] in the key derivation mode using the context of `C`. DAGs hashed
+/// with different contexts assign different ids to the same content.
+pub struct DerivedBlake3<C> {
+    hasher: Blake3,
+    _context: PhantomData<C>,
+}
+
+impl<C: KeyContext> Default for DerivedBlake3<C> {
+    fn default() -> Self {
+        Self {
+            hasher: Blake3::derive_key(C::CONTEXT),
+            _context: PhantomData,
+        }
+    }
+}
+
+impl<C> Clone for DerivedBlake3<C> {
+    fn clone(&self) -> Self {
+        Self {
+            hasher: self.hasher.clone(),
+            _context: PhantomData,
+        }
+    }
+}
+
+impl<C> fmt::Debug for DerivedBlake3<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DerivedBlake3")
+            .field("hasher", &self.hasher)
+            .finish()
+    }
+}
+
+impl<C: KeyContext> HashWriter for DerivedBlake3<C> {
+    const OUTPUT_LEN: usize = Blake3::OUTPUT_LEN;
+    const ALGORITHM_ID: &'static str = "blake3-derived";
+
+    fn record<I: Iterator<Item = u8>>(&mut self, bs: I) {
+        self.hasher.record(bs)
+    }
+
+    fn record_bytes(&mut self, bs: &[u8]) {
+        self.hasher.record_bytes(bs)
+    }
+
+    fn hash(&self) -> Vec<u8> {
+        self.hasher.hash()
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! The [Clock] every time dependent feature of this crate reads.
+//!
+//! Nothing outside this module reads the system clock or sleeps directly. Tests and
+//! simulations use a [SimClock] to control time deterministically.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A source of time.
+pub trait Clock: Send + Sync {
+    /// The current time as the duration since the unix epoch.
+    fn now(&self) -> Duration;
+
+    /// Wait until [Clock::now] reaches the `deadline`.
+    fn sleep_until(&self, deadline: Duration);
+}
+
+/// The [Clock] backed by the system clock.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        let now = self.now();
+        if deadline > now {
+            std::thread::sleep(deadline - now);
+        }
+    }
+}
+
+#[derive(Default)]
+struct SimState {
+    now: Duration,
+    next_timer: u64,
+    // Timers ordered by deadline and then by registration order.
+    timers: BTreeSet<(Duration, u64)>,
+}
+
+/// A [Clock] that only moves when it is advanced. Clones share the same time.
+///
+/// [Clock::sleep_until] advances the clock to the deadline instead of blocking so a single
+/// threaded simulation never waits on real time.
+#[derive(Clone, Default)]
+pub struct SimClock {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimClock {
+    /// Construct a clock starting at `now`.
+    pub fn starting_at(now: Duration) -> Self {
+        let clock = Self::default();
+        clock.state.lock().unwrap().now = now;
+        clock
+    }
+
+    /// Register a timer firing at the `deadline` and return its id. Ids increase in
+    /// registration order.
+    pub fn timer(&self, deadline: Duration) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_timer;
+        state.next_timer += 1;
+        state.timers.insert((deadline, id));
+        id
+    }
+
+    /// Advance the clock by `by` returning the ids of the timers that fired.
+    pub fn advance(&self, by: Duration) -> Vec<u64> {
+        let deadline = self.now() + by;
+        self.advance_to(deadline)
+    }
+
+    /// Advance the clock to `deadline` returning the ids of the timers that fired ordered by
+    /// their deadline and then their registration order. The clock never moves backwards.
+    pub fn advance_to(&self, deadline: Duration) -> Vec<u64> {
+        let mut state = self.state.lock().unwrap();
+        let now = state.now.max(deadline);
+        state.now = now;
+        let pending = state.timers.split_off(&(now, u64::MAX));
+        let fired = std::mem::replace(&mut state.timers, pending);
+        fired.into_iter().map(|(_, id)| id).collect()
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Duration {
+        self.state.lock().unwrap().now
+    }
+
+    fn sleep_until(&self, deadline: Duration) {
+        self.advance_to(deadline);
+    }
+}
+
+impl fmt::Debug for SimClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimClock")
+            .field("now", &self.now())
+            .finish()
+    }
+}
+
+// A shared handle to a Clock so the DAG can stay Clone and Debug.
+#[derive(Clone)]
+pub(crate) struct ClockHandle(pub(crate) Arc<dyn Clock>);
+
+impl ClockHandle {
+    pub(crate) fn now(&self) -> Duration {
+        self.0.now()
+    }
+}
+
+impl Default for ClockHandle {
+    fn default() -> Self {
+        Self(Arc::new(SystemClock))
+    }
+}
+
+impl fmt::Debug for ClockHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
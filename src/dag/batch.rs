@@ -0,0 +1,195 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::id::hex;
+use crate::store::{StoreError, StoreErrorKind};
+
+/// The number of errors a batch operation records by default before only counting them.
+pub const DEFAULT_MAX_BATCH_ERRORS: usize = 100;
+
+/// A single problem found by a batch operation.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct BatchEntryError {
+    /// The position of the offending entry in the input of the operation.
+    pub index: usize,
+    /// The id of the offending [Node](crate::node::Node) if it is known.
+    pub node_id: Option<Vec<u8>>,
+    pub kind: StoreErrorKind,
+    pub detail: String,
+}
+
+impl BatchEntryError {
+    /// Record the `err` for the entry at `index`.
+    pub fn from_store_error(index: usize, node_id: Option<Vec<u8>>, err: &StoreError) -> Self {
+        Self {
+            index,
+            node_id,
+            kind: err.kind(),
+            detail: format!("{:?}", err),
+        }
+    }
+}
+
+/// The errors found by a batch operation. Only the first `cap` errors are recorded. The
+/// rest are counted in [BatchErrors::overflow].
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct BatchErrors {
+    cap: usize,
+    entries: Vec<BatchEntryError>,
+    overflow: u64,
+}
+
+impl Default for BatchErrors {
+    fn default() -> Self {
+        Self::with_cap(DEFAULT_MAX_BATCH_ERRORS)
+    }
+}
+
+impl BatchErrors {
+    /// Construct an empty collection recording at most `cap` errors.
+    pub fn with_cap(cap: usize) -> Self {
+        Self {
+            cap,
+            entries: Vec::new(),
+            overflow: 0,
+        }
+    }
+
+    /// Record the `err` or count it if the cap was reached.
+    pub fn push(&mut self, err: BatchEntryError) {
+        if self.entries.len() < self.cap {
+            self.entries.push(err);
+        } else {
+            self.overflow += 1;
+        }
+    }
+
+    /// The number of recorded errors.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no error was found at all.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.overflow == 0
+    }
+
+    /// The number of errors that were found after the cap was reached.
+    pub fn overflow(&self) -> u64 {
+        self.overflow
+    }
+
+    /// The total number of errors found.
+    pub fn total(&self) -> u64 {
+        self.entries.len() as u64 + self.overflow
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, BatchEntryError> {
+        self.entries.iter()
+    }
+}
+
+impl IntoIterator for BatchErrors {
+    type Item = BatchEntryError;
+    type IntoIter = std::vec::IntoIter<BatchEntryError>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a BatchErrors {
+    type Item = &'a BatchEntryError;
+    type IntoIter = std::slice::Iter<'a, BatchEntryError>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+// Rendered as a table with one row per recorded error.
+impl fmt::Display for BatchErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows: Vec<[String; 4]> = self
+            .entries
+            .iter()
+            .map(|e| {
+                [
+                    e.index.to_string(),
+                    e.node_id.as_deref().map(hex).unwrap_or_else(|| "-".into()),
+                    format!("{:?}", e.kind),
+                    e.detail.clone(),
+                ]
+            })
+            .collect();
+        let header = ["index", "node_id", "kind", "detail"].map(String::from);
+        let mut widths = header.clone().map(|h| h.len());
+        for row in rows.iter() {
+            for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        writeln!(f, "{} errors", self.total())?;
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            writeln!(
+                f,
+                "{:>w0$}  {:<w1$}  {:<w2$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+            )?;
+        }
+        if self.overflow > 0 {
+            writeln!(f, "... {} more errors not recorded", self.overflow)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BatchErrors {}
+
+/// What a failed batch operation left behind. Each operation documents which applies.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum BatchProgress {
+    /// The operation is transactional and nothing was applied.
+    NothingApplied,
+    /// The operation can be resumed and this many entries were applied.
+    Applied(usize),
+}
+
+/// The error returned by batch operations carrying every problem found and what was applied.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct BatchFailure {
+    pub errors: BatchErrors,
+    pub progress: BatchProgress,
+}
+
+impl fmt::Display for BatchFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.progress {
+            BatchProgress::NothingApplied => writeln!(f, "batch failed: nothing applied")?,
+            BatchProgress::Applied(count) => writeln!(f, "batch failed: {} applied", count)?,
+        }
+        write!(f, "{}", self.errors)
+    }
+}
+
+impl std::error::Error for BatchFailure {}
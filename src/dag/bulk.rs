@@ -0,0 +1,311 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use super::DEFAULT_MAX_BATCH_ERRORS;
+use super::{BatchEntryError, BatchErrors, BatchFailure, BatchProgress, Merkle};
+use crate::hash::HashWriter;
+use crate::id::hex;
+use crate::node::{Node, NodeIntegrityError};
+use crate::store::{Result, Store, StoreError, StoreErrorKind};
+
+/// Options for [Merkle::bulk_load].
+#[derive(Clone, Debug)]
+pub struct BulkLoadOpts {
+    /// The number of [nodes](Node) written per [Store] batch when the [Store] supports
+    /// batches and the load is not rolled back as a whole.
+    pub batch_size: usize,
+    /// Write the whole load as a single [Store] batch and roll it back if verification fails.
+    /// Stores without batch support keep the loaded nodes and only report the violations.
+    pub rollback_on_failure: bool,
+    /// The number of errors recorded before the rest are only counted.
+    pub max_errors: usize,
+}
+
+impl Default for BulkLoadOpts {
+    fn default() -> Self {
+        Self {
+            batch_size: 1000,
+            rollback_on_failure: false,
+            max_errors: DEFAULT_MAX_BATCH_ERRORS,
+        }
+    }
+}
+
+/// The outcome of a [Merkle::bulk_load].
+#[derive(Clone, Default, Debug)]
+pub struct BulkLoadReport {
+    /// The number of [nodes](Node) written to the [Store].
+    pub loaded: usize,
+    /// The number of [nodes](Node) that were already in the [Store].
+    pub skipped: usize,
+    /// How long the load took.
+    pub duration: Duration,
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Load [nodes](Node) from a trusted source such as an archive without checking the
+    /// dependencies of each node as it is written. The source does not need to be in
+    /// topological order. A single verification pass at the end checks that every dependency
+    /// was either already in the [Store] or part of the load and then recomputes the roots.
+    ///
+    /// Errors yielded by the source and dependencies the verification pass could not find
+    /// are all collected and the load fails with every one of them. The errors of the source
+    /// are recorded first followed by the missing dependencies in source order. A [Store]
+    /// error stops the load immediately. The roots are only updated if the load succeeds.
+    ///
+    /// With [BulkLoadOpts::rollback_on_failure] set and a [Store] supporting batches a failed
+    /// load is rolled back and reports [BatchProgress::NothingApplied]. Otherwise the written
+    /// nodes are kept and the failure reports how many were applied so the load can be
+    /// resumed.
+    pub fn bulk_load<I>(
+        &mut self,
+        source: I,
+        opts: BulkLoadOpts,
+    ) -> std::result::Result<BulkLoadReport, BatchFailure>
+    where
+        I: Iterator<Item = Result<Node<HW>>>,
+    {
+        let start = self.clock.now();
+        let mut errors = BatchErrors::with_cap(opts.max_errors);
+        let mut report = BulkLoadReport::default();
+        let batch_size = opts.batch_size.max(1);
+        let mut whole_load_batch = false;
+        let mut in_batch = false;
+        // The number of loaded nodes that are committed to the store.
+        let mut applied = 0;
+        let mut index = 0;
+        let mut seen: BTreeSet<Vec<u8>> = BTreeSet::new();
+        let mut loaded_ids: BTreeSet<Vec<u8>> = BTreeSet::new();
+        // Dependencies that had not been seen yet when they were referenced along with the
+        // position and id of the first node referencing them.
+        let mut unresolved: BTreeMap<Vec<u8>, (usize, Vec<u8>)> = BTreeMap::new();
+        let mut referenced: BTreeSet<Vec<u8>> = BTreeSet::new();
+        #[cfg(feature = "cbor")]
+        let mut loaded_bytes = 0;
+        #[cfg(feature = "cbor")]
+        let mut applied_bytes = 0;
+        let mut visited = 0;
+        let result = (|| -> Result<()> {
+            whole_load_batch = opts.rollback_on_failure && self.try_begin_batch()?;
+            in_batch = whole_load_batch;
+            for node in source {
+                let node = match node {
+                    Ok(node) => node,
+                    Err(e) => {
+                        errors.push(BatchEntryError::from_store_error(index, None, &e));
+                        index += 1;
+                        continue;
+                    }
+                };
+                self.charge_visit(&mut visited)?;
+                let id = node.id().to_vec();
+                // Rejected nodes are left out so nodes depending on them are reported as
+                // missing a dependency too. Nodes already stored aren't checked again.
+                let checked = self
+                    .check_id_version(&node)
+                    .and_then(|_| self.check_signature_policy(&node));
+                if let Err(e) = checked {
+                    if !seen.contains(&id) && !self.nodes.contains(&id)? {
+                        errors.push(BatchEntryError::from_store_error(index, Some(id), &e));
+                        index += 1;
+                        continue;
+                    }
+                }
+                if seen.insert(id.clone()) {
+                    unresolved.remove(&id);
+                    for dep in node.dep_set() {
+                        if !seen.contains(dep) {
+                            unresolved
+                                .entry(dep.clone())
+                                .or_insert_with(|| (index, id.clone()));
+                        }
+                        referenced.insert(dep.clone());
+                    }
+                    if self.nodes.contains(&id)? {
+                        report.skipped += 1;
+                    } else {
+                        if !whole_load_batch && !in_batch {
+                            in_batch = self.try_begin_batch()?;
+                        }
+                        #[cfg(feature = "cbor")]
+                        {
+                            loaded_bytes += crate::store::codec::encoded_size(&node);
+                        }
+                        self.nodes.store(node)?;
+                        loaded_ids.insert(id);
+                        report.loaded += 1;
+                        if !in_batch {
+                            applied = report.loaded;
+                            #[cfg(feature = "cbor")]
+                            {
+                                applied_bytes = loaded_bytes;
+                            }
+                        } else if !whole_load_batch && report.loaded % batch_size == 0 {
+                            self.nodes.commit_batch()?;
+                            applied = report.loaded;
+                            #[cfg(feature = "cbor")]
+                            {
+                                applied_bytes = loaded_bytes;
+                            }
+                            in_batch = false;
+                        }
+                    }
+                }
+                index += 1;
+            }
+            if in_batch && !whole_load_batch {
+                self.nodes.commit_batch()?;
+                applied = report.loaded;
+                #[cfg(feature = "cbor")]
+                {
+                    applied_bytes = loaded_bytes;
+                }
+                in_batch = false;
+            }
+            let mut dangling: Vec<(usize, Vec<u8>, Vec<u8>)> = Vec::new();
+            for (dep, (index, id)) in std::mem::take(&mut unresolved) {
+                if !self.nodes.contains(&dep)? {
+                    dangling.push((index, id, dep));
+                }
+            }
+            dangling.sort();
+            for (index, id, dep) in dangling {
+                errors.push(BatchEntryError {
+                    index,
+                    node_id: Some(id),
+                    kind: StoreErrorKind::NoSuchNode,
+                    detail: format!("missing dependency {}", hex(&dep)),
+                });
+            }
+            if whole_load_batch && errors.is_empty() {
+                self.nodes.commit_batch()?;
+                applied = report.loaded;
+                #[cfg(feature = "cbor")]
+                {
+                    applied_bytes = loaded_bytes;
+                }
+                in_batch = false;
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            errors.push(BatchEntryError::from_store_error(index, None, &e));
+        }
+        if !errors.is_empty() {
+            if in_batch {
+                if let Err(e) = self.nodes.rollback_batch() {
+                    errors.push(BatchEntryError::from_store_error(index, None, &e));
+                }
+            }
+            #[cfg(feature = "cbor")]
+            {
+                self.stored_bytes += applied_bytes;
+            }
+            let progress = if whole_load_batch {
+                BatchProgress::NothingApplied
+            } else {
+                BatchProgress::Applied(applied)
+            };
+            return Err(BatchFailure { errors, progress });
+        }
+        #[cfg(feature = "cbor")]
+        {
+            self.stored_bytes += applied_bytes;
+        }
+        self.roots.retain(|root| !referenced.contains(root));
+        self.roots
+            .extend(loaded_ids.difference(&referenced).cloned());
+        if let Err(e) = self.write_roots() {
+            errors.push(BatchEntryError::from_store_error(index, None, &e));
+            return Err(BatchFailure {
+                errors,
+                progress: BatchProgress::Applied(applied),
+            });
+        }
+        #[cfg(feature = "debug-invariants")]
+        if let Err(e) = self.debug_check_sampled("bulk_load") {
+            errors.push(BatchEntryError::from_store_error(index, None, &e));
+            return Err(BatchFailure {
+                errors,
+                progress: BatchProgress::Applied(applied),
+            });
+        }
+        report.duration = self.clock.now().saturating_sub(start);
+        Ok(report)
+    }
+
+    /// Add many [nodes](Node) with a single [Store::store_many] call returning their ids in
+    /// input order. Every dependency must either be in the DAG already or be one of the
+    /// `nodes`. They are all checked before anything is written so a missing dependency fails
+    /// with [StoreError::NoSuchDependents] and leaves the DAG unchanged. Nodes that are
+    /// already in the DAG are skipped. The ids of a [detached](Node::is_detached) node can't
+    /// be checked without its item so one fails the call with [StoreError::InvalidNode]. Use
+    /// [Merkle::add_detached](crate::dag::Merkle::add_detached) with the item instead. Nodes
+    /// older than the [minimum id version](Merkle::min_id_version) fail it with
+    /// [StoreError::IdVersionTooOld].
+    pub fn add_nodes<I>(&mut self, nodes: I) -> Result<Vec<Vec<u8>>>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let mut ids = Vec::new();
+        let mut new_ids = BTreeSet::new();
+        let mut new_nodes = Vec::new();
+        let mut visited = 0;
+        for node in nodes {
+            self.charge_visit(&mut visited)?;
+            self.check_id_version(&node)?;
+            if node.is_detached() {
+                return Err(StoreError::InvalidNode(NodeIntegrityError::DetachedItem {
+                    id: node.id().to_vec(),
+                }));
+            }
+            let id = node.id().to_vec();
+            ids.push(id.clone());
+            if !new_ids.contains(&id) && !self.check_for_node(&id)? {
+                new_ids.insert(id);
+                new_nodes.push(node);
+            }
+        }
+        let mut referenced = BTreeSet::new();
+        for node in new_nodes.iter() {
+            for dep in node.dep_set() {
+                if referenced.insert(dep.clone())
+                    && !new_ids.contains(dep)
+                    && !self.check_for_node(dep)?
+                {
+                    return Err(StoreError::NoSuchDependents);
+                }
+            }
+        }
+        self.commit_staged(new_nodes, &referenced)?;
+        #[cfg(feature = "debug-invariants")]
+        self.debug_check_sampled("add_nodes")?;
+        Ok(ids)
+    }
+
+    // Start a store batch returning false if the store doesn't support them.
+    fn try_begin_batch(&mut self) -> Result<bool> {
+        match self.nodes.begin_batch() {
+            Ok(()) => Ok(true),
+            Err(StoreError::Unsupported(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
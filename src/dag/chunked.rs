@@ -0,0 +1,254 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeSet;
+use std::io::{self, Read};
+
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::id::hex;
+use crate::store::{Result, Store, StoreError};
+
+/// The most children a manifest written by [Merkle::add_chunked] lists. Payloads with more
+/// chunks get a tree of manifests.
+pub const MANIFEST_FANOUT: usize = 256;
+
+// The prefix of every manifest item.
+const MANIFEST_MAGIC: &[u8] = b"merkle-dag/manifest";
+
+// A manifest lists its children in order. The children of a level 0 manifest are chunks and
+// the children of a level n manifest are level n - 1 manifests. `len` is the number of payload
+// bytes under the manifest.
+struct Manifest {
+    level: u8,
+    len: u64,
+    children: Vec<Vec<u8>>,
+}
+
+impl Manifest {
+    fn encode(level: u8, len: u64, children: &[Vec<u8>]) -> Vec<u8> {
+        let mut item = MANIFEST_MAGIC.to_vec();
+        item.push(level);
+        item.extend_from_slice(&len.to_le_bytes());
+        for child in children {
+            item.extend_from_slice(child);
+        }
+        item
+    }
+
+    fn decode<HW: HashWriter>(id: &[u8], item: &[u8], dependency_ids: &[&[u8]]) -> Result<Self> {
+        let invalid = |reason: &str| {
+            StoreError::StoreFailure(format!("Invalid chunk manifest {}: {}", hex(id), reason))
+        };
+        let rest = item
+            .strip_prefix(MANIFEST_MAGIC)
+            .ok_or_else(|| invalid("not a manifest"))?;
+        if rest.len() < 9 || (rest.len() - 9) % HW::OUTPUT_LEN != 0 {
+            return Err(invalid("truncated"));
+        }
+        let level = rest[0];
+        let len = u64::from_le_bytes(rest[1..9].try_into().expect("8 bytes were checked"));
+        let children: Vec<Vec<u8>> = rest[9..]
+            .chunks(HW::OUTPUT_LEN)
+            .map(<[u8]>::to_vec)
+            .collect();
+        // The dependencies are part of the id so children that are dependencies can't have
+        // been swapped.
+        if children
+            .iter()
+            .any(|child| !dependency_ids.contains(&child.as_slice()))
+        {
+            return Err(invalid("lists a child that isn't a dependency"));
+        }
+        Ok(Self {
+            level,
+            len,
+            children,
+        })
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Add the bytes of `reader` split into chunk nodes of `chunk_size` bytes with a manifest
+    /// node on top listing them in order and return the id of the manifest. The manifest
+    /// depends on its chunks and on `dependency_ids`. Payloads of more than
+    /// [MANIFEST_FANOUT] chunks get a balanced tree of manifests.
+    ///
+    /// Chunks are cut at every `chunk_size` bytes so the same content always gives the same
+    /// chunk and manifest nodes. The chunks are added as they are read. If adding fails the
+    /// chunks added before stay in the DAG as roots. Read the bytes back with
+    /// [Merkle::read_chunked].
+    pub fn add_chunked<R: Read>(
+        &mut self,
+        mut reader: R,
+        chunk_size: usize,
+        mut dependency_ids: BTreeSet<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        if chunk_size == 0 {
+            return Err(StoreError::StoreFailure(
+                "The chunk size must be at least 1 byte".to_owned(),
+            ));
+        }
+        // The id and payload length of every node of the level being built.
+        let mut level: Vec<(Vec<u8>, u64)> = Vec::new();
+        let mut chunk = Vec::with_capacity(chunk_size);
+        loop {
+            chunk.clear();
+            (&mut reader)
+                .take(chunk_size as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            let id = self.add_node(chunk.as_slice(), BTreeSet::new())?;
+            level.push((id, chunk.len() as u64));
+            if chunk.len() < chunk_size {
+                break;
+            }
+        }
+        let mut depth = 0u8;
+        while level.len() > MANIFEST_FANOUT {
+            let mut parents = Vec::with_capacity(level.len().div_ceil(MANIFEST_FANOUT));
+            for group in level.chunks(MANIFEST_FANOUT) {
+                let children: Vec<Vec<u8>> = group.iter().map(|(id, _)| id.clone()).collect();
+                let len = group.iter().map(|(_, len)| len).sum();
+                let item = Manifest::encode(depth, len, &children);
+                let id = self.add_node(item, children.into_iter().collect())?;
+                parents.push((id, len));
+            }
+            level = parents;
+            depth += 1;
+        }
+        let children: Vec<Vec<u8>> = level.iter().map(|(id, _)| id.clone()).collect();
+        let len = level.iter().map(|(_, len)| len).sum();
+        let item = Manifest::encode(depth, len, &children);
+        dependency_ids.extend(children);
+        self.add_node(item, dependency_ids)
+    }
+
+    /// Stream the bytes added with [Merkle::add_chunked] under the manifest `manifest_id`.
+    /// Chunks are read from the [Store] as the reader reaches them. Fails if the node isn't a
+    /// manifest. Errors found while reading are returned as [io::Error]s wrapping the
+    /// [StoreError].
+    pub fn read_chunked(&self, manifest_id: &[u8]) -> Result<ChunkedReader<'_, S, HW, P>> {
+        let manifest = self.get_manifest(manifest_id)?;
+        Ok(ChunkedReader {
+            dag: self,
+            len: manifest.len,
+            stack: vec![(manifest, 0)],
+            chunk: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    fn get_manifest(&self, id: &[u8]) -> Result<Manifest> {
+        let node = self
+            .get_node_by_id(id)?
+            .ok_or_else(|| StoreError::NoSuchNode(id.to_vec()))?;
+        let dependency_ids: Vec<&[u8]> = node.dep_set().iter().map(Vec::as_slice).collect();
+        Manifest::decode::<HW>(id, node.item(), &dependency_ids)
+    }
+}
+
+/// Streams the bytes under a manifest written by [Merkle::add_chunked]. Returned by
+/// [Merkle::read_chunked].
+pub struct ChunkedReader<'dag, S, HW, P = Vec<u8>>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    dag: &'dag Merkle<S, HW, P>,
+    len: u64,
+    // The manifests from the top down each with the index of the next child to read.
+    stack: Vec<(Manifest, usize)>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<'dag, S, HW, P> ChunkedReader<'dag, S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// The number of bytes under the manifest as recorded in it.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the manifest records no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Read the next chunk in order or None after the last one.
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let (manifest, next) = match self.stack.last_mut() {
+                Some(top) => top,
+                None => return Ok(None),
+            };
+            let child = match manifest.children.get(*next) {
+                Some(child) => child.clone(),
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+            *next += 1;
+            if manifest.level == 0 {
+                let node = self
+                    .dag
+                    .get_node_by_id(&child)?
+                    .ok_or(StoreError::NoSuchNode(child))?;
+                return Ok(Some(node.item().to_vec()));
+            }
+            let level = manifest.level;
+            let child_manifest = self.dag.get_manifest(&child)?;
+            if child_manifest.level + 1 != level {
+                return Err(StoreError::StoreFailure(format!(
+                    "Invalid chunk manifest {}: level {} under level {}",
+                    hex(&child),
+                    child_manifest.level,
+                    level
+                )));
+            }
+            self.stack.push((child_manifest, 0));
+        }
+    }
+}
+
+impl<'dag, S, HW, P> Read for ChunkedReader<'dag, S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.next_chunk().map_err(io::Error::other)? {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.pos);
+        buf[..read].copy_from_slice(&self.chunk[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
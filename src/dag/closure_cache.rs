@@ -0,0 +1,65 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::{Merkle, WorkUnits};
+use crate::hash::HashWriter;
+use crate::store::{Result, Store};
+
+/// A value materialized by a [Store] along with how fresh it is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CachedValue<T> {
+    /// The value is exact for the current contents of the [Store].
+    Fresh(T),
+    /// The value was exact when it was computed but writes since then may have changed it.
+    Stale(T),
+    /// The value has not been computed yet.
+    Missing,
+}
+
+impl<T> CachedValue<T> {
+    /// The cached value regardless of its freshness.
+    pub fn value(self) -> Option<T> {
+        match self {
+            Self::Fresh(value) | Self::Stale(value) => Some(value),
+            Self::Missing => None,
+        }
+    }
+
+    /// Whether the value is exact for the current contents of the [Store].
+    pub fn is_fresh(&self) -> bool {
+        matches!(self, Self::Fresh(_))
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Get the materialized number of ancestors of the `id`. Unlike [Merkle::closure_stats]
+    /// this is a single read but the value may be stale or missing until
+    /// [Merkle::refresh_closure_sizes] catches up. Requires a [Store] that supports
+    /// [Store::cached_closure_size].
+    pub fn closure_size_cached(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.charge(WorkUnits::StoreReads(1))?;
+        self.nodes.cached_closure_size(id)
+    }
+
+    /// Compute the materialized ancestor counts of up to `batch` [nodes](crate::node::Node)
+    /// whose value is missing or stale, newest first, returning how many were computed. Call
+    /// it until it returns 0 to bring every value up to date. Requires a [Store] that supports
+    /// [Store::refresh_closure_sizes].
+    pub fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.nodes.refresh_closure_sizes(batch)
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeSet;
+
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::node::Node;
+use crate::store::{BlobStore, Result, Store, StoreError};
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW> + BlobStore,
+{
+    /// Add a new node like [Merkle::add_node] keeping its item in the [BlobStore] under the
+    /// item id. The [Store] gets a [detached](Node::is_detached) node without the item so
+    /// traversals and [Merkle::compare] never read the blob. The id is the id the same item
+    /// and dependencies get from [Merkle::add_node] so both can be mixed in one DAG. If the
+    /// node already exists nothing is written. Read the item with [Merkle::get_item].
+    pub fn add_detached<N: Into<Vec<u8>>>(
+        &mut self,
+        item: N,
+        dependency_ids: BTreeSet<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let node = Node::<HW>::new_with_id_version(item, dependency_ids, self.id_version);
+        let (node, item) = node.into_detached();
+        self.add_node_with(node, |nodes, node, roots| {
+            // The blob is written first so a stored node always finds its item.
+            nodes.put_blob(node.item_id(), item)?;
+            match roots {
+                Some(roots) => nodes.store_with_roots(node, roots),
+                None => nodes.store(node),
+            }
+        })
+    }
+
+    /// Get the item of a [Node] if it exists. The item of a [detached](Node::is_detached) node
+    /// is fetched from the [BlobStore] and checked against the id of the node. Fails with
+    /// [StoreError::CorruptNode] if it doesn't hash to the id or is missing.
+    pub fn get_item(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        let node = match self.get_node_by_id(id)? {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        if !node.is_detached() {
+            return Ok(Some(node.item().to_vec()));
+        }
+        let item = self
+            .nodes
+            .get_blob(node.item_id())?
+            .ok_or_else(|| StoreError::CorruptNode { id: id.to_vec() })?;
+        node.validate_item(&item)
+            .map_err(|_| StoreError::CorruptNode { id: id.to_vec() })?;
+        Ok(Some(item))
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::node::DepSet;
+use crate::store::{Result, Store, StoreError};
+
+/// An opaque identifier for a peer replicating a [Merkle DAG](Merkle).
+pub type PeerId = Vec<u8>;
+
+/// A summary of how far a fleet of peers has diverged from their common state.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct FleetDivergence {
+    /// The deepest ids that are ancestors of or equal to a head of every peer.
+    pub common_frontier: BTreeSet<Vec<u8>>,
+    /// The number of ids reachable from each peer's heads that are above the common frontier.
+    pub suffix_counts: BTreeMap<PeerId, usize>,
+    /// The total number of ids between the common frontier and the union of all peers' heads.
+    pub divergent_count: usize,
+    /// The heads each peer reported that are not in the local DAG.
+    pub unknown_heads: BTreeMap<PeerId, BTreeSet<Vec<u8>>>,
+}
+
+// A fixed size set of peer indexes.
+#[derive(Clone, PartialEq, Eq)]
+struct PeerBits(Vec<u64>);
+
+impl PeerBits {
+    fn new(peers: usize) -> Self {
+        Self(vec![0; peers.div_ceil(64)])
+    }
+
+    fn insert(&mut self, peer: usize) {
+        self.0[peer / 64] |= 1 << (peer % 64);
+    }
+
+    fn contains(&self, peer: usize) -> bool {
+        self.0[peer / 64] & (1 << (peer % 64)) != 0
+    }
+
+    fn union(&mut self, other: &PeerBits) {
+        for (word, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *word |= other;
+        }
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Compute the [FleetDivergence] for the reported heads of a set of peers in a single
+    /// traversal of the local DAG. Heads that are not in the local DAG are reported per peer
+    /// and peers without any known heads do not constrain the common frontier.
+    pub fn fleet_divergence(
+        &self,
+        peer_heads: &BTreeMap<PeerId, BTreeSet<Vec<u8>>>,
+    ) -> Result<FleetDivergence> {
+        let mut divergence = FleetDivergence::default();
+        let peers: Vec<&PeerId> = peer_heads.keys().collect();
+        let mut labels: BTreeMap<Vec<u8>, PeerBits> = BTreeMap::new();
+        let mut all = PeerBits::new(peers.len());
+        for (idx, peer) in peers.iter().enumerate() {
+            for head in peer_heads[*peer].iter() {
+                if self.check_for_node(head)? {
+                    all.insert(idx);
+                    labels
+                        .entry(head.clone())
+                        .or_insert_with(|| PeerBits::new(peers.len()))
+                        .insert(idx);
+                } else {
+                    divergence
+                        .unknown_heads
+                        .entry((*peer).clone())
+                        .or_default()
+                        .insert(head.clone());
+                }
+            }
+        }
+        // Collect the closure of every known head along with the number of children each
+        // id has inside that closure.
+        let mut deps: BTreeMap<Vec<u8>, DepSet> = BTreeMap::new();
+        let mut child_counts: BTreeMap<Vec<u8>, usize> = BTreeMap::new();
+        let mut stack: Vec<Vec<u8>> = labels.keys().cloned().collect();
+        let mut visited = 0;
+        while let Some(id) = stack.pop() {
+            self.charge_visit(&mut visited)?;
+            if deps.contains_key(&id) {
+                continue;
+            }
+            let node = match self.get_node_by_id(&id)? {
+                Some(n) => n,
+                None => return Err(StoreError::NoSuchNode(id.clone())),
+            };
+            child_counts.entry(id.clone()).or_insert(0);
+            for dep in node.dep_set() {
+                *child_counts.entry(dep.clone()).or_insert(0) += 1;
+                stack.push(dep.clone());
+            }
+            deps.insert(id, node.dep_set().clone());
+        }
+        // Propagate the peer labels from the heads towards the leaves visiting each id
+        // only after all of its children in the closure.
+        let mut remaining = child_counts.clone();
+        let mut ready: Vec<Vec<u8>> = child_counts
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut common = BTreeSet::new();
+        while let Some(id) = ready.pop() {
+            self.charge_visit(&mut visited)?;
+            let label = labels
+                .entry(id.clone())
+                .or_insert_with(|| PeerBits::new(peers.len()))
+                .clone();
+            if label == all {
+                common.insert(id.clone());
+            } else {
+                divergence.divergent_count += 1;
+                for (idx, peer) in peers.iter().enumerate() {
+                    if label.contains(idx) {
+                        *divergence.suffix_counts.entry((*peer).clone()).or_insert(0) += 1;
+                    }
+                }
+            }
+            for dep in deps[&id].iter() {
+                labels
+                    .entry(dep.clone())
+                    .or_insert_with(|| PeerBits::new(peers.len()))
+                    .union(&label);
+                let count = remaining.get_mut(dep).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    ready.push(dep.clone());
+                }
+            }
+        }
+        for peer in peers {
+            divergence.suffix_counts.entry(peer.clone()).or_insert(0);
+        }
+        // The frontier is the common ids that have no common children.
+        let mut covered = BTreeSet::new();
+        for id in common.iter() {
+            covered.extend(deps[id].iter().cloned());
+        }
+        divergence.common_frontier = common.difference(&covered).cloned().collect();
+        Ok(divergence)
+    }
+}
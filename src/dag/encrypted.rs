@@ -0,0 +1,44 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeSet;
+
+use super::Merkle;
+use crate::encryption::PayloadKey;
+use crate::hash::HashWriter;
+use crate::store::{Result, Store};
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Encrypt `plaintext` with `key` and add a node like [Merkle::add_node] with the
+    /// ciphertext as its item. The id is computed over the ciphertext so the node can be
+    /// verified without the key. Read the plaintext back with
+    /// [Node::decrypt_item](crate::node::Node::decrypt_item).
+    ///
+    /// With the default [EncryptionMode::RandomNonce](crate::encryption::EncryptionMode)
+    /// adding the same plaintext twice gives two different nodes. Use a key with
+    /// [EncryptionMode::Deterministic](crate::encryption::EncryptionMode) to deduplicate
+    /// them.
+    pub fn add_encrypted<N: AsRef<[u8]>>(
+        &mut self,
+        plaintext: N,
+        dependency_ids: BTreeSet<Vec<u8>>,
+        key: &PayloadKey,
+    ) -> Result<Vec<u8>> {
+        let item = key.encrypt(plaintext.as_ref())?;
+        self.add_node(item, dependency_ids)
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeSet, VecDeque};
+
+use super::{Merkle, WorkUnits};
+use crate::hash::HashWriter;
+use crate::store::{Result, Store, StoreError};
+
+/// The [nodes](crate::node::Node) affected by expunging a set of directly attributed ids.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct ExpungeReport {
+    /// The attributed ids that are present in the [Store].
+    pub attributed: BTreeSet<Vec<u8>>,
+    /// Descendants of the attributed ids whose every dependency path passes through an
+    /// attributed or collateral id.
+    pub collateral: BTreeSet<Vec<u8>>,
+    /// Descendants of the attributed ids that also depend on unaffected history.
+    pub retained: BTreeSet<Vec<u8>>,
+}
+
+impl ExpungeReport {
+    /// All the ids that would be expunged.
+    pub fn affected(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.attributed.iter().chain(self.collateral.iter())
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Compute which [nodes](crate::node::Node) would be affected by expunging the `attributed`
+    /// ids, for example every node written by a revoked author. A descendant of an attributed
+    /// id is collateral if all of its dependencies are attributed or collateral and retained
+    /// if any of them is supported by unaffected history. Requires a [Store] that supports
+    /// [Store::children_of]. Nothing in the DAG is modified.
+    pub fn plan_expunge<'a, I>(&self, attributed: I) -> Result<ExpungeReport>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut report = ExpungeReport::default();
+        for id in attributed {
+            if self.check_for_node(id)? {
+                report.attributed.insert(id.to_vec());
+            }
+        }
+        let mut candidates = BTreeSet::new();
+        let mut queue = VecDeque::new();
+        for id in report.attributed.iter() {
+            self.charge(WorkUnits::StoreReads(1))?;
+            queue.extend(self.nodes.children_of(id)?);
+        }
+        let mut visited = 0;
+        // A node is only settled as collateral once all of its dependencies are affected so
+        // each child of a newly affected node is checked again.
+        while let Some(id) = queue.pop_front() {
+            self.charge_visit(&mut visited)?;
+            if report.attributed.contains(&id) || report.collateral.contains(&id) {
+                continue;
+            }
+            candidates.insert(id.clone());
+            let node = match self.get_node_by_id(&id)? {
+                Some(n) => n,
+                None => return Err(StoreError::NoSuchNode(id.clone())),
+            };
+            let orphaned = node
+                .dep_set()
+                .iter()
+                .all(|dep| report.attributed.contains(dep) || report.collateral.contains(dep));
+            if orphaned {
+                self.charge(WorkUnits::StoreReads(1))?;
+                queue.extend(self.nodes.children_of(&id)?);
+                report.collateral.insert(id);
+            }
+        }
+        // The candidates that never became collateral and their descendants are retained.
+        let mut stack: Vec<Vec<u8>> = candidates.difference(&report.collateral).cloned().collect();
+        while let Some(id) = stack.pop() {
+            self.charge_visit(&mut visited)?;
+            if report.collateral.contains(&id) || !report.retained.insert(id.clone()) {
+                continue;
+            }
+            self.charge(WorkUnits::StoreReads(1))?;
+            stack.extend(self.nodes.children_of(&id)?);
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{Merkle, WorkUnits};
+use crate::hash::HashWriter;
+use crate::node::Node;
+use crate::store::{Result, Store, StoreError};
+
+#[cfg(feature = "cbor")]
+use crate::store::codec;
+
+/// How many [nodes](crate::node::Node) [Merkle::retain_reachable] kept and removed.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct GcReport {
+    /// Nodes reachable from the kept roots.
+    pub kept: usize,
+    /// Nodes deleted from the [Store].
+    pub removed: usize,
+}
+
+// What garbage collection keeps of a doomed node until it is deleted.
+struct Doomed {
+    deps: BTreeSet<Vec<u8>>,
+    #[cfg(feature = "cbor")]
+    encoded_size: usize,
+}
+
+impl<HW: HashWriter> From<&Node<HW>> for Doomed {
+    fn from(node: &Node<HW>) -> Self {
+        Self {
+            deps: node.dep_set().iter().cloned().collect(),
+            #[cfg(feature = "cbor")]
+            encoded_size: codec::encoded_size(node),
+        }
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// The ids [pinned](Merkle::pin) against garbage collection.
+    pub fn pins(&self) -> &BTreeSet<Vec<u8>> {
+        &self.pins
+    }
+
+    /// Pin the `id` so [Merkle::retain_reachable] keeps it and its ancestors even if no kept
+    /// root reaches it. Fails with [StoreError::NoSuchNode] if the DAG has no such node. Pins
+    /// are persisted with the roots of DAGs opened with [Merkle::load].
+    pub fn pin(&mut self, id: &[u8]) -> Result<()> {
+        self.charge(WorkUnits::StoreReads(1))?;
+        if !self.nodes.contains(id)? {
+            return Err(StoreError::NoSuchNode(id.to_vec()));
+        }
+        if self.pins.insert(id.to_vec()) {
+            self.write_roots()?;
+        }
+        Ok(())
+    }
+
+    /// Remove the pin from the `id` returning whether it was pinned.
+    pub fn unpin(&mut self, id: &[u8]) -> Result<bool> {
+        let pinned = self.pins.remove(id);
+        if pinned {
+            self.write_roots()?;
+        }
+        Ok(pinned)
+    }
+
+    /// Delete every [Node](crate::node::Node) that is not one of the `keep_roots`, a
+    /// [pinned](Merkle::pin) id or one of their ancestors. Fails with
+    /// [StoreError::NoSuchNode] before deleting anything if one of the `keep_roots` is not in
+    /// the DAG. Afterwards the roots of the DAG are the kept nodes nothing kept depends on.
+    /// If a delete fails the roots are the nodes nothing left in the [Store] depends on and
+    /// are persisted before the error is returned. Requires a [Store] that supports
+    /// [Store::ids] and [Store::delete].
+    pub fn retain_reachable(&mut self, keep_roots: &BTreeSet<Vec<u8>>) -> Result<GcReport> {
+        for id in keep_roots {
+            if !self.check_for_node(id)? {
+                return Err(StoreError::NoSuchNode(id.clone()));
+            }
+        }
+        let mut kept = BTreeSet::new();
+        let mut depended_on = BTreeSet::new();
+        let mut stack: Vec<Vec<u8>> = keep_roots.union(&self.pins).cloned().collect();
+        let mut visited = 0;
+        while let Some(id) = stack.pop() {
+            if kept.contains(&id) {
+                continue;
+            }
+            self.charge_visit(&mut visited)?;
+            let deps = match self.get_handle_by_id(&id)? {
+                Some(handle) => handle.dependency_ids().clone(),
+                None => return Err(StoreError::NoSuchNode(id.clone())),
+            };
+            for dep in deps {
+                depended_on.insert(dep.clone());
+                stack.push(dep);
+            }
+            kept.insert(id);
+        }
+        let mut doomed = BTreeMap::new();
+        for id in self.nodes.ids()? {
+            let id = id?;
+            if !kept.contains(&id) {
+                doomed.insert(id, 0usize);
+            }
+        }
+        // The descendants of a doomed node are doomed as well. Deleting a node only once
+        // every doomed node depending on it is gone means the store never holds a dangling
+        // dependency if a delete fails part way.
+        let mut doomed_nodes = BTreeMap::new();
+        for id in doomed.keys() {
+            self.charge_visit(&mut visited)?;
+            let node = match self.get_node_by_id(id)? {
+                Some(node) => node,
+                None => return Err(StoreError::NoSuchNode(id.clone())),
+            };
+            doomed_nodes.insert(id.clone(), Doomed::from(&node));
+        }
+        for node in doomed_nodes.values() {
+            for dep in &node.deps {
+                if let Some(count) = doomed.get_mut(dep) {
+                    *count += 1;
+                }
+            }
+        }
+        let mut ready: Vec<Vec<u8>> = doomed
+            .iter()
+            .filter(|(_, dependents)| **dependents == 0)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut removed = 0;
+        while let Some(id) = ready.pop() {
+            let node = doomed_nodes.remove(&id).expect("Doomed nodes are loaded");
+            if let Err(err) = self.nodes.delete(&id) {
+                // Keep the roots of the nodes deleted so far. The failed delete is the error
+                // worth reporting.
+                let _ = self.write_roots();
+                return Err(err);
+            }
+            removed += 1;
+            self.roots.remove(&id);
+            #[cfg(feature = "cbor")]
+            {
+                self.stored_bytes = self.stored_bytes.saturating_sub(node.encoded_size);
+            }
+            for dep in &node.deps {
+                match doomed.get_mut(dep) {
+                    Some(count) => {
+                        *count -= 1;
+                        if *count == 0 {
+                            self.roots.insert(dep.clone());
+                            ready.push(dep.clone());
+                        }
+                    }
+                    None if !depended_on.contains(dep) => {
+                        self.roots.insert(dep.clone());
+                    }
+                    None => {}
+                }
+            }
+            self.sticky_roots.remove(&id);
+            self.tag_markers.remove(&id);
+        }
+        self.roots = kept.difference(&depended_on).cloned().collect();
+        self.write_roots()?;
+        #[cfg(feature = "debug-invariants")]
+        self.debug_check_sampled("retain_reachable")?;
+        Ok(GcReport {
+            kept: kept.len(),
+            removed,
+        })
+    }
+}
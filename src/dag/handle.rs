@@ -0,0 +1,199 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeSet, VecDeque};
+use std::marker::PhantomData;
+use std::sync::{Arc, OnceLock};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{Merkle, WorkUnits};
+use crate::hash::HashWriter;
+use crate::node::{DepSet, Node};
+use crate::store::{Result, Store, StoreError};
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "NodeHandle")]
+struct HandleInner {
+    id: Vec<u8>,
+    item_id: Vec<u8>,
+    dependency_ids: DepSet,
+    #[serde(skip)]
+    payload: OnceLock<Vec<u8>>,
+}
+
+/// The structure of a [Node] with its payload fetched on demand.
+///
+/// Handles are cheap to clone and serialize without the payload. The payload is fetched by
+/// [NodeHandle::payload] the first time it is needed and memoized in the handle and its clones.
+pub struct NodeHandle<HW> {
+    inner: Arc<HandleInner>,
+    _phantom: PhantomData<HW>,
+}
+
+impl<HW> NodeHandle<HW>
+where
+    HW: HashWriter,
+{
+    /// The id of the [Node].
+    pub fn id(&self) -> &[u8] {
+        &self.inner.id
+    }
+
+    /// The id of the payload of the [Node].
+    pub fn item_id(&self) -> &[u8] {
+        &self.inner.item_id
+    }
+
+    /// The dependency ids of the [Node].
+    pub fn dependency_ids(&self) -> &DepSet {
+        &self.inner.dependency_ids
+    }
+
+    /// Whether the payload has already been fetched.
+    pub fn is_hydrated(&self) -> bool {
+        self.inner.payload.get().is_some()
+    }
+
+    /// Fetch the payload of the [Node] from the `dag` the first time it is needed. Fails with
+    /// [StoreError::StaleHandle] if the [Node] was removed or its payload no longer matches
+    /// the item id recorded in the handle.
+    pub fn payload<S, P>(&self, dag: &Merkle<S, HW, P>) -> Result<&[u8]>
+    where
+        S: Store<HW>,
+    {
+        if let Some(payload) = self.inner.payload.get() {
+            return Ok(payload);
+        }
+        let node = dag.get_node_by_id(self.id())?;
+        match node {
+            Some(node) if node.item_id() == self.item_id() => {
+                Ok(self.inner.payload.get_or_init(|| node.item().to_vec()))
+            }
+            node => Err(StoreError::StaleHandle {
+                id: self.id().to_vec(),
+                expected_item_id: self.item_id().to_vec(),
+                found_item_id: node.map(|n| n.item_id().to_vec()),
+            }),
+        }
+    }
+}
+
+impl<HW> Clone for NodeHandle<HW> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<HW> std::fmt::Debug for NodeHandle<HW> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeHandle")
+            .field("id", &self.inner.id)
+            .field("item_id", &self.inner.item_id)
+            .field("dependency_ids", &self.inner.dependency_ids)
+            .field("hydrated", &self.inner.payload.get().is_some())
+            .finish()
+    }
+}
+
+impl<HW> From<&Node<HW>> for NodeHandle<HW>
+where
+    HW: HashWriter,
+{
+    fn from(node: &Node<HW>) -> Self {
+        Self {
+            inner: Arc::new(HandleInner {
+                id: node.id().to_vec(),
+                item_id: node.item_id().to_vec(),
+                dependency_ids: node.dep_set().clone(),
+                payload: OnceLock::new(),
+            }),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+// Serialized without the payload.
+impl<HW> Serialize for NodeHandle<HW> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.inner.serialize(serializer)
+    }
+}
+
+impl<'de, HW> Deserialize<'de> for NodeHandle<HW> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(Self {
+            inner: Arc::new(HandleInner::deserialize(deserializer)?),
+            _phantom: PhantomData,
+        })
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Get the [NodeHandle] for an id without fetching the payload if the [Store] supports it.
+    pub fn get_handle_by_id(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        self.charge(WorkUnits::StoreReads(1))?;
+        self.nodes.get_handle(id)
+    }
+
+    /// Get the [NodeHandle] of every ancestor of the `id` in breadth first order.
+    pub fn ancestors_handles(&self, id: &[u8]) -> Result<Vec<NodeHandle<HW>>> {
+        let start = match self.get_handle_by_id(id)? {
+            Some(handle) => handle,
+            None => return Ok(Vec::new()),
+        };
+        let mut seen = BTreeSet::from([id.to_vec()]);
+        let mut queue: VecDeque<Vec<u8>> = start.dependency_ids().iter().cloned().collect();
+        let mut handles = Vec::new();
+        let mut visited = 0;
+        while let Some(id) = queue.pop_front() {
+            if !seen.insert(id.clone()) {
+                continue;
+            }
+            self.charge_visit(&mut visited)?;
+            let handle = match self.get_handle_by_id(&id)? {
+                Some(handle) => handle,
+                None => return Err(StoreError::NoSuchNode(id.clone())),
+            };
+            queue.extend(
+                handle
+                    .dependency_ids()
+                    .iter()
+                    .filter(|dep| !seen.contains(*dep))
+                    .cloned(),
+            );
+            handles.push(handle);
+        }
+        Ok(handles)
+    }
+
+    /// Like [Merkle::find_next_non_descendant_nodes] but returning [NodeHandle]s so no
+    /// payloads are fetched.
+    pub fn find_next_non_descendant_handles(
+        &self,
+        search_nodes: &BTreeSet<Vec<u8>>,
+    ) -> Result<Vec<NodeHandle<HW>>> {
+        let mut result = Vec::new();
+        for id in self.find_next_non_descendant_ids(search_nodes)? {
+            result.push(self.get_handle_by_id(id.as_slice())?.unwrap());
+        }
+        Ok(result)
+    }
+}
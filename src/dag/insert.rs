@@ -0,0 +1,90 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::node::NodeBuilder;
+use crate::store::{Result, Store};
+
+/// A [Node](crate::node::Node) being built for insertion into a [Merkle DAG](Merkle) with the
+/// surface of a [NodeBuilder]. Started by [Merkle::insert]. Nothing is added until
+/// [DagInsert::commit].
+pub struct DagInsert<'dag, S, HW, P = Vec<u8>>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    dag: &'dag mut Merkle<S, HW, P>,
+    node: NodeBuilder<HW>,
+}
+
+impl<'dag, S, HW, P> DagInsert<'dag, S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Set the payload item replacing any set before.
+    pub fn payload<N: Into<Vec<u8>>>(mut self, item: N) -> Self {
+        self.node = self.node.payload(item);
+        self
+    }
+
+    /// Add a dependency id.
+    pub fn depends_on<D: AsRef<[u8]>>(mut self, id: D) -> Self {
+        self.node = self.node.depends_on(id);
+        self
+    }
+
+    /// Add every dependency id of `ids`.
+    pub fn depends_on_all<D, I>(mut self, ids: I) -> Self
+    where
+        D: AsRef<[u8]>,
+        I: IntoIterator<Item = D>,
+    {
+        self.node = self.node.depends_on_all(ids);
+        self
+    }
+
+    /// Set a metadata attribute replacing the value of the key if it was set before.
+    pub fn attribute<K: Into<String>, V: Into<Vec<u8>>>(mut self, key: K, value: V) -> Self {
+        self.node = self.node.attribute(key, value);
+        self
+    }
+
+    /// Add the node to the DAG like [Merkle::add_node_with_attrs] returning its id. The id is
+    /// computed with the [NodeIdVersion](crate::node::NodeIdVersion) of the DAG.
+    pub fn commit(self) -> Result<Vec<u8>> {
+        let NodeBuilder {
+            item,
+            dependency_ids,
+            attributes,
+            ..
+        } = self.node;
+        self.dag
+            .add_node_with_attrs(item, dependency_ids, attributes)
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Start a [DagInsert] building a new node for this DAG.
+    pub fn insert(&mut self) -> DagInsert<'_, S, HW, P> {
+        DagInsert {
+            dag: self,
+            node: NodeBuilder::new(),
+        }
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeSet;
+
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::id::hex;
+use crate::store::{Result, Store};
+
+/// How much of the DAG [Merkle::assert_invariants] checks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Thoroughness {
+    /// Check that every root exists in the [Store].
+    Roots,
+    /// Walk the full closure of the roots checking that every dependency exists and
+    /// that no root is a dependency of another node.
+    Full,
+}
+
+/// With the `debug-invariants` feature enabled a sampled check is run after this many
+/// mutating operations. It checks the roots and continues a walk of their closure by up to
+/// [INVARIANT_SAMPLE_SIZE] nodes so every reachable node is checked eventually.
+pub const INVARIANT_SAMPLE_INTERVAL: usize = 16;
+
+/// The most nodes a sampled check run with the `debug-invariants` feature reads.
+pub const INVARIANT_SAMPLE_SIZE: usize = 64;
+
+// Where the sampled check left off. The walk starts over from the roots once it has visited
+// their whole closure.
+#[cfg(feature = "debug-invariants")]
+#[derive(Clone, Default, Debug)]
+pub(crate) struct InvariantSample {
+    ops: usize,
+    stack: Vec<Vec<u8>>,
+    seen: BTreeSet<Vec<u8>>,
+}
+
+fn violation(operation: &str, detail: &str, ids: &[&[u8]]) -> ! {
+    let ids: Vec<String> = ids.iter().map(|id| hex(id)).collect();
+    panic!(
+        "Merkle invariant violated after {}: {} ids=[{}]",
+        operation,
+        detail,
+        ids.join(", ")
+    );
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Check the structural invariants of the DAG panicking with a message naming the
+    /// offending ids if any of them are violated.
+    pub fn assert_invariants(&self, thoroughness: Thoroughness) -> Result<()> {
+        self.check_invariants("assert_invariants", thoroughness)
+    }
+
+    fn check_invariants(&self, operation: &str, thoroughness: Thoroughness) -> Result<()> {
+        for root in self.roots.iter() {
+            if !self.nodes.contains(root)? {
+                violation(operation, "root missing from store", &[root]);
+            }
+        }
+        if thoroughness == Thoroughness::Roots {
+            return Ok(());
+        }
+        let mut stack: Vec<Vec<u8>> = self.roots.iter().cloned().collect();
+        let mut seen: BTreeSet<Vec<u8>> = self.roots.clone();
+        while let Some(id) = stack.pop() {
+            if !self.check_node_invariants(operation, &id, &mut stack, &mut seen)? {
+                violation(operation, "node missing from store", &[&id]);
+            }
+        }
+        Ok(())
+    }
+
+    // Checks the dependencies of the node with this id pushing the unseen ones on the stack.
+    // Returns false if the node is not in the store.
+    fn check_node_invariants(
+        &self,
+        operation: &str,
+        id: &[u8],
+        stack: &mut Vec<Vec<u8>>,
+        seen: &mut BTreeSet<Vec<u8>>,
+    ) -> Result<bool> {
+        let node = match self.nodes.get(id)? {
+            Some(n) => n,
+            None => return Ok(false),
+        };
+        for dep in node.dep_set() {
+            if self.roots.contains(dep) {
+                violation(operation, "root is a dependency", &[id, dep]);
+            }
+            if !self.nodes.contains(dep)? {
+                violation(operation, "dangling dependency", &[id, dep]);
+            }
+            if seen.insert(dep.clone()) {
+                stack.push(dep.clone());
+            }
+        }
+        Ok(true)
+    }
+
+    // Incremental checks run after a node has been added. These are O(deps) except for
+    // the sampled check.
+    #[cfg(feature = "debug-invariants")]
+    pub(crate) fn debug_check_add(
+        &mut self,
+        operation: &str,
+        id: &[u8],
+        dependency_ids: &BTreeSet<Vec<u8>>,
+    ) -> Result<()> {
+        if !self.nodes.contains(id)? {
+            violation(operation, "added node missing from store", &[id]);
+        }
+        if !self.roots.contains(id) {
+            violation(operation, "added node is not a root", &[id]);
+        }
+        for dep in dependency_ids {
+            if !self.nodes.contains(dep)? {
+                violation(operation, "dangling dependency", &[id, dep]);
+            }
+            if self.roots.contains(dep) {
+                violation(
+                    operation,
+                    "dependency was not removed from roots",
+                    &[id, dep],
+                );
+            }
+        }
+        self.debug_check_sampled(operation)
+    }
+
+    #[cfg(feature = "debug-invariants")]
+    pub(crate) fn debug_check_sampled(&mut self, operation: &str) -> Result<()> {
+        let mut sample = std::mem::take(&mut self.invariant_sample);
+        sample.ops += 1;
+        let result = if sample.ops.is_multiple_of(INVARIANT_SAMPLE_INTERVAL) {
+            self.check_sample(operation, &mut sample)
+        } else {
+            Ok(())
+        };
+        self.invariant_sample = sample;
+        result
+    }
+
+    #[cfg(feature = "debug-invariants")]
+    fn check_sample(&self, operation: &str, sample: &mut InvariantSample) -> Result<()> {
+        self.check_invariants(operation, Thoroughness::Roots)?;
+        if sample.stack.is_empty() {
+            sample.stack = self.roots.iter().cloned().collect();
+            sample.seen = self.roots.clone();
+        }
+        for _ in 0..INVARIANT_SAMPLE_SIZE {
+            let id = match sample.stack.pop() {
+                Some(id) => id,
+                None => break,
+            };
+            // A node that is gone was removed since the walk reached it. Its dependents were
+            // checked when they were visited.
+            self.check_node_invariants(operation, &id, &mut sample.stack, &mut sample.seen)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn roots_mut(&mut self) -> &mut BTreeSet<Vec<u8>> {
+        &mut self.roots
+    }
+}
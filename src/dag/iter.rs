@@ -11,41 +11,90 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 use super::Merkle;
 use crate::hash::HashWriter;
 use crate::node::Node;
-use crate::store::{Result, Store};
+use crate::store::{Result, Store, StoreError};
 
 /// An iterator over the missing [nodes](Node) in a [Merkle DAG](Merkle) given a set of root nodes.
-pub struct Missing<'dag, S, HW>
+///
+/// Every batch only contains nodes whose dependencies were in an earlier batch or are known to
+/// the holder of the root nodes, so a receiver can add each batch in order. Every missing node
+/// is returned exactly once.
+pub struct Missing<'dag, S, HW, P = Vec<u8>>
 where
     S: Store<HW>,
     HW: HashWriter,
 {
-    dag: &'dag Merkle<S, HW>,
+    dag: &'dag Merkle<S, HW, P>,
     root_nodes: BTreeSet<Vec<u8>>,
+    // The ids the receiver has or was sent.
+    known: BTreeSet<Vec<u8>>,
+    // The nodes left to send. Computed on the first call to next_nodes.
+    pending: Option<BTreeMap<Vec<u8>, Node<HW>>>,
 }
 
-impl<'dag, S, HW> Missing<'dag, S, HW>
+impl<'dag, S, HW, P> Missing<'dag, S, HW, P>
 where
     S: Store<HW>,
     HW: HashWriter,
 {
     /// Create an iterator for the missing [nodes](Node) given a set of root [nodes](Node).
-    pub fn new(dag: &'dag Merkle<S, HW>, root_nodes: BTreeSet<Vec<u8>>) -> Self {
-        Self { dag, root_nodes }
+    pub fn new(dag: &'dag Merkle<S, HW, P>, root_nodes: BTreeSet<Vec<u8>>) -> Self {
+        Self {
+            dag,
+            root_nodes,
+            known: BTreeSet::new(),
+            pending: None,
+        }
+    }
+
+    // Collects every node reachable from the roots of the DAG that is not an ancestor of the
+    // root nodes.
+    fn find_pending(&mut self) -> Result<BTreeMap<Vec<u8>, Node<HW>>> {
+        for root in self.root_nodes.iter() {
+            self.known.extend(self.dag.ancestors_of(root)?);
+            self.known.insert(root.clone());
+        }
+        let mut pending = BTreeMap::new();
+        let mut frontier: BTreeSet<Vec<u8>> = self.dag.get_roots().clone();
+        let mut visited = 0;
+        // Fetch a whole level of unknown ids with a single store call.
+        while !frontier.is_empty() {
+            frontier.retain(|id| !self.known.contains(id) && !pending.contains_key(id));
+            let ids: Vec<&[u8]> = frontier.iter().map(Vec::as_slice).collect();
+            let nodes = self.dag.get_nodes_by_ids(&ids)?;
+            let mut next = BTreeSet::new();
+            for (id, node) in frontier.into_iter().zip(nodes) {
+                self.dag.charge_visit(&mut visited)?;
+                let node = node.ok_or_else(|| StoreError::NoSuchNode(id.clone()))?;
+                next.extend(node.dep_set().iter().cloned());
+                pending.insert(id, node);
+            }
+            frontier = next;
+        }
+        Ok(pending)
     }
 
     /// Returns the next set of missing [nodes](Node) in the iterator.
     pub fn next_nodes(&mut self) -> Result<Option<Vec<Node<HW>>>> {
-        let nodes = self.dag.find_next_non_descendant_nodes(&self.root_nodes)?;
-        self.root_nodes = BTreeSet::new();
-        for id in nodes.iter().map(|n| n.id().to_vec()) {
-            self.root_nodes.insert(id);
+        if self.pending.is_none() {
+            self.pending = Some(self.find_pending()?);
+        }
+        let pending = self.pending.as_mut().unwrap();
+        let ready: Vec<Vec<u8>> = pending
+            .iter()
+            .filter(|(_, node)| node.dep_set().iter().all(|d| self.known.contains(d)))
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut nodes = Vec::with_capacity(ready.len());
+        for id in ready {
+            nodes.push(pending.remove(&id).unwrap());
+            self.known.insert(id);
         }
-        if nodes.len() > 0 {
+        if !nodes.is_empty() {
             Ok(Some(nodes))
         } else {
             Ok(None)
@@ -53,7 +102,7 @@ where
     }
 }
 
-impl<'dag, S, HW> Iterator for Missing<'dag, S, HW>
+impl<'dag, S, HW, P> Iterator for Missing<'dag, S, HW, P>
 where
     S: Store<HW>,
     HW: HashWriter,
@@ -0,0 +1,97 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::store::{Result, Store, StoreError};
+
+/// Traversals charge a [WorkMeter] once per this many visited [nodes](crate::node::Node).
+pub const METER_NODE_BATCH: usize = 64;
+
+/// An amount of work charged to a [WorkMeter].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WorkUnits {
+    /// Nodes visited by a traversal.
+    NodesVisited(usize),
+    /// Reads against the [Store].
+    StoreReads(usize),
+    /// Bytes serialized for export or sync.
+    BytesSerialized(usize),
+}
+
+/// Returned by a [WorkMeter] to abort the operation being charged.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Throttled {
+    /// How long the caller should wait before retrying if known.
+    pub retry_after_hint: Option<Duration>,
+}
+
+impl From<Throttled> for StoreError {
+    fn from(t: Throttled) -> Self {
+        StoreError::Throttled {
+            retry_after_hint: t.retry_after_hint,
+        }
+    }
+}
+
+/// Meters the work done by a [Merkle DAG](Merkle) so that embedders can throttle callers.
+///
+/// A meter is responsible for attributing the work to the current logical caller. Operations
+/// charged while the meter returns [Throttled] fail with [StoreError::Throttled].
+pub trait WorkMeter: Send + Sync {
+    /// Charge the `units` of work to the current caller.
+    fn charge(&self, units: WorkUnits) -> std::result::Result<(), Throttled>;
+}
+
+// A shared handle to a WorkMeter so the DAG can stay Clone and Debug.
+#[derive(Clone)]
+pub(crate) struct MeterHandle(Arc<dyn WorkMeter>);
+
+impl fmt::Debug for MeterHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WorkMeter")
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Set the [WorkMeter] charged by traversals and store reads or remove it with None.
+    pub fn set_work_meter(&mut self, meter: Option<Arc<dyn WorkMeter>>) {
+        self.meter = meter.map(MeterHandle);
+    }
+
+    pub(crate) fn charge(&self, units: WorkUnits) -> Result<()> {
+        if let Some(MeterHandle(meter)) = &self.meter {
+            meter.charge(units)?;
+        }
+        Ok(())
+    }
+
+    // Count a visited node charging the meter for every METER_NODE_BATCH visits.
+    pub(crate) fn charge_visit(&self, visited: &mut usize) -> Result<()> {
+        if self.meter.is_some() {
+            *visited += 1;
+            if visited.is_multiple_of(METER_NODE_BATCH) {
+                self.charge(WorkUnits::NodesVisited(METER_NODE_BATCH))?;
+            }
+        }
+        Ok(())
+    }
+}
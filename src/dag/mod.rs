@@ -13,16 +13,75 @@
 // limitations under the License.
 //! Implementation of the MerkleDag based off of the merkle-crdt whitepaper.
 
-use std::{collections::BTreeSet, marker::PhantomData};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    marker::PhantomData,
+    sync::Arc,
+};
 
 use crate::{
+    clock::{Clock, ClockHandle},
     hash::HashWriter,
-    node::Node,
-    store::{Result, Store, StoreError},
+    id::NodeId,
+    node::{DepSet, Node, NodeIdVersion},
+    payload::{ByteDecoder, ByteEncoder},
+    store::{check_id_len, PersistedRoots, Result, Store, StoreError, TransactionalStore},
 };
 
+#[cfg(feature = "cbor")]
+use serde::Serialize;
+
+#[cfg(feature = "signatures")]
+use crate::signature::SignaturePolicy;
+
+#[cfg(feature = "cbor")]
+use crate::store::{codec, StoreStats};
+
+mod batch;
+mod bulk;
+mod chunked;
+mod closure_cache;
+mod detached;
+mod divergence;
+#[cfg(feature = "encryption")]
+mod encrypted;
+mod expunge;
+mod gc;
+mod handle;
+mod insert;
+mod invariants;
 mod iter;
+mod meter;
+mod persist;
+mod prefix;
+mod read_token;
+mod remove;
+mod root_policy;
+#[cfg(feature = "signatures")]
+mod signed;
+mod tags;
+mod text;
+mod transaction;
+mod uniformity;
+pub use batch::*;
+pub use bulk::*;
+pub use chunked::*;
+pub use closure_cache::*;
+pub use divergence::*;
+pub use expunge::*;
+pub use gc::*;
+pub use handle::*;
+pub use insert::*;
+pub use invariants::*;
 pub use iter::*;
+pub use meter::*;
+pub use prefix::*;
+pub use read_token::*;
+pub use remove::*;
+pub use root_policy::*;
+pub use tags::*;
+pub use transaction::*;
+pub use uniformity::*;
 
 /// Node comparison values. In a given Merkle DAG a Node can come [After](NodeCompare::After), [Before](NodeCompare::After), be [Equivalent](NodeCompare::Equivalent), or [Uncomparable](NodeCompare::Uncomparable).
 /// If the two nodes have the same id they are eqivalent. If two nodes are not part of the same sub graph within the DAG
@@ -36,6 +95,25 @@ pub enum NodeCompare {
     Uncomparable,
 }
 
+/// The direction a traversal of the [Merkle DAG](Merkle) expands in. [Up](Direction::Up) follows
+/// the dependency ids of each [Node] towards its ancestors. [Down](Direction::Down) follows the
+/// reverse dependency index of the [Store] towards its descendants and requires a [Store] that
+/// supports [Store::children_of].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// Statistics about the closure of a [Node] in a given [Direction].
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct ClosureStats {
+    /// The number of [nodes](Node) in the closure not counting the starting node.
+    pub size: usize,
+    /// The number of breadth first levels in the closure.
+    pub depth: usize,
+}
+
 /// A Merkle-DAG implementation. This is a modification on the standard Merkle Tree data structure
 /// but instead of a tree it is a DAG and as a result can have multiple roots. A merkle-dag specifies
 /// a partial ordering on all the nodes and utilizes the api to ensure that this ordering is
@@ -46,15 +124,36 @@ pub enum NodeCompare {
 ///
 /// A Merkle instance is tied to a specific implementation of the [HashWriter] interface to ensure
 /// that all hash identifiers are of the same hash algorithm.
+///
+/// The items of the [nodes](Node) are bytes. A DAG whose items all encode a single payload type
+/// `P` can add and read them as `P` with [Merkle::add_payload] and [Merkle::get_item_by_id].
+/// See [ByteEncoder] and [ByteDecoder]. `P` defaults to `Vec<u8>`.
 #[derive(Clone, Debug)]
-pub struct Merkle<S, HW>
+pub struct Merkle<S, HW, P = Vec<u8>>
 where
     HW: HashWriter,
     S: Store<HW>,
 {
     roots: BTreeSet<Vec<u8>>,
+    sticky_roots: BTreeSet<Vec<u8>>,
+    pins: BTreeSet<Vec<u8>>,
+    root_policy: RootPolicyHandle,
     nodes: S,
+    #[cfg(feature = "debug-invariants")]
+    invariant_sample: invariants::InvariantSample,
+    #[cfg(feature = "cbor")]
+    stored_bytes: usize,
+    meter: Option<meter::MeterHandle>,
+    tag_markers: BTreeMap<Vec<u8>, BTreeSet<String>>,
+    clock: ClockHandle,
+    // Whether root changes are written to the store. Set by Merkle::load.
+    persist_roots: bool,
+    id_version: NodeIdVersion,
+    min_id_version: NodeIdVersion,
+    #[cfg(feature = "signatures")]
+    signature_policy: SignaturePolicy,
     _phantom_node: PhantomData<Node<HW>>,
+    _phantom_payload: PhantomData<fn() -> P>,
 }
 
 impl<S, HW> Merkle<S, HW>
@@ -62,12 +161,78 @@ where
     HW: HashWriter,
     S: Store<HW>,
 {
-    /// Construct a new DAG.
+    /// Construct a new DAG. Use [Merkle::try_new] to check that the [Store] wasn't written
+    /// with another [HashWriter].
     pub fn new(s: S) -> Self {
         Self {
             nodes: s,
             roots: Default::default(),
+            sticky_roots: BTreeSet::new(),
+            pins: BTreeSet::new(),
+            root_policy: RootPolicyHandle::default(),
+            #[cfg(feature = "debug-invariants")]
+            invariant_sample: Default::default(),
+            #[cfg(feature = "cbor")]
+            stored_bytes: 0,
+            meter: None,
+            tag_markers: BTreeMap::new(),
+            clock: ClockHandle::default(),
+            persist_roots: false,
+            id_version: NodeIdVersion::LATEST,
+            min_id_version: NodeIdVersion::LATEST,
+            #[cfg(feature = "signatures")]
+            signature_policy: SignaturePolicy::default(),
             _phantom_node: PhantomData,
+            _phantom_payload: PhantomData,
+        }
+    }
+
+    /// Construct a new DAG that computes the ids of the [nodes](Node) it adds with
+    /// `id_version` and accepts no older nodes. Nodes already in the [Store] keep the version
+    /// they were added with.
+    pub fn new_with_id_version(s: S, id_version: NodeIdVersion) -> Self {
+        let mut dag = Self::new(s);
+        dag.id_version = id_version;
+        dag.min_id_version = id_version;
+        dag
+    }
+
+    /// Construct a new DAG failing with [StoreError::HashAlgorithmMismatch] if the [Store]
+    /// holds nodes hashed with another [HashWriter].
+    pub fn try_new(s: S) -> Result<Self> {
+        s.check_hash_algorithm()?;
+        Ok(Self::new(s))
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Use the DAG with the payload type `Q`. The [nodes](Node) and settings are kept as they
+    /// are. Construct a DAG with a payload type like `Merkle::new(store).with_payload_type()`.
+    pub fn with_payload_type<Q>(self) -> Merkle<S, HW, Q> {
+        Merkle {
+            roots: self.roots,
+            sticky_roots: self.sticky_roots,
+            pins: self.pins,
+            root_policy: self.root_policy,
+            nodes: self.nodes,
+            #[cfg(feature = "debug-invariants")]
+            invariant_sample: self.invariant_sample,
+            #[cfg(feature = "cbor")]
+            stored_bytes: self.stored_bytes,
+            meter: self.meter,
+            tag_markers: self.tag_markers,
+            clock: self.clock,
+            persist_roots: self.persist_roots,
+            id_version: self.id_version,
+            min_id_version: self.min_id_version,
+            #[cfg(feature = "signatures")]
+            signature_policy: self.signature_policy,
+            _phantom_node: PhantomData,
+            _phantom_payload: PhantomData,
         }
     }
 
@@ -77,62 +242,299 @@ where
     ///
     /// One result of not constructing and then adding [nodes](Node) is that we ensure that we always
     /// satisfy the implementation rule in the merkel-crdt's whitepaper.
-    pub fn add_node<'a, N: Into<Vec<u8>>>(
-        &'a mut self,
+    pub fn add_node<N: Into<Vec<u8>>>(
+        &mut self,
+        item: N,
+        dependency_ids: BTreeSet<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        self.add_node_with_attrs(item, dependency_ids, BTreeMap::new())
+    }
+
+    /// Add a new node like [Merkle::add_node] with metadata attributes. The attributes are part
+    /// of the id so the same payload and dependencies with other attributes is another node.
+    /// Read them back with [Node::attributes].
+    pub fn add_node_with_attrs<N: Into<Vec<u8>>>(
+        &mut self,
         item: N,
         dependency_ids: BTreeSet<Vec<u8>>,
+        attributes: BTreeMap<String, Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let node = Node::<HW>::new_with_attrs_and_id_version(
+            item,
+            dependency_ids,
+            attributes,
+            self.id_version,
+        );
+        self.add_node_with(node, |nodes, node, roots| match roots {
+            Some(roots) => nodes.store_with_roots(node, roots),
+            None => nodes.store(node),
+        })
+    }
+
+    /// Add a new node like [Merkle::add_node] taking and returning [NodeIds](NodeId).
+    pub fn add<N: Into<Vec<u8>>>(
+        &mut self,
+        item: N,
+        dependency_ids: BTreeSet<NodeId>,
+    ) -> Result<NodeId> {
+        let dependency_ids = dependency_ids.into_iter().map(NodeId::into_vec).collect();
+        self.add_node(item, dependency_ids).map(NodeId::from)
+    }
+
+    /// Add a new node whose item is the canonical cbor encoding of `item`. Equal values get the
+    /// same id. Read the value back with [Node::item_as]. Requires the `cbor` feature.
+    #[cfg(feature = "cbor")]
+    pub fn add_item<T: Serialize + ?Sized>(
+        &mut self,
+        item: &T,
+        dependency_ids: BTreeSet<Vec<u8>>,
     ) -> Result<Vec<u8>> {
-        let node = Node::<HW>::new(item.into(), dependency_ids.clone());
+        let item = codec::encode_item(item).map_err(|e| StoreError::StoreFailure(e.0))?;
+        self.add_node(item, dependency_ids)
+    }
+
+    /// Add a new node whose item is the encoding of a payload of the type of the DAG like
+    /// [Merkle::add_node]. Read it back with [Merkle::get_item_by_id].
+    pub fn add_payload(&mut self, item: &P, dependency_ids: BTreeSet<Vec<u8>>) -> Result<Vec<u8>>
+    where
+        P: ByteEncoder,
+    {
+        let item = item
+            .encode_bytes()
+            .map_err(|e| StoreError::StoreFailure(e.0))?;
+        self.add_node(item, dependency_ids)
+    }
+
+    // Validates and adds a new node using the `store` function to write it to the store.
+    // The node must have been built with the id version of the DAG.
+    // The `store` function also gets the new roots to persist if the DAG persists them. The
+    // roots are only updated once `store` has succeeded.
+    fn add_node_with<F>(&mut self, node: Node<HW>, store: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce(&mut S, Node<HW>, Option<&PersistedRoots>) -> Result<()>,
+    {
+        self.check_id_version(&node)?;
+        let dependency_ids: BTreeSet<Vec<u8>> = node.dep_set().clone().into();
+        for dep_id in dependency_ids.iter() {
+            check_id_len::<HW>(dep_id)?;
+        }
         let id = node.id().to_vec();
         if self.nodes.contains(id.as_slice())? {
             // We've already added this node so there is nothing left to do.
-            return Ok(self
-                .nodes
-                .get(id.as_slice())
-                .unwrap()
-                .unwrap()
-                .id()
-                .to_vec());
-        }
-        let mut root_removals = Vec::new();
-        for dep_id in dependency_ids.iter() {
-            if !self.nodes.contains(dep_id)? {
+            return Ok(id);
+        }
+        self.check_signature_policy(&node)?;
+        if !dependency_ids.is_empty() {
+            let dep_ids: Vec<&[u8]> = dependency_ids.iter().map(Vec::as_slice).collect();
+            if self.nodes.contains_many(&dep_ids)?.contains(&false) {
                 return Err(StoreError::NoSuchDependents);
             }
-            // If any of our dependencies is in the roots pointer list then
-            // we need to remove it below.
-            if self.roots.contains(dep_id) {
-                root_removals.push(dep_id);
+        }
+        // If any of our dependencies is in the roots pointer list then
+        // we need to remove it below.
+        let root_removals: Vec<&Vec<u8>> = dependency_ids
+            .iter()
+            .filter(|dep_id| self.roots.contains(*dep_id))
+            .collect();
+        #[cfg(feature = "cbor")]
+        let encoded_size = codec::encoded_size(&node);
+        let persisted = self.persist_roots.then(|| {
+            let mut record = self.root_record();
+            for removal in root_removals.iter() {
+                record.roots.remove(*removal);
+            }
+            record.roots.insert(id.clone());
+            #[cfg(feature = "cbor")]
+            {
+                record.stored_bytes = Some((self.stored_bytes + encoded_size) as u64);
             }
+            record
+        });
+        store(&mut self.nodes, node, persisted.as_ref())?;
+        #[cfg(feature = "cbor")]
+        {
+            self.stored_bytes += encoded_size;
         }
-        self.nodes.store(node)?;
         for removal in root_removals {
             self.roots.remove(removal);
         }
         self.roots.insert(id.to_vec());
+        #[cfg(feature = "debug-invariants")]
+        self.debug_check_add("add_node", &id, &dependency_ids)?;
         Ok(id.to_vec())
     }
 
+    // Fails with StoreError::IdVersionTooOld if the node is older than the DAG accepts.
+    pub(crate) fn check_id_version(&self, node: &Node<HW>) -> Result<()> {
+        if node.id_version() < self.min_id_version {
+            return Err(StoreError::IdVersionTooOld {
+                id: node.id().to_vec(),
+                minimum: self.min_id_version,
+                found: node.id_version(),
+            });
+        }
+        Ok(())
+    }
+
+    // Fails with StoreError::InvalidSignature if the signature policy rejects the node.
+    #[cfg(feature = "signatures")]
+    pub(crate) fn check_signature_policy(&self, node: &Node<HW>) -> Result<()> {
+        self.signature_policy.check(node)
+    }
+
+    #[cfg(not(feature = "signatures"))]
+    pub(crate) fn check_signature_policy(&self, _node: &Node<HW>) -> Result<()> {
+        Ok(())
+    }
+
     /// Check if we already have a copy of a [Node].
     pub fn check_for_node(&self, id: &[u8]) -> Result<bool> {
-        return self.nodes.contains(id);
+        check_id_len::<HW>(id)?;
+        self.charge(WorkUnits::StoreReads(1))?;
+        self.nodes.contains(id)
+    }
+
+    /// Get the item of a [Node] from the DAG decoded as the payload type of the DAG if the
+    /// [Node] exists. Fails with [StoreError::StoreFailure] if the item doesn't decode.
+    pub fn get_item_by_id(&self, id: &[u8]) -> Result<Option<P>>
+    where
+        P: ByteDecoder,
+    {
+        match self.get_node_by_id(id)? {
+            Some(node) => P::decode_bytes(node.item())
+                .map(Some)
+                .map_err(|e| StoreError::StoreFailure(e.0)),
+            None => Ok(None),
+        }
     }
 
     /// Get a [Node] from the DAG by it's hash identifier if it exists.
     pub fn get_node_by_id(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        check_id_len::<HW>(id)?;
+        self.charge(WorkUnits::StoreReads(1))?;
         self.nodes.get(id)
     }
 
-    /// Get the set of root [Node] ids.
+    /// Get a [Node] from the DAG and [validate](Node::validate_against) it against `id`. Fails
+    /// with [StoreError::NoSuchNode] if the DAG doesn't have the node and with
+    /// [StoreError::InvalidNode] describing the damage if it doesn't validate.
+    pub fn verify_node(&self, id: &[u8]) -> Result<Node<HW>> {
+        let node = self
+            .get_node_by_id(id)?
+            .ok_or_else(|| StoreError::NoSuchNode(id.to_vec()))?;
+        node.validate_against(id)?;
+        Ok(node)
+    }
+
+    /// Get a [Node] from the DAG by its [NodeId] like [Merkle::get_node_by_id].
+    pub fn get(&self, id: &NodeId) -> Result<Option<Node<HW>>> {
+        self.get_node_by_id(id)
+    }
+
+    /// Get the [nodes](Node) with these ids from the DAG in the same order with `None` for
+    /// the ids it doesn't have. The [Store] fetches them with a single [Store::get_many].
+    pub fn get_nodes_by_ids(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        for id in ids.iter() {
+            check_id_len::<HW>(id)?;
+        }
+        self.charge(WorkUnits::StoreReads(ids.len()))?;
+        self.nodes.get_many(ids)
+    }
+
+    /// Get the at rest [codec] encoding of a [Node] by it's hash identifier if it exists. The
+    /// bytes can be sent to peers as is since decoding hashes the item of every node again.
+    /// The record of a [detached](Node::is_detached) node has no item to hash so
+    /// [Merkle::add_nodes] refuses it and its item has to be sent along to check it with
+    /// [Node::validate_item]. Requires the `cbor` feature.
+    #[cfg(feature = "cbor")]
+    pub fn get_encoded_node(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.charge(WorkUnits::StoreReads(1))?;
+        self.nodes.get_raw(id)
+    }
+
+    /// Get the set of root [Node] ids. These are the [frontier roots](Merkle::frontier_roots)
+    /// regardless of the [RootPolicy].
     pub fn get_roots(&self) -> &BTreeSet<Vec<u8>> {
         &self.roots
     }
 
+    /// Get the set of root [Node] ids like [Merkle::get_roots] as [NodeIds](NodeId).
+    pub fn root_ids(&self) -> BTreeSet<NodeId> {
+        self.roots
+            .iter()
+            .map(|id| NodeId::from(id.as_slice()))
+            .collect()
+    }
+
+    /// Count the [nodes](Node) in the DAG using [Store::len].
+    pub fn node_count(&self) -> Result<usize> {
+        self.charge(WorkUnits::StoreReads(1))?;
+        self.nodes.len()
+    }
+
     /// Get the map of all [nodes](Node) in the DAG.
     pub fn get_nodes(&self) -> &S {
         &self.nodes
     }
 
+    /// The [NodeIdVersion] the ids of added [nodes](Node) are computed with. Defaults to
+    /// [NodeIdVersion::LATEST].
+    pub fn id_version(&self) -> NodeIdVersion {
+        self.id_version
+    }
+
+    /// Set the [NodeIdVersion] the ids of [nodes](Node) added from now on are computed with.
+    /// The [minimum](Merkle::min_id_version) is lowered to it if it is older.
+    pub fn set_id_version(&mut self, id_version: NodeIdVersion) {
+        self.id_version = id_version;
+        self.min_id_version = self.min_id_version.min(id_version);
+    }
+
+    /// The oldest [NodeIdVersion] of the [nodes](Node) the DAG adds. Older nodes fail with
+    /// [StoreError::IdVersionTooOld]. Defaults to [NodeIdVersion::LATEST]. Nodes already in
+    /// the [Store] are read whatever their version.
+    pub fn min_id_version(&self) -> NodeIdVersion {
+        self.min_id_version
+    }
+
+    /// Set the oldest [NodeIdVersion] of the [nodes](Node) the DAG adds. Lower it to
+    /// [NodeIdVersion::V0] to accept nodes from a DAG written before [NodeIdVersion::V1].
+    pub fn set_min_id_version(&mut self, min_id_version: NodeIdVersion) {
+        self.min_id_version = min_id_version;
+    }
+
+    /// Set the [Clock] read by the time dependent features of the DAG. Defaults to the
+    /// [SystemClock](crate::clock::SystemClock).
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = ClockHandle(clock);
+    }
+
+    /// An estimate of the bytes the [nodes](Node) of this DAG occupy at rest using the cbor
+    /// encoding of the serializing [Store] backends. DAGs opened with [Merkle::load] or
+    /// [Merkle::from_store] count the nodes already stored, others only the nodes added through
+    /// them. Requires the `cbor` feature.
+    #[cfg(feature = "cbor")]
+    pub fn estimated_storage_bytes(&self) -> usize {
+        self.stored_bytes
+    }
+
+    /// What the [Store] holds. See [Store::stats]. Requires the `cbor` feature.
+    #[cfg(feature = "cbor")]
+    pub fn store_stats(&self) -> Result<StoreStats> {
+        self.nodes.stats()
+    }
+
+    /// Makes every node added so far durable in the [Store]. See [Store::flush].
+    pub fn flush(&mut self) -> Result<()> {
+        self.nodes.flush()
+    }
+
+    // Mutable access to the store for store specific operations that don't change the
+    // nodes in the DAG.
+    pub(crate) fn nodes_mut(&mut self) -> &mut S {
+        &mut self.nodes
+    }
+
     /// Compare two [nodes](Node) by id in the graph. If the left id is an ancestor of the right node
     /// then returns [NodeCompare::Before]. If the right id is an ancestor of the left node
     /// then returns [NodeCompare::After]. If both id's are equal then the returns
@@ -158,7 +560,7 @@ where
     pub fn missing<'dag, 'iter>(
         &'dag self,
         search_nodes: BTreeSet<Vec<u8>>,
-    ) -> Missing<'iter, S, HW>
+    ) -> Missing<'iter, S, HW, P>
     where
         'dag: 'iter,
     {
@@ -170,13 +572,29 @@ where
         &self,
         search_nodes: &BTreeSet<Vec<u8>>,
     ) -> Result<Vec<Node<HW>>> {
+        let ids = self.find_next_non_descendant_ids(search_nodes)?;
+        let ids: Vec<&[u8]> = ids.iter().map(Vec::as_slice).collect();
+        Ok(self
+            .get_nodes_by_ids(&ids)?
+            .into_iter()
+            .map(Option::unwrap)
+            .collect())
+    }
+
+    fn find_next_non_descendant_ids(
+        &self,
+        search_nodes: &BTreeSet<Vec<u8>>,
+    ) -> Result<BTreeSet<Vec<u8>>> {
         let mut stack: Vec<Vec<u8>> = self.roots.iter().cloned().collect();
         let mut ids = BTreeSet::new();
-        while !stack.is_empty() {
-            let node_id = stack.pop().unwrap();
-            let node = self.get_node_by_id(node_id.as_slice())?.unwrap();
+        let mut visited = 0;
+        while let Some(node_id) = stack.pop() {
+            self.charge_visit(&mut visited)?;
+            let node = self
+                .get_handle_by_id(node_id.as_slice())?
+                .ok_or_else(|| StoreError::NoSuchNode(node_id.clone()))?;
             let deps = node.dependency_ids();
-            if deps.len() == 0 {
+            if deps.is_empty() {
                 // This is a leaf node which means it's the beginning of a sub graph
                 // the search_nodes_are not part of.
                 ids.insert(node.id().to_owned());
@@ -191,38 +609,164 @@ where
                 stack.push(dep.to_owned())
             }
         }
-        let mut result = Vec::new();
-        for id in ids {
-            result.push(self.get_node_by_id(id.as_slice())?.unwrap());
+        Ok(ids)
+    }
+
+    /// Get the set of ids reachable from the `from` id in the given [Direction] not counting
+    /// `from` itself.
+    pub fn reachable(&self, from: &[u8], direction: Direction) -> Result<BTreeSet<Vec<u8>>> {
+        let mut ids = BTreeSet::new();
+        self.walk(from, direction, |id, _, _| {
+            ids.insert(id.to_vec());
+            true
+        })?;
+        Ok(ids)
+    }
+
+    /// Get the set of ancestor ids for the `id`.
+    pub fn ancestors_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.reachable(id, Direction::Up)
+    }
+
+    /// Get the set of descendant ids for the `id`. Requires a [Store] that supports
+    /// [Store::children_of].
+    pub fn descendants_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.reachable(id, Direction::Down)
+    }
+
+    /// Compute the [ClosureStats] for the `id` in the given [Direction].
+    pub fn closure_stats_directed(&self, id: &[u8], direction: Direction) -> Result<ClosureStats> {
+        let mut stats = ClosureStats::default();
+        self.walk(id, direction, |_, _, depth| {
+            stats.size += 1;
+            stats.depth = stats.depth.max(depth);
+            true
+        })?;
+        Ok(stats)
+    }
+
+    /// Compute the [ClosureStats] for the ancestors of the `id`.
+    pub fn closure_stats(&self, id: &[u8]) -> Result<ClosureStats> {
+        self.closure_stats_directed(id, Direction::Up)
+    }
+
+    /// Find a shortest path of ids starting at `from` and ending at `to` expanding in the given
+    /// [Direction]. Returns None if `to` is not reachable from `from`.
+    pub fn path_between_directed(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        direction: Direction,
+    ) -> Result<Option<Vec<Vec<u8>>>> {
+        if from == to {
+            return Ok(if self.check_for_node(from)? {
+                Some(vec![from.to_vec()])
+            } else {
+                None
+            });
+        }
+        let mut parents: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+        let mut found = false;
+        self.walk(from, direction, |id, parent, _| {
+            parents.insert(id.to_vec(), parent.to_vec());
+            found = id == to;
+            !found
+        })?;
+        if !found {
+            return Ok(None);
+        }
+        let mut path = vec![to.to_vec()];
+        while let Some(parent) = parents.get(path.last().unwrap()) {
+            path.push(parent.clone());
         }
-        Ok(result)
+        path.reverse();
+        Ok(Some(path))
+    }
+
+    /// Find a shortest path of ids from `from` up to its ancestor `to`.
+    pub fn path_between(&self, from: &[u8], to: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+        self.path_between_directed(from, to, Direction::Up)
     }
 
     fn search_graph(&self, root_id: &[u8], search_id: &[u8]) -> Result<bool> {
         if root_id == search_id {
             return Ok(true);
         }
-        let root_node = match self.get_node_by_id(root_id)? {
-            Some(n) => n,
-            None => {
-                return Ok(false);
+        let mut found = false;
+        self.walk(root_id, Direction::Up, |id, _, _| {
+            found = id == search_id;
+            !found
+        })?;
+        Ok(found)
+    }
+
+    fn expand(&self, id: &[u8], direction: Direction) -> Result<DepSet> {
+        match direction {
+            Direction::Up => match self.get_handle_by_id(id)? {
+                Some(n) => Ok(n.dependency_ids().clone()),
+                None => Err(StoreError::NoSuchNode(id.to_vec())),
+            },
+            Direction::Down => {
+                self.charge(WorkUnits::StoreReads(1))?;
+                Ok(self.nodes.children_of(id)?.into())
             }
-        };
-        let mut stack = vec![root_node];
-        while !stack.is_empty() {
-            let node = stack.pop().unwrap();
-            let deps = node.dependency_ids();
-            for dep in deps {
-                if search_id == dep {
-                    return Ok(true);
+        }
+    }
+
+    // Breadth first walk from the `from` id in the given direction. The `visit` callback
+    // receives each newly discovered id along with the id it was discovered from and its depth.
+    // Returning false from `visit` stops the walk.
+    fn walk<F>(&self, from: &[u8], direction: Direction, mut visit: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8], usize) -> bool,
+    {
+        if !self.check_for_node(from)? {
+            return Ok(());
+        }
+        let mut seen = BTreeSet::from([from.to_vec()]);
+        let mut queue = VecDeque::from([(from.to_vec(), 0)]);
+        let mut visited = 0;
+        while let Some((id, depth)) = queue.pop_front() {
+            self.charge_visit(&mut visited)?;
+            for next in self.expand(&id, direction)? {
+                if seen.contains(&next) {
+                    continue;
                 }
-                stack.push(match self.get_node_by_id(dep)? {
-                    Some(n) => n,
-                    None => panic!("Invalid DAG STATE encountered"),
-                })
+                if !visit(&next, &id, depth + 1) {
+                    return Ok(());
+                }
+                seen.insert(next.clone());
+                queue.push_back((next, depth + 1));
             }
         }
-        return Ok(false);
+        Ok(())
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: TransactionalStore<HW>,
+{
+    /// Add a new payload with a required set of dependency_ids like [Merkle::add_node] while
+    /// running the `side_effect` in the same store transaction as the node insert. If the
+    /// `side_effect` fails then neither the node nor the `side_effect` writes are persisted and
+    /// the roots are left unchanged. The `side_effect` is not run if the node already exists.
+    /// A DAG that [persists its roots](Merkle::load) writes them in the same transaction.
+    pub fn add_node_with_side_effect<N, F>(
+        &mut self,
+        item: N,
+        dependency_ids: BTreeSet<Vec<u8>>,
+        side_effect: F,
+    ) -> Result<Vec<u8>>
+    where
+        N: Into<Vec<u8>>,
+        F: FnOnce(&S::Transaction<'_>) -> std::result::Result<(), S::Error>,
+    {
+        let node = Node::<HW>::new_with_id_version(item, dependency_ids, self.id_version);
+        self.add_node_with(node, |nodes, node, roots| {
+            nodes.store_with(node, roots, side_effect)
+        })
     }
 }
 
@@ -234,8 +778,24 @@ where
     fn default() -> Self {
         Self {
             roots: BTreeSet::new(),
+            sticky_roots: BTreeSet::new(),
+            pins: BTreeSet::new(),
+            root_policy: RootPolicyHandle::default(),
             nodes: S::default(),
+            #[cfg(feature = "debug-invariants")]
+            invariant_sample: Default::default(),
+            #[cfg(feature = "cbor")]
+            stored_bytes: 0,
+            meter: None,
+            tag_markers: BTreeMap::new(),
+            clock: ClockHandle::default(),
+            persist_roots: false,
+            id_version: NodeIdVersion::LATEST,
+            min_id_version: NodeIdVersion::LATEST,
+            #[cfg(feature = "signatures")]
+            signature_policy: SignaturePolicy::default(),
             _phantom_node: Default::default(),
+            _phantom_payload: PhantomData,
         }
     }
 }
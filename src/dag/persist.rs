@@ -0,0 +1,183 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeSet;
+
+use super::Merkle;
+use crate::hash::HashWriter;
+#[cfg(feature = "cbor")]
+use crate::store::{codec, StoreError};
+use crate::store::{PersistedRoots, Result, Store};
+
+// The number of nodes fetched per Store::get_many call while reconstructing the roots.
+const RECONSTRUCT_BATCH: usize = 500;
+
+// What reading every node in a store found.
+#[derive(Default)]
+struct StoreScan {
+    ids: BTreeSet<Vec<u8>>,
+    referenced: BTreeSet<Vec<u8>>,
+    #[cfg(feature = "cbor")]
+    bytes: usize,
+    visited: usize,
+}
+
+impl<S, HW> Merkle<S, HW>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Open a DAG over a [Store] that persists its roots restoring the roots, sticky ids and
+    /// pins written by an earlier DAG. From then on every change to the roots is written to the
+    /// [Store]. Adding a node writes the new roots in the same atomic write as the node.
+    ///
+    /// A [Store] that holds nodes but no roots, for example one written before roots were
+    /// persisted, gets its roots [reconstructed](Merkle::reconstruct_roots). Fails with
+    /// [StoreError::Unsupported](crate::store::StoreError::Unsupported) if the [Store] can't
+    /// persist roots and with
+    /// [StoreError::HashAlgorithmMismatch](crate::store::StoreError::HashAlgorithmMismatch) if
+    /// it holds nodes hashed with another [HashWriter]. With the `cbor` feature
+    /// [Merkle::estimated_storage_bytes] is restored from the roots as well. Only roots
+    /// written before it was persisted make every stored node be read to seed it.
+    pub fn load(store: S) -> Result<Self> {
+        store.check_hash_algorithm()?;
+        let persisted = store.persisted_roots()?;
+        let mut dag = Self::new(store);
+        dag.persist_roots = true;
+        match persisted {
+            Some(persisted) => {
+                dag.roots = persisted.roots;
+                dag.sticky_roots = persisted.sticky;
+                dag.pins = persisted.pins;
+                #[cfg(feature = "cbor")]
+                match persisted.stored_bytes {
+                    Some(bytes) => dag.stored_bytes = bytes as usize,
+                    None => dag.count_stored_bytes()?,
+                }
+            }
+            None => dag.reconstruct_roots()?,
+        }
+        Ok(dag)
+    }
+
+    /// Construct a DAG over a [Store] that already holds [nodes](crate::node::Node), for
+    /// example one copied from another replica, [reconstructing](Merkle::reconstruct_roots)
+    /// its roots. Fails with
+    /// [StoreError::HashAlgorithmMismatch](crate::store::StoreError::HashAlgorithmMismatch) if
+    /// the [Store] holds nodes hashed with another [HashWriter].
+    pub fn from_store(store: S) -> Result<Self> {
+        let mut dag = Self::try_new(store)?;
+        dag.reconstruct_roots()?;
+        Ok(dag)
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Replace the roots with the ids in the [Store] no stored node depends on. Reads every
+    /// node in the [Store] and requires a [Store] that supports [Store::ids]. Sticky ids and
+    /// pins that are no longer stored are dropped.
+    pub fn reconstruct_roots(&mut self) -> Result<()> {
+        let scan = self.scan_store()?;
+        self.roots = scan.ids.difference(&scan.referenced).cloned().collect();
+        self.sticky_roots.retain(|id| scan.ids.contains(id));
+        self.pins.retain(|id| scan.ids.contains(id));
+        #[cfg(feature = "cbor")]
+        {
+            self.stored_bytes = scan.bytes;
+        }
+        #[cfg(feature = "debug-invariants")]
+        self.debug_check_sampled("reconstruct_roots")?;
+        self.write_roots()
+    }
+
+    // Seeds the estimate of the stored bytes with the nodes already in the store. Stores that
+    // can't list their ids leave it at zero.
+    #[cfg(feature = "cbor")]
+    fn count_stored_bytes(&mut self) -> Result<()> {
+        match self.scan_store() {
+            Ok(scan) => self.stored_bytes = scan.bytes,
+            Err(StoreError::Unsupported(_)) => {}
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+
+    // Reads every node in the store.
+    fn scan_store(&self) -> Result<StoreScan> {
+        let mut scan = StoreScan::default();
+        let mut batch = Vec::with_capacity(RECONSTRUCT_BATCH);
+        for id in self.nodes.ids()? {
+            batch.push(id?);
+            if batch.len() == RECONSTRUCT_BATCH {
+                self.collect_references(&mut batch, &mut scan)?;
+            }
+        }
+        self.collect_references(&mut batch, &mut scan)?;
+        Ok(scan)
+    }
+
+    // Reads the nodes of the batch recording their ids, dependencies and sizes.
+    fn collect_references(&self, batch: &mut Vec<Vec<u8>>, scan: &mut StoreScan) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let nodes = {
+            let batch: Vec<&[u8]> = batch.iter().map(Vec::as_slice).collect();
+            self.get_nodes_by_ids(&batch)?
+        };
+        for (id, node) in batch.drain(..).zip(nodes) {
+            self.charge_visit(&mut scan.visited)?;
+            // Ids removed since the scan started don't count.
+            if let Some(node) = node {
+                #[cfg(feature = "cbor")]
+                {
+                    scan.bytes += codec::encoded_size(&node);
+                }
+                scan.referenced.extend(node.dep_set().iter().cloned());
+                scan.ids.insert(id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether changes to the roots are written to the [Store]. Only DAGs opened with
+    /// [Merkle::load] persist their roots.
+    pub fn persists_roots(&self) -> bool {
+        self.persist_roots
+    }
+
+    pub(crate) fn root_record(&self) -> PersistedRoots {
+        PersistedRoots {
+            roots: self.roots.clone(),
+            sticky: self.sticky_roots.clone(),
+            pins: self.pins.clone(),
+            #[cfg(feature = "cbor")]
+            stored_bytes: Some(self.stored_bytes as u64),
+            #[cfg(not(feature = "cbor"))]
+            stored_bytes: None,
+        }
+    }
+
+    // Writes the current roots to the store if the DAG persists them.
+    pub(crate) fn write_roots(&mut self) -> Result<()> {
+        if !self.persist_roots {
+            return Ok(());
+        }
+        let record = self.root_record();
+        self.nodes.persist_roots(&record)
+    }
+}
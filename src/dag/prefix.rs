@@ -0,0 +1,130 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::{Merkle, WorkUnits};
+use crate::hash::HashWriter;
+use crate::store::{Result, Store, StoreError};
+
+/// The result of resolving an abbreviated hex id with [Merkle::resolve_id_prefix].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum PrefixResolution {
+    /// Exactly one id matches the prefix.
+    Unique(Vec<u8>),
+    /// More than one id matches the prefix. At most [PrefixConfig::ambiguity_cap] candidates are reported.
+    Ambiguous(Vec<Vec<u8>>),
+    /// No id matches the prefix.
+    NotFound,
+}
+
+/// Limits applied when resolving an abbreviated hex id.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PrefixConfig {
+    /// The minimum number of hex digits a prefix must have.
+    pub min_hex_len: usize,
+    /// The maximum number of candidates reported for an ambiguous prefix.
+    pub ambiguity_cap: usize,
+}
+
+impl Default for PrefixConfig {
+    fn default() -> Self {
+        Self {
+            min_hex_len: 4,
+            ambiguity_cap: 8,
+        }
+    }
+}
+
+fn parse_nibbles(hex_prefix: &str) -> Result<Vec<u8>> {
+    hex_prefix
+        .chars()
+        .map(|c| {
+            c.to_digit(16)
+                .map(|d| d as u8)
+                .ok_or_else(|| StoreError::InvalidIdPrefix(format!("'{}' is not a hex digit", c)))
+        })
+        .collect()
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Resolve an abbreviated id to the one id in the DAG that starts with the `prefix` bytes.
+    /// Returns `None` if no id does and fails with [StoreError::AmbiguousPrefix] if several
+    /// do. Requires a [Store] that supports [Store::find_by_prefix].
+    pub fn resolve_prefix(&self, prefix: &[u8]) -> Result<Option<Vec<u8>>> {
+        let cap = PrefixConfig::default().ambiguity_cap;
+        self.charge(WorkUnits::StoreReads(1))?;
+        let mut ids = self.nodes.find_by_prefix(prefix, cap)?;
+        if ids.len() > 1 {
+            return Err(StoreError::AmbiguousPrefix {
+                prefix: prefix.to_vec(),
+                candidates: ids,
+            });
+        }
+        Ok(ids.pop())
+    }
+
+    /// Resolve an abbreviated hex id using the default [PrefixConfig].
+    pub fn resolve_id_prefix(&self, hex_prefix: &str) -> Result<PrefixResolution> {
+        self.resolve_id_prefix_with(hex_prefix, &PrefixConfig::default())
+    }
+
+    /// Resolve an abbreviated hex id to the ids in the DAG that start with it. Prefixes with
+    /// an odd number of digits match on the high nibble of the final byte.
+    pub fn resolve_id_prefix_with(
+        &self,
+        hex_prefix: &str,
+        config: &PrefixConfig,
+    ) -> Result<PrefixResolution> {
+        if hex_prefix.len() < config.min_hex_len {
+            return Err(StoreError::InvalidIdPrefix(format!(
+                "prefix of {} hex digits is shorter than the minimum of {}",
+                hex_prefix.len(),
+                config.min_hex_len
+            )));
+        }
+        let nibbles = parse_nibbles(hex_prefix)?;
+        let bytes: Vec<u8> = nibbles
+            .chunks_exact(2)
+            .map(|pair| (pair[0] << 4) | pair[1])
+            .collect();
+        let limit = config.ambiguity_cap.max(2);
+        let mut ids = Vec::new();
+        if nibbles.len() % 2 == 0 {
+            self.charge(WorkUnits::StoreReads(1))?;
+            ids = self.nodes.find_by_prefix(&bytes, limit)?;
+        } else {
+            // An odd prefix covers the sixteen byte prefixes sharing its high nibble.
+            let high = nibbles[nibbles.len() - 1] << 4;
+            for low in 0..16 {
+                let mut prefix = bytes.clone();
+                prefix.push(high | low);
+                self.charge(WorkUnits::StoreReads(1))?;
+                ids.extend(self.nodes.find_by_prefix(&prefix, limit - ids.len())?);
+                if ids.len() >= limit {
+                    break;
+                }
+            }
+        }
+        Ok(match ids.len() {
+            0 => PrefixResolution::NotFound,
+            1 => PrefixResolution::Unique(ids.pop().unwrap()),
+            _ => {
+                ids.truncate(config.ambiguity_cap);
+                PrefixResolution::Ambiguous(ids)
+            }
+        })
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::store::{Result, Store};
+
+/// How often [Merkle::wait_until_satisfied] checks the [Store] again.
+pub const READ_TOKEN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The causal state of a [Merkle DAG](Merkle) a client has observed. Its size is bounded by
+/// the number of roots.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct ReadToken {
+    /// A hash of the roots used to recognize an unchanged DAG without reading the [Store].
+    pub digest: Vec<u8>,
+    /// The roots of the DAG when the token was taken.
+    pub roots: BTreeSet<Vec<u8>>,
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Capture the current causal state of this DAG. A replica that
+    /// [satisfies](Merkle::satisfies) the token has applied everything it implies.
+    pub fn read_token(&self) -> ReadToken {
+        ReadToken {
+            digest: self.roots_digest(),
+            roots: self.roots.clone(),
+        }
+    }
+
+    /// Whether this replica has applied at least everything the `token` implies. Every root
+    /// of the token must be in the [Store]. Since a [Node] is only stored after its
+    /// dependencies this takes one read per root of the token. Tokens from an unrelated DAG
+    /// are never satisfied.
+    pub fn satisfies(&self, token: &ReadToken) -> Result<bool> {
+        if token.digest == self.roots_digest() {
+            return Ok(true);
+        }
+        for root in token.roots.iter() {
+            if !self.check_for_node(root)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Wait for the [Store] to catch up with the `token` checking again every
+    /// [READ_TOKEN_POLL_INTERVAL] of the DAG's [Clock](crate::clock::Clock). Returns false if
+    /// the `timeout` passes first.
+    pub fn wait_until_satisfied(&self, token: &ReadToken, timeout: Duration) -> Result<bool> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            if self.satisfies(token)? {
+                return Ok(true);
+            }
+            let now = self.clock.now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            self.clock
+                .0
+                .sleep_until(deadline.min(now + READ_TOKEN_POLL_INTERVAL));
+        }
+    }
+
+    fn roots_digest(&self) -> Vec<u8> {
+        let mut hw = HW::default();
+        for root in self.roots.iter() {
+            hw.record_bytes(root);
+        }
+        hw.hash()
+    }
+}
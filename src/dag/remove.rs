@@ -0,0 +1,148 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::store::{Result, Store, StoreError};
+
+#[cfg(feature = "cbor")]
+use crate::store::codec;
+
+/// What [Merkle::remove_node] removes besides the node itself.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RemoveScope {
+    /// Only remove the node. Fails if other nodes depend on it.
+    Node,
+    /// Remove the node and every node that depends on it directly or transitively.
+    WithDescendants,
+}
+
+enum Step {
+    Visit(Vec<u8>),
+    Finish(Vec<u8>, BTreeSet<Vec<u8>>),
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Remove the [Node](crate::node::Node) with the `id` from the DAG returning the removed
+    /// ids. Fails with [StoreError::NoSuchNode] if there is no such node and with
+    /// [StoreError::HasDescendants] if other nodes depend on it and the `scope` is
+    /// [RemoveScope::Node]. Dependencies of removed nodes become roots again once nothing
+    /// else depends on them. If a delete fails the nodes deleted so far stay removed and the
+    /// roots they leave behind are persisted before the error is returned. Requires a [Store]
+    /// that supports [Store::delete].
+    ///
+    /// Finding the descendants and the new roots walks the whole DAG from its roots.
+    pub fn remove_node(&mut self, id: &[u8], scope: RemoveScope) -> Result<BTreeSet<Vec<u8>>> {
+        if !self.check_for_node(id)? {
+            return Err(StoreError::NoSuchNode(id.to_vec()));
+        }
+        if scope == RemoveScope::Node && !self.roots.contains(id) {
+            return Err(StoreError::HasDescendants(id.to_vec()));
+        }
+        // Depth first from the roots. A node is finished after all of its dependencies so
+        // whether it depends on the `id` is known when it is finished.
+        let mut depends_on_id = BTreeMap::new();
+        let mut dependents = BTreeMap::<Vec<u8>, usize>::new();
+        let mut doomed = Vec::new();
+        let mut stack: Vec<Step> = self.roots.iter().cloned().map(Step::Visit).collect();
+        let mut visited = 0;
+        while let Some(step) = stack.pop() {
+            match step {
+                Step::Visit(node_id) => {
+                    if depends_on_id.contains_key(&node_id) {
+                        continue;
+                    }
+                    self.charge_visit(&mut visited)?;
+                    let deps = match self.get_handle_by_id(&node_id)? {
+                        Some(handle) => handle.dependency_ids().clone(),
+                        None => return Err(StoreError::NoSuchNode(node_id.clone())),
+                    };
+                    let deps: BTreeSet<Vec<u8>> = deps.into_iter().collect();
+                    let visits: Vec<Step> = deps.iter().cloned().map(Step::Visit).collect();
+                    stack.push(Step::Finish(node_id, deps));
+                    stack.extend(visits);
+                }
+                Step::Finish(node_id, deps) => {
+                    let mut doomed_node = node_id == id;
+                    for dep in deps {
+                        doomed_node |= depends_on_id[&dep];
+                        *dependents.entry(dep).or_default() += 1;
+                    }
+                    if doomed_node {
+                        doomed.push(node_id.clone());
+                    }
+                    depends_on_id.insert(node_id, doomed_node);
+                }
+            }
+        }
+        let removed: BTreeSet<Vec<u8>> = doomed.iter().cloned().collect();
+        let mut candidates = BTreeSet::new();
+        let mut deleted = BTreeSet::new();
+        let mut failure = None;
+        // Nodes were finished after their dependencies so in reverse dependents are deleted
+        // first and the store never holds a dangling dependency if a delete fails part way.
+        for doomed_id in doomed.iter().rev() {
+            let deleted_node = self.get_node_by_id(doomed_id).and_then(|node| {
+                let node = match node {
+                    Some(node) => node,
+                    None => return Err(StoreError::NoSuchNode(doomed_id.clone())),
+                };
+                self.nodes.delete(doomed_id)?;
+                Ok(node)
+            });
+            let node = match deleted_node {
+                Ok(node) => node,
+                Err(err) => {
+                    failure = Some(err);
+                    break;
+                }
+            };
+            #[cfg(feature = "cbor")]
+            {
+                self.stored_bytes = self.stored_bytes.saturating_sub(codec::encoded_size(&node));
+            }
+            for dep in node.dep_set() {
+                if let Some(count) = dependents.get_mut(dep) {
+                    *count -= 1;
+                }
+                candidates.insert(dep.clone());
+            }
+            deleted.insert(doomed_id.clone());
+            self.roots.remove(doomed_id);
+            self.sticky_roots.remove(doomed_id);
+            self.pins.remove(doomed_id);
+            self.tag_markers.remove(doomed_id);
+        }
+        // A doomed node is a candidate too if the delete failed before reaching it.
+        for candidate in candidates.difference(&deleted) {
+            if dependents.get(candidate).copied().unwrap_or(0) == 0 {
+                self.roots.insert(candidate.clone());
+            }
+        }
+        if let Some(err) = failure {
+            // The failed delete is the error worth reporting.
+            let _ = self.write_roots();
+            return Err(err);
+        }
+        self.write_roots()?;
+        #[cfg(feature = "debug-invariants")]
+        self.debug_check_sampled("remove_node")?;
+        Ok(removed)
+    }
+}
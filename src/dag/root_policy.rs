@@ -0,0 +1,132 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+
+use super::{Merkle, WorkUnits};
+use crate::hash::HashWriter;
+use crate::store::{Result, Store, StoreError};
+
+/// Decides which roots a [Merkle DAG](Merkle) announces to its peers.
+///
+/// The DAG always maintains the strict frontier, the [nodes](crate::node::Node) nothing
+/// depends on, and every algorithm that walks the DAG uses it. A policy only changes the
+/// [announced roots](Merkle::announced_roots).
+pub trait RootPolicy: Send + Sync {
+    /// Compute the announced roots from the strict `frontier` and the ids marked with
+    /// [Merkle::mark_sticky_root].
+    fn announced_roots(
+        &self,
+        frontier: &BTreeSet<Vec<u8>>,
+        sticky: &BTreeSet<Vec<u8>>,
+    ) -> BTreeSet<Vec<u8>>;
+}
+
+/// The default [RootPolicy]. Announces the strict frontier and ignores sticky ids.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct StrictFrontier;
+
+impl RootPolicy for StrictFrontier {
+    fn announced_roots(
+        &self,
+        frontier: &BTreeSet<Vec<u8>>,
+        _sticky: &BTreeSet<Vec<u8>>,
+    ) -> BTreeSet<Vec<u8>> {
+        frontier.clone()
+    }
+}
+
+/// A [RootPolicy] that keeps announcing sticky ids after other nodes depend on them.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RetainMarked;
+
+impl RootPolicy for RetainMarked {
+    fn announced_roots(
+        &self,
+        frontier: &BTreeSet<Vec<u8>>,
+        sticky: &BTreeSet<Vec<u8>>,
+    ) -> BTreeSet<Vec<u8>> {
+        frontier.union(sticky).cloned().collect()
+    }
+}
+
+// A shared handle to a RootPolicy so the DAG can stay Clone and Debug.
+#[derive(Clone)]
+pub(crate) struct RootPolicyHandle(pub(crate) Arc<dyn RootPolicy>);
+
+impl Default for RootPolicyHandle {
+    fn default() -> Self {
+        Self(Arc::new(StrictFrontier))
+    }
+}
+
+impl fmt::Debug for RootPolicyHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RootPolicy")
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Set the [RootPolicy] deciding the [announced roots](Merkle::announced_roots). Defaults
+    /// to [StrictFrontier].
+    pub fn set_root_policy(&mut self, policy: Arc<dyn RootPolicy>) {
+        self.root_policy = RootPolicyHandle(policy);
+    }
+
+    /// Get the ids of the [nodes](crate::node::Node) no other node depends on. This is the set
+    /// traversals, comparisons and sync planning start from and is the same as
+    /// [Merkle::get_roots].
+    pub fn frontier_roots(&self) -> &BTreeSet<Vec<u8>> {
+        &self.roots
+    }
+
+    /// Get the roots to announce to peers as decided by the [RootPolicy].
+    pub fn announced_roots(&self) -> BTreeSet<Vec<u8>> {
+        self.root_policy
+            .0
+            .announced_roots(&self.roots, &self.sticky_roots)
+    }
+
+    /// Get the ids marked with [Merkle::mark_sticky_root].
+    pub fn sticky_roots(&self) -> &BTreeSet<Vec<u8>> {
+        &self.sticky_roots
+    }
+
+    /// Mark the `id` as sticky so a [RetainMarked] policy keeps announcing it after other
+    /// nodes depend on it. Fails with [StoreError::NoSuchNode] if the DAG has no such node.
+    pub fn mark_sticky_root(&mut self, id: &[u8]) -> Result<()> {
+        self.charge(WorkUnits::StoreReads(1))?;
+        if !self.nodes.contains(id)? {
+            return Err(StoreError::NoSuchNode(id.to_vec()));
+        }
+        if self.sticky_roots.insert(id.to_vec()) {
+            self.write_roots()?;
+        }
+        Ok(())
+    }
+
+    /// Remove the sticky mark from the `id` returning whether it was marked.
+    pub fn unmark_sticky_root(&mut self, id: &[u8]) -> Result<bool> {
+        let marked = self.sticky_roots.remove(id);
+        if marked {
+            self.write_roots()?;
+        }
+        Ok(marked)
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeSet;
+
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::node::Node;
+use crate::signature::{SignaturePolicy, SigningKey};
+use crate::store::{Result, Store};
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Add a new node like [Merkle::add_node] signed with the `key` of its author. The
+    /// signature isn't part of the id so if the node is already in the DAG the stored copy is
+    /// kept as it is.
+    pub fn add_signed_node<N: Into<Vec<u8>>>(
+        &mut self,
+        item: N,
+        dependency_ids: BTreeSet<Vec<u8>>,
+        key: &SigningKey,
+    ) -> Result<Vec<u8>> {
+        let node = Node::<HW>::new_with_id_version(item, dependency_ids, self.id_version).sign(key);
+        self.add_node_with(node, |nodes, node, roots| match roots {
+            Some(roots) => nodes.store_with_roots(node, roots),
+            None => nodes.store(node),
+        })
+    }
+
+    /// The [SignaturePolicy] new nodes are checked with.
+    pub fn signature_policy(&self) -> &SignaturePolicy {
+        &self.signature_policy
+    }
+
+    /// Set the [SignaturePolicy] new nodes are checked with. Nodes already in the DAG aren't
+    /// checked again.
+    pub fn set_signature_policy(&mut self, policy: SignaturePolicy) {
+        self.signature_policy = policy;
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{Direction, Merkle};
+use crate::hash::HashWriter;
+use crate::node::{DepSet, Node};
+use crate::store::{Result, Store, StoreError};
+
+/// Selects the [nodes](Node) a sync may offer or accept by their effective tags.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum TagFilter {
+    /// Admit nodes carrying at least one of the tags.
+    Allow(BTreeSet<String>),
+    /// Admit nodes carrying none of the tags.
+    Deny(BTreeSet<String>),
+}
+
+impl TagFilter {
+    /// A filter admitting nodes carrying at least one of the `tags`.
+    pub fn allow<I: IntoIterator<Item = T>, T: Into<String>>(tags: I) -> Self {
+        Self::Allow(tags.into_iter().map(Into::into).collect())
+    }
+
+    /// A filter admitting nodes carrying none of the `tags`.
+    pub fn deny<I: IntoIterator<Item = T>, T: Into<String>>(tags: I) -> Self {
+        Self::Deny(tags.into_iter().map(Into::into).collect())
+    }
+
+    /// Whether a node with the effective `tags` is admitted.
+    pub fn admits(&self, tags: &BTreeSet<String>) -> bool {
+        match self {
+            Self::Allow(allowed) => !allowed.is_disjoint(tags),
+            Self::Deny(denied) => denied.is_disjoint(tags),
+        }
+    }
+
+    // The tags responsible for rejecting a node with the effective `tags`.
+    fn blocking(&self, tags: &BTreeSet<String>) -> BTreeSet<String> {
+        match self {
+            Self::Allow(_) => tags.clone(),
+            Self::Deny(denied) => denied.intersection(tags).cloned().collect(),
+        }
+    }
+}
+
+/// The outcome of [Merkle::plan_tag_filtered_sync].
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct TagFilteredSync {
+    /// The ids that may be offered with every dependency before its dependents.
+    pub offered: Vec<Vec<u8>>,
+    /// The withheld ids with the tags that blocked them. A node admitted by the filter is
+    /// still withheld with the blocking tags of its dependencies if any of them is withheld.
+    /// Untagged nodes rejected by an allow filter are blocked by no tags.
+    pub withheld: BTreeMap<Vec<u8>, BTreeSet<String>>,
+    /// The withheld ids whose dependencies were all offered. A sync stops here.
+    pub boundary: BTreeSet<Vec<u8>>,
+}
+
+impl TagFilteredSync {
+    /// Every tag that kept a node from being offered.
+    pub fn blocking_tags(&self) -> BTreeSet<String> {
+        self.withheld.values().flatten().cloned().collect()
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Tag the [Node] with the `head` id and every node built on top of it. Only a marker for
+    /// the head is recorded. The tags of the other nodes are resolved when they are needed
+    /// so nodes added later inherit the tag as well.
+    pub fn tag_subgraph(&mut self, head: &[u8], tag: &str) -> Result<()> {
+        if !self.check_for_node(head)? {
+            return Err(StoreError::NoSuchNode(head.to_vec()));
+        }
+        self.tag_markers
+            .entry(head.to_vec())
+            .or_default()
+            .insert(tag.to_owned());
+        Ok(())
+    }
+
+    /// Get the effective tags of the `id`. These are the tags of every marker on the id or
+    /// one of its ancestors.
+    pub fn tags_of(&self, id: &[u8]) -> Result<BTreeSet<String>> {
+        if !self.check_for_node(id)? {
+            return Err(StoreError::NoSuchNode(id.to_vec()));
+        }
+        let mut tags = BTreeSet::new();
+        if self.tag_markers.is_empty() {
+            return Ok(tags);
+        }
+        tags.extend(self.marker_tags(id).cloned());
+        self.walk(id, Direction::Up, |ancestor, _, _| {
+            tags.extend(self.marker_tags(ancestor).cloned());
+            true
+        })?;
+        Ok(tags)
+    }
+
+    /// Whether a sync filtered by the `filter` should accept the `node`. Its dependencies must
+    /// already be in the DAG.
+    pub fn admits_node(&self, node: &Node<HW>, filter: &TagFilter) -> Result<bool> {
+        let mut tags: BTreeSet<String> = self.marker_tags(node.id()).cloned().collect();
+        for dep in node.dep_set() {
+            tags.extend(self.tags_of(dep)?);
+        }
+        Ok(filter.admits(&tags))
+    }
+
+    /// Work out which [nodes](Node) of the DAG a sync filtered by the `filter` may offer.
+    /// A node is only offered if the filter admits it and every one of its dependencies is
+    /// offered so a peer never receives a node without its dependencies.
+    pub fn plan_tag_filtered_sync(&self, filter: &TagFilter) -> Result<TagFilteredSync> {
+        let mut deps: BTreeMap<Vec<u8>, DepSet> = BTreeMap::new();
+        let mut order = Vec::new();
+        let mut stack: Vec<(Vec<u8>, bool)> =
+            self.roots.iter().map(|id| (id.clone(), false)).collect();
+        let mut visited = 0;
+        // Post order depth first search so every dependency is ordered before its dependents.
+        while let Some((id, expanded)) = stack.pop() {
+            if expanded {
+                order.push(id);
+                continue;
+            }
+            if deps.contains_key(&id) {
+                continue;
+            }
+            self.charge_visit(&mut visited)?;
+            let handle = match self.get_handle_by_id(&id)? {
+                Some(handle) => handle,
+                None => return Err(StoreError::NoSuchNode(id.clone())),
+            };
+            stack.push((id.clone(), true));
+            for dep in handle.dependency_ids() {
+                if !deps.contains_key(dep) {
+                    stack.push((dep.clone(), false));
+                }
+            }
+            deps.insert(id, handle.dependency_ids().clone());
+        }
+        let mut plan = TagFilteredSync::default();
+        let mut tags: BTreeMap<Vec<u8>, BTreeSet<String>> = BTreeMap::new();
+        for id in order {
+            let node_deps = &deps[&id];
+            let mut node_tags: BTreeSet<String> = self.marker_tags(&id).cloned().collect();
+            let mut blocking = BTreeSet::new();
+            let mut complete = true;
+            for dep in node_deps {
+                node_tags.extend(tags[dep].iter().cloned());
+                if let Some(dep_blocking) = plan.withheld.get(dep) {
+                    complete = false;
+                    blocking.extend(dep_blocking.iter().cloned());
+                }
+            }
+            if !filter.admits(&node_tags) {
+                plan.withheld
+                    .insert(id.clone(), filter.blocking(&node_tags));
+                if complete {
+                    plan.boundary.insert(id.clone());
+                }
+            } else if !complete {
+                plan.withheld.insert(id.clone(), blocking);
+            } else {
+                plan.offered.push(id.clone());
+            }
+            tags.insert(id, node_tags);
+        }
+        Ok(plan)
+    }
+
+    fn marker_tags(&self, id: &[u8]) -> impl Iterator<Item = &String> {
+        self.tag_markers.get(id).into_iter().flatten()
+    }
+}
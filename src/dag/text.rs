@@ -0,0 +1,473 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! A compact textual description of a DAG for tests and bug reports.
+//!
+//! Each line of a spec describes one [Node] as a name, an optional parenthesized list of
+//! dependency names and a quoted payload.
+//!
+//! ```text
+//! # Comments and blank lines are ignored.
+//! a: "payload-a"
+//! b(a, c): "payload-b"
+//! c: "line\nbreak \x00"
+//! ```
+//!
+//! Names are made of ASCII letters, digits, `_`, `-` and `.`. Dependencies may be named
+//! before they are described. Payloads support the `\\`, `\"`, `\n`, `\r`, `\t`, `\0` and
+//! `\xHH` escapes.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::node::Node;
+use crate::store::{Result, Store, StoreError};
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_name_char)
+}
+
+fn spec_error(line: usize, column: usize, message: String) -> StoreError {
+    StoreError::SpecParse {
+        line,
+        column,
+        message,
+    }
+}
+
+// A single described node before its dependencies are resolved.
+struct SpecEntry {
+    name: String,
+    line: usize,
+    column: usize,
+    // The dependency names with the column they appear at.
+    deps: Vec<(String, usize)>,
+    payload: Vec<u8>,
+}
+
+// Reads the characters of a single spec line tracking the column for errors.
+struct Cursor {
+    line: usize,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Cursor {
+    fn new(line: usize, text: &str) -> Self {
+        Self {
+            line,
+            chars: text.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn column(&self) -> usize {
+        self.pos + 1
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn error(&self, message: String) -> StoreError {
+        spec_error(self.line, self.column(), message)
+    }
+
+    fn describe_next(&self) -> String {
+        match self.peek() {
+            Some(c) => format!("'{}'", c),
+            None => "end of line".to_owned(),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map(char::is_whitespace).unwrap_or(false) {
+            self.pos += 1;
+        }
+    }
+
+    // Whether only whitespace or a comment is left on the line.
+    fn at_end(&mut self) -> bool {
+        self.skip_whitespace();
+        matches!(self.peek(), None | Some('#'))
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_whitespace();
+        if self.peek() != Some(expected) {
+            return Err(self.error(format!(
+                "expected '{}' but found {}",
+                expected,
+                self.describe_next()
+            )));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<(String, usize)> {
+        self.skip_whitespace();
+        let column = self.column();
+        let start = self.pos;
+        while self.peek().map(is_name_char).unwrap_or(false) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error(format!(
+                "expected a node name but found {}",
+                self.describe_next()
+            )));
+        }
+        Ok((self.chars[start..self.pos].iter().collect(), column))
+    }
+
+    fn hex_digit(&mut self) -> Result<u8> {
+        match self.peek().and_then(|c| c.to_digit(16)) {
+            Some(digit) => {
+                self.pos += 1;
+                Ok(digit as u8)
+            }
+            None => Err(self.error(format!(
+                "expected a hex digit but found {}",
+                self.describe_next()
+            ))),
+        }
+    }
+
+    fn payload(&mut self) -> Result<Vec<u8>> {
+        self.expect('"')?;
+        let mut payload = Vec::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err(self.error("unterminated payload literal".to_owned())),
+            };
+            self.pos += 1;
+            match c {
+                '"' => return Ok(payload),
+                '\\' => {
+                    let escape = self.peek();
+                    self.pos += 1;
+                    match escape {
+                        Some('\\') => payload.push(b'\\'),
+                        Some('"') => payload.push(b'"'),
+                        Some('n') => payload.push(b'\n'),
+                        Some('r') => payload.push(b'\r'),
+                        Some('t') => payload.push(b'\t'),
+                        Some('0') => payload.push(0),
+                        Some('x') => {
+                            let high = self.hex_digit()?;
+                            let low = self.hex_digit()?;
+                            payload.push(high << 4 | low);
+                        }
+                        Some(other) => {
+                            self.pos -= 2;
+                            return Err(self.error(format!("unknown escape '\\{}'", other)));
+                        }
+                        None => {
+                            self.pos -= 2;
+                            return Err(self.error("unterminated payload literal".to_owned()));
+                        }
+                    }
+                }
+                c => {
+                    let mut buf = [0; 4];
+                    payload.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+        }
+    }
+}
+
+// Parses a single non blank line of a spec.
+fn parse_entry(cursor: &mut Cursor) -> Result<SpecEntry> {
+    let (name, column) = cursor.name()?;
+    let mut deps = Vec::new();
+    cursor.skip_whitespace();
+    if cursor.peek() == Some('(') {
+        cursor.pos += 1;
+        cursor.skip_whitespace();
+        if cursor.peek() == Some(')') {
+            cursor.pos += 1;
+        } else {
+            loop {
+                deps.push(cursor.name()?);
+                cursor.skip_whitespace();
+                match cursor.peek() {
+                    Some(',') => cursor.pos += 1,
+                    Some(')') => {
+                        cursor.pos += 1;
+                        break;
+                    }
+                    _ => {
+                        return Err(cursor.error(format!(
+                            "expected ',' or ')' but found {}",
+                            cursor.describe_next()
+                        )))
+                    }
+                }
+            }
+        }
+    }
+    cursor.expect(':')?;
+    let payload = cursor.payload()?;
+    if !cursor.at_end() {
+        return Err(cursor.error(format!(
+            "unexpected {} after the payload",
+            cursor.describe_next()
+        )));
+    }
+    Ok(SpecEntry {
+        name,
+        line: cursor.line,
+        column,
+        deps,
+        payload,
+    })
+}
+
+fn parse_spec(spec: &str) -> Result<Vec<SpecEntry>> {
+    let mut entries: Vec<SpecEntry> = Vec::new();
+    let mut lines_by_name: BTreeMap<String, usize> = BTreeMap::new();
+    for (index, text) in spec.lines().enumerate() {
+        let mut cursor = Cursor::new(index + 1, text);
+        if cursor.at_end() {
+            continue;
+        }
+        cursor.pos = 0;
+        let entry = parse_entry(&mut cursor)?;
+        if let Some(line) = lines_by_name.get(&entry.name) {
+            return Err(spec_error(
+                entry.line,
+                entry.column,
+                format!(
+                    "node '{}' is already described on line {}",
+                    entry.name, line
+                ),
+            ));
+        }
+        lines_by_name.insert(entry.name.clone(), entry.line);
+        entries.push(entry);
+    }
+    for entry in entries.iter() {
+        for (dep, column) in entry.deps.iter() {
+            if !lines_by_name.contains_key(dep) {
+                return Err(spec_error(
+                    entry.line,
+                    *column,
+                    format!("unknown dependency '{}' of node '{}'", dep, entry.name),
+                ));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Orders the entries so every dependency comes before its dependents keeping the spec order
+// where the dependencies allow it.
+fn order_entries(entries: &[SpecEntry]) -> Result<Vec<usize>> {
+    let indexes: BTreeMap<&str, usize> = entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| (entry.name.as_str(), index))
+        .collect();
+    let dep_indexes: Vec<BTreeSet<usize>> = entries
+        .iter()
+        .map(|entry| {
+            entry
+                .deps
+                .iter()
+                .map(|(dep, _)| indexes[dep.as_str()])
+                .collect()
+        })
+        .collect();
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); entries.len()];
+    let mut pending: Vec<usize> = dep_indexes.iter().map(BTreeSet::len).collect();
+    for (index, deps) in dep_indexes.iter().enumerate() {
+        for dep in deps {
+            dependents[*dep].push(index);
+        }
+    }
+    let mut ready: BTreeSet<usize> = (0..entries.len()).filter(|i| pending[*i] == 0).collect();
+    let mut order = Vec::with_capacity(entries.len());
+    while let Some(index) = ready.pop_first() {
+        order.push(index);
+        for dependent in dependents[index].iter() {
+            pending[*dependent] -= 1;
+            if pending[*dependent] == 0 {
+                ready.insert(*dependent);
+            }
+        }
+    }
+    if order.len() < entries.len() {
+        // Every unordered entry waits on another unordered entry so following them from any
+        // of them must revisit one.
+        let mut path = vec![(0..entries.len()).find(|i| pending[*i] > 0).unwrap()];
+        loop {
+            let last = *path.last().unwrap();
+            let next = *dep_indexes[last]
+                .iter()
+                .find(|dep| pending[**dep] > 0)
+                .unwrap();
+            if let Some(start) = path.iter().position(|i| *i == next) {
+                path.drain(..start);
+                path.push(next);
+                break;
+            }
+            path.push(next);
+        }
+        let first = &entries[path[0]];
+        let cycle: Vec<String> = path
+            .iter()
+            .map(|i| format!("{} (line {})", entries[*i].name, entries[*i].line))
+            .collect();
+        return Err(spec_error(
+            first.line,
+            first.column,
+            format!("dependency cycle {}", cycle.join(" -> ")),
+        ));
+    }
+    Ok(order)
+}
+
+fn write_payload(out: &mut String, payload: &[u8]) {
+    out.push('"');
+    for b in payload {
+        match b {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0 => out.push_str("\\0"),
+            0x20..=0x7e => out.push(*b as char),
+            _ => write!(out, "\\x{:02x}", b).unwrap(),
+        }
+    }
+    out.push('"');
+}
+
+impl<S, HW> Merkle<S, HW>
+where
+    HW: HashWriter,
+    S: Store<HW> + Default,
+{
+    /// Build a DAG from a textual spec returning it with the id of every described name.
+    /// Errors in the spec are reported as [StoreError::SpecParse] including any dependency
+    /// cycle.
+    pub fn from_text(spec: &str) -> Result<(Self, BTreeMap<String, Vec<u8>>)> {
+        let entries = parse_spec(spec)?;
+        let mut dag = Self::default();
+        let mut ids: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+        for index in order_entries(&entries)? {
+            let entry = &entries[index];
+            let deps = entry.deps.iter().map(|(dep, _)| ids[dep].clone()).collect();
+            let id = dag.add_node(entry.payload.clone(), deps)?;
+            ids.insert(entry.name.clone(), id);
+        }
+        Ok((dag, ids))
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Render every [Node] reachable from the roots as a spec accepted by [Merkle::from_text]
+    /// using the `namer` to name each node. Dependencies come before their dependents and
+    /// nodes that are ready at the same time are listed by name, so the output only depends
+    /// on the DAG and the names.
+    ///
+    /// Names that are empty, contain characters a spec does not allow or are given to more
+    /// than one node fail with [StoreError::SpecParse] at the line the name would have been
+    /// written to.
+    pub fn to_text(&self, namer: impl Fn(&Node<HW>) -> String) -> Result<String> {
+        let mut ids = self.roots.clone();
+        for root in self.roots.iter() {
+            ids.extend(self.ancestors_of(root)?);
+        }
+        let mut nodes: BTreeMap<Vec<u8>, (String, Node<HW>)> = BTreeMap::new();
+        let fetched = {
+            let ids: Vec<&[u8]> = ids.iter().map(Vec::as_slice).collect();
+            self.get_nodes_by_ids(&ids)?
+        };
+        for (id, node) in ids.into_iter().zip(fetched) {
+            let node = node.ok_or_else(|| StoreError::NoSuchNode(id.clone()))?;
+            nodes.insert(id, (namer(&node), node));
+        }
+        let mut pending: BTreeMap<&[u8], usize> = BTreeMap::new();
+        let mut dependents: BTreeMap<&[u8], Vec<&[u8]>> = BTreeMap::new();
+        let mut ready: BTreeSet<(&str, &[u8])> = BTreeSet::new();
+        for (id, (name, node)) in nodes.iter() {
+            let deps = node.dep_set();
+            pending.insert(id.as_slice(), deps.len());
+            for dep in deps {
+                dependents
+                    .entry(dep.as_slice())
+                    .or_default()
+                    .push(id.as_slice());
+            }
+            if deps.is_empty() {
+                ready.insert((name.as_str(), id.as_slice()));
+            }
+        }
+        let mut out = String::new();
+        let mut written: BTreeMap<&str, usize> = BTreeMap::new();
+        while let Some((name, id)) = ready.pop_first() {
+            let line = written.len() + 1;
+            if !is_name(name) {
+                return Err(spec_error(
+                    line,
+                    1,
+                    format!("'{}' is not a valid node name", name),
+                ));
+            }
+            if let Some(previous) = written.insert(name, line) {
+                return Err(spec_error(
+                    line,
+                    1,
+                    format!("node '{}' is already described on line {}", name, previous),
+                ));
+            }
+            out.push_str(name);
+            let node = &nodes[id].1;
+            if !node.dep_set().is_empty() {
+                let dep_names: BTreeSet<&str> = node
+                    .dep_set()
+                    .iter()
+                    .map(|dep| nodes[dep].0.as_str())
+                    .collect();
+                out.push('(');
+                out.push_str(&dep_names.into_iter().collect::<Vec<_>>().join(", "));
+                out.push(')');
+            }
+            out.push_str(": ");
+            write_payload(&mut out, node.item());
+            out.push('\n');
+            for dependent in dependents.get(id).into_iter().flatten() {
+                let count = pending.get_mut(dependent).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    ready.insert((nodes[*dependent].0.as_str(), dependent));
+                }
+            }
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::Merkle;
+use crate::hash::HashWriter;
+use crate::node::Node;
+use crate::store::{PersistedRoots, Result, Store, StoreError};
+
+/// The [nodes](Node) staged by a [Merkle::transaction]. Nothing is written to the [Store]
+/// until the transaction closure returns successfully.
+pub struct Transaction<'dag, S, HW, P = Vec<u8>>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    dag: &'dag Merkle<S, HW, P>,
+    staged: Vec<Node<HW>>,
+    staged_ids: BTreeMap<Vec<u8>, usize>,
+    referenced: BTreeSet<Vec<u8>>,
+}
+
+impl<'dag, S, HW, P> Transaction<'dag, S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Stage a new payload with a required set of dependency_ids like [Merkle::add_node].
+    /// The dependencies may be nodes staged earlier in the transaction.
+    pub fn add_node<N: Into<Vec<u8>>>(
+        &mut self,
+        item: N,
+        dependency_ids: BTreeSet<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        let node = Node::<HW>::new_with_id_version(
+            item.into(),
+            dependency_ids.clone(),
+            self.dag.id_version(),
+        );
+        let id = node.id().to_vec();
+        if self.check_for_node(&id)? {
+            return Ok(id);
+        }
+        for dep_id in dependency_ids.iter() {
+            if !self.check_for_node(dep_id)? {
+                return Err(StoreError::NoSuchDependents);
+            }
+        }
+        self.referenced.extend(dependency_ids);
+        self.staged_ids.insert(id.clone(), self.staged.len());
+        self.staged.push(node);
+        Ok(id)
+    }
+
+    /// Check if the DAG or the transaction already has a copy of a [Node].
+    pub fn check_for_node(&self, id: &[u8]) -> Result<bool> {
+        Ok(self.staged_ids.contains_key(id) || self.dag.check_for_node(id)?)
+    }
+
+    /// Get a [Node] staged by the transaction or stored in the DAG by it's hash identifier
+    /// if it exists.
+    pub fn get_node_by_id(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        match self.staged_ids.get(id) {
+            Some(idx) => Ok(Some(self.staged[*idx].clone())),
+            None => self.dag.get_node_by_id(id),
+        }
+    }
+
+    /// Get the root ids the DAG will have once the transaction is committed.
+    pub fn get_roots(&self) -> BTreeSet<Vec<u8>> {
+        self.dag
+            .roots
+            .iter()
+            .chain(self.staged_ids.keys())
+            .filter(|id| !self.referenced.contains(*id))
+            .cloned()
+            .collect()
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Run `f` with a [Transaction] that stages the nodes it adds. Once `f` succeeds the
+    /// staged nodes and the new roots are applied with a single [Store::store_many], or a
+    /// single [Store::store_many_with_roots] if the DAG [persists its
+    /// roots](Merkle::persists_roots). If `f` fails nothing is written and the roots are
+    /// left untouched.
+    ///
+    /// The [Store] backends write many nodes in one atomic write so a crash can't leave
+    /// part of the transaction behind.
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction<'_, S, HW, P>) -> Result<T>,
+    {
+        let mut txn = Transaction {
+            dag: self,
+            staged: Vec::new(),
+            staged_ids: BTreeMap::new(),
+            referenced: BTreeSet::new(),
+        };
+        let out = f(&mut txn)?;
+        let Transaction {
+            staged, referenced, ..
+        } = txn;
+        self.commit_staged(staged, &referenced)?;
+        #[cfg(feature = "debug-invariants")]
+        self.debug_check_sampled("transaction")?;
+        Ok(out)
+    }
+
+    // Writes new nodes whose dependencies are all stored or among the nodes. `referenced`
+    // holds the dependencies of the nodes. The roots are only updated once the write
+    // has succeeded.
+    pub(super) fn commit_staged(
+        &mut self,
+        nodes: Vec<Node<HW>>,
+        referenced: &BTreeSet<Vec<u8>>,
+    ) -> Result<()> {
+        if nodes.is_empty() {
+            return Ok(());
+        }
+        for node in nodes.iter() {
+            self.check_signature_policy(node)?;
+        }
+        let mut roots = self.roots.clone();
+        roots.retain(|root| !referenced.contains(root));
+        roots.extend(
+            nodes
+                .iter()
+                .map(|node| node.id().to_vec())
+                .filter(|id| !referenced.contains(id)),
+        );
+        #[cfg(feature = "cbor")]
+        let encoded_size: usize = nodes.iter().map(crate::store::codec::encoded_size).sum();
+        if self.persist_roots {
+            let record = PersistedRoots {
+                roots: roots.clone(),
+                sticky: self.sticky_roots.clone(),
+                pins: self.pins.clone(),
+                #[cfg(feature = "cbor")]
+                stored_bytes: Some((self.stored_bytes + encoded_size) as u64),
+                #[cfg(not(feature = "cbor"))]
+                stored_bytes: None,
+            };
+            self.nodes.store_many_with_roots(nodes, &record)?;
+        } else {
+            self.nodes.store_many(nodes)?;
+        }
+        #[cfg(feature = "cbor")]
+        {
+            self.stored_bytes += encoded_size;
+        }
+        self.roots = roots;
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeMap;
+
+use super::{Merkle, WorkUnits};
+use crate::hash::HashWriter;
+use crate::store::{Result, Store, StoreError};
+
+/// The outcome of [Merkle::audit_id_uniformity].
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct IdUniformityReport {
+    /// The id length produced by the configured [HashWriter].
+    pub expected_len: usize,
+    /// The number of stored ids by length in bytes.
+    pub histogram: BTreeMap<usize, u64>,
+}
+
+impl IdUniformityReport {
+    /// The populations of ids whose length differs from [IdUniformityReport::expected_len].
+    pub fn foreign(&self) -> BTreeMap<usize, u64> {
+        self.histogram
+            .iter()
+            .filter(|(len, _)| **len != self.expected_len)
+            .map(|(len, count)| (*len, *count))
+            .collect()
+    }
+
+    /// Whether every stored id has the expected length.
+    pub fn is_uniform(&self) -> bool {
+        self.histogram.keys().all(|len| *len == self.expected_len)
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    /// Compare the id lengths in the [Store] with the length of the ids produced by the
+    /// configured [HashWriter]. Stores written before id lengths were enforced can mix ids
+    /// from several hashers. Requires a [Store] that supports [Store::key_length_histogram].
+    pub fn audit_id_uniformity(&self) -> Result<IdUniformityReport> {
+        self.charge(WorkUnits::StoreReads(1))?;
+        Ok(IdUniformityReport {
+            expected_len: HW::OUTPUT_LEN,
+            histogram: self.nodes.key_length_histogram()?,
+        })
+    }
+
+    /// Fail with [StoreError::NonUniformIds] unless every stored id has the length produced by
+    /// the configured [HashWriter]. Operations that assume a uniform id length should call
+    /// this first.
+    pub fn ensure_uniform_ids(&self) -> Result<()> {
+        let report = self.audit_id_uniformity()?;
+        if report.is_uniform() {
+            return Ok(());
+        }
+        Err(StoreError::NonUniformIds {
+            expected_len: report.expected_len,
+            foreign: report.foreign(),
+        })
+    }
+
+    /// Move every [Node] whose id is not as long as the ids of the configured [HashWriter]
+    /// into the quarantine keyspace of the [Store] returning the number of nodes moved.
+    /// Nothing is deleted and the quarantined nodes stay available through
+    /// [Merkle::get_quarantined]. Roots, sticky ids and pins with a foreign length are dropped.
+    pub fn quarantine_foreign_ids(&mut self) -> Result<u64> {
+        let expected_len = HW::OUTPUT_LEN;
+        let moved = self.nodes.quarantine_foreign_keys(expected_len)?;
+        self.roots.retain(|id| id.len() == expected_len);
+        self.sticky_roots.retain(|id| id.len() == expected_len);
+        self.pins.retain(|id| id.len() == expected_len);
+        self.write_roots()?;
+        #[cfg(feature = "debug-invariants")]
+        self.debug_check_sampled("quarantine_foreign_ids")?;
+        Ok(moved)
+    }
+
+    /// Get the stored record of a quarantined node by the id it was stored under. The record
+    /// is the cbor encoding of a [Node](crate::node::Node) of the [HashWriter] that wrote it
+    /// which is not the one of this DAG.
+    pub fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.charge(WorkUnits::StoreReads(1))?;
+        self.nodes.get_quarantined(id)
+    }
+}
@@ -0,0 +1,276 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! [DepSet] the sorted and deduplicated set of dependency ids of a [Node](crate::node::Node).
+use std::collections::{btree_set, BTreeSet};
+use std::fmt;
+
+use serde::{
+    de::{SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// The number of ids a [DepSet] holds without allocating a tree.
+pub const DEP_SET_INLINE: usize = 2;
+
+#[derive(Clone)]
+enum Repr {
+    // The first `len` ids are sorted and the rest are empty.
+    Inline {
+        len: usize,
+        ids: [Vec<u8>; DEP_SET_INLINE],
+    },
+    Spilled(BTreeSet<Vec<u8>>),
+}
+
+/// A sorted and deduplicated set of dependency ids.
+///
+/// Most [nodes](crate::node::Node) have very few dependencies so up to [DEP_SET_INLINE] ids are
+/// stored inline. Larger sets spill into a [BTreeSet]. Iteration order and serialization are
+/// the same as for a [BTreeSet] of the same ids.
+#[derive(Clone)]
+pub struct DepSet(Repr);
+
+impl DepSet {
+    /// Construct an empty set.
+    pub fn new() -> Self {
+        Self(Repr::Inline {
+            len: 0,
+            ids: Default::default(),
+        })
+    }
+
+    /// The number of ids in the set.
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            Repr::Inline { len, .. } => *len,
+            Repr::Spilled(set) => set.len(),
+        }
+    }
+
+    /// Whether the set has no ids.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the set contains the id.
+    pub fn contains(&self, id: &[u8]) -> bool {
+        match &self.0 {
+            Repr::Inline { len, ids } => ids[..*len].iter().any(|i| i.as_slice() == id),
+            Repr::Spilled(set) => set.contains(id),
+        }
+    }
+
+    /// Add an id to the set returning false if it was already present.
+    pub fn insert(&mut self, id: Vec<u8>) -> bool {
+        match &mut self.0 {
+            Repr::Inline { len, ids } => match ids[..*len].binary_search(&id) {
+                Ok(_) => false,
+                Err(pos) if *len < DEP_SET_INLINE => {
+                    // Rotate the first empty slot into the insert position.
+                    ids[pos..=*len].rotate_right(1);
+                    ids[pos] = id;
+                    *len += 1;
+                    true
+                }
+                Err(_) => {
+                    let mut set: BTreeSet<Vec<u8>> = ids.iter_mut().map(std::mem::take).collect();
+                    set.insert(id);
+                    self.0 = Repr::Spilled(set);
+                    true
+                }
+            },
+            Repr::Spilled(set) => set.insert(id),
+        }
+    }
+
+    /// Iterate over the ids in ascending order.
+    pub fn iter(&self) -> Iter<'_> {
+        match &self.0 {
+            Repr::Inline { len, ids } => Iter(IterRepr::Inline(ids[..*len].iter())),
+            Repr::Spilled(set) => Iter(IterRepr::Spilled(set.iter())),
+        }
+    }
+}
+
+impl Default for DepSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartialEq for DepSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for DepSet {}
+
+impl fmt::Debug for DepSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl From<BTreeSet<Vec<u8>>> for DepSet {
+    fn from(set: BTreeSet<Vec<u8>>) -> Self {
+        if set.len() > DEP_SET_INLINE {
+            return Self(Repr::Spilled(set));
+        }
+        let mut deps = Self::new();
+        if let Repr::Inline { len, ids } = &mut deps.0 {
+            for (slot, id) in ids.iter_mut().zip(set) {
+                *slot = id;
+                *len += 1;
+            }
+        }
+        deps
+    }
+}
+
+impl From<DepSet> for BTreeSet<Vec<u8>> {
+    fn from(deps: DepSet) -> Self {
+        match deps.0 {
+            Repr::Spilled(set) => set,
+            inline => DepSet(inline).into_iter().collect(),
+        }
+    }
+}
+
+impl FromIterator<Vec<u8>> for DepSet {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(iter: I) -> Self {
+        let mut deps = Self::new();
+        for id in iter {
+            deps.insert(id);
+        }
+        deps
+    }
+}
+
+enum IterRepr<'a> {
+    Inline(std::slice::Iter<'a, Vec<u8>>),
+    Spilled(btree_set::Iter<'a, Vec<u8>>),
+}
+
+/// An iterator over the ids of a [DepSet] in ascending order.
+pub struct Iter<'a>(IterRepr<'a>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            IterRepr::Inline(iter) => iter.next(),
+            IterRepr::Spilled(iter) => iter.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.0 {
+            IterRepr::Inline(iter) => iter.size_hint(),
+            IterRepr::Spilled(iter) => iter.size_hint(),
+        }
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            IterRepr::Inline(iter) => iter.next_back(),
+            IterRepr::Spilled(iter) => iter.next_back(),
+        }
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+enum IntoIterRepr {
+    Inline(std::iter::Take<std::array::IntoIter<Vec<u8>, DEP_SET_INLINE>>),
+    Spilled(btree_set::IntoIter<Vec<u8>>),
+}
+
+/// An owning iterator over the ids of a [DepSet] in ascending order.
+pub struct IntoIter(IntoIterRepr);
+
+impl Iterator for IntoIter {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            IntoIterRepr::Inline(iter) => iter.next(),
+            IntoIterRepr::Spilled(iter) => iter.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.0 {
+            IntoIterRepr::Inline(iter) => iter.size_hint(),
+            IntoIterRepr::Spilled(iter) => iter.size_hint(),
+        }
+    }
+}
+
+impl ExactSizeIterator for IntoIter {}
+
+impl IntoIterator for DepSet {
+    type Item = Vec<u8>;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> IntoIter {
+        match self.0 {
+            Repr::Inline { len, ids } => IntoIter(IntoIterRepr::Inline(ids.into_iter().take(len))),
+            Repr::Spilled(set) => IntoIter(IntoIterRepr::Spilled(set.into_iter())),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a DepSet {
+    type Item = &'a Vec<u8>;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+// Serialized exactly like a BTreeSet<Vec<u8>>.
+impl Serialize for DepSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+struct DepSetVisitor;
+
+impl<'de> Visitor<'de> for DepSetVisitor {
+    type Value = DepSet;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of dependency ids")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<DepSet, A::Error> {
+        let mut deps = DepSet::new();
+        while let Some(id) = seq.next_element::<Vec<u8>>()? {
+            deps.insert(id);
+        }
+        Ok(deps)
+    }
+}
+
+impl<'de> Deserialize<'de> for DepSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(DepSetVisitor)
+    }
+}
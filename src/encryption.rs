@@ -0,0 +1,167 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Encrypted node payloads. The item of the [Node] holds the ciphertext so its id is computed
+//! over the ciphertext and replicas can verify ids and structure without the key. Requires
+//! the `encryption` feature.
+//!
+//! An encrypted item is a mode tag followed by the nonce and the AEAD ciphertext.
+//! [EncryptionMode::RandomNonce] uses XChaCha20-Poly1305 with a random 24 byte nonce and
+//! [EncryptionMode::Deterministic] uses AES-256-GCM-SIV with a fixed 12 byte nonce.
+use std::fmt;
+
+use aes_gcm_siv::{Aes256GcmSiv, Nonce};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::hash::HashWriter;
+use crate::node::Node;
+use crate::store::{Result, StoreError};
+
+// Authenticated with every encrypted item so it can't be confused with ciphertexts made with
+// the same key for something else.
+const ITEM_CONTEXT: &[u8] = b"merkle-dag/encrypted-item/v1";
+
+const RANDOM_NONCE_TAG: u8 = 0x01;
+const RANDOM_NONCE_LEN: usize = 24;
+const DETERMINISTIC_TAG: u8 = 0x02;
+const DETERMINISTIC_NONCE_LEN: usize = 12;
+
+/// How [PayloadKey::encrypt] picks nonces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EncryptionMode {
+    /// A random nonce for every item so identical plaintexts give different ciphertexts and
+    /// therefore different node ids.
+    #[default]
+    RandomNonce,
+    /// A fixed nonce with the nonce misuse resistant AES-256-GCM-SIV so identical plaintexts
+    /// give the same ciphertext and are deduplicated like plain payloads. Anyone holding the
+    /// items can tell which of them have the same plaintext.
+    Deterministic,
+}
+
+/// A 256 bit key encrypting node payloads with [Merkle::add_encrypted](crate::dag::Merkle::add_encrypted)
+/// and decrypting them with [Node::decrypt_item]. Items are decrypted with the mode they were
+/// encrypted with whatever the mode of the key.
+#[derive(Clone)]
+pub struct PayloadKey {
+    key: [u8; 32],
+    mode: EncryptionMode,
+}
+
+impl PayloadKey {
+    /// A key encrypting with [EncryptionMode::RandomNonce].
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            key: *key,
+            mode: EncryptionMode::default(),
+        }
+    }
+
+    /// Set the [EncryptionMode] the key encrypts with.
+    pub fn with_mode(mut self, mode: EncryptionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// The [EncryptionMode] the key encrypts with.
+    pub fn mode(&self) -> EncryptionMode {
+        self.mode
+    }
+
+    /// Encrypt `plaintext` into the bytes of an encrypted item.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: plaintext,
+            aad: ITEM_CONTEXT,
+        };
+        let failed = |e| StoreError::StoreFailure(format!("Encryption failed {:?}", e));
+        let mut item = Vec::new();
+        match self.mode {
+            EncryptionMode::RandomNonce => {
+                let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let sealed = XChaCha20Poly1305::new(&self.key.into())
+                    .encrypt(&nonce, payload)
+                    .map_err(failed)?;
+                item.push(RANDOM_NONCE_TAG);
+                item.extend_from_slice(&nonce);
+                item.extend_from_slice(&sealed);
+            }
+            EncryptionMode::Deterministic => {
+                let nonce = [0; DETERMINISTIC_NONCE_LEN];
+                let sealed = Aes256GcmSiv::new(&self.key.into())
+                    .encrypt(Nonce::from_slice(&nonce), payload)
+                    .map_err(failed)?;
+                item.push(DETERMINISTIC_TAG);
+                item.extend_from_slice(&nonce);
+                item.extend_from_slice(&sealed);
+            }
+        }
+        Ok(item)
+    }
+
+    // Decrypt the bytes of an encrypted item returning None if they don't authenticate.
+    fn decrypt(&self, item: &[u8]) -> Option<Vec<u8>> {
+        let (tag, rest) = item.split_first()?;
+        match *tag {
+            RANDOM_NONCE_TAG if rest.len() >= RANDOM_NONCE_LEN => {
+                let (nonce, sealed) = rest.split_at(RANDOM_NONCE_LEN);
+                XChaCha20Poly1305::new(&self.key.into())
+                    .decrypt(
+                        XNonce::from_slice(nonce),
+                        Payload {
+                            msg: sealed,
+                            aad: ITEM_CONTEXT,
+                        },
+                    )
+                    .ok()
+            }
+            DETERMINISTIC_TAG if rest.len() >= DETERMINISTIC_NONCE_LEN => {
+                let (nonce, sealed) = rest.split_at(DETERMINISTIC_NONCE_LEN);
+                Aes256GcmSiv::new(&self.key.into())
+                    .decrypt(
+                        Nonce::from_slice(nonce),
+                        Payload {
+                            msg: sealed,
+                            aad: ITEM_CONTEXT,
+                        },
+                    )
+                    .ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+// The key is left out so it doesn't end up in logs.
+impl fmt::Debug for PayloadKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PayloadKey")
+            .field("mode", &self.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<HW> Node<HW>
+where
+    HW: HashWriter,
+{
+    /// Decrypt the item of a node added with
+    /// [Merkle::add_encrypted](crate::dag::Merkle::add_encrypted). Fails with
+    /// [StoreError::DecryptionFailed] if the item wasn't encrypted with `key` or was tampered
+    /// with.
+    pub fn decrypt_item(&self, key: &PayloadKey) -> Result<Vec<u8>> {
+        key.decrypt(self.item())
+            .ok_or_else(|| StoreError::DecryptionFailed(self.id().to_vec()))
+    }
+}
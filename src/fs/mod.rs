@@ -0,0 +1,378 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Module implementing a [Store] interface that keeps every node in its own file for a
+//! [Merkle Dag](crate::dag::Merkle). Requires the `cbor` feature to be enabled.
+//!
+//! The layout follows git's object directory. A node is stored in
+//! `objects/ab/cdef...` where the path is the hex of its id with the first bytes split off
+//! into shard directories.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    hash::HashWriter,
+    id::hex,
+    inspect::{BackendKind, StoreMeta},
+    node::Node,
+    store::{PersistedRoots, Result, Store, StoreError},
+};
+
+use ciborium;
+
+/// The shard depth of a new [FsStore] opened with [FsStore::open].
+pub const DEFAULT_SHARD_DEPTH: usize = 1;
+
+const OBJECTS_DIR: &str = "objects";
+const QUARANTINE_DIR: &str = "quarantine";
+const TMP_DIR: &str = "tmp";
+const META_FILE: &str = "meta";
+const ROOTS_FILE: &str = "roots";
+const SHARD_DEPTH_OPTION: &str = "shard-depth=";
+
+/// A [Store] implementation keeping each [Node] as a cbor encoded file in a directory.
+///
+/// Every file is written to a temporary file first and then renamed into place so a crash
+/// never leaves a partially written node or roots file behind. Nodes are written before the
+/// roots so a crash in between at worst leaves nodes no root reaches yet.
+pub struct FsStore {
+    root: PathBuf,
+    shard_depth: usize,
+    meta: StoreMeta,
+}
+
+impl FsStore {
+    /// Open the object directory at `path` creating it if it doesn't exist. A new directory
+    /// uses the [DEFAULT_SHARD_DEPTH] and an existing one the depth it was created with.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_dir(path.as_ref(), None)
+    }
+
+    /// Open the object directory at `path` splitting `shard_depth` bytes of every id off into
+    /// directories. Fails if the directory already exists with a different shard depth.
+    pub fn open_with_shard_depth<P: AsRef<Path>>(path: P, shard_depth: usize) -> Result<Self> {
+        Self::open_dir(path.as_ref(), Some(shard_depth))
+    }
+
+    fn open_dir(root: &Path, shard_depth: Option<usize>) -> Result<Self> {
+        for dir in [OBJECTS_DIR, QUARANTINE_DIR] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+        // Temporary files left over from a crash never made it into place.
+        match fs::remove_dir_all(root.join(TMP_DIR)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => fs::create_dir_all(root.join(TMP_DIR))?,
+        }
+        let existing = read_file(&root.join(META_FILE))?;
+        let mut meta = StoreMeta::reopen(BackendKind::Filesystem, existing.as_deref());
+        let recorded = meta
+            .options
+            .iter()
+            .find_map(|option| option.strip_prefix(SHARD_DEPTH_OPTION)?.parse().ok());
+        let shard_depth = match (recorded, shard_depth) {
+            (Some(recorded), Some(requested)) if recorded != requested => {
+                return Err(StoreError::StoreFailure(format!(
+                    "The object directory has a shard depth of {} not {}",
+                    recorded, requested
+                )))
+            }
+            (Some(recorded), _) => recorded,
+            (None, requested) => requested.unwrap_or(DEFAULT_SHARD_DEPTH),
+        };
+        meta.options
+            .insert(format!("{}{}", SHARD_DEPTH_OPTION, shard_depth));
+        let me = Self {
+            root: root.to_path_buf(),
+            shard_depth,
+            meta,
+        };
+        me.write_meta()?;
+        Ok(me)
+    }
+
+    fn write_meta(&self) -> Result<()> {
+        self.write_atomic(&self.root.join(META_FILE), &self.meta.encode())
+    }
+
+    // Files can't be replaced together so the hash algorithm is recorded before the first node
+    // is written. A node is never in place without it.
+    fn record_hash_algorithm<HW: HashWriter>(&mut self) -> Result<()> {
+        if let Some(meta) = self.meta.recording_hash_algorithm::<HW>() {
+            self.write_atomic(&self.root.join(META_FILE), &meta.encode())?;
+            self.meta = meta;
+        }
+        Ok(())
+    }
+
+    // Writes the bytes to a temporary file and renames it over the path once they are synced.
+    fn write_atomic(&self, path: &Path, bytes: &[u8]) -> Result<()> {
+        static TMP_FILES: AtomicU64 = AtomicU64::new(0);
+        let tmp = self.root.join(TMP_DIR).join(format!(
+            "{}-{}",
+            std::process::id(),
+            TMP_FILES.fetch_add(1, Ordering::SeqCst)
+        ));
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // The path of the object file for an id. The last byte always stays in the file name so
+    // short ids are sharded less deeply. Empty ids have no path.
+    fn object_path(&self, dir: &str, id: &[u8]) -> Option<PathBuf> {
+        if id.is_empty() {
+            return None;
+        }
+        let mut path = self.root.join(dir);
+        let shards = self.shard_depth.min(id.len() - 1);
+        for byte in &id[..shards] {
+            path.push(hex(std::slice::from_ref(byte)));
+        }
+        path.push(hex(&id[shards..]));
+        Some(path)
+    }
+
+    fn read_object(&self, dir: &str, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.object_path(dir, id) {
+            Some(path) => read_file(&path),
+            None => Ok(None),
+        }
+    }
+
+    fn write_node<HW: HashWriter>(&self, node: &Node<HW>) -> Result<()> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(node, &mut buf).unwrap();
+        self.write_object(node.id(), &buf)
+    }
+
+    fn write_object(&self, id: &[u8], bytes: &[u8]) -> Result<()> {
+        match self.object_path(OBJECTS_DIR, id) {
+            Some(path) => self.write_atomic(&path, bytes),
+            None => Err(StoreError::StoreFailure(
+                "Can not store a node with an empty id".to_owned(),
+            )),
+        }
+    }
+
+    // Collects the ids of the objects whose hex starts with or is a prefix of `prefix`.
+    fn walk(&self, prefix: &str) -> Result<Vec<Vec<u8>>> {
+        let mut ids = Vec::new();
+        walk_dir(
+            &self.root.join(OBJECTS_DIR),
+            String::new(),
+            prefix,
+            &mut ids,
+        )?;
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// The metadata block of this store.
+    pub fn meta(&self) -> &StoreMeta {
+        &self.meta
+    }
+
+    /// The number of bytes of each id split off into shard directories.
+    pub fn shard_depth(&self) -> usize {
+        self.shard_depth
+    }
+
+    /// The directory holding the store.
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl<HW> Store<HW> for FsStore
+where
+    HW: HashWriter,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        match self.object_path(OBJECTS_DIR, id) {
+            Some(path) => Ok(path.try_exists()?),
+            None => Ok(false),
+        }
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        self.read_object(OBJECTS_DIR, id)?
+            .map(|bytes| decode(&bytes))
+            .transpose()
+    }
+
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read_object(OBJECTS_DIR, id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.record_hash_algorithm::<HW>()?;
+        self.write_node(&node)?;
+        Ok(())
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        self.record_hash_algorithm::<HW>()?;
+        self.write_object(node.id(), &encoded)?;
+        Ok(())
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        self.record_hash_algorithm::<HW>()?;
+        // The roots are written last so they never reference a node that isn't in place.
+        for (node, encoded) in &records {
+            self.write_object(node.id(), encoded)?;
+        }
+        if let Some(roots) = roots {
+            self.write_atomic(&self.root.join(ROOTS_FILE), &roots.encode())?;
+        }
+        Ok(())
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.store_many_with_roots([node], roots)
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.record_hash_algorithm::<HW>()?;
+        // The roots are written last so they never reference a node that isn't in place.
+        for node in nodes {
+            self.write_node(&node)?;
+        }
+        self.write_atomic(&self.root.join(ROOTS_FILE), &roots.encode())?;
+        Ok(())
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.write_atomic(&self.root.join(ROOTS_FILE), &roots.encode())
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        read_file(&self.root.join(ROOTS_FILE))?
+            .map(|bytes| PersistedRoots::decode(&bytes))
+            .transpose()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.meta.check_hash_algorithm::<HW>()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        if let Some(path) = self.object_path(OBJECTS_DIR, id) {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        let mut ids = self.walk(&hex(prefix))?;
+        ids.retain(|id| id.starts_with(prefix));
+        ids.truncate(limit);
+        Ok(ids)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        Ok(Box::new(self.walk("")?.into_iter().map(Ok)))
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        let mut histogram = BTreeMap::new();
+        for id in self.walk("")? {
+            *histogram.entry(id.len()).or_insert(0) += 1;
+        }
+        Ok(histogram)
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        let mut moved = 0;
+        for id in self.walk("")? {
+            if id.len() == expected_len {
+                continue;
+            }
+            let from = self.object_path(OBJECTS_DIR, &id).unwrap();
+            let to = self.root.join(QUARANTINE_DIR).join(hex(&id));
+            fs::rename(from, to)?;
+            moved += 1;
+        }
+        self.meta.options.insert("quarantine".to_owned());
+        self.meta.record_maintenance();
+        self.write_meta()?;
+        Ok(moved)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        read_file(&self.root.join(QUARANTINE_DIR).join(hex(id)))
+    }
+}
+
+// Walks the shard directories below `dir` whose hex so far is compatible with `prefix`.
+fn walk_dir(dir: &Path, so_far: String, prefix: &str, ids: &mut Vec<Vec<u8>>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let path_hex = format!("{}{}", so_far, name);
+        if !(path_hex.starts_with(prefix) || prefix.starts_with(&path_hex)) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            walk_dir(&entry.path(), path_hex, prefix, ids)?;
+        } else if let Some(id) = unhex(&path_hex) {
+            ids.push(id);
+        }
+    }
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn decode<HW: HashWriter>(bytes: &[u8]) -> Result<Node<HW>> {
+    ciborium::de::from_reader(bytes)
+        .map_err(|e| StoreError::StoreFailure(format!("Invalid serialization {:?}", e)))
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+        .collect()
+}
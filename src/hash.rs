@@ -12,27 +12,94 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //! HashWriter trait specification and default implementations.
+//!
+//! The [HashWriter] implementation for the std
+//! [DefaultHasher](std::collections::hash_map::DefaultHasher) requires the `insecure-hashes`
+//! feature. Its key is fixed and public so anyone can compute and collide its 8 byte ids. Use
+//! [SipHash24](siphash::SipHash24) with a key of your own where a fast hash is wanted.
 
+#[cfg(feature = "insecure-hashes")]
 use std::collections::hash_map::DefaultHasher;
+#[cfg(feature = "insecure-hashes")]
 use std::hash::Hasher;
+use std::io::{self, Read};
+
+pub mod siphash;
+
+/// The size of the chunks [HashWriter::record_reader] reads at a time.
+pub const RECORD_CHUNK_SIZE: usize = 64 * 1024;
 
 /// Utility Trait to specify the hashing algorithm and provide a common
 /// interface for that algorithm to provide. This interface is expected to
 /// be stateful.
 pub trait HashWriter: Default {
+    /// The length in bytes of the hashes this algorithm produces and so of every node id.
+    const OUTPUT_LEN: usize;
+
+    /// A stable name of the algorithm like `"blake2b"` or `"sha2"`. Persistent stores record
+    /// it with [OUTPUT_LEN](HashWriter::OUTPUT_LEN) to refuse nodes hashed with another
+    /// algorithm, so it must never change once stores were written with it. Hashers that
+    /// assign the same ids for the same content and output length should share it.
+    const ALGORITHM_ID: &'static str;
+
     /// Record bytes from an iterator into our hash algorithm.
     fn record<I: Iterator<Item = u8>>(&mut self, bs: I);
 
+    /// Record bytes from a slice into our hash algorithm. Hashers that can take a slice
+    /// directly should override this to avoid copying the bytes.
+    fn record_bytes(&mut self, bs: &[u8]) {
+        self.record(bs.iter().cloned())
+    }
+
+    /// Record the bytes of a reader until it is exhausted in chunks of [RECORD_CHUNK_SIZE].
+    /// Returns the number of bytes recorded.
+    fn record_reader<R: Read + ?Sized>(&mut self, reader: &mut R) -> io::Result<u64> {
+        let mut buf = vec![0; RECORD_CHUNK_SIZE];
+        let mut total = 0;
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(total),
+                Ok(read) => {
+                    self.record_bytes(&buf[..read]);
+                    total += read as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     /// Provide the current hash value based on the bytes that have so far been recorded.
     fn hash(&self) -> Vec<u8>;
 }
 
+/// Supplies the secret key of a keyed [HashWriter]. The key is part of the type of the hasher
+/// because every hasher of a DAG is built with [Default], including the ones its [Store]
+/// decodes nodes with, so they all have to agree on the key. A key chosen at startup can be
+/// read from a `static` such as a `OnceLock`.
+///
+/// [Store]: crate::store::Store
+pub trait HashKey {
+    /// The key. It must not change while the process runs.
+    fn key() -> [u8; 32];
+}
+
+/// Unkeyed SipHash-1-3 with 8 byte ids. Fine for toy DAGs and tests but don't use it where
+/// payloads can't be trusted. Requires the `insecure-hashes` feature.
+#[cfg(feature = "insecure-hashes")]
 impl HashWriter for DefaultHasher {
+    const OUTPUT_LEN: usize = 8;
+    const ALGORITHM_ID: &'static str = "std-default-hasher";
+
     fn record<I: Iterator<Item = u8>>(&mut self, iter: I) {
         let bytes = iter.collect::<Vec<u8>>();
         self.write(bytes.as_slice());
     }
 
+    fn record_bytes(&mut self, bs: &[u8]) {
+        self.write(bs);
+    }
+
     fn hash(&self) -> Vec<u8> {
         self.finish().to_le_bytes().to_vec()
     }
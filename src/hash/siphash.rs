@@ -0,0 +1,83 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Implements the [HashWriter] interface for keyed SipHash-2-4.
+
+use std::fmt;
+use std::hash::Hasher;
+use std::marker::PhantomData;
+
+use siphasher::sip::SipHasher24;
+
+use super::{HashKey, HashWriter};
+
+/// A keyed SipHash-2-4 [HashWriter] with 8 byte ids using the key of `K`. A cheap replacement
+/// for the std [DefaultHasher](std::collections::hash_map::DefaultHasher) whose key is fixed
+/// and public. Ids can't be computed or collided without the key but 8 bytes is too short
+/// for ids an attacker can search, so prefer a cryptographic hash when they choose the
+/// payloads.
+///
+/// SipHash takes a 16 byte key. The two halves of the 32 byte key of `K` are xored together
+/// into it.
+pub struct SipHash24<K> {
+    hasher: SipHasher24,
+    _key: PhantomData<K>,
+}
+
+impl<K: HashKey> Default for SipHash24<K> {
+    fn default() -> Self {
+        let key = K::key();
+        let mut folded = [0; 16];
+        for (idx, byte) in folded.iter_mut().enumerate() {
+            *byte = key[idx] ^ key[idx + 16];
+        }
+        Self {
+            hasher: SipHasher24::new_with_key(&folded),
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K> Clone for SipHash24<K> {
+    fn clone(&self) -> Self {
+        Self {
+            hasher: self.hasher,
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K> fmt::Debug for SipHash24<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The hasher state would give the key away.
+        f.debug_struct("SipHash24").finish_non_exhaustive()
+    }
+}
+
+impl<K: HashKey> HashWriter for SipHash24<K> {
+    const OUTPUT_LEN: usize = 8;
+    const ALGORITHM_ID: &'static str = "siphash24-keyed";
+
+    fn record<I: Iterator<Item = u8>>(&mut self, bs: I) {
+        let vec: Vec<u8> = bs.collect();
+        self.hasher.write(&vec);
+    }
+
+    fn record_bytes(&mut self, bs: &[u8]) {
+        self.hasher.write(bs);
+    }
+
+    fn hash(&self) -> Vec<u8> {
+        self.hasher.finish().to_le_bytes().to_vec()
+    }
+}
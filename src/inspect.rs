@@ -0,0 +1,246 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Describe a store on disk without opening it through the normal constructors.
+//!
+//! Every persistent backend keeps a [StoreMeta] block under [META_KEY]. It records the layout,
+//! versions and enabled options of the store and is updated when the store is opened and after
+//! maintenance. [inspect_path] reads the block and a cheap node count without taking write
+//! locks or migrating anything. Requires the `cbor` feature to be enabled.
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SystemClock};
+use crate::hash::HashWriter;
+use crate::store::{codec, Result, StoreError};
+
+/// The key of the metadata block in every backend.
+pub const META_KEY: &[u8] = b"__merkle_dag_meta__";
+
+/// The version of the [StoreMeta] layout written by this crate.
+pub const META_VERSION: u32 = 1;
+
+/// The persistent backends a store on disk can be written by.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+pub enum BackendKind {
+    Sqlite,
+    LevelDb,
+    RocksDb,
+    Sled,
+    Redb,
+    Lmdb,
+    Filesystem,
+    Log,
+}
+
+/// The self describing metadata block of a store.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct StoreMeta {
+    /// The [META_VERSION] the block was written with.
+    pub version: u32,
+    /// The backend that wrote the store.
+    pub backend: BackendKind,
+    /// The [codec::CODEC_VERSION] of the stored nodes.
+    pub codec_version: u32,
+    /// The [name](hash_algorithm_name) of the hash algorithm of the stored nodes once a node
+    /// was stored.
+    pub hash_algorithm: Option<String>,
+    /// The optional layouts the store was opened with, like `payload_index`.
+    pub options: BTreeSet<String>,
+    /// When the store was last opened in seconds since the unix epoch.
+    pub opened_at_secs: u64,
+    /// When maintenance last ran in seconds since the unix epoch.
+    pub last_maintenance_secs: Option<u64>,
+}
+
+impl StoreMeta {
+    /// A fresh block for a store opened now.
+    pub fn new(backend: BackendKind) -> Self {
+        Self {
+            version: META_VERSION,
+            backend,
+            codec_version: codec::CODEC_VERSION,
+            hash_algorithm: None,
+            options: BTreeSet::new(),
+            opened_at_secs: now_secs(),
+            last_maintenance_secs: None,
+        }
+    }
+
+    /// A block for a store opened now that keeps what the `existing` block recorded about the
+    /// stored nodes. Unreadable or newer blocks are replaced.
+    pub fn reopen(backend: BackendKind, existing: Option<&[u8]>) -> Self {
+        let mut meta = Self::new(backend);
+        if let Some(MetaBlock::Current(previous)) = existing.map(MetaBlock::decode) {
+            meta.hash_algorithm = previous.hash_algorithm.filter(|name| !is_legacy_name(name));
+            meta.options = previous.options;
+            meta.last_maintenance_secs = previous.last_maintenance_secs;
+        }
+        meta
+    }
+
+    /// Fail with [StoreError::HashAlgorithmMismatch] if the stored nodes were hashed with
+    /// another [HashWriter](crate::hash::HashWriter) than `HW`. A store without nodes has
+    /// nothing to mismatch.
+    pub fn check_hash_algorithm<HW: HashWriter>(&self) -> Result<()> {
+        let expected = hash_algorithm_name::<HW>();
+        match self.hash_algorithm.as_deref() {
+            Some(found) if found != expected && !is_legacy_name(found) => {
+                Err(StoreError::HashAlgorithmMismatch {
+                    expected,
+                    found: found.to_owned(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The block recording the hash algorithm of `HW` or None if one is recorded already.
+    // Backends write it in the same write as the first node and keep it once the write
+    // succeeded.
+    pub(crate) fn recording_hash_algorithm<HW: HashWriter>(&self) -> Option<StoreMeta> {
+        if self.hash_algorithm.is_some() {
+            return None;
+        }
+        let mut meta = self.clone();
+        meta.hash_algorithm = Some(hash_algorithm_name::<HW>());
+        Some(meta)
+    }
+
+    /// Record that maintenance ran now.
+    pub fn record_maintenance(&mut self) {
+        self.last_maintenance_secs = Some(now_secs());
+    }
+
+    /// The canonical cbor encoding of the block.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(self, &mut buf).expect("Encoding store metadata can not fail");
+        buf
+    }
+}
+
+/// The name a store records for the hash algorithm of `HW`. It is the
+/// [ALGORITHM_ID](HashWriter::ALGORITHM_ID) followed by the length of the ids in bits like
+/// `blake2b-512`.
+pub fn hash_algorithm_name<HW: HashWriter>() -> String {
+    format!("{}-{}", HW::ALGORITHM_ID, HW::OUTPUT_LEN * 8)
+}
+
+// Blocks written before HashWriter::ALGORITHM_ID recorded Rust type names, which change with
+// compiler and crate versions. They are treated as if nothing was recorded.
+fn is_legacy_name(name: &str) -> bool {
+    name.contains("::")
+}
+
+fn now_secs() -> u64 {
+    SystemClock.now().as_secs()
+}
+
+/// The state of the metadata block found by [inspect_path].
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum MetaBlock {
+    /// A block this crate understands.
+    Current(StoreMeta),
+    /// A block written by a newer version of this crate. Only its version is reported.
+    Newer { version: u64 },
+    /// A block that could not be decoded.
+    Corrupt(String),
+    /// The store has no block. It was never opened by a version of this crate that writes one.
+    Missing,
+}
+
+impl MetaBlock {
+    /// Decode a metadata block.
+    pub fn decode(bytes: &[u8]) -> Self {
+        let value: ciborium::value::Value = match ciborium::de::from_reader(bytes) {
+            Ok(value) => value,
+            Err(e) => return Self::Corrupt(format!("{:?}", e)),
+        };
+        let version = value.as_map().and_then(|fields| {
+            fields
+                .iter()
+                .find(|(key, _)| key.as_text() == Some("version"))
+                .and_then(|(_, version)| version.as_integer())
+                .and_then(|version| u64::try_from(version).ok())
+        });
+        match version {
+            None => Self::Corrupt("the block has no version".to_owned()),
+            Some(version) if version > META_VERSION as u64 => Self::Newer { version },
+            Some(_) => match value.deserialized() {
+                Ok(meta) => Self::Current(meta),
+                Err(e) => Self::Corrupt(format!("{:?}", e)),
+            },
+        }
+    }
+}
+
+/// What [inspect_path] found at a path.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct StoreDescription {
+    /// The backend the files on disk belong to.
+    pub backend: BackendKind,
+    /// The metadata block of the store.
+    pub meta: MetaBlock,
+    /// The number of stored nodes. Backends that only keep an estimate report the estimate.
+    pub node_count: Option<u64>,
+    /// Backend specific details like the sqlite tables.
+    pub details: BTreeMap<String, String>,
+}
+
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// Detect the backend that wrote the files at `path`. Fails with
+/// [StoreError::UnrecognizedStore] if they don't belong to a known backend.
+pub fn sniff_backend<P: AsRef<Path>>(path: P) -> Result<BackendKind> {
+    let path = path.as_ref();
+    let unrecognized = || StoreError::UnrecognizedStore(path.display().to_string());
+    if path.is_file() {
+        let mut header = [0; SQLITE_HEADER.len()];
+        let mut file = std::fs::File::open(path).map_err(|_| unrecognized())?;
+        return match std::io::Read::read_exact(&mut file, &mut header) {
+            Ok(()) if header == SQLITE_HEADER => Ok(BackendKind::Sqlite),
+            _ => Err(unrecognized()),
+        };
+    }
+    if !path.join("CURRENT").is_file() {
+        return Err(unrecognized());
+    }
+    // Only RocksDB writes an options file next to its manifest.
+    let entries = std::fs::read_dir(path).map_err(|_| unrecognized())?;
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with("OPTIONS-") {
+            return Ok(BackendKind::RocksDb);
+        }
+    }
+    Ok(BackendKind::LevelDb)
+}
+
+/// Describe the store at `path` without opening it for writing or migrating it. Fails with
+/// [StoreError::UnrecognizedStore] if the files don't belong to a known backend and with
+/// [StoreError::Unsupported] if the backend was not compiled in.
+pub fn inspect_path<P: AsRef<Path>>(path: P) -> Result<StoreDescription> {
+    let path = path.as_ref();
+    match sniff_backend(path)? {
+        #[cfg(feature = "sqlite")]
+        BackendKind::Sqlite => crate::sqlite::inspect(path),
+        #[cfg(feature = "rusty-leveldb")]
+        BackendKind::LevelDb => crate::leveldb::inspect(path),
+        #[cfg(feature = "rocksdb")]
+        BackendKind::RocksDb => crate::rocksdb::inspect(path),
+        #[allow(unreachable_patterns)]
+        _ => Err(StoreError::Unsupported("inspect_path")),
+    }
+}
@@ -14,25 +14,39 @@
 //! Module implementing a [Store] interface using LevelDB for a [Merkle Dag](crate::dag::Merkle).
 //! Requires the `rusty-leveldb` feature to be enabled.
 
-use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
 
 use crate::{
     hash::HashWriter,
+    inspect::{BackendKind, MetaBlock, StoreDescription, StoreMeta, META_KEY},
     node::Node,
-    store::{Result as StoreResult, Store, StoreError},
+    store::{PersistedRoots, Result as StoreResult, Store, StoreError, ROOTS_KEY},
 };
 
 use ciborium;
-use rusty_leveldb::{self, Options, Status};
+use rusty_leveldb::{self, CompressionType, LdbIterator, Options, Status};
 
 pub type Result<T> = std::result::Result<T, Status>;
 
+/// Keys in the quarantine keyspace of a [LevelStore] are the original id behind this prefix.
+pub const QUARANTINE_PREFIX: &[u8] = b"\0merkle-dag/quarantine/";
+
+// rusty-leveldb caches this many blocks whatever their size.
+const CACHED_BLOCKS: usize = 2048;
+
 /// A [Store] implementation using the rusty-leveldb port of leveldb.
 /// The Default implementation of this is an in-memory implementation
 /// of the store.
+///
+/// rusty-leveldb needs `&mut` access even for reads so the database sits behind a [Mutex].
+/// Every read takes the lock, which is uncontended but not free, while writes go through
+/// `&mut self` and skip it. The database holds `Rc`s internally so the store still can't be
+/// sent to or shared with other threads.
 pub struct LevelStore {
-    store: RefCell<rusty_leveldb::DB>,
+    store: Mutex<rusty_leveldb::DB>,
+    meta: StoreMeta,
 }
 
 impl LevelStore {
@@ -42,9 +56,79 @@ impl LevelStore {
     }
 
     pub fn open_with_opts<P: AsRef<Path>>(path: P, opts: Options) -> Result<Self> {
-        Ok(Self {
-            store: RefCell::new(rusty_leveldb::DB::open(path, opts)?),
-        })
+        Self::open_db(rusty_leveldb::DB::open(path, opts)?)
+    }
+
+    /// Open the database with a block cache of roughly `cache_size` bytes. rusty-leveldb caches
+    /// a fixed number of blocks so this sets the block size of newly written tables.
+    pub fn open_with_cache_size<P: AsRef<Path>>(path: P, cache_size: usize) -> Result<Self> {
+        let opts = Options {
+            block_size: (cache_size / CACHED_BLOCKS).max(1024),
+            ..Default::default()
+        };
+        Self::open_with_opts(path, opts)
+    }
+
+    /// Open the database compressing newly written tables with snappy if `compressed` is true.
+    /// Tables that are already written are read either way.
+    pub fn open_with_compression<P: AsRef<Path>>(path: P, compressed: bool) -> Result<Self> {
+        let opts = Options {
+            compression_type: if compressed {
+                CompressionType::CompressionSnappy
+            } else {
+                CompressionType::CompressionNone
+            },
+            ..Default::default()
+        };
+        Self::open_with_opts(path, opts)
+    }
+
+    // Wraps the database refreshing the metadata block.
+    fn open_db(mut db: rusty_leveldb::DB) -> Result<Self> {
+        let existing = db.get(META_KEY);
+        let me = Self {
+            store: Mutex::new(db),
+            meta: StoreMeta::reopen(BackendKind::LevelDb, existing.as_deref()),
+        };
+        me.write_meta()?;
+        Ok(me)
+    }
+
+    fn write_meta(&self) -> Result<()> {
+        self.db().put(META_KEY, &self.meta.encode())
+    }
+
+    // Locks the database for a read.
+    fn db(&self) -> MutexGuard<'_, rusty_leveldb::DB> {
+        self.store.lock().unwrap()
+    }
+
+    // Writes have exclusive access already so they don't need the lock.
+    fn db_mut(&mut self) -> &mut rusty_leveldb::DB {
+        self.store.get_mut().unwrap()
+    }
+
+    /// Compact the keys between `from` and `to` inclusive.
+    pub fn compact_range(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+        self.db_mut().compact_range(from, to)
+    }
+
+    // Writes a batch of nodes recording the hash algorithm with the first of them.
+    fn write_nodes<HW: HashWriter>(&mut self, mut batch: rusty_leveldb::WriteBatch) -> Result<()> {
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        if let Some(meta) = &pending {
+            batch.put(META_KEY, &meta.encode());
+        }
+        self.db_mut().write(batch, false)?;
+        if let Some(meta) = pending {
+            self.meta = meta;
+        }
+        Ok(())
+    }
+
+    /// The metadata block of this store.
+    pub fn meta(&self) -> &StoreMeta {
+        &self.meta
     }
 }
 
@@ -53,23 +137,266 @@ where
     HW: HashWriter,
 {
     fn contains(&self, id: &[u8]) -> StoreResult<bool> {
-        Ok(self.store.borrow_mut().get(id).is_some())
+        Ok(self.db().get(id).is_some())
     }
 
     fn get(&self, id: &[u8]) -> StoreResult<Option<Node<HW>>> {
-        Ok(match self.store.borrow_mut().get(id) {
+        Ok(match self.db().get(id) {
             Some(bs) => ciborium::de::from_reader(bs.as_slice())
                 .map_err(|e| StoreError::StoreFailure(format!("Invalid serialization {:?}", e)))?,
             None => None,
         })
     }
 
+    fn get_raw(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        Ok(self.db().get(id))
+    }
+
     fn store(&mut self, node: Node<HW>) -> StoreResult<()> {
         let mut buf = Vec::new();
         ciborium::ser::into_writer(&node, &mut buf).unwrap();
-        self.store.borrow_mut().put(node.id(), &buf)?;
+        let mut batch = rusty_leveldb::WriteBatch::new();
+        batch.put(node.id(), &buf);
+        self.write_nodes::<HW>(batch)?;
+        Ok(())
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> StoreResult<()> {
+        let mut batch = rusty_leveldb::WriteBatch::new();
+        batch.put(node.id(), &encoded);
+        self.write_nodes::<HW>(batch)?;
+        Ok(())
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> StoreResult<()> {
+        let mut batch = rusty_leveldb::WriteBatch::new();
+        for (node, encoded) in &records {
+            batch.put(node.id(), encoded);
+        }
+        if let Some(roots) = roots {
+            batch.put(ROOTS_KEY, &roots.encode());
+        }
+        self.write_nodes::<HW>(batch)?;
+        Ok(())
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let mut batch = rusty_leveldb::WriteBatch::new();
+        let mut buf = Vec::new();
+        for node in nodes {
+            buf.clear();
+            ciborium::ser::into_writer(&node, &mut buf).unwrap();
+            batch.put(node.id(), &buf);
+        }
+        self.write_nodes::<HW>(batch)?;
+        Ok(())
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> StoreResult<()> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&node, &mut buf).unwrap();
+        let mut batch = rusty_leveldb::WriteBatch::new();
+        batch.put(node.id(), &buf);
+        batch.put(ROOTS_KEY, &roots.encode());
+        self.write_nodes::<HW>(batch)?;
         Ok(())
     }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let mut batch = rusty_leveldb::WriteBatch::new();
+        let mut buf = Vec::new();
+        for node in nodes {
+            buf.clear();
+            ciborium::ser::into_writer(&node, &mut buf).unwrap();
+            batch.put(node.id(), &buf);
+        }
+        batch.put(ROOTS_KEY, &roots.encode());
+        self.write_nodes::<HW>(batch)?;
+        Ok(())
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> StoreResult<()> {
+        self.db_mut().put(ROOTS_KEY, &roots.encode())?;
+        Ok(())
+    }
+
+    fn persisted_roots(&self) -> StoreResult<Option<PersistedRoots>> {
+        self.db()
+            .get(ROOTS_KEY)
+            .map(|bytes| PersistedRoots::decode(&bytes))
+            .transpose()
+    }
+
+    fn check_hash_algorithm(&self) -> StoreResult<()> {
+        self.meta.check_hash_algorithm::<HW>()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> StoreResult<()> {
+        self.db_mut().delete(id)?;
+        Ok(())
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> StoreResult<Vec<Vec<u8>>> {
+        let mut iter = self.db().new_iter()?;
+        iter.seek(prefix);
+        let (mut key, mut val) = (Vec::new(), Vec::new());
+        let mut ids = Vec::new();
+        while ids.len() < limit && iter.current(&mut key, &mut val) && key.starts_with(prefix) {
+            if !is_reserved(&key) {
+                ids.push(key.clone());
+            }
+            iter.advance();
+        }
+        Ok(ids)
+    }
+
+    fn ids(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Vec<u8>>> + '_>> {
+        let mut iter = self.db().new_iter()?;
+        iter.seek_to_first();
+        let (mut key, mut val) = (Vec::new(), Vec::new());
+        Ok(Box::new(std::iter::from_fn(move || {
+            while iter.current(&mut key, &mut val) {
+                iter.advance();
+                if !is_reserved(&key) {
+                    return Some(Ok(key.clone()));
+                }
+            }
+            None
+        })))
+    }
+
+    fn key_length_histogram(&self) -> StoreResult<BTreeMap<usize, u64>> {
+        let mut histogram = BTreeMap::new();
+        for key in self.keys()? {
+            if !is_reserved(&key) {
+                *histogram.entry(key.len()).or_insert(0) += 1;
+            }
+        }
+        Ok(histogram)
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> StoreResult<u64> {
+        let mut batch = rusty_leveldb::WriteBatch::new();
+        let mut moved = 0;
+        for key in self.keys()? {
+            if key.len() == expected_len || is_reserved(&key) {
+                continue;
+            }
+            if let Some(val) = self.db_mut().get(&key) {
+                batch.put(&quarantine_key(&key), &val);
+                batch.delete(&key);
+                moved += 1;
+            }
+        }
+        self.db_mut().write(batch, true)?;
+        self.meta.options.insert("quarantine".to_owned());
+        self.meta.record_maintenance();
+        self.write_meta()?;
+        Ok(moved)
+    }
+
+    fn flush(&mut self) -> StoreResult<()> {
+        self.db_mut().flush()?;
+        Ok(())
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        Ok(self.db().get(&quarantine_key(id)).map(|bs| bs.to_vec()))
+    }
+}
+
+impl LevelStore {
+    // Every key in the database including the quarantined ones.
+    fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        let mut iter = self.db().new_iter()?;
+        iter.seek_to_first();
+        let (mut key, mut val) = (Vec::new(), Vec::new());
+        let mut keys = Vec::new();
+        while iter.current(&mut key, &mut val) {
+            keys.push(key.clone());
+            iter.advance();
+        }
+        Ok(keys)
+    }
+}
+
+// Keys that don't hold a node: the quarantine keyspace, the metadata block and the roots.
+fn is_reserved(key: &[u8]) -> bool {
+    key == META_KEY || key == ROOTS_KEY || key.starts_with(QUARANTINE_PREFIX)
+}
+
+// Describes the database at the path. LevelDB can't be opened read only so this opens a copy
+// and the original files are never touched.
+pub(crate) fn inspect(path: &Path) -> StoreResult<StoreDescription> {
+    static COPIES: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let copy = std::env::temp_dir().join(format!(
+        "merkle-dag-inspect-{}-{}",
+        std::process::id(),
+        COPIES.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+    ));
+    let described = copy_dir(path, &copy)
+        .map_err(|e| StoreError::StoreFailure(format!("{:?}", e)))
+        .and_then(|_| describe_copy(&copy));
+    let _ = std::fs::remove_dir_all(&copy);
+    described
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        // The lock file belongs to whoever has the original open.
+        if entry.file_type()?.is_file() && entry.file_name() != "LOCK" {
+            std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+fn describe_copy(copy: &Path) -> StoreResult<StoreDescription> {
+    let opts = Options {
+        create_if_missing: false,
+        ..Default::default()
+    };
+    let mut db = rusty_leveldb::DB::open(copy, opts)?;
+    let meta = db
+        .get(META_KEY)
+        .map(|block| MetaBlock::decode(&block))
+        .unwrap_or(MetaBlock::Missing);
+    let mut iter = db.new_iter()?;
+    iter.seek_to_first();
+    let (mut key, mut val) = (Vec::new(), Vec::new());
+    let (mut nodes, mut quarantined) = (0, 0);
+    while iter.current(&mut key, &mut val) {
+        if key.starts_with(QUARANTINE_PREFIX) {
+            quarantined += 1;
+        } else if !is_reserved(&key) {
+            nodes += 1;
+        }
+        iter.advance();
+    }
+    Ok(StoreDescription {
+        backend: BackendKind::LevelDb,
+        meta,
+        node_count: Some(nodes),
+        details: BTreeMap::from([("quarantined".to_owned(), quarantined.to_string())]),
+    })
+}
+
+fn quarantine_key(id: &[u8]) -> Vec<u8> {
+    let mut key = QUARANTINE_PREFIX.to_vec();
+    key.extend_from_slice(id);
+    key
 }
 
 impl From<rusty_leveldb::Status> for StoreError {
@@ -80,10 +407,7 @@ impl From<rusty_leveldb::Status> for StoreError {
 
 impl Default for LevelStore {
     fn default() -> Self {
-        Self {
-            store: RefCell::new(
-                rusty_leveldb::DB::open("memory", rusty_leveldb::in_memory()).unwrap(),
-            ),
-        }
+        Self::open_db(rusty_leveldb::DB::open("memory", rusty_leveldb::in_memory()).unwrap())
+            .unwrap()
     }
 }
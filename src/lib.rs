@@ -15,20 +15,56 @@
 
 #[cfg(feature = "blake2")]
 pub mod blake2;
+#[cfg(feature = "blake3")]
+pub mod blake3;
+pub mod clock;
 pub mod dag;
+pub mod depset;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(feature = "cbor")]
+pub mod fs;
 pub mod hash;
+pub mod id;
+#[cfg(feature = "cbor")]
+pub mod inspect;
 #[cfg(feature = "rusty-leveldb")]
 pub mod leveldb;
+#[cfg(feature = "lmdb")]
+pub mod lmdb;
+#[cfg(feature = "cbor")]
+pub mod log;
 pub mod node;
+pub mod payload;
+pub mod payload_index;
 pub mod prelude;
+#[cfg(feature = "redb")]
+pub mod redb;
 #[cfg(feature = "rocksdb")]
 pub mod rocksdb;
+#[cfg(feature = "schema")]
+pub mod schema;
+#[cfg(feature = "sha2")]
+pub mod sha2;
+#[cfg(feature = "signatures")]
+pub mod signature;
+#[cfg(feature = "sled")]
+pub mod sled;
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
 pub mod store;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "cbor")]
+pub mod trace;
+#[cfg(feature = "xxhash")]
+pub mod xxhash;
 
 #[cfg(test)]
 mod test;
 
 #[cfg(all(test, feature = "proptest"))]
 mod proptest;
+
+#[cfg(all(test, feature = "cbor"))]
+mod size_regression;
@@ -0,0 +1,348 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Module implementing a [Store] interface using LMDB through heed for a
+//! [Merkle Dag](crate::dag::Merkle). Requires the `lmdb` feature to be enabled.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::{
+    hash::HashWriter,
+    inspect::{BackendKind, StoreMeta, META_KEY},
+    node::Node,
+    store::{PersistedRoots, Result as StoreResult, Store, StoreError, ROOTS_KEY},
+};
+
+use ciborium;
+use heed::{types::Bytes, Database, Env, EnvOpenOptions, MdbError, RoTxn, RwTxn};
+
+pub type Result<T> = std::result::Result<T, heed::Error>;
+
+type Table = Database<Bytes, Bytes>;
+
+/// A [Store] implementation using LMDB. Reads decode [nodes](Node) straight from the memory
+/// map. Every write is its own write transaction except for [Store::store_many] which writes
+/// all of its nodes in one.
+pub struct LmdbStore {
+    env: Env,
+    // The cbor encoded nodes keyed by id.
+    nodes: Table,
+    // The metadata block and the persisted roots.
+    meta_table: Table,
+    // The nodes moved aside by Store::quarantine_foreign_keys keyed by their original id.
+    quarantine: Table,
+    meta: StoreMeta,
+}
+
+impl LmdbStore {
+    /// Open the environment in the directory at `path` creating both if they don't exist. The
+    /// environment can grow to `map_size` bytes after which writes fail with
+    /// [StoreError::StoreFull]. The files must not be modified by anything but LMDB while the
+    /// store is open.
+    pub fn open<P: AsRef<Path>>(path: P, map_size: usize) -> Result<Self> {
+        std::fs::create_dir_all(path.as_ref())?;
+        // SAFETY: heed can't rule out other processes truncating the memory mapped files
+        // under us. Callers are told not to let anything but LMDB touch them.
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(map_size)
+                .max_dbs(3)
+                .open(path)?
+        };
+        let mut txn = env.write_txn()?;
+        let nodes = env.create_database(&mut txn, Some("nodes"))?;
+        let meta_table: Table = env.create_database(&mut txn, Some("meta"))?;
+        let quarantine = env.create_database(&mut txn, Some("quarantine"))?;
+        let existing = meta_table.get(&txn, META_KEY)?.map(<[u8]>::to_vec);
+        txn.commit()?;
+        let me = Self {
+            env,
+            nodes,
+            meta_table,
+            quarantine,
+            meta: StoreMeta::reopen(BackendKind::Lmdb, existing.as_deref()),
+        };
+        me.write_meta()?;
+        Ok(me)
+    }
+
+    fn write_meta(&self) -> Result<()> {
+        self.write(|txn| self.meta_table.put(txn, META_KEY, &self.meta.encode()))
+    }
+
+    // Runs node writes in one write transaction recording the hash algorithm with the first
+    // of them. Returns the metadata block to keep once the transaction committed.
+    fn write_nodes<HW, F>(&self, writes: F) -> Result<Option<StoreMeta>>
+    where
+        HW: HashWriter,
+        F: FnOnce(&mut RwTxn) -> Result<()>,
+    {
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        self.write(|txn| {
+            writes(txn)?;
+            match &pending {
+                Some(meta) => self.meta_table.put(txn, META_KEY, &meta.encode()),
+                None => Ok(()),
+            }
+        })?;
+        Ok(pending)
+    }
+
+    fn keep_meta(&mut self, pending: Option<StoreMeta>) {
+        if let Some(meta) = pending {
+            self.meta = meta;
+        }
+    }
+
+    // Runs the writes in one write transaction committing it if they succeed.
+    fn write<F>(&self, writes: F) -> Result<()>
+    where
+        F: FnOnce(&mut RwTxn) -> Result<()>,
+    {
+        let mut txn = self.env.write_txn()?;
+        writes(&mut txn)?;
+        txn.commit()
+    }
+
+    fn put_nodes<HW, I>(&self, txn: &mut RwTxn, nodes: I) -> Result<()>
+    where
+        HW: HashWriter,
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let mut buf = Vec::new();
+        for node in nodes {
+            buf.clear();
+            ciborium::ser::into_writer(&node, &mut buf).unwrap();
+            self.nodes.put(txn, node.id(), &buf)?;
+        }
+        Ok(())
+    }
+
+    // LMDB rejects empty keys so they are never stored and are looked up as absent.
+    fn lookup<'t>(&self, txn: &'t RoTxn, table: &Table, id: &[u8]) -> Result<Option<&'t [u8]>> {
+        if id.is_empty() {
+            return Ok(None);
+        }
+        table.get(txn, id)
+    }
+
+    /// The metadata block of this store.
+    pub fn meta(&self) -> &StoreMeta {
+        &self.meta
+    }
+
+    /// The LMDB environment, for example to [resize](Env::resize) its memory map.
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+}
+
+impl<HW> Store<HW> for LmdbStore
+where
+    HW: HashWriter,
+{
+    fn contains(&self, id: &[u8]) -> StoreResult<bool> {
+        let txn = self.env.read_txn()?;
+        Ok(self.lookup(&txn, &self.nodes, id)?.is_some())
+    }
+
+    fn get(&self, id: &[u8]) -> StoreResult<Option<Node<HW>>> {
+        let txn = self.env.read_txn()?;
+        self.lookup(&txn, &self.nodes, id)?.map(decode).transpose()
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> StoreResult<Vec<Option<Node<HW>>>> {
+        let txn = self.env.read_txn()?;
+        ids.iter()
+            .map(|id| self.lookup(&txn, &self.nodes, id)?.map(decode).transpose())
+            .collect()
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> StoreResult<Vec<bool>> {
+        let txn = self.env.read_txn()?;
+        ids.iter()
+            .map(|id| Ok(self.lookup(&txn, &self.nodes, id)?.is_some()))
+            .collect()
+    }
+
+    fn get_raw(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        let txn = self.env.read_txn()?;
+        Ok(self.lookup(&txn, &self.nodes, id)?.map(<[u8]>::to_vec))
+    }
+
+    fn store(&mut self, node: Node<HW>) -> StoreResult<()> {
+        self.store_many([node])
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> StoreResult<()> {
+        let pending = self.write_nodes::<HW, _>(|txn| self.nodes.put(txn, node.id(), &encoded))?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> StoreResult<()> {
+        let pending = self.write_nodes::<HW, _>(|txn| {
+            for (node, encoded) in &records {
+                self.nodes.put(txn, node.id(), encoded)?;
+            }
+            match roots {
+                Some(roots) => self.meta_table.put(txn, ROOTS_KEY, &roots.encode()),
+                None => Ok(()),
+            }
+        })?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let pending = self.write_nodes::<HW, _>(|txn| self.put_nodes(txn, nodes))?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> StoreResult<()> {
+        self.store_many_with_roots([node], roots)
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let pending = self.write_nodes::<HW, _>(|txn| {
+            self.put_nodes(txn, nodes)?;
+            self.meta_table.put(txn, ROOTS_KEY, &roots.encode())
+        })?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> StoreResult<()> {
+        self.write(|txn| self.meta_table.put(txn, ROOTS_KEY, &roots.encode()))?;
+        Ok(())
+    }
+
+    fn persisted_roots(&self) -> StoreResult<Option<PersistedRoots>> {
+        let txn = self.env.read_txn()?;
+        self.meta_table
+            .get(&txn, ROOTS_KEY)?
+            .map(PersistedRoots::decode)
+            .transpose()
+    }
+
+    fn check_hash_algorithm(&self) -> StoreResult<()> {
+        self.meta.check_hash_algorithm::<HW>()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> StoreResult<()> {
+        self.write(|txn| self.nodes.delete(txn, id).map(|_| ()))?;
+        Ok(())
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> StoreResult<Vec<Vec<u8>>> {
+        let txn = self.env.read_txn()?;
+        let mut ids = Vec::new();
+        if prefix.is_empty() {
+            for entry in self.nodes.iter(&txn)?.take(limit) {
+                ids.push(entry?.0.to_vec());
+            }
+        } else {
+            for entry in self.nodes.prefix_iter(&txn, prefix)?.take(limit) {
+                ids.push(entry?.0.to_vec());
+            }
+        }
+        Ok(ids)
+    }
+
+    fn ids(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Vec<u8>>> + '_>> {
+        // The iterator can't outlive the read transaction so the ids are read up front.
+        let txn = self.env.read_txn()?;
+        let mut ids = Vec::new();
+        for entry in self.nodes.iter(&txn)? {
+            let (key, _) = entry?;
+            ids.push(Ok(key.to_vec()));
+        }
+        Ok(Box::new(ids.into_iter()))
+    }
+
+    fn len(&self) -> StoreResult<usize> {
+        let txn = self.env.read_txn()?;
+        Ok(self.nodes.len(&txn)? as usize)
+    }
+
+    fn key_length_histogram(&self) -> StoreResult<BTreeMap<usize, u64>> {
+        let txn = self.env.read_txn()?;
+        let mut histogram = BTreeMap::new();
+        for entry in self.nodes.iter(&txn)? {
+            let (key, _) = entry?;
+            *histogram.entry(key.len()).or_insert(0) += 1;
+        }
+        Ok(histogram)
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> StoreResult<u64> {
+        let mut moved = 0;
+        self.write(|txn| {
+            let mut foreign = Vec::new();
+            for entry in self.nodes.iter(txn)? {
+                let (key, val) = entry?;
+                if key.len() != expected_len {
+                    foreign.push((key.to_vec(), val.to_vec()));
+                }
+            }
+            for (key, val) in foreign {
+                self.quarantine.put(txn, &key, &val)?;
+                self.nodes.delete(txn, &key)?;
+                moved += 1;
+            }
+            Ok(())
+        })?;
+        self.meta.options.insert("quarantine".to_owned());
+        self.meta.record_maintenance();
+        self.write_meta()?;
+        Ok(moved)
+    }
+
+    fn flush(&mut self) -> StoreResult<()> {
+        self.env.force_sync()?;
+        Ok(())
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        let txn = self.env.read_txn()?;
+        Ok(self.lookup(&txn, &self.quarantine, id)?.map(<[u8]>::to_vec))
+    }
+}
+
+fn decode<HW: HashWriter>(bytes: &[u8]) -> StoreResult<Node<HW>> {
+    ciborium::de::from_reader(bytes)
+        .map_err(|e| StoreError::StoreFailure(format!("Invalid serialization {:?}", e)))
+}
+
+impl From<heed::Error> for StoreError {
+    fn from(err: heed::Error) -> Self {
+        match err {
+            heed::Error::Mdb(MdbError::MapFull) => StoreError::StoreFull(
+                "The LMDB map is full. Reopen the store with a larger map size.".to_owned(),
+            ),
+            err => StoreError::StoreFailure(format!("{}", err)),
+        }
+    }
+}
@@ -0,0 +1,497 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Module implementing a [Store] interface as a single append only log file for a
+//! [Merkle Dag](crate::dag::Merkle). Requires the `cbor` feature to be enabled.
+//!
+//! Every record in the log is the little endian `u32` length of a key, the key, the little
+//! endian `u32` length of the value and the value. Nodes are keyed by their id and their value
+//! is their cbor encoding. A value length of [u32::MAX] with no value marks a deleted key.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    hash::HashWriter,
+    inspect::{BackendKind, StoreMeta, META_KEY},
+    node::Node,
+    store::{PersistedRoots, Result, Store, StoreError, ROOTS_KEY},
+};
+
+use ciborium;
+
+/// Keys in the quarantine keyspace of a [LogStore] are the original id behind this prefix.
+pub const QUARANTINE_PREFIX: &[u8] = b"\0merkle-dag/quarantine/";
+
+const TOMBSTONE: u32 = u32::MAX;
+
+#[cfg(test)]
+thread_local! {
+    static FAIL_APPEND_AFTER: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+// Makes the next append of this thread fail after writing `bytes` bytes of it.
+#[cfg(test)]
+pub(crate) fn fail_next_append_after(bytes: usize) {
+    FAIL_APPEND_AFTER.with(|fail| fail.set(Some(bytes)));
+}
+
+fn write_log(file: &mut File, buf: &[u8]) -> io::Result<()> {
+    #[cfg(test)]
+    if let Some(bytes) = FAIL_APPEND_AFTER.with(|fail| fail.take()) {
+        file.write_all(&buf[..bytes.min(buf.len())])?;
+        return Err(io::Error::other("injected append failure"));
+    }
+    file.write_all(buf)
+}
+
+// The offset and length of the value of the latest record of every live key.
+type Index = BTreeMap<Vec<u8>, (u64, u32)>;
+
+/// A [Store] implementation appending every write to a single log file. An index of where
+/// the latest record of every key is in the log is kept in memory and rebuilt by replaying
+/// the log when it is opened.
+///
+/// Nothing in the log is ever overwritten so superseded and deleted records pile up until
+/// [LogStore::compact] rewrites the log without them.
+pub struct LogStore {
+    path: PathBuf,
+    file: RefCell<File>,
+    index: Index,
+    // The length of the log.
+    end: u64,
+    // The records in the log that a compaction would drop.
+    stale: u64,
+    sync_writes: bool,
+    meta: StoreMeta,
+}
+
+impl LogStore {
+    /// Open the log at `path` creating it if it doesn't exist. A partial record at the end of
+    /// the log, left behind by a crash in the middle of an append, is truncated away.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let (index, end, stale) = replay(&mut file)?;
+        if end < file.metadata()?.len() {
+            file.set_len(end)?;
+            file.sync_all()?;
+        }
+        let mut me = Self {
+            path,
+            file: RefCell::new(file),
+            index,
+            end,
+            stale,
+            sync_writes: false,
+            meta: StoreMeta::new(BackendKind::Log),
+        };
+        let existing = me.read_key(META_KEY)?;
+        me.meta = StoreMeta::reopen(BackendKind::Log, existing.as_deref());
+        me.write_meta()?;
+        Ok(me)
+    }
+
+    /// Sync the log to disk after every append. Without this a crash can lose the latest
+    /// writes until [Store::flush] is called but never leaves the log inconsistent.
+    pub fn with_sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    /// The metadata block of this store.
+    pub fn meta(&self) -> &StoreMeta {
+        &self.meta
+    }
+
+    /// The path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The number of superseded and deleted records in the log that [LogStore::compact]
+    /// would drop.
+    pub fn stale_records(&self) -> u64 {
+        self.stale
+    }
+
+    /// Rewrites the log keeping only the latest record of every live key. The new log is
+    /// written next to the old one and renamed over it so a crash during compaction leaves
+    /// the old log intact. Returns the number of bytes reclaimed.
+    pub fn compact(&mut self) -> Result<u64> {
+        // The metadata is updated first so the compacted log carries it.
+        self.meta.record_maintenance();
+        self.write_meta()?;
+        let mut compacting = self.path.clone().into_os_string();
+        compacting.push(".compacting");
+        let compacting = PathBuf::from(compacting);
+        let mut out = BufWriter::new(File::create(&compacting)?);
+        let mut index = BTreeMap::new();
+        let mut end = 0;
+        for (key, (offset, len)) in self.index.iter() {
+            let value = self.read(*offset, *len)?;
+            let record = encode_record(key, Some(&value));
+            out.write_all(&record)?;
+            index.insert(key.clone(), (end + 8 + key.len() as u64, *len));
+            end += record.len() as u64;
+        }
+        out.into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+        fs::rename(&compacting, &self.path)?;
+        *self.file.get_mut() = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        let reclaimed = self.end - end;
+        self.index = index;
+        self.end = end;
+        self.stale = 0;
+        Ok(reclaimed)
+    }
+
+    fn write_meta(&mut self) -> Result<()> {
+        let meta = self.meta.encode();
+        self.append(&[(META_KEY, Some(&meta))])
+    }
+
+    // Appends node records recording the hash algorithm in the same append as the first of
+    // them.
+    fn append_nodes<HW: HashWriter>(&mut self, mut records: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        if let Some(meta) = &pending {
+            records.insert(0, (META_KEY.to_vec(), meta.encode()));
+        }
+        self.append(&record_refs(&records))?;
+        if let Some(meta) = pending {
+            self.meta = meta;
+        }
+        Ok(())
+    }
+
+    // Appends the records in a single write. A `None` value deletes the key.
+    fn append(&mut self, records: &[(&[u8], Option<&[u8]>)]) -> Result<()> {
+        let mut buf = Vec::new();
+        let mut positions = Vec::with_capacity(records.len());
+        for (key, value) in records {
+            let value_offset = self.end + buf.len() as u64 + 8 + key.len() as u64;
+            buf.extend_from_slice(&encode_record(key, *value));
+            positions.push(value_offset);
+        }
+        let file = self.file.get_mut();
+        let written = write_log(file, &buf).and_then(|_| match self.sync_writes {
+            true => file.sync_data(),
+            false => Ok(()),
+        });
+        if let Err(err) = written {
+            // Whatever part of the append reached the log is cut off again so a later append
+            // doesn't follow a torn record and a reopen doesn't replay a failed write.
+            file.set_len(self.end)?;
+            return Err(err.into());
+        }
+        self.end += buf.len() as u64;
+        for ((key, value), value_offset) in records.iter().zip(positions) {
+            let replaced = match value {
+                Some(value) => self
+                    .index
+                    .insert(key.to_vec(), (value_offset, value.len() as u32)),
+                None => {
+                    // The tombstone itself is stale as soon as it is written.
+                    self.stale += 1;
+                    self.index.remove(*key)
+                }
+            };
+            if replaced.is_some() {
+                self.stale += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&self, offset: u64, len: u32) -> Result<Vec<u8>> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut value = vec![0; len as usize];
+        file.read_exact(&mut value)?;
+        Ok(value)
+    }
+
+    fn read_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key) {
+            Some((offset, len)) => Ok(Some(self.read(*offset, *len)?)),
+            None => Ok(None),
+        }
+    }
+
+    // The node ids in the index in ascending order.
+    fn node_ids(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.index.keys().filter(|key| !is_reserved(key))
+    }
+}
+
+impl<HW> Store<HW> for LogStore
+where
+    HW: HashWriter,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        Ok(self.index.contains_key(id))
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        self.read_key(id)?.map(|bytes| decode(&bytes)).transpose()
+    }
+
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read_key(id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.store_many([node])
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        self.append_nodes::<HW>(vec![(node.id().to_vec(), encoded)])?;
+        Ok(())
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        let mut records: Vec<(Vec<u8>, Vec<u8>)> = records
+            .into_iter()
+            .map(|(node, encoded)| (node.id().to_vec(), encoded))
+            .collect();
+        if let Some(roots) = roots {
+            records.push((ROOTS_KEY.to_vec(), roots.encode()));
+        }
+        self.append_nodes::<HW>(records)?;
+        Ok(())
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.append_nodes::<HW>(encode_nodes(nodes))?;
+        Ok(())
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.store_many_with_roots([node], roots)
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        // The roots go last in the same append so a torn append never leaves roots behind
+        // that reference missing nodes.
+        let mut records = encode_nodes(nodes);
+        records.push((ROOTS_KEY.to_vec(), roots.encode()));
+        self.append_nodes::<HW>(records)?;
+        Ok(())
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.append(&[(ROOTS_KEY, Some(&roots.encode()))])
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.read_key(ROOTS_KEY)?
+            .map(|bytes| PersistedRoots::decode(&bytes))
+            .transpose()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.meta.check_hash_algorithm::<HW>()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        if self.index.contains_key(id) {
+            self.append(&[(id, None)])?;
+        }
+        Ok(())
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .index
+            .range(prefix.to_vec()..)
+            .map(|(id, _)| id)
+            .take_while(|id| id.starts_with(prefix))
+            .filter(|id| !is_reserved(id))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        Ok(Box::new(self.node_ids().cloned().map(Ok)))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.node_ids().count())
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        let mut histogram = BTreeMap::new();
+        for id in self.node_ids() {
+            *histogram.entry(id.len()).or_insert(0) += 1;
+        }
+        Ok(histogram)
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        let foreign: Vec<Vec<u8>> = self
+            .node_ids()
+            .filter(|id| id.len() != expected_len)
+            .cloned()
+            .collect();
+        let mut records = Vec::new();
+        for id in foreign.iter() {
+            let value = self.read_key(id)?.unwrap();
+            records.push((quarantine_key(id), Some(value)));
+            records.push((id.clone(), None));
+        }
+        let refs: Vec<(&[u8], Option<&[u8]>)> = records
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_deref()))
+            .collect();
+        self.append(&refs)?;
+        self.meta.options.insert("quarantine".to_owned());
+        self.meta.record_maintenance();
+        self.write_meta()?;
+        Ok(foreign.len() as u64)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.get_mut().sync_data()?;
+        Ok(())
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read_key(&quarantine_key(id))
+    }
+}
+
+// Rebuilds the index from the log. Returns the index, the length of the complete records and
+// the number of stale records. Anything after the last complete record is a torn append.
+fn replay(file: &mut File) -> Result<(Index, u64, u64)> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file);
+    let mut index = BTreeMap::new();
+    let (mut end, mut stale) = (0, 0);
+    while let Some(key_len) = read_len(&mut reader)? {
+        let mut key = vec![0; key_len as usize];
+        if !read_full(&mut reader, &mut key)? {
+            break;
+        }
+        let len = match read_len(&mut reader)? {
+            Some(len) => len,
+            None => break,
+        };
+        let value_offset = end + 8 + key.len() as u64;
+        if len == TOMBSTONE {
+            stale += 1;
+            if index.remove(&key).is_some() {
+                stale += 1;
+            }
+            end = value_offset;
+            continue;
+        }
+        let skipped = io::copy(&mut (&mut reader).take(len as u64), &mut io::sink())?;
+        if skipped < len as u64 {
+            break;
+        }
+        if index.insert(key, (value_offset, len)).is_some() {
+            stale += 1;
+        }
+        end = value_offset + len as u64;
+    }
+    Ok((index, end, stale))
+}
+
+fn read_len<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
+    let mut len = [0; 4];
+    Ok(read_full(reader, &mut len)?.then(|| u32::from_le_bytes(len)))
+}
+
+// Fills the buffer returning false if the log ends first.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn encode_record(key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+    let mut record = Vec::with_capacity(8 + key.len() + value.map_or(0, <[u8]>::len));
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(key);
+    match value {
+        Some(value) => {
+            record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            record.extend_from_slice(value);
+        }
+        None => record.extend_from_slice(&TOMBSTONE.to_le_bytes()),
+    }
+    record
+}
+
+fn encode_nodes<HW, I>(nodes: I) -> Vec<(Vec<u8>, Vec<u8>)>
+where
+    HW: HashWriter,
+    I: IntoIterator<Item = Node<HW>>,
+{
+    nodes
+        .into_iter()
+        .map(|node| {
+            let mut buf = Vec::new();
+            ciborium::ser::into_writer(&node, &mut buf).unwrap();
+            (node.id().to_vec(), buf)
+        })
+        .collect()
+}
+
+fn record_refs(records: &[(Vec<u8>, Vec<u8>)]) -> Vec<(&[u8], Option<&[u8]>)> {
+    records
+        .iter()
+        .map(|(key, value)| (key.as_slice(), Some(value.as_slice())))
+        .collect()
+}
+
+fn decode<HW: HashWriter>(bytes: &[u8]) -> Result<Node<HW>> {
+    ciborium::de::from_reader(bytes)
+        .map_err(|e| StoreError::StoreFailure(format!("Invalid serialization {:?}", e)))
+}
+
+// Keys that don't hold a node: the quarantine keyspace, the metadata block and the roots.
+fn is_reserved(key: &[u8]) -> bool {
+    key == META_KEY || key == ROOTS_KEY || key.starts_with(QUARANTINE_PREFIX)
+}
+
+fn quarantine_key(id: &[u8]) -> Vec<u8> {
+    let mut key = QUARANTINE_PREFIX.to_vec();
+    key.extend_from_slice(id);
+    key
+}
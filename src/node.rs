@@ -13,11 +13,22 @@
 // limitations under the License.
 //! [Node] type satisfying the properties necessary for a [Merkle Dag](crate::dag::Merkle).
 
-use std::{collections::BTreeSet, marker::PhantomData};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, Read};
+use std::marker::PhantomData;
+use std::sync::OnceLock;
 
+#[cfg(feature = "cbor")]
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use crate::hash::HashWriter;
+use crate::id::{hex, NodeId};
+#[cfg(feature = "cbor")]
+use crate::payload::DecodeError;
+
+pub use crate::depset::DepSet;
 
 // NOTE(jwall): Since we enforce certain properties by construction in our DAG
 // It's important that serialization isn't able to bypass that. This struct
@@ -26,15 +37,152 @@ use crate::hash::HashWriter;
 #[derive(Serialize, Deserialize)]
 struct NodeSerde {
     item: Vec<u8>,
-    dependency_ids: BTreeSet<Vec<u8>>,
+    dependency_ids: DepSet,
+    #[serde(default)]
+    id_version: NodeIdVersion,
+    #[serde(default)]
+    attributes: BTreeMap<String, Vec<u8>>,
+    // A detached node has no item to hash so its ids are taken as they were stored. Nothing
+    // decoded this way can be checked without the item so Node::validate refuses it.
+    #[serde(default)]
+    detached: bool,
+    #[serde(default)]
+    id: Vec<u8>,
+    #[serde(default)]
+    item_id: Vec<u8>,
+    #[serde(default)]
+    signature: Option<NodeSignature>,
 }
 
-impl<HW> From<NodeSerde> for Node<HW>
+impl<HW> TryFrom<NodeSerde> for Node<HW>
 where
     HW: HashWriter,
 {
-    fn from(ns: NodeSerde) -> Self {
-        Self::new(ns.item, ns.dependency_ids)
+    type Error = String;
+
+    fn try_from(ns: NodeSerde) -> Result<Self, Self::Error> {
+        // A dependency id of the wrong length can't be the id of a node in this DAG.
+        if let Some(dep) = ns
+            .dependency_ids
+            .iter()
+            .find(|dep| dep.len() != HW::OUTPUT_LEN)
+        {
+            return Err(format!(
+                "Malformed dependency id of {} bytes, expected {}",
+                dep.len(),
+                HW::OUTPUT_LEN
+            ));
+        }
+        let node = if ns.detached {
+            if !ns.item.is_empty() {
+                return Err("Detached node has an item".to_owned());
+            }
+            Self::from_detached_parts(
+                ns.id,
+                ns.item_id,
+                ns.dependency_ids,
+                ns.attributes,
+                ns.id_version,
+            )?
+        } else {
+            Self::new_with_attrs_and_id_version(
+                ns.item,
+                ns.dependency_ids,
+                ns.attributes,
+                ns.id_version,
+            )
+        };
+        Ok(match ns.signature {
+            Some(signature) => node.with_signature(signature),
+            None => node,
+        })
+    }
+}
+
+/// The signature of a [Node] by its author. It signs the id of the node so it covers the item,
+/// dependencies and attributes but isn't part of the id itself. The bytes are checked by a
+/// [SignaturePolicy](crate::signature::SignaturePolicy) with the `signatures` feature. Nodes
+/// keep their signatures through every [Store](crate::store::Store) with or without it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeSignature {
+    /// The public key of the author.
+    pub author: Vec<u8>,
+    /// The signature of the id by the author.
+    pub signature: Vec<u8>,
+}
+
+/// The way the id of a [Node] is computed. Every node records the version its id was computed
+/// with so DAGs holding nodes of both versions can be read. New nodes use
+/// [NodeIdVersion::LATEST]. The default is [NodeIdVersion::V0], the version of records written
+/// before the version was recorded.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum NodeIdVersion {
+    /// The item bytes followed by the dependency ids back to back. A payload ending in the
+    /// bytes of a dependency id can give the same id as a node with that dependency.
+    #[default]
+    V0,
+    /// Every field is recorded after a domain tag and its length so no two different nodes
+    /// hash the same bytes.
+    V1,
+}
+
+impl NodeIdVersion {
+    /// The version new nodes should use.
+    pub const LATEST: Self = NodeIdVersion::V1;
+
+    pub(crate) fn is_v0(&self) -> bool {
+        *self == NodeIdVersion::V0
+    }
+}
+
+impl From<NodeIdVersion> for u8 {
+    fn from(version: NodeIdVersion) -> Self {
+        match version {
+            NodeIdVersion::V0 => 0,
+            NodeIdVersion::V1 => 1,
+        }
+    }
+}
+
+impl TryFrom<u8> for NodeIdVersion {
+    type Error = String;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            0 => Ok(NodeIdVersion::V0),
+            1 => Ok(NodeIdVersion::V1),
+            version => Err(format!("Unknown node id version {}", version)),
+        }
+    }
+}
+
+// The domain tags recorded before the fields of a V1 node.
+const V1_ITEM_TAG: &[u8] = b"merkle-dag/v1/item";
+const V1_DEPENDENCY_TAG: &[u8] = b"merkle-dag/v1/dependency";
+// The domain tags recorded before the attributes of a node of any version.
+const ATTRIBUTE_KEY_TAG: &[u8] = b"merkle-dag/attribute/key";
+const ATTRIBUTE_VALUE_TAG: &[u8] = b"merkle-dag/attribute/value";
+
+// Records the domain tag and length of a field before the field is recorded.
+fn record_field_prefix<HW: HashWriter>(hw: &mut HW, tag: &[u8], len: usize) {
+    hw.record_bytes(tag);
+    hw.record_bytes(&(len as u64).to_le_bytes());
+}
+
+// A reader that keeps a copy of every byte read through it.
+struct Spool<'a, R> {
+    inner: &'a mut R,
+    spooled: &'a mut Vec<u8>,
+}
+
+impl<'a, R: Read> Read for Spool<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.spooled.extend_from_slice(&buf[..read]);
+        Ok(read)
     }
 }
 
@@ -48,8 +196,8 @@ where
 /// Nodes are tied to a specific implementation of the [HashWriter] trait which is itself tied
 /// to the DAG they are stored in guaranteeing that the same Hashing implementation is used
 /// for each node in the [Merkle DAG](crate::dag::Merkle).
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(from = "NodeSerde")]
+#[derive(PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "NodeSerde")]
 pub struct Node<HW>
 where
     HW: HashWriter,
@@ -57,8 +205,50 @@ where
     id: Vec<u8>,
     item: Vec<u8>,
     item_id: Vec<u8>,
-    dependency_ids: BTreeSet<Vec<u8>>,
+    dependency_ids: DepSet,
+    #[serde(skip)]
+    dependency_set: DependencySetCache,
     _phantom: PhantomData<HW>,
+    #[serde(skip_serializing_if = "NodeIdVersion::is_v0")]
+    id_version: NodeIdVersion,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: BTreeMap<String, Vec<u8>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    detached: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<NodeSignature>,
+}
+
+// The dependency ids as a BTreeSet built on the first call to Node::dependency_ids. It is
+// derived from the DepSet so it takes no part in comparisons.
+#[derive(Default)]
+struct DependencySetCache(OnceLock<BTreeSet<Vec<u8>>>);
+
+impl PartialEq for DependencySetCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for DependencySetCache {}
+
+impl<HW> fmt::Debug for Node<HW>
+where
+    HW: HashWriter,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("id", &self.id)
+            .field("item", &self.item)
+            .field("item_id", &self.item_id)
+            .field("dependency_ids", &self.dependency_ids)
+            .field("_phantom", &self._phantom)
+            .field("id_version", &self.id_version)
+            .field("attributes", &self.attributes)
+            .field("detached", &self.detached)
+            .field("signature", &self.signature)
+            .finish()
+    }
 }
 
 impl<HW> Clone for Node<HW>
@@ -71,7 +261,12 @@ where
             item: self.item.clone(),
             item_id: self.item_id.clone(),
             dependency_ids: self.dependency_ids.clone(),
+            dependency_set: DependencySetCache::default(),
             _phantom: PhantomData,
+            id_version: self.id_version,
+            attributes: self.attributes.clone(),
+            detached: self.detached,
+            signature: self.signature.clone(),
         }
     }
 }
@@ -80,48 +275,538 @@ impl<HW> Node<HW>
 where
     HW: HashWriter,
 {
-    /// Construct a new node with a payload and a set of dependency_ids.
-    pub fn new<P: Into<Vec<u8>>>(item: P, dependency_ids: BTreeSet<Vec<u8>>) -> Self {
-        let mut hw = HW::default();
+    /// Start a [NodeBuilder] for a new node.
+    pub fn builder() -> NodeBuilder<HW> {
+        NodeBuilder::new()
+    }
+
+    /// Construct a new node with a payload and a set of dependency_ids. The id is computed with
+    /// [NodeIdVersion::LATEST].
+    pub fn new<P: Into<Vec<u8>>, D: Into<DepSet>>(item: P, dependency_ids: D) -> Self {
+        Self::new_with_id_version(item, dependency_ids, NodeIdVersion::LATEST)
+    }
+
+    /// Construct a new node with a payload and a set of dependency_ids computing its id with
+    /// `id_version`.
+    pub fn new_with_id_version<P: Into<Vec<u8>>, D: Into<DepSet>>(
+        item: P,
+        dependency_ids: D,
+        id_version: NodeIdVersion,
+    ) -> Self {
+        Self::new_with_attrs_and_id_version(item, dependency_ids, BTreeMap::new(), id_version)
+    }
+
+    /// Construct a new node with a payload, a set of dependency_ids and metadata attributes.
+    /// The attributes are part of the id. The id is computed with [NodeIdVersion::LATEST].
+    pub fn new_with_attrs<P: Into<Vec<u8>>, D: Into<DepSet>>(
+        item: P,
+        dependency_ids: D,
+        attributes: BTreeMap<String, Vec<u8>>,
+    ) -> Self {
+        Self::new_with_attrs_and_id_version(item, dependency_ids, attributes, NodeIdVersion::LATEST)
+    }
+
+    /// Construct a new node with a payload, a set of dependency_ids and metadata attributes
+    /// computing its id with `id_version`.
+    pub fn new_with_attrs_and_id_version<P: Into<Vec<u8>>, D: Into<DepSet>>(
+        item: P,
+        dependency_ids: D,
+        attributes: BTreeMap<String, Vec<u8>>,
+        id_version: NodeIdVersion,
+    ) -> Self {
         let item = item.into();
-        // NOTE(jwall): The order here is important. Our reliable id creation must be stable
-        // for multiple calls to this constructor. This means that we must *always*
-        // 1. Record the `item_id` hash first.
-        hw.record(item.iter().cloned());
-        let item_id = hw.hash();
-        // 2. Sort the dependency ids before recording them into our node id hash.
-        let mut dependency_list = dependency_ids.iter().cloned().collect::<Vec<Vec<u8>>>();
-        dependency_list.sort();
-        // 3. record the dependency ids into our node id hash in the sorted order.
-        for d in dependency_list.iter() {
-            hw.record(d.iter().cloned());
+        let hw = record_item::<HW>(&item, id_version);
+        Self::with_recorded_item(hw, item, dependency_ids.into(), attributes, id_version)
+    }
+
+    /// Construct a new node with a payload read from `reader` and a set of dependency_ids.
+    /// The node has the same id as one constructed by [Node::new] from the same bytes and uses
+    /// [NodeIdVersion::LATEST]. Its ids record the length of the payload before the payload
+    /// so it is hashed once the reader is done. Use [Node::new_from_sized_reader] to hash it as
+    /// it is read.
+    pub fn new_from_reader<R: Read, D: Into<DepSet>>(
+        mut reader: R,
+        dependency_ids: D,
+    ) -> io::Result<Self> {
+        let mut item = Vec::new();
+        reader.read_to_end(&mut item)?;
+        Ok(Self::new(item, dependency_ids))
+    }
+
+    /// Construct a new node like [Node::new_from_reader] from exactly `len` bytes of `reader`
+    /// hashing the payload as it is read instead of after it was buffered. Fails with
+    /// [io::ErrorKind::UnexpectedEof] if the reader ends before `len` bytes.
+    pub fn new_from_sized_reader<R: Read, D: Into<DepSet>>(
+        reader: R,
+        len: u64,
+        dependency_ids: D,
+    ) -> io::Result<Self> {
+        let mut hw = HW::default();
+        record_field_prefix(&mut hw, V1_ITEM_TAG, len as usize);
+        let mut item = Vec::new();
+        hw.record_reader(&mut Spool {
+            inner: &mut reader.take(len),
+            spooled: &mut item,
+        })?;
+        if item.len() as u64 != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Payload ended after {} of {} bytes", item.len(), len),
+            ));
         }
+        Ok(Self::with_recorded_item(
+            hw,
+            item,
+            dependency_ids.into(),
+            BTreeMap::new(),
+            NodeIdVersion::V1,
+        ))
+    }
+
+    // Finishes a node from a hasher that has recorded exactly the bytes of `item`.
+    fn with_recorded_item(
+        hw: HW,
+        item: Vec<u8>,
+        dependency_ids: DepSet,
+        attributes: BTreeMap<String, Vec<u8>>,
+        id_version: NodeIdVersion,
+    ) -> Self {
+        let (item_id, id) = finish_ids(hw, &dependency_ids, &attributes, id_version);
         Self {
-            id: hw.hash(),
+            id,
             item,
             item_id,
             dependency_ids,
+            dependency_set: DependencySetCache::default(),
             _phantom: PhantomData,
+            id_version,
+            attributes,
+            detached: false,
+            signature: None,
+        }
+    }
+
+    // Rebuilds a detached node from its stored ids. They can't be checked without the item.
+    pub(crate) fn from_detached_parts<D: Into<DepSet>>(
+        id: Vec<u8>,
+        item_id: Vec<u8>,
+        dependency_ids: D,
+        attributes: BTreeMap<String, Vec<u8>>,
+        id_version: NodeIdVersion,
+    ) -> Result<Self, String> {
+        if id.len() != HW::OUTPUT_LEN || item_id.len() != HW::OUTPUT_LEN {
+            return Err(format!(
+                "Malformed detached node ids of {} and {} bytes, expected {}",
+                id.len(),
+                item_id.len(),
+                HW::OUTPUT_LEN
+            ));
         }
+        Ok(Self {
+            id,
+            item: Vec::new(),
+            item_id,
+            dependency_ids: dependency_ids.into(),
+            dependency_set: DependencySetCache::default(),
+            _phantom: PhantomData,
+            id_version,
+            attributes,
+            detached: true,
+            signature: None,
+        })
+    }
+
+    /// Split the item off this node. The detached node keeps the id and item id so it can be
+    /// stored and traversed like the original while the item is kept apart, for example in a
+    /// [BlobStore](crate::store::BlobStore) under the item id.
+    pub fn into_detached(mut self) -> (Self, Vec<u8>) {
+        self.detached = true;
+        let item = std::mem::take(&mut self.item);
+        (self, item)
     }
 
     pub fn id(&self) -> &[u8] {
         &self.id
     }
 
+    /// The id of this node as a [NodeId].
+    pub fn node_id(&self) -> NodeId {
+        NodeId::from(self.id.as_slice())
+    }
+
+    /// The payload item. It is empty for a [detached](Node::is_detached) node.
     pub fn item(&self) -> &[u8] {
         &self.item
     }
 
+    /// Whether the item was split off this node with [Node::into_detached]. Only the
+    /// [item id](Node::item_id) of a detached node identifies its item.
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    /// Decode the item as a `T` from the cbor encoding written by
+    /// [Merkle::add_item](crate::dag::Merkle::add_item). Requires the `cbor` feature.
+    #[cfg(feature = "cbor")]
+    pub fn item_as<T: DeserializeOwned>(&self) -> Result<T, DecodeError> {
+        ciborium::de::from_reader(self.item.as_slice())
+            .map_err(|e| DecodeError(format!("Invalid item {:?}", e)))
+    }
+
     pub fn item_id(&self) -> &[u8] {
         &self.item_id
     }
 
     pub fn dependency_ids(&self) -> &BTreeSet<Vec<u8>> {
+        self.dependency_set
+            .0
+            .get_or_init(|| self.dependency_ids.iter().cloned().collect())
+    }
+
+    /// The dependency ids of this node in the [DepSet] it keeps them in. Unlike
+    /// [Node::dependency_ids] this doesn't allocate.
+    pub fn dep_set(&self) -> &DepSet {
         &self.dependency_ids
     }
 
+    /// The dependency ids of this node as [NodeIds](NodeId).
+    pub fn dependency_node_ids(&self) -> BTreeSet<NodeId> {
+        self.dependency_ids
+            .iter()
+            .map(|id| NodeId::from(id.as_slice()))
+            .collect()
+    }
+
+    /// The metadata attributes of this node. They are part of its id.
+    pub fn attributes(&self) -> &BTreeMap<String, Vec<u8>> {
+        &self.attributes
+    }
+
+    /// Attach a [NodeSignature] replacing any attached before. The id doesn't change.
+    pub fn with_signature(mut self, signature: NodeSignature) -> Self {
+        self.signature = Some(signature);
+        self
+    }
+
+    /// The [NodeSignature] of this node if it is signed.
+    pub fn signature(&self) -> Option<&NodeSignature> {
+        self.signature.as_ref()
+    }
+
+    /// The [NodeIdVersion] the id of this node was computed with.
+    pub fn id_version(&self) -> NodeIdVersion {
+        self.id_version
+    }
+
     pub fn out_degree(&self) -> usize {
         self.dependency_ids.len()
     }
+
+    /// Hash the item, dependency ids and attributes again with a fresh `HW` and check they
+    /// give the recorded item id and id. Nodes are built consistent so this only fails for
+    /// nodes that were damaged or put together by hand. A [detached](Node::is_detached) node
+    /// has no item to hash so it fails with [NodeIntegrityError::DetachedItem] once the
+    /// lengths of its ids are checked. Check it with [Node::validate_item] instead.
+    pub fn validate(&self) -> Result<(), NodeIntegrityError> {
+        self.check_dependencies()?;
+        if self.detached {
+            self.check_detached_ids()?;
+            return Err(NodeIntegrityError::DetachedItem {
+                id: self.id.clone(),
+            });
+        }
+        self.check_ids(&self.item)
+    }
+
+    /// [Validate](Node::validate) the node as if it had `item` for its item. Use it to check
+    /// a [detached](Node::is_detached) node against the item kept apart from it.
+    pub fn validate_item(&self, item: &[u8]) -> Result<(), NodeIntegrityError> {
+        self.check_dependencies()?;
+        if self.detached {
+            self.check_detached_ids()?;
+        }
+        self.check_ids(item)
+    }
+
+    fn check_dependencies(&self) -> Result<(), NodeIntegrityError> {
+        if let Some(dependency) = self
+            .dependency_ids
+            .iter()
+            .find(|dep| dep.len() != HW::OUTPUT_LEN)
+        {
+            return Err(NodeIntegrityError::MalformedDependency {
+                id: self.id.clone(),
+                dependency: dependency.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    fn check_detached_ids(&self) -> Result<(), NodeIntegrityError> {
+        if self.id.len() != HW::OUTPUT_LEN || self.item_id.len() != HW::OUTPUT_LEN {
+            return Err(NodeIntegrityError::MalformedDetachedIds {
+                id: self.id.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    // Hashes `item` with the dependency ids and attributes of this node and compares the
+    // result with the recorded ids.
+    fn check_ids(&self, item: &[u8]) -> Result<(), NodeIntegrityError> {
+        let hw = record_item::<HW>(item, self.id_version);
+        let (item_id, id) = finish_ids(hw, &self.dependency_ids, &self.attributes, self.id_version);
+        if item_id != self.item_id {
+            return Err(NodeIntegrityError::ItemIdMismatch {
+                id: self.id.clone(),
+                recorded: self.item_id.clone(),
+                computed: item_id,
+            });
+        }
+        if id != self.id {
+            return Err(NodeIntegrityError::IdMismatch {
+                recorded: self.id.clone(),
+                computed: id,
+            });
+        }
+        Ok(())
+    }
+
+    /// [Validate](Node::validate) the node and check that it is the node with `expected_id`.
+    pub fn validate_against(&self, expected_id: &[u8]) -> Result<(), NodeIntegrityError> {
+        if self.id != expected_id {
+            return Err(NodeIntegrityError::UnexpectedId {
+                expected: expected_id.to_vec(),
+                found: self.id.clone(),
+            });
+        }
+        self.validate()
+    }
+
+    // Damages a node so tests can check that the damage is detected.
+    #[cfg(test)]
+    pub(crate) fn id_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.id
+    }
+
+    #[cfg(test)]
+    pub(crate) fn item_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.item
+    }
+
+    #[cfg(test)]
+    pub(crate) fn item_id_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.item_id
+    }
+
+    #[cfg(test)]
+    pub(crate) fn dependency_ids_mut(&mut self) -> &mut DepSet {
+        self.dependency_set = DependencySetCache::default();
+        &mut self.dependency_ids
+    }
+
+    #[cfg(test)]
+    pub(crate) fn attributes_mut(&mut self) -> &mut BTreeMap<String, Vec<u8>> {
+        &mut self.attributes
+    }
+
+    #[cfg(test)]
+    pub(crate) fn id_version_mut(&mut self) -> &mut NodeIdVersion {
+        &mut self.id_version
+    }
+}
+
+// Starts the hash of a node by recording its item.
+fn record_item<HW: HashWriter>(item: &[u8], id_version: NodeIdVersion) -> HW {
+    let mut hw = HW::default();
+    if id_version == NodeIdVersion::V1 {
+        record_field_prefix(&mut hw, V1_ITEM_TAG, item.len());
+    }
+    hw.record_bytes(item);
+    hw
+}
+
+// Finishes the hash of a node from a hasher that has recorded exactly the bytes of its item
+// and returns the item id and the id.
+fn finish_ids<HW: HashWriter>(
+    mut hw: HW,
+    dependency_ids: &DepSet,
+    attributes: &BTreeMap<String, Vec<u8>>,
+    id_version: NodeIdVersion,
+) -> (Vec<u8>, Vec<u8>) {
+    // NOTE(jwall): The order here is important. Our reliable id creation must be stable
+    // for multiple calls to this constructor. This means that we must *always*
+    // 1. Record the `item_id` hash first. The callers have recorded the item already.
+    let item_id = hw.hash();
+    // 2. record the dependency ids into our node id hash in sorted order. A DepSet
+    // always iterates in sorted order.
+    for d in dependency_ids.iter() {
+        if id_version == NodeIdVersion::V1 {
+            record_field_prefix(&mut hw, V1_DEPENDENCY_TAG, d.len());
+        }
+        hw.record_bytes(d);
+    }
+    // 3. record the attributes sorted by key. Nodes without attributes record nothing
+    // here so their ids don't depend on this step.
+    for (key, value) in attributes.iter() {
+        record_field_prefix(&mut hw, ATTRIBUTE_KEY_TAG, key.len());
+        hw.record_bytes(key.as_bytes());
+        record_field_prefix(&mut hw, ATTRIBUTE_VALUE_TAG, value.len());
+        hw.record_bytes(value);
+    }
+    (item_id, hw.hash())
+}
+
+/// Why [Node::validate] or [Node::validate_against] rejected a [Node].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeIntegrityError {
+    /// A dependency id of the node with this id doesn't have the length of the ids of the
+    /// [HashWriter].
+    MalformedDependency { id: Vec<u8>, dependency: Vec<u8> },
+    /// The ids of the detached node with this id don't have the length of the ids of the
+    /// [HashWriter].
+    MalformedDetachedIds { id: Vec<u8> },
+    /// The node with this id is [detached](Node::is_detached) so its ids can't be checked
+    /// without its item. Check it with [Node::validate_item].
+    DetachedItem { id: Vec<u8> },
+    /// The item of the node with this id doesn't hash to its recorded item id.
+    ItemIdMismatch {
+        id: Vec<u8>,
+        recorded: Vec<u8>,
+        computed: Vec<u8>,
+    },
+    /// The item id, dependency ids and attributes don't hash to the recorded id.
+    IdMismatch {
+        recorded: Vec<u8>,
+        computed: Vec<u8>,
+    },
+    /// The node isn't the one that was expected.
+    UnexpectedId { expected: Vec<u8>, found: Vec<u8> },
+}
+
+// Ids are rendered as hex.
+impl fmt::Display for NodeIntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeIntegrityError::MalformedDependency { id, dependency } => write!(
+                f,
+                "node {} has a malformed dependency id {}",
+                hex(id),
+                hex(dependency)
+            ),
+            NodeIntegrityError::MalformedDetachedIds { id } => {
+                write!(f, "detached node {} has malformed ids", hex(id))
+            }
+            NodeIntegrityError::DetachedItem { id } => {
+                write!(
+                    f,
+                    "detached node {} can't be checked without its item",
+                    hex(id)
+                )
+            }
+            NodeIntegrityError::ItemIdMismatch {
+                id,
+                recorded,
+                computed,
+            } => write!(
+                f,
+                "item of node {} hashes to {} not the recorded item id {}",
+                hex(id),
+                hex(computed),
+                hex(recorded)
+            ),
+            NodeIntegrityError::IdMismatch { recorded, computed } => {
+                write!(f, "node {} hashes to {}", hex(recorded), hex(computed))
+            }
+            NodeIntegrityError::UnexpectedId { expected, found } => {
+                write!(f, "expected node {} found {}", hex(expected), hex(found))
+            }
+        }
+    }
+}
+
+impl std::error::Error for NodeIntegrityError {}
+
+/// Builds a [Node] from its payload, dependency ids and attributes. Dependency ids added more
+/// than once are kept once. A builder without a [payload](NodeBuilder::payload) builds a node
+/// with an empty item. The id is computed with [NodeIdVersion::LATEST] unless another
+/// [version](NodeBuilder::id_version) is set.
+pub struct NodeBuilder<HW>
+where
+    HW: HashWriter,
+{
+    pub(crate) item: Vec<u8>,
+    pub(crate) dependency_ids: BTreeSet<Vec<u8>>,
+    pub(crate) attributes: BTreeMap<String, Vec<u8>>,
+    id_version: NodeIdVersion,
+    _phantom: PhantomData<HW>,
+}
+
+impl<HW> Default for NodeBuilder<HW>
+where
+    HW: HashWriter,
+{
+    fn default() -> Self {
+        Self {
+            item: Vec::new(),
+            dependency_ids: BTreeSet::new(),
+            attributes: BTreeMap::new(),
+            id_version: NodeIdVersion::LATEST,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<HW> NodeBuilder<HW>
+where
+    HW: HashWriter,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the payload item replacing any set before.
+    pub fn payload<N: Into<Vec<u8>>>(mut self, item: N) -> Self {
+        self.item = item.into();
+        self
+    }
+
+    /// Add a dependency id.
+    pub fn depends_on<D: AsRef<[u8]>>(mut self, id: D) -> Self {
+        self.dependency_ids.insert(id.as_ref().to_vec());
+        self
+    }
+
+    /// Add every dependency id of `ids`.
+    pub fn depends_on_all<D, I>(mut self, ids: I) -> Self
+    where
+        D: AsRef<[u8]>,
+        I: IntoIterator<Item = D>,
+    {
+        self.dependency_ids
+            .extend(ids.into_iter().map(|id| id.as_ref().to_vec()));
+        self
+    }
+
+    /// Set a metadata attribute replacing the value of the key if it was set before.
+    pub fn attribute<K: Into<String>, V: Into<Vec<u8>>>(mut self, key: K, value: V) -> Self {
+        self.attributes.insert(key.into(), value.into());
+        self
+    }
+
+    /// Compute the id with `id_version`.
+    pub fn id_version(mut self, id_version: NodeIdVersion) -> Self {
+        self.id_version = id_version;
+        self
+    }
+
+    /// Build the [Node].
+    pub fn build(self) -> Node<HW> {
+        Node::new_with_attrs_and_id_version(
+            self.item,
+            self.dependency_ids,
+            self.attributes,
+            self.id_version,
+        )
+    }
 }
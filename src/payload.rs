@@ -0,0 +1,115 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Traits converting the payload type of a [Merkle DAG](crate::dag::Merkle) to and from the
+//! bytes stored as the items of its [nodes](crate::node::Node).
+use std::fmt;
+
+#[cfg(feature = "cbor")]
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The error returned when a payload can't be encoded as the item of a [Node](crate::node::Node).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodeError(pub String);
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// The error returned when the item of a [Node](crate::node::Node) isn't the encoding of the
+/// requested type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError(pub String);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A payload that can be stored as the item of a [Node](crate::node::Node). The encoding must
+/// be deterministic since equal payloads have to encode to the same bytes to get the same id.
+pub trait ByteEncoder {
+    /// Encode the payload into the bytes of an item.
+    fn encode_bytes(&self) -> Result<Vec<u8>, EncodeError>;
+}
+
+/// A payload that can be read back from the item of a [Node](crate::node::Node).
+pub trait ByteDecoder: Sized {
+    /// Decode the payload from the bytes of an item.
+    fn decode_bytes(bytes: &[u8]) -> Result<Self, DecodeError>;
+}
+
+impl ByteEncoder for Vec<u8> {
+    fn encode_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        Ok(self.clone())
+    }
+}
+
+impl ByteDecoder for Vec<u8> {
+    fn decode_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl ByteEncoder for &[u8] {
+    fn encode_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        Ok(self.to_vec())
+    }
+}
+
+impl ByteEncoder for String {
+    fn encode_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        Ok(self.as_bytes().to_vec())
+    }
+}
+
+impl ByteDecoder for String {
+    fn decode_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        String::from_utf8(bytes.to_vec()).map_err(|e| DecodeError(format!("Invalid item {}", e)))
+    }
+}
+
+impl ByteEncoder for &str {
+    fn encode_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        Ok(self.as_bytes().to_vec())
+    }
+}
+
+/// Adapts any serde type into a payload stored as its canonical cbor encoding. See
+/// [encode_item](crate::store::codec::encode_item). Requires the `cbor` feature.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+pub struct Cbor<T>(pub T);
+
+#[cfg(feature = "cbor")]
+impl<T: Serialize> ByteEncoder for Cbor<T> {
+    fn encode_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        crate::store::codec::encode_item(&self.0)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl<T: DeserializeOwned> ByteDecoder for Cbor<T> {
+    fn decode_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        ciborium::de::from_reader(bytes)
+            .map(Cbor)
+            .map_err(|e| DecodeError(format!("Invalid item {:?}", e)))
+    }
+}
@@ -0,0 +1,492 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Inverted indexes over [Node] payloads for finding nodes by the terms in their payload.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    dag::{CachedValue, Merkle, NodeHandle},
+    hash::HashWriter,
+    node::Node,
+    store::{PersistedRoots, Result, Store},
+};
+
+#[cfg(feature = "cbor")]
+use crate::store::StoreStats;
+
+/// Breaks a [Node] payload into the terms it is indexed under.
+pub trait PayloadIndexer {
+    /// The distinct terms in the payload.
+    fn terms(&self, payload: &[u8]) -> Vec<Vec<u8>>;
+}
+
+/// A [PayloadIndexer] that indexes the ASCII lowercased whitespace separated words of a payload.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WhitespaceIndexer;
+
+impl PayloadIndexer for WhitespaceIndexer {
+    fn terms(&self, payload: &[u8]) -> Vec<Vec<u8>> {
+        let terms: BTreeSet<Vec<u8>> = payload
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_ascii_lowercase())
+            .collect();
+        terms.into_iter().collect()
+    }
+}
+
+/// A [PayloadIndexer] that indexes every `n` byte window of a payload. Payloads shorter
+/// than `n` are indexed as a single term.
+#[derive(Clone, Copy, Debug)]
+pub struct NgramIndexer {
+    pub n: usize,
+}
+
+impl PayloadIndexer for NgramIndexer {
+    fn terms(&self, payload: &[u8]) -> Vec<Vec<u8>> {
+        if payload.is_empty() {
+            return Vec::new();
+        }
+        if payload.len() < self.n {
+            return vec![payload.to_vec()];
+        }
+        let terms: BTreeSet<Vec<u8>> = payload.windows(self.n.max(1)).map(|w| w.to_vec()).collect();
+        terms.into_iter().collect()
+    }
+}
+
+/// How [Merkle::search_all] combines the results for multiple terms.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SearchMode {
+    /// Match nodes with any of the terms.
+    AnyOf,
+    /// Match only nodes with all of the terms.
+    AllOf,
+}
+
+/// A [Store] that maintains an inverted index of the terms in its [Node] payloads.
+pub trait PayloadSearch<HW>: Store<HW>
+where
+    HW: HashWriter,
+{
+    /// Find up to `limit` ids of nodes indexed under the `term` in ascending order.
+    fn search_term(&self, term: &[u8], limit: usize) -> Result<Vec<Vec<u8>>>;
+
+    /// Every term in the index with the ids indexed under it.
+    fn payload_index_entries(&self) -> Result<BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>>;
+
+    /// Turn index maintenance on or off for subsequent stores. Turning it off is useful for
+    /// bulk imports followed by [PayloadSearch::rebuild_payload_index].
+    fn set_payload_indexing(&mut self, enabled: bool);
+
+    /// Discard the index and rebuild it from every node in the [Store].
+    fn rebuild_payload_index(&mut self) -> Result<()>;
+}
+
+/// A [Store] wrapper maintaining an in memory payload index for any inner [Store].
+///
+/// The index starts out empty. Nodes already in the inner [Store] are indexed by
+/// [PayloadSearch::rebuild_payload_index] which requires an inner [Store] that supports
+/// [Store::ids]. Index changes made inside a batch are undone by [Store::rollback_batch].
+pub struct PayloadIndexStore<S, I> {
+    inner: S,
+    indexer: I,
+    indexing: bool,
+    // The terms every indexed id is indexed under so it can be unindexed without a scan.
+    terms: BTreeMap<Vec<u8>, Vec<Vec<u8>>>,
+    index: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+    // The index changes of the open batch.
+    batch: Option<Vec<IndexChange>>,
+}
+
+// An index change to undo if the batch it was made in is rolled back.
+enum IndexChange {
+    Indexed(Vec<u8>),
+    Unindexed(Vec<u8>, Vec<Vec<u8>>),
+}
+
+impl<S, I> PayloadIndexStore<S, I>
+where
+    I: PayloadIndexer,
+{
+    /// Wrap a [Store] indexing payloads with the `indexer`.
+    pub fn new(inner: S, indexer: I) -> Self {
+        Self {
+            inner,
+            indexer,
+            indexing: true,
+            terms: BTreeMap::new(),
+            index: BTreeMap::new(),
+            batch: None,
+        }
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn index_payload(&mut self, id: &[u8], payload: &[u8]) {
+        // Equal ids have equal payloads so an indexed id is left alone.
+        if self.terms.contains_key(id) {
+            return;
+        }
+        let terms = self.indexer.terms(payload);
+        self.insert_terms(id, terms);
+        if let Some(batch) = self.batch.as_mut() {
+            batch.push(IndexChange::Indexed(id.to_vec()));
+        }
+    }
+
+    fn insert_terms(&mut self, id: &[u8], terms: Vec<Vec<u8>>) {
+        for term in terms.iter() {
+            self.index
+                .entry(term.clone())
+                .or_default()
+                .insert(id.to_vec());
+        }
+        self.terms.insert(id.to_vec(), terms);
+    }
+
+    // Removes the id from the index returning the terms it was indexed under.
+    fn remove_terms(&mut self, id: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let terms = self.terms.remove(id)?;
+        for term in terms.iter() {
+            if let Some(ids) = self.index.get_mut(term) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.index.remove(term);
+                }
+            }
+        }
+        Some(terms)
+    }
+
+    fn unindex(&mut self, id: &[u8]) {
+        if let Some(terms) = self.remove_terms(id) {
+            if let Some(batch) = self.batch.as_mut() {
+                batch.push(IndexChange::Unindexed(id.to_vec(), terms));
+            }
+        }
+    }
+
+    // The ids and, while indexing, the payloads of nodes about to be stored.
+    fn staged_items<HW: HashWriter>(&self, nodes: &[Node<HW>]) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        nodes
+            .iter()
+            .map(|node| {
+                let item = Some(node.item().to_vec()).filter(|_| self.indexing);
+                (node.id().to_vec(), item)
+            })
+            .collect()
+    }
+
+    fn index_items(&mut self, items: Vec<(Vec<u8>, Option<Vec<u8>>)>) {
+        for (id, item) in items {
+            if let Some(item) = item {
+                self.index_payload(&id, &item);
+            }
+        }
+    }
+}
+
+impl<S, I> Default for PayloadIndexStore<S, I>
+where
+    S: Default,
+    I: PayloadIndexer + Default,
+{
+    fn default() -> Self {
+        Self::new(S::default(), I::default())
+    }
+}
+
+impl<HW, S, I> Store<HW> for PayloadIndexStore<S, I>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+    I: PayloadIndexer,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        self.inner.contains(id)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        self.inner.contains_many(ids)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        self.inner.get(id)
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        self.inner.get_many(ids)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_raw(id)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        self.inner.get_handle(id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        let items = self.staged_items(std::slice::from_ref(&node));
+        self.inner.store(node)?;
+        self.index_items(items);
+        Ok(())
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        let items = self.staged_items(std::slice::from_ref(&node));
+        self.inner.store_encoded(node, encoded)?;
+        self.index_items(items);
+        Ok(())
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        let items = records
+            .iter()
+            .map(|(node, _)| {
+                let item = Some(node.item().to_vec()).filter(|_| self.indexing);
+                (node.id().to_vec(), item)
+            })
+            .collect();
+        self.inner.store_many_encoded(records, roots)?;
+        self.index_items(items);
+        Ok(())
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        let items = self.staged_items(std::slice::from_ref(&node));
+        self.inner.store_with_roots(node, roots)?;
+        self.index_items(items);
+        Ok(())
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.inner.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn store_many<It>(&mut self, nodes: It) -> Result<()>
+    where
+        It: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let items = self.staged_items(&nodes);
+        self.inner.store_many(nodes)?;
+        self.index_items(items);
+        Ok(())
+    }
+
+    fn store_many_with_roots<It>(&mut self, nodes: It, roots: &PersistedRoots) -> Result<()>
+    where
+        It: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let items = self.staged_items(&nodes);
+        self.inner.store_many_with_roots(nodes, roots)?;
+        self.index_items(items);
+        Ok(())
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.inner.delete(id)?;
+        self.unindex(id);
+        Ok(())
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.inner.children_of(id)
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        let moved = self.inner.quarantine_foreign_keys(expected_len)?;
+        let foreign: Vec<Vec<u8>> = self
+            .terms
+            .keys()
+            .filter(|id| id.len() != expected_len)
+            .cloned()
+            .collect();
+        for id in foreign {
+            self.unindex(&id);
+        }
+        Ok(moved)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.inner.begin_batch()?;
+        self.batch = Some(Vec::new());
+        Ok(())
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.inner.commit_batch()?;
+        self.batch = None;
+        Ok(())
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.inner.rollback_batch()?;
+        for change in self.batch.take().unwrap_or_default().into_iter().rev() {
+            match change {
+                IndexChange::Indexed(id) => {
+                    self.remove_terms(&id);
+                }
+                IndexChange::Unindexed(id, terms) => self.insert_terms(&id, terms),
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<HW, S, I> PayloadSearch<HW> for PayloadIndexStore<S, I>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+    I: PayloadIndexer,
+{
+    fn search_term(&self, term: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        Ok(match self.index.get(term) {
+            Some(ids) => ids.iter().take(limit).cloned().collect(),
+            None => Vec::new(),
+        })
+    }
+
+    fn payload_index_entries(&self) -> Result<BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>> {
+        Ok(self.index.clone())
+    }
+
+    fn set_payload_indexing(&mut self, enabled: bool) {
+        self.indexing = enabled;
+    }
+
+    fn rebuild_payload_index(&mut self) -> Result<()> {
+        let ids = Store::<HW>::ids(&self.inner)?.collect::<Result<Vec<Vec<u8>>>>()?;
+        let mut terms = BTreeMap::new();
+        for id in ids {
+            if let Some(node) = Store::<HW>::get(&self.inner, &id)? {
+                terms.insert(id, self.indexer.terms(node.item()));
+            }
+        }
+        self.terms.clear();
+        self.index.clear();
+        for (id, terms) in terms {
+            self.insert_terms(&id, terms);
+        }
+        Ok(())
+    }
+}
+
+impl<S, HW, P> Merkle<S, HW, P>
+where
+    HW: HashWriter,
+    S: PayloadSearch<HW>,
+{
+    /// Find up to `limit` ids of nodes whose payload contains the `term` in ascending order.
+    pub fn search_payloads(&self, term: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.get_nodes().search_term(term, limit)
+    }
+
+    /// Turn payload index maintenance on or off for subsequently added nodes.
+    pub fn set_payload_indexing(&mut self, enabled: bool) {
+        self.nodes_mut().set_payload_indexing(enabled)
+    }
+
+    /// Rebuild the payload index from every node in the [Store]. Use this after adding nodes
+    /// with indexing turned off.
+    pub fn rebuild_payload_index(&mut self) -> Result<()> {
+        self.nodes_mut().rebuild_payload_index()
+    }
+
+    /// Find up to `limit` ids of nodes whose payload contains any or all of the `terms`
+    /// depending on the [SearchMode] in ascending order.
+    pub fn search_all(
+        &self,
+        terms: &[&[u8]],
+        mode: SearchMode,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut result: Option<BTreeSet<Vec<u8>>> = None;
+        for term in terms {
+            let ids: BTreeSet<Vec<u8>> = self
+                .get_nodes()
+                .search_term(term, usize::MAX)?
+                .into_iter()
+                .collect();
+            result = Some(match (result, mode) {
+                (None, _) => ids,
+                (Some(acc), SearchMode::AnyOf) => acc.union(&ids).cloned().collect(),
+                (Some(acc), SearchMode::AllOf) => acc.intersection(&ids).cloned().collect(),
+            });
+        }
+        Ok(result.unwrap_or_default().into_iter().take(limit).collect())
+    }
+}
@@ -12,6 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //! Exports the most common set of types for this crate.
+#[cfg(feature = "blake3")]
+pub use crate::blake3::{Blake3, DerivedBlake3, KeyContext, KeyedBlake3};
 pub use crate::dag::*;
+#[cfg(feature = "encryption")]
+pub use crate::encryption::{EncryptionMode, PayloadKey};
+pub use crate::hash::siphash::SipHash24;
 pub use crate::hash::*;
+pub use crate::id::{NodeId, ParseIdError};
 pub use crate::node::*;
+pub use crate::payload::*;
+#[cfg(feature = "sha2")]
+pub use crate::sha2::{Sha256, Sha512};
+#[cfg(feature = "signatures")]
+pub use crate::signature::{SignaturePolicy, SigningKey, VerifyingKey};
+pub use crate::store::{BTreeStore, HashStore};
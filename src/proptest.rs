@@ -11,13 +11,19 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use std::collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
 
 use proptest::prelude::*;
 
 use crate::prelude::*;
+use crate::store::{BTreeStore, ReverseIndexStore, Store};
+use crate::test::TestHasher;
+#[cfg(feature = "xxhash")]
+use crate::xxhash::Xxh3_64;
 
-type TestDag = Merkle<BTreeMap<Vec<u8>, Node<DefaultHasher>>, DefaultHasher>;
+type TestDag = Merkle<BTreeStore<TestHasher>, TestHasher>;
+type IndexedTestDag = Merkle<ReverseIndexStore<BTreeStore<TestHasher>>, TestHasher>;
 
 fn simple_edge_strategy(
     nodes_count: usize,
@@ -33,14 +39,20 @@ fn simple_edge_strategy(
     })
 }
 
-fn complex_dag_strategy(
+// Builds DAGs of `depth` layers hashed with `HW`. Pick a fast `HW` like
+// [Xxh3_64](crate::xxhash::Xxh3_64) when hash strength doesn't matter to the property.
+fn complex_dag_strategy<S, HW>(
     nodes_count: usize,
     depth: usize,
     branch: usize,
-) -> impl Strategy<Value = TestDag> {
+) -> impl Strategy<Value = Merkle<S, HW>>
+where
+    HW: HashWriter + Clone + Debug,
+    S: Store<HW> + Default + Clone + Debug,
+{
     prop::collection::vec(".*", depth..nodes_count).prop_flat_map(move |payloads| {
         let nodes_len = payloads.len();
-        let mut dag = TestDag::new(BTreeMap::new());
+        let mut dag = Merkle::<S, HW>::new(S::default());
         // partition the payloads into depth pieces
         let mut id_stack: Vec<Vec<u8>> = Vec::new();
         for chunk in payloads.chunks(nodes_len / depth) {
@@ -82,11 +94,7 @@ proptest! {
                 let node_id = dag.add_node(n.as_bytes(), BTreeSet::new()).unwrap();
                 node_set.insert(node_id.clone());
                 let parent = idx % parent_count;
-                if dependents.contains_key(&parent) {
-                    dependents.get_mut(&parent).map(|v: &mut BTreeSet<Vec<u8>>| v.insert(node_id));
-                } else {
-                    dependents.insert(parent, BTreeSet::from([node_id]));
-                }
+                dependents.entry(parent).or_insert_with(BTreeSet::new).insert(node_id);
             }
         }
         for (pidx, dep_ids) in dependents {
@@ -94,56 +102,161 @@ proptest! {
             node_set.insert(node_id.clone());
         }
         assert!(dag.get_roots().len() <= parent_count);
-        assert!(dag.get_nodes().len() == node_set.len());
+        assert!(dag.node_count().unwrap() == node_set.len());
+    }
+}
+
+fn check_complex_dag_node_properties<HW: HashWriter>(dag: Merkle<BTreeStore<HW>, HW>) {
+    // TODO(jwall): We can assert much more about the Merkle if we get more clever in what we return.
+    dag.assert_invariants(Thoroughness::Full).unwrap();
+    let nodes = dag.get_nodes();
+    assert!(nodes.len() <= 100);
+
+    let roots = dag.get_roots();
+    assert!(roots.len() < dag.node_count().unwrap());
+
+    for node_id in nodes.keys() {
+        let mut is_descendant = false;
+        if roots.contains(node_id) {
+            continue;
+        }
+        for root in roots.iter() {
+            if let NodeCompare::After = dag.compare(root, node_id).unwrap() {
+                // success
+                is_descendant = true;
+            }
+        }
+        assert!(is_descendant);
+    }
+    // Check that every root node is uncomparable.
+    for left_root in roots.iter() {
+        for right_root in roots.iter() {
+            if left_root != right_root {
+                assert_eq!(
+                    dag.compare(left_root, right_root).unwrap(),
+                    NodeCompare::Uncomparable
+                );
+            }
+        }
     }
 }
 
 proptest! {
     #[test]
-    fn test_complex_dag_node_properties(dag in complex_dag_strategy(100, 10, 3)) {
-        // TODO(jwall): We can assert much more about the Merkle if we get more clever in what we return.
-        let nodes = dag.get_nodes();
-        assert!(nodes.len() <= 100);
+    fn test_complex_dag_node_properties(dag in complex_dag_strategy::<BTreeStore<TestHasher>, TestHasher>(100, 10, 3)) {
+        check_complex_dag_node_properties(dag);
+    }
+}
 
-        let roots = dag.get_roots();
-        assert!(roots.len() < dag.get_nodes().len());
+#[cfg(feature = "xxhash")]
+proptest! {
+    #[test]
+    fn test_xxh3_complex_dag_node_properties(dag in complex_dag_strategy::<BTreeStore<Xxh3_64>, Xxh3_64>(100, 10, 3)) {
+        check_complex_dag_node_properties(dag);
+    }
+}
 
-        for node_id in nodes.keys() {
-            let mut is_descendant = false;
-            if roots.contains(node_id) {
-                continue;
+fn check_traversal_direction_duality<HW: HashWriter>(
+    dag: Merkle<ReverseIndexStore<BTreeStore<HW>>, HW>,
+) {
+    let ids: Vec<Vec<u8>> = dag.get_nodes().inner().keys().cloned().collect();
+    for x in ids.iter() {
+        let ancestors = dag.reachable(x, Direction::Up).unwrap();
+        for y in ids.iter() {
+            let descendants = dag.reachable(y, Direction::Down).unwrap();
+            assert_eq!(ancestors.contains(y), descendants.contains(x));
+        }
+        for y in ancestors.iter() {
+            let up_path = dag
+                .path_between_directed(x, y, Direction::Up)
+                .unwrap()
+                .unwrap();
+            let mut down_path = dag
+                .path_between_directed(y, x, Direction::Down)
+                .unwrap()
+                .unwrap();
+            assert_eq!(up_path.len(), down_path.len());
+            assert_eq!(up_path.first(), Some(x));
+            assert_eq!(up_path.last(), Some(y));
+            for pair in up_path.windows(2) {
+                assert!(dag
+                    .get_node_by_id(&pair[0])
+                    .unwrap()
+                    .unwrap()
+                    .dependency_ids()
+                    .contains(&pair[1]));
             }
-            for root in roots.iter() {
-                if let NodeCompare::After = dag.compare(root, node_id).unwrap() {
-                    // success
-                    is_descendant = true;
-                }
+            down_path.reverse();
+            for pair in down_path.windows(2) {
+                assert!(dag
+                    .get_node_by_id(&pair[0])
+                    .unwrap()
+                    .unwrap()
+                    .dependency_ids()
+                    .contains(&pair[1]));
             }
-            assert!(is_descendant);
         }
-        // Check that every root node is uncomparable.
-        for left_root in roots.iter() {
-            for right_root in roots.iter() {
-                if left_root != right_root {
-                    assert_eq!(dag.compare(left_root, right_root).unwrap(), NodeCompare::Uncomparable);
-                }
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_traversal_direction_duality(dag in complex_dag_strategy::<ReverseIndexStore<BTreeStore<TestHasher>>, TestHasher>(50, 5, 3)) {
+        let dag: IndexedTestDag = dag;
+        check_traversal_direction_duality(dag);
+    }
+}
+
+#[cfg(feature = "xxhash")]
+proptest! {
+    #[test]
+    fn test_xxh3_traversal_direction_duality(dag in complex_dag_strategy::<ReverseIndexStore<BTreeStore<Xxh3_64>>, Xxh3_64>(50, 5, 3)) {
+        check_traversal_direction_duality(dag);
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_retain_marked_matches_strict_frontier(strict in complex_dag_strategy::<BTreeStore<TestHasher>, TestHasher>(50, 5, 3)) {
+        let mut strict: TestDag = strict;
+        let mut retained = strict.clone();
+        retained.set_root_policy(std::sync::Arc::new(RetainMarked));
+        let ids: Vec<Vec<u8>> = strict.get_nodes().keys().cloned().collect();
+        for id in ids.iter().step_by(2) {
+            retained.mark_sticky_root(id).unwrap();
+        }
+        let heads = strict.get_roots().clone();
+        let head = strict.add_node("head", heads.clone()).unwrap();
+        assert_eq!(retained.add_node("head", heads).unwrap(), head);
+        assert_eq!(retained.frontier_roots(), strict.frontier_roots());
+        assert_eq!(
+            retained.announced_roots(),
+            strict.announced_roots().union(retained.sticky_roots()).cloned().collect()
+        );
+        for left in ids.iter() {
+            for right in ids.iter() {
+                assert_eq!(retained.compare(left, right).unwrap(), strict.compare(left, right).unwrap());
             }
         }
+        for id in ids.iter() {
+            retained.unmark_sticky_root(id).unwrap();
+        }
+        assert_eq!(retained.announced_roots(), strict.announced_roots());
     }
 }
 
 #[cfg(feature = "cbor")]
 proptest! {
     #[test]
-    fn test_node_serde_strategy(dag in complex_dag_strategy(100, 10, 3)) {
+    fn test_node_serde_strategy(dag in complex_dag_strategy::<BTreeStore<TestHasher>, TestHasher>(100, 10, 3)) {
         use ciborium::{de::from_reader, ser::into_writer};
 
         let nodes = dag.get_nodes();
-        for (_, node) in nodes {
+        for node in nodes.values() {
             let node = node.clone();
             let mut buf: Vec<u8> = Vec::new();
             into_writer(&node, &mut buf).unwrap();
-            let node_de: Node<DefaultHasher> = from_reader(buf.as_slice()).unwrap();
+            let node_de: Node<TestHasher> = from_reader(buf.as_slice()).unwrap();
             assert_eq!(node.id(), node_de.id());
             assert_eq!(node.item_id(), node_de.item_id());
             assert_eq!(node.item(), node_de.item());
@@ -151,3 +264,163 @@ proptest! {
         }
     }
 }
+
+proptest! {
+    #[test]
+    fn test_dep_set_matches_btree_set(
+        ids in prop::collection::vec(prop::collection::vec(0u8..4, 0..3), 0..12),
+        probe in prop::collection::vec(0u8..4, 0..3),
+    ) {
+        let mut deps = DepSet::new();
+        let mut set = BTreeSet::new();
+        for id in ids.iter() {
+            prop_assert_eq!(set.insert(id.clone()), deps.insert(id.clone()));
+            prop_assert_eq!(set.len(), deps.len());
+        }
+        prop_assert!(set.iter().eq(deps.iter()));
+        prop_assert!(set.iter().rev().eq(deps.iter().rev()));
+        prop_assert_eq!(set.contains(&probe), deps.contains(&probe));
+        prop_assert_eq!(&deps, &ids.iter().cloned().collect::<DepSet>());
+        prop_assert_eq!(&deps, &DepSet::from(set.clone()));
+        prop_assert_eq!(set, BTreeSet::from(deps));
+    }
+}
+
+// Counts the allocations made on the current thread so the tests don't disturb each other.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static COUNTING_ALLOC: CountingAlloc = CountingAlloc;
+
+fn count_allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(|count| count.get());
+    f();
+    ALLOCATIONS.with(|count| count.get()) - before
+}
+
+proptest! {
+    #[test]
+    fn test_dep_set_allocates_less_than_btree_set(dag in complex_dag_strategy::<BTreeStore<TestHasher>, TestHasher>(100, 10, 1)) {
+        let mut dep_set_allocs = 0;
+        let mut btree_set_allocs = 0;
+        for node in dag.get_nodes().values() {
+            let deps = node.dep_set();
+            let set: BTreeSet<Vec<u8>> = deps.iter().cloned().collect();
+            dep_set_allocs += count_allocations(|| drop(deps.clone()));
+            btree_set_allocs += count_allocations(|| drop(set.clone()));
+        }
+        // Every node past the first layer has a single dependency which is stored inline.
+        prop_assert!(dep_set_allocs < btree_set_allocs);
+    }
+}
+
+// A single step of a replica convergence scenario.
+#[derive(Clone, Debug)]
+enum ReplicaOp {
+    // Add a node to the replica depending on the nodes it knows at the picked indexes.
+    Add {
+        replica: usize,
+        payload: u8,
+        deps: Vec<prop::sample::Index>,
+    },
+    // Send the nodes the receiving replica is missing.
+    Sync {
+        from: usize,
+        to: usize,
+    },
+}
+
+const REPLICAS: usize = 4;
+
+fn replica_op_strategy() -> impl Strategy<Value = ReplicaOp> {
+    prop_oneof![
+        (
+            0..REPLICAS,
+            any::<u8>(),
+            prop::collection::vec(any::<prop::sample::Index>(), 0..3)
+        )
+            .prop_map(|(replica, payload, deps)| ReplicaOp::Add {
+                replica,
+                payload,
+                deps
+            }),
+        (0..REPLICAS, 0..REPLICAS).prop_map(|(from, to)| ReplicaOp::Sync { from, to }),
+    ]
+}
+
+// Every id reachable from the roots of the DAG.
+fn graph_ids(dag: &TestDag) -> BTreeSet<Vec<u8>> {
+    let mut ids = dag.get_roots().clone();
+    for root in dag.get_roots().iter() {
+        ids.extend(dag.ancestors_of(root).unwrap());
+    }
+    ids
+}
+
+fn sync_replicas(from: &TestDag, to: &mut TestDag) {
+    for batch in from.missing(to.get_roots().clone()) {
+        for node in batch.unwrap() {
+            let id = to
+                .add_node(node.item(), node.dependency_ids().clone())
+                .unwrap();
+            assert_eq!(id.as_slice(), node.id());
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+    #[test]
+    fn test_replicas_converge(ops in prop::collection::vec(replica_op_strategy(), 0..40)) {
+        let mut replicas: Vec<TestDag> = (0..REPLICAS).map(|_| TestDag::new(BTreeMap::new())).collect();
+        let mut created = BTreeSet::new();
+        for op in ops {
+            match op {
+                ReplicaOp::Add { replica, payload, deps } => {
+                    let known: Vec<Vec<u8>> = replicas[replica].get_nodes().keys().cloned().collect();
+                    let deps = if known.is_empty() {
+                        BTreeSet::new()
+                    } else {
+                        deps.iter().map(|i| i.get(&known).clone()).collect()
+                    };
+                    created.insert(replicas[replica].add_node(vec![payload], deps).unwrap());
+                }
+                ReplicaOp::Sync { from, to } => {
+                    if from != to {
+                        let sender = replicas[from].clone();
+                        sync_replicas(&sender, &mut replicas[to]);
+                    }
+                }
+            }
+        }
+        for from in 0..REPLICAS {
+            for to in 0..REPLICAS {
+                if from != to {
+                    let sender = replicas[from].clone();
+                    sync_replicas(&sender, &mut replicas[to]);
+                }
+            }
+        }
+        for replica in replicas.iter() {
+            replica.assert_invariants(Thoroughness::Full).unwrap();
+            prop_assert_eq!(replica.get_roots(), replicas[0].get_roots());
+            prop_assert_eq!(&graph_ids(replica), &created);
+            prop_assert!(replica.get_nodes().keys().eq(created.iter()));
+        }
+    }
+}
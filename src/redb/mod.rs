@@ -0,0 +1,370 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Module implementing a [Store] interface using redb for a [Merkle Dag](crate::dag::Merkle).
+//! Requires the `redb` feature to be enabled.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::{
+    hash::HashWriter,
+    inspect::{BackendKind, StoreMeta, META_KEY},
+    node::Node,
+    store::{PersistedRoots, Result as StoreResult, Store, StoreError, ROOTS_KEY},
+};
+
+use ciborium;
+use redb::{
+    backends::InMemoryBackend, Database, ReadableTable, ReadableTableMetadata, TableDefinition,
+    WriteTransaction,
+};
+
+// The cbor encoded nodes keyed by id.
+const NODES: TableDefinition<&[u8], &[u8]> = TableDefinition::new("nodes");
+// The metadata block and the persisted roots.
+const META: TableDefinition<&[u8], &[u8]> = TableDefinition::new("meta");
+// The nodes moved aside by Store::quarantine_foreign_keys keyed by their original id.
+const QUARANTINE: TableDefinition<&[u8], &[u8]> = TableDefinition::new("quarantine");
+
+/// A [Store] implementation using the pure Rust redb embedded database. Every write is its
+/// own redb write transaction and every read its own read transaction.
+pub struct RedbStore {
+    db: Database,
+    meta: StoreMeta,
+}
+
+impl RedbStore {
+    /// Open the database at the path creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<Self> {
+        Self::open_db(Database::create(path)?)
+    }
+
+    /// A database held in memory.
+    pub fn in_memory() -> StoreResult<Self> {
+        Self::open_db(Database::builder().create_with_backend(InMemoryBackend::new())?)
+    }
+
+    // Wraps the database creating the tables and refreshing the metadata block.
+    fn open_db(db: Database) -> StoreResult<Self> {
+        let txn = db.begin_write()?;
+        txn.open_table(NODES)?;
+        txn.open_table(QUARANTINE)?;
+        let existing = txn
+            .open_table(META)?
+            .get(META_KEY)?
+            .map(|bytes| bytes.value().to_vec());
+        txn.commit()?;
+        let me = Self {
+            db,
+            meta: StoreMeta::reopen(BackendKind::Redb, existing.as_deref()),
+        };
+        me.write_meta()?;
+        Ok(me)
+    }
+
+    fn write_meta(&self) -> StoreResult<()> {
+        self.write(|txn| {
+            txn.open_table(META)?
+                .insert(META_KEY, self.meta.encode().as_slice())?;
+            Ok(())
+        })
+    }
+
+    // Runs writes storing nodes recording the hash algorithm in the same transaction as the
+    // first of them.
+    fn write_nodes<HW, F>(&mut self, writes: F) -> StoreResult<()>
+    where
+        HW: HashWriter,
+        F: FnOnce(&WriteTransaction) -> StoreResult<()>,
+    {
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        self.write(|txn| {
+            writes(txn)?;
+            if let Some(meta) = &pending {
+                txn.open_table(META)?
+                    .insert(META_KEY, meta.encode().as_slice())?;
+            }
+            Ok(())
+        })?;
+        if let Some(meta) = pending {
+            self.meta = meta;
+        }
+        Ok(())
+    }
+
+    // Runs the writes in one write transaction committing it if they succeed.
+    fn write<F>(&self, writes: F) -> StoreResult<()>
+    where
+        F: FnOnce(&WriteTransaction) -> StoreResult<()>,
+    {
+        let txn = self.db.begin_write()?;
+        writes(&txn)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn get_bytes(
+        &self,
+        table: TableDefinition<&[u8], &[u8]>,
+        key: &[u8],
+    ) -> StoreResult<Option<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(table)?;
+        let bytes = table.get(key)?.map(|bytes| bytes.value().to_vec());
+        Ok(bytes)
+    }
+
+    /// The metadata block of this store.
+    pub fn meta(&self) -> &StoreMeta {
+        &self.meta
+    }
+
+    /// The underlying redb [Database].
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+}
+
+impl<HW> Store<HW> for RedbStore
+where
+    HW: HashWriter,
+{
+    fn contains(&self, id: &[u8]) -> StoreResult<bool> {
+        let txn = self.db.begin_read()?;
+        let found = txn.open_table(NODES)?.get(id)?.is_some();
+        Ok(found)
+    }
+
+    fn get(&self, id: &[u8]) -> StoreResult<Option<Node<HW>>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(NODES)?;
+        let node = match table.get(id)? {
+            Some(bytes) => Some(decode(bytes.value())?),
+            None => None,
+        };
+        Ok(node)
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> StoreResult<Vec<Option<Node<HW>>>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(NODES)?;
+        let mut nodes = Vec::with_capacity(ids.len());
+        for id in ids {
+            nodes.push(match table.get(*id)? {
+                Some(bytes) => Some(decode(bytes.value())?),
+                None => None,
+            });
+        }
+        Ok(nodes)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> StoreResult<Vec<bool>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(NODES)?;
+        let mut found = Vec::with_capacity(ids.len());
+        for id in ids {
+            found.push(table.get(*id)?.is_some());
+        }
+        Ok(found)
+    }
+
+    fn get_raw(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        self.get_bytes(NODES, id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> StoreResult<()> {
+        self.store_many([node])
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> StoreResult<()> {
+        self.write_nodes::<HW, _>(|txn| {
+            txn.open_table(NODES)?
+                .insert(node.id(), encoded.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> StoreResult<()> {
+        self.write_nodes::<HW, _>(|txn| {
+            let mut table = txn.open_table(NODES)?;
+            for (node, encoded) in &records {
+                table.insert(node.id(), encoded.as_slice())?;
+            }
+            if let Some(roots) = roots {
+                txn.open_table(META)?
+                    .insert(ROOTS_KEY, roots.encode().as_slice())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.write_nodes::<HW, _>(|txn| insert_nodes(txn, nodes))
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> StoreResult<()> {
+        self.store_many_with_roots([node], roots)
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.write_nodes::<HW, _>(|txn| {
+            insert_nodes(txn, nodes)?;
+            txn.open_table(META)?
+                .insert(ROOTS_KEY, roots.encode().as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> StoreResult<()> {
+        self.write(|txn| {
+            txn.open_table(META)?
+                .insert(ROOTS_KEY, roots.encode().as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn persisted_roots(&self) -> StoreResult<Option<PersistedRoots>> {
+        self.get_bytes(META, ROOTS_KEY)?
+            .map(|bytes| PersistedRoots::decode(&bytes))
+            .transpose()
+    }
+
+    fn check_hash_algorithm(&self) -> StoreResult<()> {
+        self.meta.check_hash_algorithm::<HW>()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> StoreResult<()> {
+        self.write(|txn| {
+            txn.open_table(NODES)?.remove(id)?;
+            Ok(())
+        })
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> StoreResult<Vec<Vec<u8>>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(NODES)?;
+        let mut ids = Vec::new();
+        for entry in table.range(prefix..)? {
+            let (key, _) = entry?;
+            if ids.len() == limit || !key.value().starts_with(prefix) {
+                break;
+            }
+            ids.push(key.value().to_vec());
+        }
+        Ok(ids)
+    }
+
+    fn ids(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Vec<u8>>> + '_>> {
+        let txn = self.db.begin_read()?;
+        let range = txn.open_table(NODES)?.range::<&[u8]>(..)?;
+        Ok(Box::new(range.map(|entry| {
+            let (key, _) = entry?;
+            Ok(key.value().to_vec())
+        })))
+    }
+
+    fn len(&self) -> StoreResult<usize> {
+        let txn = self.db.begin_read()?;
+        let len = txn.open_table(NODES)?.len()?;
+        Ok(len as usize)
+    }
+
+    fn key_length_histogram(&self) -> StoreResult<BTreeMap<usize, u64>> {
+        let mut histogram = BTreeMap::new();
+        for id in Store::<HW>::ids(self)? {
+            *histogram.entry(id?.len()).or_insert(0) += 1;
+        }
+        Ok(histogram)
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> StoreResult<u64> {
+        let mut moved = 0;
+        self.write(|txn| {
+            let mut nodes = txn.open_table(NODES)?;
+            let mut quarantine = txn.open_table(QUARANTINE)?;
+            let foreign = nodes.extract_if(|key, _| key.len() != expected_len)?;
+            for entry in foreign {
+                let (key, val) = entry?;
+                quarantine.insert(key.value(), val.value())?;
+                moved += 1;
+            }
+            Ok(())
+        })?;
+        self.meta.options.insert("quarantine".to_owned());
+        self.meta.record_maintenance();
+        self.write_meta()?;
+        Ok(moved)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        self.get_bytes(QUARANTINE, id)
+    }
+}
+
+fn insert_nodes<HW, I>(txn: &WriteTransaction, nodes: I) -> StoreResult<()>
+where
+    HW: HashWriter,
+    I: IntoIterator<Item = Node<HW>>,
+{
+    let mut table = txn.open_table(NODES)?;
+    let mut buf = Vec::new();
+    for node in nodes {
+        buf.clear();
+        ciborium::ser::into_writer(&node, &mut buf).unwrap();
+        table.insert(node.id(), buf.as_slice())?;
+    }
+    Ok(())
+}
+
+fn decode<HW: HashWriter>(bytes: &[u8]) -> StoreResult<Node<HW>> {
+    ciborium::de::from_reader(bytes)
+        .map_err(|e| StoreError::StoreFailure(format!("Invalid serialization {:?}", e)))
+}
+
+impl From<redb::Error> for StoreError {
+    fn from(err: redb::Error) -> Self {
+        StoreError::StoreFailure(format!("{}", err))
+    }
+}
+
+// redb reports each kind of failure with its own type that converts into a redb::Error.
+macro_rules! from_redb_error {
+    ($($err:ident),*) => {
+        $(
+            impl From<redb::$err> for StoreError {
+                fn from(err: redb::$err) -> Self {
+                    redb::Error::from(err).into()
+                }
+            }
+        )*
+    };
+}
+
+from_redb_error!(
+    DatabaseError,
+    TransactionError,
+    TableError,
+    StorageError,
+    CommitError
+);
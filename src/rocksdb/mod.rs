@@ -14,26 +14,94 @@
 //! Module implementing a [Store] interface using rocksdb for a [Merkle Dag](crate::dag::Merkle).
 //! Requires the `rocksdb` feature to be enabled.
 
+use std::collections::BTreeMap;
 use std::path::Path;
+use std::sync::Arc;
 
 use crate::{
     hash::HashWriter,
+    inspect::{BackendKind, MetaBlock, StoreDescription, StoreMeta, META_KEY},
     node::Node,
-    store::{Result as StoreResult, Store, StoreError},
+    store::{
+        ConcurrentStore, PersistedRoots, Result as StoreResult, Store, StoreError, StoreStats,
+        ROOTS_KEY,
+    },
 };
 
 use ciborium;
-use rocksdb::{DBWithThreadMode, MultiThreaded, Options, SingleThreaded, ThreadMode};
+use rocksdb::{
+    AsColumnFamilyRef, BoundColumnFamily, ColumnFamily, DBIteratorWithThreadMode, DBPinnableSlice,
+    DBWithThreadMode, Direction, IteratorMode, MultiThreaded, Options, SingleThreaded, ThreadMode,
+    WriteBatch, WriteOptions,
+};
 
 pub type Result<T> = std::result::Result<T, rocksdb::Error>;
 
+/// Keys in the quarantine keyspace of a [RocksStore] are the original id behind this prefix.
+pub const QUARANTINE_PREFIX: &[u8] = b"\0merkle-dag/quarantine/";
+
+/// The [ThreadMode]s a [RocksStore] can run in. RocksDB hands out column family handles
+/// differently in its single and multithreaded modes.
+pub trait RocksThreadMode: ThreadMode + Sized {
+    /// The handle of a column family.
+    type Handle<'a>: AsColumnFamilyRef
+    where
+        Self: 'a;
+
+    /// Looks up the handle of the column family with this name.
+    fn cf_handle<'a>(db: &'a DBWithThreadMode<Self>, name: &str) -> Option<Self::Handle<'a>>;
+}
+
+impl RocksThreadMode for SingleThreaded {
+    type Handle<'a> = &'a ColumnFamily;
+
+    fn cf_handle<'a>(db: &'a DBWithThreadMode<Self>, name: &str) -> Option<Self::Handle<'a>> {
+        db.cf_handle(name)
+    }
+}
+
+impl RocksThreadMode for MultiThreaded {
+    type Handle<'a> = Arc<BoundColumnFamily<'a>>;
+
+    fn cf_handle<'a>(db: &'a DBWithThreadMode<Self>, name: &str) -> Option<Self::Handle<'a>> {
+        db.cf_handle(name)
+    }
+}
+
+/// The name of the column family holding the metadata block and the persisted roots of a
+/// [RocksStore] whose nodes are in the column family `nodes`.
+pub fn meta_column_family(nodes: &str) -> String {
+    format!("{}-meta", nodes)
+}
+
 /// A Rocksdb `Store` implementation generic over the single and multithreaded
 /// versions.
+///
+/// By default everything is kept in the default column family. A store opened with
+/// [RocksStore::open_with_column_family] keeps its nodes in the named column family and its
+/// metadata and roots in the [meta_column_family] so it can share a database with others.
 pub struct RocksStore<TM>
 where
-    TM: ThreadMode,
+    TM: RocksThreadMode,
 {
-    store: DBWithThreadMode<TM>,
+    store: Arc<DBWithThreadMode<TM>>,
+    column_families: Option<ColumnFamilies>,
+    write_opts: WriteOptions,
+    meta: StoreMeta,
+}
+
+// The column families of a store that doesn't use the default column family.
+struct ColumnFamilies {
+    nodes: String,
+    meta: String,
+}
+
+// The keyspaces of a store. Quarantined nodes live in the nodes keyspace behind the
+// QUARANTINE_PREFIX.
+#[derive(Clone, Copy)]
+enum Keyspace {
+    Nodes,
+    Meta,
 }
 
 /// Type alias for a [RocksStore<SingleThreaded>].
@@ -43,7 +111,7 @@ pub type MultiThreadedRocksStore = RocksStore<MultiThreaded>;
 
 impl<TM> RocksStore<TM>
 where
-    TM: ThreadMode,
+    TM: RocksThreadMode,
 {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         let opts = Options::default();
@@ -51,46 +119,490 @@ where
     }
 
     pub fn open_with_opts<P: AsRef<Path>>(path: P, opts: &Options) -> Result<Self> {
-        Ok(Self {
-            store: DBWithThreadMode::<TM>::open(&opts, path)?,
+        let store = DBWithThreadMode::<TM>::open(opts, path)?;
+        let existing = store.get(META_KEY)?;
+        let me = Self {
+            store: Arc::new(store),
+            column_families: None,
+            write_opts: WriteOptions::default(),
+            meta: StoreMeta::reopen(BackendKind::RocksDb, existing.as_deref()),
+        };
+        me.store.put(META_KEY, me.meta.encode())?;
+        Ok(me)
+    }
+
+    /// Open the database keeping the nodes in the column family `nodes` and the metadata in
+    /// its [meta_column_family]. Both are created if they are missing. The other column
+    /// families of an existing database are opened too and left untouched.
+    pub fn open_with_column_family<P: AsRef<Path>>(
+        path: P,
+        opts: &Options,
+        nodes: &str,
+    ) -> StoreResult<Self> {
+        let mut names = DBWithThreadMode::<TM>::list_cf(opts, path.as_ref()).unwrap_or_default();
+        for name in [nodes.to_owned(), meta_column_family(nodes)] {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        let mut opts = opts.clone();
+        opts.create_missing_column_families(true);
+        let db = DBWithThreadMode::<TM>::open_cf(&opts, path, names)?;
+        Self::from_db(Arc::new(db), Some(nodes))
+    }
+
+    /// Wrap an already open database so it can be shared with other users. The nodes are kept
+    /// in the column family `nodes` and the metadata in its [meta_column_family], both of which
+    /// must already exist, or in the default column family if `nodes` is `None`.
+    pub fn from_db(db: Arc<DBWithThreadMode<TM>>, nodes: Option<&str>) -> StoreResult<Self> {
+        let mut me = Self {
+            store: db,
+            column_families: nodes.map(|nodes| ColumnFamilies {
+                nodes: nodes.to_owned(),
+                meta: meta_column_family(nodes),
+            }),
+            write_opts: WriteOptions::default(),
+            meta: StoreMeta::new(BackendKind::RocksDb),
+        };
+        // Fail early if a column family is missing.
+        me.column_family(Keyspace::Nodes)?;
+        let existing = me.get_in(Keyspace::Meta, META_KEY)?;
+        me.meta = StoreMeta::reopen(BackendKind::RocksDb, existing.as_deref());
+        me.write_meta()?;
+        Ok(me)
+    }
+
+    fn write_meta(&self) -> StoreResult<()> {
+        self.put_in(Keyspace::Meta, META_KEY, &self.meta.encode())
+    }
+
+    // Writes a batch of nodes recording the hash algorithm in the same batch as the first of
+    // them.
+    fn write_nodes<HW: HashWriter>(&mut self, mut batch: WriteBatch) -> StoreResult<()> {
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        if let Some(meta) = &pending {
+            self.batch_put(&mut batch, Keyspace::Meta, META_KEY, &meta.encode())?;
+        }
+        self.store.write_opt(batch, &self.write_opts)?;
+        if let Some(meta) = pending {
+            self.meta = meta;
+        }
+        Ok(())
+    }
+
+    /// Use these [WriteOptions] for every write to the database.
+    pub fn with_write_options(mut self, write_opts: WriteOptions) -> Self {
+        self.write_opts = write_opts;
+        self
+    }
+
+    /// Whether every write waits for RocksDB to sync its write ahead log to disk. Writes
+    /// aren't synced by default so a crash of the machine can lose the latest writes but never
+    /// leaves part of a write batch behind.
+    pub fn with_sync_writes(mut self, sync: bool) -> Self {
+        self.write_opts.set_sync(sync);
+        self
+    }
+
+    /// The metadata block of this store.
+    pub fn meta(&self) -> &StoreMeta {
+        &self.meta
+    }
+
+    /// The database of this store.
+    pub fn db(&self) -> &Arc<DBWithThreadMode<TM>> {
+        &self.store
+    }
+
+    // The handle of the column family the keyspace lives in or None for the default column
+    // family.
+    fn column_family(&self, space: Keyspace) -> StoreResult<Option<TM::Handle<'_>>> {
+        let cfs = match &self.column_families {
+            Some(cfs) => cfs,
+            None => return Ok(None),
+        };
+        let name = match space {
+            Keyspace::Nodes => &cfs.nodes,
+            Keyspace::Meta => &cfs.meta,
+        };
+        match TM::cf_handle(&self.store, name) {
+            Some(cf) => Ok(Some(cf)),
+            None => Err(StoreError::StoreFailure(format!(
+                "Missing column family {}",
+                name
+            ))),
+        }
+    }
+
+    fn get_in(&self, space: Keyspace, key: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        Ok(match self.column_family(space)? {
+            Some(cf) => self.store.get_cf(&cf, key)?,
+            None => self.store.get(key)?,
+        })
+    }
+
+    // Reads the value without copying it out of RocksDB.
+    fn get_pinned_in(
+        &self,
+        space: Keyspace,
+        key: &[u8],
+    ) -> StoreResult<Option<DBPinnableSlice<'_>>> {
+        Ok(match self.column_family(space)? {
+            Some(cf) => self.store.get_pinned_cf(&cf, key)?,
+            None => self.store.get_pinned(key)?,
+        })
+    }
+
+    // False means the key is definitely absent. True means it has to be read to be sure.
+    fn key_may_exist_in(&self, space: Keyspace, key: &[u8]) -> StoreResult<bool> {
+        Ok(match self.column_family(space)? {
+            Some(cf) => self.store.key_may_exist_cf(&cf, key),
+            None => self.store.key_may_exist(key),
+        })
+    }
+
+    fn multi_get_in(&self, space: Keyspace, keys: &[&[u8]]) -> StoreResult<Vec<Option<Vec<u8>>>> {
+        let values = match self.column_family(space)? {
+            Some(cf) => self.store.multi_get_cf(keys.iter().map(|key| (&cf, key))),
+            None => self.store.multi_get(keys),
+        };
+        values
+            .into_iter()
+            .map(|value| value.map_err(StoreError::from))
+            .collect()
+    }
+
+    fn put_in(&self, space: Keyspace, key: &[u8], value: &[u8]) -> StoreResult<()> {
+        match self.column_family(space)? {
+            Some(cf) => self.store.put_cf_opt(&cf, key, value, &self.write_opts)?,
+            None => self.store.put_opt(key, value, &self.write_opts)?,
+        }
+        Ok(())
+    }
+
+    fn delete_in(&self, space: Keyspace, key: &[u8]) -> StoreResult<()> {
+        match self.column_family(space)? {
+            Some(cf) => self.store.delete_cf_opt(&cf, key, &self.write_opts)?,
+            None => self.store.delete_opt(key, &self.write_opts)?,
+        }
+        Ok(())
+    }
+
+    fn batch_put(
+        &self,
+        batch: &mut WriteBatch,
+        space: Keyspace,
+        key: &[u8],
+        value: &[u8],
+    ) -> StoreResult<()> {
+        match self.column_family(space)? {
+            Some(cf) => batch.put_cf(&cf, key, value),
+            None => batch.put(key, value),
+        }
+        Ok(())
+    }
+
+    fn batch_delete(&self, batch: &mut WriteBatch, space: Keyspace, key: &[u8]) -> StoreResult<()> {
+        match self.column_family(space)? {
+            Some(cf) => batch.delete_cf(&cf, key),
+            None => batch.delete(key),
+        }
+        Ok(())
+    }
+
+    fn iter_in(
+        &self,
+        space: Keyspace,
+        mode: IteratorMode,
+    ) -> StoreResult<DBIteratorWithThreadMode<'_, DBWithThreadMode<TM>>> {
+        Ok(match self.column_family(space)? {
+            Some(cf) => self.store.iterator_cf(&cf, mode),
+            None => self.store.iterator(mode),
         })
     }
+
+    fn batch_nodes<HW, I>(&self, batch: &mut WriteBatch, nodes: I) -> StoreResult<()>
+    where
+        HW: HashWriter,
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let mut buf = Vec::new();
+        for node in nodes {
+            buf.clear();
+            ciborium::ser::into_writer(&node, &mut buf).unwrap();
+            self.batch_put(batch, Keyspace::Nodes, node.id(), &buf)?;
+        }
+        Ok(())
+    }
+
+    fn flush_all(&self) -> StoreResult<()> {
+        match self.column_family(Keyspace::Nodes)? {
+            Some(cf) => self.store.flush_cf(&cf)?,
+            None => return Ok(self.store.flush()?),
+        }
+        if let Some(cf) = self.column_family(Keyspace::Meta)? {
+            self.store.flush_cf(&cf)?;
+        }
+        Ok(())
+    }
 }
 
 impl<TM, HW> Store<HW> for RocksStore<TM>
 where
-    TM: ThreadMode,
+    TM: RocksThreadMode,
     HW: HashWriter,
 {
     fn contains(&self, id: &[u8]) -> StoreResult<bool> {
+        if !self.key_may_exist_in(Keyspace::Nodes, id)? {
+            return Ok(false);
+        }
+        Ok(self.get_pinned_in(Keyspace::Nodes, id)?.is_some())
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> StoreResult<Vec<bool>> {
         Ok(self
-            .store
-            .get(id)
-            .map_err(|e| StoreError::StoreFailure(format!("{:?}", e)))?
-            .is_some())
+            .multi_get_in(Keyspace::Nodes, ids)?
+            .into_iter()
+            .map(|bytes| bytes.is_some())
+            .collect())
     }
 
     fn get(&self, id: &[u8]) -> StoreResult<Option<Node<HW>>> {
-        Ok(
-            match self
-                .store
-                .get(id)
-                .map_err(|e| StoreError::StoreFailure(format!("{:?}", e)))?
-            {
-                Some(bs) => ciborium::de::from_reader(bs.as_slice()).map_err(|e| {
-                    StoreError::StoreFailure(format!("Invalid serialization {:?}", e))
-                })?,
-                None => None,
-            },
-        )
+        self.get_pinned_in(Keyspace::Nodes, id)?
+            .map(|bs| decode(&bs))
+            .transpose()
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> StoreResult<Vec<Option<Node<HW>>>> {
+        self.multi_get_in(Keyspace::Nodes, ids)?
+            .into_iter()
+            .map(|bytes| bytes.map(|bs| decode(&bs)).transpose())
+            .collect()
+    }
+
+    fn get_raw(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        self.get_in(Keyspace::Nodes, id)
     }
 
     fn store(&mut self, node: Node<HW>) -> StoreResult<()> {
         let mut buf = Vec::new();
         ciborium::ser::into_writer(&node, &mut buf).unwrap();
-        self.store.put(node.id(), &buf)?;
+        self.store_encoded(node, buf)
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> StoreResult<()> {
+        let mut batch = WriteBatch::default();
+        self.batch_put(&mut batch, Keyspace::Nodes, node.id(), &encoded)?;
+        self.write_nodes::<HW>(batch)
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> StoreResult<()> {
+        let mut batch = WriteBatch::default();
+        for (node, encoded) in &records {
+            self.batch_put(&mut batch, Keyspace::Nodes, node.id(), encoded)?;
+        }
+        if let Some(roots) = roots {
+            self.batch_put(&mut batch, Keyspace::Meta, ROOTS_KEY, &roots.encode())?;
+        }
+        self.write_nodes::<HW>(batch)
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let mut batch = WriteBatch::default();
+        self.batch_nodes(&mut batch, nodes)?;
+        self.write_nodes::<HW>(batch)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> StoreResult<()> {
+        self.store_many_with_roots([node], roots)
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        // A write batch is atomic across column families too.
+        let mut batch = WriteBatch::default();
+        self.batch_nodes(&mut batch, nodes)?;
+        self.batch_put(&mut batch, Keyspace::Meta, ROOTS_KEY, &roots.encode())?;
+        self.write_nodes::<HW>(batch)
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> StoreResult<()> {
+        self.put_in(Keyspace::Meta, ROOTS_KEY, &roots.encode())
+    }
+
+    fn persisted_roots(&self) -> StoreResult<Option<PersistedRoots>> {
+        self.get_in(Keyspace::Meta, ROOTS_KEY)?
+            .map(|bytes| PersistedRoots::decode(&bytes))
+            .transpose()
+    }
+
+    fn check_hash_algorithm(&self) -> StoreResult<()> {
+        self.meta.check_hash_algorithm::<HW>()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> StoreResult<()> {
+        self.delete_in(Keyspace::Nodes, id)
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> StoreResult<Vec<Vec<u8>>> {
+        let mut ids = Vec::new();
+        for item in self.iter_in(
+            Keyspace::Nodes,
+            IteratorMode::From(prefix, Direction::Forward),
+        )? {
+            let (key, _) = item?;
+            if ids.len() >= limit || !key.starts_with(prefix) {
+                break;
+            }
+            if !is_reserved(&key) {
+                ids.push(key.to_vec());
+            }
+        }
+        Ok(ids)
+    }
+
+    fn ids(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Vec<u8>>> + '_>> {
+        Ok(Box::new(
+            self.iter_in(Keyspace::Nodes, IteratorMode::Start)?
+                .filter_map(|item| match item {
+                    Ok((key, _)) if is_reserved(&key) => None,
+                    Ok((key, _)) => Some(Ok(key.to_vec())),
+                    Err(e) => Some(Err(e.into())),
+                }),
+        ))
+    }
+
+    fn key_length_histogram(&self) -> StoreResult<BTreeMap<usize, u64>> {
+        let mut histogram = BTreeMap::new();
+        for item in self.iter_in(Keyspace::Nodes, IteratorMode::Start)? {
+            let (key, _) = item?;
+            if !is_reserved(&key) {
+                *histogram.entry(key.len()).or_insert(0) += 1;
+            }
+        }
+        Ok(histogram)
+    }
+
+    fn stats(&self) -> StoreResult<StoreStats> {
+        // The size properties of RocksDB are estimates and don't track the largest value so
+        // the records are scanned in one pass without decoding them.
+        let mut stats = StoreStats::default();
+        for item in self.iter_in(Keyspace::Nodes, IteratorMode::Start)? {
+            let (key, val) = item?;
+            if !is_reserved(&key) {
+                stats.record(val.len());
+            }
+        }
+        Ok(stats)
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> StoreResult<u64> {
+        let mut batch = WriteBatch::default();
+        let mut moved = 0;
+        for item in self.iter_in(Keyspace::Nodes, IteratorMode::Start)? {
+            let (key, val) = item?;
+            if key.len() == expected_len || is_reserved(&key) {
+                continue;
+            }
+            self.batch_put(&mut batch, Keyspace::Nodes, &quarantine_key(&key), &val)?;
+            self.batch_delete(&mut batch, Keyspace::Nodes, &key)?;
+            moved += 1;
+        }
+        self.store.write_opt(batch, &self.write_opts)?;
+        self.meta.options.insert("quarantine".to_owned());
+        self.meta.record_maintenance();
+        self.write_meta()?;
+        Ok(moved)
+    }
+
+    fn flush(&mut self) -> StoreResult<()> {
+        self.flush_all()
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        Ok(self
+            .get_pinned_in(Keyspace::Nodes, &quarantine_key(id))?
+            .map(|bs| bs.to_vec()))
+    }
+}
+
+fn decode<HW: HashWriter>(bytes: &[u8]) -> StoreResult<Node<HW>> {
+    ciborium::de::from_reader(bytes)
+        .map_err(|e| StoreError::StoreFailure(format!("Invalid serialization {:?}", e)))
+}
+
+// Keys that don't hold a node: the quarantine keyspace, the metadata block and the roots.
+fn is_reserved(key: &[u8]) -> bool {
+    key == META_KEY || key == ROOTS_KEY || key.starts_with(QUARANTINE_PREFIX)
+}
+
+// Describes the database at the path opening it read only.
+pub(crate) fn inspect(path: &Path) -> StoreResult<StoreDescription> {
+    let db =
+        DBWithThreadMode::<SingleThreaded>::open_for_read_only(&Options::default(), path, false)?;
+    let meta = db
+        .get(META_KEY)?
+        .map(|block| MetaBlock::decode(&block))
+        .unwrap_or(MetaBlock::Missing);
+    let estimate = db.property_int_value("rocksdb.estimate-num-keys")?;
+    let mut reserved = 0;
+    for key in [META_KEY, ROOTS_KEY] {
+        if db.get(key)?.is_some() {
+            reserved += 1;
+        }
+    }
+    let mut details = BTreeMap::new();
+    if let Some(estimate) = estimate {
+        details.insert("estimate-num-keys".to_owned(), estimate.to_string());
+    }
+    Ok(StoreDescription {
+        backend: BackendKind::RocksDb,
+        meta,
+        // The estimate counts the metadata block and the roots too.
+        node_count: estimate.map(|estimate| estimate.saturating_sub(reserved)),
+        details,
+    })
+}
+
+fn quarantine_key(id: &[u8]) -> Vec<u8> {
+    let mut key = QUARANTINE_PREFIX.to_vec();
+    key.extend_from_slice(id);
+    key
+}
+
+/// RocksDB synchronizes writes internally so a [RocksStore], usually a
+/// [MultiThreadedRocksStore], can be shared by several writers behind an
+/// [Arc](std::sync::Arc).
+impl<TM, HW> ConcurrentStore<HW> for RocksStore<TM>
+where
+    TM: RocksThreadMode,
+    HW: HashWriter,
+{
+    fn store_shared(&self, node: Node<HW>) -> StoreResult<()> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&node, &mut buf).unwrap();
+        let mut batch = WriteBatch::default();
+        self.batch_put(&mut batch, Keyspace::Nodes, node.id(), &buf)?;
+        // The metadata held in memory only picks up the hash algorithm on reopen.
+        if let Some(meta) = self.meta.recording_hash_algorithm::<HW>() {
+            self.batch_put(&mut batch, Keyspace::Meta, META_KEY, &meta.encode())?;
+        }
+        self.store.write_opt(batch, &self.write_opts)?;
         Ok(())
     }
+
+    fn flush_shared(&self) -> StoreResult<()> {
+        self.flush_all()
+    }
 }
 
 impl From<rocksdb::Error> for StoreError {
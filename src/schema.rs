@@ -0,0 +1,1273 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Machine readable descriptions of the wire visible types for implementations in other
+//! languages. Requires the `schema` feature to be enabled.
+//!
+//! The [Format] of each type is traced from golden fixture values through serde so it
+//! can not drift from what the cbor encoder actually writes. Invariants that the format
+//! can not express are maintained by hand alongside the fixtures. The committed artifacts
+//! live under `schemas/` and are written by [generate] and checked by [verify_fixtures].
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Write as _};
+use std::path::{Path, PathBuf};
+
+use serde::{de::DeserializeOwned, ser, Serialize};
+
+use crate::dag::{Merkle, NodeHandle, ReadToken};
+use crate::hash::{siphash::SipHash24, HashKey};
+use crate::id::hex;
+use crate::node::Node;
+use crate::store::{BTreeStore, PersistedRoots};
+use crate::trace::{
+    TraceEntry, TraceHeader, TraceOp, TraceResult, TracedNode, TRACE_FORMAT, TRACE_VERSION,
+};
+
+/// The error produced while tracing, verifying or generating schemas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaError(pub String);
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl ser::Error for SchemaError {
+    fn custom<T: Display>(msg: T) -> Self {
+        SchemaError(msg.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, SchemaError>;
+
+/// The serde data model shape of a type.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Format {
+    Unit,
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+    Char,
+    Str,
+    Bytes,
+    Option(Box<Format>),
+    Seq(Box<Format>),
+    Map {
+        key: Box<Format>,
+        value: Box<Format>,
+    },
+    Tuple(Vec<Format>),
+    UnitStruct(&'static str),
+    NewtypeStruct {
+        name: &'static str,
+        format: Box<Format>,
+    },
+    TupleStruct {
+        name: &'static str,
+        formats: Vec<Format>,
+    },
+    Struct {
+        name: &'static str,
+        fields: Vec<(&'static str, Format)>,
+    },
+    Enum {
+        name: &'static str,
+        variants: BTreeMap<u32, (&'static str, VariantFormat)>,
+    },
+    /// Nothing was observed for this position, e.g. the elements of an empty sequence.
+    Unknown,
+}
+
+/// The shape of a single [Format::Enum] variant.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum VariantFormat {
+    Unit,
+    Newtype(Box<Format>),
+    Tuple(Vec<Format>),
+    Struct(Vec<(&'static str, Format)>),
+}
+
+fn mismatch(left: &Format, right: &Format) -> SchemaError {
+    SchemaError(format!(
+        "Conflicting formats {} and {}",
+        left.summary(),
+        right.summary()
+    ))
+}
+
+fn merge_all(left: Vec<Format>, right: Vec<Format>) -> Result<Vec<Format>> {
+    if left.len() != right.len() {
+        return Err(SchemaError(format!(
+            "Conflicting lengths {} and {}",
+            left.len(),
+            right.len()
+        )));
+    }
+    left.into_iter()
+        .zip(right)
+        .map(|(l, r)| l.merge(r))
+        .collect()
+}
+
+fn merge_fields(
+    left: Vec<(&'static str, Format)>,
+    right: Vec<(&'static str, Format)>,
+) -> Result<Vec<(&'static str, Format)>> {
+    let names: Vec<&'static str> = left.iter().map(|(name, _)| *name).collect();
+    if !names.iter().eq(right.iter().map(|(name, _)| name)) {
+        return Err(SchemaError(format!("Conflicting fields {:?}", names)));
+    }
+    let formats = merge_all(
+        left.into_iter().map(|(_, f)| f).collect(),
+        right.into_iter().map(|(_, f)| f).collect(),
+    )?;
+    Ok(names.into_iter().zip(formats).collect())
+}
+
+impl VariantFormat {
+    fn merge(self, other: VariantFormat) -> Result<VariantFormat> {
+        Ok(match (self, other) {
+            (VariantFormat::Unit, VariantFormat::Unit) => VariantFormat::Unit,
+            (VariantFormat::Newtype(l), VariantFormat::Newtype(r)) => {
+                VariantFormat::Newtype(Box::new(l.merge(*r)?))
+            }
+            (VariantFormat::Tuple(l), VariantFormat::Tuple(r)) => {
+                VariantFormat::Tuple(merge_all(l, r)?)
+            }
+            (VariantFormat::Struct(l), VariantFormat::Struct(r)) => {
+                VariantFormat::Struct(merge_fields(l, r)?)
+            }
+            _ => return Err(SchemaError("Conflicting variant shapes".to_owned())),
+        })
+    }
+
+    fn has_unknown(&self) -> bool {
+        match self {
+            VariantFormat::Unit => false,
+            VariantFormat::Newtype(f) => f.has_unknown(),
+            VariantFormat::Tuple(fs) => fs.iter().any(Format::has_unknown),
+            VariantFormat::Struct(fields) => fields.iter().any(|(_, f)| f.has_unknown()),
+        }
+    }
+}
+
+impl Format {
+    /// Combine the formats traced from two values of the same type filling in the
+    /// positions only one of them observed.
+    pub fn merge(self, other: Format) -> Result<Format> {
+        Ok(match (self, other) {
+            (Format::Unknown, f) | (f, Format::Unknown) => f,
+            (Format::Option(l), Format::Option(r)) => Format::Option(Box::new(l.merge(*r)?)),
+            (Format::Seq(l), Format::Seq(r)) => Format::Seq(Box::new(l.merge(*r)?)),
+            (Format::Map { key: lk, value: lv }, Format::Map { key: rk, value: rv }) => {
+                Format::Map {
+                    key: Box::new(lk.merge(*rk)?),
+                    value: Box::new(lv.merge(*rv)?),
+                }
+            }
+            (Format::Tuple(l), Format::Tuple(r)) => Format::Tuple(merge_all(l, r)?),
+            (
+                Format::NewtypeStruct { name, format: l },
+                Format::NewtypeStruct {
+                    name: other,
+                    format: r,
+                },
+            ) if name == other => Format::NewtypeStruct {
+                name,
+                format: Box::new(l.merge(*r)?),
+            },
+            (
+                Format::TupleStruct { name, formats: l },
+                Format::TupleStruct {
+                    name: other,
+                    formats: r,
+                },
+            ) if name == other => Format::TupleStruct {
+                name,
+                formats: merge_all(l, r)?,
+            },
+            (
+                Format::Struct { name, fields: l },
+                Format::Struct {
+                    name: other,
+                    fields: r,
+                },
+            ) if name == other => Format::Struct {
+                name,
+                fields: merge_fields(l, r)?,
+            },
+            (
+                Format::Enum {
+                    name,
+                    variants: mut l,
+                },
+                Format::Enum {
+                    name: other,
+                    variants: r,
+                },
+            ) if name == other => {
+                for (idx, (variant, format)) in r {
+                    let merged = match l.remove(&idx) {
+                        Some((existing, _)) if existing != variant => {
+                            return Err(SchemaError(format!(
+                                "Conflicting names for variant {} of {}",
+                                idx, name
+                            )))
+                        }
+                        Some((_, existing)) => existing.merge(format)?,
+                        None => format,
+                    };
+                    l.insert(idx, (variant, merged));
+                }
+                Format::Enum { name, variants: l }
+            }
+            (l, r) if l == r => l,
+            (l, r) => return Err(mismatch(&l, &r)),
+        })
+    }
+
+    /// Whether any position in the format was never observed.
+    pub fn has_unknown(&self) -> bool {
+        match self {
+            Format::Unknown => true,
+            Format::Option(f) | Format::Seq(f) => f.has_unknown(),
+            Format::NewtypeStruct { format, .. } => format.has_unknown(),
+            Format::Map { key, value } => key.has_unknown() || value.has_unknown(),
+            Format::Tuple(fs) | Format::TupleStruct { formats: fs, .. } => {
+                fs.iter().any(Format::has_unknown)
+            }
+            Format::Struct { fields, .. } => fields.iter().any(|(_, f)| f.has_unknown()),
+            Format::Enum { variants, .. } => variants.values().any(|(_, v)| v.has_unknown()),
+            _ => false,
+        }
+    }
+
+    /// A short human oriented rendering of the format. Named types are rendered by name.
+    pub fn summary(&self) -> String {
+        match self {
+            Format::Option(f) => format!("Option<{}>", f.summary()),
+            Format::Seq(f) => format!("Seq<{}>", f.summary()),
+            Format::Map { key, value } => format!("Map<{}, {}>", key.summary(), value.summary()),
+            Format::Tuple(fs) => format!(
+                "({})",
+                fs.iter()
+                    .map(Format::summary)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Format::UnitStruct(name)
+            | Format::NewtypeStruct { name, .. }
+            | Format::TupleStruct { name, .. }
+            | Format::Struct { name, .. }
+            | Format::Enum { name, .. } => name.to_string(),
+            primitive => format!("{:?}", primitive),
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        let tagged = |tag: &str, value: Json| Json::Obj(vec![(tag.to_owned(), value)]);
+        match self {
+            Format::Option(f) => tagged("Option", f.to_json()),
+            Format::Seq(f) => tagged("Seq", f.to_json()),
+            Format::Map { key, value } => tagged(
+                "Map",
+                Json::Obj(vec![
+                    ("key".to_owned(), key.to_json()),
+                    ("value".to_owned(), value.to_json()),
+                ]),
+            ),
+            Format::Tuple(fs) => {
+                tagged("Tuple", Json::Arr(fs.iter().map(Format::to_json).collect()))
+            }
+            Format::UnitStruct(name) => tagged("UnitStruct", Json::Str(name.to_string())),
+            Format::NewtypeStruct { name, format } => tagged(
+                "NewtypeStruct",
+                Json::Obj(vec![
+                    ("name".to_owned(), Json::Str(name.to_string())),
+                    ("format".to_owned(), format.to_json()),
+                ]),
+            ),
+            Format::TupleStruct { name, formats } => tagged(
+                "TupleStruct",
+                Json::Obj(vec![
+                    ("name".to_owned(), Json::Str(name.to_string())),
+                    (
+                        "formats".to_owned(),
+                        Json::Arr(formats.iter().map(Format::to_json).collect()),
+                    ),
+                ]),
+            ),
+            Format::Struct { name, fields } => tagged(
+                "Struct",
+                Json::Obj(vec![
+                    ("name".to_owned(), Json::Str(name.to_string())),
+                    ("fields".to_owned(), fields_json(fields)),
+                ]),
+            ),
+            Format::Enum { name, variants } => tagged(
+                "Enum",
+                Json::Obj(vec![
+                    ("name".to_owned(), Json::Str(name.to_string())),
+                    (
+                        "variants".to_owned(),
+                        Json::Arr(
+                            variants
+                                .iter()
+                                .map(|(idx, (variant, format))| {
+                                    Json::Obj(vec![
+                                        ("index".to_owned(), Json::Num(*idx as u64)),
+                                        ("name".to_owned(), Json::Str(variant.to_string())),
+                                        ("format".to_owned(), format.to_json()),
+                                    ])
+                                })
+                                .collect(),
+                        ),
+                    ),
+                ]),
+            ),
+            primitive => Json::Str(format!("{:?}", primitive)),
+        }
+    }
+
+    // Append a field table for every named struct and enum in the format to `tables`.
+    fn tables(&self, tables: &mut BTreeMap<&'static str, String>) {
+        match self {
+            Format::Option(f) | Format::Seq(f) | Format::NewtypeStruct { format: f, .. } => {
+                f.tables(tables)
+            }
+            Format::Map { key, value } => {
+                key.tables(tables);
+                value.tables(tables);
+            }
+            Format::Tuple(fs) | Format::TupleStruct { formats: fs, .. } => {
+                fs.iter().for_each(|f| f.tables(tables))
+            }
+            Format::Struct { name, fields } => {
+                let mut table = format!("### {}\n\n| # | field | format |\n|---|---|---|\n", name);
+                for (idx, (field, format)) in fields.iter().enumerate() {
+                    let _ = writeln!(table, "| {} | {} | {} |", idx, field, format.summary());
+                }
+                tables.insert(name, table);
+                fields.iter().for_each(|(_, f)| f.tables(tables));
+            }
+            Format::Enum { name, variants } => {
+                let mut table = format!(
+                    "### {}\n\n| index | variant | format |\n|---|---|---|\n",
+                    name
+                );
+                for (idx, (variant, format)) in variants.iter() {
+                    let _ = writeln!(table, "| {} | {} | {} |", idx, variant, format.summary());
+                }
+                tables.insert(name, table);
+                for (_, format) in variants.values() {
+                    match format {
+                        VariantFormat::Unit => {}
+                        VariantFormat::Newtype(f) => f.tables(tables),
+                        VariantFormat::Tuple(fs) => fs.iter().for_each(|f| f.tables(tables)),
+                        VariantFormat::Struct(fields) => {
+                            fields.iter().for_each(|(_, f)| f.tables(tables))
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl VariantFormat {
+    fn summary(&self) -> String {
+        match self {
+            VariantFormat::Unit => "Unit".to_owned(),
+            VariantFormat::Newtype(f) => f.summary(),
+            VariantFormat::Tuple(fs) => format!(
+                "({})",
+                fs.iter()
+                    .map(Format::summary)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            VariantFormat::Struct(fields) => format!(
+                "{{ {} }}",
+                fields
+                    .iter()
+                    .map(|(name, f)| format!("{}: {}", name, f.summary()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        match self {
+            VariantFormat::Unit => Json::Str("Unit".to_owned()),
+            VariantFormat::Newtype(f) => Json::Obj(vec![("Newtype".to_owned(), f.to_json())]),
+            VariantFormat::Tuple(fs) => Json::Obj(vec![(
+                "Tuple".to_owned(),
+                Json::Arr(fs.iter().map(Format::to_json).collect()),
+            )]),
+            VariantFormat::Struct(fields) => {
+                Json::Obj(vec![("Struct".to_owned(), fields_json(fields))])
+            }
+        }
+    }
+}
+
+fn fields_json(fields: &[(&'static str, Format)]) -> Json {
+    Json::Arr(
+        fields
+            .iter()
+            .map(|(name, format)| {
+                Json::Obj(vec![
+                    ("name".to_owned(), Json::Str(name.to_string())),
+                    ("format".to_owned(), format.to_json()),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Trace the [Format] of a value through its [Serialize] implementation.
+pub fn trace_value<T: Serialize + ?Sized>(value: &T) -> Result<Format> {
+    value.serialize(Tracer)
+}
+
+struct Tracer;
+
+struct SeqTracer(Format);
+
+struct TupleTracer(Vec<Format>);
+
+struct MapTracer {
+    key: Format,
+    value: Format,
+}
+
+struct StructTracer(Vec<(&'static str, Format)>);
+
+struct NamedTracer<T> {
+    name: &'static str,
+    variant: Option<(u32, &'static str)>,
+    inner: T,
+}
+
+fn enum_format(
+    name: &'static str,
+    idx: u32,
+    variant: &'static str,
+    format: VariantFormat,
+) -> Format {
+    Format::Enum {
+        name,
+        variants: BTreeMap::from([(idx, (variant, format))]),
+    }
+}
+
+impl ser::Serializer for Tracer {
+    type Ok = Format;
+    type Error = SchemaError;
+    type SerializeSeq = SeqTracer;
+    type SerializeTuple = TupleTracer;
+    type SerializeTupleStruct = NamedTracer<TupleTracer>;
+    type SerializeTupleVariant = NamedTracer<TupleTracer>;
+    type SerializeMap = MapTracer;
+    type SerializeStruct = NamedTracer<StructTracer>;
+    type SerializeStructVariant = NamedTracer<StructTracer>;
+
+    fn serialize_bool(self, _: bool) -> Result<Format> {
+        Ok(Format::Bool)
+    }
+    fn serialize_i8(self, _: i8) -> Result<Format> {
+        Ok(Format::I8)
+    }
+    fn serialize_i16(self, _: i16) -> Result<Format> {
+        Ok(Format::I16)
+    }
+    fn serialize_i32(self, _: i32) -> Result<Format> {
+        Ok(Format::I32)
+    }
+    fn serialize_i64(self, _: i64) -> Result<Format> {
+        Ok(Format::I64)
+    }
+    fn serialize_u8(self, _: u8) -> Result<Format> {
+        Ok(Format::U8)
+    }
+    fn serialize_u16(self, _: u16) -> Result<Format> {
+        Ok(Format::U16)
+    }
+    fn serialize_u32(self, _: u32) -> Result<Format> {
+        Ok(Format::U32)
+    }
+    fn serialize_u64(self, _: u64) -> Result<Format> {
+        Ok(Format::U64)
+    }
+    fn serialize_f32(self, _: f32) -> Result<Format> {
+        Ok(Format::F32)
+    }
+    fn serialize_f64(self, _: f64) -> Result<Format> {
+        Ok(Format::F64)
+    }
+    fn serialize_char(self, _: char) -> Result<Format> {
+        Ok(Format::Char)
+    }
+    fn serialize_str(self, _: &str) -> Result<Format> {
+        Ok(Format::Str)
+    }
+    fn serialize_bytes(self, _: &[u8]) -> Result<Format> {
+        Ok(Format::Bytes)
+    }
+    fn serialize_none(self) -> Result<Format> {
+        Ok(Format::Option(Box::new(Format::Unknown)))
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Format> {
+        Ok(Format::Option(Box::new(trace_value(value)?)))
+    }
+    fn serialize_unit(self) -> Result<Format> {
+        Ok(Format::Unit)
+    }
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Format> {
+        Ok(Format::UnitStruct(name))
+    }
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        idx: u32,
+        variant: &'static str,
+    ) -> Result<Format> {
+        Ok(enum_format(name, idx, variant, VariantFormat::Unit))
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Format> {
+        Ok(Format::NewtypeStruct {
+            name,
+            format: Box::new(trace_value(value)?),
+        })
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        idx: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Format> {
+        Ok(enum_format(
+            name,
+            idx,
+            variant,
+            VariantFormat::Newtype(Box::new(trace_value(value)?)),
+        ))
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<SeqTracer> {
+        Ok(SeqTracer(Format::Unknown))
+    }
+    fn serialize_tuple(self, _: usize) -> Result<TupleTracer> {
+        Ok(TupleTracer(Vec::new()))
+    }
+    fn serialize_tuple_struct(
+        self,
+        name: &'static str,
+        _: usize,
+    ) -> Result<NamedTracer<TupleTracer>> {
+        Ok(NamedTracer {
+            name,
+            variant: None,
+            inner: TupleTracer(Vec::new()),
+        })
+    }
+    fn serialize_tuple_variant(
+        self,
+        name: &'static str,
+        idx: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<NamedTracer<TupleTracer>> {
+        Ok(NamedTracer {
+            name,
+            variant: Some((idx, variant)),
+            inner: TupleTracer(Vec::new()),
+        })
+    }
+    fn serialize_map(self, _: Option<usize>) -> Result<MapTracer> {
+        Ok(MapTracer {
+            key: Format::Unknown,
+            value: Format::Unknown,
+        })
+    }
+    fn serialize_struct(self, name: &'static str, _: usize) -> Result<NamedTracer<StructTracer>> {
+        Ok(NamedTracer {
+            name,
+            variant: None,
+            inner: StructTracer(Vec::new()),
+        })
+    }
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        idx: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<NamedTracer<StructTracer>> {
+        Ok(NamedTracer {
+            name,
+            variant: Some((idx, variant)),
+            inner: StructTracer(Vec::new()),
+        })
+    }
+}
+
+impl ser::SerializeSeq for SeqTracer {
+    type Ok = Format;
+    type Error = SchemaError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let element = std::mem::replace(&mut self.0, Format::Unknown);
+        self.0 = element.merge(trace_value(value)?)?;
+        Ok(())
+    }
+
+    fn end(self) -> Result<Format> {
+        Ok(Format::Seq(Box::new(self.0)))
+    }
+}
+
+impl ser::SerializeTuple for TupleTracer {
+    type Ok = Format;
+    type Error = SchemaError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.0.push(trace_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Format> {
+        Ok(Format::Tuple(self.0))
+    }
+}
+
+impl ser::SerializeTupleStruct for NamedTracer<TupleTracer> {
+    type Ok = Format;
+    type Error = SchemaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.inner.0.push(trace_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Format> {
+        Ok(Format::TupleStruct {
+            name: self.name,
+            formats: self.inner.0,
+        })
+    }
+}
+
+impl ser::SerializeTupleVariant for NamedTracer<TupleTracer> {
+    type Ok = Format;
+    type Error = SchemaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.inner.0.push(trace_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Format> {
+        let (idx, variant) = self.variant.expect("Tuple variants always have a variant");
+        Ok(enum_format(
+            self.name,
+            idx,
+            variant,
+            VariantFormat::Tuple(self.inner.0),
+        ))
+    }
+}
+
+impl ser::SerializeMap for MapTracer {
+    type Ok = Format;
+    type Error = SchemaError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        let known = std::mem::replace(&mut self.key, Format::Unknown);
+        self.key = known.merge(trace_value(key)?)?;
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let known = std::mem::replace(&mut self.value, Format::Unknown);
+        self.value = known.merge(trace_value(value)?)?;
+        Ok(())
+    }
+
+    fn end(self) -> Result<Format> {
+        Ok(Format::Map {
+            key: Box::new(self.key),
+            value: Box::new(self.value),
+        })
+    }
+}
+
+impl ser::SerializeStruct for NamedTracer<StructTracer> {
+    type Ok = Format;
+    type Error = SchemaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        field: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.inner.0.push((field, trace_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Format> {
+        Ok(Format::Struct {
+            name: self.name,
+            fields: self.inner.0,
+        })
+    }
+}
+
+impl ser::SerializeStructVariant for NamedTracer<StructTracer> {
+    type Ok = Format;
+    type Error = SchemaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        field: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.inner.0.push((field, trace_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Format> {
+        let (idx, variant) = self.variant.expect("Struct variants always have a variant");
+        Ok(enum_format(
+            self.name,
+            idx,
+            variant,
+            VariantFormat::Struct(self.inner.0),
+        ))
+    }
+}
+
+// A minimal JSON document model for the schema artifacts.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) enum Json {
+    Str(String),
+    Num(u64),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn render(&self, indent: usize, out: &mut String) {
+        let pad = "  ".repeat(indent + 1);
+        match self {
+            Json::Str(s) => {
+                out.push('"');
+                for c in s.chars() {
+                    match c {
+                        '"' => out.push_str("\\\""),
+                        '\\' => out.push_str("\\\\"),
+                        '\n' => out.push_str("\\n"),
+                        c => out.push(c),
+                    }
+                }
+                out.push('"');
+            }
+            Json::Num(n) => {
+                let _ = write!(out, "{}", n);
+            }
+            Json::Arr(items) if items.is_empty() => out.push_str("[]"),
+            Json::Arr(items) => {
+                out.push_str("[\n");
+                for (idx, item) in items.iter().enumerate() {
+                    out.push_str(&pad);
+                    item.render(indent + 1, out);
+                    out.push_str(if idx + 1 < items.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push(']');
+            }
+            Json::Obj(fields) => {
+                out.push_str("{\n");
+                for (idx, (key, value)) in fields.iter().enumerate() {
+                    out.push_str(&pad);
+                    Json::Str(key.clone()).render(indent + 1, out);
+                    out.push_str(": ");
+                    value.render(indent + 1, out);
+                    out.push_str(if idx + 1 < fields.len() { ",\n" } else { "\n" });
+                }
+                out.push_str(&"  ".repeat(indent));
+                out.push('}');
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Obj(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Parse a JSON document containing only strings, unsigned integers, arrays and objects.
+    #[cfg(test)]
+    pub(crate) fn parse(input: &str) -> Result<Json> {
+        let mut chars = input.chars().peekable();
+        let value = Self::parse_value(&mut chars)?;
+        Self::skip_ws(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(SchemaError(format!("Unexpected trailing {:?}", c))),
+        }
+    }
+
+    #[cfg(test)]
+    fn skip_ws(chars: &mut std::iter::Peekable<std::str::Chars>) {
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+    }
+
+    #[cfg(test)]
+    fn expect(chars: &mut std::iter::Peekable<std::str::Chars>, expected: char) -> Result<()> {
+        Self::skip_ws(chars);
+        match chars.next() {
+            Some(c) if c == expected => Ok(()),
+            other => Err(SchemaError(format!(
+                "Expected {:?} found {:?}",
+                expected, other
+            ))),
+        }
+    }
+
+    #[cfg(test)]
+    fn parse_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Json> {
+        Self::skip_ws(chars);
+        match chars.peek().copied() {
+            Some('"') => Self::parse_str(chars).map(Json::Str),
+            Some('[') => {
+                chars.next();
+                let mut items = Vec::new();
+                Self::skip_ws(chars);
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    return Ok(Json::Arr(items));
+                }
+                loop {
+                    items.push(Self::parse_value(chars)?);
+                    Self::skip_ws(chars);
+                    match chars.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Json::Arr(items)),
+                        other => return Err(SchemaError(format!("Unexpected {:?}", other))),
+                    }
+                }
+            }
+            Some('{') => {
+                chars.next();
+                let mut fields = Vec::new();
+                Self::skip_ws(chars);
+                if chars.peek() == Some(&'}') {
+                    chars.next();
+                    return Ok(Json::Obj(fields));
+                }
+                loop {
+                    Self::skip_ws(chars);
+                    let key = Self::parse_str(chars)?;
+                    Self::expect(chars, ':')?;
+                    fields.push((key, Self::parse_value(chars)?));
+                    Self::skip_ws(chars);
+                    match chars.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Json::Obj(fields)),
+                        other => return Err(SchemaError(format!("Unexpected {:?}", other))),
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let mut n: u64 = 0;
+                while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    n = n * 10 + d as u64;
+                    chars.next();
+                }
+                Ok(Json::Num(n))
+            }
+            other => Err(SchemaError(format!("Unexpected {:?}", other))),
+        }
+    }
+
+    #[cfg(test)]
+    fn parse_str(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String> {
+        Self::expect(chars, '"')?;
+        let mut s = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some(c) => s.push(c),
+                    None => return Err(SchemaError("Unterminated escape".to_owned())),
+                },
+                Some(c) => s.push(c),
+                None => return Err(SchemaError("Unterminated string".to_owned())),
+            }
+        }
+    }
+}
+
+/// The description of a single wire visible type.
+#[derive(Clone, Debug)]
+pub struct WireSchema {
+    /// The name of the type and of its artifacts under `schemas/`.
+    pub name: &'static str,
+    /// The traced [Format] of the type.
+    pub format: Format,
+    /// Invariants the [Format] can not express.
+    pub invariants: &'static [&'static str],
+    /// The cbor encoding of each golden fixture value.
+    pub fixtures: Vec<Vec<u8>>,
+}
+
+impl WireSchema {
+    // Trace the format of the fixtures checking that each one round trips through serde to
+    // the same encoding.
+    fn describe<T>(
+        name: &'static str,
+        invariants: &'static [&'static str],
+        fixtures: &[T],
+    ) -> Result<Self>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let mut format = Format::Unknown;
+        let mut encoded = Vec::new();
+        for fixture in fixtures {
+            format = format.merge(trace_value(fixture)?)?;
+            let mut buf = Vec::new();
+            ciborium::ser::into_writer(fixture, &mut buf)
+                .map_err(|e| SchemaError(format!("{}: {:?}", name, e)))?;
+            let decoded: T = ciborium::de::from_reader(buf.as_slice())
+                .map_err(|e| SchemaError(format!("{}: {:?}", name, e)))?;
+            let mut again = Vec::new();
+            ciborium::ser::into_writer(&decoded, &mut again)
+                .map_err(|e| SchemaError(format!("{}: {:?}", name, e)))?;
+            if again != buf {
+                return Err(SchemaError(format!(
+                    "{} fixture does not round trip to the same encoding",
+                    name
+                )));
+            }
+            encoded.push(buf);
+        }
+        if format.has_unknown() {
+            return Err(SchemaError(format!(
+                "{} fixtures do not cover every position of the format",
+                name
+            )));
+        }
+        Ok(Self {
+            name,
+            format,
+            invariants,
+            fixtures: encoded,
+        })
+    }
+
+    /// The JSON description of the type.
+    pub fn to_json(&self) -> String {
+        let doc = Json::Obj(vec![
+            ("name".to_owned(), Json::Str(self.name.to_owned())),
+            ("encoding".to_owned(), Json::Str("cbor".to_owned())),
+            (
+                "invariants".to_owned(),
+                Json::Arr(
+                    self.invariants
+                        .iter()
+                        .map(|i| Json::Str(i.to_string()))
+                        .collect(),
+                ),
+            ),
+            ("format".to_owned(), self.format.to_json()),
+            (
+                "fixtures".to_owned(),
+                Json::Arr(self.fixtures.iter().map(|f| Json::Str(hex(f))).collect()),
+            ),
+        ]);
+        let mut out = String::new();
+        doc.render(0, &mut out);
+        out.push('\n');
+        out
+    }
+
+    /// Human oriented markdown tables of the fields of every named type in the format.
+    pub fn field_tables(&self) -> String {
+        let mut tables = BTreeMap::new();
+        self.format.tables(&mut tables);
+        let mut out = format!("# {}\n\n", self.name);
+        for invariant in self.invariants {
+            let _ = writeln!(out, "- {}", invariant);
+        }
+        if !self.invariants.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(&tables.into_values().collect::<Vec<_>>().join("\n"));
+        out
+    }
+}
+
+const NODE_INVARIANTS: &[&str] = &[
+    "Encoded as a cbor map with the fields in the listed order.",
+    "id is the hash of the item followed by each dependency id in ascending order.",
+    "item_id is the hash of the item alone.",
+    "dependency_ids is sorted ascending and deduplicated.",
+    "Decoders only read item and dependency_ids and recompute id and item_id.",
+    "_phantom is always null.",
+    "The fixtures use SipHash24 with a fixed key. The id lengths depend on the HashWriter.",
+];
+
+const NODE_HANDLE_INVARIANTS: &[&str] = &[
+    "Encoded as a cbor map with the fields in the listed order.",
+    "Carries the id, item_id and dependency_ids of a Node without its item.",
+    "dependency_ids is sorted ascending and deduplicated.",
+    "Decoders trust the ids. The item_id is checked when the payload is fetched.",
+];
+
+const READ_TOKEN_INVARIANTS: &[&str] = &[
+    "Encoded as a cbor map with the fields in the listed order.",
+    "digest is the hash of the roots in ascending order.",
+    "roots is sorted ascending and deduplicated.",
+    "Decoders trust the digest. A token is only satisfied once every root is stored.",
+];
+
+const TRACE_HEADER_INVARIANTS: &[&str] = &[
+    "A trace is a TraceHeader followed by a stream of TraceEntry values.",
+    "format is always merkle-dag-trace.",
+];
+
+const TRACE_ENTRY_INVARIANTS: &[&str] = &[
+    "Enums are externally tagged. Unit variants are encoded as the variant name and other variants as a single entry map from the variant name to the value.",
+    "item is null when payloads are scrubbed from the trace.",
+    "Ids sets are sorted ascending and deduplicated.",
+];
+
+// The key of the hasher the fixtures are built with.
+struct FixtureKey;
+
+impl HashKey for FixtureKey {
+    fn key() -> [u8; 32] {
+        *b"schema fixtures are not a secret"
+    }
+}
+
+type FixtureHasher = SipHash24<FixtureKey>;
+
+fn node_fixtures() -> Vec<Node<FixtureHasher>> {
+    let quake = Node::new("quake", BTreeSet::new());
+    let qualm = Node::new("qualm", BTreeSet::new());
+    let quell = Node::new(
+        "quell",
+        BTreeSet::from([quake.id().to_vec(), qualm.id().to_vec()]),
+    );
+    vec![quake, qualm, quell]
+}
+
+fn read_token_fixtures() -> Result<Vec<ReadToken>> {
+    let mut dag = Merkle::<BTreeStore<FixtureHasher>, FixtureHasher>::new(BTreeStore::new());
+    let mut tokens = vec![dag.read_token()];
+    for node in node_fixtures() {
+        dag.add_node(node.item(), node.dependency_ids().clone())
+            .map_err(|e| SchemaError(format!("{:?}", e)))?;
+        tokens.push(dag.read_token());
+    }
+    Ok(tokens)
+}
+
+fn trace_entry_fixtures() -> Vec<TraceEntry> {
+    let nodes = node_fixtures();
+    let traced = |node: &Node<FixtureHasher>, item: bool| TracedNode {
+        id: node.id().to_vec(),
+        item_id: node.item_id().to_vec(),
+        item: if item {
+            Some(node.item().to_vec())
+        } else {
+            None
+        },
+        dependency_ids: node.dependency_ids().clone(),
+        id_version: node.id_version(),
+        attributes: node.attributes().clone(),
+        detached: node.is_detached(),
+        signature: node.signature().cloned(),
+    };
+    vec![
+        TraceEntry {
+            op: TraceOp::Contains,
+            key: nodes[0].id().to_vec(),
+            result: TraceResult::Bool(true),
+        },
+        TraceEntry {
+            op: TraceOp::Get,
+            key: nodes[2].id().to_vec(),
+            result: TraceResult::Node(Some(traced(&nodes[2], true))),
+        },
+        TraceEntry {
+            op: TraceOp::Get,
+            key: nodes[1].id().to_vec(),
+            result: TraceResult::Node(Some(traced(&nodes[1], false))),
+        },
+        TraceEntry {
+            op: TraceOp::Store,
+            key: nodes[0].id().to_vec(),
+            result: TraceResult::Error("StoreFailure".to_owned()),
+        },
+        TraceEntry {
+            op: TraceOp::ChildrenOf,
+            key: nodes[0].id().to_vec(),
+            result: TraceResult::Ids(BTreeSet::from([nodes[2].id().to_vec()])),
+        },
+        TraceEntry {
+            op: TraceOp::Delete,
+            key: nodes[2].id().to_vec(),
+            result: TraceResult::Done,
+        },
+        TraceEntry {
+            op: TraceOp::Ids,
+            key: Vec::new(),
+            result: TraceResult::IdList(vec![nodes[0].id().to_vec(), nodes[1].id().to_vec()]),
+        },
+        TraceEntry {
+            op: TraceOp::PersistRoots,
+            key: Vec::new(),
+            result: TraceResult::Roots(Some(PersistedRoots {
+                roots: BTreeSet::from([nodes[1].id().to_vec()]),
+                sticky: BTreeSet::from([nodes[1].id().to_vec()]),
+                pins: BTreeSet::from([nodes[0].id().to_vec()]),
+                stored_bytes: Some(128),
+            })),
+        },
+        TraceEntry {
+            op: TraceOp::PersistedRoots,
+            key: Vec::new(),
+            result: TraceResult::Roots(None),
+        },
+        TraceEntry {
+            op: TraceOp::BeginBatch,
+            key: Vec::new(),
+            result: TraceResult::Done,
+        },
+        TraceEntry {
+            op: TraceOp::CommitBatch,
+            key: Vec::new(),
+            result: TraceResult::Done,
+        },
+        TraceEntry {
+            op: TraceOp::RollbackBatch,
+            key: Vec::new(),
+            result: TraceResult::Error("Unsupported(\"rollback_batch\")".to_owned()),
+        },
+    ]
+}
+
+/// Describe every wire visible type from its golden fixtures.
+pub fn wire_schemas() -> Result<Vec<WireSchema>> {
+    Ok(vec![
+        WireSchema::describe("Node", NODE_INVARIANTS, &node_fixtures())?,
+        WireSchema::describe(
+            "NodeHandle",
+            NODE_HANDLE_INVARIANTS,
+            &node_fixtures()
+                .iter()
+                .map(NodeHandle::from)
+                .collect::<Vec<NodeHandle<FixtureHasher>>>(),
+        )?,
+        WireSchema::describe("ReadToken", READ_TOKEN_INVARIANTS, &read_token_fixtures()?)?,
+        WireSchema::describe(
+            "TraceHeader",
+            TRACE_HEADER_INVARIANTS,
+            &[TraceHeader {
+                format: TRACE_FORMAT.to_owned(),
+                version: TRACE_VERSION,
+            }],
+        )?,
+        WireSchema::describe(
+            "TraceEntry",
+            TRACE_ENTRY_INVARIANTS,
+            &trace_entry_fixtures(),
+        )?,
+    ])
+}
+
+/// The directory the committed schema artifacts live in.
+pub fn schema_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("schemas")
+}
+
+fn artifacts(schema: &WireSchema) -> [(String, String); 2] {
+    [
+        (format!("{}.json", schema.name), schema.to_json()),
+        (format!("{}.md", schema.name), schema.field_tables()),
+    ]
+}
+
+/// Write the schema artifacts for every wire visible type into `dir`.
+pub fn generate<P: AsRef<Path>>(dir: P) -> Result<()> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir).map_err(|e| SchemaError(format!("{:?}", e)))?;
+    for schema in wire_schemas()? {
+        for (file, contents) in artifacts(&schema) {
+            std::fs::write(dir.join(file), contents)
+                .map_err(|e| SchemaError(format!("{:?}", e)))?;
+        }
+    }
+    Ok(())
+}
+
+/// Check the schema artifacts in `dir` against the formats traced from the fixtures.
+pub fn verify_fixtures_in<P: AsRef<Path>>(dir: P) -> Result<()> {
+    for schema in wire_schemas()? {
+        for (file, contents) in artifacts(&schema) {
+            let path = dir.as_ref().join(&file);
+            let committed = std::fs::read_to_string(&path)
+                .map_err(|e| SchemaError(format!("Unable to read {:?}: {:?}", path, e)))?;
+            if committed != contents {
+                return Err(SchemaError(format!(
+                    "{} has drifted from the wire format. Regenerate the schemas with schema::generate.",
+                    file
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check the committed schema artifacts under `schemas/` against the formats traced
+/// from the fixtures.
+pub fn verify_fixtures() -> Result<()> {
+    verify_fixtures_in(schema_dir())
+}
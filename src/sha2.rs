@@ -0,0 +1,48 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Implements the [HashWriter] interface for the SHA-2 hash functions.
+//! Requires the `sha2` feature to be enabled.
+
+use crate::hash::*;
+use sha2::digest::Digest;
+pub use sha2::{Sha256, Sha512};
+
+macro_rules! hash_writer_impl {
+    ($tname:ident, $len:expr) => {
+        impl HashWriter for $tname {
+            const OUTPUT_LEN: usize = $len;
+            const ALGORITHM_ID: &'static str = "sha2";
+
+            fn record<I: Iterator<Item = u8>>(&mut self, bs: I) {
+                let vec: Vec<u8> = bs.collect();
+                self.update(&vec);
+            }
+
+            fn record_bytes(&mut self, bs: &[u8]) {
+                self.update(bs);
+            }
+
+            fn hash(&self) -> Vec<u8> {
+                let mut out = Vec::new();
+                // Finalizing consumes the hasher so hash a copy of the state.
+                let arr = self.clone().finalize();
+                out.extend(arr);
+                out
+            }
+        }
+    };
+}
+
+hash_writer_impl!(Sha256, 32);
+hash_writer_impl!(Sha512, 64);
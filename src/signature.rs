@@ -0,0 +1,113 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Ed25519 signatures of [nodes](Node) by their authors and the [SignaturePolicy] a
+//! [Merkle DAG](crate::dag::Merkle) checks them with. Requires the `signatures` feature.
+use std::collections::BTreeSet;
+
+use ed25519_dalek::{Signature, Signer, Verifier};
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+use crate::hash::HashWriter;
+use crate::id::hex;
+use crate::node::{Node, NodeSignature};
+use crate::store::{Result, StoreError};
+
+// Signatures sign this prefix followed by the node id so they can't be replayed as
+// signatures of anything else made with the same key.
+const SIGNATURE_CONTEXT: &[u8] = b"merkle-dag/signature/v1";
+
+fn signed_message(id: &[u8]) -> Vec<u8> {
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(id);
+    message
+}
+
+impl<HW> Node<HW>
+where
+    HW: HashWriter,
+{
+    /// Sign the id of this node with the `key` of its author replacing any signature attached
+    /// before.
+    pub fn sign(self, key: &SigningKey) -> Self {
+        let signature = key.sign(&signed_message(self.id()));
+        self.with_signature(NodeSignature {
+            author: key.verifying_key().to_bytes().to_vec(),
+            signature: signature.to_bytes().to_vec(),
+        })
+    }
+
+    /// Verify the signature of this node returning its author. Fails with
+    /// [StoreError::InvalidSignature] if the node is unsigned or the signature doesn't verify.
+    pub fn verify_signature(&self) -> Result<VerifyingKey> {
+        let invalid = |reason: &str| StoreError::InvalidSignature {
+            id: self.id().to_vec(),
+            reason: reason.to_owned(),
+        };
+        let signed = self.signature().ok_or_else(|| invalid("unsigned"))?;
+        let author = <[u8; 32]>::try_from(signed.author.as_slice())
+            .ok()
+            .and_then(|author| VerifyingKey::from_bytes(&author).ok())
+            .ok_or_else(|| invalid("malformed author key"))?;
+        let signature =
+            Signature::from_slice(&signed.signature).map_err(|_| invalid("malformed signature"))?;
+        author
+            .verify(&signed_message(self.id()), &signature)
+            .map_err(|_| invalid("signature doesn't verify"))?;
+        Ok(author)
+    }
+}
+
+/// Which [nodes](Node) a [Merkle DAG](crate::dag::Merkle) accepts by their signatures. It is
+/// checked for every node added to the DAG including the nodes of
+/// [Merkle::add_nodes](crate::dag::Merkle::add_nodes),
+/// [Merkle::bulk_load](crate::dag::Merkle::bulk_load) and transactions. Nodes already in the
+/// [Store](crate::store::Store) aren't checked again.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Accept signed and unsigned nodes without checking signatures.
+    #[default]
+    AcceptAll,
+    /// Accept only nodes with a valid signature by any author.
+    RequireValidSignature,
+    /// Accept only nodes with a valid signature by one of these authors given as the bytes of
+    /// their [VerifyingKey]s.
+    RequireKnownAuthor(BTreeSet<[u8; 32]>),
+}
+
+impl SignaturePolicy {
+    /// A [SignaturePolicy::RequireKnownAuthor] policy accepting the `authors`.
+    pub fn known_authors<I: IntoIterator<Item = VerifyingKey>>(authors: I) -> Self {
+        SignaturePolicy::RequireKnownAuthor(authors.into_iter().map(|key| key.to_bytes()).collect())
+    }
+
+    /// Check a [Node] against the policy failing with [StoreError::InvalidSignature] if it is
+    /// rejected.
+    pub fn check<HW: HashWriter>(&self, node: &Node<HW>) -> Result<()> {
+        match self {
+            SignaturePolicy::AcceptAll => Ok(()),
+            SignaturePolicy::RequireValidSignature => node.verify_signature().map(|_| ()),
+            SignaturePolicy::RequireKnownAuthor(authors) => {
+                let author = node.verify_signature()?;
+                if authors.contains(author.as_bytes()) {
+                    Ok(())
+                } else {
+                    Err(StoreError::InvalidSignature {
+                        id: node.id().to_vec(),
+                        reason: format!("unknown author {}", hex(author.as_bytes())),
+                    })
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Module implementing a [Store] interface using sled for a [Merkle Dag](crate::dag::Merkle).
+//! Requires the `sled` feature to be enabled.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::{
+    hash::HashWriter,
+    inspect::{BackendKind, StoreMeta, META_KEY},
+    node::Node,
+    store::{PersistedRoots, Result as StoreResult, Store, StoreError, ROOTS_KEY},
+};
+
+use ciborium;
+
+pub type Result<T> = std::result::Result<T, sled::Error>;
+
+/// Keys in the quarantine keyspace of a [SledStore] are the original id behind this prefix.
+pub const QUARANTINE_PREFIX: &[u8] = b"\0merkle-dag/quarantine/";
+
+/// A [Store] implementation using the pure Rust sled embedded database.
+pub struct SledStore {
+    store: sled::Db,
+    meta: StoreMeta,
+}
+
+impl SledStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_db(sled::open(path)?)
+    }
+
+    /// A store in a temporary directory that is removed when the store is dropped.
+    pub fn temporary() -> Result<Self> {
+        Self::open_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    // Wraps the database refreshing the metadata block.
+    fn open_db(db: sled::Db) -> Result<Self> {
+        let existing = db.get(META_KEY)?;
+        let me = Self {
+            store: db,
+            meta: StoreMeta::reopen(BackendKind::Sled, existing.as_deref()),
+        };
+        me.write_meta()?;
+        Ok(me)
+    }
+
+    fn write_meta(&self) -> Result<()> {
+        self.store.insert(META_KEY, self.meta.encode())?;
+        Ok(())
+    }
+
+    // Applies a batch of nodes recording the hash algorithm with the first of them.
+    fn write_nodes<HW: HashWriter>(&mut self, mut batch: sled::Batch) -> Result<()> {
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        if let Some(meta) = &pending {
+            batch.insert(META_KEY, meta.encode());
+        }
+        self.store.apply_batch(batch)?;
+        if let Some(meta) = pending {
+            self.meta = meta;
+        }
+        Ok(())
+    }
+
+    /// The metadata block of this store.
+    pub fn meta(&self) -> &StoreMeta {
+        &self.meta
+    }
+}
+
+impl<HW> Store<HW> for SledStore
+where
+    HW: HashWriter,
+{
+    fn contains(&self, id: &[u8]) -> StoreResult<bool> {
+        Ok(self.store.contains_key(id)?)
+    }
+
+    fn get(&self, id: &[u8]) -> StoreResult<Option<Node<HW>>> {
+        Ok(match self.store.get(id)? {
+            Some(bs) => ciborium::de::from_reader(bs.as_ref())
+                .map_err(|e| StoreError::StoreFailure(format!("Invalid serialization {:?}", e)))?,
+            None => None,
+        })
+    }
+
+    fn get_raw(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        Ok(self.store.get(id)?.map(|bs| bs.to_vec()))
+    }
+
+    fn store(&mut self, node: Node<HW>) -> StoreResult<()> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&node, &mut buf).unwrap();
+        let mut batch = sled::Batch::default();
+        batch.insert(node.id(), buf);
+        self.write_nodes::<HW>(batch)?;
+        Ok(())
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> StoreResult<()> {
+        let mut batch = sled::Batch::default();
+        batch.insert(node.id(), encoded);
+        self.write_nodes::<HW>(batch)?;
+        Ok(())
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> StoreResult<()> {
+        let mut batch = sled::Batch::default();
+        for (node, encoded) in records {
+            batch.insert(node.id(), encoded);
+        }
+        if let Some(roots) = roots {
+            batch.insert(ROOTS_KEY, roots.encode());
+        }
+        self.write_nodes::<HW>(batch)?;
+        Ok(())
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.write_nodes::<HW>(node_batch(nodes))?;
+        Ok(())
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> StoreResult<()> {
+        let mut batch = node_batch([node]);
+        batch.insert(ROOTS_KEY, roots.encode());
+        self.write_nodes::<HW>(batch)?;
+        Ok(())
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let mut batch = node_batch(nodes);
+        batch.insert(ROOTS_KEY, roots.encode());
+        self.write_nodes::<HW>(batch)?;
+        Ok(())
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> StoreResult<()> {
+        self.store.insert(ROOTS_KEY, roots.encode())?;
+        Ok(())
+    }
+
+    fn persisted_roots(&self) -> StoreResult<Option<PersistedRoots>> {
+        self.store
+            .get(ROOTS_KEY)?
+            .map(|bytes| PersistedRoots::decode(&bytes))
+            .transpose()
+    }
+
+    fn check_hash_algorithm(&self) -> StoreResult<()> {
+        self.meta.check_hash_algorithm::<HW>()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> StoreResult<()> {
+        self.store.remove(id)?;
+        Ok(())
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> StoreResult<Vec<Vec<u8>>> {
+        let mut ids = Vec::new();
+        for entry in self.store.scan_prefix(prefix).keys() {
+            if ids.len() == limit {
+                break;
+            }
+            let key = entry?;
+            if !is_reserved(&key) {
+                ids.push(key.to_vec());
+            }
+        }
+        Ok(ids)
+    }
+
+    fn ids(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Vec<u8>>> + '_>> {
+        Ok(Box::new(self.store.iter().keys().filter_map(
+            |key| match key {
+                Ok(key) if is_reserved(&key) => None,
+                Ok(key) => Some(Ok(key.to_vec())),
+                Err(err) => Some(Err(err.into())),
+            },
+        )))
+    }
+
+    fn key_length_histogram(&self) -> StoreResult<BTreeMap<usize, u64>> {
+        let mut histogram = BTreeMap::new();
+        for key in self.store.iter().keys() {
+            let key = key?;
+            if !is_reserved(&key) {
+                *histogram.entry(key.len()).or_insert(0) += 1;
+            }
+        }
+        Ok(histogram)
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> StoreResult<u64> {
+        let mut batch = sled::Batch::default();
+        let mut moved = 0;
+        for entry in self.store.iter() {
+            let (key, val) = entry?;
+            if key.len() == expected_len || is_reserved(&key) {
+                continue;
+            }
+            batch.insert(quarantine_key(&key), val);
+            batch.remove(key);
+            moved += 1;
+        }
+        self.store.apply_batch(batch)?;
+        self.meta.options.insert("quarantine".to_owned());
+        self.meta.record_maintenance();
+        self.write_meta()?;
+        Ok(moved)
+    }
+
+    fn flush(&mut self) -> StoreResult<()> {
+        self.store.flush()?;
+        Ok(())
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        Ok(self.store.get(quarantine_key(id))?.map(|bs| bs.to_vec()))
+    }
+}
+
+// A batch writing the cbor encoding of each node under its id.
+fn node_batch<HW, I>(nodes: I) -> sled::Batch
+where
+    HW: HashWriter,
+    I: IntoIterator<Item = Node<HW>>,
+{
+    let mut batch = sled::Batch::default();
+    for node in nodes {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&node, &mut buf).unwrap();
+        batch.insert(node.id(), buf);
+    }
+    batch
+}
+
+// Keys that don't hold a node: the quarantine keyspace, the metadata block and the roots.
+fn is_reserved(key: &[u8]) -> bool {
+    key == META_KEY || key == ROOTS_KEY || key.starts_with(QUARANTINE_PREFIX)
+}
+
+fn quarantine_key(id: &[u8]) -> Vec<u8> {
+    let mut key = QUARANTINE_PREFIX.to_vec();
+    key.extend_from_slice(id);
+    key
+}
+
+impl From<sled::Error> for StoreError {
+    fn from(err: sled::Error) -> Self {
+        StoreError::StoreFailure(format!("{}", err))
+    }
+}
+
+impl Default for SledStore {
+    fn default() -> Self {
+        Self::temporary().unwrap()
+    }
+}
@@ -13,12 +13,20 @@
 // limitations under the License.
 //! Module implementing a [Store] interface using sqlite for a [Merkle Dag](crate::dag::Merkle).
 //! Requires the `sqlite` feature to be enabled.
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
+use std::time::Duration;
 
 use crate::{
+    dag::CachedValue,
     hash::HashWriter,
+    inspect::{BackendKind, MetaBlock, StoreDescription, StoreMeta, META_KEY},
     node::Node,
-    store::{Result as StoreResult, Store, StoreError},
+    payload_index::{PayloadIndexer, PayloadSearch},
+    store::{
+        PersistedRoots, Result as StoreResult, Store, StoreError, StoreStats, TransactionalStore,
+        ROOTS_KEY,
+    },
 };
 
 use ciborium;
@@ -27,31 +35,345 @@ use rusqlite::{self, OptionalExtension};
 /// A [Store] implementation using the [rusqlite] bindings for sqlite.
 pub struct SqliteStore {
     conn: rusqlite::Connection,
+    indexer: Option<Box<dyn PayloadIndexer + Send>>,
+    indexing: bool,
+    closure_sizes: bool,
+    meta: StoreMeta,
+}
+
+// The number of ids looked up per query by get_many.
+const GET_MANY_CHUNK: usize = 500;
+
+// The number of ids read per query by ids.
+const ID_PAGE_SIZE: usize = 1000;
+
+// The number of prepared statements kept per connection. The hot reads and writes plus the
+// chunked lookups of get_many and contains_many fit comfortably.
+const STATEMENT_CACHE_SIZE: usize = 64;
+
+// The steps from one schema version to the next. Step n takes a database at version n to
+// version n + 1 and runs in its own transaction. Version 0 is a database from before the
+// schema was versioned, which may or may not have its tables yet, so the first step only
+// creates what is missing. New schema changes are added as new steps at the end.
+const MIGRATIONS: &[&[Migration]] = &[
+    &[Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS content_store(content_id BLOB PRIMARY KEY, node BLOB NOT NULL);
+        CREATE TABLE IF NOT EXISTS merkle_dag_meta(key BLOB PRIMARY KEY, value BLOB NOT NULL);",
+    )],
+    // The closure size columns. Databases from before this step may already have the first
+    // two columns since with_closure_sizes used to add them itself.
+    &[
+        Migration::AddColumn("content_store", "closure_size", "INTEGER"),
+        Migration::AddColumn(
+            "content_store",
+            "closure_stale",
+            "INTEGER NOT NULL DEFAULT 0",
+        ),
+        Migration::AddColumn(
+            "content_store",
+            "closure_epoch",
+            "INTEGER NOT NULL DEFAULT 0",
+        ),
+        Migration::Sql(
+            "CREATE TABLE IF NOT EXISTS closure_missing(
+            missing_id BLOB NOT NULL,
+            content_id BLOB NOT NULL,
+            PRIMARY KEY (missing_id, content_id));
+            CREATE TABLE IF NOT EXISTS closure_epoch(epoch INTEGER NOT NULL);
+            INSERT INTO closure_epoch (epoch) SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM closure_epoch);",
+        ),
+    ],
+    &[Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS payload_index(
+        term BLOB NOT NULL,
+        content_id BLOB NOT NULL,
+        PRIMARY KEY (term, content_id));",
+    )],
+];
+
+// A single change in a migration step.
+enum Migration {
+    Sql(&'static str),
+    // Adds the column with the definition to the table unless it already has it.
+    AddColumn(&'static str, &'static str, &'static str),
+}
+
+impl Migration {
+    fn apply(&self, conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+        match self {
+            Migration::Sql(sql) => conn.execute_batch(sql),
+            Migration::AddColumn(table, column, definition) => {
+                let has_column: bool = conn.query_row(
+                    "select count(*) > 0 from pragma_table_info(?) where name = ?",
+                    [table, column],
+                    |r| r.get(0),
+                )?;
+                if !has_column {
+                    conn.execute_batch(&format!(
+                        "ALTER TABLE {} ADD COLUMN {} {};",
+                        table, column, definition
+                    ))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// How hard sqlite works to get a committed transaction onto the disk. See the sqlite
+/// documentation of the `synchronous` pragma.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn pragma_value(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Options for [SqliteStore::connect_with_opts].
+#[derive(Clone, Debug)]
+pub struct SqliteOpts {
+    /// Use a write ahead log so connections reading the database aren't blocked by a writer.
+    pub wal: bool,
+    /// How long to wait for a lock held by another connection before failing with
+    /// `database is locked`.
+    pub busy_timeout: Duration,
+    /// How hard sqlite works to get committed transactions onto the disk.
+    pub synchronous: Synchronous,
+    /// The page size in bytes. Only applies to a database that has no tables yet.
+    pub page_size: Option<u32>,
+    /// The size of the page cache in KiB.
+    pub cache_size_kib: Option<u32>,
+    /// Open the database read only. The schema isn't migrated and every write fails.
+    pub read_only: bool,
+}
+
+impl Default for SqliteOpts {
+    fn default() -> Self {
+        Self {
+            wal: true,
+            busy_timeout: Duration::from_secs(5),
+            // Normal is durable in WAL mode apart from the last transactions before a power
+            // loss.
+            synchronous: Synchronous::Normal,
+            page_size: None,
+            cache_size_kib: None,
+            read_only: false,
+        }
+    }
 }
 
 impl SqliteStore {
+    /// Open the database at the path bringing its schema up to date.
     pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, rusqlite::Error> {
-        Ok(Self {
-            conn: rusqlite::Connection::open(path)?,
-        })
+        Self::open_connection(rusqlite::Connection::open(path)?, false)
+    }
+
+    /// Open the database at the path configuring the connection with the `opts`. Unless it is
+    /// opened read only its schema is brought up to date.
+    pub fn connect_with_opts<P: AsRef<Path>>(
+        path: P,
+        opts: &SqliteOpts,
+    ) -> Result<Self, rusqlite::Error> {
+        let conn = if opts.read_only {
+            rusqlite::Connection::open_with_flags(
+                path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?
+        } else {
+            rusqlite::Connection::open(path)?
+        };
+        conn.busy_timeout(opts.busy_timeout)?;
+        // The page size has to be set before the journal mode.
+        if let Some(page_size) = opts.page_size {
+            conn.pragma_update(None, "page_size", page_size)?;
+        }
+        if let Some(cache_size) = opts.cache_size_kib {
+            // Negative sizes are in KiB instead of pages.
+            conn.pragma_update(None, "cache_size", -i64::from(cache_size))?;
+        }
+        conn.pragma_update(None, "synchronous", opts.synchronous.pragma_value())?;
+        if opts.wal && !opts.read_only {
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+        }
+        Self::open_connection(conn, opts.read_only)
     }
 
     pub fn in_memory() -> Result<Self, rusqlite::Error> {
+        Self::open_connection(rusqlite::Connection::open_in_memory()?, false)
+    }
+
+    // Wraps the connection migrating the schema and refreshing the metadata block. A read only
+    // connection only checks that it understands the schema.
+    fn open_connection(
+        conn: rusqlite::Connection,
+        read_only: bool,
+    ) -> Result<Self, rusqlite::Error> {
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
+        if read_only {
+            check_schema_version(schema_version(&conn)?)?;
+        } else {
+            migrate(&conn)?;
+        }
+        let existing: Option<Vec<u8>> = conn
+            .query_row(
+                "select value from merkle_dag_meta where key = ?",
+                [META_KEY],
+                |r| r.get(0),
+            )
+            .optional()?;
         let me = Self {
-            conn: rusqlite::Connection::open_in_memory()?,
+            conn,
+            indexer: None,
+            indexing: true,
+            closure_sizes: false,
+            meta: StoreMeta::reopen(BackendKind::Sqlite, existing.as_deref()),
         };
-        me.init_db()?;
+        if !read_only {
+            me.write_meta()?;
+        }
         Ok(me)
     }
 
+    fn write_meta(&self) -> Result<(), rusqlite::Error> {
+        write_pending_meta(&self.conn, Some(&self.meta))
+    }
+
+    fn record_option(&mut self, option: &str) -> Result<(), rusqlite::Error> {
+        if self.meta.options.insert(option.to_owned()) {
+            self.write_meta()?;
+        }
+        Ok(())
+    }
+
+    fn record_maintenance(&mut self) -> Result<(), rusqlite::Error> {
+        self.meta.record_maintenance();
+        self.write_meta()
+    }
+
+    // Keeps the block written by write_pending_meta once its transaction committed.
+    fn keep_meta(&mut self, pending: Option<StoreMeta>) {
+        if let Some(meta) = pending {
+            self.meta = meta;
+        }
+    }
+
+    /// The metadata block of this store.
+    pub fn meta(&self) -> &StoreMeta {
+        &self.meta
+    }
+
+    /// Maintain a [PayloadSearch] index in the `payload_index` table using the `indexer`.
+    /// Index rows are written in the same transaction as the node they index.
+    pub fn with_payload_indexer<I>(mut self, indexer: I) -> Result<Self, rusqlite::Error>
+    where
+        I: PayloadIndexer + Send + 'static,
+    {
+        self.indexer = Some(Box::new(indexer));
+        self.record_option("payload_index")?;
+        Ok(self)
+    }
+
+    /// Materialize the number of ancestors of every node in the nullable `closure_size` column
+    /// of the `content_store` table. Values are computed by [Store::refresh_closure_sizes] and
+    /// read with [Store::cached_closure_size].
+    ///
+    /// The ancestors of a stored node only grow when one of them was missing when the value
+    /// was computed. The missing ids are recorded in the `closure_missing` table and storing
+    /// one of them marks the values that depend on it stale. Deleting nodes bumps the epoch in
+    /// the `closure_epoch` table instead of touching every row, and values computed in an
+    /// earlier epoch read as stale.
+    pub fn with_closure_sizes(mut self) -> Result<Self, rusqlite::Error> {
+        self.closure_sizes = true;
+        self.record_option("closure_sizes")?;
+        Ok(self)
+    }
+
+    /// Get the underlying sqlite connection. This is useful for reading rows written by
+    /// the side effects of [TransactionalStore::store_with].
+    pub fn conn(&self) -> &rusqlite::Connection {
+        &self.conn
+    }
+
+    /// Bring the schema up to date. [SqliteStore::connect] already does this so calling it
+    /// again does nothing.
     pub fn init_db(&self) -> Result<(), rusqlite::Error> {
-        self.conn.execute_batch(
-            "BEGIN;
-        CREATE TABLE content_store(content_id BLOB PRIMARY KEY, node BLOB NOT NULL);
-        COMMIT;",
+        migrate(&self.conn)
+    }
+
+    /// Set the sqlite pragma `name` to `value` on the connection. This is an escape hatch for
+    /// tuning [SqliteOpts] doesn't cover.
+    pub fn pragma<V: rusqlite::ToSql>(&self, name: &str, value: V) -> Result<(), rusqlite::Error> {
+        self.conn.pragma_update(None, name, value)
+    }
+
+    /// The schema version of the database.
+    pub fn schema_version(&self) -> Result<usize, rusqlite::Error> {
+        schema_version(&self.conn)
+    }
+}
+
+// Runs the migration steps the database hasn't seen yet.
+fn migrate(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch("CREATE TABLE IF NOT EXISTS merkle_dag_schema(version INTEGER NOT NULL);")?;
+    let version = schema_version(conn)?;
+    check_schema_version(version)?;
+    for (step, changes) in MIGRATIONS.iter().enumerate().skip(version) {
+        let txn = conn.unchecked_transaction()?;
+        for change in changes.iter() {
+            change.apply(&txn)?;
+        }
+        txn.execute("delete from merkle_dag_schema", [])?;
+        txn.execute(
+            "insert into merkle_dag_schema (version) values (?)",
+            [step as i64 + 1],
         )?;
-        Ok(())
+        txn.commit()?;
     }
+    Ok(())
+}
+
+// Refuses a schema from a newer version of this crate.
+fn check_schema_version(version: usize) -> Result<(), rusqlite::Error> {
+    if version > MIGRATIONS.len() {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+            Some(format!(
+                "Schema version {} is newer than the supported version {}",
+                version,
+                MIGRATIONS.len()
+            )),
+        ));
+    }
+    Ok(())
+}
+
+fn schema_version(conn: &rusqlite::Connection) -> Result<usize, rusqlite::Error> {
+    let versioned: bool = conn.query_row(
+        "select count(*) > 0 from sqlite_master where type = 'table' and name = 'merkle_dag_schema'",
+        [],
+        |r| r.get(0),
+    )?;
+    if !versioned {
+        return Ok(0);
+    }
+    let version: Option<i64> =
+        conn.query_row("select max(version) from merkle_dag_schema", [], |r| {
+            r.get(0)
+        })?;
+    Ok(version.unwrap_or(0) as usize)
 }
 
 impl<HW> Store<HW> for SqliteStore
@@ -59,44 +381,736 @@ where
     HW: HashWriter,
 {
     fn contains(&self, id: &[u8]) -> StoreResult<bool> {
-        let val: Option<Vec<u8>> = self
+        let mut stmt = self
+            .conn
+            .prepare_cached("select 1 from content_store where content_id = ?")?;
+        Ok(stmt.exists([id])?)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> StoreResult<Vec<bool>> {
+        let mut found: BTreeSet<Vec<u8>> = BTreeSet::new();
+        for chunk in ids.chunks(GET_MANY_CHUNK) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let mut stmt = self.conn.prepare_cached(&format!(
+                "select content_id from content_store where content_id in ({})",
+                placeholders
+            ))?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(chunk.iter()), |r| {
+                r.get::<_, Vec<u8>>(0)
+            })?;
+            for row in rows {
+                found.insert(row?);
+            }
+        }
+        Ok(ids.iter().map(|id| found.contains(*id)).collect())
+    }
+
+    fn get(&self, id: &[u8]) -> StoreResult<Option<Node<HW>>> {
+        get_node(&self.conn, id)
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> StoreResult<Vec<Option<Node<HW>>>> {
+        let mut found: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+        // Stay well below the bound parameter limit of older sqlite versions.
+        for chunk in ids.chunks(GET_MANY_CHUNK) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let mut stmt = self.conn.prepare_cached(&format!(
+                "select content_id, node from content_store where content_id in ({})",
+                placeholders
+            ))?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(chunk.iter()), |r| {
+                Ok((r.get::<_, Vec<u8>>(0)?, r.get::<_, Vec<u8>>(1)?))
+            })?;
+            for row in rows {
+                let (id, node) = row?;
+                found.insert(id, node);
+            }
+        }
+        let mut nodes = Vec::with_capacity(ids.len());
+        for id in ids {
+            nodes.push(match found.get(*id) {
+                Some(bs) => ciborium::de::from_reader(bs.as_slice()).map_err(|e| {
+                    StoreError::StoreFailure(format!("Invalid serialization {:?}", e))
+                })?,
+                None => None,
+            });
+        }
+        Ok(nodes)
+    }
+
+    fn get_raw(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        Ok(get_encoded(&self.conn, id)?)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> StoreResult<()> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&node, &mut buf).unwrap();
+        let indexer = self.indexer.as_deref().filter(|_| self.indexing);
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        // A savepoint nests inside a batch started by begin_batch.
+        let txn = self.conn.savepoint()?;
+        insert_node(&txn, indexer, self.closure_sizes, &node, &buf)?;
+        write_pending_meta(&txn, pending.as_ref())?;
+        txn.commit()?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> StoreResult<()> {
+        let indexer = self.indexer.as_deref().filter(|_| self.indexing);
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        let txn = self.conn.savepoint()?;
+        insert_node(&txn, indexer, self.closure_sizes, &node, &encoded)?;
+        write_pending_meta(&txn, pending.as_ref())?;
+        txn.commit()?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> StoreResult<()> {
+        let indexer = self.indexer.as_deref().filter(|_| self.indexing);
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        let txn = self.conn.savepoint()?;
+        for (node, encoded) in &records {
+            insert_node(&txn, indexer, self.closure_sizes, node, encoded)?;
+        }
+        if let Some(roots) = roots {
+            write_roots(&txn, roots)?;
+        }
+        write_pending_meta(&txn, pending.as_ref())?;
+        txn.commit()?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let indexer = self.indexer.as_deref().filter(|_| self.indexing);
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        let txn = self.conn.savepoint()?;
+        let mut buf = Vec::new();
+        for node in nodes {
+            buf.clear();
+            ciborium::ser::into_writer(&node, &mut buf).unwrap();
+            insert_node(&txn, indexer, self.closure_sizes, &node, &buf)?;
+        }
+        write_pending_meta(&txn, pending.as_ref())?;
+        txn.commit()?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> StoreResult<()> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&node, &mut buf).unwrap();
+        let indexer = self.indexer.as_deref().filter(|_| self.indexing);
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        let txn = self.conn.savepoint()?;
+        insert_node(&txn, indexer, self.closure_sizes, &node, &buf)?;
+        write_roots(&txn, roots)?;
+        write_pending_meta(&txn, pending.as_ref())?;
+        txn.commit()?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> StoreResult<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let indexer = self.indexer.as_deref().filter(|_| self.indexing);
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        let txn = self.conn.savepoint()?;
+        let mut buf = Vec::new();
+        for node in nodes {
+            buf.clear();
+            ciborium::ser::into_writer(&node, &mut buf).unwrap();
+            insert_node(&txn, indexer, self.closure_sizes, &node, &buf)?;
+        }
+        write_roots(&txn, roots)?;
+        write_pending_meta(&txn, pending.as_ref())?;
+        txn.commit()?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> StoreResult<()> {
+        write_roots(&self.conn, roots)?;
+        Ok(())
+    }
+
+    fn persisted_roots(&self) -> StoreResult<Option<PersistedRoots>> {
+        let bytes: Option<Vec<u8>> = self
+            .conn
+            .prepare_cached("select value from merkle_dag_meta where key = ?")?
+            .query_row([ROOTS_KEY], |r| r.get(0))
+            .optional()?;
+        bytes
+            .map(|bytes| PersistedRoots::decode(&bytes))
+            .transpose()
+    }
+
+    fn check_hash_algorithm(&self) -> StoreResult<()> {
+        self.meta.check_hash_algorithm::<HW>()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> StoreResult<()> {
+        let txn = self.conn.savepoint()?;
+        txn.execute("delete from content_store where content_id = ?", [id])?;
+        if self.indexer.is_some() {
+            txn.execute("delete from payload_index where content_id = ?", [id])?;
+        }
+        if self.closure_sizes {
+            txn.execute("delete from closure_missing where content_id = ?", [id])?;
+            // The ancestor counts of any remaining descendants shrank.
+            bump_closure_epoch(&txn)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn ids(&self) -> StoreResult<Box<dyn Iterator<Item = StoreResult<Vec<u8>>> + '_>> {
+        // Page through the ids so the iterator doesn't hold a statement open on the connection.
+        let mut page: std::vec::IntoIter<Vec<u8>> = Vec::new().into_iter();
+        let mut after: Option<Vec<u8>> = None;
+        let mut done = false;
+        Ok(Box::new(std::iter::from_fn(move || loop {
+            if let Some(id) = page.next() {
+                after = Some(id.clone());
+                return Some(Ok(id));
+            }
+            if done {
+                return None;
+            }
+            match id_page(&self.conn, after.as_deref()) {
+                Ok(ids) => {
+                    done = ids.len() < ID_PAGE_SIZE;
+                    page = ids.into_iter();
+                }
+                Err(e) => {
+                    done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        })))
+    }
+
+    fn len(&self) -> StoreResult<usize> {
+        let count: i64 = self
+            .conn
+            .query_row("select count(*) from content_store", [], |r| r.get(0))?;
+        Ok(count as usize)
+    }
+
+    fn is_empty(&self) -> StoreResult<bool> {
+        let any: bool =
+            self.conn
+                .query_row("select exists(select 1 from content_store)", [], |r| {
+                    r.get(0)
+                })?;
+        Ok(!any)
+    }
+
+    fn key_length_histogram(&self) -> StoreResult<BTreeMap<usize, u64>> {
+        let mut stmt = self
+            .conn
+            .prepare("select length(content_id), count(*) from content_store group by 1")?;
+        let rows = stmt.query_map([], |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?)))?;
+        let mut histogram = BTreeMap::new();
+        for row in rows {
+            let (len, count) = row?;
+            histogram.insert(len as usize, count as u64);
+        }
+        Ok(histogram)
+    }
+
+    fn stats(&self) -> StoreResult<StoreStats> {
+        let (count, total, max): (i64, i64, i64) = self.conn.query_row(
+            "select count(*), coalesce(sum(length(node)), 0), coalesce(max(length(node)), 0)
+            from content_store",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )?;
+        Ok(StoreStats {
+            node_count: count as u64,
+            total_bytes: total as u64,
+            max_node_bytes: max as u64,
+        })
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> StoreResult<u64> {
+        let expected_len = expected_len as i64;
+        let txn = self.conn.savepoint()?;
+        txn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS content_quarantine(content_id BLOB PRIMARY KEY, node BLOB NOT NULL);",
+        )?;
+        txn.execute(
+            "insert or replace into content_quarantine (content_id, node)
+            select content_id, node from content_store where length(content_id) != ?",
+            [expected_len],
+        )?;
+        if self.indexer.is_some() {
+            txn.execute(
+                "delete from payload_index where length(content_id) != ?",
+                [expected_len],
+            )?;
+        }
+        let moved = txn.execute(
+            "delete from content_store where length(content_id) != ?",
+            [expected_len],
+        )?;
+        if self.closure_sizes && moved > 0 {
+            // Any remaining node may have lost ancestors.
+            bump_closure_epoch(&txn)?;
+        }
+        txn.commit()?;
+        self.record_option("quarantine")?;
+        self.record_maintenance()?;
+        Ok(moved as u64)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> StoreResult<Option<Vec<u8>>> {
+        let has_quarantine: bool = self.conn.query_row(
+            "select count(*) > 0 from sqlite_master where type = 'table' and name = 'content_quarantine'",
+            [],
+            |r| r.get(0),
+        )?;
+        if !has_quarantine {
+            return Ok(None);
+        }
+        Ok(self
             .conn
             .query_row(
-                "select node from content_store where content_id = ?",
+                "select node from content_quarantine where content_id = ?",
                 [id],
                 |r| r.get(0),
             )
-            .optional()?;
-        Ok(val.is_some())
+            .optional()?)
     }
 
-    fn get(&self, id: &[u8]) -> StoreResult<Option<Node<HW>>> {
-        let result: Option<Vec<u8>> = self
+    fn cached_closure_size(&self, id: &[u8]) -> StoreResult<CachedValue<u64>> {
+        if !self.closure_sizes {
+            return Err(StoreError::Unsupported("cached_closure_size"));
+        }
+        let row: Option<(Option<i64>, bool)> = self
             .conn
             .query_row(
-                "select node from content_store where content_id = ?",
+                "select closure_size, closure_stale or closure_epoch < (select epoch from closure_epoch)
+                from content_store where content_id = ?",
                 [id],
-                |r| r.get(0),
+                |r| Ok((r.get(0)?, r.get(1)?)),
             )
             .optional()?;
-        Ok(match result {
-            Some(bs) => ciborium::de::from_reader(bs.as_slice())
-                .map_err(|e| StoreError::StoreFailure(format!("Invalid serialization {:?}", e)))?,
-            None => None,
+        Ok(match row {
+            None => return Err(StoreError::NoSuchNode(id.to_vec())),
+            Some((None, _)) => CachedValue::Missing,
+            Some((Some(size), false)) => CachedValue::Fresh(size as u64),
+            Some((Some(size), true)) => CachedValue::Stale(size as u64),
         })
     }
 
-    fn store(&mut self, node: Node<HW>) -> StoreResult<()> {
+    fn refresh_closure_sizes(&mut self, batch: usize) -> StoreResult<usize> {
+        if !self.closure_sizes {
+            return Err(StoreError::Unsupported("refresh_closure_sizes"));
+        }
+        let limit = batch.min(i64::MAX as usize) as i64;
+        let ids = {
+            let mut stmt = self.conn.prepare(
+                "select content_id from content_store
+                where closure_size is null or closure_stale = 1
+                or closure_epoch < (select epoch from closure_epoch)
+                order by rowid desc limit ?",
+            )?;
+            let rows = stmt.query_map([limit], |r| r.get(0))?;
+            rows.collect::<Result<Vec<Vec<u8>>, rusqlite::Error>>()?
+        };
+        let txn = self.conn.transaction()?;
+        for id in ids.iter() {
+            let (size, missing) = closure_of::<HW>(&txn, id)?;
+            txn.execute(
+                "update content_store set closure_size = ?, closure_stale = 0,
+                closure_epoch = (select epoch from closure_epoch) where content_id = ?",
+                rusqlite::params![size as i64, id],
+            )?;
+            txn.execute("delete from closure_missing where content_id = ?", [id])?;
+            for missing_id in missing {
+                txn.execute(
+                    "insert or ignore into closure_missing (missing_id, content_id) values (?, ?)",
+                    [missing_id.as_slice(), id],
+                )?;
+            }
+        }
+        txn.commit()?;
+        self.record_maintenance()?;
+        Ok(ids.len())
+    }
+
+    fn begin_batch(&mut self) -> StoreResult<()> {
+        self.conn.execute_batch("BEGIN")?;
+        Ok(())
+    }
+
+    fn commit_batch(&mut self) -> StoreResult<()> {
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback_batch(&mut self) -> StoreResult<()> {
+        self.conn.execute_batch("ROLLBACK")?;
+        // A hash algorithm recorded by the batch was rolled back with it.
+        let existing: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "select value from merkle_dag_meta where key = ?",
+                [META_KEY],
+                |r| r.get(0),
+            )
+            .optional()?;
+        self.meta.hash_algorithm =
+            StoreMeta::reopen(BackendKind::Sqlite, existing.as_deref()).hash_algorithm;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StoreResult<()> {
+        // Committed transactions are already synced. In WAL mode this also moves them from
+        // the log into the database file. Otherwise the checkpoint does nothing.
+        self.conn
+            .query_row("PRAGMA wal_checkpoint(FULL)", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> StoreResult<Vec<Vec<u8>>> {
+        let limit = limit as i64;
+        let ids = match prefix_upper_bound(prefix) {
+            Some(upper) => {
+                let mut stmt = self.conn.prepare(
+                    "select content_id from content_store where content_id >= ? and content_id < ? order by content_id limit ?",
+                )?;
+                let rows = stmt.query_map(rusqlite::params![prefix, upper, limit], |r| r.get(0))?;
+                rows.collect::<Result<Vec<Vec<u8>>, rusqlite::Error>>()?
+            }
+            None => {
+                let mut stmt = self.conn.prepare(
+                    "select content_id from content_store where content_id >= ? order by content_id limit ?",
+                )?;
+                let rows = stmt.query_map(rusqlite::params![prefix, limit], |r| r.get(0))?;
+                rows.collect::<Result<Vec<Vec<u8>>, rusqlite::Error>>()?
+            }
+        };
+        Ok(ids)
+    }
+}
+
+impl<HW> TransactionalStore<HW> for SqliteStore
+where
+    HW: HashWriter,
+{
+    type Transaction<'t> = rusqlite::Savepoint<'t>;
+    type Error = rusqlite::Error;
+
+    fn store_with<F>(
+        &mut self,
+        node: Node<HW>,
+        roots: Option<&PersistedRoots>,
+        side_effect: F,
+    ) -> StoreResult<()>
+    where
+        F: FnOnce(&Self::Transaction<'_>) -> Result<(), Self::Error>,
+    {
         let mut buf = Vec::new();
         ciborium::ser::into_writer(&node, &mut buf).unwrap();
-        self.conn.execute(
-            "insert into content_store (content_id, node) values (?, ?)",
-            [node.id(), buf.as_slice()],
+        // Dropping the savepoint without committing rolls it back. A savepoint nests inside a
+        // batch started by begin_batch.
+        let indexer = self.indexer.as_deref().filter(|_| self.indexing);
+        let pending = self.meta.recording_hash_algorithm::<HW>();
+        let txn = self.conn.savepoint()?;
+        insert_node(&txn, indexer, self.closure_sizes, &node, &buf)?;
+        side_effect(&txn)?;
+        if let Some(roots) = roots {
+            write_roots(&txn, roots)?;
+        }
+        write_pending_meta(&txn, pending.as_ref())?;
+        txn.commit()?;
+        self.keep_meta(pending);
+        Ok(())
+    }
+}
+
+impl<HW> PayloadSearch<HW> for SqliteStore
+where
+    HW: HashWriter,
+{
+    fn search_term(&self, term: &[u8], limit: usize) -> StoreResult<Vec<Vec<u8>>> {
+        if self.indexer.is_none() {
+            return Err(StoreError::Unsupported("search_term"));
+        }
+        let limit = limit.min(i64::MAX as usize) as i64;
+        let mut stmt = self.conn.prepare(
+            "select content_id from payload_index where term = ? order by content_id limit ?",
         )?;
+        let rows = stmt.query_map(rusqlite::params![term, limit], |r| r.get(0))?;
+        Ok(rows.collect::<Result<Vec<Vec<u8>>, rusqlite::Error>>()?)
+    }
+
+    fn payload_index_entries(&self) -> StoreResult<BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>> {
+        if self.indexer.is_none() {
+            return Err(StoreError::Unsupported("payload_index_entries"));
+        }
+        let mut stmt = self
+            .conn
+            .prepare("select term, content_id from payload_index")?;
+        let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        let mut entries: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> = BTreeMap::new();
+        for row in rows {
+            let (term, id): (Vec<u8>, Vec<u8>) = row?;
+            entries.entry(term).or_default().insert(id);
+        }
+        Ok(entries)
+    }
+
+    fn set_payload_indexing(&mut self, enabled: bool) {
+        self.indexing = enabled;
+    }
+
+    fn rebuild_payload_index(&mut self) -> StoreResult<()> {
+        let indexer = match self.indexer.as_deref() {
+            Some(indexer) => indexer,
+            None => return Err(StoreError::Unsupported("rebuild_payload_index")),
+        };
+        let txn = self.conn.transaction()?;
+        txn.execute("delete from payload_index", [])?;
+        {
+            let mut stmt = txn.prepare("select node from content_store")?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let bs: Vec<u8> = row.get(0)?;
+                let node: Node<HW> = ciborium::de::from_reader(bs.as_slice()).map_err(|e| {
+                    StoreError::StoreFailure(format!("Invalid serialization {:?}", e))
+                })?;
+                index_payload(&txn, indexer, node.id(), node.item())?;
+            }
+        }
+        txn.commit()?;
+        self.record_maintenance()?;
         Ok(())
     }
 }
 
+// Describes the sqlite database at the path opening it read only.
+pub(crate) fn inspect(path: &Path) -> StoreResult<StoreDescription> {
+    let conn = rusqlite::Connection::open_with_flags(
+        path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let tables = {
+        let mut stmt =
+            conn.prepare("select name from sqlite_master where type = 'table' order by name")?;
+        let rows = stmt.query_map([], |r| r.get(0))?;
+        rows.collect::<Result<Vec<String>, rusqlite::Error>>()?
+    };
+    let has_table = |name: &str| tables.iter().any(|table| table == name);
+    let meta = if has_table("merkle_dag_meta") {
+        let block: Option<Vec<u8>> = conn
+            .query_row(
+                "select value from merkle_dag_meta where key = ?",
+                [META_KEY],
+                |r| r.get(0),
+            )
+            .optional()?;
+        block
+            .map(|block| MetaBlock::decode(&block))
+            .unwrap_or(MetaBlock::Missing)
+    } else {
+        MetaBlock::Missing
+    };
+    let schema_version = schema_version(&conn)?;
+    let node_count = if has_table("content_store") {
+        let count: i64 = conn.query_row("select count(*) from content_store", [], |r| r.get(0))?;
+        Some(count as u64)
+    } else {
+        None
+    };
+    Ok(StoreDescription {
+        backend: BackendKind::Sqlite,
+        meta,
+        node_count,
+        details: BTreeMap::from([
+            ("tables".to_owned(), tables.join(",")),
+            ("schema-version".to_owned(), schema_version.to_string()),
+        ]),
+    })
+}
+
+// The next page of ids in ascending order after the `after` id.
+fn id_page(
+    conn: &rusqlite::Connection,
+    after: Option<&[u8]>,
+) -> Result<Vec<Vec<u8>>, rusqlite::Error> {
+    let limit = ID_PAGE_SIZE as i64;
+    match after {
+        Some(after) => {
+            let mut stmt = conn.prepare_cached(
+                "select content_id from content_store where content_id > ? order by content_id limit ?",
+            )?;
+            let rows = stmt.query_map(rusqlite::params![after, limit], |r| r.get(0))?;
+            rows.collect()
+        }
+        None => {
+            let mut stmt = conn.prepare_cached(
+                "select content_id from content_store order by content_id limit ?",
+            )?;
+            let rows = stmt.query_map([limit], |r| r.get(0))?;
+            rows.collect()
+        }
+    }
+}
+
+// Writes the metadata block if there is one. Blocks recording the hash algorithm are written
+// in the transaction of the first node.
+fn write_pending_meta(
+    conn: &rusqlite::Connection,
+    meta: Option<&StoreMeta>,
+) -> Result<(), rusqlite::Error> {
+    if let Some(meta) = meta {
+        conn.prepare_cached("insert or replace into merkle_dag_meta (key, value) values (?, ?)")?
+            .execute([META_KEY, meta.encode().as_slice()])?;
+    }
+    Ok(())
+}
+
+// Replaces the persisted roots. They live next to the metadata block.
+fn write_roots(conn: &rusqlite::Connection, roots: &PersistedRoots) -> Result<(), rusqlite::Error> {
+    conn.prepare_cached("insert or replace into merkle_dag_meta (key, value) values (?, ?)")?
+        .execute([ROOTS_KEY, roots.encode().as_slice()])?;
+    Ok(())
+}
+
+// Writes the encoded node and its index entries in the transaction. A node that is already
+// stored is left alone since the id covers its whole content.
+fn insert_node<HW: HashWriter>(
+    txn: &rusqlite::Connection,
+    indexer: Option<&(dyn PayloadIndexer + Send)>,
+    closure_sizes: bool,
+    node: &Node<HW>,
+    encoded: &[u8],
+) -> StoreResult<()> {
+    let inserted = txn
+        .prepare_cached(
+            "insert into content_store (content_id, node) values (?, ?)
+            on conflict(content_id) do nothing",
+        )?
+        .execute([node.id(), encoded])?;
+    if inserted == 0 {
+        return Ok(());
+    }
+    if let Some(indexer) = indexer {
+        index_payload(txn, indexer, node.id(), node.item())?;
+    }
+    if closure_sizes {
+        mark_closure_arrival(txn, node.id())?;
+    }
+    Ok(())
+}
+
+fn get_encoded(conn: &rusqlite::Connection, id: &[u8]) -> Result<Option<Vec<u8>>, rusqlite::Error> {
+    conn.prepare_cached("select node from content_store where content_id = ?")?
+        .query_row([id], |r| r.get(0))
+        .optional()
+}
+
+fn get_node<HW: HashWriter>(
+    conn: &rusqlite::Connection,
+    id: &[u8],
+) -> StoreResult<Option<Node<HW>>> {
+    Ok(match get_encoded(conn, id)? {
+        Some(bs) => ciborium::de::from_reader(bs.as_slice())
+            .map_err(|e| StoreError::StoreFailure(format!("Invalid serialization {:?}", e)))?,
+        None => None,
+    })
+}
+
+// Counts the stored ancestors of the id returning the count and the ancestor ids that are
+// not stored yet.
+fn closure_of<HW: HashWriter>(
+    conn: &rusqlite::Connection,
+    id: &[u8],
+) -> StoreResult<(u64, BTreeSet<Vec<u8>>)> {
+    let mut seen = BTreeSet::from([id.to_vec()]);
+    let mut missing = BTreeSet::new();
+    let mut stack = vec![id.to_vec()];
+    let mut size = 0;
+    while let Some(next) = stack.pop() {
+        let node = match get_node::<HW>(conn, &next)? {
+            Some(node) => node,
+            None => {
+                missing.insert(next);
+                continue;
+            }
+        };
+        if next != id {
+            size += 1;
+        }
+        for dep in node.dep_set() {
+            if seen.insert(dep.clone()) {
+                stack.push(dep.clone());
+            }
+        }
+    }
+    Ok((size, missing))
+}
+
+// Makes every closure size computed so far stale.
+fn bump_closure_epoch(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    conn.prepare_cached("update closure_epoch set epoch = epoch + 1")?
+        .execute([])?;
+    Ok(())
+}
+
+// Marks the closure sizes computed while this id was missing stale.
+fn mark_closure_arrival(conn: &rusqlite::Connection, id: &[u8]) -> Result<(), rusqlite::Error> {
+    conn.prepare_cached(
+        "update content_store set closure_stale = 1 where content_id in
+        (select content_id from closure_missing where missing_id = ?)",
+    )?
+    .execute([id])?;
+    conn.prepare_cached("delete from closure_missing where missing_id = ?")?
+        .execute([id])?;
+    Ok(())
+}
+
+fn index_payload(
+    conn: &rusqlite::Connection,
+    indexer: &dyn PayloadIndexer,
+    id: &[u8],
+    payload: &[u8],
+) -> Result<(), rusqlite::Error> {
+    let mut stmt = conn
+        .prepare_cached("insert or ignore into payload_index (term, content_id) values (?, ?)")?;
+    for term in indexer.terms(payload) {
+        stmt.execute([term.as_slice(), id])?;
+    }
+    Ok(())
+}
+
+// The smallest key greater than every key starting with the prefix or None if there is no
+// such key.
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < 0xff {
+            upper.push(last + 1);
+            return Some(upper);
+        }
+    }
+    None
+}
+
 impl From<rusqlite::Error> for StoreError {
     fn from(e: rusqlite::Error) -> Self {
         StoreError::StoreFailure(format!("{:?}", e))
@@ -13,16 +13,376 @@
 // limitations under the License.
 //! The [Merkle Dag](crate::dag::Merkle) backing store trait.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 
-use crate::{hash::HashWriter, node::Node};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dag::{CachedValue, NodeHandle},
+    hash::HashWriter,
+    id::hex,
+    node::{Node, NodeIdVersion, NodeIntegrityError},
+};
+
+mod blob;
+mod bloom;
+mod cached;
+#[cfg(feature = "cbor")]
+pub mod codec;
+#[cfg(feature = "flate2")]
+mod compressed;
+#[cfg(feature = "dashmap")]
+mod concurrent_memory;
+#[cfg(any(test, feature = "testing"))]
+pub mod conformance;
+mod copy;
+#[cfg(feature = "encryption")]
+mod encrypted;
+mod lru;
+mod metered;
+mod mirrored;
+#[cfg(feature = "cbor")]
+mod serialized_cache;
+mod shared;
+mod tiered;
+mod union;
+mod verifying;
+pub use blob::{BlobStore, DetachedStore};
+pub use bloom::BloomStore;
+pub use cached::CachedStore;
+#[cfg(feature = "flate2")]
+pub use compressed::{CompressedStore, COMPRESSED_TAG};
+#[cfg(feature = "dashmap")]
+pub use concurrent_memory::ConcurrentMemoryStore;
+pub use copy::{copy_store, CopyReport};
+#[cfg(feature = "encryption")]
+pub use encrypted::{EncryptedStore, ENCRYPTED_TAG};
+pub use metered::{MeteredStore, StoreMetrics};
+pub use mirrored::{MirrorFailure, MirrorOp, MirrorPolicy, MirroredStore};
+#[cfg(feature = "cbor")]
+pub use serialized_cache::SerializedCache;
+pub use shared::ConcurrentStore;
+pub use tiered::TieredStore;
+pub use union::UnionStore;
+pub use verifying::VerifyingStore;
 
 pub type Result<T> = std::result::Result<T, StoreError>;
 
+/// The key persisted roots are written under by key value backends.
+pub const ROOTS_KEY: &[u8] = b"__merkle_dag_roots__";
+
+/// What a [Store] holds as returned by [Store::stats].
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct StoreStats {
+    /// The number of stored [nodes](Node).
+    pub node_count: u64,
+    /// The bytes of the stored records.
+    pub total_bytes: u64,
+    /// The bytes of the largest stored record.
+    pub max_node_bytes: u64,
+}
+
+impl StoreStats {
+    /// The mean bytes of a stored record. 0 for an empty [Store].
+    pub fn mean_node_bytes(&self) -> f64 {
+        if self.node_count == 0 {
+            return 0.0;
+        }
+        self.total_bytes as f64 / self.node_count as f64
+    }
+
+    /// Count a record of `bytes` bytes.
+    #[cfg(feature = "cbor")]
+    pub(crate) fn record(&mut self, bytes: usize) {
+        self.node_count += 1;
+        self.total_bytes += bytes as u64;
+        self.max_node_bytes = self.max_node_bytes.max(bytes as u64);
+    }
+}
+
+/// The roots of a [Merkle DAG](crate::dag::Merkle) as persisted in a [Store].
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct PersistedRoots {
+    /// The [frontier roots](crate::dag::Merkle::frontier_roots).
+    pub roots: BTreeSet<Vec<u8>>,
+    /// The ids marked with [Merkle::mark_sticky_root](crate::dag::Merkle::mark_sticky_root).
+    pub sticky: BTreeSet<Vec<u8>>,
+    /// The ids [pinned](crate::dag::Merkle::pin) against garbage collection.
+    #[serde(default)]
+    pub pins: BTreeSet<Vec<u8>>,
+    /// The [estimated storage bytes](crate::dag::Merkle::estimated_storage_bytes) of the DAG
+    /// when the roots were written. Records written before it was persisted don't have it.
+    #[serde(default)]
+    pub stored_bytes: Option<u64>,
+}
+
+#[cfg(feature = "cbor")]
+impl PersistedRoots {
+    /// The cbor encoding backends persist.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(self, &mut buf).expect("Encoding roots can not fail");
+        buf
+    }
+
+    /// Decode roots written by [PersistedRoots::encode].
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        ciborium::de::from_reader(bytes)
+            .map_err(|e| StoreError::StoreFailure(format!("Invalid persisted roots {:?}", e)))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum StoreError {
     StoreFailure(String),
     NoSuchDependents,
+    /// The [Store] has no [Node] with this id.
+    NoSuchNode(Vec<u8>),
+    /// The [Store] does not support the named operation.
+    Unsupported(&'static str),
+    /// An abbreviated id could not be used for a lookup.
+    InvalidIdPrefix(String),
+    /// More than one id starts with the prefix. At most
+    /// [PrefixConfig::ambiguity_cap](crate::dag::PrefixConfig::ambiguity_cap) candidates are
+    /// reported.
+    AmbiguousPrefix {
+        prefix: Vec<u8>,
+        candidates: Vec<Vec<u8>>,
+    },
+    /// A [WorkMeter](crate::dag::WorkMeter) aborted the operation. It can be retried later.
+    Throttled {
+        retry_after_hint: Option<std::time::Duration>,
+    },
+    /// A [NodeHandle] no longer matches the [Store]. The node was removed or its payload
+    /// changed after the handle was created.
+    StaleHandle {
+        id: Vec<u8>,
+        expected_item_id: Vec<u8>,
+        found_item_id: Option<Vec<u8>>,
+    },
+    /// The [Store] holds ids of more than one length. Use
+    /// [Merkle::audit_id_uniformity](crate::dag::Merkle::audit_id_uniformity) to find them and
+    /// [Merkle::quarantine_foreign_ids](crate::dag::Merkle::quarantine_foreign_ids) to move
+    /// them aside.
+    NonUniformIds {
+        expected_len: usize,
+        foreign: BTreeMap<usize, u64>,
+    },
+    /// The [Node] with this id can't be removed on its own because other nodes depend on it.
+    HasDescendants(Vec<u8>),
+    /// The files at this path don't belong to a known backend.
+    UnrecognizedStore(String),
+    /// The named operation would write to a [ReadOnlyStore].
+    ReadOnly(&'static str),
+    /// The record or encrypted item of the [Node] with this id failed authentication. It was
+    /// tampered with, written under another id or key, or never encrypted.
+    DecryptionFailed(Vec<u8>),
+    /// The [Node] read under this id no longer hashes to it. Returned by [VerifyingStore].
+    CorruptNode {
+        id: Vec<u8>,
+    },
+    /// A [Node] failed [Node::validate]. Returned by
+    /// [Merkle::verify_node](crate::dag::Merkle::verify_node).
+    InvalidNode(NodeIntegrityError),
+    /// A textual DAG description could not be parsed. Lines and columns start at 1.
+    SpecParse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// The [Store] has no room left for the write, for example because the memory map of an
+    /// LMDB environment is full. Nothing of the failed write was stored.
+    StoreFull(String),
+    /// An id doesn't have the length of the ids produced by the [HashWriter] of the DAG.
+    MalformedId {
+        expected: usize,
+        actual: usize,
+    },
+    /// The [Store] holds nodes hashed with another [HashWriter] than the DAG opening it.
+    HashAlgorithmMismatch {
+        expected: String,
+        found: String,
+    },
+    /// The [Node] with this id was rejected by the
+    /// [SignaturePolicy](crate::signature::SignaturePolicy) of the DAG. It is unsigned, its
+    /// signature doesn't verify or its author isn't known.
+    InvalidSignature {
+        id: Vec<u8>,
+        reason: String,
+    },
+    /// The id of the [Node] with this id was computed with an older [NodeIdVersion] than the
+    /// [minimum](crate::dag::Merkle::min_id_version) the DAG accepts.
+    IdVersionTooOld {
+        id: Vec<u8>,
+        minimum: NodeIdVersion,
+        found: NodeIdVersion,
+    },
+}
+
+/// The variant of a [StoreError] without its details.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum StoreErrorKind {
+    StoreFailure,
+    NoSuchDependents,
+    NoSuchNode,
+    Unsupported,
+    InvalidIdPrefix,
+    AmbiguousPrefix,
+    Throttled,
+    StaleHandle,
+    NonUniformIds,
+    HasDescendants,
+    UnrecognizedStore,
+    ReadOnly,
+    DecryptionFailed,
+    CorruptNode,
+    InvalidNode,
+    SpecParse,
+    StoreFull,
+    MalformedId,
+    HashAlgorithmMismatch,
+    InvalidSignature,
+    IdVersionTooOld,
+}
+
+impl StoreError {
+    /// The [StoreErrorKind] of this error.
+    pub fn kind(&self) -> StoreErrorKind {
+        match self {
+            StoreError::StoreFailure(_) => StoreErrorKind::StoreFailure,
+            StoreError::NoSuchDependents => StoreErrorKind::NoSuchDependents,
+            StoreError::NoSuchNode(_) => StoreErrorKind::NoSuchNode,
+            StoreError::Unsupported(_) => StoreErrorKind::Unsupported,
+            StoreError::InvalidIdPrefix(_) => StoreErrorKind::InvalidIdPrefix,
+            StoreError::AmbiguousPrefix { .. } => StoreErrorKind::AmbiguousPrefix,
+            StoreError::Throttled { .. } => StoreErrorKind::Throttled,
+            StoreError::StaleHandle { .. } => StoreErrorKind::StaleHandle,
+            StoreError::NonUniformIds { .. } => StoreErrorKind::NonUniformIds,
+            StoreError::HasDescendants(_) => StoreErrorKind::HasDescendants,
+            StoreError::UnrecognizedStore(_) => StoreErrorKind::UnrecognizedStore,
+            StoreError::ReadOnly(_) => StoreErrorKind::ReadOnly,
+            StoreError::DecryptionFailed(_) => StoreErrorKind::DecryptionFailed,
+            StoreError::CorruptNode { .. } => StoreErrorKind::CorruptNode,
+            StoreError::InvalidNode(_) => StoreErrorKind::InvalidNode,
+            StoreError::SpecParse { .. } => StoreErrorKind::SpecParse,
+            StoreError::StoreFull(_) => StoreErrorKind::StoreFull,
+            StoreError::MalformedId { .. } => StoreErrorKind::MalformedId,
+            StoreError::HashAlgorithmMismatch { .. } => StoreErrorKind::HashAlgorithmMismatch,
+            StoreError::InvalidSignature { .. } => StoreErrorKind::InvalidSignature,
+            StoreError::IdVersionTooOld { .. } => StoreErrorKind::IdVersionTooOld,
+        }
+    }
+}
+
+// Ids are rendered as hex.
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::StoreFailure(msg) => write!(f, "store failure: {}", msg),
+            StoreError::NoSuchDependents => f.write_str("no such dependents"),
+            StoreError::NoSuchNode(id) => write!(f, "no such node {}", hex(id)),
+            StoreError::Unsupported(op) => write!(f, "unsupported operation {}", op),
+            StoreError::InvalidIdPrefix(msg) => write!(f, "invalid id prefix: {}", msg),
+            StoreError::AmbiguousPrefix { prefix, candidates } => {
+                let candidates: Vec<String> = candidates.iter().map(|id| hex(id)).collect();
+                write!(
+                    f,
+                    "ambiguous id prefix {} matches [{}]",
+                    hex(prefix),
+                    candidates.join(", ")
+                )
+            }
+            StoreError::Throttled {
+                retry_after_hint: Some(hint),
+            } => write!(f, "throttled, retry after {:?}", hint),
+            StoreError::Throttled {
+                retry_after_hint: None,
+            } => f.write_str("throttled"),
+            StoreError::StaleHandle {
+                id,
+                expected_item_id,
+                found_item_id,
+            } => write!(
+                f,
+                "stale handle for node {} expected item {} found {}",
+                hex(id),
+                hex(expected_item_id),
+                found_item_id
+                    .as_deref()
+                    .map(hex)
+                    .unwrap_or_else(|| "nothing".into())
+            ),
+            StoreError::NonUniformIds {
+                expected_len,
+                foreign,
+            } => write!(
+                f,
+                "ids are not all {} bytes long, found lengths {:?}",
+                expected_len, foreign
+            ),
+            StoreError::HasDescendants(id) => write!(f, "node {} has descendants", hex(id)),
+            StoreError::UnrecognizedStore(path) => write!(f, "unrecognized store at {}", path),
+            StoreError::ReadOnly(op) => write!(f, "{} would write to a read only store", op),
+            StoreError::DecryptionFailed(id) => {
+                write!(f, "record of node {} failed to decrypt", hex(id))
+            }
+            StoreError::CorruptNode { id } => {
+                write!(f, "node {} no longer hashes to its id", hex(id))
+            }
+            StoreError::InvalidNode(err) => write!(f, "invalid node: {}", err),
+            StoreError::SpecParse {
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{}: {}", line, column, message),
+            StoreError::StoreFull(msg) => write!(f, "store full: {}", msg),
+            StoreError::MalformedId { expected, actual } => {
+                write!(f, "malformed id of {} bytes, expected {}", actual, expected)
+            }
+            StoreError::HashAlgorithmMismatch { expected, found } => write!(
+                f,
+                "store was hashed with {} but the dag uses {}",
+                found, expected
+            ),
+            StoreError::InvalidSignature { id, reason } => {
+                write!(f, "invalid signature on node {}: {}", hex(id), reason)
+            }
+            StoreError::IdVersionTooOld { id, minimum, found } => write!(
+                f,
+                "node {} has id version {:?} older than the minimum {:?}",
+                hex(id),
+                found,
+                minimum
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// Fail with [StoreError::MalformedId] unless `id` has the length of the ids produced by `HW`.
+pub fn check_id_len<HW: HashWriter>(id: &[u8]) -> Result<()> {
+    if id.len() == HW::OUTPUT_LEN {
+        Ok(())
+    } else {
+        Err(StoreError::MalformedId {
+            expected: HW::OUTPUT_LEN,
+            actual: id.len(),
+        })
+    }
+}
+
+impl From<NodeIntegrityError> for StoreError {
+    fn from(err: NodeIntegrityError) -> Self {
+        StoreError::InvalidNode(err)
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::StoreFailure(format!("{}", err))
+    }
 }
 
 /// Trait representing the backing storage interface for a [Merkle DAG](crate::dag::Merkle).
@@ -36,6 +396,275 @@ where
     fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>>;
     /// Stores a given [Node].
     fn store(&mut self, node: Node<HW>) -> Result<()>;
+
+    /// Fetches the [nodes](Node) with these ids in the same order returning `None` for the
+    /// missing ones. Stores that can fetch many records at once should override this.
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        ids.iter().map(|id| self.get(id)).collect()
+    }
+
+    /// Checks which of these ids the [Store] holds in the same order. Stores that can look up
+    /// many ids at once should override this.
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        ids.iter().map(|id| self.contains(id)).collect()
+    }
+
+    /// Fetches the at rest [codec] encoding of a node from the [Store] by id if it exists.
+    /// Serializing stores should return their stored bytes instead of encoding the node again.
+    /// Requires the `cbor` feature.
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(id)?.as_ref().map(codec::encode))
+    }
+
+    /// Stores a given [Node] writing `encoded` as its at rest bytes in place of its [codec]
+    /// encoding. Wrappers that transform records at rest read them back with
+    /// [Store::get_raw]. The [Store] may still use the `node` to maintain its indexes but
+    /// its other reads expect the [codec] encoding. Requires the `cbor` feature.
+    ///
+    /// Stores that don't keep encoded records return [StoreError::Unsupported].
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, _node: Node<HW>, _encoded: Vec<u8>) -> Result<()> {
+        Err(StoreError::Unsupported("store_encoded"))
+    }
+
+    /// Stores every `(node, encoded)` record like [Store::store_encoded] replacing the
+    /// persisted roots with `roots` when given. Stores that implement it write the records
+    /// and the roots in a single atomic write so wrappers that transform records at rest
+    /// keep [Store::store_many_with_roots] atomic. Requires the `cbor` feature.
+    ///
+    /// The default stores the records one at a time and returns [StoreError::Unsupported]
+    /// when it is asked to persist roots with them.
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        if roots.is_some() {
+            return Err(StoreError::Unsupported("store_many_encoded"));
+        }
+        for (node, encoded) in records {
+            self.store_encoded(node, encoded)?;
+        }
+        Ok(())
+    }
+
+    /// Fetches the [NodeHandle] of a node from the [Store] by id if it exists. Stores that
+    /// can read the structure of a node without its payload should override this.
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        Ok(self.get(id)?.as_ref().map(NodeHandle::from))
+    }
+
+    /// Stores every [Node] of `nodes`. Stores with a cheaper way to write many records at once
+    /// should override this. The [Store] doesn't check the dependencies of the nodes. Use
+    /// [Merkle::add_nodes](crate::dag::Merkle::add_nodes) to keep the DAG consistent.
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+        Self: Sized,
+    {
+        for node in nodes {
+            self.store(node)?;
+        }
+        Ok(())
+    }
+
+    /// Stores a given [Node] and replaces the persisted roots in a single atomic write.
+    ///
+    /// Stores that can't persist roots return [StoreError::Unsupported].
+    fn store_with_roots(&mut self, _node: Node<HW>, _roots: &PersistedRoots) -> Result<()> {
+        Err(StoreError::Unsupported("store_with_roots"))
+    }
+
+    /// Stores every [Node] of `nodes` and replaces the persisted roots in a single atomic
+    /// write.
+    ///
+    /// Stores that can't persist roots return [StoreError::Unsupported].
+    fn store_many_with_roots<I>(&mut self, _nodes: I, _roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+        Self: Sized,
+    {
+        Err(StoreError::Unsupported("store_many_with_roots"))
+    }
+
+    /// Replaces the persisted roots.
+    ///
+    /// Stores that can't persist roots return [StoreError::Unsupported].
+    fn persist_roots(&mut self, _roots: &PersistedRoots) -> Result<()> {
+        Err(StoreError::Unsupported("persist_roots"))
+    }
+
+    /// Fetches the roots last written by [Store::persist_roots] or [Store::store_with_roots].
+    /// Returns `None` if roots were never persisted.
+    ///
+    /// Stores that can't persist roots return [StoreError::Unsupported].
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        Err(StoreError::Unsupported("persisted_roots"))
+    }
+
+    /// Fails with [StoreError::HashAlgorithmMismatch] if the [Store] recorded that its nodes
+    /// were hashed with another [HashWriter] than `HW`.
+    ///
+    /// Stores that don't record their [HashWriter] always succeed.
+    fn check_hash_algorithm(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Removes the [Node] with this id if it exists. The [Store] doesn't check whether other
+    /// nodes depend on it. Use [Merkle::remove_node](crate::dag::Merkle::remove_node) to keep
+    /// the DAG consistent.
+    ///
+    /// Stores that can't remove nodes return [StoreError::Unsupported].
+    fn delete(&mut self, _id: &[u8]) -> Result<()> {
+        Err(StoreError::Unsupported("delete"))
+    }
+
+    /// Fetches the ids of the [nodes](Node) that directly depend on this id.
+    ///
+    /// Stores without a reverse dependency index return [StoreError::Unsupported].
+    fn children_of(&self, _id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        Err(StoreError::Unsupported("children_of"))
+    }
+
+    /// Iterates over every id in the [Store] in ascending order without reading the
+    /// [nodes](Node). Quarantined ids are not included.
+    ///
+    /// Stores that can't enumerate their ids return [StoreError::Unsupported].
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        Err(StoreError::Unsupported("ids"))
+    }
+
+    /// Counts the [nodes](Node) in the [Store]. Quarantined nodes are not counted. Defaults to
+    /// counting [Store::ids].
+    fn len(&self) -> Result<usize> {
+        let mut count = 0;
+        for id in self.ids()? {
+            id?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Checks if the [Store] holds no [nodes](Node). Defaults to looking for a first id in
+    /// [Store::ids].
+    fn is_empty(&self) -> Result<bool> {
+        match self.ids()?.next() {
+            Some(id) => id.map(|_| false),
+            None => Ok(true),
+        }
+    }
+
+    /// Finds up to `limit` ids in the [Store] starting with `prefix` in ascending order.
+    /// Implementations should seek to the prefix rather than scan every key.
+    fn find_by_prefix(&self, _prefix: &[u8], _limit: usize) -> Result<Vec<Vec<u8>>> {
+        Err(StoreError::Unsupported("find_by_prefix"))
+    }
+
+    /// Counts the stored ids by their length in bytes without reading the [nodes](Node).
+    /// Quarantined ids are not counted.
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        Err(StoreError::Unsupported("key_length_histogram"))
+    }
+
+    /// Counts the stored [nodes](Node) and the bytes of their records. The default reads
+    /// every record with [Store::get_raw] so the sizes are those of the [codec] encoding.
+    /// Backends that can sum their record sizes without reading them should override this.
+    /// Requires the `cbor` feature and a [Store] that supports [Store::ids].
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        let mut stats = StoreStats::default();
+        for id in self.ids()? {
+            if let Some(record) = self.get_raw(&id?)? {
+                stats.record(record.len());
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Moves every record whose id is not `expected_len` bytes long into a separate
+    /// quarantine keyspace returning the number of records moved. Quarantined records are
+    /// only reachable through [Store::get_quarantined].
+    fn quarantine_foreign_keys(&mut self, _expected_len: usize) -> Result<u64> {
+        Err(StoreError::Unsupported("quarantine_foreign_keys"))
+    }
+
+    /// Fetches the record of a quarantined node by the id it was stored under. Records are
+    /// returned undecoded since they were written under another [HashWriter]. Wrappers that
+    /// transform records at rest return the cbor encoding of the node.
+    fn get_quarantined(&self, _id: &[u8]) -> Result<Option<Vec<u8>>> {
+        Err(StoreError::Unsupported("get_quarantined"))
+    }
+
+    /// Fetches the materialized number of ancestors of the node with this id. Fails with
+    /// [StoreError::NoSuchNode] if there is no such node.
+    ///
+    /// Stores without materialized closure sizes return [StoreError::Unsupported].
+    fn cached_closure_size(&self, _id: &[u8]) -> Result<CachedValue<u64>> {
+        Err(StoreError::Unsupported("cached_closure_size"))
+    }
+
+    /// Computes the materialized number of ancestors for up to `batch` nodes whose value is
+    /// missing or stale, newest first, returning the number of nodes computed.
+    ///
+    /// Stores without materialized closure sizes return [StoreError::Unsupported].
+    fn refresh_closure_sizes(&mut self, _batch: usize) -> Result<usize> {
+        Err(StoreError::Unsupported("refresh_closure_sizes"))
+    }
+
+    /// Starts grouping subsequent writes into a batch that is written when the batch is
+    /// committed or discarded when it is rolled back.
+    ///
+    /// Stores without atomic batches return [StoreError::Unsupported].
+    fn begin_batch(&mut self) -> Result<()> {
+        Err(StoreError::Unsupported("begin_batch"))
+    }
+
+    /// Writes the batch started by [Store::begin_batch].
+    fn commit_batch(&mut self) -> Result<()> {
+        Err(StoreError::Unsupported("commit_batch"))
+    }
+
+    /// Discards the batch started by [Store::begin_batch].
+    fn rollback_batch(&mut self) -> Result<()> {
+        Err(StoreError::Unsupported("rollback_batch"))
+    }
+
+    /// Makes every write that returned so far durable, for example before a frontier is
+    /// acknowledged to peers. Writes of an open batch are only durable once it is committed.
+    ///
+    /// Stores that are not persistent or write through do nothing.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A [Store] that can persist caller supplied writes in the same transaction as a [Node].
+pub trait TransactionalStore<HW>: Store<HW>
+where
+    HW: HashWriter,
+{
+    /// The transaction handle given to side effects. Side effects only get a shared
+    /// reference so they can not commit or rollback the transaction themselves.
+    type Transaction<'t>
+    where
+        Self: 't;
+    /// The error side effects can fail with.
+    type Error;
+
+    /// Stores a given [Node], runs the `side_effect` and persists the `roots` if there are
+    /// any in a single transaction. If the `side_effect` or any write fails the whole
+    /// transaction is rolled back. Inside a batch started with [Store::begin_batch] the
+    /// transaction nests in the batch.
+    fn store_with<F>(
+        &mut self,
+        node: Node<HW>,
+        roots: Option<&PersistedRoots>,
+        side_effect: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&Self::Transaction<'_>) -> std::result::Result<(), Self::Error>;
 }
 
 pub type BTreeStore<HW> = BTreeMap<Vec<u8>, Node<HW>>;
@@ -56,4 +685,555 @@ where
         self.insert(node.id().to_vec(), node);
         Ok(())
     }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.remove(id);
+        Ok(())
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        Ok(BTreeMap::get(self, id).map(NodeHandle::from))
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        Ok(Box::new(self.keys().cloned().map(Ok)))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(BTreeMap::len(self))
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(BTreeMap::is_empty(self))
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        let mut histogram = BTreeMap::new();
+        for id in self.keys() {
+            *histogram.entry(id.len()).or_insert(0) += 1;
+        }
+        Ok(histogram)
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .range(prefix.to_vec()..)
+            .map(|(id, _)| id)
+            .take_while(|id| id.starts_with(prefix))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+/// An unordered in memory [Store]. It avoids the upkeep of the ordering that [BTreeStore]
+/// maintains so [Store::find_by_prefix] and [Store::ids] have to collect and sort the keys
+/// instead.
+pub type HashStore<HW> = HashMap<Vec<u8>, Node<HW>>;
+
+impl<HW> Store<HW> for HashStore<HW>
+where
+    HW: HashWriter,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        Ok(self.contains_key(id))
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        Ok(self.get(id).cloned())
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.insert(node.id().to_vec(), node);
+        Ok(())
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.remove(id);
+        Ok(())
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        Ok(HashMap::get(self, id).map(NodeHandle::from))
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        let mut ids: Vec<Vec<u8>> = self.keys().cloned().collect();
+        ids.sort();
+        Ok(Box::new(ids.into_iter().map(Ok)))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(HashMap::len(self))
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(HashMap::is_empty(self))
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        let mut histogram = BTreeMap::new();
+        for id in self.keys() {
+            *histogram.entry(id.len()).or_insert(0) += 1;
+        }
+        Ok(histogram)
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        let mut ids: Vec<Vec<u8>> = self
+            .keys()
+            .filter(|id| id.starts_with(prefix))
+            .cloned()
+            .collect();
+        ids.sort();
+        ids.truncate(limit);
+        Ok(ids)
+    }
+}
+
+/// A [Store] wrapper that maintains an in memory reverse dependency index
+/// so that [Store::children_of] is supported for any inner [Store].
+///
+/// [ReverseIndexStore::new] starts with an empty index that covers the [nodes](Node) stored
+/// through the wrapper. Use [ReverseIndexStore::open] for an inner [Store] that already holds
+/// nodes. Changes to the index made inside a batch are undone when it rolls back.
+#[derive(Clone, Debug, Default)]
+pub struct ReverseIndexStore<S> {
+    inner: S,
+    children: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+    batch: Option<Vec<EdgeChange>>,
+}
+
+// A change to the reverse index journaled while a batch is open.
+#[derive(Clone, Debug)]
+enum EdgeChange {
+    Linked(Vec<u8>, Vec<u8>),
+    Unlinked(Vec<u8>, Vec<u8>),
+}
+
+impl<S> ReverseIndexStore<S> {
+    /// Wrap a [Store] with a reverse dependency index.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            children: BTreeMap::new(),
+            batch: None,
+        }
+    }
+
+    /// Wrap a [Store] with a reverse dependency index built from every [Node] it holds.
+    pub fn open<HW>(inner: S) -> Result<Self>
+    where
+        HW: HashWriter,
+        S: Store<HW>,
+    {
+        let mut store = Self::new(inner);
+        let ids = store.inner.ids()?.collect::<Result<Vec<Vec<u8>>>>()?;
+        for id in ids {
+            if let Some(node) = store.inner.get(&id)? {
+                store.link_node(&node);
+            }
+        }
+        Ok(store)
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn link(&mut self, dep_id: Vec<u8>, id: Vec<u8>) {
+        if self
+            .children
+            .entry(dep_id.clone())
+            .or_default()
+            .insert(id.clone())
+        {
+            if let Some(batch) = self.batch.as_mut() {
+                batch.push(EdgeChange::Linked(dep_id, id));
+            }
+        }
+    }
+
+    fn unlink(&mut self, dep_id: &[u8], id: &[u8]) {
+        let Some(children) = self.children.get_mut(dep_id) else {
+            return;
+        };
+        if children.remove(id) {
+            if children.is_empty() {
+                self.children.remove(dep_id);
+            }
+            if let Some(batch) = self.batch.as_mut() {
+                batch.push(EdgeChange::Unlinked(dep_id.to_vec(), id.to_vec()));
+            }
+        }
+    }
+
+    fn link_node<HW: HashWriter>(&mut self, node: &Node<HW>) {
+        for dep_id in node.dep_set() {
+            self.link(dep_id.clone(), node.id().to_vec());
+        }
+    }
+
+    fn link_nodes<'a, HW, I>(&mut self, nodes: I)
+    where
+        HW: HashWriter + 'a,
+        I: IntoIterator<Item = &'a Node<HW>>,
+    {
+        for node in nodes {
+            self.link_node(node);
+        }
+    }
+}
+
+impl<HW, S> Store<HW> for ReverseIndexStore<S>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        self.inner.contains(id)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        self.inner.contains_many(ids)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        self.inner.get(id)
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        self.inner.get_many(ids)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_raw(id)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        self.inner.get_handle(id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.inner.store(node.clone())?;
+        self.link_node(&node);
+        Ok(())
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        self.inner.store_encoded(node.clone(), encoded)?;
+        self.link_node(&node);
+        Ok(())
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        let nodes: Vec<Node<HW>> = records.iter().map(|(node, _)| node.clone()).collect();
+        self.inner.store_many_encoded(records, roots)?;
+        self.link_nodes(&nodes);
+        Ok(())
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.inner.store_with_roots(node.clone(), roots)?;
+        self.link_node(&node);
+        Ok(())
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        self.inner.store_many_with_roots(nodes.clone(), roots)?;
+        self.link_nodes(&nodes);
+        Ok(())
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.inner.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        self.inner.store_many(nodes.clone())?;
+        self.link_nodes(&nodes);
+        Ok(())
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        let node = match self.inner.get(id)? {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        self.inner.delete(id)?;
+        for dep_id in node.dep_set() {
+            self.unlink(dep_id, id);
+        }
+        Ok(())
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        Ok(self.children.get(id).cloned().unwrap_or_default())
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    // Drops the edges of the quarantined ids from the index.
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        let quarantined = self.inner.quarantine_foreign_keys(expected_len)?;
+        let foreign: Vec<(Vec<u8>, Vec<u8>)> = self
+            .children
+            .iter()
+            .flat_map(|(dep_id, children)| {
+                children
+                    .iter()
+                    .filter(move |id| dep_id.len() != expected_len || id.len() != expected_len)
+                    .map(move |id| (dep_id.clone(), id.clone()))
+            })
+            .collect();
+        for (dep_id, id) in foreign {
+            self.unlink(&dep_id, &id);
+        }
+        Ok(quarantined)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_quarantined(id)
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.inner.begin_batch()?;
+        self.batch = Some(Vec::new());
+        Ok(())
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.inner.commit_batch()?;
+        self.batch = None;
+        Ok(())
+    }
+
+    // Undoes the index changes of the batch in reverse order.
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.inner.rollback_batch()?;
+        for change in self.batch.take().unwrap_or_default().into_iter().rev() {
+            match change {
+                EdgeChange::Linked(dep_id, id) => self.unlink(&dep_id, &id),
+                EdgeChange::Unlinked(dep_id, id) => self.link(dep_id, id),
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A [Store] wrapper that refuses every write with [StoreError::ReadOnly]. Use it to hand a
+/// [Merkle DAG](crate::dag::Merkle) to code that may query but must not change the wrapped
+/// [Store]. [Merkle::load](crate::dag::Merkle::load) opens a read-only view of a [Store]
+/// with persisted roots without writing to it.
+#[derive(Clone, Debug, Default)]
+pub struct ReadOnlyStore<S> {
+    inner: S,
+}
+
+impl<S> ReadOnlyStore<S> {
+    /// Wrap a [Store] refusing writes.
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Unwrap the [Store].
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<HW, S> Store<HW> for ReadOnlyStore<S>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        self.inner.contains(id)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        self.inner.contains_many(ids)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        self.inner.get(id)
+    }
+
+    fn store(&mut self, _node: Node<HW>) -> Result<()> {
+        Err(StoreError::ReadOnly("store"))
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        self.inner.get_many(ids)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_raw(id)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        self.inner.get_handle(id)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, _node: Node<HW>, _encoded: Vec<u8>) -> Result<()> {
+        Err(StoreError::ReadOnly("store_encoded"))
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        _records: Vec<(Node<HW>, Vec<u8>)>,
+        _roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        Err(StoreError::ReadOnly("store_many_encoded"))
+    }
+
+    fn store_many<I>(&mut self, _nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        Err(StoreError::ReadOnly("store_many"))
+    }
+
+    fn store_with_roots(&mut self, _node: Node<HW>, _roots: &PersistedRoots) -> Result<()> {
+        Err(StoreError::ReadOnly("store_with_roots"))
+    }
+
+    fn store_many_with_roots<I>(&mut self, _nodes: I, _roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        Err(StoreError::ReadOnly("store_many_with_roots"))
+    }
+
+    fn persist_roots(&mut self, _roots: &PersistedRoots) -> Result<()> {
+        Err(StoreError::ReadOnly("persist_roots"))
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, _id: &[u8]) -> Result<()> {
+        Err(StoreError::ReadOnly("delete"))
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.inner.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, _expected_len: usize) -> Result<u64> {
+        Err(StoreError::ReadOnly("quarantine_foreign_keys"))
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, _batch: usize) -> Result<usize> {
+        Err(StoreError::ReadOnly("refresh_closure_sizes"))
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        Err(StoreError::ReadOnly("begin_batch"))
+    }
 }
@@ -0,0 +1,344 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[cfg(feature = "cbor")]
+use super::StoreStats;
+use super::{PersistedRoots, Result, Store};
+use crate::{
+    dag::{CachedValue, NodeHandle},
+    hash::HashWriter,
+    node::Node,
+};
+
+/// Storage for the items of [detached](Node::is_detached) [nodes](Node) keyed by their item
+/// ids. Blobs are content addressed so writing the same item id twice writes the same bytes.
+pub trait BlobStore {
+    /// Store the `bytes` of an item under its `item_id`.
+    fn put_blob(&mut self, item_id: &[u8], bytes: Vec<u8>) -> Result<()>;
+    /// Fetch the bytes stored under `item_id` if there are any.
+    fn get_blob(&self, item_id: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Remove the bytes stored under `item_id`. Removing a missing blob is not an error.
+    fn delete_blob(&mut self, item_id: &[u8]) -> Result<()>;
+}
+
+impl BlobStore for BTreeMap<Vec<u8>, Vec<u8>> {
+    fn put_blob(&mut self, item_id: &[u8], bytes: Vec<u8>) -> Result<()> {
+        self.insert(item_id.to_vec(), bytes);
+        Ok(())
+    }
+
+    fn get_blob(&self, item_id: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(item_id).cloned())
+    }
+
+    fn delete_blob(&mut self, item_id: &[u8]) -> Result<()> {
+        self.remove(item_id);
+        Ok(())
+    }
+}
+
+impl BlobStore for HashMap<Vec<u8>, Vec<u8>> {
+    fn put_blob(&mut self, item_id: &[u8], bytes: Vec<u8>) -> Result<()> {
+        self.insert(item_id.to_vec(), bytes);
+        Ok(())
+    }
+
+    fn get_blob(&self, item_id: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(item_id).cloned())
+    }
+
+    fn delete_blob(&mut self, item_id: &[u8]) -> Result<()> {
+        self.remove(item_id);
+        Ok(())
+    }
+}
+
+/// A [Store] of [nodes](Node) paired with a [BlobStore] for the items of detached nodes. Every
+/// [Store] operation goes to the node [Store] so traversals never read the blobs. Use it with
+/// [Merkle::add_detached](crate::dag::Merkle::add_detached) and
+/// [Merkle::get_item](crate::dag::Merkle::get_item).
+///
+/// Deleting the last detached node with an item id deletes its blob as well so
+/// [Merkle::remove_node](crate::dag::Merkle::remove_node) and garbage collection don't leave
+/// blobs behind. Inside a batch the blobs are deleted when the batch commits.
+#[derive(Debug, Default)]
+pub struct DetachedStore<S, B> {
+    nodes: S,
+    blobs: B,
+    // The number of stored detached nodes per item id. Counted on the first delete after a
+    // write so a run of deletes reads the nodes once.
+    item_refs: Option<BTreeMap<Vec<u8>, usize>>,
+    // Item ids of deleted detached nodes whose blobs are checked when the batch commits.
+    doomed_blobs: Vec<Vec<u8>>,
+    in_batch: bool,
+}
+
+impl<S, B> DetachedStore<S, B> {
+    /// Pair a [Store] of nodes with a [BlobStore].
+    pub fn new(nodes: S, blobs: B) -> Self {
+        Self {
+            nodes,
+            blobs,
+            item_refs: None,
+            doomed_blobs: Vec::new(),
+            in_batch: false,
+        }
+    }
+
+    /// Get a reference to the node [Store].
+    pub fn nodes(&self) -> &S {
+        &self.nodes
+    }
+
+    /// Get a reference to the [BlobStore].
+    pub fn blobs(&self) -> &B {
+        &self.blobs
+    }
+
+    /// Unwrap the node [Store] and the [BlobStore].
+    pub fn into_inner(self) -> (S, B) {
+        (self.nodes, self.blobs)
+    }
+}
+
+impl<S, B> DetachedStore<S, B>
+where
+    B: BlobStore,
+{
+    // Deletes the blobs of the doomed item ids no stored detached node refers to anymore.
+    fn delete_doomed_blobs<HW>(&mut self) -> Result<()>
+    where
+        HW: HashWriter,
+        S: Store<HW>,
+    {
+        if self.doomed_blobs.is_empty() {
+            return Ok(());
+        }
+        if self.item_refs.is_none() {
+            let mut item_refs = BTreeMap::new();
+            for id in self.nodes.ids()? {
+                if let Some(node) = self.nodes.get(&id?)? {
+                    if node.is_detached() {
+                        *item_refs.entry(node.item_id().to_vec()).or_insert(0) += 1;
+                    }
+                }
+            }
+            self.item_refs = Some(item_refs);
+        }
+        let item_refs = self
+            .item_refs
+            .as_ref()
+            .expect("Item references were just counted");
+        for item_id in std::mem::take(&mut self.doomed_blobs) {
+            if !item_refs.contains_key(&item_id) {
+                self.blobs.delete_blob(&item_id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S, B> BlobStore for DetachedStore<S, B>
+where
+    B: BlobStore,
+{
+    fn put_blob(&mut self, item_id: &[u8], bytes: Vec<u8>) -> Result<()> {
+        self.blobs.put_blob(item_id, bytes)
+    }
+
+    fn get_blob(&self, item_id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.blobs.get_blob(item_id)
+    }
+
+    fn delete_blob(&mut self, item_id: &[u8]) -> Result<()> {
+        self.blobs.delete_blob(item_id)
+    }
+}
+
+impl<HW, S, B> Store<HW> for DetachedStore<S, B>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+    B: BlobStore,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        self.nodes.contains(id)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        self.nodes.contains_many(ids)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        self.nodes.get(id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.item_refs = None;
+        self.nodes.store(node)
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        self.nodes.get_many(ids)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.nodes.get_raw(id)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        self.item_refs = None;
+        self.nodes.store_encoded(node, encoded)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        self.item_refs = None;
+        self.nodes.store_many_encoded(records, roots)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        self.nodes.get_handle(id)
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.item_refs = None;
+        self.nodes.store_many(nodes)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.item_refs = None;
+        self.nodes.store_with_roots(node, roots)
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.item_refs = None;
+        self.nodes.store_many_with_roots(nodes, roots)
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.nodes.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.nodes.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.nodes.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        let item_id = match self.nodes.get(id)? {
+            Some(node) if node.is_detached() => node.item_id().to_vec(),
+            _ => return self.nodes.delete(id),
+        };
+        self.nodes.delete(id)?;
+        if let Some(item_refs) = self.item_refs.as_mut() {
+            if let Some(count) = item_refs.get_mut(&item_id) {
+                *count -= 1;
+                if *count == 0 {
+                    item_refs.remove(&item_id);
+                }
+            }
+        }
+        self.doomed_blobs.push(item_id);
+        if self.in_batch {
+            return Ok(());
+        }
+        self.delete_doomed_blobs()
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.nodes.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.nodes.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.nodes.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.nodes.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.nodes.find_by_prefix(prefix, limit)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.nodes.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.nodes.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        self.item_refs = None;
+        self.nodes.quarantine_foreign_keys(expected_len)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.nodes.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.nodes.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.nodes.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.nodes.begin_batch()?;
+        self.in_batch = true;
+        Ok(())
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.nodes.commit_batch()?;
+        self.in_batch = false;
+        self.delete_doomed_blobs()
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.in_batch = false;
+        self.doomed_blobs.clear();
+        self.item_refs = None;
+        self.nodes.rollback_batch()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.nodes.flush()
+    }
+}
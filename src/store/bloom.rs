@@ -0,0 +1,324 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+
+#[cfg(feature = "cbor")]
+use super::StoreStats;
+use super::{PersistedRoots, Result, Store, StoreError};
+use crate::{
+    dag::{CachedValue, NodeHandle},
+    hash::HashWriter,
+    node::Node,
+};
+
+// A bloom filter over ids using double hashing to derive its probes.
+#[derive(Debug)]
+struct BloomFilter {
+    bits: Vec<u64>,
+    probes: u32,
+}
+
+impl BloomFilter {
+    // Sizes the filter for `items` ids at the `false_positive_rate`.
+    fn new(items: usize, false_positive_rate: f64) -> Self {
+        let items = items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-items * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0);
+        let probes = ((bits / items) * ln2).round().clamp(1.0, 32.0) as u32;
+        Self {
+            bits: vec![0; (bits as usize).div_ceil(64)],
+            probes,
+        }
+    }
+
+    fn positions(&self, id: &[u8]) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        let first = hasher.finish();
+        0xb100_u16.hash(&mut hasher);
+        let second = hasher.finish() | 1;
+        let len = (self.bits.len() * 64) as u64;
+        (0..self.probes as u64)
+            .map(move |probe| (first.wrapping_add(probe.wrapping_mul(second)) % len) as usize)
+    }
+
+    fn insert(&mut self, id: &[u8]) {
+        for bit in self.positions(id).collect::<Vec<_>>() {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    fn may_contain(&self, id: &[u8]) -> bool {
+        self.positions(id)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// A [Store] wrapper that keeps a bloom filter over the stored ids so lookups of ids the
+/// wrapped [Store] doesn't hold are mostly answered without reaching it. Ids the filter might
+/// hold are still checked with the wrapped [Store] so answers are never wrong.
+///
+/// The filter is sized for an expected number of ids and fills up past that raising the false
+/// positive rate. Deleted ids stay in the filter and keep being checked with the wrapped
+/// [Store]. Writes made to the wrapped [Store] directly are not seen by the filter and must
+/// not happen while it is wrapped.
+#[derive(Debug)]
+pub struct BloomStore<S> {
+    inner: S,
+    filter: BloomFilter,
+}
+
+impl<S> BloomStore<S> {
+    /// Wrap a [Store] reading every stored id into a filter sized for `expected_ids` ids, or
+    /// the stored ones if there are more, at the `false_positive_rate`. Requires a [Store]
+    /// that supports [Store::ids].
+    pub fn new<HW>(inner: S, expected_ids: usize, false_positive_rate: f64) -> Result<Self>
+    where
+        HW: HashWriter,
+        S: Store<HW>,
+    {
+        if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+            return Err(StoreError::StoreFailure(format!(
+                "Invalid false positive rate {}",
+                false_positive_rate
+            )));
+        }
+        let ids = inner.ids()?.collect::<Result<Vec<Vec<u8>>>>()?;
+        let mut filter = BloomFilter::new(expected_ids.max(ids.len()), false_positive_rate);
+        for id in ids.iter() {
+            filter.insert(id);
+        }
+        Ok(Self { inner, filter })
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Whether the filter might hold the `id`. False means the wrapped [Store] doesn't hold it.
+    pub fn may_contain(&self, id: &[u8]) -> bool {
+        self.filter.may_contain(id)
+    }
+}
+
+impl<HW, S> Store<HW> for BloomStore<S>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        if !self.filter.may_contain(id) {
+            return Ok(false);
+        }
+        self.inner.contains(id)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        if !self.filter.may_contain(id) {
+            return Ok(None);
+        }
+        self.inner.get(id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        let id = node.id().to_vec();
+        self.inner.store(node)?;
+        self.filter.insert(&id);
+        Ok(())
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        let maybe: Vec<&[u8]> = ids
+            .iter()
+            .copied()
+            .filter(|id| self.filter.may_contain(id))
+            .collect();
+        let mut found = self.inner.get_many(&maybe)?.into_iter();
+        Ok(ids
+            .iter()
+            .map(|id| match self.filter.may_contain(id) {
+                true => found.next().flatten(),
+                false => None,
+            })
+            .collect())
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        let maybe: Vec<&[u8]> = ids
+            .iter()
+            .copied()
+            .filter(|id| self.filter.may_contain(id))
+            .collect();
+        if maybe.is_empty() {
+            return Ok(vec![false; ids.len()]);
+        }
+        let mut found = self.inner.contains_many(&maybe)?.into_iter();
+        Ok(ids
+            .iter()
+            .map(|id| self.filter.may_contain(id) && found.next().unwrap_or(false))
+            .collect())
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !self.filter.may_contain(id) {
+            return Ok(None);
+        }
+        self.inner.get_raw(id)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        let id = node.id().to_vec();
+        self.inner.store_encoded(node, encoded)?;
+        self.filter.insert(&id);
+        Ok(())
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        let ids: Vec<Vec<u8>> = records.iter().map(|(node, _)| node.id().to_vec()).collect();
+        self.inner.store_many_encoded(records, roots)?;
+        for id in ids {
+            self.filter.insert(&id);
+        }
+        Ok(())
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        if !self.filter.may_contain(id) {
+            return Ok(None);
+        }
+        self.inner.get_handle(id)
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let ids: Vec<Vec<u8>> = nodes.iter().map(|node| node.id().to_vec()).collect();
+        self.inner.store_many(nodes)?;
+        for id in ids {
+            self.filter.insert(&id);
+        }
+        Ok(())
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        let id = node.id().to_vec();
+        self.inner.store_with_roots(node, roots)?;
+        self.filter.insert(&id);
+        Ok(())
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let ids: Vec<Vec<u8>> = nodes.iter().map(|node| node.id().to_vec()).collect();
+        self.inner.store_many_with_roots(nodes, roots)?;
+        for id in ids {
+            self.filter.insert(&id);
+        }
+        Ok(())
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.inner.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.inner.delete(id)
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.inner.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        self.inner.quarantine_foreign_keys(expected_len)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.inner.begin_batch()
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.inner.commit_batch()
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.inner.rollback_batch()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
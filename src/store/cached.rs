@@ -0,0 +1,262 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use super::lru::Lru;
+#[cfg(feature = "cbor")]
+use super::StoreStats;
+use super::{PersistedRoots, Result, Store};
+use crate::{
+    dag::{CachedValue, NodeHandle},
+    hash::HashWriter,
+    node::Node,
+};
+
+/// A [Store] wrapper that keeps the most recently read [nodes](Node) so walks that visit the
+/// same ids over and over, like [Merkle::compare](crate::dag::Merkle::compare), don't read
+/// and decode them from the wrapped [Store] every time. Cache hits return a clone of the
+/// cached node.
+///
+/// Handles are built from the cached nodes so a miss reads the whole node even if the wrapped
+/// [Store] could read its structure alone. Writes and deletes through the wrapper drop the
+/// affected ids from the cache.
+#[derive(Debug)]
+pub struct CachedStore<S, HW>
+where
+    HW: HashWriter,
+{
+    inner: S,
+    cache: Mutex<Lru<Vec<u8>, Node<HW>>>,
+}
+
+impl<S, HW> CachedStore<S, HW>
+where
+    HW: HashWriter,
+{
+    /// Wrap a [Store] caching up to `capacity` nodes.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            cache: Mutex::new(Lru::new(capacity)),
+        }
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The number of cached nodes.
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    /// Drop every cached node.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().retain(|_| false);
+    }
+
+    fn cached_node(&self, id: &[u8]) -> Option<Node<HW>> {
+        self.cache.lock().unwrap().get(&id.to_vec())
+    }
+
+    fn invalidate(&self, id: &[u8]) {
+        self.cache.lock().unwrap().remove(&id.to_vec());
+    }
+}
+
+impl<S: Default, HW: HashWriter> Default for CachedStore<S, HW> {
+    fn default() -> Self {
+        Self::new(S::default(), 1024)
+    }
+}
+
+impl<HW, S> Store<HW> for CachedStore<S, HW>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        if self.cached_node(id).is_some() {
+            return Ok(true);
+        }
+        self.inner.contains(id)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        if let Some(node) = self.cached_node(id) {
+            return Ok(Some(node));
+        }
+        let node = self.inner.get(id)?;
+        if let Some(node) = node.as_ref() {
+            self.cache.lock().unwrap().insert(id.to_vec(), node.clone());
+        }
+        Ok(node)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        let id = node.id().to_vec();
+        self.inner.store(node)?;
+        self.invalidate(&id);
+        Ok(())
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        let mut nodes: Vec<Option<Node<HW>>> = ids.iter().map(|id| self.cached_node(id)).collect();
+        let misses: Vec<usize> = (0..ids.len()).filter(|idx| nodes[*idx].is_none()).collect();
+        if misses.is_empty() {
+            return Ok(nodes);
+        }
+        let missing: Vec<&[u8]> = misses.iter().map(|idx| ids[*idx]).collect();
+        let fetched = self.inner.get_many(&missing)?;
+        let mut cache = self.cache.lock().unwrap();
+        for (idx, node) in misses.into_iter().zip(fetched) {
+            if let Some(node) = node.as_ref() {
+                cache.insert(ids[idx].to_vec(), node.clone());
+            }
+            nodes[idx] = node;
+        }
+        Ok(nodes)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_raw(id)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        // Walks read handles so misses cache the whole node for the next walk.
+        Ok(self.get(id)?.as_ref().map(NodeHandle::from))
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let ids: Vec<Vec<u8>> = nodes.iter().map(|node| node.id().to_vec()).collect();
+        self.inner.store_many(nodes)?;
+        for id in ids {
+            self.invalidate(&id);
+        }
+        Ok(())
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        let id = node.id().to_vec();
+        self.inner.store_with_roots(node, roots)?;
+        self.invalidate(&id);
+        Ok(())
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let ids: Vec<Vec<u8>> = nodes.iter().map(|node| node.id().to_vec()).collect();
+        self.inner.store_many_with_roots(nodes, roots)?;
+        for id in ids {
+            self.invalidate(&id);
+        }
+        Ok(())
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.inner.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.inner.delete(id)?;
+        self.invalidate(id);
+        Ok(())
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.inner.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        let moved = self.inner.quarantine_foreign_keys(expected_len)?;
+        self.cache
+            .lock()
+            .unwrap()
+            .retain(|id| id.len() == expected_len);
+        Ok(moved)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.inner.begin_batch()
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.inner.commit_batch()
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        // Nodes read during the batch may not survive the rollback.
+        self.clear();
+        self.inner.rollback_batch()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
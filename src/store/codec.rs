@@ -0,0 +1,148 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Accounting for the at rest cbor encoding of [Node]s used by the serializing [Store](super::Store)
+//! backends. Requires the `cbor` feature to be enabled.
+use std::io::{self, Write};
+
+use ciborium::value::Value;
+use serde::Serialize;
+
+use crate::{
+    hash::HashWriter,
+    node::Node,
+    payload::EncodeError,
+    store::{Result, StoreError},
+};
+
+/// The version of the at rest encoding. Caches of encoded [nodes](Node) key their entries by
+/// it so a change to the encoding invalidates them.
+pub const CODEC_VERSION: u32 = 1;
+
+#[cfg(test)]
+thread_local! {
+    static ENCODE_COUNT: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+// The number of nodes encoded by this thread so tests can assert encodes are not repeated.
+#[cfg(test)]
+pub(crate) fn encode_count() -> u64 {
+    ENCODE_COUNT.with(|count| count.get())
+}
+
+// A writer that only counts the bytes written to it.
+#[derive(Default)]
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The exact number of bytes in the cbor encoding of a [Node] without allocating the encoded
+/// buffer.
+pub fn encoded_size<HW>(node: &Node<HW>) -> usize
+where
+    HW: HashWriter,
+{
+    let mut counter = ByteCounter::default();
+    ciborium::ser::into_writer(node, &mut counter)
+        .expect("Counting the encoded bytes of a node can not fail");
+    counter.0
+}
+
+/// The at rest cbor encoding of a [Node].
+pub fn encode<HW>(node: &Node<HW>) -> Vec<u8>
+where
+    HW: HashWriter,
+{
+    #[cfg(test)]
+    ENCODE_COUNT.with(|count| count.set(count.get() + 1));
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(node, &mut buf).expect("Encoding a node can not fail");
+    buf
+}
+
+/// Pairs every [Node] with its at rest cbor encoding for [Store::store_many_encoded].
+///
+/// [Store::store_many_encoded]: super::Store::store_many_encoded
+#[cfg(any(feature = "flate2", feature = "encryption"))]
+pub(crate) fn encode_records<HW, I>(nodes: I) -> Vec<(Node<HW>, Vec<u8>)>
+where
+    HW: HashWriter,
+    I: IntoIterator<Item = Node<HW>>,
+{
+    nodes
+        .into_iter()
+        .map(|node| {
+            let encoded = encode(&node);
+            (node, encoded)
+        })
+        .collect()
+}
+
+/// Decode a [Node] from its at rest cbor encoding.
+pub fn decode<HW>(bytes: &[u8]) -> Result<Node<HW>>
+where
+    HW: HashWriter,
+{
+    ciborium::de::from_reader(bytes)
+        .map_err(|e| StoreError::StoreFailure(format!("Invalid serialization {:?}", e)))
+}
+
+/// The canonical cbor encoding of an item. The keys of every map are sorted by their encoded
+/// bytes as in the deterministic encoding of RFC 8949 so equal values encode to the same bytes
+/// whatever order their maps iterate in. Lengths are always definite and integers and floats
+/// always take their shortest encoding.
+pub fn encode_item<T: Serialize + ?Sized>(item: &T) -> std::result::Result<Vec<u8>, EncodeError> {
+    let value =
+        Value::serialized(item).map_err(|e| EncodeError(format!("Invalid item {:?}", e)))?;
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&canonical(value), &mut buf)
+        .expect("Encoding a cbor value can not fail");
+    Ok(buf)
+}
+
+// Sorts the map keys of `value` and everything nested in it by their encoded bytes.
+fn canonical(value: Value) -> Value {
+    match value {
+        Value::Map(entries) => {
+            let mut entries: Vec<(Vec<u8>, Value, Value)> = entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = canonical(key);
+                    let mut encoded = Vec::new();
+                    ciborium::ser::into_writer(&key, &mut encoded)
+                        .expect("Encoding a cbor value can not fail");
+                    (encoded, key, canonical(value))
+                })
+                .collect();
+            entries.sort_by(|left, right| left.0.cmp(&right.0));
+            Value::Map(
+                entries
+                    .into_iter()
+                    .map(|(_, key, value)| (key, value))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonical).collect()),
+        Value::Tag(tag, value) => Value::Tag(tag, Box::new(canonical(*value))),
+        value => value,
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+use super::{codec, PersistedRoots, Result, Store, StoreError, StoreStats};
+use crate::{dag::CachedValue, hash::HashWriter, node::Node};
+
+/// The first byte of a compressed record. Uncompressed records are cbor maps which never
+/// start with it.
+pub const COMPRESSED_TAG: u8 = 0x01;
+
+/// A [Store] wrapper that deflates the [codec] encoding of every [Node] before writing it
+/// with [Store::store_encoded] and inflates it again on reads. Records that don't shrink
+/// are written as is, and records written without the wrapper can still be read through it.
+/// Checking for a node never inflates its record. Requires the `flate2` feature.
+///
+/// The wrapped [Store] must support [Store::store_encoded] and, to persist roots with the
+/// nodes, [Store::store_many_encoded]. Backend features that read
+/// records themselves, like the closure sizes of the SQLite backend, can't read compressed
+/// records.
+#[derive(Debug, Default)]
+pub struct CompressedStore<S> {
+    inner: S,
+    level: Compression,
+}
+
+impl<S> CompressedStore<S> {
+    /// Wrap a [Store] compressing with the default level.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            level: Compression::default(),
+        }
+    }
+
+    /// Compress with `level` from 0 for none to 9 for the smallest records.
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = Compression::new(level.min(9));
+        self
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn compress(&self, encoded: Vec<u8>) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(vec![COMPRESSED_TAG], self.level);
+        encoder
+            .write_all(&encoded)
+            .expect("Compressing into memory can not fail");
+        let compressed = encoder
+            .finish()
+            .expect("Compressing into memory can not fail");
+        if compressed.len() < encoded.len() {
+            compressed
+        } else {
+            encoded
+        }
+    }
+}
+
+// Returns the codec encoding of a stored record.
+fn decompress(record: Vec<u8>) -> Result<Vec<u8>> {
+    if record.first() != Some(&COMPRESSED_TAG) {
+        return Ok(record);
+    }
+    let mut encoded = Vec::new();
+    DeflateDecoder::new(&record[1..])
+        .read_to_end(&mut encoded)
+        .map_err(|e| StoreError::StoreFailure(format!("Invalid compressed record {:?}", e)))?;
+    Ok(encoded)
+}
+
+impl<HW, S> Store<HW> for CompressedStore<S>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        self.inner.contains(id)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        self.inner.contains_many(ids)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        match self.get_raw(id)? {
+            Some(encoded) => Ok(Some(codec::decode(&encoded)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        let record = self.compress(codec::encode(&node));
+        self.inner.store_encoded(node, record)
+    }
+
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_raw(id)?.map(decompress).transpose()
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        ids.iter().map(|id| self.get(id)).collect()
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        let record = self.compress(encoded);
+        self.inner.store_encoded(node, record)
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        let records = records
+            .into_iter()
+            .map(|(node, encoded)| {
+                let record = self.compress(encoded);
+                (node, record)
+            })
+            .collect();
+        self.inner.store_many_encoded(records, roots)
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.store_many_encoded(codec::encode_records(nodes), None)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.store_many_encoded(codec::encode_records([node]), Some(roots))
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.store_many_encoded(codec::encode_records(nodes), Some(roots))
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.inner.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.inner.delete(id)
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.inner.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        self.inner.quarantine_foreign_keys(expected_len)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_quarantined(id)?.map(decompress).transpose()
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.inner.begin_batch()
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.inner.commit_batch()
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.inner.rollback_batch()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
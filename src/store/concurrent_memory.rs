@@ -0,0 +1,133 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use super::{ConcurrentStore, Result, Store};
+use crate::{hash::HashWriter, node::Node};
+
+/// An in memory [ConcurrentStore] that many threads can write to at once without a global
+/// lock. Clones share the same [nodes](Node) so every [Merkle DAG](crate::dag::Merkle) built
+/// on a clone sees the nodes added through the others. Requires the `dashmap` feature.
+pub struct ConcurrentMemoryStore<HW>
+where
+    HW: HashWriter,
+{
+    nodes: Arc<DashMap<Vec<u8>, Node<HW>>>,
+}
+
+impl<HW> ConcurrentMemoryStore<HW>
+where
+    HW: HashWriter,
+{
+    /// An empty store.
+    pub fn new() -> Self {
+        Self {
+            nodes: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+impl<HW> Default for ConcurrentMemoryStore<HW>
+where
+    HW: HashWriter,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<HW> Clone for ConcurrentMemoryStore<HW>
+where
+    HW: HashWriter,
+{
+    fn clone(&self) -> Self {
+        Self {
+            nodes: Arc::clone(&self.nodes),
+        }
+    }
+}
+
+impl<HW> Store<HW> for ConcurrentMemoryStore<HW>
+where
+    HW: HashWriter,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        Ok(self.nodes.contains_key(id))
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        Ok(self.nodes.get(id).map(|node| node.value().clone()))
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.store_shared(node)
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.nodes.remove(id);
+        Ok(())
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        // The shards are unordered so the ids are collected and sorted first.
+        let mut ids: Vec<Vec<u8>> = self.nodes.iter().map(|node| node.key().clone()).collect();
+        ids.sort();
+        Ok(Box::new(ids.into_iter().map(Ok)))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.nodes.len())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.nodes.is_empty())
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        let mut histogram = BTreeMap::new();
+        for node in self.nodes.iter() {
+            *histogram.entry(node.key().len()).or_insert(0) += 1;
+        }
+        Ok(histogram)
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        let mut ids: Vec<Vec<u8>> = self
+            .nodes
+            .iter()
+            .filter(|node| node.key().starts_with(prefix))
+            .map(|node| node.key().clone())
+            .collect();
+        ids.sort();
+        ids.truncate(limit);
+        Ok(ids)
+    }
+}
+
+impl<HW> ConcurrentStore<HW> for ConcurrentMemoryStore<HW>
+where
+    HW: HashWriter,
+{
+    fn store_shared(&self, node: Node<HW>) -> Result<()> {
+        self.nodes.insert(node.id().to_vec(), node);
+        Ok(())
+    }
+
+    fn flush_shared(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Checks of the behavior every [Store] is expected to share. Each check panics on the first
+//! difference so it can be run from a test. Use [store_conformance_tests](crate::store_conformance_tests)
+//! to run all of them against a [Store]. Requires the `testing` feature.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{Store, StoreError};
+use crate::{
+    dag::Merkle,
+    dag::NodeCompare,
+    hash::{HashKey, HashWriter},
+    node::{Node, NodeIdVersion, NodeIntegrityError, NodeSignature},
+};
+
+/// The key of the [SipHash24](crate::hash::siphash::SipHash24) hasher
+/// [store_conformance_tests](crate::store_conformance_tests) uses by default. It isn't a
+/// secret.
+pub struct ConformanceKey;
+
+impl HashKey for ConformanceKey {
+    fn key() -> [u8; 32] {
+        *b"merkle-dag store conformance key"
+    }
+}
+
+// Payloads bigger than the usual page and block sizes of the backends.
+const LARGE_PAYLOAD: usize = 4 * 1024 * 1024;
+
+fn assert_same_node<HW: HashWriter>(found: &Node<HW>, expected: &Node<HW>) {
+    assert_eq!(found.id(), expected.id());
+    assert_eq!(found.item(), expected.item());
+    assert_eq!(found.dependency_ids(), expected.dependency_ids());
+    assert_eq!(found.id_version(), expected.id_version());
+    assert_eq!(found.attributes(), expected.attributes());
+    assert_eq!(found.is_detached(), expected.is_detached());
+    assert_eq!(found.signature(), expected.signature());
+}
+
+/// Checks that adding the same payload and dependencies twice through a
+/// [Merkle DAG](Merkle) stores a single node and returns the same id both times.
+pub fn check_idempotent_add<HW, S>(store: S)
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    let mut dag = Merkle::<S, HW>::new(store);
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm = dag
+        .add_node("qualm", BTreeSet::from([quake.clone()]))
+        .unwrap();
+    assert_eq!(dag.add_node("quake", BTreeSet::new()).unwrap(), quake);
+    assert_eq!(
+        dag.add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap(),
+        qualm
+    );
+    assert_eq!(dag.get_roots(), &BTreeSet::from([qualm.clone()]));
+    let node = dag.get_node_by_id(&qualm).unwrap().unwrap();
+    assert_same_node(&node, &Node::new("qualm", BTreeSet::from([quake])));
+}
+
+/// Checks that storing a node the [Store] already holds directly through [Store::store] or
+/// [Store::store_many] succeeds and leaves a single copy of the node.
+pub fn check_idempotent_store<HW, S>(mut store: S)
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    let quake = Node::<HW>::new("quake", BTreeSet::new());
+    let qualm = Node::<HW>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+    store.store(quake.clone()).unwrap();
+    store.store(quake.clone()).unwrap();
+    store
+        .store_many([quake.clone(), qualm.clone(), qualm.clone()])
+        .unwrap();
+    let found = store
+        .get_many(&[quake.id(), qualm.id(), quake.id()])
+        .unwrap();
+    assert!(found.iter().all(Option::is_some));
+    for node in [&quake, &qualm] {
+        assert!(store.contains(node.id()).unwrap());
+        assert_same_node(&store.get(node.id()).unwrap().unwrap(), node);
+    }
+}
+
+/// Checks that stored [nodes](Node) read back with the same id, payload and dependencies
+/// through [Store::get], [Store::get_many] and [Store::get_handle].
+pub fn check_round_trip<HW, S>(mut store: S)
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    let quake = Node::<HW>::new("quake", BTreeSet::new());
+    let qualm = Node::<HW>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+    let empty = Node::<HW>::new(Vec::new(), BTreeSet::from([qualm.id().to_vec()]));
+    let nodes = [quake, qualm, empty];
+    for node in nodes.iter() {
+        store.store(node.clone()).unwrap();
+    }
+    for node in nodes.iter() {
+        assert!(store.contains(node.id()).unwrap());
+        assert_same_node(&store.get(node.id()).unwrap().unwrap(), node);
+        let handle = store.get_handle(node.id()).unwrap().unwrap();
+        assert_eq!(handle.id(), node.id());
+        assert_eq!(handle.item_id(), node.item_id());
+        assert_eq!(handle.dependency_ids(), node.dep_set());
+    }
+    let ids: Vec<&[u8]> = nodes.iter().rev().map(Node::id).collect();
+    let found = store.get_many(&ids).unwrap();
+    assert_eq!(found.len(), nodes.len());
+    for (found, node) in found.iter().zip(nodes.iter().rev()) {
+        assert_same_node(found.as_ref().unwrap(), node);
+    }
+}
+
+/// Checks that a [Store] holding [nodes](Node) with ids of different [versions](NodeIdVersion)
+/// reads each of them back under its own version and that a [Merkle DAG](Merkle) extends them
+/// with either version.
+pub fn check_mixed_id_versions<HW, S>(mut store: S)
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    let quake = Node::<HW>::new("quake", BTreeSet::new());
+    let qualm = Node::<HW>::new_with_id_version(
+        "qualm",
+        BTreeSet::from([quake.id().to_vec()]),
+        NodeIdVersion::V1,
+    );
+    let nodes = [quake, qualm];
+    store.store_many(nodes.iter().cloned()).unwrap();
+    for node in nodes.iter() {
+        assert_same_node(&store.get(node.id()).unwrap().unwrap(), node);
+    }
+    let ids: Vec<&[u8]> = nodes.iter().map(Node::id).collect();
+    for (found, node) in store.get_many(&ids).unwrap().iter().zip(nodes.iter()) {
+        assert_same_node(found.as_ref().unwrap(), node);
+    }
+    let mut dag = Merkle::<S, HW>::new(store);
+    let quell = dag
+        .add_node("quell", BTreeSet::from([nodes[1].id().to_vec()]))
+        .unwrap();
+    dag.set_id_version(NodeIdVersion::V1);
+    let quill = dag
+        .add_node("quill", BTreeSet::from([nodes[0].id().to_vec()]))
+        .unwrap();
+    assert_same_node(
+        &dag.get_node_by_id(&quell).unwrap().unwrap(),
+        &Node::new("quell", BTreeSet::from([nodes[1].id().to_vec()])),
+    );
+    assert_same_node(
+        &dag.get_node_by_id(&quill).unwrap().unwrap(),
+        &Node::new_with_id_version(
+            "quill",
+            BTreeSet::from([nodes[0].id().to_vec()]),
+            NodeIdVersion::V1,
+        ),
+    );
+}
+
+/// Checks that the [Store] keeps the attributes of [nodes](Node) and that nodes differing only
+/// in their attributes are stored apart.
+pub fn check_attributes<HW, S>(store: S)
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    let mut dag = Merkle::<S, HW>::new(store);
+    let plain = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let attributes = BTreeMap::from([
+        ("author".to_owned(), b"jwall".to_vec()),
+        ("op".to_owned(), vec![1]),
+    ]);
+    let tagged = dag
+        .add_node_with_attrs("quake", BTreeSet::new(), attributes.clone())
+        .unwrap();
+    assert_ne!(plain, tagged);
+    assert_same_node(
+        &dag.get_node_by_id(&plain).unwrap().unwrap(),
+        &Node::new("quake", BTreeSet::new()),
+    );
+    assert_same_node(
+        &dag.get_node_by_id(&tagged).unwrap().unwrap(),
+        &Node::new_with_attrs("quake", BTreeSet::new(), attributes),
+    );
+}
+
+/// Checks that the [Store] keeps [detached](Node::is_detached) nodes with their ids and
+/// without their items next to attached nodes. A [Store] that checks the nodes it reads may
+/// refuse detached nodes with [StoreError::InvalidNode] instead.
+pub fn check_detached_nodes<HW, S>(mut store: S)
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    let quake = Node::<HW>::new("quake", BTreeSet::new());
+    let (qualm, _) = Node::<HW>::new_with_attrs(
+        "qualm",
+        BTreeSet::from([quake.id().to_vec()]),
+        BTreeMap::from([("op".to_owned(), vec![1])]),
+    )
+    .into_detached();
+    let nodes = [quake, qualm];
+    store.store_many(nodes.iter().cloned()).unwrap();
+    let found = store.get(nodes[0].id()).unwrap().unwrap();
+    assert_same_node(&found, &nodes[0]);
+    match store.get(nodes[1].id()) {
+        Ok(found) => {
+            let found = found.unwrap();
+            assert_same_node(&found, &nodes[1]);
+            assert_eq!(found.item_id(), nodes[1].item_id());
+            assert!(found.item().is_empty());
+        }
+        Err(StoreError::InvalidNode(NodeIntegrityError::DetachedItem { id })) => {
+            assert_eq!(id, nodes[1].id());
+        }
+        Err(err) => panic!("Detached node could not be read: {}", err),
+    }
+}
+
+/// Checks that the signature attached to a [Node] is stored and read back with it. The
+/// [Store] doesn't check signatures so any bytes are kept as they are.
+pub fn check_signed_nodes<HW, S>(mut store: S)
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    let quake = Node::<HW>::new("quake", BTreeSet::new()).with_signature(NodeSignature {
+        author: vec![1; 32],
+        signature: vec![2; 64],
+    });
+    let qualm = Node::<HW>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+    let nodes = [quake, qualm];
+    store.store_many(nodes.iter().cloned()).unwrap();
+    for node in nodes.iter() {
+        assert_same_node(&store.get(node.id()).unwrap().unwrap(), node);
+    }
+    assert!(store
+        .get(nodes[1].id())
+        .unwrap()
+        .unwrap()
+        .signature()
+        .is_none());
+}
+
+/// Checks that ids the [Store] doesn't hold are reported missing by every lookup, both in an
+/// empty [Store] and next to stored [nodes](Node).
+pub fn check_absent_ids<HW, S>(mut store: S)
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    let stored = Node::<HW>::new("quake", BTreeSet::new());
+    let absent = Node::<HW>::new("qualm", BTreeSet::new());
+    for round in 0..2 {
+        assert!(!store.contains(absent.id()).unwrap());
+        assert!(store.get(absent.id()).unwrap().is_none());
+        assert!(store.get_handle(absent.id()).unwrap().is_none());
+        assert_eq!(
+            store.contains_many(&[absent.id(), stored.id()]).unwrap(),
+            vec![false, round == 1]
+        );
+        let found = store.get_many(&[absent.id(), stored.id()]).unwrap();
+        assert!(found[0].is_none());
+        assert_eq!(found[1].is_some(), round == 1);
+        if round == 0 {
+            store.store(stored.clone()).unwrap();
+        }
+    }
+    assert!(!store.contains(b"").unwrap());
+    assert!(store.get(b"").unwrap().is_none());
+}
+
+/// Checks that [nodes](Node) with large payloads and many dependencies round trip.
+pub fn check_large_payloads<HW, S>(mut store: S)
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    let deps: Vec<Node<HW>> = (0..100)
+        .map(|idx| Node::new(format!("dep-{}", idx), BTreeSet::new()))
+        .collect();
+    for dep in deps.iter() {
+        store.store(dep.clone()).unwrap();
+    }
+    let payload: Vec<u8> = (0..LARGE_PAYLOAD).map(|idx| (idx % 251) as u8).collect();
+    let large = Node::<HW>::new(
+        payload,
+        deps.iter()
+            .map(|dep| dep.id().to_vec())
+            .collect::<BTreeSet<_>>(),
+    );
+    store.store(large.clone()).unwrap();
+    assert_same_node(&store.get(large.id()).unwrap().unwrap(), &large);
+}
+
+/// Checks that a [Merkle DAG](Merkle) sees its own writes while adding nodes, reading them
+/// and comparing them in turn.
+pub fn check_interleaved_operations<HW, S>(store: S)
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    let mut dag = Merkle::<S, HW>::new(store);
+    let mut ids: Vec<Vec<u8>> = Vec::new();
+    for idx in 0..200 {
+        let deps: BTreeSet<Vec<u8>> = ids.iter().rev().step_by(5).take(2).cloned().collect();
+        let payload = format!("interleaved-{}", idx);
+        let id = dag.add_node(payload.clone(), deps.clone()).unwrap();
+        assert!(dag.check_for_node(&id).unwrap());
+        let node = dag.get_node_by_id(&id).unwrap().unwrap();
+        assert_same_node(&node, &Node::new(payload, deps.clone()));
+        for dep in deps.iter() {
+            assert_eq!(dag.compare(&id, dep).unwrap(), NodeCompare::After);
+            assert!(!dag.get_roots().contains(dep));
+        }
+        assert!(dag.get_roots().contains(&id));
+        ids.push(id);
+    }
+    assert_eq!(
+        dag.compare(&ids[0], &ids[199]).unwrap(),
+        NodeCompare::Before
+    );
+    let island = dag.add_node("island", BTreeSet::new()).unwrap();
+    assert_eq!(
+        dag.compare(&island, &ids[199]).unwrap(),
+        NodeCompare::Uncomparable
+    );
+}
+
+/// Generate a `#[test]` for every check in [store::conformance](crate::store::conformance)
+/// calling `$make` for a fresh [Store](crate::store::Store) each time. The hasher defaults to
+/// [SipHash24](crate::hash::siphash::SipHash24) keyed with [ConformanceKey]. Invoke it inside
+/// its own test module.
+///
+/// ```ignore
+/// mod conformance {
+///     merkle_dag::store_conformance_tests!(MyStore::default);
+/// }
+/// ```
+#[macro_export]
+macro_rules! store_conformance_tests {
+    ($make:expr) => {
+        $crate::store_conformance_tests!(
+            $make,
+            $crate::hash::siphash::SipHash24<$crate::store::conformance::ConformanceKey>
+        );
+    };
+    ($make:expr, $hw:ty) => {
+        #[test]
+        fn conformance_idempotent_add() {
+            $crate::store::conformance::check_idempotent_add::<$hw, _>(($make)());
+        }
+
+        #[test]
+        fn conformance_idempotent_store() {
+            $crate::store::conformance::check_idempotent_store::<$hw, _>(($make)());
+        }
+
+        #[test]
+        fn conformance_round_trip() {
+            $crate::store::conformance::check_round_trip::<$hw, _>(($make)());
+        }
+
+        #[test]
+        fn conformance_mixed_id_versions() {
+            $crate::store::conformance::check_mixed_id_versions::<$hw, _>(($make)());
+        }
+
+        #[test]
+        fn conformance_attributes() {
+            $crate::store::conformance::check_attributes::<$hw, _>(($make)());
+        }
+
+        #[test]
+        fn conformance_detached_nodes() {
+            $crate::store::conformance::check_detached_nodes::<$hw, _>(($make)());
+        }
+
+        #[test]
+        fn conformance_signed_nodes() {
+            $crate::store::conformance::check_signed_nodes::<$hw, _>(($make)());
+        }
+
+        #[test]
+        fn conformance_absent_ids() {
+            $crate::store::conformance::check_absent_ids::<$hw, _>(($make)());
+        }
+
+        #[test]
+        fn conformance_large_payloads() {
+            $crate::store::conformance::check_large_payloads::<$hw, _>(($make)());
+        }
+
+        #[test]
+        fn conformance_interleaved_operations() {
+            $crate::store::conformance::check_interleaved_operations::<$hw, _>(($make)());
+        }
+    };
+}
@@ -0,0 +1,109 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use super::verifying::verify;
+use super::{Result, Store, StoreError};
+use crate::{hash::HashWriter, node::Node};
+
+// The number of nodes read and written per batch while copying.
+const COPY_BATCH: usize = 500;
+
+/// What [copy_store] copied.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct CopyReport {
+    /// The [nodes](Node) written to the destination.
+    pub copied: u64,
+    /// The ids skipped because the destination already held them.
+    pub already_present: u64,
+    /// The ids whose [Node] no longer hashes to them in the source. They are not copied.
+    pub corrupt: Vec<Vec<u8>>,
+    /// The ids listed by the source that were gone by the time they were read.
+    pub vanished: Vec<Vec<u8>>,
+    /// Whether the persisted roots of the source were written to the destination.
+    pub roots_copied: bool,
+}
+
+/// Copy every [Node] of the `src` [Store] into the `dst` [Store] in batches, for example to
+/// move a DAG to another backend. Nodes are hashed again while reading and the ones that no
+/// longer match their id are reported instead of copied. Ids the destination already holds
+/// are skipped so an interrupted copy can be run again to finish it.
+///
+/// The persisted roots of the source are copied last if both stores support them. Requires a
+/// source that supports [Store::ids].
+pub fn copy_store<HW, Src, Dst>(src: &Src, dst: &mut Dst) -> Result<CopyReport>
+where
+    HW: HashWriter,
+    Src: Store<HW>,
+    Dst: Store<HW>,
+{
+    let mut report = CopyReport::default();
+    let mut batch = Vec::with_capacity(COPY_BATCH);
+    for id in src.ids()? {
+        let id = id?;
+        if dst.contains(&id)? {
+            report.already_present += 1;
+            continue;
+        }
+        batch.push(id);
+        if batch.len() == COPY_BATCH {
+            copy_batch(src, dst, &mut batch, &mut report)?;
+        }
+    }
+    copy_batch(src, dst, &mut batch, &mut report)?;
+    let roots = match src.persisted_roots() {
+        Ok(roots) => roots,
+        Err(StoreError::Unsupported(_)) => None,
+        Err(e) => return Err(e),
+    };
+    if let Some(roots) = roots {
+        match dst.persist_roots(&roots) {
+            Ok(()) => report.roots_copied = true,
+            Err(StoreError::Unsupported(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(report)
+}
+
+fn copy_batch<HW, Src, Dst>(
+    src: &Src,
+    dst: &mut Dst,
+    batch: &mut Vec<Vec<u8>>,
+    report: &mut CopyReport,
+) -> Result<()>
+where
+    HW: HashWriter,
+    Src: Store<HW>,
+    Dst: Store<HW>,
+{
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let nodes = {
+        let ids: Vec<&[u8]> = batch.iter().map(Vec::as_slice).collect();
+        src.get_many(&ids)?
+    };
+    let mut verified: Vec<Node<HW>> = Vec::with_capacity(batch.len());
+    for (id, node) in batch.drain(..).zip(nodes) {
+        match node.map(|node| verify(&id, node)) {
+            Some(Ok(node)) => verified.push(node),
+            Some(Err(StoreError::CorruptNode { id })) => report.corrupt.push(id),
+            Some(Err(e)) => return Err(e),
+            None => report.vanished.push(id),
+        }
+    }
+    let copied = verified.len() as u64;
+    dst.store_many(verified)?;
+    report.copied += copied;
+    Ok(())
+}
@@ -0,0 +1,249 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use super::{codec, PersistedRoots, Result, Store, StoreError, StoreStats};
+use crate::{dag::CachedValue, hash::HashWriter, node::Node};
+
+/// The first byte of an encrypted record.
+pub const ENCRYPTED_TAG: u8 = 0x02;
+
+const NONCE_LEN: usize = 24;
+
+/// A [Store] wrapper that encrypts the [codec] encoding of every [Node] with
+/// XChaCha20-Poly1305 before writing it with [Store::store_encoded]. Each record gets a
+/// random nonce and is bound to the id it is stored under so a record moved to another id
+/// fails to decrypt. Reads fail with [StoreError::DecryptionFailed] for records that don't
+/// authenticate, including records written without the wrapper. Requires the `encryption`
+/// feature.
+///
+/// Ids, dependency structure and persisted roots are not encrypted. Backend indexes built
+/// from the payload, like the payload index of the SQLite backend, hold it in the clear and
+/// should be turned off. Backend features that read records themselves, like the closure
+/// sizes of the SQLite backend, can't read encrypted records. The wrapped [Store] must
+/// support [Store::store_encoded] and, to persist roots with the nodes,
+/// [Store::store_many_encoded].
+pub struct EncryptedStore<S> {
+    inner: S,
+    cipher: XChaCha20Poly1305,
+}
+
+impl<S> EncryptedStore<S> {
+    /// Wrap a [Store] encrypting with the 256 bit `key`.
+    pub fn new(inner: S, key: &[u8; 32]) -> Self {
+        Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn encrypt(&self, id: &[u8], encoded: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: encoded,
+                    aad: id,
+                },
+            )
+            .map_err(|e| StoreError::StoreFailure(format!("Encryption failed {:?}", e)))?;
+        let mut record = Vec::with_capacity(1 + NONCE_LEN + sealed.len());
+        record.push(ENCRYPTED_TAG);
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&sealed);
+        Ok(record)
+    }
+
+    fn decrypt(&self, id: &[u8], record: &[u8]) -> Result<Vec<u8>> {
+        let failed = || StoreError::DecryptionFailed(id.to_vec());
+        if record.len() < 1 + NONCE_LEN || record[0] != ENCRYPTED_TAG {
+            return Err(failed());
+        }
+        let (nonce, sealed) = record[1..].split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: id,
+                },
+            )
+            .map_err(|_| failed())
+    }
+}
+
+impl<HW, S> Store<HW> for EncryptedStore<S>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        self.inner.contains(id)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        self.inner.contains_many(ids)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        match self.get_raw(id)? {
+            Some(encoded) => Ok(Some(codec::decode(&encoded)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        let record = self.encrypt(node.id(), &codec::encode(&node))?;
+        self.inner.store_encoded(node, record)
+    }
+
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.inner.get_raw(id)? {
+            Some(record) => Ok(Some(self.decrypt(id, &record)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        ids.iter().map(|id| self.get(id)).collect()
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        let record = self.encrypt(node.id(), &encoded)?;
+        self.inner.store_encoded(node, record)
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        let records = records
+            .into_iter()
+            .map(|(node, encoded)| {
+                let record = self.encrypt(node.id(), &encoded)?;
+                Ok((node, record))
+            })
+            .collect::<Result<_>>()?;
+        self.inner.store_many_encoded(records, roots)
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.store_many_encoded(codec::encode_records(nodes), None)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.store_many_encoded(codec::encode_records([node]), Some(roots))
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.store_many_encoded(codec::encode_records(nodes), Some(roots))
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.inner.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.inner.delete(id)
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.inner.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        self.inner.quarantine_foreign_keys(expected_len)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner
+            .get_quarantined(id)?
+            .map(|record| self.decrypt(id, &record))
+            .transpose()
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.inner.begin_batch()
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.inner.commit_batch()
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.inner.rollback_batch()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::BTreeMap;
+
+/// A bounded map that evicts its least recently used entries.
+#[derive(Debug)]
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    // The values with the tick of their last use.
+    entries: BTreeMap<K, (u64, V)>,
+    // The keys of the entries ordered by their last use.
+    recency: BTreeMap<u64, K>,
+}
+
+impl<K, V> Lru<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: BTreeMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Returns a copy of the value marking it as the most recently used.
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (used, value) = self.entries.get_mut(key)?;
+        let key = self.recency.remove(used).unwrap();
+        *used = tick;
+        self.recency.insert(tick, key);
+        Some(value.clone())
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        self.tick += 1;
+        if let Some((used, _)) = self.entries.insert(key.clone(), (self.tick, value)) {
+            self.recency.remove(&used);
+        }
+        self.recency.insert(self.tick, key);
+        while self.entries.len() > self.capacity {
+            let (_, oldest) = self.recency.pop_first().unwrap();
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some((used, _)) = self.entries.remove(key) {
+            self.recency.remove(&used);
+        }
+    }
+
+    // Keeps only the entries whose key satisfies `keep`.
+    pub(crate) fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&K) -> bool,
+    {
+        self.entries.retain(|key, _| keep(key));
+        self.recency.retain(|_, key| keep(key));
+    }
+}
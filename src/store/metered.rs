@@ -0,0 +1,281 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "cbor")]
+use super::StoreStats;
+use super::{PersistedRoots, Result, Store};
+use crate::{
+    dag::{CachedValue, NodeHandle},
+    hash::HashWriter,
+    node::Node,
+};
+
+/// A snapshot of the counters of a [MeteredStore].
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct StoreMetrics {
+    /// Calls to [Store::contains] and [Store::contains_many].
+    pub contains_calls: u64,
+    /// Calls to [Store::get], [Store::get_many] and [Store::get_handle].
+    pub get_calls: u64,
+    /// Calls to [Store::store], [Store::store_many] and their variants writing roots.
+    pub store_calls: u64,
+    /// Calls to [Store::delete].
+    pub delete_calls: u64,
+    /// The [nodes](Node) returned by the get calls. Handles don't count.
+    pub nodes_read: u64,
+    /// The [nodes](Node) passed to the store calls.
+    pub nodes_written: u64,
+    /// The payload bytes of the [nodes](Node) read.
+    pub payload_bytes_read: u64,
+    /// The payload bytes of the [nodes](Node) written.
+    pub payload_bytes_written: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    contains_calls: AtomicU64,
+    get_calls: AtomicU64,
+    store_calls: AtomicU64,
+    delete_calls: AtomicU64,
+    nodes_read: AtomicU64,
+    nodes_written: AtomicU64,
+    payload_bytes_read: AtomicU64,
+    payload_bytes_written: AtomicU64,
+}
+
+fn bump(counter: &AtomicU64, by: u64) {
+    counter.fetch_add(by, Ordering::Relaxed);
+}
+
+/// A [Store] wrapper counting the calls the DAG makes and the payload bytes they move. The
+/// counters are relaxed atomics so the wrapper is cheap enough to leave on.
+#[derive(Debug, Default)]
+pub struct MeteredStore<S> {
+    inner: S,
+    counters: Counters,
+}
+
+impl<S> MeteredStore<S> {
+    /// Wrap a [Store] counting its calls.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// A snapshot of the counters.
+    pub fn metrics(&self) -> StoreMetrics {
+        let counters = &self.counters;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StoreMetrics {
+            contains_calls: load(&counters.contains_calls),
+            get_calls: load(&counters.get_calls),
+            store_calls: load(&counters.store_calls),
+            delete_calls: load(&counters.delete_calls),
+            nodes_read: load(&counters.nodes_read),
+            nodes_written: load(&counters.nodes_written),
+            payload_bytes_read: load(&counters.payload_bytes_read),
+            payload_bytes_written: load(&counters.payload_bytes_written),
+        }
+    }
+
+    /// Set every counter back to zero returning the counts up to now.
+    pub fn reset(&self) -> StoreMetrics {
+        let metrics = self.metrics();
+        let counters = &self.counters;
+        for counter in [
+            &counters.contains_calls,
+            &counters.get_calls,
+            &counters.store_calls,
+            &counters.delete_calls,
+            &counters.nodes_read,
+            &counters.nodes_written,
+            &counters.payload_bytes_read,
+            &counters.payload_bytes_written,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        metrics
+    }
+
+    fn count_read<HW: HashWriter>(&self, node: Option<&Node<HW>>) {
+        if let Some(node) = node {
+            bump(&self.counters.nodes_read, 1);
+            bump(&self.counters.payload_bytes_read, node.item().len() as u64);
+        }
+    }
+
+    fn count_write<HW: HashWriter>(&self, nodes: &[Node<HW>]) {
+        bump(&self.counters.store_calls, 1);
+        bump(&self.counters.nodes_written, nodes.len() as u64);
+        let bytes: usize = nodes.iter().map(|node| node.item().len()).sum();
+        bump(&self.counters.payload_bytes_written, bytes as u64);
+    }
+}
+
+impl<HW, S> Store<HW> for MeteredStore<S>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        bump(&self.counters.contains_calls, 1);
+        self.inner.contains(id)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        bump(&self.counters.contains_calls, 1);
+        self.inner.contains_many(ids)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        bump(&self.counters.get_calls, 1);
+        let node = self.inner.get(id)?;
+        self.count_read(node.as_ref());
+        Ok(node)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.count_write(std::slice::from_ref(&node));
+        self.inner.store(node)
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        bump(&self.counters.get_calls, 1);
+        let nodes = self.inner.get_many(ids)?;
+        for node in nodes.iter() {
+            self.count_read(node.as_ref());
+        }
+        Ok(nodes)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_raw(id)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        bump(&self.counters.get_calls, 1);
+        self.inner.get_handle(id)
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        self.count_write(&nodes);
+        self.inner.store_many(nodes)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.count_write(std::slice::from_ref(&node));
+        self.inner.store_with_roots(node, roots)
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        self.count_write(&nodes);
+        self.inner.store_many_with_roots(nodes, roots)
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.inner.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        bump(&self.counters.delete_calls, 1);
+        self.inner.delete(id)
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.inner.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        self.inner.quarantine_foreign_keys(expected_len)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.inner.begin_batch()
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.inner.commit_batch()
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.inner.rollback_batch()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
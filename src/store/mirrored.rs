@@ -0,0 +1,395 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(feature = "cbor")]
+use super::StoreStats;
+use super::{PersistedRoots, Result, Store, StoreError};
+use crate::{
+    dag::{CachedValue, NodeHandle},
+    hash::HashWriter,
+    node::Node,
+};
+
+/// What a [MirroredStore] does when a write to its secondary [Store] fails.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MirrorPolicy {
+    /// Fail the call. The write to the primary has already happened.
+    Strict,
+    /// Record the failure in [MirroredStore::failures] and carry on.
+    BestEffort,
+    /// Queue the write in [MirroredStore::pending] to be retried by
+    /// [MirroredStore::flush_pending].
+    Retry,
+}
+
+/// A write to the secondary [Store] of a [MirroredStore].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum MirrorOp {
+    /// Store the [Node] with this id.
+    Store(Vec<u8>),
+    /// Delete the [Node] with this id.
+    Delete(Vec<u8>),
+    /// Quarantine the records whose id is not this many bytes long.
+    QuarantineForeignKeys(usize),
+}
+
+/// A failed write to the secondary [Store] of a [MirroredStore].
+#[derive(Clone, Debug)]
+pub struct MirrorFailure {
+    pub op: MirrorOp,
+    pub error: StoreError,
+}
+
+#[derive(Debug, Default)]
+struct MirrorLog {
+    pending: Vec<MirrorOp>,
+    failures: Vec<MirrorFailure>,
+}
+
+/// A [Store] that mirrors every [Node] written to or deleted from its primary [Store] to a
+/// secondary [Store], for example a backup. Reads only go to the primary.
+///
+/// The secondary is written after the primary and the [MirrorPolicy] decides what happens
+/// when that fails. Persisted roots are only written to the primary. A secondary can
+/// [reconstruct](crate::dag::Merkle::reconstruct_roots) them. Inside a batch only the
+/// primary is written. The writes are mirrored once the primary committed the batch.
+#[derive(Debug)]
+pub struct MirroredStore<P, S> {
+    primary: P,
+    secondary: Mutex<S>,
+    policy: MirrorPolicy,
+    log: Mutex<MirrorLog>,
+    // The writes of the open batch to mirror when it is committed.
+    batch: Option<Vec<MirrorOp>>,
+}
+
+impl<P, S> MirroredStore<P, S> {
+    /// Mirror the writes to the `primary` [Store] to the `secondary` [Store].
+    pub fn new(primary: P, secondary: S, policy: MirrorPolicy) -> Self {
+        Self {
+            primary,
+            secondary: Mutex::new(secondary),
+            policy,
+            log: Mutex::new(MirrorLog::default()),
+            batch: None,
+        }
+    }
+
+    /// Get a reference to the primary [Store].
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// Get the secondary [Store].
+    pub fn secondary(&self) -> MutexGuard<'_, S> {
+        self.secondary.lock().unwrap()
+    }
+
+    /// The [MirrorPolicy] of this store.
+    pub fn policy(&self) -> MirrorPolicy {
+        self.policy
+    }
+
+    /// The writes queued for [MirroredStore::flush_pending] in the order they failed.
+    pub fn pending(&self) -> Vec<MirrorOp> {
+        self.log.lock().unwrap().pending.clone()
+    }
+
+    /// The failed writes recorded under [MirrorPolicy::BestEffort].
+    pub fn failures(&self) -> Vec<MirrorFailure> {
+        self.log.lock().unwrap().failures.clone()
+    }
+
+    /// Take the failed writes recorded under [MirrorPolicy::BestEffort] clearing the list.
+    pub fn take_failures(&self) -> Vec<MirrorFailure> {
+        std::mem::take(&mut self.log.lock().unwrap().failures)
+    }
+
+    /// Retry the queued writes in order returning the number that succeeded. Stops at the
+    /// first failure leaving it and the rest queued. Nodes deleted from the primary since
+    /// they were queued are skipped.
+    pub fn flush_pending<HW>(&self) -> Result<usize>
+    where
+        HW: HashWriter,
+        P: Store<HW>,
+        S: Store<HW>,
+    {
+        let mut log = self.log.lock().unwrap();
+        let mut secondary = self.secondary.lock().unwrap();
+        let mut flushed = 0;
+        while let Some(op) = log.pending.first() {
+            apply(&self.primary, &mut *secondary, op)?;
+            log.pending.remove(0);
+            flushed += 1;
+        }
+        Ok(flushed)
+    }
+
+    // Applies the policy to the outcome of the secondary writes for the ops.
+    fn mirrored(&self, ops: Vec<MirrorOp>, result: Result<()>) -> Result<()> {
+        let error = match result {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        let mut log = self.log.lock().unwrap();
+        match self.policy {
+            MirrorPolicy::Strict => return Err(error),
+            MirrorPolicy::BestEffort => {
+                log.failures.extend(ops.into_iter().map(|op| MirrorFailure {
+                    op,
+                    error: error.clone(),
+                }))
+            }
+            MirrorPolicy::Retry => log.pending.extend(ops),
+        }
+        Ok(())
+    }
+
+    // Writes to the secondary applying the policy or, inside a batch, holds the ops back
+    // until the batch is committed.
+    fn mirror<F>(&mut self, ops: Vec<MirrorOp>, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut S) -> Result<()>,
+    {
+        if let Some(batch) = self.batch.as_mut() {
+            batch.extend(ops);
+            return Ok(());
+        }
+        let result = write(self.secondary.get_mut().unwrap());
+        self.mirrored(ops, result)
+    }
+}
+
+// Applies the op to the secondary. Nodes deleted from the primary since are skipped.
+fn apply<HW, P, S>(primary: &P, secondary: &mut S, op: &MirrorOp) -> Result<()>
+where
+    HW: HashWriter,
+    P: Store<HW>,
+    S: Store<HW>,
+{
+    match op {
+        MirrorOp::Store(id) => {
+            if let Some(node) = primary.get(id)? {
+                secondary.store(node)?;
+            }
+        }
+        MirrorOp::Delete(id) => secondary.delete(id)?,
+        MirrorOp::QuarantineForeignKeys(expected_len) => {
+            secondary.quarantine_foreign_keys(*expected_len)?;
+        }
+    }
+    Ok(())
+}
+
+impl<HW, P, S> Store<HW> for MirroredStore<P, S>
+where
+    HW: HashWriter,
+    P: Store<HW>,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        self.primary.contains(id)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        self.primary.contains_many(ids)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        self.primary.get(id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        let op = MirrorOp::Store(node.id().to_vec());
+        self.primary.store(node.clone())?;
+        self.mirror(vec![op], |secondary| secondary.store(node))
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        self.primary.get_many(ids)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.primary.get_raw(id)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        self.primary.get_handle(id)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        let op = MirrorOp::Store(node.id().to_vec());
+        self.primary.store_encoded(node.clone(), encoded.clone())?;
+        self.mirror(vec![op], |secondary| secondary.store_encoded(node, encoded))
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        let ops = records
+            .iter()
+            .map(|(node, _)| MirrorOp::Store(node.id().to_vec()))
+            .collect();
+        self.primary.store_many_encoded(records.clone(), roots)?;
+        self.mirror(ops, |secondary| secondary.store_many_encoded(records, None))
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let ops = store_ops(&nodes);
+        self.primary.store_many(nodes.clone())?;
+        self.mirror(ops, |secondary| secondary.store_many(nodes))
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        let op = MirrorOp::Store(node.id().to_vec());
+        self.primary.store_with_roots(node.clone(), roots)?;
+        self.mirror(vec![op], |secondary| secondary.store(node))
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let ops = store_ops(&nodes);
+        self.primary.store_many_with_roots(nodes.clone(), roots)?;
+        self.mirror(ops, |secondary| secondary.store_many(nodes))
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.primary.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.primary.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.primary.check_hash_algorithm()?;
+        self.secondary().check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.primary.delete(id)?;
+        self.mirror(vec![MirrorOp::Delete(id.to_vec())], |secondary| {
+            secondary.delete(id)
+        })
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.primary.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.primary.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.primary.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.primary.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.primary.find_by_prefix(prefix, limit)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.primary.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.primary.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        let moved = self.primary.quarantine_foreign_keys(expected_len)?;
+        let op = MirrorOp::QuarantineForeignKeys(expected_len);
+        self.mirror(vec![op], |secondary| {
+            secondary.quarantine_foreign_keys(expected_len).map(|_| ())
+        })?;
+        Ok(moved)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.primary.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.primary.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.primary.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.primary.begin_batch()?;
+        self.batch = Some(Vec::new());
+        Ok(())
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.primary.commit_batch()?;
+        let mut ops = self.batch.take().unwrap_or_default();
+        let secondary = self.secondary.get_mut().unwrap();
+        let mut mirrored = 0;
+        let mut result = Ok(());
+        for op in ops.iter() {
+            result = apply(&self.primary, &mut *secondary, op);
+            if result.is_err() {
+                break;
+            }
+            mirrored += 1;
+        }
+        // The ops from the failed one on are subject to the policy.
+        self.mirrored(ops.split_off(mirrored), result)
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.primary.rollback_batch()?;
+        self.batch = None;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.primary.flush()?;
+        match self.secondary.get_mut().unwrap().flush() {
+            // Only a strict mirror promises the secondary is as durable as the primary.
+            Err(error) if self.policy == MirrorPolicy::Strict => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn store_ops<HW: HashWriter>(nodes: &[Node<HW>]) -> Vec<MirrorOp> {
+    nodes
+        .iter()
+        .map(|node| MirrorOp::Store(node.id().to_vec()))
+        .collect()
+}
@@ -0,0 +1,223 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use super::lru::Lru;
+use super::{codec, PersistedRoots, Result, Store, StoreStats};
+use crate::{
+    dag::{CachedValue, NodeHandle},
+    hash::HashWriter,
+    node::Node,
+};
+
+// Encodings keyed by codec version and id.
+type EncodingCache = Lru<(u32, Vec<u8>), Vec<u8>>;
+
+/// A [Store] wrapper that remembers the [codec] encoding of the most recently read
+/// [nodes](Node) so sending the same node to many peers encodes it once.
+///
+/// Entries are keyed by the codec version as well as the id so changing the version
+/// invalidates every cached encoding. Requires the `cbor` feature.
+#[derive(Debug)]
+pub struct SerializedCache<S> {
+    inner: S,
+    codec_version: u32,
+    state: Mutex<EncodingCache>,
+}
+
+impl<S> SerializedCache<S> {
+    /// Wrap a [Store] caching up to `capacity` encoded nodes for [codec::CODEC_VERSION].
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            codec_version: codec::CODEC_VERSION,
+            state: Mutex::new(Lru::new(capacity)),
+        }
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The codec version cached encodings are currently keyed by.
+    pub fn codec_version(&self) -> u32 {
+        self.codec_version
+    }
+
+    /// Key cached encodings by a different codec version. Encodings cached under the previous
+    /// version are never returned again.
+    pub fn set_codec_version(&mut self, version: u32) {
+        self.codec_version = version;
+    }
+
+    /// The number of cached encodings.
+    pub fn cached(&self) -> usize {
+        self.state.lock().unwrap().len()
+    }
+}
+
+impl<S: Default> Default for SerializedCache<S> {
+    fn default() -> Self {
+        Self::new(S::default(), 1024)
+    }
+}
+
+impl<HW, S> Store<HW> for SerializedCache<S>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        self.inner.contains(id)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        self.inner.contains_many(ids)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        self.inner.get(id)
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        self.inner.get_many(ids)
+    }
+
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = (self.codec_version, id.to_vec());
+        if let Some(bytes) = self.state.lock().unwrap().get(&key) {
+            return Ok(Some(bytes));
+        }
+        let bytes = self.inner.get_raw(id)?;
+        if let Some(bytes) = bytes.as_ref() {
+            self.state.lock().unwrap().insert(key, bytes.clone());
+        }
+        Ok(bytes)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        self.inner.get_handle(id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.inner.store(node)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.inner.store_with_roots(node, roots)
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.inner.store_many_with_roots(nodes, roots)
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.inner.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.inner.store_many(nodes)
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.inner.delete(id)?;
+        self.state
+            .lock()
+            .unwrap()
+            .retain(|(_, cached)| cached != id);
+        Ok(())
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.inner.children_of(id)
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        let moved = self.inner.quarantine_foreign_keys(expected_len)?;
+        self.state
+            .lock()
+            .unwrap()
+            .retain(|(_, id)| id.len() == expected_len);
+        Ok(moved)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.inner.begin_batch()
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.inner.commit_batch()
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.inner.rollback_batch()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, RwLock};
+
+#[cfg(feature = "cbor")]
+use super::StoreStats;
+use super::{PersistedRoots, Result, Store};
+use crate::{
+    dag::{CachedValue, NodeHandle},
+    hash::HashWriter,
+    node::Node,
+};
+
+/// A [Store] that can write [nodes](Node) through a shared reference so several writers can
+/// share it, for example behind an [Arc]. An `Arc<S>` of a [ConcurrentStore] is itself a
+/// [Store] so every [Merkle DAG](crate::dag::Merkle) sharing it can add nodes without
+/// exclusive access to the store. Operations besides reads, [Store::store] and [Store::flush]
+/// are not supported through the [Arc].
+///
+/// This is also how several [Merkle DAGs](crate::dag::Merkle), say one per document, share a
+/// backend that can only be opened once like RocksDB. Each DAG tracks its own roots but sees
+/// every node added through the others.
+///
+/// Any [Store] becomes a [ConcurrentStore] behind a [Mutex] or, if it can be read from
+/// several threads at once like a [BTreeStore](super::BTreeStore), an [RwLock].
+pub trait ConcurrentStore<HW>: Store<HW>
+where
+    HW: HashWriter,
+{
+    /// Stores a given [Node] without exclusive access to the [Store].
+    fn store_shared(&self, node: Node<HW>) -> Result<()>;
+
+    /// Flushes the [Store] without exclusive access to it. See [Store::flush].
+    fn flush_shared(&self) -> Result<()>;
+}
+
+// Implements Store and ConcurrentStore for a lock around a Store. Reads take the `$read` lock
+// and shared writes the `$write` lock. Exclusive access doesn't lock at all.
+macro_rules! locked_store_impl {
+    ($lock:ident, $read:ident, $write:ident) => {
+        impl<HW, S> Store<HW> for $lock<S>
+        where
+            HW: HashWriter,
+            S: Store<HW>,
+        {
+            fn contains(&self, id: &[u8]) -> Result<bool> {
+                self.$read().unwrap().contains(id)
+            }
+
+            fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+                self.$read().unwrap().get(id)
+            }
+
+            fn store(&mut self, node: Node<HW>) -> Result<()> {
+                self.get_mut().unwrap().store(node)
+            }
+
+            fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+                self.$read().unwrap().get_many(ids)
+            }
+
+            fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+                self.$read().unwrap().contains_many(ids)
+            }
+
+            #[cfg(feature = "cbor")]
+            fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+                self.$read().unwrap().get_raw(id)
+            }
+
+            #[cfg(feature = "cbor")]
+            fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+                self.get_mut().unwrap().store_encoded(node, encoded)
+            }
+
+            #[cfg(feature = "cbor")]
+            fn store_many_encoded(
+                &mut self,
+                records: Vec<(Node<HW>, Vec<u8>)>,
+                roots: Option<&PersistedRoots>,
+            ) -> Result<()> {
+                self.get_mut().unwrap().store_many_encoded(records, roots)
+            }
+
+            fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+                self.$read().unwrap().get_handle(id)
+            }
+
+            fn store_many<I>(&mut self, nodes: I) -> Result<()>
+            where
+                I: IntoIterator<Item = Node<HW>>,
+            {
+                self.get_mut().unwrap().store_many(nodes)
+            }
+
+            fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+                self.get_mut().unwrap().store_with_roots(node, roots)
+            }
+
+            fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+            where
+                I: IntoIterator<Item = Node<HW>>,
+            {
+                self.get_mut().unwrap().store_many_with_roots(nodes, roots)
+            }
+
+            fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+                self.get_mut().unwrap().persist_roots(roots)
+            }
+
+            fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+                self.$read().unwrap().persisted_roots()
+            }
+
+            fn check_hash_algorithm(&self) -> Result<()> {
+                self.$read().unwrap().check_hash_algorithm()
+            }
+
+            fn delete(&mut self, id: &[u8]) -> Result<()> {
+                self.get_mut().unwrap().delete(id)
+            }
+
+            fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+                self.$read().unwrap().children_of(id)
+            }
+
+            fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+                // The ids can't borrow from the lock guard so they are collected first.
+                let ids = self.$read().unwrap().ids()?.collect::<Vec<_>>();
+                Ok(Box::new(ids.into_iter()))
+            }
+
+            fn len(&self) -> Result<usize> {
+                self.$read().unwrap().len()
+            }
+
+            fn is_empty(&self) -> Result<bool> {
+                self.$read().unwrap().is_empty()
+            }
+
+            fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+                self.$read().unwrap().find_by_prefix(prefix, limit)
+            }
+
+            fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+                self.$read().unwrap().key_length_histogram()
+            }
+
+            #[cfg(feature = "cbor")]
+            fn stats(&self) -> Result<StoreStats> {
+                self.$read().unwrap().stats()
+            }
+
+            fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+                self.get_mut()
+                    .unwrap()
+                    .quarantine_foreign_keys(expected_len)
+            }
+
+            fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+                self.$read().unwrap().get_quarantined(id)
+            }
+
+            fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+                self.$read().unwrap().cached_closure_size(id)
+            }
+
+            fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+                self.get_mut().unwrap().refresh_closure_sizes(batch)
+            }
+
+            fn begin_batch(&mut self) -> Result<()> {
+                self.get_mut().unwrap().begin_batch()
+            }
+
+            fn commit_batch(&mut self) -> Result<()> {
+                self.get_mut().unwrap().commit_batch()
+            }
+
+            fn rollback_batch(&mut self) -> Result<()> {
+                self.get_mut().unwrap().rollback_batch()
+            }
+
+            fn flush(&mut self) -> Result<()> {
+                self.get_mut().unwrap().flush()
+            }
+        }
+
+        impl<HW, S> ConcurrentStore<HW> for $lock<S>
+        where
+            HW: HashWriter,
+            S: Store<HW>,
+        {
+            fn store_shared(&self, node: Node<HW>) -> Result<()> {
+                self.$write().unwrap().store(node)
+            }
+
+            fn flush_shared(&self) -> Result<()> {
+                self.$write().unwrap().flush()
+            }
+        }
+    };
+}
+
+locked_store_impl!(Mutex, lock, lock);
+locked_store_impl!(RwLock, read, write);
+
+impl<HW, S> Store<HW> for Arc<S>
+where
+    HW: HashWriter,
+    S: ConcurrentStore<HW> + ?Sized,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        (**self).contains(id)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        (**self).get(id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.store_shared(node)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flush_shared()
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        (**self).get_many(ids)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        (**self).contains_many(ids)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        (**self).get_raw(id)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        (**self).get_handle(id)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        (**self).persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        (**self).check_hash_algorithm()
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        (**self).children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        (**self).ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        (**self).len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        (**self).is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        (**self).find_by_prefix(prefix, limit)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        (**self).key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        (**self).stats()
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        (**self).get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        (**self).cached_closure_size(id)
+    }
+}
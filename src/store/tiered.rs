@@ -0,0 +1,259 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(feature = "cbor")]
+use super::StoreStats;
+use super::{PersistedRoots, Result, Store};
+use crate::{dag::CachedValue, hash::HashWriter, node::Node};
+
+/// A [Store] that keeps the nodes it reads or writes in a fast front [Store] in front of a
+/// durable back [Store], for example a [BTreeStore](super::BTreeStore) in front of a
+/// `RocksStore`.
+///
+/// Writes go to the back first and then to the front so the front never holds a node the
+/// back lacks. Reads check the front first and copy nodes found only in the back into the
+/// front. The back is the authority for everything else, including ids, counts and
+/// persisted roots. Since the front can't roll back only the back is written inside a
+/// batch and reads don't copy nodes into the front until the batch is over. The front
+/// catches up on the nodes of a committed batch as they are read.
+#[derive(Debug, Default)]
+pub struct TieredStore<Front, Back> {
+    front: Mutex<Front>,
+    back: Back,
+    in_batch: bool,
+}
+
+impl<Front, Back> TieredStore<Front, Back> {
+    /// Put the `front` [Store] in front of the `back` [Store].
+    pub fn new(front: Front, back: Back) -> Self {
+        Self {
+            front: Mutex::new(front),
+            back,
+            in_batch: false,
+        }
+    }
+
+    /// Get the front [Store].
+    pub fn front(&self) -> MutexGuard<'_, Front> {
+        self.front.lock().unwrap()
+    }
+
+    /// Get a reference to the back [Store].
+    pub fn back(&self) -> &Back {
+        &self.back
+    }
+}
+
+impl<Front, Back> TieredStore<Front, Back> {
+    // Writes nodes just written to the back to the front unless a batch is open.
+    fn store_front<HW>(&mut self, nodes: Vec<Node<HW>>) -> Result<()>
+    where
+        HW: HashWriter,
+        Front: Store<HW>,
+    {
+        if self.in_batch {
+            return Ok(());
+        }
+        self.front.get_mut().unwrap().store_many(nodes)
+    }
+}
+
+impl<HW, Front, Back> Store<HW> for TieredStore<Front, Back>
+where
+    HW: HashWriter,
+    Front: Store<HW>,
+    Back: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        Ok(self.front().contains(id)? || self.back.contains(id)?)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        let mut front = self.front();
+        if let Some(node) = front.get(id)? {
+            return Ok(Some(node));
+        }
+        let node = self.back.get(id)?;
+        if let Some(node) = node.as_ref().filter(|_| !self.in_batch) {
+            front.store(node.clone())?;
+        }
+        Ok(node)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.back.store(node.clone())?;
+        self.store_front(vec![node])
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        let mut front = self.front();
+        let mut nodes = front.get_many(ids)?;
+        let misses: Vec<usize> = (0..ids.len()).filter(|idx| nodes[*idx].is_none()).collect();
+        if misses.is_empty() {
+            return Ok(nodes);
+        }
+        let missing: Vec<&[u8]> = misses.iter().map(|idx| ids[*idx]).collect();
+        let fetched = self.back.get_many(&missing)?;
+        for (idx, node) in misses.into_iter().zip(fetched) {
+            if let Some(node) = node.as_ref().filter(|_| !self.in_batch) {
+                front.store(node.clone())?;
+            }
+            nodes[idx] = node;
+        }
+        Ok(nodes)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.back.get_raw(id)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        self.back.store_encoded(node.clone(), encoded)?;
+        self.store_front(vec![node])
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        let nodes = records.iter().map(|(node, _)| node.clone()).collect();
+        self.back.store_many_encoded(records, roots)?;
+        self.store_front(nodes)
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        self.back.store_many(nodes.clone())?;
+        self.store_front(nodes)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.back.store_with_roots(node.clone(), roots)?;
+        self.store_front(vec![node])
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        self.back.store_many_with_roots(nodes.clone(), roots)?;
+        self.store_front(nodes)
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.back.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.back.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.front().check_hash_algorithm()?;
+        self.back.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.back.delete(id)?;
+        self.front.get_mut().unwrap().delete(id)
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.back.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.back.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.back.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.back.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.back.find_by_prefix(prefix, limit)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.back.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.back.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        let moved = self.back.quarantine_foreign_keys(expected_len)?;
+        // Fronts like the BTreeStore can't quarantine so the foreign nodes are dropped.
+        let front = self.front.get_mut().unwrap();
+        let foreign = front
+            .ids()?
+            .filter(|id| id.as_ref().map_or(true, |id| id.len() != expected_len))
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+        for id in foreign {
+            front.delete(&id)?;
+        }
+        Ok(moved)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.back.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.back.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.back.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.back.begin_batch()?;
+        self.in_batch = true;
+        Ok(())
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.back.commit_batch()?;
+        self.in_batch = false;
+        Ok(())
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.back.rollback_batch()?;
+        self.in_batch = false;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.back.flush()?;
+        self.front.get_mut().unwrap().flush()
+    }
+}
@@ -0,0 +1,326 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::Peekable;
+
+use super::{PersistedRoots, Result, Store, StoreError};
+use crate::{dag::NodeHandle, hash::HashWriter, node::Node};
+
+type IdIter<'a> = Peekable<Box<dyn Iterator<Item = Result<Vec<u8>>> + 'a>>;
+
+/// A [Store] that reads from an ordered list of stores, for example while nodes are being
+/// migrated from one backend to another. Reads are answered by the first store that has the
+/// id so dependencies stored in any of the stores satisfy
+/// [Merkle::add_node](crate::dag::Merkle::add_node). Writes go to the designated primary
+/// store.
+///
+/// An id stored in more than one store is expected to hold the same [Node] everywhere, which
+/// content addressing guarantees for stores of the same DAG. Ids are only listed once.
+///
+/// Batches are run by the primary. Deletes from the other stores are held back until the
+/// batch commits and the ids they remove are hidden from reads in the meantime.
+pub struct UnionStore<HW> {
+    stores: Vec<Box<dyn Store<HW>>>,
+    primary: Option<usize>,
+    deferred_deletes: Option<BTreeSet<Vec<u8>>>,
+}
+
+impl<HW> Default for UnionStore<HW> {
+    fn default() -> Self {
+        Self {
+            stores: Vec::new(),
+            primary: None,
+            deferred_deletes: None,
+        }
+    }
+}
+
+impl<HW> UnionStore<HW>
+where
+    HW: HashWriter + 'static,
+{
+    /// Construct a union without stores. Add them in read order with
+    /// [UnionStore::with_primary] and [UnionStore::with_fallback].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the `store` that receives every write. It replaces an earlier primary which
+    /// is still read from.
+    pub fn with_primary<S: Store<HW> + 'static>(mut self, store: S) -> Self {
+        self.primary = Some(self.stores.len());
+        self.stores.push(Box::new(store));
+        self
+    }
+
+    /// Add a `store` that is only read from.
+    pub fn with_fallback<S: Store<HW> + 'static>(mut self, store: S) -> Self {
+        self.stores.push(Box::new(store));
+        self
+    }
+
+    /// The stores in read order.
+    pub fn stores(&self) -> &[Box<dyn Store<HW>>] {
+        &self.stores
+    }
+
+    /// The store that receives every write if there is one.
+    pub fn primary(&self) -> Option<&dyn Store<HW>> {
+        self.primary.map(|idx| self.stores[idx].as_ref())
+    }
+
+    // Writes fail with StoreError::ReadOnly when there is no primary.
+    fn primary_mut(&mut self, operation: &'static str) -> Result<&mut dyn Store<HW>> {
+        match self.primary {
+            Some(idx) => Ok(self.stores[idx].as_mut()),
+            None => Err(StoreError::ReadOnly(operation)),
+        }
+    }
+
+    // Whether the id was deleted by the open batch.
+    fn hidden(&self, id: &[u8]) -> bool {
+        self.deferred_deletes
+            .as_ref()
+            .is_some_and(|ids| ids.contains(id))
+    }
+
+    // The stores to read the id from.
+    fn readers(&self, id: &[u8]) -> &[Box<dyn Store<HW>>] {
+        if self.hidden(id) {
+            &[]
+        } else {
+            &self.stores
+        }
+    }
+}
+
+impl<HW> Store<HW> for UnionStore<HW>
+where
+    HW: HashWriter + 'static,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        for store in self.readers(id) {
+            if store.contains(id)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        for store in self.readers(id) {
+            if let Some(node) = store.get(id)? {
+                return Ok(Some(node));
+            }
+        }
+        Ok(None)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.primary_mut("store")?.store(node)
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        let mut nodes: Vec<Option<Node<HW>>> = vec![None; ids.len()];
+        let mut misses: Vec<usize> = (0..ids.len())
+            .filter(|idx| !self.hidden(ids[*idx]))
+            .collect();
+        for store in self.stores.iter() {
+            if misses.is_empty() {
+                break;
+            }
+            let missing: Vec<&[u8]> = misses.iter().map(|idx| ids[*idx]).collect();
+            let fetched = store.get_many(&missing)?;
+            let mut still_missing = Vec::new();
+            for (idx, node) in misses.into_iter().zip(fetched) {
+                match node {
+                    Some(node) => nodes[idx] = Some(node),
+                    None => still_missing.push(idx),
+                }
+            }
+            misses = still_missing;
+        }
+        Ok(nodes)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        for store in self.readers(id) {
+            if let Some(bytes) = store.get_raw(id)? {
+                return Ok(Some(bytes));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        for store in self.readers(id) {
+            if let Some(handle) = store.get_handle(id)? {
+                return Ok(Some(handle));
+            }
+        }
+        Ok(None)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        self.primary_mut("store_encoded")?
+            .store_encoded(node, encoded)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        self.primary_mut("store_many_encoded")?
+            .store_many_encoded(records, roots)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.primary_mut("store_with_roots")?
+            .store_with_roots(node, roots)
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.primary_mut("persist_roots")?.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        match self.primary() {
+            Some(primary) => primary.persisted_roots(),
+            None => Err(StoreError::Unsupported("persisted_roots")),
+        }
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        for store in self.stores.iter() {
+            store.check_hash_algorithm()?;
+        }
+        Ok(())
+    }
+
+    // Deletes the id from every store that has it. Inside a batch only the primary deletes
+    // it right away.
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        if self.deferred_deletes.is_some() {
+            let primary = self.primary_mut("delete")?;
+            if primary.contains(id)? {
+                primary.delete(id)?;
+            }
+            if let Some(ids) = self.deferred_deletes.as_mut() {
+                ids.insert(id.to_vec());
+            }
+            return Ok(());
+        }
+        for store in self.stores.iter_mut() {
+            if store.contains(id)? {
+                store.delete(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        let mut children = BTreeSet::new();
+        for store in self.stores.iter() {
+            children.extend(store.children_of(id)?);
+        }
+        children.retain(|child| !self.hidden(child));
+        Ok(children)
+    }
+
+    // Merges the ascending ids of every store listing an id held by several stores once.
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        let mut iters: Vec<IdIter<'_>> = Vec::with_capacity(self.stores.len());
+        for store in self.stores.iter() {
+            iters.push(store.ids()?.peekable());
+        }
+        Ok(Box::new(
+            std::iter::from_fn(move || {
+                let mut smallest: Option<Vec<u8>> = None;
+                for iter in iters.iter_mut() {
+                    match iter.peek() {
+                        Some(Err(_)) => return iter.next(),
+                        Some(Ok(id)) if smallest.as_ref().is_none_or(|smallest| id < smallest) => {
+                            smallest = Some(id.clone());
+                        }
+                        _ => (),
+                    }
+                }
+                let smallest = smallest?;
+                for iter in iters.iter_mut() {
+                    iter.next_if(|id| matches!(id, Ok(id) if *id == smallest));
+                }
+                Some(Ok(smallest))
+            })
+            .filter(|id| !matches!(id, Ok(id) if self.hidden(id))),
+        ))
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        let mut ids = BTreeSet::new();
+        for store in self.stores.iter() {
+            ids.extend(store.find_by_prefix(prefix, limit)?);
+        }
+        Ok(ids
+            .into_iter()
+            .filter(|id| !self.hidden(id))
+            .take(limit)
+            .collect())
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        let mut histogram = BTreeMap::new();
+        for id in self.ids()? {
+            *histogram.entry(id?.len()).or_default() += 1;
+        }
+        Ok(histogram)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.primary_mut("begin_batch")?.begin_batch()?;
+        self.deferred_deletes = Some(BTreeSet::new());
+        Ok(())
+    }
+
+    // Deletes the ids held back by the batch from the other stores once the primary has
+    // committed it.
+    fn commit_batch(&mut self) -> Result<()> {
+        self.primary_mut("commit_batch")?.commit_batch()?;
+        let primary = self.primary;
+        for id in self.deferred_deletes.take().unwrap_or_default() {
+            for (idx, store) in self.stores.iter_mut().enumerate() {
+                if Some(idx) != primary && store.contains(&id)? {
+                    store.delete(&id)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.primary_mut("rollback_batch")?.rollback_batch()?;
+        self.deferred_deletes = None;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for store in self.stores.iter_mut() {
+            store.flush()?;
+        }
+        Ok(())
+    }
+}
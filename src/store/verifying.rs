@@ -0,0 +1,225 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+use std::collections::{BTreeMap, BTreeSet};
+use std::marker::PhantomData;
+
+#[cfg(feature = "cbor")]
+use super::{codec, StoreStats};
+use super::{PersistedRoots, Result, Store, StoreError};
+use crate::{
+    dag::{CachedValue, NodeHandle},
+    hash::HashWriter,
+    node::{Node, NodeIntegrityError},
+};
+
+// Hashes the node read under the `id` again failing with StoreError::CorruptNode if it no longer
+// matches. A detached node has no item to hash so it is refused with StoreError::InvalidNode.
+pub(crate) fn verify<HW: HashWriter>(id: &[u8], node: Node<HW>) -> Result<Node<HW>> {
+    match node.validate_against(id) {
+        Ok(()) => Ok(node),
+        Err(err @ NodeIntegrityError::DetachedItem { .. }) => Err(StoreError::InvalidNode(err)),
+        Err(_) => Err(StoreError::CorruptNode { id: id.to_vec() }),
+    }
+}
+
+/// A [Store] wrapper that hashes every [Node] it reads again and fails with
+/// [StoreError::CorruptNode] if the payload and dependencies no longer hash to the id it was
+/// read under. Catches records damaged at rest or written under the wrong key at the cost of
+/// hashing every payload read. [Detached](Node::is_detached) nodes can't be checked without
+/// their items so reading one fails with [StoreError::InvalidNode].
+///
+/// Handles are built from the verified nodes so reading a handle reads the whole node even if
+/// the wrapped [Store] could read its structure alone.
+#[derive(Debug, Default)]
+pub struct VerifyingStore<S, HW> {
+    inner: S,
+    _phantom: PhantomData<HW>,
+}
+
+impl<S, HW> VerifyingStore<S, HW>
+where
+    HW: HashWriter,
+{
+    /// Wrap a [Store] verifying the nodes read from it.
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Unwrap the wrapped [Store].
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<HW, S> Store<HW> for VerifyingStore<S, HW>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        self.inner.contains(id)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        self.inner.contains_many(ids)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        self.inner.get(id)?.map(|node| verify(id, node)).transpose()
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.inner.store(node)
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        let nodes = self.inner.get_many(ids)?;
+        ids.iter()
+            .zip(nodes)
+            .map(|(id, node)| node.map(|node| verify(id, node)).transpose())
+            .collect()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.inner.get_raw(id)? {
+            Some(encoded) => {
+                verify::<HW>(id, codec::decode(&encoded)?)?;
+                Ok(Some(encoded))
+            }
+            None => Ok(None),
+        }
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        self.inner.store_encoded(node, encoded)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        self.inner.store_many_encoded(records, roots)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        Ok(self.get(id)?.as_ref().map(NodeHandle::from))
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.inner.store_many(nodes)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.inner.store_with_roots(node, roots)
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        self.inner.store_many_with_roots(nodes, roots)
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.inner.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.inner.delete(id)
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.inner.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        self.inner.quarantine_foreign_keys(expected_len)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.inner.begin_batch()
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.inner.commit_batch()
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.inner.rollback_batch()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
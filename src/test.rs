@@ -11,28 +11,122 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, BTreeSet};
+#[cfg(feature = "cbor")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "cbor")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::clock::{Clock, SimClock};
+use crate::id::hex;
+use crate::payload_index::{
+    NgramIndexer, PayloadIndexStore, PayloadIndexer, PayloadSearch, SearchMode, WhitespaceIndexer,
+};
 use crate::prelude::*;
+use crate::store::{
+    BTreeStore, BlobStore, CachedStore, ConcurrentStore, DetachedStore, MirrorOp, MirrorPolicy,
+    MirroredStore, ReadOnlyStore, ReverseIndexStore, Store, StoreError, StoreErrorKind,
+    TieredStore, UnionStore,
+};
+
+// The key of the hasher the tests use unless they check a particular one.
+pub(crate) struct TestKey;
+
+impl HashKey for TestKey {
+    fn key() -> [u8; 32] {
+        *b"merkle-dag tests keep no secrets"
+    }
+}
+
+pub(crate) type TestHasher = SipHash24<TestKey>;
+
+#[cfg(feature = "cbor")]
+static NEXT_TEMP_PATH: AtomicUsize = AtomicUsize::new(0);
+
+// A path in the temporary directory no other test uses. Whatever the test left there, a file
+// or a directory, is removed when it is dropped so a failing test doesn't leak it. Every
+// store that writes to disk needs the `cbor` feature.
+#[cfg(feature = "cbor")]
+pub(crate) struct TempPath(PathBuf);
+
+#[cfg(feature = "cbor")]
+pub(crate) fn temp_path(name: &str) -> TempPath {
+    let path = std::env::temp_dir().join(format!(
+        "merkle-dag-{}-{}-{}",
+        std::process::id(),
+        NEXT_TEMP_PATH.fetch_add(1, Ordering::SeqCst),
+        name
+    ));
+    let path = TempPath(path);
+    path.remove();
+    path
+}
+
+#[cfg(feature = "cbor")]
+impl TempPath {
+    // Leaves the path behind for a store that outlives the fixture opening it.
+    pub(crate) fn keep(self) -> PathBuf {
+        let path = self.0.clone();
+        std::mem::forget(self);
+        path
+    }
+
+    fn remove(&self) {
+        if self.0.is_dir() {
+            let _ = std::fs::remove_dir_all(&self.0);
+        } else {
+            let _ = std::fs::remove_file(&self.0);
+        }
+        // The write ahead log files sqlite keeps next to a database.
+        for suffix in ["-wal", "-shm"] {
+            let mut sidecar = self.0.clone().into_os_string();
+            sidecar.push(suffix);
+            let _ = std::fs::remove_file(sidecar);
+        }
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl std::ops::Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+type TestDag<'a> = Merkle<BTreeMap<Vec<u8>, Node<TestHasher>>, TestHasher>;
 
-type TestDag<'a> = Merkle<
-    BTreeMap<Vec<u8>, Node<std::collections::hash_map::DefaultHasher>>,
-    std::collections::hash_map::DefaultHasher,
->;
+type IndexedTestDag = Merkle<ReverseIndexStore<BTreeStore<TestHasher>>, TestHasher>;
 
 #[test]
 fn test_root_pointer_hygiene() {
-    let mut dag = TestDag::new(BTreeMap::new());
-    let quax_node_id = dag.add_node("quax", BTreeSet::new()).unwrap();
+    let (mut dag, ids) = TestDag::from_text(r#"quax: "quax""#).unwrap();
+    let quax_node_id = ids["quax"].clone();
     assert_eq!(
         quax_node_id,
         *dag.get_node_by_id(&quax_node_id).unwrap().unwrap().id()
     );
     assert!(dag.get_roots().contains(&quax_node_id));
-    let mut dep_set = BTreeSet::new();
-    dep_set.insert(quax_node_id.clone());
-    let quux_node_id = dag.add_node("quux", dep_set).unwrap();
+    let quux_node_id = dag
+        .add_node("quux", BTreeSet::from([quax_node_id.clone()]))
+        .unwrap();
     assert!(!dag.get_roots().contains(&quax_node_id));
     assert!(dag.get_roots().contains(&quux_node_id));
     assert_eq!(
@@ -41,21 +135,30 @@ fn test_root_pointer_hygiene() {
     );
 }
 
-#[test]
-fn test_insert_no_such_dependents_error() {
-    let missing_dependent =
-        Node::<DefaultHasher>::new("missing".as_bytes().to_vec(), BTreeSet::new());
-    let mut dag = TestDag::new(BTreeMap::new());
+// Checks that a node with a missing dependency is rejected without touching the DAG.
+fn check_insert_no_such_dependents_error<S: Store<TestHasher>>(store: S) {
+    let missing_dependent = Node::<TestHasher>::new("missing".as_bytes().to_vec(), BTreeSet::new());
+    let mut dag = Merkle::<S, TestHasher>::new(store);
     let mut dep_set = BTreeSet::new();
     dep_set.insert(missing_dependent.id().to_vec());
     assert!(dag.add_node("foo", dep_set).is_err());
     assert!(dag.get_roots().is_empty());
-    assert!(dag.get_nodes().is_empty());
+    assert!(Store::<TestHasher>::is_empty(dag.get_nodes()).unwrap());
 }
 
 #[test]
-fn test_adding_nodes_is_idempotent() {
-    let mut dag = TestDag::new(BTreeMap::new());
+fn test_insert_no_such_dependents_error() {
+    check_insert_no_such_dependents_error(BTreeStore::new());
+}
+
+#[test]
+fn test_hash_store_insert_no_such_dependents_error() {
+    check_insert_no_such_dependents_error(HashStore::new());
+}
+
+// Checks that adding the same node twice leaves the roots and the node count unchanged.
+fn check_adding_nodes_is_idempotent<S: Store<TestHasher>>(store: S) {
+    let mut dag = Merkle::<S, TestHasher>::new(store);
     let quax_node_id = dag.add_node("quax", BTreeSet::new()).unwrap();
     assert_eq!(
         quax_node_id,
@@ -63,10 +166,32 @@ fn test_adding_nodes_is_idempotent() {
     );
     assert!(dag.get_roots().contains(&quax_node_id));
     let root_size = dag.get_roots().len();
-    let nodes_size = dag.get_nodes().len();
+    let nodes_size = dag.node_count().unwrap();
     dag.add_node("quax", BTreeSet::new()).unwrap();
     assert_eq!(root_size, dag.get_roots().len());
-    assert_eq!(nodes_size, dag.get_nodes().len());
+    assert_eq!(nodes_size, dag.node_count().unwrap());
+}
+
+#[test]
+fn test_adding_nodes_is_idempotent() {
+    check_adding_nodes_is_idempotent(BTreeStore::new());
+}
+
+#[test]
+fn test_hash_store_adding_nodes_is_idempotent() {
+    check_adding_nodes_is_idempotent(HashStore::new());
+}
+
+#[test]
+fn test_hash_store_dag_default() {
+    let mut dag = Merkle::<HashStore<TestHasher>, TestHasher>::default();
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm = dag
+        .add_node("qualm", BTreeSet::from([quake.clone()]))
+        .unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([qualm.clone()]));
+    assert_eq!(dag.node_count().unwrap(), 2);
+    assert_eq!(dag.compare(&quake, &qualm).unwrap(), NodeCompare::Before);
 }
 
 #[test]
@@ -82,7 +207,7 @@ fn test_adding_nodes_is_idempotent_regardless_of_dep_order() {
     ]);
     dag.add_node("foo", dep_ids).unwrap();
     let root_size = dag.get_roots().len();
-    let nodes_size = dag.get_nodes().len();
+    let nodes_size = dag.node_count().unwrap();
 
     let dep_ids = BTreeSet::from([
         quell_node_id.clone(),
@@ -91,7 +216,7 @@ fn test_adding_nodes_is_idempotent_regardless_of_dep_order() {
     ]);
     dag.add_node("foo", dep_ids).unwrap();
     assert_eq!(root_size, dag.get_roots().len());
-    assert_eq!(nodes_size, dag.get_nodes().len());
+    assert_eq!(nodes_size, dag.node_count().unwrap());
 
     let dep_ids = BTreeSet::from([
         qualm_node_id.clone(),
@@ -100,207 +225,9173 @@ fn test_adding_nodes_is_idempotent_regardless_of_dep_order() {
     ]);
     dag.add_node("foo", dep_ids).unwrap();
     assert_eq!(root_size, dag.get_roots().len());
-    assert_eq!(nodes_size, dag.get_nodes().len());
+    assert_eq!(nodes_size, dag.node_count().unwrap());
 }
 
 #[test]
 fn test_node_comparison_equivalent() {
-    let mut dag = TestDag::new(BTreeMap::new());
-    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let (dag, ids) = TestDag::from_text(r#"quake: "quake""#).unwrap();
     assert_eq!(
-        dag.compare(&quake_node_id, &quake_node_id).unwrap(),
+        dag.compare(&ids["quake"], &ids["quake"]).unwrap(),
         NodeCompare::Equivalent
     );
 }
 
+const QUAKE_CHAIN: &str = r#"
+quake: "quake"
+qualm(quake): "qualm"
+quell(qualm): "quell"
+"#;
+
 #[test]
 fn test_node_comparison_before() {
-    let mut dag = TestDag::new(BTreeMap::new());
-    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
-    let qualm_node_id = dag
-        .add_node("qualm", BTreeSet::from([quake_node_id.clone()]))
-        .unwrap();
-    let quell_node_id = dag
-        .add_node("quell", BTreeSet::from([qualm_node_id.clone()]))
-        .unwrap();
+    let (dag, ids) = TestDag::from_text(QUAKE_CHAIN).unwrap();
     assert_eq!(
-        dag.compare(&quake_node_id, &qualm_node_id).unwrap(),
+        dag.compare(&ids["quake"], &ids["qualm"]).unwrap(),
         NodeCompare::Before
     );
     assert_eq!(
-        dag.compare(&quake_node_id, &quell_node_id).unwrap(),
+        dag.compare(&ids["quake"], &ids["quell"]).unwrap(),
         NodeCompare::Before
     );
 }
 
 #[test]
 fn test_node_comparison_after() {
-    let mut dag = TestDag::new(BTreeMap::new());
-    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
-    let qualm_node_id = dag
-        .add_node("qualm", BTreeSet::from([quake_node_id.clone()]))
-        .unwrap();
-    let quell_node_id = dag
-        .add_node("quell", BTreeSet::from([qualm_node_id.clone()]))
-        .unwrap();
+    let (dag, ids) = TestDag::from_text(QUAKE_CHAIN).unwrap();
     assert_eq!(
-        dag.compare(&qualm_node_id, &quake_node_id).unwrap(),
+        dag.compare(&ids["qualm"], &ids["quake"]).unwrap(),
         NodeCompare::After
     );
     assert_eq!(
-        dag.compare(&quell_node_id, &quake_node_id).unwrap(),
+        dag.compare(&ids["quell"], &ids["quake"]).unwrap(),
         NodeCompare::After
     );
 }
 
 #[test]
 fn test_node_comparison_no_shared_graph() {
-    let mut dag = TestDag::new(BTreeMap::new());
-    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
-    let qualm_node_id = dag.add_node("qualm", BTreeSet::new()).unwrap();
-    let quell_node_id = dag.add_node("quell", BTreeSet::new()).unwrap();
+    let (dag, ids) = TestDag::from_text(
+        r#"
+        quake: "quake"
+        qualm: "qualm"
+        quell: "quell"
+        "#,
+    )
+    .unwrap();
     assert_eq!(
-        dag.compare(&qualm_node_id, &quake_node_id).unwrap(),
+        dag.compare(&ids["qualm"], &ids["quake"]).unwrap(),
         NodeCompare::Uncomparable
     );
     assert_eq!(
-        dag.compare(&quell_node_id, &quake_node_id).unwrap(),
+        dag.compare(&ids["quell"], &ids["quake"]).unwrap(),
         NodeCompare::Uncomparable
     );
     assert_eq!(
-        dag.compare(&quell_node_id, &qualm_node_id).unwrap(),
+        dag.compare(&ids["quell"], &ids["qualm"]).unwrap(),
         NodeCompare::Uncomparable
     );
 }
 
-#[test]
-fn test_find_next_missing_nodes_disjoint_graphs_no_deps() {
-    let mut dag1 = TestDag::new(BTreeMap::new());
-    let mut dag2 = TestDag::new(BTreeMap::new());
-    let quake_node_id = dag1.add_node("quake", BTreeSet::new()).unwrap();
-    let qualm_node_id = dag1.add_node("qualm", BTreeSet::new()).unwrap();
-    dag2.add_node("quell", BTreeSet::new()).unwrap();
+fn missing_node_ids(dag1: &str, dag2: &str) -> (BTreeSet<Vec<u8>>, BTreeMap<String, Vec<u8>>) {
+    let (dag1, ids) = TestDag::from_text(dag1).unwrap();
+    let (dag2, _) = TestDag::from_text(dag2).unwrap();
     let missing_nodes = dag1
         .find_next_non_descendant_nodes(dag2.get_roots())
         .unwrap();
-    assert_eq!(missing_nodes.len(), 2);
-    let mut found_quake = false;
-    let mut found_qualm = false;
-    for node in missing_nodes {
-        if node.id().to_owned() == quake_node_id {
-            found_quake = true;
-        }
-        if node.id().to_owned() == qualm_node_id {
-            found_qualm = true;
-        }
-    }
-    assert!(found_quake);
-    assert!(found_qualm);
+    let missing_ids = missing_nodes.iter().map(|n| n.id().to_vec()).collect();
+    (missing_ids, ids)
+}
+
+#[test]
+fn test_find_next_missing_nodes_disjoint_graphs_no_deps() {
+    let (missing, ids) = missing_node_ids(
+        r#"
+        quake: "quake"
+        qualm: "qualm"
+        "#,
+        r#"quell: "quell""#,
+    );
+    assert_eq!(
+        missing,
+        BTreeSet::from([ids["quake"].clone(), ids["qualm"].clone()])
+    );
 }
 
 #[test]
 fn test_find_next_missing_nodes_sub_graphs_one_degree_off() {
-    let mut dag1 = TestDag::new(BTreeMap::new());
-    let mut dag2 = TestDag::new(BTreeMap::new());
-    dag1.add_node("quake", BTreeSet::new()).unwrap();
-    let quake_node_id = dag2.add_node("quake", BTreeSet::new()).unwrap();
+    let (missing, ids) = missing_node_ids(
+        r#"
+        quake: "quake"
+        qualm(quake): "qualm"
+        "#,
+        r#"quake: "quake""#,
+    );
+    assert_eq!(missing, BTreeSet::from([ids["qualm"].clone()]));
+}
+
+#[test]
+fn test_find_next_missing_nodes_sub_graphs_two_degree_off() {
+    let (missing, ids) = missing_node_ids(
+        r#"
+        quake: "quake"
+        qualm(quake): "qualm"
+        quell(quake, qualm): "quell"
+        "#,
+        r#"quake: "quake""#,
+    );
+    assert_eq!(
+        missing,
+        BTreeSet::from([ids["qualm"].clone(), ids["quell"].clone()])
+    );
+}
 
-    let mut deps = BTreeSet::new();
-    deps.insert(quake_node_id);
-    let qualm_node_id = dag1.add_node("qualm", deps).unwrap();
+#[test]
+fn test_missing_batches_follow_dependencies_and_terminate() {
+    let (dag, ids) = TestDag::from_text(
+        r#"
+        a: "a"
+        b(a): "b"
+        y: "y"
+        z(y): "z"
+        w(z): "w"
+        d(b, w): "d"
+        "#,
+    )
+    .unwrap();
+    let names: BTreeMap<Vec<u8>, String> =
+        ids.iter().map(|(n, id)| (id.clone(), n.clone())).collect();
+    let batches: Vec<BTreeSet<String>> = dag
+        .missing(BTreeSet::from([ids["a"].clone()]))
+        .map(|batch| {
+            batch
+                .unwrap()
+                .iter()
+                .map(|n| names[n.id()].clone())
+                .collect()
+        })
+        .collect();
+    let expected: Vec<BTreeSet<String>> = vec![
+        BTreeSet::from(["b".into(), "y".into()]),
+        BTreeSet::from(["z".into()]),
+        BTreeSet::from(["w".into()]),
+        BTreeSet::from(["d".into()]),
+    ];
+    assert_eq!(batches, expected);
+    assert_eq!(dag.missing(dag.get_roots().clone()).count(), 0);
+    assert_eq!(dag.missing(BTreeSet::new()).flatten().flatten().count(), 6);
+}
 
-    let missing_nodes = dag1
-        .find_next_non_descendant_nodes(dag2.get_roots())
-        .unwrap();
-    assert_eq!(missing_nodes.len(), 1);
-    let mut found_qualm = false;
-    for node in missing_nodes {
-        if node.id().to_owned() == qualm_node_id {
-            found_qualm = true;
-        }
+fn spec_error(
+    result: crate::store::Result<(TestDag<'static>, BTreeMap<String, Vec<u8>>)>,
+) -> (usize, usize, String) {
+    match result {
+        Err(StoreError::SpecParse {
+            line,
+            column,
+            message,
+        }) => (line, column, message),
+        Err(err) => panic!("expected SpecParse got {:?}", err),
+        Ok(_) => panic!("expected SpecParse got a DAG"),
     }
-    assert!(found_qualm);
 }
 
 #[test]
-fn test_find_next_missing_nodes_sub_graphs_two_degree_off() {
-    let mut dag1 = TestDag::new(BTreeMap::new());
-    let mut dag2 = TestDag::new(BTreeMap::new());
-    dag1.add_node("quake", BTreeSet::new()).unwrap();
-    let quake_node_id = dag2.add_node("quake", BTreeSet::new()).unwrap();
+fn test_text_round_trip() {
+    let spec = r#"
+        # The merge is described before its dependencies.
+        merge(left, right): "merge \"quoted\""
+        left(base): "left\n"
+        right(base): "right\x00\xff"
+        base: "base"
+        other: "disconnected"
+        "#;
+    let (dag, ids) = TestDag::from_text(spec).unwrap();
+    let names: BTreeMap<Vec<u8>, String> =
+        ids.iter().map(|(n, id)| (id.clone(), n.clone())).collect();
+    let text = dag.to_text(|n| names[n.id()].clone()).unwrap();
+    assert_eq!(
+        text,
+        r#"base: "base"
+left(base): "left\n"
+other: "disconnected"
+right(base): "right\0\xff"
+merge(left, right): "merge \"quoted\""
+"#
+    );
+    let (round_trip, round_trip_ids) = TestDag::from_text(&text).unwrap();
+    assert_eq!(round_trip_ids, ids);
+    assert_eq!(round_trip.get_roots(), dag.get_roots());
+    assert_eq!(round_trip.to_text(|n| names[n.id()].clone()).unwrap(), text);
+}
 
-    let mut deps = BTreeSet::new();
-    deps.insert(quake_node_id.clone());
-    let qualm_node_id = dag1.add_node("qualm", deps).unwrap();
+#[test]
+fn test_text_rejects_cycles() {
+    let (line, column, message) = spec_error(TestDag::from_text(
+        "a: \"a\"\nb(a, d): \"b\"\nc(b): \"c\"\nd(c): \"d\"\n",
+    ));
+    assert_eq!((line, column), (2, 1));
+    assert_eq!(
+        message,
+        "dependency cycle b (line 2) -> d (line 4) -> c (line 3) -> b (line 2)"
+    );
+    let (line, _, message) = spec_error(TestDag::from_text("a(a): \"a\""));
+    assert_eq!(line, 1);
+    assert_eq!(message, "dependency cycle a (line 1) -> a (line 1)");
+}
 
-    deps = BTreeSet::new();
-    deps.insert(quake_node_id.clone());
-    deps.insert(qualm_node_id.clone());
-    let quell_node_id = dag1.add_node("quell", deps).unwrap();
+#[test]
+fn test_text_rejects_duplicate_and_unknown_names() {
+    assert_eq!(
+        spec_error(TestDag::from_text("a: \"a\"\n\n  a: \"b\"")),
+        (3, 3, "node 'a' is already described on line 1".to_owned())
+    );
+    assert_eq!(
+        spec_error(TestDag::from_text("a: \"a\"\nb(a,  c): \"b\"")),
+        (2, 7, "unknown dependency 'c' of node 'b'".to_owned())
+    );
+    assert_eq!(
+        spec_error(TestDag::from_text(r#"a: "a\q""#)),
+        (1, 6, "unknown escape '\\q'".to_owned())
+    );
+    assert_eq!(
+        spec_error(TestDag::from_text("a b: \"a\"")),
+        (1, 3, "expected ':' but found 'b'".to_owned())
+    );
+    let (dag, _) = TestDag::from_text("a: \"a\"\nb: \"b\"").unwrap();
+    match dag.to_text(|_| "same".to_owned()) {
+        Err(StoreError::SpecParse { line: 2, .. }) => (),
+        result => panic!("expected SpecParse on line 2 got {:?}", result),
+    }
+}
 
-    let missing_nodes = dag1
-        .find_next_non_descendant_nodes(dag2.get_roots())
+#[test]
+fn test_retain_marked_keeps_sticky_heads_announced() {
+    let (mut dag, ids) = TestDag::from_text(
+        r#"
+        release: "release"
+        next(release): "next"
+        "#,
+    )
+    .unwrap();
+    dag.set_root_policy(Arc::new(RetainMarked));
+    dag.mark_sticky_root(&ids["next"]).unwrap();
+    let head = dag
+        .add_node("head", BTreeSet::from([ids["next"].clone()]))
         .unwrap();
-    assert_eq!(missing_nodes.len(), 2);
-    let mut found_qualm = false;
-    let mut found_quell = false;
-    for node in missing_nodes {
-        if node.id().to_owned() == qualm_node_id {
-            found_qualm = true;
-        }
-        if node.id().to_owned() == quell_node_id {
-            found_quell = true;
-        }
+    assert_eq!(dag.frontier_roots(), &BTreeSet::from([head.clone()]));
+    assert_eq!(
+        dag.announced_roots(),
+        BTreeSet::from([head.clone(), ids["next"].clone()])
+    );
+    assert_eq!(
+        dag.compare(&head, &ids["next"]).unwrap(),
+        NodeCompare::After
+    );
+    let missing = dag
+        .find_next_non_descendant_nodes(&BTreeSet::from([ids["release"].clone()]))
+        .unwrap();
+    assert_eq!(missing.len(), 1);
+    assert_eq!(missing[0].id(), ids["next"].as_slice());
+
+    dag.set_root_policy(Arc::new(StrictFrontier));
+    assert_eq!(dag.announced_roots(), BTreeSet::from([head.clone()]));
+    dag.set_root_policy(Arc::new(RetainMarked));
+    assert!(dag.unmark_sticky_root(&ids["next"]).unwrap());
+    assert!(!dag.unmark_sticky_root(&ids["next"]).unwrap());
+    assert_eq!(dag.announced_roots(), BTreeSet::from([head]));
+    assert!(dag.sticky_roots().is_empty());
+}
+
+#[test]
+fn test_mark_sticky_root_requires_node() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    match dag.mark_sticky_root(b"missing") {
+        Err(StoreError::NoSuchNode(id)) => assert_eq!(id, b"missing".to_vec()),
+        result => panic!("expected NoSuchNode got {:?}", result),
     }
-    assert!(found_qualm);
-    assert!(found_quell);
+    assert!(dag.sticky_roots().is_empty());
 }
 
-#[cfg(feature = "cbor")]
-mod cbor_serialization_tests {
-    use super::TestDag;
-    use crate::prelude::*;
-    use ciborium::{de::from_reader, ser::into_writer};
-    use std::collections::BTreeMap;
-    use std::collections::{hash_map::DefaultHasher, BTreeSet};
+#[test]
+fn test_reachable_in_both_directions() {
+    let mut dag = IndexedTestDag::default();
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm_node_id = dag
+        .add_node("qualm", BTreeSet::from([quake_node_id.clone()]))
+        .unwrap();
+    let quell_node_id = dag
+        .add_node("quell", BTreeSet::from([qualm_node_id.clone()]))
+        .unwrap();
+    assert_eq!(
+        dag.ancestors_of(&quell_node_id).unwrap(),
+        BTreeSet::from([quake_node_id.clone(), qualm_node_id.clone()])
+    );
+    assert_eq!(
+        dag.descendants_of(&quake_node_id).unwrap(),
+        BTreeSet::from([qualm_node_id.clone(), quell_node_id.clone()])
+    );
+    assert!(dag.ancestors_of(&quake_node_id).unwrap().is_empty());
+    assert!(dag.descendants_of(&quell_node_id).unwrap().is_empty());
+}
 
-    #[test]
-    fn test_node_deserializaton() {
-        let mut dag = TestDag::new(BTreeMap::new());
-        let simple_node_id = dag.add_node("simple", BTreeSet::new()).unwrap();
-        let mut dep_set = BTreeSet::new();
-        dep_set.insert(simple_node_id.clone());
-        let root_node_id = dag.add_node("root", dep_set).unwrap();
+#[test]
+fn test_closure_stats_directed() {
+    let mut dag = IndexedTestDag::default();
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm_node_id = dag.add_node("qualm", BTreeSet::new()).unwrap();
+    let quell_node_id = dag
+        .add_node(
+            "quell",
+            BTreeSet::from([quake_node_id.clone(), qualm_node_id.clone()]),
+        )
+        .unwrap();
+    let quux_node_id = dag
+        .add_node("quux", BTreeSet::from([quell_node_id.clone()]))
+        .unwrap();
+    assert_eq!(
+        dag.closure_stats(&quux_node_id).unwrap(),
+        ClosureStats { size: 3, depth: 2 }
+    );
+    assert_eq!(
+        dag.closure_stats_directed(&quake_node_id, Direction::Down)
+            .unwrap(),
+        ClosureStats { size: 2, depth: 2 }
+    );
+}
 
-        let simple_node_to_serialize = dag
-            .get_node_by_id(simple_node_id.as_slice())
-            .unwrap()
-            .unwrap();
-        let root_node_to_serialize = dag
-            .get_node_by_id(root_node_id.as_slice())
-            .unwrap()
+// Builds a DAG with an untagged base, an EU tagged branch, an untagged branch and a merge of
+// both branches.
+fn regional_dag() -> (TestDag<'static>, BTreeMap<&'static str, Vec<u8>>) {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let mut ids: BTreeMap<&'static str, Vec<u8>> = BTreeMap::new();
+    let mut add = |dag: &mut TestDag, name: &'static str, deps: &[&str]| {
+        let deps = deps.iter().map(|dep| ids[dep].clone()).collect();
+        ids.insert(name, dag.add_node(name, deps).unwrap());
+    };
+    add(&mut dag, "base", &[]);
+    add(&mut dag, "eu 0", &["base"]);
+    add(&mut dag, "eu 1", &["eu 0"]);
+    add(&mut dag, "eu 2", &["eu 1"]);
+    add(&mut dag, "local 1", &["base"]);
+    add(&mut dag, "local 2", &["local 1"]);
+    add(&mut dag, "merge", &["eu 2", "local 2"]);
+    dag.tag_subgraph(&ids["eu 0"], "eu").unwrap();
+    (dag, ids)
+}
+
+#[test]
+fn test_tags_resolve_through_markers() {
+    let (mut dag, ids) = regional_dag();
+    let eu = BTreeSet::from(["eu".to_owned()]);
+    for name in ["eu 0", "eu 1", "eu 2", "merge"] {
+        assert_eq!(dag.tags_of(&ids[name]).unwrap(), eu, "{}", name);
+    }
+    for name in ["base", "local 1", "local 2"] {
+        assert!(dag.tags_of(&ids[name]).unwrap().is_empty(), "{}", name);
+    }
+    // Nodes added below a marker later inherit its tags without touching the marker.
+    let mut deep = ids["eu 2"].clone();
+    for idx in 0..50 {
+        deep = dag
+            .add_node(format!("eu deep {}", idx), BTreeSet::from([deep]))
             .unwrap();
+    }
+    assert_eq!(dag.tags_of(&deep).unwrap(), eu);
+    dag.tag_subgraph(&ids["eu 1"], "audited").unwrap();
+    assert_eq!(
+        dag.tags_of(&deep).unwrap(),
+        BTreeSet::from(["audited".to_owned(), "eu".to_owned()])
+    );
+    let deny = TagFilter::deny(["eu"]);
+    assert!(!dag
+        .admits_node(&Node::new("next", BTreeSet::from([deep])), &deny)
+        .unwrap());
+    assert!(dag
+        .admits_node(
+            &Node::new("next", BTreeSet::from([ids["local 2"].clone()])),
+            &deny
+        )
+        .unwrap());
+    assert!(matches!(
+        dag.tag_subgraph(b"unknown!", "eu"),
+        Err(StoreError::NoSuchNode(_))
+    ));
+}
 
-        let mut simple_node_vec: Vec<u8> = Vec::new();
-        let mut root_node_vec: Vec<u8> = Vec::new();
-        into_writer(&simple_node_to_serialize, &mut simple_node_vec).unwrap();
-        into_writer(&root_node_to_serialize, &mut root_node_vec).unwrap();
+#[test]
+fn test_tag_filtered_sync_stops_at_denied_branch() {
+    let (dag, ids) = regional_dag();
+    let plan = dag
+        .plan_tag_filtered_sync(&TagFilter::deny(["eu"]))
+        .unwrap();
+    assert_eq!(
+        plan.offered,
+        vec![
+            ids["base"].clone(),
+            ids["local 1"].clone(),
+            ids["local 2"].clone()
+        ]
+    );
+    let eu = BTreeSet::from(["eu".to_owned()]);
+    assert_eq!(
+        plan.withheld,
+        ["eu 0", "eu 1", "eu 2", "merge"]
+            .iter()
+            .map(|name| (ids[name].clone(), eu.clone()))
+            .collect::<BTreeMap<_, _>>()
+    );
+    assert_eq!(plan.boundary, BTreeSet::from([ids["eu 0"].clone()]));
+    assert_eq!(plan.blocking_tags(), eu);
+}
 
-        let simple_node_de: Node<DefaultHasher> = from_reader(simple_node_vec.as_slice()).unwrap();
-        let root_node_de: Node<DefaultHasher> = from_reader(root_node_vec.as_slice()).unwrap();
-        assert_eq!(simple_node_to_serialize.id(), simple_node_de.id());
-        assert_eq!(simple_node_to_serialize.item_id(), simple_node_de.item_id());
-        assert_eq!(simple_node_to_serialize.item(), simple_node_de.item());
-        assert_eq!(
-            simple_node_to_serialize.dependency_ids(),
-            simple_node_de.dependency_ids()
-        );
-        assert_eq!(root_node_to_serialize.id(), root_node_de.id());
-        assert_eq!(root_node_to_serialize.item_id(), root_node_de.item_id());
-        assert_eq!(root_node_to_serialize.item(), root_node_de.item());
-        assert_eq!(
-            root_node_to_serialize.dependency_ids(),
-            root_node_de.dependency_ids()
-        );
+#[test]
+fn test_tag_allow_filtered_sync_reproduces_tagged_closure() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let eu_0 = dag.add_node("eu 0", BTreeSet::new()).unwrap();
+    let eu_1 = dag
+        .add_node("eu 1", BTreeSet::from([eu_0.clone()]))
+        .unwrap();
+    let eu_2 = dag
+        .add_node("eu 2", BTreeSet::from([eu_0.clone(), eu_1.clone()]))
+        .unwrap();
+    let local = dag.add_node("local", BTreeSet::new()).unwrap();
+    dag.tag_subgraph(&eu_0, "eu").unwrap();
+    let plan = dag
+        .plan_tag_filtered_sync(&TagFilter::allow(["eu"]))
+        .unwrap();
+    assert_eq!(plan.offered, vec![eu_0.clone(), eu_1.clone(), eu_2.clone()]);
+    assert_eq!(plan.boundary, BTreeSet::from([local.clone()]));
+    assert!(plan.blocking_tags().is_empty());
+
+    // A tagged node depending on an untagged one can't be shipped without it.
+    let merge = dag
+        .add_node("merge", BTreeSet::from([eu_2.clone(), local.clone()]))
+        .unwrap();
+    let plan = dag
+        .plan_tag_filtered_sync(&TagFilter::allow(["eu"]))
+        .unwrap();
+    assert_eq!(plan.offered, vec![eu_0, eu_1, eu_2]);
+    assert!(plan.withheld.contains_key(&merge));
+    assert!(!plan.boundary.contains(&merge));
+}
+
+#[test]
+fn test_plan_expunge_mixed_diamond() {
+    let mut dag = IndexedTestDag::default();
+    let base = dag.add_node("base", BTreeSet::new()).unwrap();
+    let revoked = dag
+        .add_node("revoked", BTreeSet::from([base.clone()]))
+        .unwrap();
+    let far_side = dag
+        .add_node("far side", BTreeSet::from([base.clone()]))
+        .unwrap();
+    let merge = dag
+        .add_node("merge", BTreeSet::from([revoked.clone(), far_side.clone()]))
+        .unwrap();
+    let orphan = dag
+        .add_node("orphan", BTreeSet::from([revoked.clone()]))
+        .unwrap();
+    let orphan_child = dag
+        .add_node("orphan child", BTreeSet::from([orphan.clone()]))
+        .unwrap();
+    let rescued = dag
+        .add_node(
+            "rescued",
+            BTreeSet::from([orphan_child.clone(), far_side.clone()]),
+        )
+        .unwrap();
+    let rescued_child = dag
+        .add_node("rescued child", BTreeSet::from([rescued.clone()]))
+        .unwrap();
+
+    let report = dag.plan_expunge([revoked.as_slice()]).unwrap();
+    assert_eq!(report.attributed, BTreeSet::from([revoked.clone()]));
+    assert_eq!(
+        report.collateral,
+        BTreeSet::from([orphan.clone(), orphan_child.clone()])
+    );
+    assert_eq!(
+        report.retained,
+        BTreeSet::from([merge.clone(), rescued.clone(), rescued_child.clone()])
+    );
+    assert_eq!(report.affected().count(), 3);
+
+    // Planning again or planning with the whole affected set settles on the same nodes.
+    assert_eq!(dag.plan_expunge([revoked.as_slice()]).unwrap(), report);
+    let affected: Vec<Vec<u8>> = report.affected().cloned().collect();
+    let replanned = dag
+        .plan_expunge(affected.iter().map(|id| id.as_slice()))
+        .unwrap();
+    assert!(replanned.collateral.is_empty());
+    assert_eq!(
+        replanned.affected().collect::<BTreeSet<_>>(),
+        report.affected().collect::<BTreeSet<_>>()
+    );
+    assert_eq!(replanned.retained, report.retained);
+}
+
+#[test]
+fn test_plan_expunge_ignores_unknown_ids_and_needs_children() {
+    let mut dag = IndexedTestDag::default();
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let report = dag.plan_expunge([b"unknown!".as_slice()]).unwrap();
+    assert_eq!(report, ExpungeReport::default());
+
+    let mut dag = TestDag::new(BTreeMap::new());
+    dag.add_node("quake", BTreeSet::new()).unwrap();
+    assert!(matches!(
+        dag.plan_expunge([quake_node_id.as_slice()]),
+        Err(StoreError::Unsupported("children_of"))
+    ));
+}
+
+#[test]
+fn test_path_between_directed() {
+    let mut dag = IndexedTestDag::default();
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm_node_id = dag
+        .add_node("qualm", BTreeSet::from([quake_node_id.clone()]))
+        .unwrap();
+    let quell_node_id = dag
+        .add_node("quell", BTreeSet::from([qualm_node_id.clone()]))
+        .unwrap();
+    let up_path = vec![
+        quell_node_id.clone(),
+        qualm_node_id.clone(),
+        quake_node_id.clone(),
+    ];
+    assert_eq!(
+        dag.path_between(&quell_node_id, &quake_node_id).unwrap(),
+        Some(up_path.clone())
+    );
+    let mut down_path = dag
+        .path_between_directed(&quake_node_id, &quell_node_id, Direction::Down)
+        .unwrap()
+        .unwrap();
+    down_path.reverse();
+    assert_eq!(down_path, up_path);
+    assert_eq!(
+        dag.path_between(&quake_node_id, &quell_node_id).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn test_descendants_unsupported_without_reverse_index() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    assert!(matches!(
+        dag.descendants_of(&quake_node_id),
+        Err(StoreError::Unsupported("children_of"))
+    ));
+}
+
+#[test]
+fn test_read_only_store_serves_queries_and_refuses_writes() {
+    let (dag, ids) = TestDag::from_text(QUAKE_CHAIN).unwrap();
+    let mut ro =
+        Merkle::<_, TestHasher>::from_store(ReadOnlyStore::new(dag.get_nodes().clone())).unwrap();
+    assert_eq!(ro.get_roots(), dag.get_roots());
+    assert_eq!(
+        ro.get_node_by_id(&ids["qualm"]).unwrap().unwrap().item(),
+        b"qualm"
+    );
+    assert_eq!(
+        ro.compare(&ids["quake"], &ids["quell"]).unwrap(),
+        NodeCompare::Before
+    );
+    assert_eq!(
+        ro.ancestors_of(&ids["quell"]).unwrap(),
+        BTreeSet::from([ids["quake"].clone(), ids["qualm"].clone()])
+    );
+
+    assert!(matches!(
+        ro.add_node("shake", BTreeSet::from([ids["quell"].clone()])),
+        Err(StoreError::ReadOnly("store"))
+    ));
+    assert!(matches!(
+        ro.add_nodes(vec![Node::new("shake", BTreeSet::new())]),
+        Err(StoreError::ReadOnly("store_many"))
+    ));
+    assert!(matches!(
+        ro.remove_node(&ids["quell"], RemoveScope::Node),
+        Err(StoreError::ReadOnly("delete"))
+    ));
+    assert_eq!(
+        StoreError::ReadOnly("store").kind(),
+        StoreErrorKind::ReadOnly
+    );
+    assert_eq!(ro.get_roots(), dag.get_roots());
+    assert_eq!(ro.node_count().unwrap(), 3);
+}
+
+// A store counting the node reads that reach it.
+#[derive(Default)]
+struct ReadCountingStore {
+    inner: BTreeStore<TestHasher>,
+    reads: std::cell::Cell<usize>,
+}
+
+impl Store<TestHasher> for ReadCountingStore {
+    fn contains(&self, id: &[u8]) -> crate::store::Result<bool> {
+        self.inner.contains(id)
+    }
+
+    fn get(&self, id: &[u8]) -> crate::store::Result<Option<Node<TestHasher>>> {
+        self.reads.set(self.reads.get() + 1);
+        Store::get(&self.inner, id)
+    }
+
+    fn store(&mut self, node: Node<TestHasher>) -> crate::store::Result<()> {
+        self.inner.store(node)
+    }
+
+    fn delete(&mut self, id: &[u8]) -> crate::store::Result<()> {
+        Store::<TestHasher>::delete(&mut self.inner, id)
+    }
+}
+
+#[test]
+fn test_cached_store_reads_each_node_once() {
+    let mut dag = Merkle::<CachedStore<ReadCountingStore, TestHasher>, TestHasher>::new(
+        CachedStore::new(ReadCountingStore::default(), 16),
+    );
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm = dag
+        .add_node("qualm", BTreeSet::from([quake.clone()]))
+        .unwrap();
+    let reads = dag.get_nodes().inner().reads.get();
+    for _ in 0..5 {
+        assert_eq!(
+            dag.get_node_by_id(&quake).unwrap().unwrap().item(),
+            b"quake"
+        );
+    }
+    assert_eq!(dag.get_nodes().inner().reads.get(), reads + 1);
+    for _ in 0..3 {
+        assert_eq!(dag.compare(&quake, &qualm).unwrap(), NodeCompare::Before);
+    }
+    assert_eq!(dag.get_nodes().inner().reads.get(), reads + 2);
+    assert_eq!(dag.get_nodes().cached(), 2);
+    let fetched = dag.get_nodes_by_ids(&[&qualm, &quake]).unwrap();
+    assert_eq!(fetched[0].as_ref().unwrap().item(), b"qualm");
+    assert_eq!(dag.get_nodes().inner().reads.get(), reads + 2);
+}
+
+#[test]
+fn test_cached_store_evicts_and_invalidates() {
+    let mut store = CachedStore::new(ReadCountingStore::default(), 2);
+    let mut ids = Vec::new();
+    for payload in ["quake", "qualm", "quell"] {
+        let node = Node::<TestHasher>::new(payload, BTreeSet::new());
+        ids.push(node.id().to_vec());
+        store.store(node).unwrap();
+    }
+    let get = |store: &CachedStore<ReadCountingStore, TestHasher>, idx: usize| {
+        Store::get(store, &ids[idx]).unwrap().unwrap();
+        store.inner().reads.get()
+    };
+    assert_eq!(get(&store, 0), 1);
+    assert_eq!(get(&store, 1), 2);
+    assert_eq!(get(&store, 0), 2);
+    // The least recently used qualm is evicted.
+    assert_eq!(get(&store, 2), 3);
+    assert_eq!(store.cached(), 2);
+    assert_eq!(get(&store, 0), 3);
+    assert_eq!(get(&store, 1), 4);
+    // Storing a node again drops its cached copy.
+    store
+        .store(Node::<TestHasher>::new("qualm", BTreeSet::new()))
+        .unwrap();
+    assert_eq!(get(&store, 1), 5);
+
+    // Deleted nodes are not served from the cache.
+    let mut store = CachedStore::new(BTreeStore::<TestHasher>::new(), 2);
+    let node = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let id = node.id().to_vec();
+    store.store(node).unwrap();
+    assert!(Store::get(&store, &id).unwrap().is_some());
+    store.delete(&id).unwrap();
+    assert!(Store::get(&store, &id).unwrap().is_none());
+    assert!(!store.contains(&id).unwrap());
+}
+
+type TieredTestStore = TieredStore<ReadCountingStore, ReadCountingStore>;
+
+#[test]
+fn test_tiered_store_writes_through() {
+    let mut dag = Merkle::<TieredTestStore, TestHasher>::new(TieredStore::new(
+        ReadCountingStore::default(),
+        ReadCountingStore::default(),
+    ));
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let ids = dag
+        .add_nodes(vec![Node::new("qualm", BTreeSet::from([quake.clone()]))])
+        .unwrap();
+    for id in [&quake, &ids[0]] {
+        assert!(dag.get_nodes().front().inner.contains_key(id));
+        assert!(dag.get_nodes().back().inner.contains_key(id));
+    }
+    dag.remove_node(&ids[0], RemoveScope::Node).unwrap();
+    assert!(!dag.get_nodes().front().inner.contains_key(&ids[0]));
+    assert!(!dag.get_nodes().back().inner.contains_key(&ids[0]));
+}
+
+#[test]
+fn test_tiered_store_backfills_reads() {
+    let mut back = ReadCountingStore::default();
+    let mut ids = Vec::new();
+    for payload in ["quake", "qualm", "quell"] {
+        let node = Node::<TestHasher>::new(payload, BTreeSet::new());
+        ids.push(node.id().to_vec());
+        back.store(node).unwrap();
+    }
+    let store: TieredTestStore = TieredStore::new(ReadCountingStore::default(), back);
+    assert!(Store::<TestHasher>::contains(&store, &ids[0]).unwrap());
+    assert!(store.front().inner.is_empty());
+
+    for _ in 0..3 {
+        let node: Node<TestHasher> = Store::get(&store, &ids[0]).unwrap().unwrap();
+        assert_eq!(node.item(), b"quake");
+    }
+    assert_eq!(store.back().reads.get(), 1);
+    assert_eq!(store.front().reads.get(), 3);
+    assert!(store.front().inner.contains_key(&ids[0]));
+
+    let fetched: Vec<Option<Node<TestHasher>>> =
+        store.get_many(&[&ids[0], &ids[1], &ids[2]]).unwrap();
+    assert!(fetched.iter().all(Option::is_some));
+    assert_eq!(store.back().reads.get(), 3);
+    assert_eq!(store.front().inner.len(), 3);
+    let missing = Node::<TestHasher>::new("shake", BTreeSet::new());
+    assert!(Store::<TestHasher>::get(&store, missing.id())
+        .unwrap()
+        .is_none());
+    assert_eq!(store.front().inner.len(), 3);
+}
+
+// Checks a DAG over a union of an old store holding a chain and a new primary store.
+fn check_union_store<Old, New>(mut old: Old, new: New)
+where
+    Old: Store<TestHasher> + 'static,
+    New: Store<TestHasher> + 'static,
+{
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+    let (quake_id, qualm_id) = (quake.id().to_vec(), qualm.id().to_vec());
+    old.store(quake.clone()).unwrap();
+    old.store(qualm).unwrap();
+    let union = UnionStore::new().with_primary(new).with_fallback(old);
+    let mut dag = Merkle::<_, TestHasher>::from_store(union).unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([qualm_id.clone()]));
+
+    // The dependency only exists in the old store.
+    let quell = dag
+        .add_node("quell", BTreeSet::from([qualm_id.clone()]))
+        .unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([quell.clone()]));
+    let stores = dag.get_nodes().stores();
+    assert!(stores[0].contains(&quell).unwrap());
+    assert!(!stores[1].contains(&quell).unwrap());
+    assert!(!stores[0].contains(&qualm_id).unwrap());
+    assert_eq!(dag.compare(&quake_id, &quell).unwrap(), NodeCompare::Before);
+
+    // Nodes the old store already has are not written again.
+    let shared = dag.add_node("quake", BTreeSet::new()).unwrap();
+    assert_eq!(shared, quake_id);
+    dag.add_nodes(vec![quake]).unwrap();
+    assert!(!dag.get_nodes().stores()[0].contains(&quake_id).unwrap());
+    let union = dag.get_nodes();
+    let ids: Vec<Vec<u8>> = union.ids().unwrap().map(Result::unwrap).collect();
+    assert_eq!(ids.len(), 3);
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(dag.node_count().unwrap(), 3);
+    let fetched = dag
+        .get_nodes_by_ids(&[&quell, &qualm_id, &quake_id])
+        .unwrap();
+    assert_eq!(fetched[1].as_ref().unwrap().item(), b"qualm");
+    assert_eq!(fetched[2].as_ref().unwrap().item(), b"quake");
+}
+
+#[test]
+fn test_union_store_reads_across_stores() {
+    check_union_store(
+        BTreeStore::<TestHasher>::new(),
+        BTreeStore::<TestHasher>::new(),
+    );
+}
+
+#[test]
+fn test_union_store_lists_shared_ids_once() {
+    let node = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let mut old = BTreeStore::<TestHasher>::new();
+    let mut new = BTreeStore::<TestHasher>::new();
+    old.store(node.clone()).unwrap();
+    new.store(node.clone()).unwrap();
+    let union = UnionStore::new().with_primary(new).with_fallback(old);
+    assert_eq!(
+        Store::<TestHasher>::get(&union, node.id())
+            .unwrap()
+            .unwrap()
+            .item(),
+        b"quake"
+    );
+    assert_eq!(union.len().unwrap(), 1);
+    assert_eq!(
+        union.key_length_histogram().unwrap(),
+        BTreeMap::from([(node.id().len(), 1)])
+    );
+    let mut dag = Merkle::<_, TestHasher>::from_store(union).unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([node.id().to_vec()]));
+    dag.remove_node(node.id(), RemoveScope::Node).unwrap();
+    assert!(!dag.check_for_node(node.id()).unwrap());
+
+    let mut read_only = UnionStore::new().with_fallback(BTreeStore::<TestHasher>::new());
+    assert!(matches!(
+        read_only.store(node),
+        Err(StoreError::ReadOnly("store"))
+    ));
+}
+
+#[cfg(all(feature = "sqlite", feature = "rusty-leveldb"))]
+#[test]
+fn test_union_store_over_leveldb_and_sqlite() {
+    check_union_store(
+        crate::leveldb::LevelStore::default(),
+        crate::sqlite::SqliteStore::in_memory().unwrap(),
+    );
+}
+
+// A store whose writes fail while it is down.
+#[derive(Default)]
+struct FlakyStore {
+    inner: BTreeStore<TestHasher>,
+    down: bool,
+}
+
+impl Store<TestHasher> for FlakyStore {
+    fn contains(&self, id: &[u8]) -> crate::store::Result<bool> {
+        self.inner.contains(id)
+    }
+
+    fn get(&self, id: &[u8]) -> crate::store::Result<Option<Node<TestHasher>>> {
+        Store::get(&self.inner, id)
+    }
+
+    fn store(&mut self, node: Node<TestHasher>) -> crate::store::Result<()> {
+        if self.down {
+            return Err(StoreError::StoreFailure("secondary is down".to_owned()));
+        }
+        self.inner.store(node)
+    }
+}
+
+fn mirrored_dag(
+    policy: MirrorPolicy,
+) -> Merkle<MirroredStore<BTreeStore<TestHasher>, FlakyStore>, TestHasher> {
+    Merkle::new(MirroredStore::new(
+        BTreeStore::new(),
+        FlakyStore::default(),
+        policy,
+    ))
+}
+
+#[test]
+fn test_mirrored_store_strict_fails_the_call() {
+    let mut dag = mirrored_dag(MirrorPolicy::Strict);
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    assert!(dag.get_nodes().secondary().inner.contains_key(&quake));
+    dag.get_nodes().secondary().down = true;
+    assert!(matches!(
+        dag.add_node("qualm", BTreeSet::from([quake.clone()])),
+        Err(StoreError::StoreFailure(_))
+    ));
+    assert_eq!(dag.get_roots(), &BTreeSet::from([quake]));
+    assert!(dag.get_nodes().failures().is_empty());
+    assert!(dag.get_nodes().pending().is_empty());
+}
+
+#[test]
+fn test_mirrored_store_best_effort_collects_failures() {
+    let mut dag = mirrored_dag(MirrorPolicy::BestEffort);
+    dag.get_nodes().secondary().down = true;
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let ids = dag
+        .add_nodes(vec![Node::new("qualm", BTreeSet::from([quake.clone()]))])
+        .unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([ids[0].clone()]));
+    assert!(dag.get_nodes().secondary().inner.is_empty());
+    let failures = dag.get_nodes().take_failures();
+    let ops: Vec<MirrorOp> = failures.iter().map(|failure| failure.op.clone()).collect();
+    assert_eq!(
+        ops,
+        vec![MirrorOp::Store(quake), MirrorOp::Store(ids[0].clone())]
+    );
+    assert!(failures
+        .iter()
+        .all(|failure| failure.error.kind() == StoreErrorKind::StoreFailure));
+    assert!(dag.get_nodes().failures().is_empty());
+}
+
+#[test]
+fn test_mirrored_store_retry_queues_writes() {
+    let mut dag = mirrored_dag(MirrorPolicy::Retry);
+    dag.get_nodes().secondary().down = true;
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm = dag
+        .add_node("qualm", BTreeSet::from([quake.clone()]))
+        .unwrap();
+    assert_eq!(
+        dag.get_nodes().pending(),
+        vec![
+            MirrorOp::Store(quake.clone()),
+            MirrorOp::Store(qualm.clone())
+        ]
+    );
+    assert!(dag.get_nodes().flush_pending().is_err());
+    assert_eq!(dag.get_nodes().pending().len(), 2);
+    dag.get_nodes().secondary().down = false;
+    assert_eq!(dag.get_nodes().flush_pending().unwrap(), 2);
+    assert!(dag.get_nodes().pending().is_empty());
+    let secondary = dag.get_nodes().secondary();
+    assert!(secondary.inner.contains_key(&quake));
+    assert!(secondary.inner.contains_key(&qualm));
+}
+
+// The debug invariant checks read the store as well.
+#[cfg(not(feature = "debug-invariants"))]
+#[test]
+fn test_metered_store_counts_dag_operations() {
+    use crate::store::{MeteredStore, StoreMetrics};
+    let mut dag = Merkle::<MeteredStore<BTreeStore<TestHasher>>, TestHasher>::new(
+        MeteredStore::new(BTreeStore::new()),
+    );
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm = dag
+        .add_node("qualm", BTreeSet::from([quake.clone()]))
+        .unwrap();
+    // Adding a node twice only checks for it.
+    dag.add_node("quake", BTreeSet::new()).unwrap();
+    let metrics = dag.get_nodes().metrics();
+    assert_eq!(metrics.contains_calls, 4);
+    assert_eq!(metrics.store_calls, 2);
+    assert_eq!(metrics.nodes_written, 2);
+    assert_eq!(metrics.payload_bytes_written, 10);
+    assert_eq!(metrics.get_calls, 0);
+    assert_eq!(metrics.nodes_read, 0);
+
+    let metrics = dag.get_nodes().reset();
+    assert_eq!(metrics.payload_bytes_read, 0);
+    assert_eq!(dag.get_nodes().metrics(), StoreMetrics::default());
+    dag.get_node_by_id(&qualm).unwrap().unwrap();
+    dag.get_nodes_by_ids(&[&quake, &qualm, b"missing!"])
+        .unwrap();
+    assert!(dag.check_for_node(&quake).unwrap());
+    dag.add_nodes(vec![
+        Node::new("quell", BTreeSet::from([qualm.clone()])),
+        Node::new("shake", BTreeSet::new()),
+    ])
+    .unwrap();
+    assert_eq!(
+        dag.get_nodes().metrics(),
+        StoreMetrics {
+            contains_calls: 4,
+            get_calls: 2,
+            store_calls: 1,
+            delete_calls: 0,
+            nodes_read: 3,
+            nodes_written: 2,
+            payload_bytes_read: 15,
+            payload_bytes_written: 10,
+        }
+    );
+}
+
+#[test]
+fn test_verifying_store_detects_corrupt_nodes() {
+    use crate::store::VerifyingStore;
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+    let quell = Node::<TestHasher>::new("quell", BTreeSet::from([qualm.id().to_vec()]));
+    let (quake_id, qualm_id, quell_id) = (
+        quake.id().to_vec(),
+        qualm.id().to_vec(),
+        quell.id().to_vec(),
+    );
+    let mut store = BTreeStore::new();
+    for node in [quake, qualm.clone(), quell] {
+        store.insert(node.id().to_vec(), node);
+    }
+    // A record whose payload changed under its id.
+    let rotted = Node::<TestHasher>::new("qualx", qualm.dependency_ids().clone());
+    store.insert(qualm_id.clone(), rotted);
+    assert!(Store::<TestHasher>::get(&store, &qualm_id)
+        .unwrap()
+        .is_some());
+
+    let store = VerifyingStore::new(store);
+    let corrupt = |result: Result<_, StoreError>| matches!(result, Err(StoreError::CorruptNode { ref id }) if id == &qualm_id);
+    assert!(corrupt(store.get(&qualm_id).map(|_| ())));
+    assert!(corrupt(store.get_handle(&qualm_id).map(|_| ())));
+    assert!(corrupt(
+        store
+            .get_many(&[quake_id.as_slice(), &qualm_id])
+            .map(|_| ())
+    ));
+    assert_eq!(store.get(&quake_id).unwrap().unwrap().item(), b"quake");
+    assert!(store.get(b"missing").unwrap().is_none());
+
+    let dag = Merkle::<_, TestHasher>::new(store);
+    assert!(corrupt(dag.compare(&quell_id, &quake_id).map(|_| ())));
+    let store = dag.get_nodes().inner().clone();
+    assert!(corrupt(
+        Merkle::<_, TestHasher>::from_store(VerifyingStore::new(store)).map(|_| ())
+    ));
+}
+
+#[test]
+fn test_node_validate_reports_each_corrupt_field() {
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let qualm = Node::<TestHasher>::builder()
+        .payload("qualm")
+        .depends_on(quake.id())
+        .attribute("op", vec![1])
+        .id_version(NodeIdVersion::V1)
+        .build();
+    assert_eq!(quake.validate(), Ok(()));
+    assert_eq!(qualm.validate(), Ok(()));
+    assert_eq!(qualm.validate_against(qualm.id()), Ok(()));
+    let (id, item_id) = (qualm.id().to_vec(), qualm.item_id().to_vec());
+
+    // Damage to the item or its hash is caught by the item id.
+    let item_id_mismatch = |node: &Node<TestHasher>, recorded: &[u8]| match node.validate() {
+        Err(NodeIntegrityError::ItemIdMismatch {
+            id: found,
+            recorded: found_recorded,
+            computed,
+        }) => found == id && found_recorded == recorded && computed != recorded,
+        _ => false,
+    };
+    let mut node = qualm.clone();
+    node.item_mut().push(b'x');
+    assert!(item_id_mismatch(&node, &item_id));
+    let mut node = qualm.clone();
+    node.item_id_mut()[0] ^= 1;
+    let flipped = node.item_id().to_vec();
+    assert!(item_id_mismatch(&node, &flipped));
+    let mut node = qualm.clone();
+    *node.id_version_mut() = NodeIdVersion::V0;
+    assert!(item_id_mismatch(&node, &item_id));
+
+    // Damage to the rest of the node is caught by the id.
+    let id_mismatch = |node: &Node<TestHasher>| {
+        matches!(
+            node.validate(),
+            Err(NodeIntegrityError::IdMismatch { recorded, computed })
+                if recorded == node.id() && computed != node.id()
+        )
+    };
+    let mut node = qualm.clone();
+    node.dependency_ids_mut().insert(
+        Node::<TestHasher>::new("quell", BTreeSet::new())
+            .id()
+            .to_vec(),
+    );
+    assert!(id_mismatch(&node));
+    let mut node = qualm.clone();
+    node.attributes_mut().insert("op".to_owned(), vec![2]);
+    assert!(id_mismatch(&node));
+    let mut node = qualm.clone();
+    node.id_mut()[0] ^= 1;
+    assert!(id_mismatch(&node));
+    // The recomputed id is the original one.
+    assert_eq!(
+        node.validate(),
+        Err(NodeIntegrityError::IdMismatch {
+            recorded: node.id().to_vec(),
+            computed: id.clone(),
+        })
+    );
+    assert_eq!(
+        node.validate_against(&id),
+        Err(NodeIntegrityError::UnexpectedId {
+            expected: id.clone(),
+            found: node.id().to_vec(),
+        })
+    );
+
+    let mut node = qualm.clone();
+    node.dependency_ids_mut().insert(vec![0xab; 3]);
+    assert_eq!(
+        node.validate(),
+        Err(NodeIntegrityError::MalformedDependency {
+            id: id.clone(),
+            dependency: vec![0xab; 3],
+        })
+    );
+    let err = node.validate().unwrap_err().to_string();
+    assert!(err.contains(&hex(&id)), "{}", err);
+    assert!(err.contains("ababab"), "{}", err);
+
+    // A detached node is only checked against its item.
+    let (detached, item) = qualm.clone().into_detached();
+    assert_eq!(
+        detached.validate(),
+        Err(NodeIntegrityError::DetachedItem { id: id.clone() })
+    );
+    assert_eq!(detached.validate_item(&item), Ok(()));
+    assert!(matches!(
+        detached.validate_item(b"qualx"),
+        Err(NodeIntegrityError::ItemIdMismatch { .. })
+    ));
+    let (mut node, _) = qualm.clone().into_detached();
+    node.item_id_mut().pop();
+    assert_eq!(
+        node.validate(),
+        Err(NodeIntegrityError::MalformedDetachedIds { id: id.clone() })
+    );
+    // A forged record decodes with whatever ids it claims.
+    let (mut forged, _) = qualm.clone().into_detached();
+    *forged.id_mut() = quake.id().to_vec();
+    assert_eq!(
+        forged.validate_item(&item),
+        Err(NodeIntegrityError::IdMismatch {
+            recorded: quake.id().to_vec(),
+            computed: id.clone(),
+        })
+    );
+}
+
+#[test]
+fn test_merkle_verify_node() {
+    let (dag, ids) = TestDag::from_text(QUAKE_CHAIN).unwrap();
+    let node = dag.verify_node(&ids["qualm"]).unwrap();
+    assert_eq!(node.item(), b"qualm");
+    let missing = Node::<TestHasher>::new("missing", BTreeSet::new());
+    assert!(matches!(
+        dag.verify_node(missing.id()),
+        Err(StoreError::NoSuchNode(id)) if id == missing.id()
+    ));
+
+    let mut store = dag.get_nodes().clone();
+    let mut rotted = store[&ids["qualm"]].clone();
+    rotted.item_mut().push(b'x');
+    store.insert(ids["qualm"].clone(), rotted);
+    // A record stored under another id.
+    store.insert(ids["quake"].clone(), store[&ids["quell"]].clone());
+    let dag = TestDag::new(store);
+    let err = dag.verify_node(&ids["qualm"]).unwrap_err();
+    assert_eq!(err.kind(), StoreErrorKind::InvalidNode);
+    assert!(matches!(
+        err,
+        StoreError::InvalidNode(NodeIntegrityError::ItemIdMismatch { ref id, .. }) if id == &ids["qualm"]
+    ));
+    assert!(err.to_string().starts_with("invalid node: item of node"));
+    assert!(matches!(
+        dag.verify_node(&ids["quake"]),
+        Err(StoreError::InvalidNode(NodeIntegrityError::UnexpectedId { expected, found }))
+            if expected == ids["quake"] && found == ids["quell"]
+    ));
+    assert!(dag.verify_node(&ids["quell"]).is_ok());
+}
+
+#[test]
+fn test_copy_store_skips_corrupt_and_present_nodes() {
+    use crate::store::{copy_store, CopyReport};
+    let (dag, ids) = TestDag::from_text(QUAKE_CHAIN).unwrap();
+    let mut src = dag.get_nodes().clone();
+    let rotted = Node::<TestHasher>::new("qualx", BTreeSet::from([ids["quake"].clone()]));
+    src.insert(ids["qualm"].clone(), rotted);
+    let mut dst = BTreeStore::new();
+    dst.insert(ids["quake"].clone(), src[&ids["quake"]].clone());
+
+    let report = copy_store(&src, &mut dst).unwrap();
+    assert_eq!(
+        report,
+        CopyReport {
+            copied: 1,
+            already_present: 1,
+            corrupt: vec![ids["qualm"].clone()],
+            vanished: Vec::new(),
+            roots_copied: false,
+        }
+    );
+    assert!(!dst.contains_key(&ids["qualm"]));
+    assert_eq!(dst[&ids["quell"]].item(), b"quell");
+
+    // Running the copy again only copies what is still missing.
+    src.insert(ids["qualm"].clone(), dag.get_nodes()[&ids["qualm"]].clone());
+    let report = copy_store(&src, &mut dst).unwrap();
+    assert_eq!((report.copied, report.already_present), (1, 2));
+    assert!(dst.keys().eq(dag.get_nodes().keys()));
+    assert_eq!(dst[&ids["qualm"]].item(), b"qualm");
+}
+
+#[test]
+fn test_bloom_store_skips_definite_misses() {
+    use crate::store::{BloomStore, MeteredStore};
+    let node = |idx: u64| Node::<TestHasher>::new(format!("bloom-{}", idx), BTreeSet::new());
+    let mut inner = MeteredStore::new(BTreeStore::new());
+    for idx in 0..500 {
+        inner.store(node(idx)).unwrap();
+    }
+    assert!(matches!(
+        BloomStore::new(BTreeStore::<TestHasher>::new(), 10, 1.5),
+        Err(StoreError::StoreFailure(_))
+    ));
+    let mut store = BloomStore::new(inner, 1000, 0.01).unwrap();
+    for idx in 500..1000 {
+        store.store(node(idx)).unwrap();
+    }
+    store.inner().reset();
+
+    // A pseudo random mix of stored and missing ids.
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut present = 0;
+    for _ in 0..4000 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let idx = state % 2000;
+        let id = node(idx).id().to_vec();
+        let stored = idx < 1000;
+        present += stored as u64;
+        assert_eq!(store.contains(&id).unwrap(), stored);
+        assert_eq!(store.get(&id).unwrap().is_some(), stored);
+    }
+    let metrics = store.inner().metrics();
+    let missing = 4000 - present;
+    // Stored ids always reach the wrapped store. Only false positives of the missing do.
+    assert!(metrics.contains_calls >= present);
+    assert!(metrics.contains_calls < present + missing / 20);
+    assert_eq!(metrics.contains_calls, metrics.get_calls);
+
+    let ids: Vec<Vec<u8>> = [3, 1500, 999, 1999, 0]
+        .iter()
+        .map(|idx| node(*idx).id().to_vec())
+        .collect();
+    let ids: Vec<&[u8]> = ids.iter().map(Vec::as_slice).collect();
+    assert_eq!(
+        store.contains_many(&ids).unwrap(),
+        vec![true, false, true, false, true]
+    );
+    let found: Vec<bool> = store
+        .get_many(&ids)
+        .unwrap()
+        .iter()
+        .map(Option::is_some)
+        .collect();
+    assert_eq!(found, vec![true, false, true, false, true]);
+}
+
+#[test]
+fn test_closure_size_cached_unsupported_without_materialization() {
+    let (mut dag, ids) = TestDag::from_text(r#"quake: "quake""#).unwrap();
+    assert!(matches!(
+        dag.closure_size_cached(&ids["quake"]),
+        Err(StoreError::Unsupported("cached_closure_size"))
+    ));
+    assert!(matches!(
+        dag.refresh_closure_sizes(10),
+        Err(StoreError::Unsupported("refresh_closure_sizes"))
+    ));
+}
+
+// Compute a node id through the iterator based `record` the way `Node::new` used to.
+fn id_by_iterator<HW: HashWriter>(item: &[u8], deps: &BTreeSet<Vec<u8>>) -> Vec<u8> {
+    let mut hw = HW::default();
+    hw.record(item.iter().cloned());
+    for dep in deps.iter() {
+        hw.record(dep.iter().cloned());
+    }
+    hw.hash()
+}
+
+fn check_record_paths_agree<HW: HashWriter>() {
+    let big: Vec<u8> = (0..1024 * 1024).map(|idx: u32| (idx % 251) as u8).collect();
+    let quake = Node::<HW>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+    let shake = Node::<HW>::new_with_id_version(big.clone(), BTreeSet::new(), NodeIdVersion::V0);
+    let deps = BTreeSet::from([quake.id().to_vec(), shake.id().to_vec()]);
+    let quell = Node::<HW>::new_with_id_version("quell", deps.clone(), NodeIdVersion::V0);
+    assert_eq!(quake.id(), id_by_iterator::<HW>(b"quake", &BTreeSet::new()));
+    assert_eq!(shake.id(), id_by_iterator::<HW>(&big, &BTreeSet::new()));
+    assert_eq!(quell.id(), id_by_iterator::<HW>(b"quell", &deps));
+    let mut sliced = HW::default();
+    sliced.record_bytes(&big);
+    assert_eq!(shake.item_id(), sliced.hash().as_slice());
+}
+
+#[test]
+fn test_output_len_matches_hash() {
+    fn check<HW: HashWriter>() {
+        assert_eq!(HW::default().hash().len(), HW::OUTPUT_LEN);
+    }
+    check::<TestHasher>();
+    #[cfg(feature = "insecure-hashes")]
+    check::<std::collections::hash_map::DefaultHasher>();
+    #[cfg(feature = "blake2")]
+    {
+        check::<crate::blake2::Blake2b512>();
+        check::<crate::blake2::Blake2s256>();
+    }
+    #[cfg(feature = "sha2")]
+    {
+        check::<crate::sha2::Sha256>();
+        check::<crate::sha2::Sha512>();
+    }
+    #[cfg(feature = "blake3")]
+    check::<crate::blake3::Blake3>();
+}
+
+#[test]
+fn test_truncated_ids_are_malformed() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let truncated = &quake_node_id[..4];
+    let malformed = |result: crate::store::Result<()>| {
+        matches!(
+            result,
+            Err(StoreError::MalformedId {
+                expected: 8,
+                actual: 4
+            })
+        )
+    };
+    assert!(malformed(dag.get_node_by_id(truncated).map(|_| ())));
+    assert!(malformed(dag.check_for_node(truncated).map(|_| ())));
+    assert!(malformed(
+        dag.get_nodes_by_ids(&[quake_node_id.as_slice(), truncated])
+            .map(|_| ())
+    ));
+    assert!(malformed(
+        dag.add_node("qualm", BTreeSet::from([truncated.to_vec()]))
+            .map(|_| ())
+    ));
+    assert!(malformed(
+        dag.add_nodes([Node::new("qualm", BTreeSet::from([truncated.to_vec()]))])
+            .map(|_| ())
+    ));
+    // Nothing was added by the failed calls.
+    assert_eq!(dag.get_roots(), &BTreeSet::from([quake_node_id]));
+}
+
+// Reads at most `chunk` bytes at a time and is interrupted before every other read.
+struct TrickleReader<'a> {
+    bytes: &'a [u8],
+    chunk: usize,
+    interrupt: bool,
+}
+
+impl<'a> std::io::Read for TrickleReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.interrupt = !self.interrupt;
+        if self.interrupt {
+            return Err(std::io::ErrorKind::Interrupted.into());
+        }
+        let read = self.chunk.min(buf.len()).min(self.bytes.len());
+        buf[..read].copy_from_slice(&self.bytes[..read]);
+        self.bytes = &self.bytes[read..];
+        Ok(read)
+    }
+}
+
+#[test]
+fn test_node_from_reader_matches_new() {
+    let big: Vec<u8> = (0..3 * crate::hash::RECORD_CHUNK_SIZE + 17)
+        .map(|idx| (idx % 241) as u8)
+        .collect();
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let deps = BTreeSet::from([quake.id().to_vec()]);
+    let buffered = Node::<TestHasher>::new(big.clone(), deps.clone());
+    let streamed = Node::<TestHasher>::new_from_reader(big.as_slice(), deps.clone()).unwrap();
+    let same = |left: &Node<TestHasher>, right: &Node<TestHasher>| {
+        assert_eq!(left.id(), right.id());
+        assert_eq!(left.item_id(), right.item_id());
+        assert_eq!(left.item(), right.item());
+        assert_eq!(left.dependency_ids(), right.dependency_ids());
+    };
+    same(&buffered, &streamed);
+    let trickled = Node::<TestHasher>::new_from_reader(
+        TrickleReader {
+            bytes: &big,
+            chunk: 1000,
+            interrupt: false,
+        },
+        deps.clone(),
+    )
+    .unwrap();
+    same(&buffered, &trickled);
+    let empty = Node::<TestHasher>::new_from_reader(std::io::empty(), BTreeSet::new()).unwrap();
+    same(&empty, &Node::<TestHasher>::new("", BTreeSet::new()));
+    let sized = Node::<TestHasher>::new_from_sized_reader(
+        TrickleReader {
+            bytes: &big,
+            chunk: 1000,
+            interrupt: true,
+        },
+        big.len() as u64,
+        deps.clone(),
+    )
+    .unwrap();
+    same(&buffered, &sized);
+    let err = Node::<TestHasher>::new_from_sized_reader(
+        big.as_slice(),
+        big.len() as u64 + 1,
+        deps.clone(),
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+    let mut dag = TestDag::new(BTreeMap::new());
+    dag.add_node("quake", BTreeSet::new()).unwrap();
+    let ids = dag.add_nodes([streamed]).unwrap();
+    assert_eq!(ids, vec![buffered.id().to_vec()]);
+    let stored = dag.get_node_by_id(buffered.id()).unwrap().unwrap();
+    assert_eq!(stored.item(), big.as_slice());
+    assert_eq!(stored.item_id(), buffered.item_id());
+}
+
+#[test]
+fn test_node_from_reader_fails_with_the_reader() {
+    struct Broken;
+    impl std::io::Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+    let err = Node::<TestHasher>::new_from_reader(Broken, BTreeSet::new()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_record_paths_agree() {
+    check_record_paths_agree::<TestHasher>();
+    #[cfg(feature = "insecure-hashes")]
+    check_record_paths_agree::<std::collections::hash_map::DefaultHasher>();
+    #[cfg(feature = "blake2")]
+    {
+        check_record_paths_agree::<crate::blake2::Blake2b512>();
+        check_record_paths_agree::<crate::blake2::Blake2s256>();
+    }
+    #[cfg(feature = "sha2")]
+    {
+        check_record_paths_agree::<crate::sha2::Sha256>();
+        check_record_paths_agree::<crate::sha2::Sha512>();
+    }
+    #[cfg(feature = "blake3")]
+    check_record_paths_agree::<crate::blake3::Blake3>();
+}
+
+// A payload ending in the bytes of a dependency id. Under NodeIdVersion::V0 the node holding it
+// hashes the same bytes as a node with the shorter payload and that dependency.
+fn check_crafted_id_collision<HW: HashWriter>() {
+    let quake = Node::<HW>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+    let mut crafted = b"qualm".to_vec();
+    crafted.extend_from_slice(quake.id());
+    let v0_crafted =
+        Node::<HW>::new_with_id_version(crafted.clone(), BTreeSet::new(), NodeIdVersion::V0);
+    let v0_qualm = Node::<HW>::new_with_id_version(
+        "qualm",
+        BTreeSet::from([quake.id().to_vec()]),
+        NodeIdVersion::V0,
+    );
+    assert_eq!(v0_crafted.id(), v0_qualm.id());
+
+    let v1_crafted = Node::<HW>::new_with_id_version(crafted, BTreeSet::new(), NodeIdVersion::V1);
+    let v1_qualm = Node::<HW>::new_with_id_version(
+        "qualm",
+        BTreeSet::from([quake.id().to_vec()]),
+        NodeIdVersion::V1,
+    );
+    assert_ne!(v1_crafted.id(), v1_qualm.id());
+    assert_ne!(v1_qualm.id(), v0_qualm.id());
+    assert_eq!(v1_qualm.id_version(), NodeIdVersion::V1);
+    assert_eq!(v1_qualm.id().len(), HW::OUTPUT_LEN);
+}
+
+#[test]
+fn test_crafted_id_collision() {
+    check_crafted_id_collision::<TestHasher>();
+    #[cfg(feature = "insecure-hashes")]
+    check_crafted_id_collision::<std::collections::hash_map::DefaultHasher>();
+    #[cfg(feature = "blake2")]
+    {
+        check_crafted_id_collision::<crate::blake2::Blake2b512>();
+        check_crafted_id_collision::<crate::blake2::Blake2s256>();
+    }
+    #[cfg(feature = "sha2")]
+    {
+        check_crafted_id_collision::<crate::sha2::Sha256>();
+        check_crafted_id_collision::<crate::sha2::Sha512>();
+    }
+    #[cfg(feature = "blake3")]
+    check_crafted_id_collision::<crate::blake3::Blake3>();
+}
+
+#[test]
+fn test_v1_dag_keeps_crafted_payloads_apart() {
+    let mut v0 = TestDag::new_with_id_version(BTreeMap::new(), NodeIdVersion::V0);
+    let mut v1 = TestDag::new(BTreeMap::new());
+    assert_eq!(v0.id_version(), NodeIdVersion::V0);
+    assert_eq!(v1.id_version(), NodeIdVersion::V1);
+    for dag in [&mut v0, &mut v1] {
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        let mut crafted = b"qualm".to_vec();
+        crafted.extend_from_slice(&quake);
+        let crafted_id = dag.add_node(crafted.clone(), BTreeSet::new()).unwrap();
+        let found = dag.get_node_by_id(&crafted_id).unwrap().unwrap();
+        if dag.id_version() == NodeIdVersion::V0 {
+            // The crafted payload was mistaken for the node already stored under its id.
+            assert_eq!(crafted_id, qualm);
+            assert_eq!(found.item(), b"qualm");
+        } else {
+            assert_ne!(crafted_id, qualm);
+            assert_eq!(found.item(), crafted.as_slice());
+            assert!(dag.get_roots().contains(&crafted_id));
+        }
+    }
+}
+
+#[test]
+fn test_new_nodes_use_the_latest_id_version() {
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    assert_eq!(quake.id_version(), NodeIdVersion::V1);
+    assert_eq!(
+        Node::<TestHasher>::builder()
+            .payload("quake")
+            .build()
+            .id_version(),
+        NodeIdVersion::V1
+    );
+    let mut dag = TestDag::new(BTreeMap::new());
+    assert_eq!(dag.id_version(), NodeIdVersion::V1);
+    assert_eq!(dag.min_id_version(), NodeIdVersion::V1);
+    assert_eq!(dag.add_node("quake", BTreeSet::new()).unwrap(), quake.id());
+}
+
+#[test]
+fn test_dag_rejects_nodes_older_than_its_min_id_version() {
+    let legacy =
+        Node::<TestHasher>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+    let too_old = |result: Result<_, StoreError>| {
+        matches!(
+            result,
+            Err(StoreError::IdVersionTooOld {
+                ref id,
+                minimum: NodeIdVersion::V1,
+                found: NodeIdVersion::V0,
+            }) if id == legacy.id()
+        )
+    };
+    let mut dag = TestDag::new(BTreeMap::new());
+    assert!(too_old(dag.add_nodes([legacy.clone()])));
+    let failure = dag
+        .bulk_load([Ok(legacy.clone())].into_iter(), BulkLoadOpts::default())
+        .unwrap_err();
+    let kinds: Vec<StoreErrorKind> = failure.errors.into_iter().map(|e| e.kind).collect();
+    assert_eq!(kinds, vec![StoreErrorKind::IdVersionTooOld]);
+    assert_eq!(dag.node_count().unwrap(), 0);
+
+    // Nodes of a DAG written before V1 are accepted once the minimum is lowered.
+    dag.set_min_id_version(NodeIdVersion::V0);
+    dag.add_nodes([legacy.clone()]).unwrap();
+    let qualm = dag
+        .add_node("qualm", BTreeSet::from([legacy.id().to_vec()]))
+        .unwrap();
+    assert_eq!(
+        dag.get_node_by_id(&qualm).unwrap().unwrap().id_version(),
+        NodeIdVersion::V1
+    );
+
+    // A DAG adding V0 nodes accepts them.
+    let mut v0 = TestDag::new_with_id_version(BTreeMap::new(), NodeIdVersion::V0);
+    assert_eq!(v0.min_id_version(), NodeIdVersion::V0);
+    assert_eq!(v0.add_node("quake", BTreeSet::new()).unwrap(), legacy.id());
+    let mut v1 = TestDag::new(BTreeMap::new());
+    v1.set_id_version(NodeIdVersion::V0);
+    assert_eq!(v1.min_id_version(), NodeIdVersion::V0);
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_node_id_version_round_trips_through_cbor() {
+    use ciborium::{de::from_reader, ser::into_writer};
+
+    let quake =
+        Node::<TestHasher>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+    let qualm = Node::<TestHasher>::new_with_id_version(
+        "qualm",
+        BTreeSet::from([quake.id().to_vec()]),
+        NodeIdVersion::V1,
+    );
+    let mut v0_buf = Vec::new();
+    into_writer(&quake, &mut v0_buf).unwrap();
+    // V0 nodes keep the encoding they had before ids were versioned.
+    assert!(!v0_buf.windows(10).any(|w| w == b"id_version"));
+    let mut v1_buf = Vec::new();
+    into_writer(&qualm, &mut v1_buf).unwrap();
+    assert!(v1_buf.windows(10).any(|w| w == b"id_version"));
+    for (buf, node) in [(v0_buf, quake), (v1_buf, qualm)] {
+        let node_de: Node<TestHasher> = from_reader(buf.as_slice()).unwrap();
+        assert_eq!(node_de.id(), node.id());
+        assert_eq!(node_de.item_id(), node.item_id());
+        assert_eq!(node_de.id_version(), node.id_version());
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_node_with_unknown_id_version_fails_to_deserialize() {
+    use ciborium::value::Value;
+
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let mut encoded = Value::serialized(&quake).unwrap();
+    if let Value::Map(fields) = &mut encoded {
+        fields.push((
+            Value::Text("id_version".to_owned()),
+            Value::Integer(7.into()),
+        ));
+    }
+    assert!(encoded.deserialized::<Node<TestHasher>>().is_err());
+}
+
+#[test]
+fn test_nodes_without_attributes_keep_their_ids() {
+    let quake =
+        Node::<TestHasher>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+    assert_eq!(hex(quake.id()), "6552b4d9743f015f");
+    let no_attrs = Node::<TestHasher>::new_with_attrs_and_id_version(
+        "quake",
+        BTreeSet::new(),
+        BTreeMap::new(),
+        NodeIdVersion::V0,
+    );
+    assert_eq!(no_attrs.id(), quake.id());
+    assert!(no_attrs.attributes().is_empty());
+    let deps = BTreeSet::from([quake.id().to_vec()]);
+    for version in [NodeIdVersion::V0, NodeIdVersion::V1] {
+        assert_eq!(
+            Node::<TestHasher>::new_with_attrs_and_id_version(
+                "qualm",
+                deps.clone(),
+                BTreeMap::new(),
+                version
+            )
+            .id(),
+            Node::<TestHasher>::new_with_id_version("qualm", deps.clone(), version).id()
+        );
+    }
+}
+
+#[test]
+fn test_attributes_are_part_of_the_id() {
+    let attrs = |pairs: &[(&str, &[u8])]| -> BTreeMap<String, Vec<u8>> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_vec()))
+            .collect()
+    };
+    let plain = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let authored = Node::<TestHasher>::new_with_attrs(
+        "quake",
+        BTreeSet::new(),
+        attrs(&[("author", b"jwall"), ("op", b"put")]),
+    );
+    assert_ne!(authored.id(), plain.id());
+    // The item id only covers the payload.
+    assert_eq!(authored.item_id(), plain.item_id());
+    assert_eq!(
+        authored.attributes().get("author").map(Vec::as_slice),
+        Some(&b"jwall"[..])
+    );
+    // Moving bytes between a key and its value or between attributes changes the id.
+    let shifted = [
+        attrs(&[("authorj", b"wall"), ("op", b"put")]),
+        attrs(&[("author", b"jwallop"), ("", b"put")]),
+        attrs(&[("author", b"jwall")]),
+        attrs(&[("author", b"jwall"), ("op", b"")]),
+    ];
+    for attributes in shifted {
+        let node = Node::<TestHasher>::new_with_attrs("quake", BTreeSet::new(), attributes);
+        assert_ne!(node.id(), authored.id());
+    }
+    let v0 = Node::<TestHasher>::new_with_attrs_and_id_version(
+        "quake",
+        BTreeSet::new(),
+        authored.attributes().clone(),
+        NodeIdVersion::V0,
+    );
+    assert_ne!(v0.id(), authored.id());
+}
+
+#[test]
+fn test_add_node_with_attrs() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let attributes = BTreeMap::from([("op".to_owned(), b"merge".to_vec())]);
+    let qualm = dag
+        .add_node_with_attrs("qualm", BTreeSet::from([quake.clone()]), attributes.clone())
+        .unwrap();
+    assert_eq!(
+        dag.add_node_with_attrs("qualm", BTreeSet::from([quake.clone()]), attributes.clone())
+            .unwrap(),
+        qualm
+    );
+    let node = dag.get_node_by_id(&qualm).unwrap().unwrap();
+    assert_eq!(node.attributes(), &attributes);
+    assert_eq!(
+        node.id(),
+        Node::<TestHasher>::new_with_attrs("qualm", BTreeSet::from([quake]), attributes).id()
+    );
+    assert_eq!(dag.get_roots(), &BTreeSet::from([qualm]));
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_attributes_round_trip_through_cbor() {
+    use ciborium::{de::from_reader, ser::into_writer};
+
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let qualm = Node::<TestHasher>::new_with_attrs(
+        "qualm",
+        BTreeSet::from([quake.id().to_vec()]),
+        BTreeMap::from([
+            ("author".to_owned(), b"jwall".to_vec()),
+            ("at".to_owned(), 1_700_000_000u64.to_le_bytes().to_vec()),
+        ]),
+    );
+    let mut plain_buf = Vec::new();
+    into_writer(&quake, &mut plain_buf).unwrap();
+    // Nodes without attributes keep the encoding they had before attributes existed.
+    assert!(!plain_buf.windows(10).any(|w| w == b"attributes"));
+    let mut attr_buf = Vec::new();
+    into_writer(&qualm, &mut attr_buf).unwrap();
+    assert!(attr_buf.windows(10).any(|w| w == b"attributes"));
+    for (buf, node) in [(plain_buf, quake), (attr_buf, qualm)] {
+        let node_de: Node<TestHasher> = from_reader(buf.as_slice()).unwrap();
+        assert_eq!(node_de.id(), node.id());
+        assert_eq!(node_de.attributes(), node.attributes());
+    }
+}
+
+#[test]
+fn test_node_builder_matches_node_new() {
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let qualm = Node::<TestHasher>::new("qualm", BTreeSet::new());
+    let built = Node::<TestHasher>::builder().payload("quake").build();
+    assert_eq!(built.id(), quake.id());
+
+    let deps = BTreeSet::from([quake.id().to_vec(), qualm.id().to_vec()]);
+    let expected = Node::<TestHasher>::new("quell", deps.clone());
+    let built = NodeBuilder::<TestHasher>::new()
+        .payload("quell")
+        .depends_on(quake.id())
+        .depends_on(qualm.id())
+        .build();
+    assert_eq!(built.id(), expected.id());
+    assert_eq!(built.dependency_ids(), expected.dependency_ids());
+    // Repeated dependency ids are kept once.
+    let built = NodeBuilder::<TestHasher>::new()
+        .depends_on_all(deps.iter().cloned())
+        .depends_on(quake.id())
+        .depends_on_all([qualm.id(), quake.id()])
+        .payload("quell")
+        .build();
+    assert_eq!(built.id(), expected.id());
+    assert_eq!(built.out_degree(), 2);
+
+    let attributes = BTreeMap::from([("op".to_owned(), b"put".to_vec())]);
+    let built = Node::<TestHasher>::builder()
+        .payload("quill")
+        .depends_on(quake.id())
+        .attribute("op", "put")
+        .id_version(NodeIdVersion::V1)
+        .build();
+    let expected = Node::<TestHasher>::new_with_attrs_and_id_version(
+        "quill",
+        BTreeSet::from([quake.id().to_vec()]),
+        attributes,
+        NodeIdVersion::V1,
+    );
+    assert_eq!(built.id(), expected.id());
+    assert_eq!(built.attributes(), expected.attributes());
+}
+
+#[test]
+fn test_node_builder_allows_an_empty_payload() {
+    let built = Node::<TestHasher>::builder().build();
+    assert!(built.item().is_empty());
+    assert_eq!(
+        built.id(),
+        Node::<TestHasher>::new(Vec::new(), BTreeSet::new()).id()
+    );
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let built = Node::<TestHasher>::builder()
+        .payload("ignored")
+        .payload("")
+        .depends_on(quake.id())
+        .build();
+    assert!(built.item().is_empty());
+    assert_eq!(
+        built.id(),
+        Node::<TestHasher>::new("", BTreeSet::from([quake.id().to_vec()])).id()
+    );
+}
+
+#[test]
+fn test_dag_insert() {
+    let mut dag = TestDag::new_with_id_version(BTreeMap::new(), NodeIdVersion::V1);
+    let quake = dag.insert().payload("quake").commit().unwrap();
+    let qualm = dag.add_node("qualm", BTreeSet::new()).unwrap();
+    let quell = dag
+        .insert()
+        .payload("quell")
+        .depends_on(quake.clone())
+        .depends_on_all([&qualm, &quake])
+        .attribute("author", "jwall")
+        .commit()
+        .unwrap();
+    let expected = Node::<TestHasher>::builder()
+        .payload("quell")
+        .depends_on_all([&quake, &qualm])
+        .attribute("author", "jwall")
+        .id_version(NodeIdVersion::V1)
+        .build();
+    assert_eq!(quell, expected.id());
+    assert_eq!(dag.get_roots(), &BTreeSet::from([quell.clone()]));
+    assert_eq!(
+        dag.get_node_by_id(&quell).unwrap().unwrap().attributes(),
+        expected.attributes()
+    );
+    // Inserting the same node again returns the same id.
+    assert_eq!(
+        dag.insert()
+            .attribute("author", "jwall")
+            .depends_on_all([&qualm, &quake])
+            .payload("quell")
+            .commit()
+            .unwrap(),
+        quell
+    );
+    // Dependencies are validated like add_node.
+    let missing = Node::<TestHasher>::new("missing", BTreeSet::new());
+    assert!(matches!(
+        dag.insert()
+            .payload("quill")
+            .depends_on(missing.id())
+            .commit(),
+        Err(StoreError::NoSuchDependents)
+    ));
+    assert!(matches!(
+        dag.insert().depends_on(&quake[1..]).commit(),
+        Err(StoreError::MalformedId { .. })
+    ));
+    assert_eq!(dag.get_roots(), &BTreeSet::from([quell]));
+}
+
+// A BlobStore counting its reads.
+#[derive(Default)]
+struct CountingBlobs {
+    blobs: BTreeMap<Vec<u8>, Vec<u8>>,
+    reads: std::cell::Cell<usize>,
+}
+
+impl BlobStore for CountingBlobs {
+    fn put_blob(&mut self, item_id: &[u8], bytes: Vec<u8>) -> Result<(), StoreError> {
+        self.blobs.put_blob(item_id, bytes)
+    }
+
+    fn get_blob(&self, item_id: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        self.reads.set(self.reads.get() + 1);
+        self.blobs.get_blob(item_id)
+    }
+
+    fn delete_blob(&mut self, item_id: &[u8]) -> Result<(), StoreError> {
+        self.blobs.delete_blob(item_id)
+    }
+}
+
+type DetachedDag =
+    Merkle<DetachedStore<BTreeMap<Vec<u8>, Node<TestHasher>>, CountingBlobs>, TestHasher>;
+
+fn check_detached_ids_match_inline(id_version: NodeIdVersion) {
+    let mut inline = TestDag::new_with_id_version(BTreeMap::new(), id_version);
+    let mut detached = DetachedDag::new_with_id_version(Default::default(), id_version);
+    let chunk = vec![7u8; 64 * 1024];
+    let inline_quake = inline.add_node(chunk.clone(), BTreeSet::new()).unwrap();
+    let quake = detached
+        .add_detached(chunk.clone(), BTreeSet::new())
+        .unwrap();
+    assert_eq!(quake, inline_quake);
+    let inline_qualm = inline
+        .add_node("qualm", BTreeSet::from([quake.clone()]))
+        .unwrap();
+    let qualm = detached
+        .add_detached("qualm", BTreeSet::from([quake.clone()]))
+        .unwrap();
+    assert_eq!(qualm, inline_qualm);
+    // An attached node can depend on a detached one.
+    let quell = detached
+        .add_node("quell", BTreeSet::from([qualm.clone()]))
+        .unwrap();
+    assert_eq!(
+        quell,
+        inline
+            .add_node("quell", BTreeSet::from([qualm.clone()]))
+            .unwrap()
+    );
+    assert_eq!(detached.get_roots(), inline.get_roots());
+
+    let node = detached.get_node_by_id(&quake).unwrap().unwrap();
+    assert!(node.is_detached());
+    assert!(node.item().is_empty());
+    assert_eq!(
+        node.item_id(),
+        inline.get_node_by_id(&quake).unwrap().unwrap().item_id()
+    );
+    // Adding the same bytes again in either mode finds the stored node.
+    assert_eq!(
+        detached.add_node(chunk.clone(), BTreeSet::new()).unwrap(),
+        quake
+    );
+    assert_eq!(
+        detached
+            .add_detached(chunk.clone(), BTreeSet::new())
+            .unwrap(),
+        quake
+    );
+    assert!(detached
+        .get_node_by_id(&quake)
+        .unwrap()
+        .unwrap()
+        .is_detached());
+
+    // Walking the graph doesn't read a blob.
+    assert_eq!(
+        detached.compare(&quake, &quell).unwrap(),
+        NodeCompare::Before
+    );
+    assert_eq!(
+        detached.ancestors_of(&quell).unwrap(),
+        BTreeSet::from([quake.clone(), qualm.clone()])
+    );
+    assert_eq!(detached.get_nodes().blobs().reads.get(), 0);
+
+    assert_eq!(detached.get_item(&quake).unwrap(), Some(chunk));
+    assert_eq!(detached.get_item(&qualm).unwrap(), Some(b"qualm".to_vec()));
+    assert_eq!(detached.get_nodes().blobs().reads.get(), 2);
+    assert_eq!(detached.get_item(&quell).unwrap(), Some(b"quell".to_vec()));
+    assert_eq!(detached.get_nodes().blobs().reads.get(), 2);
+    let absent = Node::<TestHasher>::new("absent", BTreeSet::new());
+    assert_eq!(detached.get_item(absent.id()).unwrap(), None);
+}
+
+#[test]
+fn test_detached_ids_match_inline() {
+    check_detached_ids_match_inline(NodeIdVersion::V0);
+    check_detached_ids_match_inline(NodeIdVersion::V1);
+}
+
+#[test]
+fn test_detached_item_is_checked_when_fetched() {
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let (slim, _) = quake.clone().into_detached();
+    let nodes = BTreeMap::from([(quake.id().to_vec(), slim)]);
+    let mut blobs = CountingBlobs::default();
+    blobs.put_blob(quake.item_id(), b"quack".to_vec()).unwrap();
+    let dag = DetachedDag::from_store(DetachedStore::new(nodes.clone(), blobs)).unwrap();
+    assert!(matches!(
+        dag.get_item(quake.id()),
+        Err(StoreError::CorruptNode { id }) if id == quake.id()
+    ));
+    let dag = DetachedDag::from_store(DetachedStore::new(nodes, CountingBlobs::default())).unwrap();
+    assert!(matches!(
+        dag.get_item(quake.id()),
+        Err(StoreError::CorruptNode { .. })
+    ));
+}
+
+#[test]
+fn test_detached_records_are_refused_unverified() {
+    use crate::store::VerifyingStore;
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let (slim, _) =
+        Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()])).into_detached();
+    // A detached record claiming the id of another node.
+    let mut forged = slim.clone();
+    *forged.id_mut() = Node::<TestHasher>::new("quell", BTreeSet::new())
+        .id()
+        .to_vec();
+    let mut dag = TestDag::new(BTreeMap::new());
+    dag.add_node("quake", BTreeSet::new()).unwrap();
+    assert!(matches!(
+        dag.add_nodes([forged.clone()]),
+        Err(StoreError::InvalidNode(NodeIntegrityError::DetachedItem { id })) if id == forged.id()
+    ));
+    assert_eq!(dag.node_count().unwrap(), 1);
+
+    let store = VerifyingStore::new(BTreeMap::from([(slim.id().to_vec(), slim.clone())]));
+    assert!(matches!(
+        Store::<TestHasher>::get(&store, slim.id()),
+        Err(StoreError::InvalidNode(
+            NodeIntegrityError::DetachedItem { .. }
+        ))
+    ));
+}
+
+#[test]
+fn test_removing_detached_nodes_deletes_unshared_blobs() {
+    let mut dag = DetachedDag::new(Default::default());
+    let quake = dag.add_detached("quake", BTreeSet::new()).unwrap();
+    // Both carry the same item under different dependencies so they share a blob.
+    let qualm = dag
+        .add_detached("shared", BTreeSet::from([quake.clone()]))
+        .unwrap();
+    let quell = dag.add_detached("shared", BTreeSet::new()).unwrap();
+    let quake_item = dag
+        .get_node_by_id(&quake)
+        .unwrap()
+        .unwrap()
+        .item_id()
+        .to_vec();
+    let shared_item = dag
+        .get_node_by_id(&qualm)
+        .unwrap()
+        .unwrap()
+        .item_id()
+        .to_vec();
+    assert_eq!(dag.get_nodes().blobs().blobs.len(), 2);
+
+    dag.remove_node(&quell, RemoveScope::Node).unwrap();
+    assert!(dag.get_nodes().blobs().blobs.contains_key(&shared_item));
+    assert_eq!(dag.get_item(&qualm).unwrap(), Some(b"shared".to_vec()));
+
+    dag.retain_reachable(&BTreeSet::new()).unwrap();
+    assert_eq!(dag.node_count().unwrap(), 0);
+    assert!(dag.get_nodes().blobs().blobs.is_empty());
+    assert!(!dag.get_nodes().blobs().blobs.contains_key(&quake_item));
+}
+
+#[test]
+fn test_detached_add_writes_no_blob_on_failure() {
+    let mut dag = DetachedDag::new(Default::default());
+    let missing = Node::<TestHasher>::new("missing", BTreeSet::new());
+    assert!(matches!(
+        dag.add_detached("quake", BTreeSet::from([missing.id().to_vec()])),
+        Err(StoreError::NoSuchDependents)
+    ));
+    assert!(dag.get_nodes().blobs().blobs.is_empty());
+}
+
+// Deterministic bytes that don't repeat at short periods.
+fn chunk_content(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(2_654_435_761).wrapping_add(1);
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+fn read_all<R: std::io::Read>(mut reader: R) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).unwrap();
+    bytes
+}
+
+#[test]
+fn test_add_chunked_round_trips() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let base = dag.add_node("base", BTreeSet::new()).unwrap();
+    let content = chunk_content(10_500, 1);
+    let manifest = dag
+        .add_chunked(content.as_slice(), 1000, BTreeSet::from([base.clone()]))
+        .unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([manifest.clone()]));
+    let node = dag.get_node_by_id(&manifest).unwrap().unwrap();
+    // 11 chunks and the dependency passed in.
+    assert_eq!(node.out_degree(), 12);
+    assert!(node.dependency_ids().contains(&base));
+    for chunk in content.chunks(1000) {
+        let leaf = Node::<TestHasher>::new(chunk, BTreeSet::new());
+        assert!(node.dependency_ids().contains(leaf.id()));
+    }
+    let reader = dag.read_chunked(&manifest).unwrap();
+    assert_eq!(reader.len(), content.len() as u64);
+    assert_eq!(read_all(reader), content);
+    // Small reads see the same bytes.
+    let mut reader = dag.read_chunked(&manifest).unwrap();
+    let mut read = Vec::new();
+    let mut buf = [0u8; 7];
+    loop {
+        let n = std::io::Read::read(&mut reader, &mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        read.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(read, content);
+}
+
+#[test]
+fn test_add_chunked_dedups_identical_content() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let content = chunk_content(4096, 2);
+    let manifest = dag
+        .add_chunked(content.as_slice(), 512, BTreeSet::new())
+        .unwrap();
+    let count = dag.node_count().unwrap();
+    assert_eq!(count, 9);
+    assert_eq!(
+        dag.add_chunked(content.as_slice(), 512, BTreeSet::new())
+            .unwrap(),
+        manifest
+    );
+    assert_eq!(dag.node_count().unwrap(), count);
+    // Content sharing its first chunks shares their nodes.
+    let mut extended = content.clone();
+    extended.extend_from_slice(&chunk_content(100, 3));
+    let other = dag
+        .add_chunked(extended.as_slice(), 512, BTreeSet::new())
+        .unwrap();
+    assert_ne!(other, manifest);
+    assert_eq!(dag.node_count().unwrap(), count + 2);
+    assert_eq!(read_all(dag.read_chunked(&other).unwrap()), extended);
+    assert_eq!(dag.get_roots(), &BTreeSet::from([manifest, other]));
+}
+
+#[test]
+fn test_add_chunked_builds_a_manifest_tree() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let chunks = MANIFEST_FANOUT * 2 + 3;
+    let content = chunk_content(chunks * 4, 4);
+    let manifest = dag
+        .add_chunked(content.as_slice(), 4, BTreeSet::new())
+        .unwrap();
+    let node = dag.get_node_by_id(&manifest).unwrap().unwrap();
+    // Three manifests of chunks under the top manifest.
+    assert_eq!(node.out_degree(), 3);
+    assert_eq!(dag.get_roots(), &BTreeSet::from([manifest.clone()]));
+    assert_eq!(dag.node_count().unwrap(), chunks + 4);
+    assert_eq!(read_all(dag.read_chunked(&manifest).unwrap()), content);
+}
+
+#[test]
+fn test_add_chunked_edge_cases() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let empty = dag.add_chunked(&[][..], 16, BTreeSet::new()).unwrap();
+    let reader = dag.read_chunked(&empty).unwrap();
+    assert!(reader.is_empty());
+    assert!(read_all(reader).is_empty());
+    // A payload of exactly one chunk.
+    let one = dag.add_chunked(&b"0123"[..], 4, BTreeSet::new()).unwrap();
+    assert_eq!(read_all(dag.read_chunked(&one).unwrap()), b"0123");
+    assert!(matches!(
+        dag.add_chunked(&b"0123"[..], 0, BTreeSet::new()),
+        Err(StoreError::StoreFailure(_))
+    ));
+    let chunk = Node::<TestHasher>::new("0123", BTreeSet::new());
+    assert!(matches!(
+        dag.read_chunked(chunk.id()),
+        Err(StoreError::StoreFailure(_))
+    ));
+    let absent = Node::<TestHasher>::new("absent", BTreeSet::new());
+    assert!(matches!(
+        dag.read_chunked(absent.id()),
+        Err(StoreError::NoSuchNode(_))
+    ));
+}
+
+// Callers never look at a DAG again after it panicked.
+fn panic_message<F: FnOnce()>(f: F) -> String {
+    let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_err();
+    err.downcast_ref::<String>().cloned().unwrap_or_default()
+}
+
+#[test]
+fn test_assert_invariants_on_valid_dag() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    dag.add_node("qualm", BTreeSet::from([quake_node_id]))
+        .unwrap();
+    dag.add_node("quell", BTreeSet::new()).unwrap();
+    dag.assert_invariants(Thoroughness::Roots).unwrap();
+    dag.assert_invariants(Thoroughness::Full).unwrap();
+}
+
+#[test]
+fn test_assert_invariants_detects_missing_root() {
+    let missing = Node::<TestHasher>::new("missing", BTreeSet::new());
+    let mut dag = TestDag::new(BTreeMap::new());
+    dag.add_node("quake", BTreeSet::new()).unwrap();
+    dag.roots_mut().insert(missing.id().to_vec());
+    let msg = panic_message(|| {
+        dag.assert_invariants(Thoroughness::Roots).unwrap();
+    });
+    assert!(msg.contains("root missing from store"));
+    assert!(msg.contains(&hex(missing.id())));
+}
+
+#[test]
+fn test_assert_invariants_detects_dangling_dependency() {
+    let missing = Node::<TestHasher>::new("missing", BTreeSet::new());
+    let dangling = Node::<TestHasher>::new("dangling", BTreeSet::from([missing.id().to_vec()]));
+    let mut dag = TestDag::new(BTreeMap::new());
+    dag.add_node("quake", BTreeSet::new()).unwrap();
+    dag.nodes_mut().store(dangling.clone()).unwrap();
+    dag.roots_mut().insert(dangling.id().to_vec());
+    dag.assert_invariants(Thoroughness::Roots).unwrap();
+    let msg = panic_message(|| {
+        dag.assert_invariants(Thoroughness::Full).unwrap();
+    });
+    assert!(msg.contains("dangling dependency"));
+    assert!(msg.contains(&format!("{}, {}", hex(dangling.id()), hex(missing.id()))));
+}
+
+#[cfg(feature = "debug-invariants")]
+#[test]
+fn test_sampled_invariant_check_catches_dangling_dependency() {
+    let missing = Node::<TestHasher>::new("missing", BTreeSet::new());
+    let dangling = Node::<TestHasher>::new("dangling", BTreeSet::from([missing.id().to_vec()]));
+    let mut dag = TestDag::new(BTreeMap::new());
+    dag.nodes_mut().store(dangling.clone()).unwrap();
+    dag.roots_mut().insert(dangling.id().to_vec());
+    let msg = panic_message(move || {
+        for idx in 0..INVARIANT_SAMPLE_INTERVAL {
+            dag.add_node(format!("node-{}", idx), BTreeSet::new())
+                .unwrap();
+        }
+    });
+    assert!(msg.contains("after add_node: dangling dependency"));
+    assert!(msg.contains(&hex(missing.id())));
+}
+
+#[cfg(feature = "debug-invariants")]
+#[test]
+fn test_sampled_invariant_check_reaches_deep_ancestors() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let mut chain = vec![dag.add_node("link-0", BTreeSet::new()).unwrap()];
+    for idx in 1..200 {
+        let deps = BTreeSet::from([chain[idx - 1].clone()]);
+        chain.push(dag.add_node(format!("link-{}", idx), deps).unwrap());
+    }
+    dag.nodes_mut().delete(&chain[10]).unwrap();
+    let msg = panic_message(move || {
+        for idx in 0..INVARIANT_SAMPLE_INTERVAL * 16 {
+            dag.add_node(format!("node-{}", idx), BTreeSet::new())
+                .unwrap();
+        }
+    });
+    assert!(msg.contains("after add_node: dangling dependency"));
+    assert!(msg.contains(&format!("{}, {}", hex(&chain[11]), hex(&chain[10]))));
+}
+
+fn seeded_prefix_dag() -> (TestDag<'static>, Vec<Vec<u8>>) {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let ids = (0..300)
+        .map(|idx| {
+            dag.add_node(format!("seed-{}", idx), BTreeSet::new())
+                .unwrap()
+        })
+        .collect();
+    (dag, ids)
+}
+
+// Checks the id length audit and quarantine of a Store holding a foreign Blake2 id among
+// TestHasher ids.
+#[cfg(feature = "blake2")]
+fn check_quarantine_foreign_ids<S>(store: S)
+where
+    S: Store<TestHasher> + Store<crate::blake2::Blake2b512>,
+{
+    let mut dag = Merkle::<S, TestHasher>::new(store);
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm_node_id = dag
+        .add_node("qualm", BTreeSet::from([quake_node_id.clone()]))
+        .unwrap();
+    let quell_node_id = dag
+        .add_node("quell", BTreeSet::from([qualm_node_id.clone()]))
+        .unwrap();
+    let foreign = Node::<crate::blake2::Blake2b512>::new("foreign", BTreeSet::new());
+    let foreign_id = foreign.id().to_vec();
+    Store::<crate::blake2::Blake2b512>::store(dag.nodes_mut(), foreign).unwrap();
+
+    let report = dag.audit_id_uniformity().unwrap();
+    assert_eq!(report.expected_len, 8);
+    assert_eq!(report.histogram, BTreeMap::from([(8, 3), (64, 1)]));
+    assert_eq!(report.foreign(), BTreeMap::from([(64, 1)]));
+    assert!(!report.is_uniform());
+    match dag.ensure_uniform_ids() {
+        Err(StoreError::NonUniformIds {
+            expected_len,
+            foreign,
+        }) => {
+            assert_eq!(expected_len, 8);
+            assert_eq!(foreign, BTreeMap::from([(64, 1)]));
+        }
+        result => panic!("expected NonUniformIds got {:?}", result),
+    }
+
+    assert_eq!(dag.quarantine_foreign_ids().unwrap(), 1);
+    let report = dag.audit_id_uniformity().unwrap();
+    assert_eq!(report.histogram, BTreeMap::from([(8, 3)]));
+    assert!(dag.ensure_uniform_ids().is_ok());
+    assert!(matches!(
+        dag.check_for_node(&foreign_id),
+        Err(StoreError::MalformedId {
+            expected: 8,
+            actual: 64
+        })
+    ));
+    let record = dag.get_quarantined(&foreign_id).unwrap().unwrap();
+    let quarantined: Node<crate::blake2::Blake2b512> =
+        crate::store::codec::decode(&record).unwrap();
+    assert_eq!(quarantined.id(), foreign_id.as_slice());
+    assert_eq!(quarantined.item(), b"foreign");
+    assert!(dag.get_quarantined(&quake_node_id).unwrap().is_none());
+    assert_eq!(dag.quarantine_foreign_ids().unwrap(), 0);
+
+    // Sharding the ids by their first byte partitions exactly the remaining nodes.
+    let mut sharded = Vec::new();
+    for shard in 0..=u8::MAX {
+        sharded.extend(
+            Store::<TestHasher>::find_by_prefix(dag.get_nodes(), &[shard], usize::MAX).unwrap(),
+        );
+    }
+    let mut expected = vec![quake_node_id, qualm_node_id, quell_node_id];
+    expected.sort();
+    assert_eq!(sharded, expected);
+    let ids: Vec<Vec<u8>> = Store::<TestHasher>::ids(dag.get_nodes())
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(ids, expected);
+    assert_eq!(dag.node_count().unwrap(), 3);
+}
+
+#[test]
+fn test_uniform_store_passes_id_audit() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    dag.add_node("qualm", BTreeSet::from([quake_node_id]))
+        .unwrap();
+    let report = dag.audit_id_uniformity().unwrap();
+    assert_eq!(report.histogram, BTreeMap::from([(8, 2)]));
+    assert!(report.foreign().is_empty());
+    assert!(dag.ensure_uniform_ids().is_ok());
+    assert!(matches!(
+        dag.quarantine_foreign_ids(),
+        Err(StoreError::Unsupported("quarantine_foreign_keys"))
+    ));
+}
+
+// Checks a Store's find_by_prefix against a brute force filter of the known ids.
+fn check_find_by_prefix<S: Store<TestHasher>>(mut store: S) {
+    let mut ids = BTreeSet::new();
+    for idx in 0..100 {
+        let node = Node::<TestHasher>::new(format!("prefix-{}", idx), BTreeSet::new());
+        ids.insert(node.id().to_vec());
+        store.store(node).unwrap();
+    }
+    for id in ids.iter() {
+        for len in [0, 1, 2, id.len()] {
+            let prefix = &id[0..len];
+            let expected: Vec<Vec<u8>> = ids
+                .iter()
+                .filter(|id| id.starts_with(prefix))
+                .cloned()
+                .collect();
+            assert_eq!(store.find_by_prefix(prefix, 1000).unwrap(), expected);
+            assert_eq!(
+                store.find_by_prefix(prefix, 1).unwrap(),
+                expected[0..1].to_vec()
+            );
+        }
+    }
+    assert!(store.find_by_prefix(&[0xff; 9], 10).unwrap().is_empty());
+
+    let dag = Merkle::<S, TestHasher>::new(store);
+    for id in ids.iter() {
+        for len in [1, 2, id.len()] {
+            let matching: Vec<Vec<u8>> = ids
+                .iter()
+                .filter(|other| other.starts_with(&id[0..len]))
+                .take(8)
+                .cloned()
+                .collect();
+            match dag.resolve_prefix(&id[0..len]) {
+                Ok(Some(found)) => assert_eq!(vec![found], matching),
+                Err(StoreError::AmbiguousPrefix { prefix, candidates }) => {
+                    assert_eq!(prefix, &id[0..len]);
+                    assert!(candidates.len() > 1);
+                    assert_eq!(candidates, matching);
+                }
+                result => panic!("unexpected resolution {:?}", result),
+            }
+        }
+    }
+    // The empty prefix matches everything.
+    assert!(matches!(
+        dag.resolve_prefix(b""),
+        Err(StoreError::AmbiguousPrefix { .. })
+    ));
+    assert_eq!(dag.resolve_prefix(&[0xff; 9]).unwrap(), None);
+}
+
+#[test]
+fn test_btree_store_find_by_prefix() {
+    check_find_by_prefix(BTreeStore::<TestHasher>::new());
+}
+
+#[test]
+fn test_hash_store_find_by_prefix() {
+    check_find_by_prefix(HashStore::<TestHasher>::new());
+}
+
+// Checks that collecting garbage only removes the branches the kept roots don't reach.
+fn check_retain_reachable<S: Store<TestHasher>>(store: S) {
+    let mut dag = Merkle::<S, TestHasher>::new(store);
+    let base = dag.add_node("base", BTreeSet::new()).unwrap();
+    let main = dag
+        .add_node("main", BTreeSet::from([base.clone()]))
+        .unwrap();
+    let tip = dag.add_node("tip", BTreeSet::from([main.clone()])).unwrap();
+    let experiment = dag
+        .add_node("experiment", BTreeSet::from([base.clone()]))
+        .unwrap();
+    let abandoned = dag
+        .add_node(
+            "abandoned",
+            BTreeSet::from([experiment.clone(), main.clone()]),
+        )
+        .unwrap();
+    let orphan = dag.add_node("orphan", BTreeSet::new()).unwrap();
+
+    let unknown = Node::<TestHasher>::new("unknown", BTreeSet::new())
+        .id()
+        .to_vec();
+    let err = dag
+        .retain_reachable(&BTreeSet::from([tip.clone(), unknown.clone()]))
+        .unwrap_err();
+    assert!(matches!(err, StoreError::NoSuchNode(id) if id == unknown));
+    assert_eq!(dag.node_count().unwrap(), 6);
+
+    let report = dag
+        .retain_reachable(&BTreeSet::from([tip.clone()]))
+        .unwrap();
+    assert_eq!(
+        report,
+        GcReport {
+            kept: 3,
+            removed: 3
+        }
+    );
+    for id in [&base, &main, &tip] {
+        assert!(dag.check_for_node(id).unwrap());
+    }
+    for id in [&experiment, &abandoned, &orphan] {
+        assert!(!dag.check_for_node(id).unwrap());
+    }
+    assert_eq!(dag.get_roots(), &BTreeSet::from([tip.clone()]));
+
+    // Keeping an ancestor of the only root makes it a root again.
+    let report = dag
+        .retain_reachable(&BTreeSet::from([main.clone()]))
+        .unwrap();
+    assert_eq!(
+        report,
+        GcReport {
+            kept: 2,
+            removed: 1
+        }
+    );
+    assert_eq!(dag.get_roots(), &BTreeSet::from([main]));
+}
+
+#[test]
+fn test_btree_store_retain_reachable() {
+    check_retain_reachable(BTreeStore::<TestHasher>::new());
+}
+
+#[test]
+fn test_hash_store_retain_reachable() {
+    check_retain_reachable(HashStore::<TestHasher>::new());
+}
+
+#[test]
+fn test_traversals_report_missing_dependencies() {
+    let missing = Node::<TestHasher>::new("missing", BTreeSet::new());
+    let dangling = Node::<TestHasher>::new("dangling", BTreeSet::from([missing.id().to_vec()]));
+    let mut dag = TestDag::new(BTreeMap::new());
+    dag.nodes_mut().store(dangling.clone()).unwrap();
+    dag.roots_mut().insert(dangling.id().to_vec());
+    let no_such_missing = |err: StoreError| {
+        assert!(matches!(err, StoreError::NoSuchNode(id) if id == missing.id()));
+    };
+    no_such_missing(dag.ancestors_handles(dangling.id()).unwrap_err());
+    no_such_missing(
+        dag.retain_reachable(&BTreeSet::from([dangling.id().to_vec()]))
+            .unwrap_err(),
+    );
+    no_such_missing(
+        dag.remove_node(dangling.id(), RemoveScope::Node)
+            .unwrap_err(),
+    );
+    assert!(dag.check_for_node(dangling.id()).unwrap());
+}
+
+#[test]
+fn test_retain_reachable_keeps_pinned_subgraphs() {
+    let (mut dag, ids) = TestDag::from_text(
+        r#"
+base: "base"
+tip(base): "tip"
+kept(base): "kept"
+pinned(kept): "pinned"
+dropped(kept): "dropped"
+orphan: "orphan"
+"#,
+    )
+    .unwrap();
+    let missing = Node::<TestHasher>::new("missing", BTreeSet::new())
+        .id()
+        .to_vec();
+    assert!(matches!(
+        dag.pin(&missing),
+        Err(StoreError::NoSuchNode(id)) if id == missing
+    ));
+    dag.pin(&ids["pinned"]).unwrap();
+    dag.pin(&ids["orphan"]).unwrap();
+    assert!(dag.unpin(&ids["orphan"]).unwrap());
+    assert!(!dag.unpin(&ids["orphan"]).unwrap());
+    assert_eq!(dag.pins(), &BTreeSet::from([ids["pinned"].clone()]));
+
+    let report = dag
+        .retain_reachable(&BTreeSet::from([ids["tip"].clone()]))
+        .unwrap();
+    assert_eq!(
+        report,
+        GcReport {
+            kept: 4,
+            removed: 2
+        }
+    );
+    for name in ["base", "tip", "kept", "pinned"] {
+        assert!(dag.check_for_node(&ids[name]).unwrap());
+    }
+    for name in ["dropped", "orphan"] {
+        assert!(!dag.check_for_node(&ids[name]).unwrap());
+    }
+    assert_eq!(
+        dag.get_roots(),
+        &BTreeSet::from([ids["tip"].clone(), ids["pinned"].clone()])
+    );
+}
+
+// Checks removing nodes through a DAG backed by the store keeps the roots consistent.
+fn check_remove_node<S: Store<TestHasher>>(store: S) {
+    let mut dag = Merkle::<S, TestHasher>::new(store);
+    let base = dag.add_node("base", BTreeSet::new()).unwrap();
+    let left = dag
+        .add_node("left", BTreeSet::from([base.clone()]))
+        .unwrap();
+    let right = dag
+        .add_node("right", BTreeSet::from([base.clone()]))
+        .unwrap();
+    let tip = dag.add_node("tip", BTreeSet::from([left.clone()])).unwrap();
+    let lone = dag.add_node("lone", BTreeSet::new()).unwrap();
+
+    // A leaf nothing depends on.
+    assert_eq!(
+        dag.remove_node(&lone, RemoveScope::Node).unwrap(),
+        BTreeSet::from([lone.clone()])
+    );
+    assert!(!dag.check_for_node(&lone).unwrap());
+    assert_eq!(
+        dag.get_roots(),
+        &BTreeSet::from([tip.clone(), right.clone()])
+    );
+
+    // Other nodes still depend on the base.
+    let err = dag.remove_node(&base, RemoveScope::Node).unwrap_err();
+    assert!(matches!(err, StoreError::HasDescendants(id) if id == base));
+    assert!(dag.check_for_node(&base).unwrap());
+
+    // Removing a root promotes its dependency.
+    dag.remove_node(&tip, RemoveScope::Node).unwrap();
+    assert_eq!(
+        dag.get_roots(),
+        &BTreeSet::from([left.clone(), right.clone()])
+    );
+
+    // The base is not promoted while the left node depends on it.
+    dag.remove_node(&right, RemoveScope::Node).unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([left.clone()]));
+
+    assert_eq!(
+        dag.remove_node(&base, RemoveScope::WithDescendants)
+            .unwrap(),
+        BTreeSet::from([base.clone(), left.clone()])
+    );
+    assert!(dag.get_roots().is_empty());
+    for id in [base, left, right, tip] {
+        assert!(!dag.check_for_node(&id).unwrap());
+    }
+    assert!(matches!(
+        dag.remove_node(&lone, RemoveScope::Node),
+        Err(StoreError::NoSuchNode(_))
+    ));
+}
+
+#[test]
+fn test_btree_store_remove_node() {
+    check_remove_node(BTreeStore::<TestHasher>::new());
+}
+
+#[test]
+fn test_hash_store_remove_node() {
+    check_remove_node(HashStore::<TestHasher>::new());
+}
+
+// Checks that Merkle::add_nodes through the store leaves the same nodes and roots as adding
+// them one at a time.
+fn check_add_nodes<S: Store<TestHasher>>(sequential: S, bulk: S) {
+    let mut sequential = Merkle::<S, TestHasher>::new(sequential);
+    let mut nodes: Vec<Node<TestHasher>> = Vec::new();
+    for idx in 0..3000_usize {
+        let mut deps = BTreeSet::new();
+        // A few separate chains that merge every so often.
+        if idx >= 3 {
+            deps.insert(nodes[idx - 3].id().to_vec());
+        }
+        if idx % 50 == 49 {
+            deps.insert(nodes[idx / 2].id().to_vec());
+        }
+        let payload = format!("bulk-{}", idx);
+        let id = sequential.add_node(payload.clone(), deps.clone()).unwrap();
+        let node = Node::<TestHasher>::new(payload, deps);
+        assert_eq!(node.id(), id.as_slice());
+        nodes.push(node);
+    }
+    let mut bulk = Merkle::<S, TestHasher>::new(bulk);
+    let expected_ids: Vec<Vec<u8>> = nodes.iter().map(|node| node.id().to_vec()).collect();
+    // Dependencies don't have to come first.
+    nodes.reverse();
+    let mut ids = bulk.add_nodes(nodes).unwrap();
+    ids.reverse();
+    assert_eq!(ids, expected_ids);
+    assert_eq!(bulk.get_roots(), sequential.get_roots());
+    assert_eq!(
+        bulk.get_nodes().key_length_histogram().unwrap(),
+        sequential.get_nodes().key_length_histogram().unwrap()
+    );
+    for id in expected_ids.iter() {
+        let stored = bulk.get_node_by_id(id).unwrap().unwrap();
+        let expected = sequential.get_node_by_id(id).unwrap().unwrap();
+        assert_eq!(stored.item(), expected.item());
+        assert_eq!(stored.dependency_ids(), expected.dependency_ids());
+    }
+    // Adding them again changes nothing.
+    let roots = bulk.get_roots().clone();
+    bulk.add_nodes(vec![Node::new("bulk-0", BTreeSet::new())])
+        .unwrap();
+    assert_eq!(bulk.get_roots(), &roots);
+}
+
+// Checks a Store's get_many against individual gets including missing and repeated ids.
+fn check_get_many<S: Store<TestHasher>>(mut store: S) {
+    let mut ids = Vec::new();
+    for idx in 0..1200 {
+        let node = Node::<TestHasher>::new(format!("many-{}", idx), BTreeSet::new());
+        ids.push(node.id().to_vec());
+        store.store(node).unwrap();
+    }
+    let missing = Node::<TestHasher>::new("not stored", BTreeSet::new())
+        .id()
+        .to_vec();
+    let mut requested: Vec<&[u8]> = ids.iter().rev().map(Vec::as_slice).collect();
+    requested.insert(7, &missing);
+    requested.push(&ids[3]);
+    requested.push(&missing);
+    let nodes = store.get_many(&requested).unwrap();
+    assert_eq!(nodes.len(), requested.len());
+    for (id, node) in requested.iter().zip(nodes) {
+        match node {
+            Some(node) => {
+                let expected = store.get(id).unwrap().unwrap();
+                assert_eq!(node.id(), *id);
+                assert_eq!(node.item(), expected.item());
+            }
+            None => assert_eq!(*id, missing.as_slice()),
+        }
+    }
+    assert!(store.get_many(&[]).unwrap().is_empty());
+}
+
+// Checks a Store's contains_many answers for every requested id in order.
+fn check_contains_many<S: Store<TestHasher>>(mut store: S) {
+    let mut ids = Vec::new();
+    for idx in 0..1200 {
+        let node = Node::<TestHasher>::new(format!("contains-{}", idx), BTreeSet::new());
+        ids.push(node.id().to_vec());
+        // Every third node is left out.
+        if idx % 3 != 0 {
+            store.store(node).unwrap();
+        }
+    }
+    let mut requested: Vec<&[u8]> = ids.iter().rev().map(Vec::as_slice).collect();
+    requested.push(&ids[1]);
+    requested.push(&ids[0]);
+    let expected: Vec<bool> = requested
+        .iter()
+        .map(|id| store.contains(id).unwrap())
+        .collect();
+    assert_eq!(expected.iter().filter(|found| !**found).count(), 401);
+    assert_eq!(store.contains_many(&requested).unwrap(), expected);
+    assert!(store.contains_many(&[]).unwrap().is_empty());
+}
+
+// Checks a Store's ids iterate over exactly the stored ids in ascending order.
+fn check_ids<S: Store<TestHasher>>(mut store: S) {
+    assert_eq!(store.ids().unwrap().count(), 0);
+    assert_eq!(store.len().unwrap(), 0);
+    assert!(store.is_empty().unwrap());
+    let mut ids = Vec::new();
+    for idx in 0..2500 {
+        let node = Node::<TestHasher>::new(format!("ids-{}", idx), BTreeSet::new());
+        ids.push(node.id().to_vec());
+        store.store(node).unwrap();
+    }
+    ids.sort();
+    let found: Vec<Vec<u8>> = store.ids().unwrap().map(Result::unwrap).collect();
+    assert_eq!(found, ids);
+    assert_eq!(store.len().unwrap(), ids.len());
+    assert!(!store.is_empty().unwrap());
+}
+
+#[test]
+fn test_btree_store_ids() {
+    check_ids(BTreeStore::<TestHasher>::new());
+}
+
+#[test]
+fn test_hash_store_ids() {
+    check_ids(HashStore::<TestHasher>::new());
+}
+
+#[test]
+fn test_btree_store_get_many() {
+    check_get_many(BTreeStore::<TestHasher>::new());
+}
+
+#[test]
+fn test_hash_store_get_many() {
+    check_get_many(HashStore::<TestHasher>::new());
+}
+
+// Checks DAGs on several threads can add nodes to one shared store.
+fn check_concurrent_writers<S>(store: S)
+where
+    S: ConcurrentStore<TestHasher> + Send + Sync + 'static,
+{
+    let store = Arc::new(store);
+    let writers: Vec<_> = (0..4)
+        .map(|writer| {
+            let store = Arc::clone(&store);
+            std::thread::spawn(move || {
+                let mut dag = Merkle::<_, TestHasher>::new(store);
+                let mut head = BTreeSet::new();
+                for idx in 0..50 {
+                    let id = dag
+                        .add_node(format!("writer-{}-{}", writer, idx), head)
+                        .unwrap();
+                    head = BTreeSet::from([id]);
+                }
+                head
+            })
+        })
+        .collect();
+    let heads: BTreeSet<Vec<u8>> = writers
+        .into_iter()
+        .flat_map(|writer| writer.join().unwrap())
+        .collect();
+    let dag = Merkle::<_, TestHasher>::from_store(store).unwrap();
+    assert_eq!(dag.get_roots(), &heads);
+    assert_eq!(dag.node_count().unwrap(), 200);
+}
+
+#[test]
+fn test_shared_btree_store_concurrent_writers() {
+    check_concurrent_writers(std::sync::RwLock::new(BTreeStore::<TestHasher>::new()));
+}
+
+// Checks that two DAGs sharing one store see each other's nodes but keep their own roots.
+fn check_shared_views<S>(store: S)
+where
+    S: ConcurrentStore<TestHasher>,
+{
+    let store = Arc::new(store);
+    let mut first = Merkle::<_, TestHasher>::new(Arc::clone(&store));
+    let mut second = Merkle::<_, TestHasher>::new(Arc::clone(&store));
+    let quake = first.add_node("quake", BTreeSet::new()).unwrap();
+    assert!(second.check_for_node(&quake).unwrap());
+    let qualm = second
+        .add_node("qualm", BTreeSet::from([quake.clone()]))
+        .unwrap();
+    assert!(first.check_for_node(&qualm).unwrap());
+    assert_eq!(first.get_roots(), &BTreeSet::from([quake]));
+    assert_eq!(second.get_roots(), &BTreeSet::from([qualm]));
+    assert_eq!(Store::<TestHasher>::len(&*store).unwrap(), 2);
+}
+
+#[test]
+fn test_shared_btree_store_views() {
+    check_shared_views(std::sync::RwLock::new(BTreeStore::<TestHasher>::new()));
+}
+
+#[test]
+fn test_btree_store_contains_many() {
+    check_contains_many(BTreeStore::<TestHasher>::new());
+}
+
+#[test]
+fn test_add_node_checks_every_dependency() {
+    let (mut dag, ids) = TestDag::from_text(QUAKE_CHAIN).unwrap();
+    let missing = Node::<TestHasher>::new("missing", BTreeSet::new())
+        .id()
+        .to_vec();
+    let deps = BTreeSet::from([ids["quake"].clone(), missing, ids["quell"].clone()]);
+    assert!(matches!(
+        dag.add_node("quash", deps),
+        Err(StoreError::NoSuchDependents)
+    ));
+    assert_eq!(dag.get_roots(), &BTreeSet::from([ids["quell"].clone()]));
+    assert_eq!(dag.node_count().unwrap(), 3);
+}
+
+// Checks that a DAG loaded from a reopened store has the roots, sticky ids and pins it had
+// when it was dropped.
+#[cfg(any(
+    feature = "sqlite",
+    feature = "rusty-leveldb",
+    feature = "sled",
+    feature = "redb",
+    feature = "lmdb",
+    feature = "rocksdb",
+    feature = "cbor"
+))]
+fn check_roots_survive_reopen<S, F>(open: F)
+where
+    S: Store<TestHasher>,
+    F: Fn() -> S,
+{
+    let (roots, sticky) = {
+        let mut dag = Merkle::<S, TestHasher>::load(open()).unwrap();
+        assert!(dag.persists_roots());
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        let shake = dag.add_node("shake", BTreeSet::new()).unwrap();
+        let drop = dag.add_node("drop", BTreeSet::from([shake])).unwrap();
+        dag.mark_sticky_root(&quake).unwrap();
+        dag.pin(&quake).unwrap();
+        assert_eq!(
+            dag.get_nodes().persisted_roots().unwrap().unwrap().roots,
+            BTreeSet::from([qualm, drop.clone()])
+        );
+        dag.remove_node(&drop, RemoveScope::Node).unwrap();
+        (dag.get_roots().clone(), dag.sticky_roots().clone())
+    };
+    let mut dag = Merkle::<S, TestHasher>::load(open()).unwrap();
+    assert_eq!(dag.get_roots(), &roots);
+    assert_eq!(dag.sticky_roots(), &sticky);
+    assert_eq!(dag.pins(), &sticky);
+    let quell = dag
+        .add_node("quell", roots.iter().cloned().collect())
+        .unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([quell.clone()]));
+    drop(dag);
+    let dag = Merkle::<S, TestHasher>::load(open()).unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([quell]));
+}
+
+// Checks that a store written with TestHasher can't be opened with another hasher.
+#[cfg(feature = "blake2")]
+fn check_hash_algorithm_mismatch<S, F>(open: F)
+where
+    S: Store<TestHasher> + Store<crate::blake2::Blake2b512>,
+    F: Fn() -> S,
+{
+    type Blake2DAG<S> = Merkle<S, crate::blake2::Blake2b512>;
+    // A store without nodes can be opened with any hasher.
+    assert!(Blake2DAG::<S>::try_new(open()).is_ok());
+    {
+        let mut dag = Merkle::<S, TestHasher>::load(open()).unwrap();
+        dag.add_node("quake", BTreeSet::new()).unwrap();
+    }
+    let mismatch = |result: crate::store::Result<Blake2DAG<S>>| match result {
+        Err(StoreError::HashAlgorithmMismatch { expected, found }) => {
+            assert_eq!(expected, "blake2b-512");
+            assert_eq!(found, "siphash24-keyed-64");
+        }
+        Err(err) => panic!("expected HashAlgorithmMismatch got {:?}", err),
+        Ok(_) => panic!("expected HashAlgorithmMismatch"),
+    };
+    mismatch(Blake2DAG::<S>::load(open()));
+    mismatch(Blake2DAG::<S>::try_new(open()));
+    mismatch(Blake2DAG::<S>::from_store(open()));
+    let dag = Merkle::<S, TestHasher>::load(open()).unwrap();
+    assert_eq!(dag.get_roots().len(), 1);
+}
+
+// Checks that nodes added before a flush are in a store reopened right after it.
+#[cfg(any(
+    feature = "sqlite",
+    feature = "rusty-leveldb",
+    feature = "rocksdb",
+    feature = "sled",
+    feature = "redb",
+    feature = "lmdb",
+    feature = "cbor"
+))]
+fn check_flush_survives_reopen<S, F>(open: F)
+where
+    S: Store<TestHasher>,
+    F: Fn() -> S,
+{
+    let mut dag = Merkle::<S, TestHasher>::new(open());
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm = dag
+        .add_node("qualm", BTreeSet::from([quake.clone()]))
+        .unwrap();
+    dag.flush().unwrap();
+    drop(dag);
+    let store = open();
+    for (id, item) in [(quake, "quake"), (qualm, "qualm")] {
+        let node = Store::<TestHasher>::get(&store, &id).unwrap().unwrap();
+        assert_eq!(node.item(), item.as_bytes());
+    }
+}
+
+// Checks that a transaction over a store persisting the roots is applied as a whole and
+// that a failing transaction writes nothing.
+#[cfg(any(
+    feature = "sqlite",
+    feature = "rusty-leveldb",
+    feature = "sled",
+    feature = "redb",
+    feature = "lmdb",
+    feature = "cbor"
+))]
+fn check_transaction<S, F>(open: F)
+where
+    S: Store<TestHasher>,
+    F: Fn() -> S,
+{
+    let (quake, roots) = {
+        let mut dag = Merkle::<S, TestHasher>::load(open()).unwrap();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let (qualm, shake) = dag
+            .transaction(|txn| {
+                let qualm = txn.add_node("qualm", BTreeSet::from([quake.clone()]))?;
+                let shake = txn.add_node("shake", BTreeSet::new())?;
+                Ok((qualm, shake))
+            })
+            .unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm, shake]));
+        let roots = dag.get_roots().clone();
+        let err = dag
+            .transaction(|txn| {
+                let quell = txn.add_node("quell", roots.clone())?;
+                txn.add_node("drop", BTreeSet::from([quell]))?;
+                Err::<(), _>(StoreError::StoreFailure("injected".to_owned()))
+            })
+            .unwrap_err();
+        assert!(matches!(err, StoreError::StoreFailure(_)));
+        assert_eq!(dag.get_roots(), &roots);
+        (quake, roots)
+    };
+    let dag = Merkle::<S, TestHasher>::load(open()).unwrap();
+    assert_eq!(dag.get_roots(), &roots);
+    assert_eq!(dag.node_count().unwrap(), 3);
+    assert!(dag.check_for_node(&quake).unwrap());
+}
+
+#[test]
+fn test_transaction_stages_nodes() {
+    let (mut dag, ids) = TestDag::from_text(QUAKE_CHAIN).unwrap();
+    let count = dag.node_count().unwrap();
+    let (shake, drop) = dag
+        .transaction(|txn| {
+            let shake = txn.add_node("shake", BTreeSet::from([ids["quell"].clone()]))?;
+            // Staged nodes are visible to the transaction but not to the DAG yet.
+            assert!(txn.check_for_node(&shake)?);
+            assert_eq!(txn.get_node_by_id(&shake)?.unwrap().item(), b"shake");
+            let drop = txn.add_node("drop", BTreeSet::from([shake.clone()]))?;
+            assert_eq!(txn.get_roots(), BTreeSet::from([drop.clone()]));
+            // Adding a staged node again is a no-op.
+            assert_eq!(txn.add_node("drop", BTreeSet::from([shake.clone()]))?, drop);
+            assert!(matches!(
+                txn.add_node("orphan", BTreeSet::from([vec![0xab; 8]])),
+                Err(StoreError::NoSuchDependents)
+            ));
+            Ok((shake, drop))
+        })
+        .unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([drop.clone()]));
+    assert_eq!(dag.node_count().unwrap(), count + 2);
+    assert!(dag.check_for_node(&shake).unwrap());
+}
+
+#[test]
+fn test_transaction_failure_leaves_dag_untouched() {
+    let (mut dag, ids) = TestDag::from_text(QUAKE_CHAIN).unwrap();
+    let roots = dag.get_roots().clone();
+    let count = dag.node_count().unwrap();
+    let mut shake = None;
+    let err = dag
+        .transaction(|txn| {
+            shake = Some(txn.add_node("shake", BTreeSet::from([ids["quell"].clone()]))?);
+            txn.add_node("drop", BTreeSet::new())?;
+            Err::<(), _>(StoreError::StoreFailure("injected".to_owned()))
+        })
+        .unwrap_err();
+    assert!(matches!(err, StoreError::StoreFailure(_)));
+    assert_eq!(dag.get_roots(), &roots);
+    assert_eq!(dag.node_count().unwrap(), count);
+    assert!(!dag.check_for_node(&shake.unwrap()).unwrap());
+}
+
+// A store whose bulk writes always fail.
+struct FailingBulkStore(BTreeStore<TestHasher>);
+
+impl Store<TestHasher> for FailingBulkStore {
+    fn contains(&self, id: &[u8]) -> crate::store::Result<bool> {
+        self.0.contains(id)
+    }
+
+    fn get(&self, id: &[u8]) -> crate::store::Result<Option<Node<TestHasher>>> {
+        Store::get(&self.0, id)
+    }
+
+    fn store(&mut self, node: Node<TestHasher>) -> crate::store::Result<()> {
+        self.0.store(node)
+    }
+
+    fn store_many<I>(&mut self, _nodes: I) -> crate::store::Result<()>
+    where
+        I: IntoIterator<Item = Node<TestHasher>>,
+    {
+        Err(StoreError::StoreFailure("disk full".to_owned()))
+    }
+}
+
+#[test]
+fn test_transaction_write_failure_leaves_roots_untouched() {
+    let mut dag = Merkle::new(FailingBulkStore(BTreeStore::new()));
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let err = dag
+        .transaction(|txn| txn.add_node("qualm", BTreeSet::from([quake.clone()])))
+        .unwrap_err();
+    assert!(matches!(err, StoreError::StoreFailure(_)));
+    assert_eq!(dag.get_roots(), &BTreeSet::from([quake]));
+}
+
+#[test]
+fn test_from_store_reconstructs_roots() {
+    let (source, ids) = TestDag::from_text(
+        r#"
+quake: "quake"
+qualm(quake): "qualm"
+quell(qualm): "quell"
+shake: "shake"
+drop(shake, quake): "drop"
+lone: "lone"
+"#,
+    )
+    .unwrap();
+    let expected = BTreeSet::from([
+        ids["quell"].clone(),
+        ids["drop"].clone(),
+        ids["lone"].clone(),
+    ]);
+    assert_eq!(source.get_roots(), &expected);
+    let copy = source.get_nodes().clone();
+    let mut dag = TestDag::from_store(copy).unwrap();
+    assert_eq!(dag.get_roots(), &expected);
+
+    // A stale root set is replaced.
+    dag.roots_mut().insert(ids["quake"].clone());
+    dag.roots_mut().remove(&ids["lone"]);
+    dag.reconstruct_roots().unwrap();
+    assert_eq!(dag.get_roots(), &expected);
+
+    assert!(TestDag::from_store(BTreeStore::new())
+        .unwrap()
+        .get_roots()
+        .is_empty());
+}
+
+#[test]
+fn test_load_needs_persisted_roots_support() {
+    assert!(matches!(
+        TestDag::load(BTreeStore::new()),
+        Err(StoreError::Unsupported("persisted_roots"))
+    ));
+}
+
+#[test]
+fn test_btree_store_add_nodes() {
+    check_add_nodes(
+        BTreeStore::<TestHasher>::new(),
+        BTreeStore::<TestHasher>::new(),
+    );
+}
+
+#[test]
+fn test_add_nodes_rejects_missing_dependencies() {
+    let (mut dag, ids) = TestDag::from_text(QUAKE_CHAIN).unwrap();
+    let roots = dag.get_roots().clone();
+    let orphan = Node::<TestHasher>::new("orphan", BTreeSet::from([vec![0xab; 8]]));
+    let shake = Node::<TestHasher>::new("shake", BTreeSet::from([ids["quell"].clone()]));
+    let shake_id = shake.id().to_vec();
+    assert!(matches!(
+        dag.add_nodes(vec![shake, orphan]),
+        Err(StoreError::NoSuchDependents)
+    ));
+    assert!(!dag.check_for_node(&shake_id).unwrap());
+    assert_eq!(dag.get_roots(), &roots);
+}
+
+#[test]
+fn test_add_nodes_keeps_indexes() {
+    let mut dag = IndexedTestDag::new(ReverseIndexStore::new(BTreeStore::new()));
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let shake = Node::<TestHasher>::new("shake", BTreeSet::from([quake.id().to_vec()]));
+    let ids = dag.add_nodes(vec![quake, shake]).unwrap();
+    assert_eq!(
+        dag.get_nodes().children_of(&ids[0]).unwrap(),
+        BTreeSet::from([ids[1].clone()])
+    );
+}
+
+#[test]
+fn test_remove_node_with_descendants_promotes_shared_dependencies() {
+    let (mut dag, ids) = IndexedTestDag::from_text(
+        r#"
+base: "base"
+left(base): "left"
+right(base): "right"
+merge(left, right): "merge"
+"#,
+    )
+    .unwrap();
+    let removed = dag
+        .remove_node(&ids["left"], RemoveScope::WithDescendants)
+        .unwrap();
+    assert_eq!(
+        removed,
+        BTreeSet::from([ids["left"].clone(), ids["merge"].clone()])
+    );
+    assert_eq!(dag.get_roots(), &BTreeSet::from([ids["right"].clone()]));
+    assert!(dag
+        .get_nodes()
+        .children_of(&ids["left"])
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        dag.get_nodes().children_of(&ids["base"]).unwrap(),
+        BTreeSet::from([ids["right"].clone()])
+    );
+}
+
+#[test]
+fn test_resolve_id_prefix_unique() {
+    let (dag, ids) = seeded_prefix_dag();
+    assert_eq!(
+        dag.resolve_id_prefix(&hex(&ids[42])[0..8]).unwrap(),
+        PrefixResolution::Unique(ids[42].clone())
+    );
+    assert_eq!(
+        dag.resolve_id_prefix(&hex(&ids[42])).unwrap(),
+        PrefixResolution::Unique(ids[42].clone())
+    );
+}
+
+#[test]
+fn test_resolve_id_prefix_ambiguous_and_not_found() {
+    let (dag, ids) = seeded_prefix_dag();
+    let config = PrefixConfig {
+        min_hex_len: 2,
+        ambiguity_cap: 3,
+    };
+    let mut counts: BTreeMap<u8, Vec<Vec<u8>>> = BTreeMap::new();
+    for id in ids.iter() {
+        counts.entry(id[0]).or_default().push(id.clone());
+    }
+    let (first_byte, mut matching) = counts
+        .iter()
+        .find(|(_, ids)| ids.len() > 1)
+        .map(|(b, ids)| (*b, ids.clone()))
+        .unwrap();
+    matching.sort();
+    matching.truncate(3);
+    assert_eq!(
+        dag.resolve_id_prefix_with(&hex(&[first_byte]), &config)
+            .unwrap(),
+        PrefixResolution::Ambiguous(matching)
+    );
+    let missing_byte = (0..=255u8).find(|b| !counts.contains_key(b)).unwrap();
+    assert_eq!(
+        dag.resolve_id_prefix_with(&hex(&[missing_byte]), &config)
+            .unwrap(),
+        PrefixResolution::NotFound
+    );
+}
+
+#[test]
+fn test_resolve_id_prefix_odd_nibbles() {
+    let (dag, ids) = seeded_prefix_dag();
+    let config = PrefixConfig {
+        min_hex_len: 1,
+        ambiguity_cap: 1000,
+    };
+    for id in ids.iter().take(20) {
+        for len in [1, 3, 5] {
+            let prefix = &hex(id)[0..len];
+            let mut expected: Vec<Vec<u8>> = ids
+                .iter()
+                .filter(|id| hex(id).starts_with(prefix))
+                .cloned()
+                .collect();
+            expected.sort();
+            let resolved = match dag.resolve_id_prefix_with(prefix, &config).unwrap() {
+                PrefixResolution::Unique(id) => vec![id],
+                PrefixResolution::Ambiguous(ids) => ids,
+                PrefixResolution::NotFound => Vec::new(),
+            };
+            assert_eq!(resolved, expected);
+        }
+    }
+}
+
+#[test]
+fn test_resolve_id_prefix_minimum_length() {
+    let (dag, ids) = seeded_prefix_dag();
+    assert!(matches!(
+        dag.resolve_id_prefix(&hex(&ids[0])[0..3]),
+        Err(StoreError::InvalidIdPrefix(_))
+    ));
+    assert!(matches!(
+        dag.resolve_id_prefix("zzzz"),
+        Err(StoreError::InvalidIdPrefix(_))
+    ));
+}
+
+// Counts the calls and keys returned by an inner BTreeStore.
+#[derive(Default)]
+struct PrefixCountingStore {
+    inner: BTreeStore<TestHasher>,
+    reads: std::cell::Cell<usize>,
+    keys_touched: std::cell::Cell<usize>,
+}
+
+impl Store<TestHasher> for PrefixCountingStore {
+    fn contains(&self, id: &[u8]) -> crate::store::Result<bool> {
+        self.reads.set(self.reads.get() + 1);
+        self.inner.contains(id)
+    }
+
+    fn get(&self, id: &[u8]) -> crate::store::Result<Option<Node<TestHasher>>> {
+        self.reads.set(self.reads.get() + 1);
+        Store::get(&self.inner, id)
+    }
+
+    // Handles skip the payload so they aren't counted as reads.
+    fn get_handle(&self, id: &[u8]) -> crate::store::Result<Option<NodeHandle<TestHasher>>> {
+        self.inner.get_handle(id)
+    }
+
+    fn store(&mut self, node: Node<TestHasher>) -> crate::store::Result<()> {
+        self.inner.store(node)
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> crate::store::Result<Vec<Vec<u8>>> {
+        let ids = self.inner.find_by_prefix(prefix, limit)?;
+        self.keys_touched.set(self.keys_touched.get() + ids.len());
+        Ok(ids)
+    }
+}
+
+#[test]
+fn test_resolve_id_prefix_only_touches_prefix_range() {
+    let mut dag = Merkle::<PrefixCountingStore, TestHasher>::default();
+    let ids: Vec<Vec<u8>> = (0..300)
+        .map(|idx| {
+            dag.add_node(format!("seed-{}", idx), BTreeSet::new())
+                .unwrap()
+        })
+        .collect();
+    let reads = dag.get_nodes().reads.get();
+    let prefix = &hex(&ids[7])[0..5];
+    let in_range = ids.iter().filter(|id| hex(id).starts_with(prefix)).count();
+    dag.resolve_id_prefix(prefix).unwrap();
+    assert_eq!(dag.get_nodes().reads.get(), reads);
+    assert_eq!(dag.get_nodes().keys_touched.get(), in_range);
+}
+
+#[test]
+fn test_node_handles_defer_payload_reads() {
+    let mut dag = Merkle::<PrefixCountingStore, TestHasher>::default();
+    let mut ids: Vec<Vec<u8>> = Vec::new();
+    for idx in 0..5 {
+        let payload = format!("{}{}", "x".repeat(64 * 1024), idx);
+        let deps = ids.last().cloned().into_iter().collect();
+        ids.push(dag.add_node(payload, deps).unwrap());
+    }
+    let reads = dag.get_nodes().reads.get();
+    let handles = dag.ancestors_handles(&ids[4]).unwrap();
+    assert_eq!(
+        handles.iter().map(|h| h.id().to_vec()).collect::<Vec<_>>(),
+        vec![
+            ids[3].clone(),
+            ids[2].clone(),
+            ids[1].clone(),
+            ids[0].clone()
+        ]
+    );
+    let next = dag
+        .find_next_non_descendant_handles(&BTreeSet::from([ids[2].clone()]))
+        .unwrap();
+    assert_eq!(next.len(), 1);
+    assert_eq!(next[0].id(), ids[3].as_slice());
+    assert_eq!(dag.get_nodes().reads.get(), reads);
+
+    let clicked = handles[1].clone();
+    assert!(!handles[1].is_hydrated());
+    let payload = clicked.payload(&dag).unwrap();
+    assert!(payload.ends_with(b"2"));
+    assert_eq!(payload.len(), 64 * 1024 + 1);
+    assert_eq!(dag.get_nodes().reads.get(), reads + 1);
+    // The payload is memoized in the handle and every clone of it.
+    assert!(handles[1].is_hydrated());
+    handles[1].payload(&dag).unwrap();
+    clicked.payload(&dag).unwrap();
+    assert_eq!(dag.get_nodes().reads.get(), reads + 1);
+    assert_eq!(handles.iter().filter(|h| h.is_hydrated()).count(), 1);
+}
+
+#[test]
+fn test_node_handle_detects_changed_store() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm_node_id = dag.add_node("qualm", BTreeSet::new()).unwrap();
+    let quake = dag.get_handle_by_id(&quake_node_id).unwrap().unwrap();
+    let qualm = dag.get_handle_by_id(&qualm_node_id).unwrap().unwrap();
+    dag.nodes_mut()
+        .insert(quake_node_id.clone(), Node::new("swapped", BTreeSet::new()));
+    dag.nodes_mut().remove(&qualm_node_id);
+    match quake.payload(&dag) {
+        Err(StoreError::StaleHandle {
+            id,
+            expected_item_id,
+            found_item_id: Some(found),
+        }) => {
+            assert_eq!(id, quake_node_id);
+            assert_eq!(expected_item_id, quake.item_id());
+            assert_ne!(found, expected_item_id);
+        }
+        result => panic!("expected StaleHandle got {:?}", result),
+    }
+    assert!(!quake.is_hydrated());
+    assert!(matches!(
+        qualm.payload(&dag),
+        Err(StoreError::StaleHandle {
+            found_item_id: None,
+            ..
+        })
+    ));
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_node_handle_serializes_without_payload() {
+    use ciborium::{de::from_reader, ser::into_writer};
+
+    let mut dag = TestDag::new(BTreeMap::new());
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let quell_node_id = dag
+        .add_node("quell", BTreeSet::from([quake_node_id.clone()]))
+        .unwrap();
+    let handle = dag.get_handle_by_id(&quell_node_id).unwrap().unwrap();
+    handle.payload(&dag).unwrap();
+    let mut buf = Vec::new();
+    into_writer(&handle, &mut buf).unwrap();
+    assert!(!buf.windows(5).any(|w| w == b"quell"));
+    let handle_de: NodeHandle<TestHasher> = from_reader(buf.as_slice()).unwrap();
+    assert_eq!(handle_de.id(), handle.id());
+    assert_eq!(handle_de.item_id(), handle.item_id());
+    assert_eq!(handle_de.dependency_ids(), handle.dependency_ids());
+    assert!(!handle_de.is_hydrated());
+    assert_eq!(handle_de.payload(&dag).unwrap(), b"quell");
+}
+
+#[cfg(feature = "cbor")]
+#[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+struct Inventory {
+    name: String,
+    counts: std::collections::HashMap<String, u64>,
+    weight: f64,
+    tags: Vec<String>,
+}
+
+#[cfg(feature = "cbor")]
+fn inventory(insert_order: impl Iterator<Item = u64>) -> Inventory {
+    Inventory {
+        name: "quake".to_owned(),
+        counts: insert_order
+            .map(|idx| (format!("item {}", idx), idx))
+            .collect(),
+        weight: 1.5,
+        tags: vec!["quell".to_owned(), "qualm".to_owned()],
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_add_item_round_trips_typed_payloads() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let quake = inventory(0..50);
+    let quake_node_id = dag.add_item(&quake, BTreeSet::new()).unwrap();
+    // Every HashMap iterates in its own order but equal values still get the same id.
+    for _ in 0..20 {
+        let again = inventory((0..50).rev());
+        assert_eq!(again, quake);
+        assert_eq!(
+            dag.add_item(&again, BTreeSet::new()).unwrap(),
+            quake_node_id
+        );
+    }
+    let quell_node_id = dag
+        .add_item(&vec![1u64, 2, 3], BTreeSet::from([quake_node_id.clone()]))
+        .unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([quell_node_id.clone()]));
+    let quake_node = dag.get_node_by_id(&quake_node_id).unwrap().unwrap();
+    assert_eq!(quake_node.item_as::<Inventory>().unwrap(), quake);
+    let quell_node = dag.get_node_by_id(&quell_node_id).unwrap().unwrap();
+    assert_eq!(quell_node.item_as::<Vec<u64>>().unwrap(), vec![1, 2, 3]);
+    assert!(quell_node.item_as::<Inventory>().is_err());
+    // Items added as raw bytes aren't cbor.
+    let raw = Node::<TestHasher>::new("quake", BTreeSet::new());
+    assert!(raw.item_as::<String>().is_err());
+}
+
+#[cfg(feature = "cbor")]
+#[test]
+fn test_encode_item_is_canonical() {
+    use crate::store::codec::encode_item;
+
+    // "b" encodes to fewer bytes than "aa" so it comes first even though the BTreeMap
+    // iterates "aa" first.
+    let map = BTreeMap::from([("aa", 2u8), ("b", 1)]);
+    assert_eq!(
+        encode_item(&map).unwrap(),
+        vec![0xa2, 0x61, b'b', 0x01, 0x62, b'a', b'a', 0x02]
+    );
+    // Nested maps are sorted and floats take their shortest lossless encoding.
+    let nested = vec![BTreeMap::from([("aa", 1.5f64), ("b", 0.1)])];
+    let mut expected = vec![0x81, 0xa2, 0x61, b'b', 0xfb];
+    expected.extend_from_slice(&0.1f64.to_be_bytes());
+    expected.extend_from_slice(&[0x62, b'a', b'a', 0xf9, 0x3e, 0x00]);
+    assert_eq!(encode_item(&nested).unwrap(), expected);
+    let hashed: std::collections::HashMap<_, _> = (0..100u32).map(|idx| (idx, idx)).collect();
+    let sorted: BTreeMap<_, _> = (0..100u32).map(|idx| (idx, idx)).collect();
+    assert_eq!(encode_item(&hashed).unwrap(), encode_item(&sorted).unwrap());
+}
+
+#[test]
+fn test_sim_clock_fires_timers_in_order() {
+    let clock = SimClock::starting_at(Duration::from_secs(100));
+    let late = clock.timer(Duration::from_secs(130));
+    let early = clock.timer(Duration::from_secs(110));
+    let late_again = clock.timer(Duration::from_secs(130));
+    let last = clock.timer(Duration::from_secs(200));
+    assert!(clock.advance(Duration::from_secs(9)).is_empty());
+    assert_eq!(clock.advance(Duration::from_secs(1)), vec![early]);
+    assert_eq!(
+        clock.advance_to(Duration::from_secs(150)),
+        vec![late, late_again]
+    );
+    assert_eq!(clock.now(), Duration::from_secs(150));
+    // The clock never moves backwards and sleeping advances it instantly.
+    assert!(clock.advance_to(Duration::from_secs(120)).is_empty());
+    assert_eq!(clock.now(), Duration::from_secs(150));
+    clock.sleep_until(Duration::from_secs(199));
+    assert_eq!(clock.now(), Duration::from_secs(199));
+    assert_eq!(clock.clone().advance(Duration::from_secs(1)), vec![last]);
+    assert_eq!(clock.now(), Duration::from_secs(200));
+}
+
+#[test]
+fn test_read_token_behind_replica() {
+    let (mut writer, _) = bulk_archive(6);
+    let (mut replica, _) = bulk_archive(3);
+    let early = replica.read_token();
+    assert!(writer.satisfies(&early).unwrap());
+    writer
+        .add_node("ahead", writer.get_roots().clone())
+        .unwrap();
+    let token = writer.read_token();
+    assert!(!replica.satisfies(&token).unwrap());
+    let archive: Vec<Node<TestHasher>> = writer.get_nodes().values().cloned().collect();
+    replica
+        .bulk_load(archive.into_iter().map(Ok), BulkLoadOpts::default())
+        .unwrap();
+    assert!(replica.satisfies(&token).unwrap());
+    assert!(replica.satisfies(&early).unwrap());
+    // Tokens only carry the roots so they stay small as the DAG grows.
+    assert_eq!(token.roots.len(), 1);
+}
+
+#[test]
+fn test_read_token_costs_one_read_per_root() {
+    let mut writer = Merkle::<PrefixCountingStore, TestHasher>::default();
+    for idx in 0..40 {
+        let dep = writer
+            .add_node(format!("base-{}", idx), BTreeSet::new())
+            .unwrap();
+        if idx % 10 == 0 {
+            writer
+                .add_node(format!("head-{}", idx), BTreeSet::from([dep]))
+                .unwrap();
+        }
+    }
+    let token = writer.read_token();
+    let mut replica = Merkle::<PrefixCountingStore, TestHasher>::default();
+    let archive: Vec<Node<TestHasher>> = writer.get_nodes().inner.values().cloned().collect();
+    replica
+        .bulk_load(archive.into_iter().map(Ok), BulkLoadOpts::default())
+        .unwrap();
+    replica.add_node("local", BTreeSet::new()).unwrap();
+    let reads = replica.get_nodes().reads.get();
+    assert!(replica.satisfies(&token).unwrap());
+    assert_eq!(replica.get_nodes().reads.get() - reads, token.roots.len());
+    // An unchanged DAG is recognized from the digest alone.
+    let reads = writer.get_nodes().reads.get();
+    assert!(writer.satisfies(&token).unwrap());
+    assert_eq!(writer.get_nodes().reads.get(), reads);
+}
+
+#[test]
+fn test_read_token_from_foreign_dag() {
+    let (writer, _) = bulk_archive(5);
+    let mut foreign = TestDag::new(BTreeMap::new());
+    foreign.add_node("elsewhere", BTreeSet::new()).unwrap();
+    assert!(!writer.satisfies(&foreign.read_token()).unwrap());
+    assert!(!foreign.satisfies(&writer.read_token()).unwrap());
+    // An empty DAG implies nothing.
+    assert!(writer
+        .satisfies(&TestDag::new(BTreeMap::new()).read_token())
+        .unwrap());
+}
+
+// A replicated store whose nodes only become visible once they arrive.
+struct ArrivingStore {
+    inner: BTreeStore<TestHasher>,
+    clock: SimClock,
+    arrivals: BTreeMap<Vec<u8>, Duration>,
+}
+
+impl Store<TestHasher> for ArrivingStore {
+    fn contains(&self, id: &[u8]) -> crate::store::Result<bool> {
+        let arrived = self
+            .arrivals
+            .get(id)
+            .is_none_or(|arrival| *arrival <= self.clock.now());
+        Ok(arrived && self.inner.contains(id)?)
+    }
+
+    fn get(&self, id: &[u8]) -> crate::store::Result<Option<Node<TestHasher>>> {
+        if !self.contains(id)? {
+            return Ok(None);
+        }
+        Store::get(&self.inner, id)
+    }
+
+    fn store(&mut self, node: Node<TestHasher>) -> crate::store::Result<()> {
+        self.inner.store(node)
+    }
+}
+
+fn arriving_replica(arrival: Duration) -> (Merkle<ArrivingStore, TestHasher>, ReadToken) {
+    let (mut writer, _) = bulk_archive(4);
+    let before = writer.read_token();
+    let late = writer.add_node("late", writer.get_roots().clone()).unwrap();
+    let clock = SimClock::starting_at(Duration::from_secs(50));
+    let mut replica = Merkle::new(ArrivingStore {
+        inner: writer.get_nodes().clone(),
+        clock: clock.clone(),
+        arrivals: BTreeMap::from([(late, Duration::from_secs(50) + arrival)]),
+    });
+    replica.set_clock(Arc::new(clock));
+    assert!(replica.satisfies(&before).unwrap());
+    (replica, writer.read_token())
+}
+
+#[test]
+fn test_wait_until_satisfied_wakes_when_the_store_catches_up() {
+    let (replica, token) = arriving_replica(Duration::from_millis(35));
+    assert!(!replica.satisfies(&token).unwrap());
+    assert!(replica
+        .wait_until_satisfied(&token, Duration::from_secs(1))
+        .unwrap());
+    // Woken by the first check after the node arrived.
+    assert_eq!(
+        replica.get_nodes().clock.now(),
+        Duration::from_secs(50) + Duration::from_millis(40)
+    );
+}
+
+#[test]
+fn test_wait_until_satisfied_times_out() {
+    let (replica, token) = arriving_replica(Duration::from_secs(10));
+    assert!(!replica
+        .wait_until_satisfied(&token, Duration::from_millis(105))
+        .unwrap());
+    assert_eq!(
+        replica.get_nodes().clock.now(),
+        Duration::from_secs(50) + Duration::from_millis(105)
+    );
+}
+
+// Every time dependent feature must read the Clock so simulations stay deterministic.
+#[test]
+fn test_no_direct_clock_calls() {
+    // Built from pieces so this test doesn't match itself.
+    let forbidden = [
+        concat!("Instant", "::now"),
+        concat!("SystemTime", "::now"),
+        concat!("thread", "::sleep"),
+    ];
+    let src = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+    let mut dirs = vec![src.clone()];
+    let mut offenders = Vec::new();
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|ext| ext == "rs")
+                && path != src.join("clock.rs")
+            {
+                let source = std::fs::read_to_string(&path).unwrap();
+                for (idx, line) in source.lines().enumerate() {
+                    if forbidden.iter().any(|call| line.contains(call)) {
+                        offenders.push(format!("{}:{}", path.display(), idx + 1));
+                    }
+                }
+            }
+        }
+    }
+    assert!(
+        offenders.is_empty(),
+        "read time through crate::clock::Clock instead: {:?}",
+        offenders
+    );
+}
+
+fn chain_dag(len: usize) -> (TestDag<'static>, Vec<Vec<u8>>) {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let mut ids: Vec<Vec<u8>> = Vec::new();
+    for idx in 0..len {
+        let deps = ids.last().cloned().into_iter().collect();
+        ids.push(dag.add_node(format!("chain-{}", idx), deps).unwrap());
+    }
+    (dag, ids)
+}
+
+#[test]
+fn test_fleet_divergence_along_chain() {
+    let (dag, ids) = chain_dag(5);
+    let peer_heads = BTreeMap::from([
+        (b"one".to_vec(), BTreeSet::from([ids[1].clone()])),
+        (b"two".to_vec(), BTreeSet::from([ids[3].clone()])),
+        (b"three".to_vec(), BTreeSet::from([ids[4].clone()])),
+    ]);
+    let divergence = dag.fleet_divergence(&peer_heads).unwrap();
+    assert_eq!(divergence.common_frontier, BTreeSet::from([ids[1].clone()]));
+    assert_eq!(
+        divergence.suffix_counts,
+        BTreeMap::from([
+            (b"one".to_vec(), 0),
+            (b"two".to_vec(), 2),
+            (b"three".to_vec(), 3),
+        ])
+    );
+    assert_eq!(divergence.divergent_count, 3);
+    assert!(divergence.unknown_heads.is_empty());
+}
+
+#[test]
+fn test_fleet_divergence_reports_unknown_heads() {
+    let (dag, ids) = chain_dag(5);
+    let unknown = Node::<TestHasher>::new("unknown", BTreeSet::new());
+    let peer_heads = BTreeMap::from([
+        (b"one".to_vec(), BTreeSet::from([ids[2].clone()])),
+        (b"two".to_vec(), BTreeSet::from([ids[4].clone()])),
+        (b"lost".to_vec(), BTreeSet::from([unknown.id().to_vec()])),
+    ]);
+    let divergence = dag.fleet_divergence(&peer_heads).unwrap();
+    assert_eq!(divergence.common_frontier, BTreeSet::from([ids[2].clone()]));
+    assert_eq!(divergence.suffix_counts[&b"two".to_vec()], 2);
+    assert_eq!(divergence.suffix_counts[&b"lost".to_vec()], 0);
+    assert_eq!(
+        divergence.unknown_heads,
+        BTreeMap::from([(b"lost".to_vec(), BTreeSet::from([unknown.id().to_vec()]))])
+    );
+}
+
+#[test]
+fn test_fleet_divergence_forked_peers() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let base = dag.add_node("base", BTreeSet::new()).unwrap();
+    let left = dag
+        .add_node("left", BTreeSet::from([base.clone()]))
+        .unwrap();
+    let right = dag
+        .add_node("right", BTreeSet::from([base.clone()]))
+        .unwrap();
+    let peer_heads = BTreeMap::from([
+        (b"left".to_vec(), BTreeSet::from([left])),
+        (b"right".to_vec(), BTreeSet::from([right])),
+    ]);
+    let divergence = dag.fleet_divergence(&peer_heads).unwrap();
+    assert_eq!(divergence.common_frontier, BTreeSet::from([base]));
+    assert_eq!(divergence.divergent_count, 2);
+}
+
+#[test]
+fn test_fleet_divergence_single_peer() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm_node_id = dag
+        .add_node("qualm", BTreeSet::from([quake_node_id.clone()]))
+        .unwrap();
+    let quell_node_id = dag.add_node("quell", BTreeSet::new()).unwrap();
+    let heads = BTreeSet::from([
+        quake_node_id.clone(),
+        qualm_node_id.clone(),
+        quell_node_id.clone(),
+    ]);
+    let divergence = dag
+        .fleet_divergence(&BTreeMap::from([(b"solo".to_vec(), heads.clone())]))
+        .unwrap();
+    // With one peer the frontier is its heads that are not ancestors of its other heads.
+    let expected: BTreeSet<Vec<u8>> = heads
+        .iter()
+        .filter(|head| {
+            heads
+                .iter()
+                .all(|other| dag.compare(head, other).unwrap() != NodeCompare::Before)
+        })
+        .cloned()
+        .collect();
+    assert_eq!(divergence.common_frontier, expected);
+    assert_eq!(
+        divergence.common_frontier,
+        BTreeSet::from([qualm_node_id, quell_node_id])
+    );
+    assert_eq!(divergence.divergent_count, 0);
+}
+
+// Exercise the payload index of a fresh store indexing with the WhitespaceIndexer.
+pub(crate) fn check_payload_search<S>(store: S)
+where
+    S: PayloadSearch<TestHasher>,
+{
+    let mut dag = Merkle::<S, TestHasher>::new(store);
+    let quake_node_id = dag
+        .add_node("quake shook the hall", BTreeSet::new())
+        .unwrap();
+    let qualm_node_id = dag
+        .add_node(
+            "a qualm about the Quake",
+            BTreeSet::from([quake_node_id.clone()]),
+        )
+        .unwrap();
+    let quell_node_id = dag
+        .add_node("quell the hall", BTreeSet::from([quake_node_id.clone()]))
+        .unwrap();
+
+    let mut quake_ids = vec![quake_node_id.clone(), qualm_node_id.clone()];
+    quake_ids.sort();
+    assert_eq!(dag.search_payloads(b"quake", 10).unwrap(), quake_ids);
+    assert_eq!(dag.search_payloads(b"quake", 1).unwrap(), quake_ids[..1]);
+    assert!(dag.search_payloads(b"quiver", 10).unwrap().is_empty());
+
+    let terms: [&[u8]; 2] = [b"quake", b"hall"];
+    let mut any_ids = vec![
+        quake_node_id.clone(),
+        qualm_node_id.clone(),
+        quell_node_id.clone(),
+    ];
+    any_ids.sort();
+    assert_eq!(
+        dag.search_all(&terms, SearchMode::AnyOf, 10).unwrap(),
+        any_ids
+    );
+    assert_eq!(
+        dag.search_all(&terms, SearchMode::AllOf, 10).unwrap(),
+        vec![quake_node_id.clone()]
+    );
+    assert!(dag
+        .search_all(&[], SearchMode::AllOf, 10)
+        .unwrap()
+        .is_empty());
+
+    // Nodes added with indexing off are only found after a rebuild and the rebuilt
+    // index matches the incrementally maintained one.
+    dag.set_payload_indexing(false);
+    let quiver_node_id = dag
+        .add_node("quiver", BTreeSet::from([quell_node_id.clone()]))
+        .unwrap();
+    assert!(dag.search_payloads(b"quiver", 10).unwrap().is_empty());
+    dag.set_payload_indexing(true);
+    let quorum_node_id = dag
+        .add_node("quorum hall", BTreeSet::from([quiver_node_id.clone()]))
+        .unwrap();
+    let mut incremental = dag.get_nodes().payload_index_entries().unwrap();
+    incremental
+        .entry(b"quiver".to_vec())
+        .or_default()
+        .insert(quiver_node_id.clone());
+    dag.rebuild_payload_index().unwrap();
+    assert_eq!(
+        dag.get_nodes().payload_index_entries().unwrap(),
+        incremental
+    );
+    assert_eq!(
+        dag.search_payloads(b"quiver", 10).unwrap(),
+        vec![quiver_node_id]
+    );
+    assert!(dag
+        .search_payloads(b"hall", 10)
+        .unwrap()
+        .contains(&quorum_node_id));
+}
+
+#[test]
+fn test_payload_index_store_search() {
+    check_payload_search(PayloadIndexStore::<BTreeStore<TestHasher>, _>::new(
+        BTreeStore::new(),
+        WhitespaceIndexer,
+    ));
+}
+
+#[test]
+fn test_payload_index_store_rebuilds_from_the_inner_store() {
+    let mut inner = BTreeStore::<TestHasher>::new();
+    let quake = Node::<TestHasher>::new("quake shook the hall", BTreeSet::new());
+    let quell = Node::<TestHasher>::new("quell the hall", BTreeSet::new());
+    inner.store(quake.clone()).unwrap();
+    inner.store(quell.clone()).unwrap();
+    let mut store = PayloadIndexStore::new(inner, WhitespaceIndexer);
+    assert!(
+        PayloadSearch::<TestHasher>::search_term(&store, b"hall", 10)
+            .unwrap()
+            .is_empty()
+    );
+    PayloadSearch::<TestHasher>::rebuild_payload_index(&mut store).unwrap();
+    let mut both = vec![quake.id().to_vec(), quell.id().to_vec()];
+    both.sort();
+    assert_eq!(
+        PayloadSearch::<TestHasher>::search_term(&store, b"hall", 10).unwrap(),
+        both
+    );
+    Store::<TestHasher>::delete(&mut store, quake.id()).unwrap();
+    let entries = PayloadSearch::<TestHasher>::payload_index_entries(&store).unwrap();
+    assert!(!entries.contains_key(b"quake".as_slice()));
+    assert!(!entries.contains_key(b"shook".as_slice()));
+    assert_eq!(
+        entries[b"hall".as_slice()],
+        BTreeSet::from([quell.id().to_vec()])
+    );
+}
+
+#[test]
+fn test_payload_indexers() {
+    assert_eq!(
+        WhitespaceIndexer.terms(b"  Quake\tquake  QUELL\n"),
+        vec![b"quake".to_vec(), b"quell".to_vec()]
+    );
+    assert!(WhitespaceIndexer.terms(b" \n").is_empty());
+    assert_eq!(
+        NgramIndexer { n: 3 }.terms(b"quaqua"),
+        vec![b"aqu".to_vec(), b"qua".to_vec(), b"uaq".to_vec()]
+    );
+    assert_eq!(NgramIndexer { n: 3 }.terms(b"qu"), vec![b"qu".to_vec()]);
+    assert!(NgramIndexer { n: 3 }.terms(b"").is_empty());
+}
+
+// A generated DAG where every node depends on the previous node and the node halfway
+// back, along with its nodes in id order.
+fn bulk_archive(len: usize) -> (TestDag<'static>, Vec<Node<TestHasher>>) {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let mut ids: Vec<Vec<u8>> = Vec::new();
+    for idx in 0..len {
+        let mut deps = BTreeSet::new();
+        if idx > 0 {
+            deps.insert(ids[idx - 1].clone());
+            deps.insert(ids[idx / 2].clone());
+        }
+        ids.push(dag.add_node(format!("bulk-{}", idx), deps).unwrap());
+    }
+    let archive = dag.get_nodes().values().cloned().collect();
+    (dag, archive)
+}
+
+#[test]
+fn test_bulk_load_matches_add_node() {
+    let len = 100_000;
+    let (original, archive) = bulk_archive(len);
+    let mut added = Merkle::<PrefixCountingStore, TestHasher>::default();
+    let mut topological = archive.clone();
+    topological.sort_by_key(|node| {
+        let item = String::from_utf8(node.item().to_vec()).unwrap();
+        item["bulk-".len()..].parse::<usize>().unwrap()
+    });
+    for node in topological {
+        added
+            .add_node(node.item(), node.dependency_ids().clone())
+            .unwrap();
+    }
+    let mut loaded = Merkle::<PrefixCountingStore, TestHasher>::default();
+    let report = loaded
+        .bulk_load(archive.into_iter().map(Ok), BulkLoadOpts::default())
+        .unwrap();
+    assert_eq!(report.loaded, len);
+    assert_eq!(loaded.get_roots(), original.get_roots());
+    #[cfg(feature = "cbor")]
+    assert_eq!(
+        loaded.estimated_storage_bytes(),
+        added.estimated_storage_bytes()
+    );
+    assert!(loaded
+        .get_nodes()
+        .inner
+        .keys()
+        .eq(original.get_nodes().keys()));
+    // add_node checks every dependency while bulk_load only checks each node once.
+    assert!(loaded.get_nodes().reads.get() * 2 < added.get_nodes().reads.get());
+}
+
+#[test]
+fn test_bulk_load_duration_reads_clock() {
+    let (_, archive) = bulk_archive(4);
+    let clock = SimClock::starting_at(Duration::from_secs(1_000));
+    let mut loaded = TestDag::new(BTreeMap::new());
+    loaded.set_clock(Arc::new(clock.clone()));
+    let source = archive.into_iter().map(|node| {
+        clock.advance(Duration::from_millis(250));
+        Ok(node)
+    });
+    let report = loaded.bulk_load(source, BulkLoadOpts::default()).unwrap();
+    assert_eq!(report.duration, Duration::from_secs(1));
+}
+
+#[test]
+fn test_bulk_load_out_of_order_source() {
+    let (original, archive) = bulk_archive(50);
+    let mut dag = TestDag::new(BTreeMap::new());
+    let report = dag
+        .bulk_load(archive.into_iter().rev().map(Ok), BulkLoadOpts::default())
+        .unwrap();
+    assert_eq!(report.loaded, 50);
+    assert_eq!(dag.get_roots(), original.get_roots());
+    dag.assert_invariants(Thoroughness::Full).unwrap();
+}
+
+#[test]
+fn test_bulk_load_extends_existing_dag() {
+    let (mut original, archive) = bulk_archive(20);
+    let (mut dag, _) = bulk_archive(10);
+    let report = dag
+        .bulk_load(archive.into_iter().map(Ok), BulkLoadOpts::default())
+        .unwrap();
+    assert_eq!((report.loaded, report.skipped), (10, 10));
+    assert_eq!(dag.get_roots(), original.get_roots());
+    let root = original.get_roots().iter().next().unwrap().clone();
+    let quake_node_id = original
+        .add_node("quake", BTreeSet::from([root.clone()]))
+        .unwrap();
+    dag.add_node("quake", BTreeSet::from([root])).unwrap();
+    assert_eq!(dag.get_roots(), &BTreeSet::from([quake_node_id]));
+}
+
+#[test]
+fn test_bulk_load_reports_dangling_dependency() {
+    let missing = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([missing.id().to_vec()]));
+    let quell = Node::<TestHasher>::new("quell", BTreeSet::new());
+    let mut dag = TestDag::new(BTreeMap::new());
+    let failure = dag
+        .bulk_load(
+            vec![Ok(qualm.clone()), Ok(quell)].into_iter(),
+            BulkLoadOpts {
+                rollback_on_failure: true,
+                ..BulkLoadOpts::default()
+            },
+        )
+        .unwrap_err();
+    assert_eq!(
+        failure.errors.into_iter().collect::<Vec<_>>(),
+        vec![BatchEntryError {
+            index: 0,
+            node_id: Some(qualm.id().to_vec()),
+            kind: StoreErrorKind::NoSuchNode,
+            detail: format!("missing dependency {}", hex(missing.id())),
+        }]
+    );
+    // The BTreeStore has no batches so the nodes stay but the roots are untouched.
+    assert_eq!(failure.progress, BatchProgress::Applied(2));
+    assert!(dag.check_for_node(qualm.id()).unwrap());
+    assert!(dag.get_roots().is_empty());
+}
+
+// An archive of quake nodes where the entries at 1, 3, 4, 6 and 8 are bad.
+fn bad_archive() -> Vec<crate::store::Result<Node<TestHasher>>> {
+    let dangling = |name: &str| {
+        let missing = Node::<TestHasher>::new(format!("missing-{}", name), BTreeSet::new());
+        Ok(Node::new(name, BTreeSet::from([missing.id().to_vec()])))
+    };
+    vec![
+        Ok(Node::new("quake-0", BTreeSet::new())),
+        Err(StoreError::StoreFailure("truncated record".to_owned())),
+        Ok(Node::new("quake-2", BTreeSet::new())),
+        dangling("quake-3"),
+        Err(StoreError::InvalidIdPrefix("zz".to_owned())),
+        Ok(Node::new("quake-5", BTreeSet::new())),
+        dangling("quake-6"),
+        Ok(Node::new("quake-7", BTreeSet::new())),
+        Err(StoreError::Unsupported("decode")),
+    ]
+}
+
+#[test]
+fn test_bulk_load_reports_every_bad_entry() {
+    let archive = bad_archive();
+    let dangling_ids: Vec<Vec<u8>> = [3, 6]
+        .iter()
+        .map(|idx| archive[*idx].as_ref().unwrap().id().to_vec())
+        .collect();
+    let mut dag = TestDag::new(BTreeMap::new());
+    let failure = dag
+        .bulk_load(archive.into_iter(), BulkLoadOpts::default())
+        .unwrap_err();
+    assert_eq!(failure.errors.total(), 5);
+    assert_eq!(
+        failure
+            .errors
+            .iter()
+            .map(|e| (e.index, e.kind))
+            .collect::<Vec<_>>(),
+        vec![
+            (1, StoreErrorKind::StoreFailure),
+            (4, StoreErrorKind::InvalidIdPrefix),
+            (8, StoreErrorKind::Unsupported),
+            (3, StoreErrorKind::NoSuchNode),
+            (6, StoreErrorKind::NoSuchNode),
+        ]
+    );
+    assert_eq!(failure.progress, BatchProgress::Applied(6));
+    assert!(dag.get_roots().is_empty());
+    let entries: Vec<BatchEntryError> = failure.errors.into_iter().collect();
+    assert!(entries[..3].iter().all(|e| e.node_id.is_none()));
+    assert_eq!(
+        entries[3..]
+            .iter()
+            .map(|e| e.node_id.clone().unwrap())
+            .collect::<Vec<_>>(),
+        dangling_ids
+    );
+}
+
+#[test]
+fn test_bulk_load_error_cap_counts_overflow() {
+    let mut dag = TestDag::new(BTreeMap::new());
+    let failure = dag
+        .bulk_load(
+            bad_archive().into_iter(),
+            BulkLoadOpts {
+                max_errors: 2,
+                ..BulkLoadOpts::default()
+            },
+        )
+        .unwrap_err();
+    assert_eq!(failure.errors.len(), 2);
+    assert_eq!(failure.errors.overflow(), 3);
+    assert_eq!(failure.errors.total(), 5);
+    assert!(failure
+        .to_string()
+        .ends_with("... 3 more errors not recorded\n"));
+}
+
+#[test]
+fn test_batch_failure_display() {
+    let mut errors = BatchErrors::with_cap(2);
+    errors.push(BatchEntryError {
+        index: 3,
+        node_id: Some(vec![0xab, 0x01]),
+        kind: StoreErrorKind::NoSuchNode,
+        detail: "missing dependency ffff".to_owned(),
+    });
+    errors.push(BatchEntryError::from_store_error(
+        12,
+        None,
+        &StoreError::StoreFailure("truncated record".to_owned()),
+    ));
+    errors.push(BatchEntryError::from_store_error(
+        13,
+        None,
+        &StoreError::NoSuchDependents,
+    ));
+    let failure = BatchFailure {
+        errors,
+        progress: BatchProgress::Applied(7),
+    };
+    assert_eq!(
+        failure.to_string(),
+        concat!(
+            "batch failed: 7 applied\n",
+            "3 errors\n",
+            "index  node_id  kind          detail\n",
+            "    3  ab01     NoSuchNode    missing dependency ffff\n",
+            "   12  -        StoreFailure  StoreFailure(\"truncated record\")\n",
+            "... 1 more errors not recorded\n",
+        )
+    );
+}
+
+// A WorkMeter that throttles once a budget of units has been spent.
+struct BudgetMeter(std::sync::atomic::AtomicUsize);
+
+impl WorkMeter for BudgetMeter {
+    fn charge(&self, units: WorkUnits) -> Result<(), Throttled> {
+        let amount = match units {
+            WorkUnits::NodesVisited(n)
+            | WorkUnits::StoreReads(n)
+            | WorkUnits::BytesSerialized(n) => n,
+        };
+        let ordering = std::sync::atomic::Ordering::SeqCst;
+        if self
+            .0
+            .fetch_update(ordering, ordering, |left| left.checked_sub(amount))
+            .is_err()
+        {
+            return Err(Throttled {
+                retry_after_hint: Some(std::time::Duration::from_secs(1)),
+            });
+        }
+        Ok(())
+    }
+}
+
+// A WorkMeter that counts the units charged for each class of work.
+#[derive(Default)]
+struct CountingMeter(std::sync::Mutex<BTreeMap<&'static str, usize>>);
+
+impl CountingMeter {
+    fn take(&self) -> BTreeMap<&'static str, usize> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl WorkMeter for CountingMeter {
+    fn charge(&self, units: WorkUnits) -> Result<(), Throttled> {
+        let (class, amount) = match units {
+            WorkUnits::NodesVisited(n) => ("visits", n),
+            WorkUnits::StoreReads(n) => ("reads", n),
+            WorkUnits::BytesSerialized(n) => ("bytes", n),
+        };
+        *self.0.lock().unwrap().entry(class).or_insert(0) += amount;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_work_meter_throttles_deep_compare() {
+    let (mut dag, ids) = chain_dag(500);
+    dag.set_work_meter(Some(std::sync::Arc::new(BudgetMeter(100.into()))));
+    match dag.compare(&ids[0], &ids[499]) {
+        Err(StoreError::Throttled { retry_after_hint }) => {
+            assert_eq!(retry_after_hint, Some(std::time::Duration::from_secs(1)))
+        }
+        other => panic!("Expected the compare to be throttled {:?}", other),
+    }
+    dag.set_work_meter(None);
+    assert_eq!(
+        dag.compare(&ids[0], &ids[499]).unwrap(),
+        NodeCompare::Before
+    );
+}
+
+type MeteredOperation<'a> = Box<dyn Fn(&IndexedTestDag) + 'a>;
+
+#[test]
+fn test_work_meter_covers_unbounded_operations() {
+    let meter = std::sync::Arc::new(CountingMeter::default());
+    let mut dag = IndexedTestDag::default();
+    let mut ids: Vec<Vec<u8>> = Vec::new();
+    for idx in 0..300 {
+        let deps = ids.last().cloned().into_iter().collect();
+        ids.push(dag.add_node(format!("metered-{}", idx), deps).unwrap());
+    }
+    dag.set_work_meter(Some(meter.clone()));
+    let head = ids.last().unwrap().clone();
+    let peer_heads = BTreeMap::from([
+        (b"one".to_vec(), BTreeSet::from([ids[100].clone()])),
+        (b"two".to_vec(), BTreeSet::from([head.clone()])),
+    ]);
+    let operations: Vec<(&str, MeteredOperation)> = vec![
+        (
+            "compare",
+            Box::new(|dag| {
+                dag.compare(&ids[0], &head).unwrap();
+            }),
+        ),
+        (
+            "ancestors_of",
+            Box::new(|dag| {
+                dag.ancestors_of(&head).unwrap();
+            }),
+        ),
+        (
+            "descendants_of",
+            Box::new(|dag| {
+                dag.descendants_of(&ids[0]).unwrap();
+            }),
+        ),
+        (
+            "closure_stats",
+            Box::new(|dag| {
+                dag.closure_stats(&head).unwrap();
+            }),
+        ),
+        (
+            "path_between",
+            Box::new(|dag| {
+                dag.path_between(&head, &ids[0]).unwrap();
+            }),
+        ),
+        (
+            "missing",
+            Box::new(|dag| {
+                dag.missing(BTreeSet::new()).take(3).for_each(|nodes| {
+                    nodes.unwrap();
+                });
+            }),
+        ),
+        (
+            "fleet_divergence",
+            Box::new(|dag| {
+                dag.fleet_divergence(&peer_heads).unwrap();
+            }),
+        ),
+    ];
+    for (name, operation) in operations {
+        meter.take();
+        operation(&dag);
+        let charged = meter.take();
+        assert!(
+            charged.get("visits").copied().unwrap_or(0) > 0,
+            "{} visits",
+            name
+        );
+        assert!(
+            charged.get("reads").copied().unwrap_or(0) > 0,
+            "{} reads",
+            name
+        );
+    }
+    let archive: Vec<Node<TestHasher>> = ids
+        .iter()
+        .map(|id| dag.get_node_by_id(id).unwrap().unwrap())
+        .collect();
+    let mut loaded = IndexedTestDag::default();
+    loaded.set_work_meter(Some(meter.clone()));
+    meter.take();
+    loaded
+        .bulk_load(archive.into_iter().map(Ok), BulkLoadOpts::default())
+        .unwrap();
+    assert!(meter.take().get("visits").copied().unwrap_or(0) > 0);
+}
+
+#[cfg(feature = "cbor")]
+fn check_get_raw_matches_get<S: Store<TestHasher>>(mut store: S) {
+    let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+    let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+    for node in [quake, qualm] {
+        let id = node.id().to_vec();
+        store.store(node).unwrap();
+        let raw = store.get_raw(&id).unwrap().unwrap();
+        let decoded: Node<TestHasher> = crate::store::codec::decode(&raw).unwrap();
+        let node = store.get(&id).unwrap().unwrap();
+        assert_eq!(decoded.id(), node.id());
+        assert_eq!(decoded.item(), node.item());
+        assert_eq!(decoded.dependency_ids(), node.dependency_ids());
+    }
+    assert!(store.get_raw(b"missing").unwrap().is_none());
+}
+
+#[cfg(feature = "cbor")]
+fn check_store_stats<S: Store<TestHasher>>(store: S) {
+    use crate::store::{codec, StoreStats};
+    let mut dag = Merkle::<S, TestHasher>::new(store);
+    let empty = dag.store_stats().unwrap();
+    assert_eq!(empty, StoreStats::default());
+    assert_eq!(empty.mean_node_bytes(), 0.0);
+    let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+    let qualm = dag
+        .add_node("qualm".repeat(40), BTreeSet::from([quake.clone()]))
+        .unwrap();
+    let quell = dag
+        .add_node("quell", BTreeSet::from([quake.clone(), qualm.clone()]))
+        .unwrap();
+    let sizes: Vec<u64> = [&quake, &qualm, &quell]
+        .iter()
+        .map(|id| codec::encoded_size(&dag.get_node_by_id(id).unwrap().unwrap()) as u64)
+        .collect();
+    let stats = dag.store_stats().unwrap();
+    assert_eq!(
+        stats,
+        StoreStats {
+            node_count: 3,
+            total_bytes: sizes.iter().sum(),
+            max_node_bytes: sizes[1],
+        }
+    );
+    assert_eq!(
+        stats.mean_node_bytes(),
+        sizes.iter().sum::<u64>() as f64 / 3.0
+    );
+}
+
+#[cfg(feature = "cbor")]
+mod cbor_serialization_tests {
+    use super::TestDag;
+    use crate::prelude::*;
+    use crate::store::{codec, BTreeStore, SerializedCache, Store};
+    use crate::test::TestHasher;
+    use ciborium::{de::from_reader, ser::into_writer};
+    use std::collections::BTreeMap;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_read_token_round_trip() {
+        let mut dag = TestDag::new(BTreeMap::new());
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        dag.add_node("qualm", BTreeSet::new()).unwrap();
+        let token = dag.read_token();
+        let mut encoded: Vec<u8> = Vec::new();
+        into_writer(&token, &mut encoded).unwrap();
+        let decoded: ReadToken = from_reader(encoded.as_slice()).unwrap();
+        assert_eq!(decoded, token);
+        dag.add_node("quell", BTreeSet::from([quake])).unwrap();
+        assert!(dag.satisfies(&decoded).unwrap());
+    }
+
+    #[test]
+    fn test_node_deserializaton() {
+        let mut dag = TestDag::new(BTreeMap::new());
+        let simple_node_id = dag.add_node("simple", BTreeSet::new()).unwrap();
+        let mut dep_set = BTreeSet::new();
+        dep_set.insert(simple_node_id.clone());
+        let root_node_id = dag.add_node("root", dep_set).unwrap();
+
+        let simple_node_to_serialize = dag
+            .get_node_by_id(simple_node_id.as_slice())
+            .unwrap()
+            .unwrap();
+        let root_node_to_serialize = dag
+            .get_node_by_id(root_node_id.as_slice())
+            .unwrap()
+            .unwrap();
+
+        let mut simple_node_vec: Vec<u8> = Vec::new();
+        let mut root_node_vec: Vec<u8> = Vec::new();
+        into_writer(&simple_node_to_serialize, &mut simple_node_vec).unwrap();
+        into_writer(&root_node_to_serialize, &mut root_node_vec).unwrap();
+
+        let simple_node_de: Node<TestHasher> = from_reader(simple_node_vec.as_slice()).unwrap();
+        let root_node_de: Node<TestHasher> = from_reader(root_node_vec.as_slice()).unwrap();
+        assert_eq!(simple_node_to_serialize.id(), simple_node_de.id());
+        assert_eq!(simple_node_to_serialize.item_id(), simple_node_de.item_id());
+        assert_eq!(simple_node_to_serialize.item(), simple_node_de.item());
+        assert_eq!(
+            simple_node_to_serialize.dependency_ids(),
+            simple_node_de.dependency_ids()
+        );
+        assert_eq!(root_node_to_serialize.id(), root_node_de.id());
+        assert_eq!(root_node_to_serialize.item_id(), root_node_de.item_id());
+        assert_eq!(root_node_to_serialize.item(), root_node_de.item());
+        assert_eq!(
+            root_node_to_serialize.dependency_ids(),
+            root_node_de.dependency_ids()
+        );
+    }
+
+    #[test]
+    fn test_node_with_malformed_dependency_fails_to_deserialize() {
+        let node = Node::<TestHasher>::new("payload", BTreeSet::from([b"short".to_vec()]));
+        let mut encoded = Vec::new();
+        into_writer(&node, &mut encoded).unwrap();
+        let decoded: Result<Node<TestHasher>, _> = from_reader(encoded.as_slice());
+        assert!(decoded.is_err());
+    }
+
+    // The encoding Node used before dependency ids were stored in a DepSet.
+    #[derive(serde::Serialize)]
+    struct BTreeSetNode<'a> {
+        id: &'a [u8],
+        item: &'a [u8],
+        item_id: &'a [u8],
+        dependency_ids: BTreeSet<Vec<u8>>,
+        _phantom: std::marker::PhantomData<TestHasher>,
+    }
+
+    #[test]
+    fn test_dep_set_serialization_matches_btree_set() {
+        for count in [0, 1, 3, 100] {
+            let deps: BTreeSet<Vec<u8>> = (0..count)
+                .map(|idx| format!("dep {:04}", idx).into_bytes())
+                .collect();
+            let node =
+                Node::<TestHasher>::new_with_id_version("payload", deps.clone(), NodeIdVersion::V0);
+            let mut expected = Vec::new();
+            into_writer(
+                &BTreeSetNode {
+                    id: node.id(),
+                    item: node.item(),
+                    item_id: node.item_id(),
+                    dependency_ids: deps.clone(),
+                    _phantom: std::marker::PhantomData,
+                },
+                &mut expected,
+            )
+            .unwrap();
+            let mut actual = Vec::new();
+            into_writer(&node, &mut actual).unwrap();
+            assert_eq!(expected, actual, "{} dependencies", count);
+            let node_de: Node<TestHasher> = from_reader(actual.as_slice()).unwrap();
+            assert_eq!(node.id(), node_de.id());
+            assert_eq!(&deps, node_de.dependency_ids(), "{} dependencies", count);
+            assert_eq!(
+                deps,
+                BTreeSet::from(node_de.dep_set().clone()),
+                "{} dependencies",
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_raw_matches_get() {
+        super::check_get_raw_matches_get(BTreeStore::<TestHasher>::new());
+    }
+
+    #[test]
+    fn test_store_stats() {
+        super::check_store_stats(BTreeStore::<TestHasher>::new());
+    }
+
+    #[test]
+    fn test_fan_out_encodes_hot_nodes_once() {
+        let mut dag = Merkle::<SerializedCache<BTreeStore<TestHasher>>, TestHasher>::new(
+            SerializedCache::new(BTreeStore::new(), 16),
+        );
+        let mut hot = Vec::new();
+        for i in 0..5 {
+            let deps = hot.last().cloned().into_iter().collect();
+            hot.push(dag.add_node(format!("hot {}", i), deps).unwrap());
+        }
+        let before = codec::encode_count();
+        let mut sent: Vec<Vec<Vec<u8>>> = vec![Vec::new(); 10];
+        for _round in 0..3 {
+            for peer in sent.iter_mut() {
+                for id in hot.iter() {
+                    peer.push(dag.get_encoded_node(id).unwrap().unwrap());
+                }
+            }
+        }
+        assert_eq!(codec::encode_count() - before, hot.len() as u64);
+        for peer in sent {
+            for (bytes, id) in peer.iter().zip(hot.iter().cycle()) {
+                let node: Node<TestHasher> = codec::decode(bytes).unwrap();
+                assert_eq!(node.id(), id.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn test_codec_version_bump_invalidates_cached_encodings() {
+        let mut store = SerializedCache::new(BTreeStore::<TestHasher>::new(), 16);
+        let node = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let id = node.id().to_vec();
+        store.store(node).unwrap();
+        let before = codec::encode_count();
+        let cached = store.get_raw(&id).unwrap().unwrap();
+        assert_eq!(store.get_raw(&id).unwrap().unwrap(), cached);
+        assert_eq!(codec::encode_count() - before, 1);
+        store.set_codec_version(store.codec_version() + 1);
+        assert_eq!(store.get_raw(&id).unwrap().unwrap(), cached);
+        assert_eq!(codec::encode_count() - before, 2);
+        assert_eq!(store.cached(), 2);
+    }
+
+    #[test]
+    fn test_serialized_cache_evicts_least_recently_used() {
+        let mut store = SerializedCache::new(BTreeStore::<TestHasher>::new(), 2);
+        let mut ids = Vec::new();
+        for payload in ["quake", "qualm", "quell"] {
+            let node = Node::<TestHasher>::new(payload, BTreeSet::new());
+            ids.push(node.id().to_vec());
+            store.store(node).unwrap();
+        }
+        store.get_raw(&ids[0]).unwrap();
+        store.get_raw(&ids[1]).unwrap();
+        store.get_raw(&ids[0]).unwrap();
+        store.get_raw(&ids[2]).unwrap();
+        assert_eq!(store.cached(), 2);
+        let before = codec::encode_count();
+        store.get_raw(&ids[0]).unwrap();
+        assert_eq!(codec::encode_count(), before);
+        store.get_raw(&ids[1]).unwrap();
+        assert_eq!(codec::encode_count(), before + 1);
+    }
+}
+
+#[cfg(feature = "flate2")]
+mod compression_tests {
+    use crate::prelude::*;
+    use crate::store::{codec, CompressedStore, Store, COMPRESSED_TAG};
+    use crate::test::{temp_path, TestHasher};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    // A store keeping the at rest bytes of its records.
+    #[derive(Default)]
+    struct RecordStore(BTreeMap<Vec<u8>, Vec<u8>>);
+
+    impl Store<TestHasher> for RecordStore {
+        fn contains(&self, id: &[u8]) -> crate::store::Result<bool> {
+            Ok(self.0.contains_key(id))
+        }
+
+        fn get(&self, id: &[u8]) -> crate::store::Result<Option<Node<TestHasher>>> {
+            self.0.get(id).map(|bytes| codec::decode(bytes)).transpose()
+        }
+
+        fn store(&mut self, node: Node<TestHasher>) -> crate::store::Result<()> {
+            self.0.insert(node.id().to_vec(), codec::encode(&node));
+            Ok(())
+        }
+
+        fn get_raw(&self, id: &[u8]) -> crate::store::Result<Option<Vec<u8>>> {
+            Ok(self.0.get(id).cloned())
+        }
+
+        fn ids(
+            &self,
+        ) -> crate::store::Result<Box<dyn Iterator<Item = crate::store::Result<Vec<u8>>> + '_>>
+        {
+            Ok(Box::new(self.0.keys().cloned().map(Ok)))
+        }
+
+        fn store_encoded(
+            &mut self,
+            node: Node<TestHasher>,
+            encoded: Vec<u8>,
+        ) -> crate::store::Result<()> {
+            self.0.insert(node.id().to_vec(), encoded);
+            Ok(())
+        }
+    }
+
+    fn json_payload(idx: usize) -> String {
+        (0..20)
+            .map(|field| format!(r#"{{"replica": "quake-{}", "field": {}}}"#, idx, field))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    // Checks nodes round trip through a compressing store and a record written without the
+    // wrapper can still be read through it.
+    fn check_compressed_round_trip<S: Store<TestHasher>>(mut legacy: S) {
+        let old = Node::<TestHasher>::new(json_payload(0), BTreeSet::new());
+        let old_id = old.id().to_vec();
+        legacy.store(old).unwrap();
+        let mut dag = Merkle::<_, TestHasher>::from_store(CompressedStore::new(legacy)).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([old_id.clone()]));
+        let mut ids = vec![old_id];
+        for idx in 1..5 {
+            let deps = BTreeSet::from([ids[idx - 1].clone()]);
+            ids.push(dag.add_node(json_payload(idx), deps).unwrap());
+        }
+        for (idx, id) in ids.iter().enumerate() {
+            let node = dag.get_node_by_id(id).unwrap().unwrap();
+            assert_eq!(node.item(), json_payload(idx).as_bytes());
+            assert_eq!(
+                dag.get_encoded_node(id).unwrap().unwrap(),
+                codec::encode(&node)
+            );
+        }
+        let record = dag.get_nodes().inner().get_raw(&ids[4]).unwrap().unwrap();
+        assert_eq!(record[0], COMPRESSED_TAG);
+        let node = dag.get_node_by_id(&ids[4]).unwrap().unwrap();
+        assert!(record.len() * 5 < codec::encoded_size(&node));
+        let legacy_record = dag.get_nodes().inner().get_raw(&ids[0]).unwrap().unwrap();
+        assert_ne!(legacy_record[0], COMPRESSED_TAG);
+    }
+
+    #[test]
+    fn test_compressed_store_round_trip() {
+        check_compressed_round_trip(RecordStore::default());
+    }
+
+    #[test]
+    fn test_compressed_store_keeps_incompressible_records() {
+        // Deflate without compression only adds to the record.
+        let mut store = CompressedStore::new(RecordStore::default()).with_level(0);
+        let node = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let id = node.id().to_vec();
+        store.store(node.clone()).unwrap();
+        assert_eq!(store.inner().0[&id], codec::encode(&node));
+        let stored: Node<TestHasher> = store.get(&id).unwrap().unwrap();
+        assert_eq!(stored.item(), b"quake");
+    }
+
+    #[cfg(feature = "rusty-leveldb")]
+    #[test]
+    fn test_compressed_level_store_round_trip() {
+        check_compressed_round_trip(crate::leveldb::LevelStore::default());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_compressed_sqlite_store_round_trip() {
+        check_compressed_round_trip(crate::sqlite::SqliteStore::in_memory().unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_compressed_store_decompresses_quarantined_records() {
+        use crate::blake2::Blake2b512;
+        let mut store = CompressedStore::new(crate::sqlite::SqliteStore::in_memory().unwrap());
+        let foreign = Node::<Blake2b512>::new(json_payload(0), BTreeSet::new());
+        Store::<Blake2b512>::store(&mut store, foreign.clone()).unwrap();
+        assert_eq!(
+            Store::<TestHasher>::quarantine_foreign_keys(&mut store, 8).unwrap(),
+            1
+        );
+        let record = Store::<TestHasher>::get_quarantined(&store, foreign.id())
+            .unwrap()
+            .unwrap();
+        let decoded = codec::decode::<Blake2b512>(&record).unwrap();
+        assert_eq!(decoded.id(), foreign.id());
+        assert_eq!(decoded.item(), foreign.item());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_compressed_sqlite_store_roots_survive_reopen() {
+        use crate::sqlite::SqliteStore;
+        let path = temp_path("compressed-roots.sqlite");
+        SqliteStore::connect(&path).unwrap().init_db().unwrap();
+        super::check_roots_survive_reopen(|| {
+            CompressedStore::new(SqliteStore::connect(&path).unwrap())
+        });
+        let mut store = CompressedStore::new(SqliteStore::connect(&path).unwrap());
+        let ids: Vec<Vec<u8>> = Store::<TestHasher>::ids(&store)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        for id in &ids {
+            let record = Store::<TestHasher>::get_raw(store.inner(), id)
+                .unwrap()
+                .unwrap();
+            assert!(Store::<TestHasher>::get(&store, id).unwrap().is_some());
+            assert_eq!(
+                record[0] == COMPRESSED_TAG,
+                codec::decode::<TestHasher>(&record).is_err()
+            );
+        }
+        let nodes: Vec<Node<TestHasher>> = (0..3)
+            .map(|idx| Node::new(json_payload(idx), BTreeSet::new()))
+            .collect();
+        store.store_many(nodes.clone()).unwrap();
+        let many: Vec<Option<Node<TestHasher>>> = store
+            .get_many(&nodes.iter().map(Node::id).collect::<Vec<_>>())
+            .unwrap();
+        assert!(many.into_iter().all(|node| node.is_some()));
+        for node in &nodes {
+            let record = Store::<TestHasher>::get_raw(store.inner(), node.id())
+                .unwrap()
+                .unwrap();
+            assert_eq!(record[0], COMPRESSED_TAG);
+        }
+        drop(store);
+    }
+}
+
+#[cfg(feature = "encryption")]
+mod encryption_tests {
+    use crate::prelude::*;
+    use crate::store::{codec, EncryptedStore, Store, StoreError, ENCRYPTED_TAG};
+    use crate::test::{temp_path, TestHasher};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    const KEY: [u8; 32] = [7; 32];
+
+    // A store keeping the at rest bytes of its records.
+    #[derive(Default)]
+    struct RecordStore(BTreeMap<Vec<u8>, Vec<u8>>);
+
+    impl Store<TestHasher> for RecordStore {
+        fn contains(&self, id: &[u8]) -> crate::store::Result<bool> {
+            Ok(self.0.contains_key(id))
+        }
+
+        fn get(&self, id: &[u8]) -> crate::store::Result<Option<Node<TestHasher>>> {
+            self.0.get(id).map(|bytes| codec::decode(bytes)).transpose()
+        }
+
+        fn store(&mut self, node: Node<TestHasher>) -> crate::store::Result<()> {
+            self.0.insert(node.id().to_vec(), codec::encode(&node));
+            Ok(())
+        }
+
+        fn get_raw(&self, id: &[u8]) -> crate::store::Result<Option<Vec<u8>>> {
+            Ok(self.0.get(id).cloned())
+        }
+
+        fn ids(
+            &self,
+        ) -> crate::store::Result<Box<dyn Iterator<Item = crate::store::Result<Vec<u8>>> + '_>>
+        {
+            Ok(Box::new(self.0.keys().cloned().map(Ok)))
+        }
+
+        fn store_encoded(
+            &mut self,
+            node: Node<TestHasher>,
+            encoded: Vec<u8>,
+        ) -> crate::store::Result<()> {
+            self.0.insert(node.id().to_vec(), encoded);
+            Ok(())
+        }
+    }
+
+    fn encrypted_dag() -> (
+        Merkle<EncryptedStore<RecordStore>, TestHasher>,
+        Vec<Vec<u8>>,
+    ) {
+        let mut dag = Merkle::new(EncryptedStore::new(RecordStore::default(), &KEY));
+        let first = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let second = dag
+            .add_node("quake 2", BTreeSet::from([first.clone()]))
+            .unwrap();
+        (dag, vec![first, second])
+    }
+
+    // Checks nodes round trip through an encrypting store and the payload is not at rest in
+    // the clear.
+    fn check_encrypted_round_trip<S: Store<TestHasher>>(store: S) {
+        let mut dag = Merkle::<_, TestHasher>::new(EncryptedStore::new(store, &KEY));
+        let mut ids: Vec<Vec<u8>> = Vec::new();
+        for idx in 0..5 {
+            let deps = ids.last().cloned().into_iter().collect();
+            ids.push(dag.add_node(format!("secret-{}", idx), deps).unwrap());
+        }
+        for (idx, id) in ids.iter().enumerate() {
+            let node = dag.get_node_by_id(id).unwrap().unwrap();
+            assert_eq!(node.item(), format!("secret-{}", idx).as_bytes());
+            assert_eq!(
+                dag.get_encoded_node(id).unwrap().unwrap(),
+                codec::encode(&node)
+            );
+            let record = dag.get_nodes().inner().get_raw(id).unwrap().unwrap();
+            assert_eq!(record[0], ENCRYPTED_TAG);
+            assert!(!record.windows(6).any(|window| window == b"secret"));
+        }
+        dag.reconstruct_roots().unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([ids[4].clone()]));
+    }
+
+    #[test]
+    fn test_encrypted_store_round_trip() {
+        check_encrypted_round_trip(RecordStore::default());
+    }
+
+    #[test]
+    fn test_encrypted_store_detects_tampering() {
+        let (dag, ids) = encrypted_dag();
+        let mut inner = dag.get_nodes().inner().0.clone();
+        let tampered = inner.get_mut(&ids[1]).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        let store = EncryptedStore::new(RecordStore(inner), &KEY);
+        let result: crate::store::Result<Option<Node<TestHasher>>> = store.get(&ids[1]);
+        assert!(matches!(result, Err(StoreError::DecryptionFailed(ref id)) if id == &ids[1]));
+        // The untouched record still reads.
+        let first: Node<TestHasher> = store.get(&ids[0]).unwrap().unwrap();
+        assert_eq!(first.item(), b"quake");
+        assert_eq!(
+            store.get_raw(&ids[1]).unwrap_err().kind(),
+            crate::store::StoreErrorKind::DecryptionFailed
+        );
+    }
+
+    #[test]
+    fn test_encrypted_store_binds_records_to_ids() {
+        let (dag, ids) = encrypted_dag();
+        let mut inner = dag.get_nodes().inner().0.clone();
+        let moved = inner[&ids[1]].clone();
+        inner.insert(ids[0].clone(), moved);
+        let store = EncryptedStore::new(RecordStore(inner), &KEY);
+        let result: crate::store::Result<Option<Node<TestHasher>>> = store.get(&ids[0]);
+        assert!(matches!(result, Err(StoreError::DecryptionFailed(_))));
+    }
+
+    #[test]
+    fn test_encrypted_store_refuses_wrong_key_and_plain_records() {
+        let (dag, ids) = encrypted_dag();
+        let store = EncryptedStore::new(RecordStore(dag.get_nodes().inner().0.clone()), &[8; 32]);
+        let result: crate::store::Result<Option<Node<TestHasher>>> = store.get(&ids[0]);
+        assert!(matches!(result, Err(StoreError::DecryptionFailed(_))));
+
+        let mut plain = RecordStore::default();
+        let node = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let id = node.id().to_vec();
+        plain.store(node).unwrap();
+        let store = EncryptedStore::new(plain, &KEY);
+        let result: crate::store::Result<Option<Node<TestHasher>>> = store.get(&id);
+        assert!(matches!(result, Err(StoreError::DecryptionFailed(_))));
+    }
+
+    #[cfg(feature = "rusty-leveldb")]
+    #[test]
+    fn test_encrypted_level_store_round_trip() {
+        check_encrypted_round_trip(crate::leveldb::LevelStore::default());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_encrypted_sqlite_store_round_trip() {
+        check_encrypted_round_trip(crate::sqlite::SqliteStore::in_memory().unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_encrypted_store_decrypts_quarantined_records() {
+        use crate::blake2::Blake2b512;
+        let mut store = EncryptedStore::new(crate::sqlite::SqliteStore::in_memory().unwrap(), &KEY);
+        let foreign = Node::<Blake2b512>::new("foreign", BTreeSet::new());
+        Store::<Blake2b512>::store(&mut store, foreign.clone()).unwrap();
+        assert_eq!(
+            Store::<TestHasher>::quarantine_foreign_keys(&mut store, 8).unwrap(),
+            1
+        );
+        let record = Store::<TestHasher>::get_quarantined(&store, foreign.id())
+            .unwrap()
+            .unwrap();
+        let decoded = codec::decode::<Blake2b512>(&record).unwrap();
+        assert_eq!(decoded.id(), foreign.id());
+        assert_eq!(decoded.item(), foreign.item());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_encrypted_sqlite_store_roots_survive_reopen() {
+        use crate::sqlite::SqliteStore;
+        let path = temp_path("encrypted-roots.sqlite");
+        SqliteStore::connect(&path).unwrap().init_db().unwrap();
+        super::check_roots_survive_reopen(|| {
+            EncryptedStore::new(SqliteStore::connect(&path).unwrap(), &KEY)
+        });
+        let mut store = EncryptedStore::new(SqliteStore::connect(&path).unwrap(), &KEY);
+        let nodes: Vec<Node<TestHasher>> = (0..3)
+            .map(|idx| Node::new(format!("secret-{}", idx), BTreeSet::new()))
+            .collect();
+        store.store_many(nodes.clone()).unwrap();
+        let many: Vec<Option<Node<TestHasher>>> = store
+            .get_many(&nodes.iter().map(Node::id).collect::<Vec<_>>())
+            .unwrap();
+        for (node, stored) in nodes.iter().zip(many) {
+            assert_eq!(stored.unwrap().item(), node.item());
+        }
+        let ids: Vec<Vec<u8>> = Store::<TestHasher>::ids(&store)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(ids.len(), 7);
+        for id in &ids {
+            let record = Store::<TestHasher>::get_raw(store.inner(), id)
+                .unwrap()
+                .unwrap();
+            assert_eq!(record[0], ENCRYPTED_TAG);
+        }
+        drop(store);
+    }
+
+    #[test]
+    fn test_encrypted_payload_round_trips() {
+        let key = PayloadKey::new(&KEY);
+        let mut dag = crate::test::TestDag::new(BTreeMap::new());
+        let quake = dag.add_encrypted("quake", BTreeSet::new(), &key).unwrap();
+        let qualm = dag
+            .add_encrypted("qualm", BTreeSet::from([quake.clone()]), &key)
+            .unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm.clone()]));
+        for (id, plaintext) in [(&quake, "quake"), (&qualm, "qualm")] {
+            let node = dag.get_node_by_id(id).unwrap().unwrap();
+            assert!(!node.item().windows(5).any(|w| w == plaintext.as_bytes()));
+            assert_eq!(node.decrypt_item(&key).unwrap(), plaintext.as_bytes());
+            // The id is computed over the ciphertext so it verifies without the key.
+            let rebuilt = Node::<TestHasher>::new(node.item(), node.dependency_ids().clone());
+            assert_eq!(rebuilt.id(), id.as_slice());
+        }
+        let empty = dag.add_encrypted("", BTreeSet::new(), &key).unwrap();
+        let node = dag.get_node_by_id(&empty).unwrap().unwrap();
+        assert!(node.decrypt_item(&key).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_encrypted_payload_fails_with_wrong_key() {
+        let mut dag = crate::test::TestDag::new(BTreeMap::new());
+        for mode in [EncryptionMode::RandomNonce, EncryptionMode::Deterministic] {
+            let key = PayloadKey::new(&KEY).with_mode(mode);
+            let id = dag.add_encrypted("quake", BTreeSet::new(), &key).unwrap();
+            let node = dag.get_node_by_id(&id).unwrap().unwrap();
+            let wrong = PayloadKey::new(&[8; 32]).with_mode(mode);
+            assert!(matches!(
+                node.decrypt_item(&wrong),
+                Err(StoreError::DecryptionFailed(failed)) if failed == id
+            ));
+            // Items are decrypted with the mode they were encrypted with.
+            let other_mode = PayloadKey::new(&KEY).with_mode(match mode {
+                EncryptionMode::RandomNonce => EncryptionMode::Deterministic,
+                EncryptionMode::Deterministic => EncryptionMode::RandomNonce,
+            });
+            assert_eq!(node.decrypt_item(&other_mode).unwrap(), b"quake");
+            let mut tampered = node.item().to_vec();
+            *tampered.last_mut().unwrap() ^= 1;
+            let tampered = Node::<TestHasher>::new(tampered, BTreeSet::new());
+            assert!(matches!(
+                tampered.decrypt_item(&key),
+                Err(StoreError::DecryptionFailed(_))
+            ));
+        }
+        let plain = Node::<TestHasher>::new("quake", BTreeSet::new());
+        assert!(matches!(
+            plain.decrypt_item(&PayloadKey::new(&KEY)),
+            Err(StoreError::DecryptionFailed(_))
+        ));
+        assert!(format!("{:?}", PayloadKey::new(&KEY)).contains("RandomNonce"));
+    }
+
+    #[test]
+    fn test_random_nonce_mode_gives_distinct_ids() {
+        let key = PayloadKey::new(&KEY);
+        assert_eq!(key.mode(), EncryptionMode::RandomNonce);
+        let mut dag = crate::test::TestDag::new(BTreeMap::new());
+        let first = dag.add_encrypted("quake", BTreeSet::new(), &key).unwrap();
+        let second = dag.add_encrypted("quake", BTreeSet::new(), &key).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(dag.node_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_deterministic_mode_dedups() {
+        let key = PayloadKey::new(&KEY).with_mode(EncryptionMode::Deterministic);
+        let mut dag = crate::test::TestDag::new(BTreeMap::new());
+        let first = dag.add_encrypted("quake", BTreeSet::new(), &key).unwrap();
+        let second = dag.add_encrypted("quake", BTreeSet::new(), &key).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(dag.node_count().unwrap(), 1);
+        // Different plaintexts and keys still give different nodes.
+        let qualm = dag.add_encrypted("qualm", BTreeSet::new(), &key).unwrap();
+        assert_ne!(qualm, first);
+        let other_key = PayloadKey::new(&[8; 32]).with_mode(EncryptionMode::Deterministic);
+        let other = dag
+            .add_encrypted("quake", BTreeSet::new(), &other_key)
+            .unwrap();
+        assert_ne!(other, first);
+        assert_eq!(dag.node_count().unwrap(), 3);
+    }
+}
+
+#[cfg(feature = "testing")]
+mod flaky_store_tests {
+    use super::QUAKE_CHAIN;
+    use crate::prelude::*;
+    use crate::store::{BTreeStore, Store, StoreError};
+    use crate::test::{temp_path, TestHasher};
+    use crate::testing::{FailureSchedule, FlakyStore, StoreCall};
+    use std::collections::BTreeSet;
+
+    type FlakyDag = Merkle<FlakyStore<BTreeStore<TestHasher>>, TestHasher>;
+
+    // The QUAKE_CHAIN with the calls made setting it up cleared.
+    fn flaky_chain(mut store: FlakyStore<BTreeStore<TestHasher>>) -> (FlakyDag, Vec<Vec<u8>>) {
+        let (dag, ids) = super::TestDag::from_text(QUAKE_CHAIN).unwrap();
+        for node in dag.get_nodes().values() {
+            store.store(node.clone()).unwrap();
+        }
+        let dag = FlakyDag::from_store(store).unwrap();
+        dag.get_nodes().take_calls();
+        let ids = ["quake", "qualm", "quell"]
+            .iter()
+            .map(|name| ids[*name].clone())
+            .collect();
+        (dag, ids)
+    }
+
+    #[test]
+    fn test_failed_store_keeps_roots() {
+        let store = FlakyStore::new(BTreeStore::new())
+            .with_schedule(FailureSchedule::EveryNth(2))
+            .on_methods(["store"]);
+        let mut dag = FlakyDag::new(store);
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        dag.get_nodes().take_calls();
+        assert!(matches!(
+            dag.add_node("qualm", BTreeSet::from([quake.clone()])),
+            Err(StoreError::StoreFailure(_))
+        ));
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.clone()]))
+            .id()
+            .to_vec();
+        // The write failed after the dependency check passed.
+        assert_eq!(
+            dag.get_nodes().take_calls(),
+            vec![
+                StoreCall {
+                    method: "contains",
+                    ids: vec![qualm.clone()],
+                    failed: false,
+                },
+                StoreCall {
+                    method: "contains_many",
+                    ids: vec![quake.clone()],
+                    failed: false,
+                },
+                StoreCall {
+                    method: "store",
+                    ids: vec![qualm.clone()],
+                    failed: true,
+                },
+            ]
+        );
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quake.clone()]));
+        assert!(!dag.check_for_node(&qualm).unwrap());
+
+        assert_eq!(
+            dag.add_node("qualm", BTreeSet::from([quake])).unwrap(),
+            qualm
+        );
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm]));
+    }
+
+    #[test]
+    fn test_transient_failure_mid_traversal() {
+        let store = FlakyStore::new(BTreeStore::new())
+            .with_schedule(FailureSchedule::Once)
+            .on_methods(["get_handle"]);
+        let (dag, ids) = flaky_chain(store);
+        assert!(matches!(
+            dag.compare(&ids[2], &ids[0]),
+            Err(StoreError::StoreFailure(_))
+        ));
+        assert_eq!(dag.compare(&ids[2], &ids[0]).unwrap(), NodeCompare::After);
+        let failed: Vec<StoreCall> = dag
+            .get_nodes()
+            .calls()
+            .into_iter()
+            .filter(|call| call.failed)
+            .collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].method, "get_handle");
+    }
+
+    #[test]
+    fn test_failures_on_ids() {
+        let (dag, ids) = flaky_chain(FlakyStore::new(BTreeStore::new()));
+        assert!(dag.get_nodes().calls().is_empty());
+        let store = FlakyStore::new(dag.get_nodes().inner().clone())
+            .with_schedule(FailureSchedule::OnIds(BTreeSet::from([ids[1].clone()])));
+        assert!(store.contains(&ids[0]).unwrap());
+        assert!(matches!(
+            store.contains(&ids[1]),
+            Err(StoreError::StoreFailure(_))
+        ));
+        assert!(matches!(
+            store.get_many(&[&ids[0], &ids[1]]),
+            Err(StoreError::StoreFailure(_))
+        ));
+        let node: Node<TestHasher> = store.get(&ids[2]).unwrap().unwrap();
+        assert_eq!(node.item(), b"quell");
+        let failed: Vec<bool> = store.calls().iter().map(|call| call.failed).collect();
+        assert_eq!(failed, vec![false, true, true, false]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_failed_remove_persists_roots() {
+        use crate::sqlite::SqliteStore;
+        type SqliteDag = Merkle<SqliteStore, TestHasher>;
+        let path = temp_path("flaky-remove.sqlite");
+        let open = || {
+            let store = SqliteStore::connect(&path).unwrap();
+            store.init_db().unwrap();
+            store
+        };
+        let (quake, qualm, quell) = {
+            let mut dag = SqliteDag::load(open()).unwrap();
+            let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+            let qualm = dag
+                .add_node("qualm", BTreeSet::from([quake.clone()]))
+                .unwrap();
+            let quell = dag
+                .add_node("quell", BTreeSet::from([qualm.clone()]))
+                .unwrap();
+            (quake, qualm, quell)
+        };
+        let store = FlakyStore::new(open())
+            .with_schedule(FailureSchedule::OnIds(BTreeSet::from([qualm.clone()])))
+            .on_methods(["delete"]);
+        let mut dag = Merkle::<_, TestHasher>::load(store).unwrap();
+        assert!(matches!(
+            dag.remove_node(&quake, RemoveScope::WithDescendants),
+            Err(StoreError::StoreFailure(_))
+        ));
+        // quell is gone so the qualm that failed to delete is a root now.
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm.clone()]));
+        assert!(!dag.check_for_node(&quell).unwrap());
+        drop(dag);
+
+        let mut dag = SqliteDag::load(open()).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm]));
+        dag.remove_node(&quake, RemoveScope::WithDescendants)
+            .unwrap();
+        assert!(dag.get_roots().is_empty());
+        assert_eq!(dag.node_count().unwrap(), 0);
+        drop(dag);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_failed_gc_delete_persists_roots() {
+        use crate::sqlite::SqliteStore;
+        type SqliteDag = Merkle<SqliteStore, TestHasher>;
+        let path = temp_path("flaky-gc.sqlite");
+        let open = || {
+            let store = SqliteStore::connect(&path).unwrap();
+            store.init_db().unwrap();
+            store
+        };
+        let (main, tip, experiment) = {
+            let mut dag = SqliteDag::load(open()).unwrap();
+            let base = dag.add_node("base", BTreeSet::new()).unwrap();
+            let main = dag
+                .add_node("main", BTreeSet::from([base.clone()]))
+                .unwrap();
+            let tip = dag.add_node("tip", BTreeSet::from([main.clone()])).unwrap();
+            let experiment = dag.add_node("experiment", BTreeSet::from([base])).unwrap();
+            dag.add_node(
+                "abandoned",
+                BTreeSet::from([experiment.clone(), main.clone()]),
+            )
+            .unwrap();
+            (main, tip, experiment)
+        };
+        let store = FlakyStore::new(open())
+            .with_schedule(FailureSchedule::OnIds(BTreeSet::from([experiment.clone()])))
+            .on_methods(["delete"]);
+        let mut dag = Merkle::<_, TestHasher>::load(store).unwrap();
+        assert!(matches!(
+            dag.retain_reachable(&BTreeSet::from([tip.clone()])),
+            Err(StoreError::StoreFailure(_))
+        ));
+        // The abandoned node is gone so the experiment it depended on is a root now.
+        let expected = BTreeSet::from([tip.clone(), experiment.clone()]);
+        assert_eq!(dag.get_roots(), &expected);
+        assert_eq!(dag.node_count().unwrap(), 4);
+        drop(dag);
+
+        let mut dag = SqliteDag::load(open()).unwrap();
+        assert_eq!(dag.get_roots(), &expected);
+        dag.retain_reachable(&BTreeSet::from([tip.clone()]))
+            .unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([tip]));
+        assert!(dag.check_for_node(&main).unwrap());
+        assert!(!dag.check_for_node(&experiment).unwrap());
+        drop(dag);
+    }
+}
+
+#[cfg(feature = "cbor")]
+mod trace_tests {
+    use super::TestDag;
+    use crate::prelude::*;
+    use crate::store::BTreeStore;
+    use crate::test::{temp_path, TestHasher};
+    use crate::trace::{RecordingStore, ReplayMode, ReplayStore};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn generated_dag() -> (TestDag<'static>, Vec<Vec<u8>>) {
+        let mut dag = TestDag::new(BTreeMap::new());
+        let mut ids: Vec<Vec<u8>> = Vec::new();
+        for idx in 0..30 {
+            let deps = ids.iter().rev().step_by(3).take(2).cloned().collect();
+            ids.push(dag.add_node(format!("payload-{}", idx), deps).unwrap());
+        }
+        (dag, ids)
+    }
+
+    #[test]
+    fn test_replay_matches_recording() {
+        let (dag, ids) = generated_dag();
+        let path = temp_path("replay.trace");
+        let recording = RecordingStore::create(dag.get_nodes().clone(), &path, true).unwrap();
+        let recorded_dag = Merkle::<_, TestHasher>::new(recording);
+        let recorded_result = recorded_dag.compare(&ids[0], &ids[29]).unwrap();
+        recorded_dag.get_nodes().flush().unwrap();
+        let recorded_count = recorded_dag.get_nodes().recorded();
+
+        let replay = ReplayStore::open(&path, ReplayMode::Strict).unwrap();
+        let replay_dag = Merkle::<_, TestHasher>::new(replay);
+        assert_eq!(
+            replay_dag.compare(&ids[0], &ids[29]).unwrap(),
+            recorded_result
+        );
+        assert_eq!(replay_dag.get_nodes().consumed(), recorded_count);
+    }
+
+    #[test]
+    fn test_replay_keeps_id_versions() {
+        let mut dag = TestDag::new(BTreeMap::new());
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        dag.set_id_version(NodeIdVersion::V1);
+        let qualm = dag.add_node("qualm", BTreeSet::from([quake])).unwrap();
+        let path = temp_path("id-versions.trace");
+        let recording = RecordingStore::create(dag.get_nodes().clone(), &path, true).unwrap();
+        let recorded_dag = Merkle::<_, TestHasher>::new(recording);
+        recorded_dag.get_node_by_id(&qualm).unwrap().unwrap();
+        recorded_dag.get_nodes().flush().unwrap();
+
+        let replay = ReplayStore::open(&path, ReplayMode::Strict).unwrap();
+        let replay_dag = Merkle::<_, TestHasher>::new(replay);
+        let node = replay_dag.get_node_by_id(&qualm).unwrap().unwrap();
+        assert_eq!(node.id(), qualm.as_slice());
+        assert_eq!(node.id_version(), NodeIdVersion::V1);
+    }
+
+    #[test]
+    fn test_replay_keeps_attributes() {
+        let mut dag = TestDag::new(BTreeMap::new());
+        let attributes = BTreeMap::from([("author".to_owned(), b"jwall".to_vec())]);
+        let quake = dag
+            .add_node_with_attrs("quake", BTreeSet::new(), attributes.clone())
+            .unwrap();
+        let path = temp_path("attributes.trace");
+        let recording = RecordingStore::create(dag.get_nodes().clone(), &path, true).unwrap();
+        let recorded_dag = Merkle::<_, TestHasher>::new(recording);
+        recorded_dag.get_node_by_id(&quake).unwrap().unwrap();
+        recorded_dag.get_nodes().flush().unwrap();
+
+        let replay = ReplayStore::open(&path, ReplayMode::Strict).unwrap();
+        let replay_dag = Merkle::<_, TestHasher>::new(replay);
+        let node = replay_dag.get_node_by_id(&quake).unwrap().unwrap();
+        assert_eq!(node.id(), quake.as_slice());
+        assert_eq!(node.attributes(), &attributes);
+    }
+
+    #[test]
+    fn test_replay_keeps_detached_nodes() {
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let (slim, _) = quake.clone().into_detached();
+        let store = BTreeMap::from([(quake.id().to_vec(), slim)]);
+        let path = temp_path("detached.trace");
+        let recording = RecordingStore::create(store, &path, true).unwrap();
+        let recorded_dag = Merkle::<_, TestHasher>::new(recording);
+        recorded_dag.get_node_by_id(quake.id()).unwrap().unwrap();
+        recorded_dag.get_nodes().flush().unwrap();
+
+        let replay = ReplayStore::open(&path, ReplayMode::Strict).unwrap();
+        let replay_dag = Merkle::<_, TestHasher>::new(replay);
+        let node = replay_dag.get_node_by_id(quake.id()).unwrap().unwrap();
+        assert!(node.is_detached());
+        assert_eq!(node.id(), quake.id());
+        assert_eq!(node.item_id(), quake.item_id());
+    }
+
+    #[test]
+    fn test_strict_replay_detects_divergence() {
+        let (dag, ids) = generated_dag();
+        let path = temp_path("divergence.trace");
+        let recording = RecordingStore::create(dag.get_nodes().clone(), &path, true).unwrap();
+        let recorded_dag = Merkle::<_, TestHasher>::new(recording);
+        recorded_dag.compare(&ids[0], &ids[29]).unwrap();
+        recorded_dag.get_nodes().flush().unwrap();
+
+        let replay = ReplayStore::open(&path, ReplayMode::Strict).unwrap();
+        let replay_dag = Merkle::<_, TestHasher>::new(replay);
+        assert!(replay_dag.compare(&ids[29], &ids[0]).is_err());
+    }
+
+    #[test]
+    fn test_scrubbed_trace_has_no_payloads() {
+        let (dag, ids) = generated_dag();
+        let path = temp_path("scrubbed.trace");
+        let recording = RecordingStore::create(dag.get_nodes().clone(), &path, false).unwrap();
+        let recorded_dag = Merkle::<_, TestHasher>::new(recording);
+        recorded_dag.ancestors_of(&ids[29]).unwrap();
+        recorded_dag.get_nodes().flush().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        for idx in 0..30 {
+            let payload = format!("payload-{}", idx);
+            assert!(!bytes
+                .windows(payload.len())
+                .any(|w| w == payload.as_bytes()));
+        }
+
+        let (rebuilt, id_map) =
+            Merkle::<BTreeStore<TestHasher>, TestHasher>::from_trace(&path).unwrap();
+        assert_eq!(
+            rebuilt
+                .compare(&id_map[&ids[29]], &id_map[&ids[0]])
+                .unwrap(),
+            NodeCompare::After
+        );
+        assert_eq!(
+            rebuilt.get_roots(),
+            &BTreeSet::from([id_map[&ids[29]].clone()])
+        );
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite_tests {
+    use super::{
+        check_add_nodes, check_concurrent_writers, check_contains_many, check_find_by_prefix,
+        check_flush_survives_reopen, check_get_many, check_get_raw_matches_get,
+        check_hash_algorithm_mismatch, check_ids, check_payload_search,
+        check_quarantine_foreign_ids, check_remove_node, check_retain_reachable,
+        check_roots_survive_reopen, check_shared_views, check_store_stats, check_transaction,
+    };
+    use crate::payload_index::WhitespaceIndexer;
+    use crate::prelude::*;
+    use crate::sqlite::{SqliteOpts, SqliteStore};
+    use crate::store::{ReadOnlyStore, Store, StoreError};
+    use crate::test::{temp_path, TestHasher};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    type SqliteDag = Merkle<SqliteStore, TestHasher>;
+
+    fn outbox_dag() -> SqliteDag {
+        let dag = SqliteDag::new(SqliteStore::in_memory().unwrap());
+        dag.get_nodes()
+            .conn()
+            .execute_batch("CREATE TABLE outbox(message TEXT NOT NULL);")
+            .unwrap();
+        dag
+    }
+
+    #[test]
+    fn test_detached_blobs_survive_a_rolled_back_batch() {
+        use crate::store::{BlobStore, DetachedStore};
+        let mut store = DetachedStore::new(
+            SqliteStore::in_memory().unwrap(),
+            BTreeMap::<Vec<u8>, Vec<u8>>::new(),
+        );
+        let (slim, item) = Node::<TestHasher>::new("quake", BTreeSet::new()).into_detached();
+        store.put_blob(slim.item_id(), item).unwrap();
+        Store::<TestHasher>::store(&mut store, slim.clone()).unwrap();
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        Store::<TestHasher>::delete(&mut store, slim.id()).unwrap();
+        assert!(store.blobs().contains_key(slim.item_id()));
+        Store::<TestHasher>::rollback_batch(&mut store).unwrap();
+        assert!(store.blobs().contains_key(slim.item_id()));
+        assert!(Store::<TestHasher>::contains(&store, slim.id()).unwrap());
+
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        Store::<TestHasher>::delete(&mut store, slim.id()).unwrap();
+        assert!(store.blobs().contains_key(slim.item_id()));
+        Store::<TestHasher>::commit_batch(&mut store).unwrap();
+        assert!(store.blobs().is_empty());
+    }
+
+    #[test]
+    fn test_payload_index_store_rolls_back_with_the_batch() {
+        use crate::payload_index::{PayloadIndexStore, PayloadSearch, WhitespaceIndexer};
+        let mut store =
+            PayloadIndexStore::new(SqliteStore::in_memory().unwrap(), WhitespaceIndexer);
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let quell = Node::<TestHasher>::new("quell", BTreeSet::new());
+        Store::<TestHasher>::store(&mut store, quake.clone()).unwrap();
+        let entries = PayloadSearch::<TestHasher>::payload_index_entries(&store).unwrap();
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        Store::<TestHasher>::delete(&mut store, quake.id()).unwrap();
+        Store::<TestHasher>::store(&mut store, quell.clone()).unwrap();
+        Store::<TestHasher>::rollback_batch(&mut store).unwrap();
+        assert!(!Store::<TestHasher>::contains(&store, quell.id()).unwrap());
+        assert_eq!(
+            PayloadSearch::<TestHasher>::payload_index_entries(&store).unwrap(),
+            entries
+        );
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        Store::<TestHasher>::store(&mut store, quell.clone()).unwrap();
+        Store::<TestHasher>::commit_batch(&mut store).unwrap();
+        assert_eq!(
+            PayloadSearch::<TestHasher>::search_term(&store, b"quell", 10).unwrap(),
+            vec![quell.id().to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_mirrored_store_mirrors_committed_batches() {
+        use crate::store::{codec, MirrorPolicy, MirroredStore};
+        let mut store = MirroredStore::new(
+            SqliteStore::in_memory().unwrap(),
+            SqliteStore::in_memory().unwrap(),
+            MirrorPolicy::Strict,
+        );
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        Store::<TestHasher>::store(&mut store, quake.clone()).unwrap();
+        assert!(!Store::<TestHasher>::contains(&*store.secondary(), quake.id()).unwrap());
+        Store::<TestHasher>::rollback_batch(&mut store).unwrap();
+        assert!(!Store::<TestHasher>::contains(&store, quake.id()).unwrap());
+        assert!(Store::<TestHasher>::is_empty(&*store.secondary()).unwrap());
+
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        Store::<TestHasher>::store(&mut store, quake.clone()).unwrap();
+        Store::<TestHasher>::store_encoded(&mut store, qualm.clone(), codec::encode(&qualm))
+            .unwrap();
+        Store::<TestHasher>::commit_batch(&mut store).unwrap();
+        for node in [&quake, &qualm] {
+            assert!(Store::<TestHasher>::contains(&*store.secondary(), node.id()).unwrap());
+        }
+        Store::<TestHasher>::delete(&mut store, qualm.id()).unwrap();
+        assert!(!Store::<TestHasher>::contains(&*store.secondary(), qualm.id()).unwrap());
+    }
+
+    #[test]
+    fn test_tiered_store_front_skips_rolled_back_batches() {
+        use crate::store::{codec, TieredStore};
+        let mut store = TieredStore::new(
+            BTreeStore::<TestHasher>::new(),
+            SqliteStore::in_memory().unwrap(),
+        );
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        Store::<TestHasher>::store(&mut store, quake.clone()).unwrap();
+        assert!(Store::<TestHasher>::get(&store, quake.id())
+            .unwrap()
+            .is_some());
+        assert!(!store.front().contains_key(quake.id()));
+        Store::<TestHasher>::rollback_batch(&mut store).unwrap();
+        assert!(!Store::<TestHasher>::contains(&store, quake.id()).unwrap());
+        assert!(Store::<TestHasher>::get(&store, quake.id())
+            .unwrap()
+            .is_none());
+
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        Store::<TestHasher>::store(&mut store, quake.clone()).unwrap();
+        Store::<TestHasher>::store_encoded(&mut store, qualm.clone(), codec::encode(&qualm))
+            .unwrap();
+        Store::<TestHasher>::commit_batch(&mut store).unwrap();
+        assert!(store.front().is_empty());
+        for node in [&quake, &qualm] {
+            assert!(Store::<TestHasher>::get(&store, node.id())
+                .unwrap()
+                .is_some());
+            assert!(store.front().contains_key(node.id()));
+        }
+    }
+
+    #[test]
+    fn test_union_store_defers_fallback_deletes_to_commit() {
+        use crate::store::{codec, UnionStore};
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let quell = Node::<TestHasher>::new("quell", BTreeSet::new());
+        let mut old = BTreeStore::<TestHasher>::new();
+        old.store(quake.clone()).unwrap();
+        let mut union = UnionStore::new()
+            .with_primary(SqliteStore::in_memory().unwrap())
+            .with_fallback(old);
+        union.begin_batch().unwrap();
+        union.delete(quake.id()).unwrap();
+        union
+            .store_encoded(quell.clone(), codec::encode(&quell))
+            .unwrap();
+        assert!(!union.contains(quake.id()).unwrap());
+        assert!(union.get(quake.id()).unwrap().is_none());
+        assert_eq!(
+            union.ids().unwrap().map(Result::unwrap).collect::<Vec<_>>(),
+            vec![quell.id().to_vec()]
+        );
+        assert!(union.stores()[1].contains(quake.id()).unwrap());
+        union.rollback_batch().unwrap();
+        assert!(union.contains(quake.id()).unwrap());
+        assert!(!union.contains(quell.id()).unwrap());
+
+        union.begin_batch().unwrap();
+        union.delete(quake.id()).unwrap();
+        union.commit_batch().unwrap();
+        assert!(!union.stores()[1].contains(quake.id()).unwrap());
+        assert!(!union.contains(quake.id()).unwrap());
+    }
+
+    #[test]
+    fn test_reverse_index_store_reopens_and_rolls_back() {
+        use crate::store::ReverseIndexStore;
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+        let quell = Node::<TestHasher>::new("quell", BTreeSet::from([quake.id().to_vec()]));
+        let mut inner = SqliteStore::in_memory().unwrap();
+        Store::<TestHasher>::store(&mut inner, quake.clone()).unwrap();
+        Store::<TestHasher>::store(&mut inner, qualm.clone()).unwrap();
+        let mut store = ReverseIndexStore::open::<TestHasher>(inner).unwrap();
+        let children = |store: &ReverseIndexStore<SqliteStore>| {
+            Store::<TestHasher>::children_of(store, quake.id()).unwrap()
+        };
+        assert_eq!(children(&store), BTreeSet::from([qualm.id().to_vec()]));
+
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        Store::<TestHasher>::delete(&mut store, qualm.id()).unwrap();
+        Store::<TestHasher>::store(&mut store, quell.clone()).unwrap();
+        assert_eq!(children(&store), BTreeSet::from([quell.id().to_vec()]));
+        Store::<TestHasher>::rollback_batch(&mut store).unwrap();
+        assert_eq!(children(&store), BTreeSet::from([qualm.id().to_vec()]));
+
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        Store::<TestHasher>::store(&mut store, quell.clone()).unwrap();
+        Store::<TestHasher>::commit_batch(&mut store).unwrap();
+        assert_eq!(
+            children(&store),
+            BTreeSet::from([qualm.id().to_vec(), quell.id().to_vec()])
+        );
+    }
+
+    // Runs every recorded kind of operation and describes the results.
+    fn exercise_trace<S: Store<TestHasher>>(store: &mut S) -> Vec<String> {
+        use crate::store::{codec, PersistedRoots};
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+        let quell = Node::<TestHasher>::new("quell", BTreeSet::from([quake.id().to_vec()]));
+        let roots = PersistedRoots {
+            roots: BTreeSet::from([qualm.id().to_vec()]),
+            ..PersistedRoots::default()
+        };
+        let mut results = Vec::new();
+        store
+            .store_many_with_roots([quake.clone(), qualm.clone()], &roots)
+            .unwrap();
+        let nodes = store.get_many(&[quake.id(), quell.id()]).unwrap();
+        results.push(format!(
+            "{:?}",
+            nodes
+                .iter()
+                .map(|n| n.as_ref().map(|n| n.item().to_vec()))
+                .collect::<Vec<_>>()
+        ));
+        store.begin_batch().unwrap();
+        store.delete(qualm.id()).unwrap();
+        store.rollback_batch().unwrap();
+        store.begin_batch().unwrap();
+        store
+            .store_encoded(quell.clone(), codec::encode(&quell))
+            .unwrap();
+        store.commit_batch().unwrap();
+        results.push(format!("{:?}", store.persisted_roots().unwrap()));
+        results.push(format!("{:?}", store.len().unwrap()));
+        results
+    }
+
+    #[test]
+    fn test_replay_answers_every_recorded_operation() {
+        use crate::trace::{RecordingStore, ReplayMode, ReplayStore};
+        let mut trace = Vec::new();
+        let (recorded, count) = {
+            let mut store =
+                RecordingStore::new(SqliteStore::in_memory().unwrap(), &mut trace, true).unwrap();
+            let recorded = exercise_trace(&mut store);
+            (recorded, store.recorded())
+        };
+        assert_eq!(recorded[2], "3");
+        let mut replay = ReplayStore::from_reader(trace.as_slice(), ReplayMode::Strict).unwrap();
+        assert_eq!(exercise_trace(&mut replay), recorded);
+        assert_eq!(replay.consumed(), count);
+    }
+
+    fn outbox_count(dag: &SqliteDag) -> i64 {
+        dag.get_nodes()
+            .conn()
+            .query_row("select count(*) from outbox", [], |r| r.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_side_effect_persists_with_node() {
+        let mut dag = outbox_dag();
+        let quake_node_id = dag
+            .add_node_with_side_effect("quake", BTreeSet::new(), |txn| {
+                txn.execute("insert into outbox (message) values ('quake')", [])?;
+                Ok(())
+            })
+            .unwrap();
+        assert!(dag.check_for_node(&quake_node_id).unwrap());
+        assert!(dag.get_roots().contains(&quake_node_id));
+        assert_eq!(outbox_count(&dag), 1);
+    }
+
+    #[test]
+    fn test_failed_side_effect_rolls_back_node() {
+        let mut dag = outbox_dag();
+        let quake_node_id = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let roots = dag.get_roots().clone();
+        let result = dag.add_node_with_side_effect(
+            "qualm",
+            BTreeSet::from([quake_node_id.clone()]),
+            |txn| {
+                txn.execute("insert into outbox (message) values ('qualm')", [])?;
+                // Simulate a crash after the outbox write.
+                Err(rusqlite::Error::InvalidQuery)
+            },
+        );
+        assert!(result.is_err());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake_node_id]));
+        assert!(!dag.check_for_node(qualm.id()).unwrap());
+        assert_eq!(dag.get_roots(), &roots);
+        assert_eq!(outbox_count(&dag), 0);
+    }
+
+    #[test]
+    fn test_failed_roots_write_rolls_back_side_effect() {
+        let mut dag = SqliteDag::load(SqliteStore::in_memory().unwrap()).unwrap();
+        dag.get_nodes()
+            .conn()
+            .execute_batch("CREATE TABLE outbox(message TEXT NOT NULL);")
+            .unwrap();
+        let quake_node_id = dag
+            .add_node_with_side_effect("quake", BTreeSet::new(), |txn| {
+                txn.execute("insert into outbox (message) values ('quake')", [])?;
+                Ok(())
+            })
+            .unwrap();
+        let roots = dag.get_roots().clone();
+        assert_eq!(roots, BTreeSet::from([quake_node_id.clone()]));
+        // Fail every write of the roots from now on.
+        dag.get_nodes()
+            .conn()
+            .execute_batch(&format!(
+                "CREATE TRIGGER fail_roots BEFORE INSERT ON merkle_dag_meta
+                WHEN NEW.key = X'{}' BEGIN SELECT RAISE(ABORT, 'roots write failed'); END",
+                crate::id::hex(crate::store::ROOTS_KEY)
+            ))
+            .unwrap();
+        let result = dag.add_node_with_side_effect(
+            "qualm",
+            BTreeSet::from([quake_node_id.clone()]),
+            |txn| {
+                txn.execute("insert into outbox (message) values ('qualm')", [])?;
+                Ok(())
+            },
+        );
+        assert!(result.is_err());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake_node_id]));
+        assert!(!dag.check_for_node(qualm.id()).unwrap());
+        assert_eq!(dag.get_roots(), &roots);
+        assert_eq!(outbox_count(&dag), 1);
+        let persisted = Store::<TestHasher>::persisted_roots(dag.get_nodes())
+            .unwrap()
+            .unwrap();
+        assert_eq!(persisted.roots, roots);
+    }
+
+    #[test]
+    fn test_side_effect_nests_in_batch() {
+        use crate::store::TransactionalStore;
+        let mut store = SqliteStore::in_memory().unwrap();
+        store
+            .conn()
+            .execute_batch("CREATE TABLE outbox(message TEXT NOT NULL);")
+            .unwrap();
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::new());
+        Store::<TestHasher>::begin_batch(&mut store).unwrap();
+        store
+            .store_with(quake.clone(), None, |txn| {
+                txn.execute("insert into outbox (message) values ('quake')", [])?;
+                Ok(())
+            })
+            .unwrap();
+        // A failed side effect only rolls back its own writes.
+        assert!(store
+            .store_with(qualm.clone(), None, |txn| {
+                txn.execute("insert into outbox (message) values ('qualm')", [])?;
+                Err(rusqlite::Error::InvalidQuery)
+            })
+            .is_err());
+        Store::<TestHasher>::commit_batch(&mut store).unwrap();
+        assert!(Store::<TestHasher>::contains(&store, quake.id()).unwrap());
+        assert!(!Store::<TestHasher>::contains(&store, qualm.id()).unwrap());
+        let dag = SqliteDag::new(store);
+        assert_eq!(outbox_count(&dag), 1);
+    }
+
+    #[test]
+    fn test_sqlite_store_stores_duplicates_directly() {
+        let mut store = SqliteStore::in_memory().unwrap();
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+        Store::<TestHasher>::store(&mut store, quake.clone()).unwrap();
+        Store::<TestHasher>::store(&mut store, quake.clone()).unwrap();
+        Store::<TestHasher>::store_many(&mut store, [quake.clone(), qualm.clone()]).unwrap();
+        Store::<TestHasher>::store_with_roots(
+            &mut store,
+            qualm.clone(),
+            &crate::store::PersistedRoots {
+                roots: BTreeSet::from([qualm.id().to_vec()]),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(Store::<TestHasher>::len(&store).unwrap(), 2);
+        let stored = Store::<TestHasher>::get(&store, qualm.id())
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.dependency_ids(), qualm.dependency_ids());
+    }
+
+    #[test]
+    fn test_sqlite_store_bulk_import_matches_naive_path() {
+        let mut nodes: Vec<Node<TestHasher>> = Vec::new();
+        for idx in 0..3000_usize {
+            let mut deps = BTreeSet::new();
+            if idx >= 4 {
+                deps.insert(nodes[idx - 4].id().to_vec());
+            }
+            if idx % 11 == 10 {
+                deps.insert(nodes[idx / 2].id().to_vec());
+            }
+            nodes.push(Node::new(format!("import-{}", idx), deps));
+        }
+        let mut naive = SqliteStore::in_memory().unwrap();
+        for node in nodes.iter().cloned() {
+            Store::<TestHasher>::store(&mut naive, node).unwrap();
+        }
+        let mut bulk = SqliteStore::in_memory().unwrap();
+        Store::<TestHasher>::store_many(&mut bulk, nodes.iter().cloned()).unwrap();
+        let ids: Vec<&[u8]> = nodes.iter().map(|node| node.id()).collect();
+        let absent = Node::<TestHasher>::new("absent", BTreeSet::new());
+        let mut lookups = ids.clone();
+        lookups.push(absent.id());
+        for id in lookups.iter() {
+            assert_eq!(
+                Store::<TestHasher>::get_raw(&bulk, id).unwrap(),
+                Store::<TestHasher>::get_raw(&naive, id).unwrap()
+            );
+            assert_eq!(
+                Store::<TestHasher>::contains(&bulk, id).unwrap(),
+                Store::<TestHasher>::contains(&naive, id).unwrap()
+            );
+        }
+        let contained = Store::<TestHasher>::contains_many(&bulk, &lookups).unwrap();
+        assert_eq!(contained.len(), lookups.len());
+        assert!(contained[..ids.len()].iter().all(|found| *found));
+        assert!(!contained[ids.len()]);
+        let found = Store::<TestHasher>::get_many(&bulk, &lookups).unwrap();
+        assert!(found[ids.len()].is_none());
+        for (node, found) in nodes.iter().zip(found.iter()) {
+            let found = found.as_ref().unwrap();
+            assert_eq!(found.id(), node.id());
+            assert_eq!(found.item(), node.item());
+            assert_eq!(found.dependency_ids(), node.dependency_ids());
+        }
+        assert_eq!(
+            Store::<TestHasher>::len(&bulk).unwrap(),
+            Store::<TestHasher>::len(&naive).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sqlite_store_find_by_prefix() {
+        check_find_by_prefix(SqliteStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_store_get_raw() {
+        check_get_raw_matches_get(SqliteStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_store_stats() {
+        check_store_stats(SqliteStore::in_memory().unwrap());
+    }
+
+    fn closure_size_dag(len: usize) -> (SqliteDag, Vec<Vec<u8>>) {
+        let store = SqliteStore::in_memory()
+            .unwrap()
+            .with_closure_sizes()
+            .unwrap();
+        let mut dag = SqliteDag::new(store);
+        let mut ids: Vec<Vec<u8>> = Vec::new();
+        for i in 0..len {
+            let deps = match i {
+                0 => BTreeSet::new(),
+                _ => BTreeSet::from([ids[i - 1].clone(), ids[i / 2].clone()]),
+            };
+            ids.push(dag.add_node(format!("node {}", i), deps).unwrap());
+        }
+        (dag, ids)
+    }
+
+    #[test]
+    fn test_sqlite_closure_sizes_refresh_newest_first() {
+        let (mut dag, ids) = closure_size_dag(20);
+        for id in ids.iter() {
+            assert_eq!(dag.closure_size_cached(id).unwrap(), CachedValue::Missing);
+        }
+        assert_eq!(dag.refresh_closure_sizes(5).unwrap(), 5);
+        let list_view: Vec<CachedValue<u64>> = ids
+            .iter()
+            .map(|id| dag.closure_size_cached(id).unwrap())
+            .collect();
+        assert!(list_view[15..].iter().all(CachedValue::is_fresh));
+        assert!(list_view[..15].iter().all(|v| *v == CachedValue::Missing));
+        while dag.refresh_closure_sizes(7).unwrap() > 0 {}
+        for id in ids.iter() {
+            let expected = dag.closure_stats(id).unwrap().size as u64;
+            assert_eq!(
+                dag.closure_size_cached(id).unwrap(),
+                CachedValue::Fresh(expected)
+            );
+        }
+        assert!(matches!(
+            dag.closure_size_cached(b"missing"),
+            Err(StoreError::NoSuchNode(_))
+        ));
+    }
+
+    #[test]
+    fn test_copy_store_btree_to_sqlite() {
+        use crate::store::{copy_store, BTreeStore};
+        // More nodes than one copy batch.
+        let mut dag = Merkle::<BTreeStore<TestHasher>, TestHasher>::new(BTreeStore::new());
+        let mut ids: Vec<Vec<u8>> = Vec::new();
+        for idx in 0..1200 {
+            let deps = ids.iter().rev().step_by(7).take(2).cloned().collect();
+            ids.push(dag.add_node(format!("node-{}", idx), deps).unwrap());
+        }
+        let mut store = SqliteStore::in_memory().unwrap();
+        let report = copy_store(dag.get_nodes(), &mut store).unwrap();
+        assert_eq!(report.copied, 1200);
+        assert!(report.corrupt.is_empty() && report.vanished.is_empty());
+
+        let copied = SqliteDag::from_store(store).unwrap();
+        assert_eq!(copied.get_roots(), dag.get_roots());
+        for id in ids.iter().step_by(97) {
+            let node = copied.get_node_by_id(id).unwrap().unwrap();
+            let original = dag.get_node_by_id(id).unwrap().unwrap();
+            assert_eq!(node.item(), original.item());
+            assert_eq!(node.dependency_ids(), original.dependency_ids());
+        }
+        assert_eq!(copied.node_count().unwrap(), 1200);
+    }
+
+    #[test]
+    fn test_sqlite_closure_sizes_marked_stale_by_late_ancestors() {
+        let (mut dag, ids) = closure_size_dag(4);
+        while dag.refresh_closure_sizes(10).unwrap() > 0 {}
+        // Building on the DAG never changes the ancestors of stored nodes.
+        let head = dag
+            .add_node("head", BTreeSet::from([ids[3].clone()]))
+            .unwrap();
+        assert_eq!(
+            dag.closure_size_cached(&ids[3]).unwrap(),
+            CachedValue::Fresh(3)
+        );
+        assert_eq!(
+            dag.closure_size_cached(&head).unwrap(),
+            CachedValue::Missing
+        );
+
+        // A sync can store a node before its dependencies arrive.
+        let late = Node::<TestHasher>::new("late", BTreeSet::new());
+        let orphan =
+            Node::<TestHasher>::new("orphan", BTreeSet::from([late.id().to_vec(), head.clone()]));
+        let orphan_id = orphan.id().to_vec();
+        dag.nodes_mut().store(orphan).unwrap();
+        while dag.refresh_closure_sizes(10).unwrap() > 0 {}
+        assert_eq!(
+            dag.closure_size_cached(&orphan_id).unwrap(),
+            CachedValue::Fresh(5)
+        );
+        dag.nodes_mut().store(late).unwrap();
+        assert_eq!(
+            dag.closure_size_cached(&orphan_id).unwrap(),
+            CachedValue::Stale(5)
+        );
+        assert_eq!(
+            dag.closure_size_cached(&head).unwrap(),
+            CachedValue::Fresh(4)
+        );
+        assert_eq!(dag.refresh_closure_sizes(10).unwrap(), 2);
+        assert_eq!(
+            dag.closure_size_cached(&orphan_id).unwrap(),
+            CachedValue::Fresh(6)
+        );
+    }
+
+    #[test]
+    fn test_sqlite_closure_sizes_stale_after_delete() {
+        let (mut dag, ids) = closure_size_dag(4);
+        while dag.refresh_closure_sizes(10).unwrap() > 0 {}
+        Store::<TestHasher>::delete(dag.nodes_mut(), &ids[0]).unwrap();
+        assert_eq!(
+            dag.closure_size_cached(&ids[3]).unwrap(),
+            CachedValue::Stale(3)
+        );
+        assert_eq!(dag.refresh_closure_sizes(10).unwrap(), 3);
+        assert_eq!(
+            dag.closure_size_cached(&ids[3]).unwrap(),
+            CachedValue::Fresh(2)
+        );
+        assert_eq!(dag.refresh_closure_sizes(10).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sqlite_closure_sizes_unsupported_unless_enabled() {
+        let mut dag = SqliteDag::new(SqliteStore::in_memory().unwrap());
+        let id = dag.add_node("quake", BTreeSet::new()).unwrap();
+        assert!(matches!(
+            dag.closure_size_cached(&id),
+            Err(StoreError::Unsupported("cached_closure_size"))
+        ));
+    }
+
+    #[test]
+    fn test_sqlite_store_quarantine_foreign_ids() {
+        check_quarantine_foreign_ids(SqliteStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_store_retain_reachable() {
+        check_retain_reachable(SqliteStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_store_remove_node() {
+        check_remove_node(SqliteStore::in_memory().unwrap());
+        check_remove_node(
+            SqliteStore::in_memory()
+                .unwrap()
+                .with_payload_indexer(WhitespaceIndexer)
+                .unwrap()
+                .with_closure_sizes()
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_sqlite_store_ids() {
+        check_ids(SqliteStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_roots_survive_reopen() {
+        let path = temp_path("roots.sqlite");
+        let store = SqliteStore::connect(&path).unwrap();
+        store.init_db().unwrap();
+        drop(store);
+        check_roots_survive_reopen(|| SqliteStore::connect(&path).unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_hash_algorithm_mismatch() {
+        let path = temp_path("hash-algorithm.sqlite");
+        check_hash_algorithm_mismatch(|| SqliteStore::connect(&path).unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_estimated_storage_bytes_survive_reopen() {
+        let path = temp_path("storage-bytes.sqlite");
+        let open = || {
+            let store = SqliteStore::connect(&path).unwrap();
+            store.init_db().unwrap();
+            store
+        };
+        let bytes = {
+            let mut dag = SqliteDag::load(open()).unwrap();
+            let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+            dag.add_node("qualm", BTreeSet::from([quake])).unwrap();
+            dag.estimated_storage_bytes()
+        };
+        assert!(bytes > 0);
+        let persisted = Store::<TestHasher>::persisted_roots(&open())
+            .unwrap()
+            .unwrap();
+        assert_eq!(persisted.stored_bytes, Some(bytes as u64));
+        // Loading restores the estimate from the roots without reading any node.
+        let dag = Merkle::<_, TestHasher>::load(crate::store::MeteredStore::new(open())).unwrap();
+        assert_eq!(dag.estimated_storage_bytes(), bytes);
+        assert_eq!(dag.get_nodes().metrics().nodes_read, 0);
+        assert_eq!(
+            SqliteDag::from_store(open())
+                .unwrap()
+                .estimated_storage_bytes(),
+            bytes
+        );
+        // Roots written before the estimate was persisted make load count it.
+        Store::<TestHasher>::persist_roots(
+            &mut open(),
+            &crate::store::PersistedRoots {
+                stored_bytes: None,
+                ..persisted
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            SqliteDag::load(open()).unwrap().estimated_storage_bytes(),
+            bytes
+        );
+        // Stores without persisted roots get them reconstructed.
+        open()
+            .conn()
+            .execute(
+                "delete from merkle_dag_meta where key = ?",
+                [crate::store::ROOTS_KEY],
+            )
+            .unwrap();
+        assert_eq!(
+            SqliteDag::load(open()).unwrap().estimated_storage_bytes(),
+            bytes
+        );
+    }
+
+    #[test]
+    fn test_sqlite_records_hash_algorithm_with_first_node() {
+        let path = temp_path("first-node.sqlite");
+        {
+            let store = SqliteStore::connect(&path).unwrap();
+            store.init_db().unwrap();
+            let mut dag = SqliteDag::new(store);
+            assert_eq!(dag.get_nodes().meta().hash_algorithm, None);
+            dag.add_node("quake", BTreeSet::new()).unwrap();
+        }
+        let store = SqliteStore::connect(&path).unwrap();
+        assert_eq!(
+            store.meta().hash_algorithm.as_deref(),
+            Some("siphash24-keyed-64")
+        );
+        drop(store);
+    }
+
+    #[test]
+    fn test_sqlite_legacy_hash_algorithm_name_loads() {
+        use crate::inspect::{BackendKind, StoreMeta, META_KEY};
+        let path = temp_path("legacy-name.sqlite");
+        let quake = {
+            let store = SqliteStore::connect(&path).unwrap();
+            store.init_db().unwrap();
+            let mut dag = SqliteDag::new(store);
+            dag.add_node("quake", BTreeSet::new()).unwrap()
+        };
+        // Blocks used to record the Rust type name of the hasher.
+        let mut meta = StoreMeta::new(BackendKind::Sqlite);
+        meta.hash_algorithm = Some("merkle_dag::hash::siphash::SipHash24<TestKey>".to_owned());
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute(
+                "insert or replace into merkle_dag_meta (key, value) values (?, ?)",
+                [META_KEY, meta.encode().as_slice()],
+            )
+            .unwrap();
+        let mut dag = SqliteDag::load(SqliteStore::connect(&path).unwrap()).unwrap();
+        assert_eq!(dag.get_nodes().meta().hash_algorithm, None);
+        dag.add_node("shake", BTreeSet::from([quake])).unwrap();
+        assert_eq!(
+            dag.get_nodes().meta().hash_algorithm.as_deref(),
+            Some("siphash24-keyed-64")
+        );
+        drop(dag);
+    }
+
+    #[test]
+    fn test_sqlite_flush_survives_reopen() {
+        let path = temp_path("flush.sqlite");
+        let store = SqliteStore::connect(&path).unwrap();
+        store.init_db().unwrap();
+        drop(store);
+        check_flush_survives_reopen(|| SqliteStore::connect(&path).unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_load_read_only() {
+        let path = temp_path("read-only.sqlite");
+        SqliteStore::connect(&path).unwrap().init_db().unwrap();
+        let roots = {
+            let mut dag = SqliteDag::load(SqliteStore::connect(&path).unwrap()).unwrap();
+            let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+            dag.add_node("qualm", BTreeSet::from([quake])).unwrap();
+            dag.get_roots().clone()
+        };
+        let mut dag =
+            Merkle::<_, TestHasher>::load(ReadOnlyStore::new(SqliteStore::connect(&path).unwrap()))
+                .unwrap();
+        assert_eq!(dag.get_roots(), &roots);
+        assert!(matches!(
+            dag.add_node("shake", BTreeSet::new()),
+            Err(StoreError::ReadOnly("store_with_roots"))
+        ));
+        assert_eq!(dag.get_roots(), &roots);
+    }
+
+    #[test]
+    fn test_sqlite_transaction() {
+        let path = temp_path("transaction.sqlite");
+        SqliteStore::connect(&path).unwrap().init_db().unwrap();
+        check_transaction(|| SqliteStore::connect(&path).unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_connect_twice() {
+        let path = temp_path("connect-twice.sqlite");
+        let roots = {
+            let store = SqliteStore::connect(&path).unwrap();
+            assert_eq!(store.schema_version().unwrap(), 3);
+            let mut dag = SqliteDag::load(store).unwrap();
+            let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+            dag.add_node("qualm", BTreeSet::from([quake])).unwrap();
+            dag.get_roots().clone()
+        };
+        let store = SqliteStore::connect(&path).unwrap();
+        assert_eq!(store.schema_version().unwrap(), 3);
+        // Running the migrations again changes nothing.
+        store.init_db().unwrap();
+        let dag = SqliteDag::load(store).unwrap();
+        assert_eq!(dag.get_roots(), &roots);
+        assert_eq!(Store::<TestHasher>::len(dag.get_nodes()).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_sqlite_migrates_unversioned_schema() {
+        let path = temp_path("unversioned.sqlite");
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        {
+            // The tables as they were before the schema was versioned.
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE merkle_dag_meta(key BLOB PRIMARY KEY, value BLOB NOT NULL);
+                CREATE TABLE content_store(content_id BLOB PRIMARY KEY, node BLOB NOT NULL);",
+            )
+            .unwrap();
+            let mut buf = Vec::new();
+            ciborium::ser::into_writer(&quake, &mut buf).unwrap();
+            conn.execute(
+                "insert into content_store (content_id, node) values (?, ?)",
+                [quake.id(), buf.as_slice()],
+            )
+            .unwrap();
+        }
+        let store = SqliteStore::connect(&path).unwrap();
+        assert_eq!(store.schema_version().unwrap(), 3);
+        let mut dag = SqliteDag::load(store).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quake.id().to_vec()]));
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.id().to_vec()]))
+            .unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm]));
+        drop(dag);
+        // A database from a newer version of the schema is refused.
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute("update merkle_dag_schema set version = 99", [])
+            .unwrap();
+        drop(conn);
+        assert!(SqliteStore::connect(&path).is_err());
+    }
+
+    #[test]
+    fn test_sqlite_migrates_ad_hoc_closure_columns() {
+        let path = temp_path("ad-hoc-closures.sqlite");
+        {
+            // A version 1 database where with_closure_sizes added its columns itself.
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE merkle_dag_schema(version INTEGER NOT NULL);
+                INSERT INTO merkle_dag_schema (version) VALUES (1);
+                CREATE TABLE merkle_dag_meta(key BLOB PRIMARY KEY, value BLOB NOT NULL);
+                CREATE TABLE content_store(content_id BLOB PRIMARY KEY, node BLOB NOT NULL);
+                ALTER TABLE content_store ADD COLUMN closure_size INTEGER;
+                ALTER TABLE content_store ADD COLUMN closure_stale INTEGER NOT NULL DEFAULT 0;
+                CREATE TABLE closure_missing(
+                missing_id BLOB NOT NULL,
+                content_id BLOB NOT NULL,
+                PRIMARY KEY (missing_id, content_id));",
+            )
+            .unwrap();
+        }
+        let store = SqliteStore::connect(&path)
+            .unwrap()
+            .with_payload_indexer(WhitespaceIndexer)
+            .unwrap()
+            .with_closure_sizes()
+            .unwrap();
+        assert_eq!(store.schema_version().unwrap(), 3);
+        let mut dag = SqliteDag::new(store);
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        assert_eq!(dag.refresh_closure_sizes(10).unwrap(), 2);
+        assert_eq!(
+            dag.closure_size_cached(&qualm).unwrap(),
+            CachedValue::Fresh(1)
+        );
+        assert_eq!(dag.search_payloads(b"qualm", 10).unwrap(), vec![qualm]);
+        drop(dag);
+    }
+
+    #[test]
+    fn test_sqlite_connect_with_opts_uses_wal() {
+        let path = temp_path("wal.sqlite");
+        let opts = SqliteOpts {
+            cache_size_kib: Some(4096),
+            ..Default::default()
+        };
+        let store = SqliteStore::connect_with_opts(&path, &opts).unwrap();
+        let pragma = |name: &str| -> String {
+            store
+                .conn()
+                .pragma_query_value(None, name, |r| r.get::<_, rusqlite::types::Value>(0))
+                .map(|value| match value {
+                    rusqlite::types::Value::Text(text) => text,
+                    rusqlite::types::Value::Integer(number) => number.to_string(),
+                    other => format!("{:?}", other),
+                })
+                .unwrap()
+        };
+        assert_eq!(pragma("journal_mode"), "wal");
+        // NORMAL
+        assert_eq!(pragma("synchronous"), "1");
+        assert_eq!(pragma("cache_size"), "-4096");
+        store.pragma("cache_size", -1024).unwrap();
+        assert_eq!(pragma("cache_size"), "-1024");
+        drop(store);
+    }
+
+    #[test]
+    fn test_sqlite_read_only_connection_reads_during_write() {
+        let path = temp_path("wal-reader.sqlite");
+        let mut writer = SqliteStore::connect_with_opts(&path, &SqliteOpts::default()).unwrap();
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+        Store::<TestHasher>::store(&mut writer, quake.clone()).unwrap();
+        Store::<TestHasher>::begin_batch(&mut writer).unwrap();
+        Store::<TestHasher>::store(&mut writer, qualm.clone()).unwrap();
+        let reader = SqliteStore::connect_with_opts(
+            &path,
+            &SqliteOpts {
+                read_only: true,
+                busy_timeout: std::time::Duration::from_millis(100),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // The reader sees the committed node but not the open transaction.
+        assert!(Store::<TestHasher>::contains(&reader, quake.id()).unwrap());
+        assert!(!Store::<TestHasher>::contains(&reader, qualm.id()).unwrap());
+        assert!(Store::<TestHasher>::store(
+            &mut SqliteStore::connect_with_opts(
+                &path,
+                &SqliteOpts {
+                    read_only: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap(),
+            Node::<TestHasher>::new("shake", BTreeSet::new()),
+        )
+        .is_err());
+        Store::<TestHasher>::commit_batch(&mut writer).unwrap();
+        assert!(Store::<TestHasher>::contains(&reader, qualm.id()).unwrap());
+        drop((writer, reader));
+    }
+
+    #[test]
+    fn test_sqlite_load_reconstructs_missing_roots() {
+        let path = temp_path("no-roots.sqlite");
+        let roots = {
+            let store = SqliteStore::connect(&path).unwrap();
+            store.init_db().unwrap();
+            let mut dag = SqliteDag::new(store);
+            let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+            dag.add_node("qualm", BTreeSet::from([quake])).unwrap();
+            dag.add_node("shake", BTreeSet::new()).unwrap();
+            dag.get_roots().clone()
+        };
+        let dag = SqliteDag::load(SqliteStore::connect(&path).unwrap()).unwrap();
+        assert_eq!(dag.get_roots(), &roots);
+        let persisted = Store::<TestHasher>::persisted_roots(dag.get_nodes()).unwrap();
+        assert_eq!(persisted.unwrap().roots, roots);
+    }
+
+    #[test]
+    fn test_sqlite_store_get_many() {
+        check_get_many(SqliteStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_store_concurrent_writers() {
+        check_concurrent_writers(std::sync::Mutex::new(SqliteStore::in_memory().unwrap()));
+    }
+
+    #[test]
+    fn test_sqlite_store_shared_views() {
+        check_shared_views(std::sync::Mutex::new(SqliteStore::in_memory().unwrap()));
+    }
+
+    #[test]
+    fn test_sqlite_store_contains_many() {
+        check_contains_many(SqliteStore::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_store_add_nodes() {
+        check_add_nodes(
+            SqliteStore::in_memory().unwrap(),
+            SqliteStore::in_memory()
+                .unwrap()
+                .with_payload_indexer(WhitespaceIndexer)
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_sqlite_delete_drops_index_entries() {
+        let mut dag = SqliteDag::new(
+            SqliteStore::in_memory()
+                .unwrap()
+                .with_payload_indexer(WhitespaceIndexer)
+                .unwrap(),
+        );
+        let quake = dag.add_node("quake shake", BTreeSet::new()).unwrap();
+        dag.add_node("shake", BTreeSet::new()).unwrap();
+        dag.remove_node(&quake, RemoveScope::Node).unwrap();
+        assert!(dag.search_payloads(b"quake", 10).unwrap().is_empty());
+        assert_eq!(dag.search_payloads(b"shake", 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_quarantine_drops_payload_index_rows() {
+        let store = SqliteStore::in_memory()
+            .unwrap()
+            .with_payload_indexer(WhitespaceIndexer)
+            .unwrap();
+        let mut dag = SqliteDag::new(store);
+        dag.add_node("quake quell", BTreeSet::new()).unwrap();
+        let foreign = Node::<crate::blake2::Blake2b512>::new("quake", BTreeSet::new());
+        crate::store::Store::<crate::blake2::Blake2b512>::store(dag.nodes_mut(), foreign).unwrap();
+        assert_eq!(dag.search_payloads(b"quake", 10).unwrap().len(), 2);
+        assert_eq!(dag.quarantine_foreign_ids().unwrap(), 1);
+        assert_eq!(dag.search_payloads(b"quake", 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_bulk_load_rolls_back_dangling_dependency() {
+        let missing = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([missing.id().to_vec()]));
+        let quell = Node::<TestHasher>::new("quell", BTreeSet::new());
+        let mut dag = SqliteDag::new(SqliteStore::in_memory().unwrap());
+        let failure = dag
+            .bulk_load(
+                vec![Ok(quell.clone()), Ok(qualm.clone())].into_iter(),
+                BulkLoadOpts {
+                    rollback_on_failure: true,
+                    ..BulkLoadOpts::default()
+                },
+            )
+            .unwrap_err();
+        assert_eq!(failure.errors.len(), 1);
+        assert_eq!(failure.progress, BatchProgress::NothingApplied);
+        assert!(!dag.check_for_node(quell.id()).unwrap());
+        assert!(!dag.check_for_node(qualm.id()).unwrap());
+        assert!(dag.get_roots().is_empty());
+    }
+
+    #[test]
+    fn test_sqlite_bulk_load_rolls_back_every_bad_entry() {
+        let archive = super::bad_archive();
+        let good: Vec<Vec<u8>> = archive
+            .iter()
+            .filter_map(|node| node.as_ref().ok())
+            .map(|node| node.id().to_vec())
+            .collect();
+        let mut dag = SqliteDag::new(SqliteStore::in_memory().unwrap());
+        let failure = dag
+            .bulk_load(
+                archive.into_iter(),
+                BulkLoadOpts {
+                    rollback_on_failure: true,
+                    ..BulkLoadOpts::default()
+                },
+            )
+            .unwrap_err();
+        assert_eq!(failure.errors.total(), 5);
+        assert_eq!(failure.progress, BatchProgress::NothingApplied);
+        for id in good {
+            assert!(!dag.check_for_node(&id).unwrap());
+        }
+        assert!(dag.get_roots().is_empty());
+    }
+
+    #[test]
+    fn test_sqlite_bulk_load_in_batches() {
+        let nodes: Vec<Node<TestHasher>> = (0..25)
+            .map(|idx| Node::new(format!("quake-{}", idx), BTreeSet::new()))
+            .collect();
+        let mut dag = SqliteDag::new(SqliteStore::in_memory().unwrap());
+        let report = dag
+            .bulk_load(
+                nodes.clone().into_iter().map(Ok),
+                BulkLoadOpts {
+                    batch_size: 10,
+                    ..BulkLoadOpts::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(report.loaded, 25);
+        for node in nodes {
+            assert!(dag.check_for_node(node.id()).unwrap());
+            assert!(dag.get_roots().contains(node.id()));
+        }
+        // The store is usable outside of a batch afterwards.
+        dag.add_node("qualm", BTreeSet::new()).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_store_payload_search() {
+        check_payload_search(
+            SqliteStore::in_memory()
+                .unwrap()
+                .with_payload_indexer(WhitespaceIndexer)
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_sqlite_store_without_indexer_is_unsupported() {
+        let dag = SqliteDag::new(SqliteStore::in_memory().unwrap());
+        assert!(matches!(
+            dag.search_payloads(b"quake", 10),
+            Err(StoreError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_sqlite_index_rolls_back_with_node() {
+        let mut dag = SqliteDag::new(
+            SqliteStore::in_memory()
+                .unwrap()
+                .with_payload_indexer(WhitespaceIndexer)
+                .unwrap(),
+        );
+        let result = dag.add_node_with_side_effect("quake", BTreeSet::new(), |_| {
+            Err(rusqlite::Error::InvalidQuery)
+        });
+        assert!(result.is_err());
+        assert!(dag.search_payloads(b"quake", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sqlite_inspect_reports_options_and_count() {
+        use crate::inspect::{inspect_path, BackendKind, MetaBlock};
+        for (name, indexed, closures) in [
+            ("plain", false, false),
+            ("indexed", true, false),
+            ("closures", false, true),
+            ("both", true, true),
+        ] {
+            let path = temp_path(&format!("{}.sqlite", name));
+            {
+                let mut store = SqliteStore::connect(&path).unwrap();
+                store.init_db().unwrap();
+                if indexed {
+                    store = store.with_payload_indexer(WhitespaceIndexer).unwrap();
+                }
+                if closures {
+                    store = store.with_closure_sizes().unwrap();
+                }
+                let mut dag = SqliteDag::new(store);
+                let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+                dag.add_node("shake", BTreeSet::from([quake])).unwrap();
+            }
+            let before = std::fs::read(&path).unwrap();
+            let description = inspect_path(&path).unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), before);
+            assert_eq!(description.backend, BackendKind::Sqlite);
+            assert_eq!(description.node_count, Some(2));
+            let meta = match description.meta {
+                MetaBlock::Current(meta) => meta,
+                other => panic!("Unexpected metadata block {:?}", other),
+            };
+            assert_eq!(meta.backend, BackendKind::Sqlite);
+            assert_eq!(meta.options.contains("payload_index"), indexed, "{}", name);
+            assert_eq!(meta.options.contains("closure_sizes"), closures, "{}", name);
+            assert_eq!(meta.hash_algorithm.as_deref(), Some("siphash24-keyed-64"));
+        }
+    }
+
+    #[test]
+    fn test_sqlite_inspect_reports_newer_and_corrupt_blocks() {
+        use crate::inspect::{inspect_path, MetaBlock, META_KEY};
+        let path = temp_path("versions.sqlite");
+        let store = SqliteStore::connect(&path).unwrap();
+        let mut newer = Vec::new();
+        ciborium::ser::into_writer(
+            &BTreeMap::from([("version", 99_u32), ("frobnication", 3)]),
+            &mut newer,
+        )
+        .unwrap();
+        store
+            .conn()
+            .execute(
+                "update merkle_dag_meta set value = ? where key = ?",
+                [newer.as_slice(), META_KEY],
+            )
+            .unwrap();
+        assert_eq!(
+            inspect_path(&path).unwrap().meta,
+            MetaBlock::Newer { version: 99 }
+        );
+        store
+            .conn()
+            .execute(
+                "update merkle_dag_meta set value = ? where key = ?",
+                [b"\xff\x00".as_slice(), META_KEY],
+            )
+            .unwrap();
+        let description = inspect_path(&path).unwrap();
+        assert!(matches!(description.meta, MetaBlock::Corrupt(_)));
+        // Connecting created the empty node table.
+        assert_eq!(description.node_count, Some(0));
+        assert_eq!(description.details["schema-version"], "3");
+        drop(store);
+    }
+
+    #[test]
+    fn test_inspect_rejects_unknown_files() {
+        use crate::inspect::inspect_path;
+        let path = temp_path("garbage.sqlite");
+        std::fs::write(&path, b"definitely not a database").unwrap();
+        assert!(matches!(
+            inspect_path(&path),
+            Err(StoreError::UnrecognizedStore(_))
+        ));
+    }
+}
+
+#[cfg(feature = "rusty-leveldb")]
+mod leveldb_tests {
+    use super::{
+        check_add_nodes, check_contains_many, check_find_by_prefix, check_flush_survives_reopen,
+        check_get_many, check_get_raw_matches_get, check_hash_algorithm_mismatch, check_ids,
+        check_quarantine_foreign_ids, check_remove_node, check_retain_reachable,
+        check_roots_survive_reopen, check_store_stats, check_transaction,
+    };
+    use crate::leveldb::LevelStore;
+    use crate::test::temp_path;
+
+    #[test]
+    fn test_level_store_get_raw() {
+        check_get_raw_matches_get(LevelStore::default());
+    }
+
+    #[test]
+    fn test_level_store_stats() {
+        check_store_stats(LevelStore::default());
+    }
+
+    #[test]
+    fn test_level_store_find_by_prefix() {
+        check_find_by_prefix(LevelStore::default());
+    }
+
+    #[test]
+    fn test_level_store_quarantine_foreign_ids() {
+        check_quarantine_foreign_ids(LevelStore::default());
+    }
+
+    #[test]
+    fn test_level_store_remove_node() {
+        check_remove_node(LevelStore::default());
+    }
+
+    #[test]
+    fn test_level_store_retain_reachable() {
+        check_retain_reachable(LevelStore::default());
+    }
+
+    #[test]
+    fn test_level_store_ids() {
+        check_ids(LevelStore::default());
+    }
+
+    #[test]
+    fn test_level_store_roots_survive_reopen() {
+        let path = temp_path("roots");
+        check_roots_survive_reopen(|| LevelStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_level_store_hash_algorithm_mismatch() {
+        let path = temp_path("hash-algorithm");
+        check_hash_algorithm_mismatch(|| LevelStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_level_store_flush_survives_reopen() {
+        let path = temp_path("flush");
+        check_flush_survives_reopen(|| LevelStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_level_store_transaction() {
+        let path = temp_path("transaction");
+        check_transaction(|| LevelStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_level_store_get_many() {
+        check_get_many(LevelStore::default());
+    }
+
+    #[test]
+    fn test_level_store_contains_many() {
+        check_contains_many(LevelStore::default());
+    }
+
+    #[test]
+    fn test_level_store_add_nodes() {
+        check_add_nodes(LevelStore::default(), LevelStore::default());
+    }
+
+    #[test]
+    fn test_level_store_interleaved_shared_reads() {
+        use crate::prelude::*;
+        use crate::store::Store;
+        use crate::test::TestHasher;
+        use std::collections::BTreeSet;
+        let mut dag = Merkle::<LevelStore, TestHasher>::default();
+        let mut last = dag.add_node("quake", BTreeSet::new()).unwrap();
+        for idx in 0..200 {
+            last = dag
+                .add_node(format!("quake-{}", idx), BTreeSet::from([last]))
+                .unwrap();
+        }
+        let (left, right) = (dag.get_nodes(), dag.get_nodes());
+        // Reads through one reference while an iterator from the other is still live.
+        let mut seen = 0;
+        for id in Store::<TestHasher>::ids(left).unwrap() {
+            let id = id.unwrap();
+            let node = Store::<TestHasher>::get(right, &id).unwrap().unwrap();
+            assert!(Store::<TestHasher>::contains(left, node.id()).unwrap());
+            for dep in node.dependency_ids() {
+                assert!(Store::<TestHasher>::get(left, dep).unwrap().is_some());
+            }
+            seen += 1;
+        }
+        assert_eq!(seen, 201);
+    }
+
+    #[test]
+    fn test_level_store_options_and_compaction() {
+        use crate::prelude::*;
+        use crate::store::Store;
+        use crate::test::TestHasher;
+        use std::collections::BTreeSet;
+        let path = temp_path("level-opts");
+        let mut ids = Vec::new();
+        {
+            let mut dag = Merkle::<LevelStore, TestHasher>::new(
+                LevelStore::open_with_compression(&path, true).unwrap(),
+            );
+            for idx in 0..2000 {
+                ids.push(
+                    dag.add_node(format!("compressed-{}", idx).repeat(8), BTreeSet::new())
+                        .unwrap(),
+                );
+            }
+            dag.nodes_mut().compact_range(&[0x00], &[0xff; 16]).unwrap();
+            Store::<TestHasher>::flush(dag.nodes_mut()).unwrap();
+        }
+        let dag = Merkle::<LevelStore, TestHasher>::load(
+            LevelStore::open_with_cache_size(&path, 1 << 20).unwrap(),
+        )
+        .unwrap();
+        for (idx, id) in ids.iter().enumerate() {
+            let node = dag.get_node_by_id(id).unwrap().unwrap();
+            assert_eq!(
+                node.item(),
+                format!("compressed-{}", idx).repeat(8).as_bytes()
+            );
+        }
+        drop(dag);
+    }
+
+    #[test]
+    fn test_level_store_inspect_leaves_files_alone() {
+        use crate::inspect::{inspect_path, BackendKind, MetaBlock};
+        use crate::prelude::*;
+        use crate::test::TestHasher;
+        use std::collections::{BTreeMap, BTreeSet};
+        let path = temp_path("inspect-level");
+        {
+            let mut dag = Merkle::<LevelStore, TestHasher>::new(LevelStore::open(&path).unwrap());
+            let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+            dag.add_node("shake", BTreeSet::from([quake])).unwrap();
+        }
+        let files = |path: &std::path::Path| {
+            std::fs::read_dir(path)
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    (entry.file_name(), std::fs::read(entry.path()).unwrap())
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let before = files(&path);
+        let description = inspect_path(&path).unwrap();
+        assert_eq!(files(&path), before);
+        assert_eq!(description.backend, BackendKind::LevelDb);
+        assert_eq!(description.node_count, Some(2));
+        assert!(
+            matches!(description.meta, MetaBlock::Current(meta) if meta.backend == BackendKind::LevelDb)
+        );
+    }
+}
+
+#[cfg(feature = "schema")]
+mod schema_tests {
+    use crate::prelude::*;
+    use crate::schema::{self, trace_value, Format, Json, VariantFormat};
+    use crate::test::{temp_path, TestHasher};
+    use crate::trace::TraceOp;
+    use ciborium::value::Value;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_committed_schemas_match_wire_format() {
+        // Set MERKLE_DAG_REGENERATE_SCHEMAS to rewrite the committed artifacts after an
+        // intentional wire format change.
+        if std::env::var_os("MERKLE_DAG_REGENERATE_SCHEMAS").is_some() {
+            schema::generate(schema::schema_dir()).unwrap();
+        }
+        schema::verify_fixtures().unwrap();
+    }
+
+    #[test]
+    fn test_schema_drift_is_detected() {
+        let dir = temp_path("schemas");
+        schema::generate(&dir).unwrap();
+        schema::verify_fixtures_in(&dir).unwrap();
+        let path = dir.join("TraceEntry.json");
+        let drifted = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("ChildrenOf", "Children");
+        std::fs::write(&path, drifted).unwrap();
+        assert!(schema::verify_fixtures_in(&dir).is_err());
+    }
+
+    #[test]
+    fn test_trace_value_merges_enum_variants() {
+        let format = [TraceOp::Contains, TraceOp::Store]
+            .iter()
+            .map(|op| trace_value(op).unwrap())
+            .try_fold(Format::Unknown, Format::merge)
+            .unwrap();
+        match format {
+            Format::Enum { name, variants } => {
+                assert_eq!(name, "TraceOp");
+                assert_eq!(
+                    variants.into_iter().collect::<Vec<_>>(),
+                    vec![
+                        (0, ("Contains", VariantFormat::Unit)),
+                        (2, ("Store", VariantFormat::Unit))
+                    ]
+                );
+            }
+            other => panic!("Unexpected format {:?}", other),
+        }
+        assert!(trace_value(&Vec::<u8>::new()).unwrap().has_unknown());
+    }
+
+    fn schema_field_names(name: &str) -> Vec<String> {
+        let path = schema::schema_dir().join(format!("{}.json", name));
+        let doc = Json::parse(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let fields = doc
+            .get("format")
+            .and_then(|f| f.get("Struct"))
+            .and_then(|s| s.get("fields"))
+            .unwrap();
+        match fields {
+            Json::Arr(fields) => fields
+                .iter()
+                .map(|field| match field.get("name") {
+                    Some(Json::Str(name)) => name.clone(),
+                    other => panic!("Unexpected field name {:?}", other),
+                })
+                .collect(),
+            other => panic!("Unexpected fields {:?}", other),
+        }
+    }
+
+    fn encoded_field_names<T: serde::Serialize>(value: &T) -> Vec<String> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(value, &mut buf).unwrap();
+        match ciborium::de::from_reader(buf.as_slice()).unwrap() {
+            Value::Map(entries) => entries
+                .into_iter()
+                .map(|(key, _)| match key {
+                    Value::Text(key) => key,
+                    other => panic!("Unexpected key {:?}", other),
+                })
+                .collect(),
+            other => panic!("Unexpected encoding {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_schema_field_order_matches_encoder() {
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let qualm = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+        assert_eq!(schema_field_names("Node"), encoded_field_names(&qualm));
+        let header = crate::trace::TraceHeader {
+            format: crate::trace::TRACE_FORMAT.to_owned(),
+            version: crate::trace::TRACE_VERSION,
+        };
+        assert_eq!(
+            schema_field_names("TraceHeader"),
+            encoded_field_names(&header)
+        );
+    }
+}
+
+#[cfg(feature = "sled")]
+mod sled_tests {
+    use super::{
+        check_add_nodes, check_contains_many, check_find_by_prefix, check_flush_survives_reopen,
+        check_get_many, check_get_raw_matches_get, check_hash_algorithm_mismatch, check_ids,
+        check_quarantine_foreign_ids, check_remove_node, check_retain_reachable,
+        check_roots_survive_reopen, check_store_stats, check_transaction,
+    };
+    use crate::prelude::*;
+    use crate::sled::SledStore;
+    use crate::test::{temp_path, TestHasher};
+    use std::collections::BTreeSet;
+
+    type SledDag = Merkle<SledStore, TestHasher>;
+
+    // Sled releases the lock of a dropped database from a background thread, so opening it
+    // again right away can fail for a moment.
+    fn reopen(path: &std::path::Path) -> SledStore {
+        use crate::clock::{Clock, SystemClock};
+        for _ in 0..100 {
+            if let Ok(store) = SledStore::open(path) {
+                return store;
+            }
+            let clock = SystemClock;
+            clock.sleep_until(clock.now() + std::time::Duration::from_millis(10));
+        }
+        SledStore::open(path).unwrap()
+    }
+
+    #[test]
+    fn test_sled_store_get_raw() {
+        check_get_raw_matches_get(SledStore::default());
+    }
+
+    #[test]
+    fn test_sled_store_stats() {
+        check_store_stats(SledStore::default());
+    }
+
+    #[test]
+    fn test_sled_store_find_by_prefix() {
+        check_find_by_prefix(SledStore::default());
+    }
+
+    #[test]
+    fn test_sled_store_quarantine_foreign_ids() {
+        check_quarantine_foreign_ids(SledStore::default());
+    }
+
+    #[test]
+    fn test_sled_store_remove_node() {
+        check_remove_node(SledStore::default());
+    }
+
+    #[test]
+    fn test_sled_store_retain_reachable() {
+        check_retain_reachable(SledStore::default());
+    }
+
+    #[test]
+    fn test_sled_store_ids() {
+        check_ids(SledStore::default());
+    }
+
+    #[test]
+    fn test_sled_store_get_many() {
+        check_get_many(SledStore::default());
+    }
+
+    #[test]
+    fn test_sled_store_contains_many() {
+        check_contains_many(SledStore::default());
+    }
+
+    #[test]
+    fn test_sled_store_add_nodes() {
+        check_add_nodes(SledStore::default(), SledStore::default());
+    }
+
+    #[test]
+    fn test_sled_store_roots_survive_reopen() {
+        let path = temp_path("sled-roots");
+        check_roots_survive_reopen(|| reopen(&path));
+    }
+
+    #[test]
+    fn test_sled_store_hash_algorithm_mismatch() {
+        let path = temp_path("sled-hash-algorithm");
+        check_hash_algorithm_mismatch(|| reopen(&path));
+    }
+
+    #[test]
+    fn test_sled_store_flush_survives_reopen() {
+        let path = temp_path("sled-flush");
+        check_flush_survives_reopen(|| reopen(&path));
+    }
+
+    #[test]
+    fn test_sled_store_transaction() {
+        let path = temp_path("sled-transaction");
+        check_transaction(|| reopen(&path));
+    }
+
+    #[test]
+    fn test_sled_dag_reloads_from_disk() {
+        let path = temp_path("sled-reload");
+        let (quell, count) = {
+            let mut dag = SledDag::load(reopen(&path)).unwrap();
+            let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+            let qualm = dag.add_node("qualm", BTreeSet::from([quake])).unwrap();
+            let quell = dag.add_node("quell", BTreeSet::from([qualm])).unwrap();
+            (quell, dag.node_count().unwrap())
+        };
+        let dag = SledDag::load(reopen(&path)).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quell.clone()]));
+        assert_eq!(dag.node_count().unwrap(), count);
+        assert_eq!(
+            dag.get_node_by_id(&quell).unwrap().unwrap().item(),
+            b"quell"
+        );
+        assert_eq!(
+            dag.get_nodes().meta().hash_algorithm.as_deref(),
+            Some(crate::inspect::hash_algorithm_name::<TestHasher>().as_str())
+        );
+        drop(dag);
+    }
+}
+
+#[cfg(feature = "redb")]
+mod redb_tests {
+    use super::{
+        check_add_nodes, check_contains_many, check_find_by_prefix, check_flush_survives_reopen,
+        check_get_many, check_get_raw_matches_get, check_hash_algorithm_mismatch, check_ids,
+        check_quarantine_foreign_ids, check_remove_node, check_retain_reachable,
+        check_roots_survive_reopen, check_store_stats, check_transaction,
+    };
+    use crate::prelude::*;
+    use crate::redb::RedbStore;
+    use crate::test::{temp_path, TestHasher};
+    use std::collections::BTreeSet;
+
+    type RedbDag = Merkle<RedbStore, TestHasher>;
+
+    fn memory() -> RedbStore {
+        RedbStore::in_memory().unwrap()
+    }
+
+    #[test]
+    fn test_redb_store_get_raw() {
+        check_get_raw_matches_get(memory());
+    }
+
+    #[test]
+    fn test_redb_store_stats() {
+        check_store_stats(memory());
+    }
+
+    #[test]
+    fn test_redb_store_find_by_prefix() {
+        check_find_by_prefix(memory());
+    }
+
+    #[test]
+    fn test_redb_store_quarantine_foreign_ids() {
+        check_quarantine_foreign_ids(memory());
+    }
+
+    #[test]
+    fn test_redb_store_remove_node() {
+        check_remove_node(memory());
+    }
+
+    #[test]
+    fn test_redb_store_retain_reachable() {
+        check_retain_reachable(memory());
+    }
+
+    #[test]
+    fn test_redb_store_ids() {
+        check_ids(memory());
+    }
+
+    #[test]
+    fn test_redb_store_get_many() {
+        check_get_many(memory());
+    }
+
+    #[test]
+    fn test_redb_store_contains_many() {
+        check_contains_many(memory());
+    }
+
+    #[test]
+    fn test_redb_store_add_nodes() {
+        check_add_nodes(memory(), memory());
+    }
+
+    #[test]
+    fn test_redb_store_roots_survive_reopen() {
+        let path = temp_path("redb-roots");
+        check_roots_survive_reopen(|| RedbStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_redb_store_hash_algorithm_mismatch() {
+        let path = temp_path("redb-hash-algorithm");
+        check_hash_algorithm_mismatch(|| RedbStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_redb_store_flush_survives_reopen() {
+        let path = temp_path("redb-flush");
+        check_flush_survives_reopen(|| RedbStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_redb_store_transaction() {
+        let path = temp_path("redb-transaction");
+        check_transaction(|| RedbStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_redb_dag_reopens_existing_database() {
+        let path = temp_path("redb-reopen");
+        let (quake, quell) = {
+            let mut dag = RedbDag::load(RedbStore::open(&path).unwrap()).unwrap();
+            let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+            let qualm = dag
+                .add_node("qualm", BTreeSet::from([quake.clone()]))
+                .unwrap();
+            let quell = dag.add_node("quell", BTreeSet::from([qualm])).unwrap();
+            (quake, quell)
+        };
+        let mut dag = RedbDag::load(RedbStore::open(&path).unwrap()).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quell.clone()]));
+        assert_eq!(dag.node_count().unwrap(), 3);
+        assert_eq!(dag.ancestors_of(&quell).unwrap().len(), 2);
+        let shake = dag.add_node("shake", BTreeSet::from([quell])).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([shake]));
+        assert!(dag.check_for_node(&quake).unwrap());
+        assert_eq!(
+            dag.get_nodes().meta().hash_algorithm.as_deref(),
+            Some(crate::inspect::hash_algorithm_name::<TestHasher>().as_str())
+        );
+        drop(dag);
+    }
+}
+
+#[cfg(feature = "dashmap")]
+mod concurrent_memory_tests {
+    use super::{
+        check_concurrent_writers, check_find_by_prefix, check_get_many, check_ids,
+        check_remove_node, check_retain_reachable, check_shared_views,
+    };
+    use crate::prelude::*;
+    use crate::store::{ConcurrentMemoryStore, ConcurrentStore, Store};
+    use crate::test::TestHasher;
+    use std::collections::BTreeSet;
+
+    type MemoryStore = ConcurrentMemoryStore<TestHasher>;
+
+    #[test]
+    fn test_concurrent_memory_store_find_by_prefix() {
+        check_find_by_prefix(MemoryStore::new());
+    }
+
+    #[test]
+    fn test_concurrent_memory_store_remove_node() {
+        check_remove_node(MemoryStore::new());
+    }
+
+    #[test]
+    fn test_concurrent_memory_store_retain_reachable() {
+        check_retain_reachable(MemoryStore::new());
+    }
+
+    #[test]
+    fn test_concurrent_memory_store_ids() {
+        check_ids(MemoryStore::new());
+    }
+
+    #[test]
+    fn test_concurrent_memory_store_get_many() {
+        check_get_many(MemoryStore::new());
+    }
+
+    #[test]
+    fn test_concurrent_memory_store_concurrent_writers() {
+        check_concurrent_writers(MemoryStore::new());
+    }
+
+    #[test]
+    fn test_concurrent_memory_store_shared_views() {
+        check_shared_views(MemoryStore::new());
+    }
+
+    #[test]
+    fn test_concurrent_memory_store_loses_no_nodes() {
+        let store = MemoryStore::new();
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    let mut ids = Vec::new();
+                    for idx in 0..500 {
+                        let node = Node::<TestHasher>::new(
+                            format!("writer-{}-{}", writer, idx),
+                            BTreeSet::new(),
+                        );
+                        ids.push(node.id().to_vec());
+                        store.store_shared(node).unwrap();
+                        // Read back our own writes and an earlier one while the others write.
+                        assert!(store.contains(&ids[idx]).unwrap());
+                        let earlier = store.get(&ids[idx / 2]).unwrap().unwrap();
+                        assert_eq!(earlier.id(), ids[idx / 2].as_slice());
+                    }
+                    ids
+                })
+            })
+            .collect();
+        let ids: Vec<Vec<u8>> = writers
+            .into_iter()
+            .flat_map(|writer| writer.join().unwrap())
+            .collect();
+        assert_eq!(Store::<TestHasher>::len(&store).unwrap(), 4000);
+        for id in ids {
+            assert!(Store::<TestHasher>::contains(&store, &id).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_concurrent_memory_store_clones_share_nodes() {
+        let mut first = Merkle::<_, TestHasher>::new(MemoryStore::new());
+        let mut second = Merkle::<_, TestHasher>::new(first.get_nodes().clone());
+        let quake = first.add_node("quake", BTreeSet::new()).unwrap();
+        let qualm = second
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        assert!(first.check_for_node(&qualm).unwrap());
+        assert_eq!(first.get_roots(), &BTreeSet::from([quake]));
+        assert_eq!(second.get_roots(), &BTreeSet::from([qualm]));
+    }
+}
+
+#[cfg(feature = "cbor")]
+mod fs_tests {
+    use super::{
+        check_add_nodes, check_contains_many, check_find_by_prefix, check_flush_survives_reopen,
+        check_get_many, check_get_raw_matches_get, check_ids, check_remove_node,
+        check_retain_reachable, check_roots_survive_reopen, check_store_stats, check_transaction,
+        hex,
+    };
+    #[cfg(feature = "blake2")]
+    use super::{check_hash_algorithm_mismatch, check_quarantine_foreign_ids};
+    use crate::fs::FsStore;
+    use crate::prelude::*;
+    use crate::store::Store;
+    use crate::test::{temp_path, TestHasher};
+    use std::collections::BTreeSet;
+
+    type FsDag = Merkle<FsStore, TestHasher>;
+
+    pub(super) fn temporary() -> FsStore {
+        FsStore::open(temp_path("fs-temporary").keep()).unwrap()
+    }
+
+    #[test]
+    fn test_fs_store_get_raw() {
+        check_get_raw_matches_get(temporary());
+    }
+
+    #[test]
+    fn test_fs_store_stats() {
+        check_store_stats(temporary());
+    }
+
+    #[test]
+    fn test_fs_store_find_by_prefix() {
+        check_find_by_prefix(temporary());
+    }
+
+    #[test]
+    fn test_fs_store_find_by_prefix_deeply_sharded() {
+        let path = temp_path("fs-deep");
+        check_find_by_prefix(FsStore::open_with_shard_depth(&path, 3).unwrap());
+    }
+
+    #[cfg(feature = "blake2")]
+    #[test]
+    fn test_fs_store_quarantine_foreign_ids() {
+        check_quarantine_foreign_ids(temporary());
+    }
+
+    #[test]
+    fn test_fs_store_remove_node() {
+        check_remove_node(temporary());
+    }
+
+    #[test]
+    fn test_fs_store_retain_reachable() {
+        check_retain_reachable(temporary());
+    }
+
+    #[test]
+    fn test_fs_store_ids() {
+        check_ids(temporary());
+    }
+
+    #[test]
+    fn test_fs_store_get_many() {
+        check_get_many(temporary());
+    }
+
+    #[test]
+    fn test_fs_store_contains_many() {
+        check_contains_many(temporary());
+    }
+
+    #[test]
+    fn test_fs_store_add_nodes() {
+        check_add_nodes(temporary(), temporary());
+    }
+
+    #[test]
+    fn test_fs_store_roots_survive_reopen() {
+        let path = temp_path("fs-roots");
+        check_roots_survive_reopen(|| FsStore::open(&path).unwrap());
+    }
+
+    #[cfg(feature = "blake2")]
+    #[test]
+    fn test_fs_store_hash_algorithm_mismatch() {
+        let path = temp_path("fs-hash-algorithm");
+        check_hash_algorithm_mismatch(|| FsStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_fs_store_flush_survives_reopen() {
+        let path = temp_path("fs-flush");
+        check_flush_survives_reopen(|| FsStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_fs_store_transaction() {
+        let path = temp_path("fs-transaction");
+        check_transaction(|| FsStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_fs_store_shards_object_paths() {
+        let path = temp_path("fs-shards");
+        let mut store = FsStore::open_with_shard_depth(&path, 2).unwrap();
+        let quake = Node::<TestHasher>::new(b"quake".to_vec(), BTreeSet::new());
+        let id = quake.id().to_vec();
+        Store::<TestHasher>::store(&mut store, quake).unwrap();
+        let object = path
+            .join("objects")
+            .join(hex(&id[0..1]))
+            .join(hex(&id[1..2]))
+            .join(hex(&id[2..]));
+        assert!(object.is_file());
+        drop(store);
+
+        let store = FsStore::open(&path).unwrap();
+        assert_eq!(store.shard_depth(), 2);
+        assert!(Store::<TestHasher>::contains(&store, &id).unwrap());
+        drop(store);
+        assert!(FsStore::open_with_shard_depth(&path, 1).is_err());
+    }
+
+    #[test]
+    fn test_fs_store_ignores_interrupted_writes() {
+        let path = temp_path("fs-crash");
+        let mut dag = FsDag::load(FsStore::open(&path).unwrap()).unwrap();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        // A crash before the rename leaves a partial temporary file and nothing in place.
+        let partial = path.join("tmp").join("interrupted");
+        std::fs::write(&partial, &std::fs::read(path.join("roots")).unwrap()[..3]).unwrap();
+        // A crash between the nodes and the roots leaves a node no root reaches.
+        let orphan = Node::<TestHasher>::new(b"orphan".to_vec(), BTreeSet::new());
+        let orphan_id = orphan.id().to_vec();
+        Store::<TestHasher>::store(dag.nodes_mut(), orphan).unwrap();
+        drop(dag);
+
+        let dag = FsDag::load(FsStore::open(&path).unwrap()).unwrap();
+        assert!(!partial.exists());
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm]));
+        assert!(dag.check_for_node(&quake).unwrap());
+        assert!(dag.check_for_node(&orphan_id).unwrap());
+        assert_eq!(dag.node_count().unwrap(), 3);
+        drop(dag);
+    }
+
+    #[test]
+    fn test_fs_dag_reopens_existing_object_directory() {
+        let path = temp_path("fs-reopen");
+        let quell = {
+            let mut dag = FsDag::load(FsStore::open(&path).unwrap()).unwrap();
+            let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+            dag.add_node("quell", BTreeSet::from([quake])).unwrap()
+        };
+        let dag = FsDag::load(FsStore::open(&path).unwrap()).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quell]));
+        assert_eq!(dag.node_count().unwrap(), 2);
+        assert_eq!(
+            dag.get_nodes().meta().hash_algorithm.as_deref(),
+            Some(crate::inspect::hash_algorithm_name::<TestHasher>().as_str())
+        );
+        drop(dag);
+    }
+}
+
+#[cfg(feature = "cbor")]
+mod log_tests {
+    use super::{
+        check_add_nodes, check_contains_many, check_find_by_prefix, check_flush_survives_reopen,
+        check_get_many, check_get_raw_matches_get, check_ids, check_remove_node,
+        check_retain_reachable, check_roots_survive_reopen, check_store_stats, check_transaction,
+    };
+    #[cfg(feature = "blake2")]
+    use super::{check_hash_algorithm_mismatch, check_quarantine_foreign_ids};
+    use crate::log::LogStore;
+    use crate::prelude::*;
+    use crate::store::Store;
+    use crate::test::{temp_path, TestHasher};
+    use std::collections::BTreeSet;
+
+    type LogDag = Merkle<LogStore, TestHasher>;
+
+    pub(super) fn temporary() -> LogStore {
+        LogStore::open(temp_path("log-temporary").keep()).unwrap()
+    }
+
+    fn file_len(path: &std::path::Path) -> u64 {
+        std::fs::metadata(path).unwrap().len()
+    }
+
+    #[test]
+    fn test_log_store_get_raw() {
+        check_get_raw_matches_get(temporary());
+    }
+
+    #[test]
+    fn test_log_store_stats() {
+        check_store_stats(temporary());
+    }
+
+    #[test]
+    fn test_log_store_find_by_prefix() {
+        check_find_by_prefix(temporary());
+    }
+
+    #[cfg(feature = "blake2")]
+    #[test]
+    fn test_log_store_quarantine_foreign_ids() {
+        check_quarantine_foreign_ids(temporary());
+    }
+
+    #[test]
+    fn test_log_store_remove_node() {
+        check_remove_node(temporary());
+    }
+
+    #[test]
+    fn test_log_store_retain_reachable() {
+        check_retain_reachable(temporary());
+    }
+
+    #[test]
+    fn test_log_store_ids() {
+        check_ids(temporary());
+    }
+
+    #[test]
+    fn test_log_store_get_many() {
+        check_get_many(temporary());
+    }
+
+    #[test]
+    fn test_log_store_contains_many() {
+        check_contains_many(temporary());
+    }
+
+    #[test]
+    fn test_log_store_add_nodes() {
+        check_add_nodes(temporary(), temporary());
+    }
+
+    #[test]
+    fn test_log_store_roots_survive_reopen() {
+        let path = temp_path("log-roots");
+        check_roots_survive_reopen(|| LogStore::open(&path).unwrap());
+    }
+
+    #[cfg(feature = "blake2")]
+    #[test]
+    fn test_log_store_hash_algorithm_mismatch() {
+        let path = temp_path("log-hash-algorithm");
+        check_hash_algorithm_mismatch(|| LogStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_log_store_flush_survives_reopen() {
+        let path = temp_path("log-flush");
+        check_flush_survives_reopen(|| LogStore::open(&path).unwrap().with_sync_writes(true));
+    }
+
+    #[test]
+    fn test_log_store_transaction() {
+        let path = temp_path("log-transaction");
+        check_transaction(|| LogStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn test_log_store_truncates_torn_appends() {
+        let path = temp_path("log-torn");
+        let mut dag = LogDag::load(LogStore::open(&path).unwrap()).unwrap();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let complete = file_len(&path);
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        let torn = file_len(&path);
+        drop(dag);
+
+        // Cut the last append off at every byte and check the log recovers to the append
+        // before it. The roots are the last record of an append so they never show up torn.
+        let log = std::fs::read(&path).unwrap();
+        let check = |store: &LogStore| {
+            assert!(Store::<TestHasher>::contains(store, &quake).unwrap());
+            let roots = Store::<TestHasher>::persisted_roots(store)
+                .unwrap()
+                .unwrap();
+            assert_eq!(roots.roots, BTreeSet::from([quake.clone()]));
+            if let Some(node) = Store::<TestHasher>::get(store, &qualm).unwrap() {
+                assert_eq!(node.item(), b"qualm");
+            }
+            assert!(store.meta().hash_algorithm.is_some());
+        };
+        for cut in (complete + 1)..torn {
+            std::fs::write(&path, &log[..cut as usize]).unwrap();
+            check(&LogStore::open(&path).unwrap());
+            // The torn bytes are gone so the metadata appended on open reads back.
+            check(&LogStore::open(&path).unwrap());
+        }
+        std::fs::write(&path, &log[..complete as usize]).unwrap();
+
+        let mut dag = LogDag::load(LogStore::open(&path).unwrap()).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quake.clone()]));
+        let qualm = dag.add_node("qualm", BTreeSet::from([quake])).unwrap();
+        drop(dag);
+        let dag = LogDag::load(LogStore::open(&path).unwrap()).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm]));
+        assert_eq!(dag.node_count().unwrap(), 2);
+        drop(dag);
+    }
+
+    #[test]
+    fn test_log_store_cuts_off_failed_appends() {
+        let path = temp_path("log-failed-append");
+        let mut dag = LogDag::load(LogStore::open(&path).unwrap()).unwrap();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let before = file_len(&path);
+        crate::log::fail_next_append_after(10);
+        assert!(dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .is_err());
+        assert_eq!(file_len(&path), before);
+        let shake = dag.add_node("shake", BTreeSet::from([quake])).unwrap();
+        drop(dag);
+        let dag = LogDag::load(LogStore::open(&path).unwrap()).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([shake]));
+        assert_eq!(dag.node_count().unwrap(), 2);
+        drop(dag);
+    }
+
+    #[test]
+    fn test_log_store_compact_drops_stale_records() {
+        let path = temp_path("log-compact");
+        let mut dag = LogDag::load(LogStore::open(&path).unwrap()).unwrap();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        let quell = dag.add_node("quell", BTreeSet::new()).unwrap();
+        let node = dag.get_node_by_id(&qualm).unwrap().unwrap();
+        Store::<TestHasher>::store(dag.nodes_mut(), node).unwrap();
+        dag.remove_node(&quell, RemoveScope::Node).unwrap();
+        assert!(dag.get_nodes().stale_records() > 0);
+
+        let before = file_len(&path);
+        let reclaimed = dag.nodes_mut().compact().unwrap();
+        assert!(reclaimed > 0);
+        assert!(file_len(&path) < before);
+        assert_eq!(dag.get_nodes().stale_records(), 0);
+        assert!(!dag.check_for_node(&quell).unwrap());
+        let shake = dag.add_node("shake", BTreeSet::from([qualm])).unwrap();
+        drop(dag);
+
+        let dag = LogDag::load(LogStore::open(&path).unwrap()).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([shake]));
+        assert_eq!(dag.node_count().unwrap(), 3);
+        assert_eq!(
+            dag.get_nodes().meta().hash_algorithm.as_deref(),
+            Some(crate::inspect::hash_algorithm_name::<TestHasher>().as_str())
+        );
+        assert!(dag.get_nodes().meta().last_maintenance_secs.is_some());
+        drop(dag);
+    }
+}
+
+#[cfg(feature = "lmdb")]
+mod lmdb_tests {
+    use super::{
+        check_add_nodes, check_contains_many, check_find_by_prefix, check_flush_survives_reopen,
+        check_get_many, check_get_raw_matches_get, check_hash_algorithm_mismatch, check_ids,
+        check_quarantine_foreign_ids, check_remove_node, check_retain_reachable,
+        check_roots_survive_reopen, check_store_stats, check_transaction,
+    };
+    use crate::lmdb::LmdbStore;
+    use crate::prelude::*;
+    use crate::store::{Store, StoreErrorKind};
+    use crate::test::{temp_path, TestHasher};
+    use std::collections::BTreeSet;
+
+    type LmdbDag = Merkle<LmdbStore, TestHasher>;
+
+    const MAP_SIZE: usize = 64 * 1024 * 1024;
+
+    pub(super) fn temporary() -> LmdbStore {
+        LmdbStore::open(temp_path("lmdb-temporary").keep(), MAP_SIZE).unwrap()
+    }
+
+    #[test]
+    fn test_lmdb_store_get_raw() {
+        check_get_raw_matches_get(temporary());
+    }
+
+    #[test]
+    fn test_lmdb_store_stats() {
+        check_store_stats(temporary());
+    }
+
+    #[test]
+    fn test_lmdb_store_find_by_prefix() {
+        check_find_by_prefix(temporary());
+    }
+
+    #[test]
+    fn test_lmdb_store_quarantine_foreign_ids() {
+        check_quarantine_foreign_ids(temporary());
+    }
+
+    #[test]
+    fn test_lmdb_store_remove_node() {
+        check_remove_node(temporary());
+    }
+
+    #[test]
+    fn test_lmdb_store_retain_reachable() {
+        check_retain_reachable(temporary());
+    }
+
+    #[test]
+    fn test_lmdb_store_ids() {
+        check_ids(temporary());
+    }
+
+    #[test]
+    fn test_lmdb_store_get_many() {
+        check_get_many(temporary());
+    }
+
+    #[test]
+    fn test_lmdb_store_contains_many() {
+        check_contains_many(temporary());
+    }
+
+    #[test]
+    fn test_lmdb_store_add_nodes() {
+        check_add_nodes(temporary(), temporary());
+    }
+
+    #[test]
+    fn test_lmdb_store_roots_survive_reopen() {
+        let path = temp_path("lmdb-roots");
+        check_roots_survive_reopen(|| LmdbStore::open(&path, MAP_SIZE).unwrap());
+    }
+
+    #[test]
+    fn test_lmdb_store_hash_algorithm_mismatch() {
+        let path = temp_path("lmdb-hash-algorithm");
+        check_hash_algorithm_mismatch(|| LmdbStore::open(&path, MAP_SIZE).unwrap());
+    }
+
+    #[test]
+    fn test_lmdb_store_flush_survives_reopen() {
+        let path = temp_path("lmdb-flush");
+        check_flush_survives_reopen(|| LmdbStore::open(&path, MAP_SIZE).unwrap());
+    }
+
+    #[test]
+    fn test_lmdb_store_transaction() {
+        let path = temp_path("lmdb-transaction");
+        check_transaction(|| LmdbStore::open(&path, MAP_SIZE).unwrap());
+    }
+
+    #[test]
+    fn test_lmdb_store_store_many_round_trips() {
+        let mut store = temporary();
+        let quake = Node::<TestHasher>::new(b"quake".to_vec(), BTreeSet::new());
+        let qualm =
+            Node::<TestHasher>::new(b"qualm".to_vec(), BTreeSet::from([quake.id().to_vec()]));
+        store.store_many([quake.clone(), qualm.clone()]).unwrap();
+        for node in [quake, qualm] {
+            let stored = Store::<TestHasher>::get(&store, node.id())
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored.id(), node.id());
+            assert_eq!(stored.item(), node.item());
+            assert_eq!(stored.dependency_ids(), node.dependency_ids());
+        }
+        assert_eq!(Store::<TestHasher>::len(&store).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_lmdb_store_full_map_is_a_store_error() {
+        let path = temp_path("lmdb-full");
+        let mut dag = LmdbDag::new(LmdbStore::open(&path, 1024 * 1024).unwrap());
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let err = dag
+            .add_node(vec![0u8; 4 * 1024 * 1024], BTreeSet::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), StoreErrorKind::StoreFull);
+        assert!(dag.check_for_node(&quake).unwrap());
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quake]));
+        drop(dag);
+    }
+
+    #[test]
+    fn test_lmdb_dag_reopens_existing_environment() {
+        let path = temp_path("lmdb-reopen");
+        let quell = {
+            let mut dag = LmdbDag::load(LmdbStore::open(&path, MAP_SIZE).unwrap()).unwrap();
+            let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+            dag.add_node("quell", BTreeSet::from([quake])).unwrap()
+        };
+        let dag = LmdbDag::load(LmdbStore::open(&path, MAP_SIZE).unwrap()).unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quell]));
+        assert_eq!(dag.node_count().unwrap(), 2);
+        assert_eq!(
+            dag.get_nodes().meta().hash_algorithm.as_deref(),
+            Some(crate::inspect::hash_algorithm_name::<TestHasher>().as_str())
+        );
+        drop(dag);
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+mod rocksdb_tests {
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+
+    use super::{
+        check_add_nodes, check_concurrent_writers, check_flush_survives_reopen,
+        check_hash_algorithm_mismatch, check_roots_survive_reopen, check_shared_views,
+    };
+    use crate::prelude::*;
+    use crate::rocksdb::{meta_column_family, MultiThreadedRocksStore, SingleThreadedRocksStore};
+    use crate::store::Store;
+    use crate::test::{temp_path, TestHasher};
+
+    // The number of writes RocksDB did through its write ahead log. A write batch counts once.
+    fn wal_writes(opts: &::rocksdb::Options) -> u64 {
+        opts.get_statistics()
+            .unwrap()
+            .lines()
+            .find(|line| line.starts_with("rocksdb.write.wal "))
+            .and_then(|line| line.split_whitespace().last())
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    fn generated_nodes(count: usize) -> Vec<Node<TestHasher>> {
+        let mut nodes: Vec<Node<TestHasher>> = Vec::with_capacity(count);
+        for idx in 0..count {
+            let mut deps = BTreeSet::new();
+            if idx >= 2 {
+                deps.insert(nodes[idx - 2].id().to_vec());
+            }
+            if idx % 7 == 6 {
+                deps.insert(nodes[idx / 3].id().to_vec());
+            }
+            nodes.push(Node::new(format!("import-{}", idx), deps));
+        }
+        nodes
+    }
+
+    #[test]
+    fn test_rocks_store_add_nodes() {
+        let (sequential, bulk) = (temp_path("rocks-add-seq"), temp_path("rocks-add-bulk"));
+        let mut opts = ::rocksdb::Options::default();
+        opts.create_if_missing(true);
+        check_add_nodes(
+            SingleThreadedRocksStore::open_with_opts(&sequential, &opts).unwrap(),
+            SingleThreadedRocksStore::open_with_opts(&bulk, &opts).unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_rocks_store_reads() {
+        let path = temp_path("rocks-reads");
+        let mut opts = ::rocksdb::Options::default();
+        opts.create_if_missing(true);
+        let nodes = generated_nodes(500);
+        {
+            let mut store = SingleThreadedRocksStore::open_with_opts(&path, &opts).unwrap();
+            store.store_many(nodes.iter().cloned()).unwrap();
+            Store::<TestHasher>::flush(&mut store).unwrap();
+        }
+        // A reopened store has nothing in its memtable or block cache so without a bloom filter
+        // RocksDB can't rule out keys that fall inside the flushed table.
+        let store = SingleThreadedRocksStore::open_with_opts(&path, &opts).unwrap();
+        let absent: Vec<Vec<u8>> = generated_nodes(600)[500..]
+            .iter()
+            .map(|node| node.id().to_vec())
+            .collect();
+        let may_exist = absent
+            .iter()
+            .filter(|id| store.db().key_may_exist(id))
+            .count();
+        assert!(may_exist > 0);
+        for id in absent.iter() {
+            assert!(!Store::<TestHasher>::contains(&store, id).unwrap());
+            assert!(Store::<TestHasher>::get(&store, id).unwrap().is_none());
+        }
+        for node in nodes.iter() {
+            assert!(Store::<TestHasher>::contains(&store, node.id()).unwrap());
+            let stored = Store::<TestHasher>::get(&store, node.id())
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored.id(), node.id());
+            assert_eq!(stored.item(), node.item());
+            assert_eq!(stored.dependency_ids(), node.dependency_ids());
+        }
+        let mut ids: Vec<&[u8]> = nodes.iter().map(|node| node.id()).collect();
+        ids.push(&absent[0]);
+        let many = Store::<TestHasher>::get_many(&store, &ids).unwrap();
+        assert!(many[nodes.len()].is_none());
+        for (node, stored) in nodes.iter().zip(many) {
+            assert_eq!(stored.unwrap().id(), node.id());
+        }
+        drop(store);
+    }
+
+    #[test]
+    fn test_rocks_store_batched_import_matches_sequential() {
+        let (sequential_path, batched_path) = (
+            temp_path("rocks-import-seq"),
+            temp_path("rocks-import-batch"),
+        );
+        let mut opts = ::rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.enable_statistics();
+        let nodes = generated_nodes(1000);
+        {
+            let mut sequential =
+                SingleThreadedRocksStore::open_with_opts(&sequential_path, &opts).unwrap();
+            let before = wal_writes(&opts);
+            for node in nodes.iter().cloned() {
+                sequential.store(node).unwrap();
+            }
+            assert!(wal_writes(&opts) - before >= nodes.len() as u64);
+        }
+        let mut opts = ::rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.enable_statistics();
+        let mut batched = SingleThreadedRocksStore::open_with_opts(&batched_path, &opts)
+            .unwrap()
+            .with_sync_writes(true);
+        let before = wal_writes(&opts);
+        batched.store_many(nodes.iter().cloned()).unwrap();
+        // One write for the batch and one for recording the hash algorithm.
+        assert!(wal_writes(&opts) - before <= 2);
+        let sequential = SingleThreadedRocksStore::open_with_opts(&sequential_path, &opts).unwrap();
+        let ids = |store: &SingleThreadedRocksStore| -> Vec<Vec<u8>> {
+            Store::<TestHasher>::ids(store)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        assert_eq!(ids(&batched), ids(&sequential));
+        for node in nodes.iter() {
+            let raw = Store::<TestHasher>::get_raw(&batched, node.id()).unwrap();
+            assert!(raw.is_some());
+            assert_eq!(
+                raw,
+                Store::<TestHasher>::get_raw(&sequential, node.id()).unwrap()
+            );
+        }
+        drop((batched, sequential));
+    }
+
+    #[test]
+    fn test_multi_threaded_rocks_store_concurrent_writers() {
+        let path = temp_path("rocks-writers");
+        let mut opts = ::rocksdb::Options::default();
+        opts.create_if_missing(true);
+        check_concurrent_writers(MultiThreadedRocksStore::open_with_opts(&path, &opts).unwrap());
+    }
+
+    #[test]
+    fn test_single_threaded_rocks_store_shared_views() {
+        let path = temp_path("rocks-views");
+        let mut opts = ::rocksdb::Options::default();
+        opts.create_if_missing(true);
+        check_shared_views(SingleThreadedRocksStore::open_with_opts(&path, &opts).unwrap());
+    }
+
+    #[test]
+    fn test_rocks_store_flush_survives_reopen() {
+        let path = temp_path("rocks-flush");
+        let mut opts = ::rocksdb::Options::default();
+        opts.create_if_missing(true);
+        check_flush_survives_reopen(|| {
+            SingleThreadedRocksStore::open_with_opts(&path, &opts).unwrap()
+        });
+    }
+
+    #[test]
+    fn test_rocks_store_column_families_are_isolated() {
+        let path = temp_path("rocks-cfs");
+        let mut opts = ::rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let db = ::rocksdb::DBWithThreadMode::<::rocksdb::MultiThreaded>::open_cf(
+            &opts,
+            &path,
+            [
+                "left".to_owned(),
+                meta_column_family("left"),
+                "right".to_owned(),
+                meta_column_family("right"),
+            ],
+        )
+        .unwrap();
+        let db = Arc::new(db);
+        let mut left = Merkle::<_, TestHasher>::from_store(
+            MultiThreadedRocksStore::from_db(db.clone(), Some("left")).unwrap(),
+        )
+        .unwrap();
+        let mut right = Merkle::<_, TestHasher>::from_store(
+            MultiThreadedRocksStore::from_db(db.clone(), Some("right")).unwrap(),
+        )
+        .unwrap();
+        let quake = left.add_node("quake", BTreeSet::new()).unwrap();
+        let shake = right.add_node("shake", BTreeSet::new()).unwrap();
+        assert!(left.check_for_node(&quake).unwrap());
+        assert!(!left.check_for_node(&shake).unwrap());
+        assert!(right.check_for_node(&shake).unwrap());
+        assert!(!right.check_for_node(&quake).unwrap());
+        let left_ids: Vec<Vec<u8>> = Store::<TestHasher>::ids(left.get_nodes())
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(left_ids, vec![quake.clone()]);
+        // Nothing was written to the default column family.
+        assert!(db.get(&quake).unwrap().is_none());
+        assert!(MultiThreadedRocksStore::from_db(db.clone(), Some("missing")).is_err());
+        drop((left, right, db));
+    }
+
+    #[test]
+    fn test_rocks_store_column_family_roots_survive_reopen() {
+        let path = temp_path("rocks-cf-roots");
+        let mut opts = ::rocksdb::Options::default();
+        opts.create_if_missing(true);
+        check_roots_survive_reopen(|| {
+            SingleThreadedRocksStore::open_with_column_family(&path, &opts, "dag").unwrap()
+        });
+    }
+
+    #[test]
+    fn test_rocks_store_hash_algorithm_mismatch() {
+        let path = temp_path("rocks-hash-algorithm");
+        let mut opts = ::rocksdb::Options::default();
+        opts.create_if_missing(true);
+        check_hash_algorithm_mismatch(|| {
+            SingleThreadedRocksStore::open_with_opts(&path, &opts).unwrap()
+        });
+    }
+}
+
+#[cfg(feature = "sha2")]
+mod sha2_tests {
+    use super::hex;
+    use crate::prelude::*;
+    use std::collections::BTreeSet;
+
+    // Checks the ids of fixed nodes so a change to the order bytes are hashed in is caught.
+    // The vectors were computed independently of this crate.
+    fn check_golden_ids<HW: HashWriter>(golden: [&str; 5]) {
+        let empty = Node::<HW>::new_with_id_version(Vec::new(), BTreeSet::new(), NodeIdVersion::V0);
+        let quake = Node::<HW>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+        let shake = Node::<HW>::new_with_id_version("shake", BTreeSet::new(), NodeIdVersion::V0);
+        let qualm = Node::<HW>::new_with_id_version(
+            "qualm",
+            BTreeSet::from([quake.id().to_vec()]),
+            NodeIdVersion::V0,
+        );
+        // Dependencies are hashed in sorted order whatever order they are given in.
+        let quell = Node::<HW>::new_with_id_version(
+            "quell",
+            BTreeSet::from([shake.id().to_vec(), quake.id().to_vec()]),
+            NodeIdVersion::V0,
+        );
+        let ids = [&empty, &quake, &shake, &qualm, &quell].map(|node| hex(node.id()));
+        assert_eq!(ids, golden.map(str::to_owned));
+        assert_eq!(quake.item_id(), quake.id());
+        assert_eq!(
+            hex(qualm.item_id()),
+            hex(Node::<HW>::new_with_id_version("qualm", BTreeSet::new(), NodeIdVersion::V0).id())
+        );
+    }
+
+    #[test]
+    fn test_sha256_golden_ids() {
+        check_golden_ids::<Sha256>([
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "aae3ba6bd925f6fa90f778c254346436559dd9cf970f71602ce99cdbdeea5adc",
+            "50d22da6c0d4799bb16322e6aa0cbcfe44c9e0755b302a1777c1d0227bbdb65d",
+            "25228c8826823bd6be8add30c2e62644947f88fe9e17d5432fa7c3ebd8076fe6",
+            "118d882fdb02220017d38c056f5c294baad8430e127b07e6bdcf467dfe211724",
+        ]);
+    }
+
+    #[test]
+    fn test_sha512_golden_ids() {
+        check_golden_ids::<Sha512>([
+            "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e",
+            "11b87a205616bdf1ed57e8c4b55e40bae3201c8864fa38074f93cb3415f727cb685d9391ab293969cbafaab52bdc6965d249c94fbf509bf04f529502a910c18d",
+            "457a0bfa4d2f3e82d9e2224062ad3c5373ad96918018015229eaa302cfd3198eb0ee14d8f399d0d5f188f2ef453ee74abeb902c543a6241097bb4f3142e22fad",
+            "0222b44e45a3801b1a4cc8c2d0ac9d663d451408ddd83a5b93f3d4dce73c01cb8d9d1650ead308a8c9bcbc26ec89be40c284470f6eeba30c8f0d4890503716e9",
+            "68468bc766b992e44417b9cf7e78115438ec8a28c7f15934b9830551f88e749c65daafd9b8919f5a18b2f14acd7000d1dc2ab6a83eea5c77dac8ca69678331c5",
+        ]);
+    }
+
+    #[test]
+    fn test_sha256_dag() {
+        let mut dag = Merkle::<BTreeStore<Sha256>, Sha256>::default();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        assert_eq!(quake.len(), 32);
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm]));
+    }
+}
+
+#[cfg(feature = "xxhash")]
+mod xxhash_tests {
+    use super::hex;
+    use crate::prelude::*;
+    use crate::store::ReverseIndexStore;
+    use crate::xxhash::{Xxh3_128, Xxh3_64};
+    use std::collections::BTreeSet;
+
+    // Pins the ids of fixed nodes so the proptest corpus stays valid across releases. The ids
+    // of empty payloads are the XXH3 reference vectors.
+    fn check_golden_ids<HW: HashWriter>(golden: [&str; 5]) {
+        let empty = Node::<HW>::new_with_id_version(Vec::new(), BTreeSet::new(), NodeIdVersion::V0);
+        let quake = Node::<HW>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+        let shake = Node::<HW>::new_with_id_version("shake", BTreeSet::new(), NodeIdVersion::V0);
+        let qualm = Node::<HW>::new_with_id_version(
+            "qualm",
+            BTreeSet::from([quake.id().to_vec()]),
+            NodeIdVersion::V0,
+        );
+        let quell = Node::<HW>::new_with_id_version(
+            "quell",
+            BTreeSet::from([shake.id().to_vec(), quake.id().to_vec()]),
+            NodeIdVersion::V0,
+        );
+        let ids = [&empty, &quake, &shake, &qualm, &quell].map(|node| hex(node.id()));
+        assert_eq!(ids, golden.map(str::to_owned));
+        assert!(ids.iter().all(|id| id.len() == 2 * HW::OUTPUT_LEN));
+    }
+
+    #[test]
+    fn test_xxh3_64_golden_ids() {
+        check_golden_ids::<Xxh3_64>([
+            "2d06800538d394c2",
+            "057fa56f241fcbc3",
+            "6aef999eade331c3",
+            "f29879580ba36d05",
+            "bca46e132386d36b",
+        ]);
+    }
+
+    #[test]
+    fn test_xxh3_128_golden_ids() {
+        check_golden_ids::<Xxh3_128>([
+            "99aa06d3014798d86001c324468d497f",
+            "406f581275694ab6b34c1620004069f4",
+            "83018728fe300fe4b4e2bcbee02d5d15",
+            "1aecd2d047122373ca48afdf57c9af94",
+            "509f18dea446746ae97b704d9e4b8795",
+        ]);
+    }
+
+    #[test]
+    fn test_xxh3_64_dag_traversal() {
+        let mut dag = Merkle::<ReverseIndexStore<BTreeStore<Xxh3_64>>, Xxh3_64>::default();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let shake = dag.add_node("shake", BTreeSet::new()).unwrap();
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        let quell = dag
+            .add_node("quell", BTreeSet::from([qualm.clone(), shake.clone()]))
+            .unwrap();
+        assert_eq!(quell.len(), 8);
+        dag.ensure_uniform_ids().unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quell.clone()]));
+        assert_eq!(dag.compare(&quell, &quake).unwrap(), NodeCompare::After);
+        assert_eq!(
+            dag.compare(&qualm, &shake).unwrap(),
+            NodeCompare::Uncomparable
+        );
+        assert_eq!(
+            dag.reachable(&quake, Direction::Down).unwrap(),
+            BTreeSet::from([qualm.clone(), quell.clone()])
+        );
+        assert_eq!(
+            dag.path_between_directed(&quell, &quake, Direction::Up)
+                .unwrap()
+                .unwrap(),
+            vec![quell, qualm, quake]
+        );
+    }
+}
+
+mod payload_tests {
+    use super::{TestDag, TestHasher};
+    use crate::prelude::*;
+    use crate::store::StoreError;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    // A sensor reading encoded as a fixed width big endian record.
+    #[derive(Debug, Clone, PartialEq)]
+    struct Reading {
+        sensor: u16,
+        value: i32,
+    }
+
+    impl ByteEncoder for Reading {
+        fn encode_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+            let mut bytes = self.sensor.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&self.value.to_be_bytes());
+            Ok(bytes)
+        }
+    }
+
+    impl ByteDecoder for Reading {
+        fn decode_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+            match bytes {
+                [s0, s1, v0, v1, v2, v3] => Ok(Reading {
+                    sensor: u16::from_be_bytes([*s0, *s1]),
+                    value: i32::from_be_bytes([*v0, *v1, *v2, *v3]),
+                }),
+                _ => Err(DecodeError(format!(
+                    "A reading is 6 bytes not {}",
+                    bytes.len()
+                ))),
+            }
+        }
+    }
+
+    type ReadingDag = Merkle<BTreeMap<Vec<u8>, Node<TestHasher>>, TestHasher, Reading>;
+
+    fn reading_dag() -> ReadingDag {
+        Merkle::new(BTreeMap::new()).with_payload_type()
+    }
+
+    #[test]
+    fn test_custom_payload_round_trips() {
+        let mut dag = reading_dag();
+        let first = Reading {
+            sensor: 7,
+            value: -40,
+        };
+        let second = Reading {
+            sensor: 7,
+            value: 21,
+        };
+        let first_id = dag.add_payload(&first, BTreeSet::new()).unwrap();
+        let second_id = dag
+            .add_payload(&second, BTreeSet::from([first_id.clone()]))
+            .unwrap();
+        assert_eq!(
+            dag.add_payload(&first.clone(), BTreeSet::new()).unwrap(),
+            first_id
+        );
+        assert_eq!(dag.get_roots(), &BTreeSet::from([second_id.clone()]));
+        assert_eq!(dag.get_item_by_id(&first_id).unwrap(), Some(first.clone()));
+        assert_eq!(dag.get_item_by_id(&second_id).unwrap(), Some(second));
+        let unknown = Node::<TestHasher>::new("unknown", BTreeSet::new());
+        assert_eq!(dag.get_item_by_id(unknown.id()).unwrap(), None);
+        // The item is the encoded payload so a byte DAG assigns the same id.
+        let mut bytes = TestDag::new(BTreeMap::new());
+        assert_eq!(
+            bytes
+                .add_node(vec![0, 7, 0xff, 0xff, 0xff, 0xd8], BTreeSet::new())
+                .unwrap(),
+            first_id
+        );
+    }
+
+    #[test]
+    fn test_custom_payload_decode_failure() {
+        let mut dag = reading_dag();
+        // Raw items can still be added but they don't decode as the payload type.
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        assert!(matches!(
+            dag.get_item_by_id(&quake),
+            Err(StoreError::StoreFailure(msg)) if msg.contains("6 bytes not 5")
+        ));
+    }
+
+    #[test]
+    fn test_with_payload_type_keeps_the_dag() {
+        let mut bytes = TestDag::new(BTreeMap::new());
+        let quake = bytes.add_node("quake", BTreeSet::new()).unwrap();
+        let qualm = bytes
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        let mut strings = bytes.with_payload_type::<String>();
+        assert_eq!(strings.get_roots(), &BTreeSet::from([qualm.clone()]));
+        assert_eq!(
+            strings.get_item_by_id(&quake).unwrap(),
+            Some("quake".to_owned())
+        );
+        let quell = strings
+            .add_payload(&"quell".to_owned(), BTreeSet::from([qualm]))
+            .unwrap();
+        let invalid = strings.add_node(vec![0xff, 0xfe], BTreeSet::new()).unwrap();
+        assert!(strings.get_item_by_id(&invalid).is_err());
+        let bytes = strings.with_payload_type::<Vec<u8>>();
+        assert_eq!(
+            bytes.get_item_by_id(&quell).unwrap(),
+            Some(b"quell".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_byte_encoders_agree() {
+        let expected = b"quake".to_vec();
+        assert_eq!(expected.encode_bytes().unwrap(), expected);
+        assert_eq!(expected.as_slice().encode_bytes().unwrap(), expected);
+        assert_eq!("quake".encode_bytes().unwrap(), expected);
+        assert_eq!("quake".to_owned().encode_bytes().unwrap(), expected);
+        assert_eq!(Vec::<u8>::decode_bytes(&expected).unwrap(), expected);
+        assert_eq!(String::decode_bytes(&expected).unwrap(), "quake");
+        let mut dag = TestDag::new(BTreeMap::new());
+        let quake = dag.add_payload(&expected, BTreeSet::new()).unwrap();
+        assert_eq!(quake, dag.add_node("quake", BTreeSet::new()).unwrap());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_cbor_payloads() {
+        #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+        struct Measurement {
+            units: String,
+            samples: BTreeMap<String, f64>,
+        }
+
+        let mut dag = TestDag::new(BTreeMap::new()).with_payload_type::<Cbor<Measurement>>();
+        let measurement = Measurement {
+            units: "celsius".to_owned(),
+            samples: BTreeMap::from([("b".to_owned(), 1.5), ("aa".to_owned(), -3.0)]),
+        };
+        let id = dag
+            .add_payload(&Cbor(measurement.clone()), BTreeSet::new())
+            .unwrap();
+        assert_eq!(
+            dag.get_item_by_id(&id).unwrap(),
+            Some(Cbor(measurement.clone()))
+        );
+        let node = dag.get_node_by_id(&id).unwrap().unwrap();
+        assert_eq!(
+            node.item(),
+            crate::store::codec::encode_item(&measurement)
+                .unwrap()
+                .as_slice()
+        );
+        assert_eq!(node.item_as::<Measurement>().unwrap(), measurement);
+    }
+}
+
+mod node_id_tests {
+    use super::TestDag;
+    use crate::prelude::*;
+    use crate::store::StoreError;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn test_node_id_parse_print_round_trip() {
+        let id = NodeId::from(vec![0x00, 0x1f, 0xab, 0xff]);
+        assert_eq!(id.to_string(), "001fabff");
+        assert_eq!(format!("{:x}", id), "001fabff");
+        assert_eq!(format!("{:?}", id), "NodeId(001fabff)");
+        assert_eq!("001fabff".parse::<NodeId>().unwrap(), id);
+        assert_eq!("001FABFF".parse::<NodeId>().unwrap(), id);
+        assert_eq!(id.to_string().parse::<NodeId>().unwrap(), id);
+        assert_eq!("".parse::<NodeId>().unwrap(), NodeId::default());
+        assert_eq!(Vec::from(id.clone()), vec![0x00, 0x1f, 0xab, 0xff]);
+    }
+
+    #[test]
+    fn test_node_id_rejects_invalid_hex() {
+        for bad in ["abc", "zz", "+f", "0x1f", "ab cd"] {
+            assert!(bad.parse::<NodeId>().is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn test_node_id_dependencies_in_btree_set() {
+        let mut dag = TestDag::new(BTreeMap::new());
+        let left = dag.add("left", BTreeSet::new()).unwrap();
+        let right = dag.add("right", BTreeSet::new()).unwrap();
+        let deps = BTreeSet::from([left.clone(), right.clone()]);
+        let merged = dag.add("merged", deps.clone()).unwrap();
+
+        let node = dag.get(&merged).unwrap().unwrap();
+        assert_eq!(node.node_id(), merged);
+        assert_eq!(node.dependency_node_ids(), deps);
+        assert_eq!(dag.root_ids(), BTreeSet::from([merged.clone()]));
+        // The slice based methods take a NodeId and agree with the NodeId based ones.
+        assert_eq!(dag.compare(&left, &merged).unwrap(), NodeCompare::Before);
+        assert_eq!(
+            dag.get_node_by_id(&merged).unwrap().unwrap().id(),
+            node.id()
+        );
+        let same = dag
+            .add_node("merged", BTreeSet::from([left.to_vec(), right.to_vec()]))
+            .unwrap();
+        assert_eq!(merged, same);
+        // Borrowing as a slice allows lookups by the raw id bytes.
+        assert!(deps.contains(left.as_bytes()));
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_node_id_serializes_as_bytes() {
+        let id = NodeId::from(vec![1, 2, 3]);
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&id, &mut buf).unwrap();
+        assert_eq!(buf, vec![0x43, 1, 2, 3]);
+        let decoded: NodeId = ciborium::de::from_reader(buf.as_slice()).unwrap();
+        assert_eq!(decoded, id);
+        // Ids serialized as a Vec<u8> decode too.
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&vec![1u8, 2, 3], &mut buf).unwrap();
+        let decoded: NodeId = ciborium::de::from_reader(buf.as_slice()).unwrap();
+        assert_eq!(decoded, id);
+    }
+
+    #[test]
+    fn test_store_error_displays_hex_ids() {
+        assert_eq!(
+            StoreError::NoSuchNode(vec![0xab, 0x01]).to_string(),
+            "no such node ab01"
+        );
+        let err = StoreError::AmbiguousPrefix {
+            prefix: vec![0xab],
+            candidates: vec![vec![0xab, 0x01], vec![0xab, 0x02]],
+        };
+        assert_eq!(
+            err.to_string(),
+            "ambiguous id prefix ab matches [ab01, ab02]"
+        );
+        let mut dag = TestDag::new(BTreeMap::new());
+        let id = dag.add("leaf", BTreeSet::new()).unwrap();
+        let err = dag.get_node_by_id(&id[1..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "malformed id of {} bytes, expected {}",
+                id.len() - 1,
+                id.len()
+            )
+        );
+    }
+}
+
+#[cfg(feature = "signatures")]
+mod signature_tests {
+    use super::{TestDag, TestHasher};
+    use crate::prelude::*;
+    use crate::store::{StoreError, StoreErrorKind};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn invalid_reason<T: std::fmt::Debug>(result: crate::store::Result<T>) -> String {
+        match result {
+            Err(StoreError::InvalidSignature { reason, .. }) => reason,
+            other => panic!("expected an invalid signature, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let alice = key(1);
+        let node = Node::<TestHasher>::new("quake", BTreeSet::new());
+        let id = node.id().to_vec();
+        assert_eq!(invalid_reason(node.verify_signature()), "unsigned");
+        let node = node.sign(&alice);
+        // Signing doesn't change the id.
+        assert_eq!(node.id(), id.as_slice());
+        assert_eq!(node.verify_signature().unwrap(), alice.verifying_key());
+        assert_eq!(
+            node.signature().unwrap().author,
+            alice.verifying_key().to_bytes().to_vec()
+        );
+        // Signing again replaces the signature.
+        let bob = key(2);
+        assert_eq!(
+            node.sign(&bob).verify_signature().unwrap(),
+            bob.verifying_key()
+        );
+    }
+
+    #[test]
+    fn test_tampered_signatures_are_rejected() {
+        let alice = key(1);
+        let signed = Node::<TestHasher>::new("quake", BTreeSet::new()).sign(&alice);
+        let signature = signed.signature().unwrap().clone();
+
+        // The signature of one node doesn't verify another.
+        let other =
+            Node::<TestHasher>::new("qualm", BTreeSet::new()).with_signature(signature.clone());
+        assert_eq!(
+            invalid_reason(other.verify_signature()),
+            "signature doesn't verify"
+        );
+
+        let mut flipped = signature.clone();
+        flipped.signature[0] ^= 1;
+        let node = signed.clone().with_signature(flipped);
+        assert_eq!(
+            invalid_reason(node.verify_signature()),
+            "signature doesn't verify"
+        );
+
+        let mut wrong_author = signature.clone();
+        wrong_author.author = key(2).verifying_key().to_bytes().to_vec();
+        let node = signed.clone().with_signature(wrong_author);
+        assert_eq!(
+            invalid_reason(node.verify_signature()),
+            "signature doesn't verify"
+        );
+
+        let mut short = signature.clone();
+        short.signature.truncate(10);
+        let node = signed.clone().with_signature(short);
+        assert_eq!(
+            invalid_reason(node.verify_signature()),
+            "malformed signature"
+        );
+
+        let mut short_author = signature;
+        short_author.author.truncate(10);
+        let node = signed.with_signature(short_author);
+        assert_eq!(
+            invalid_reason(node.verify_signature()),
+            "malformed author key"
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn test_signature_round_trips_through_cbor() {
+        use ciborium::{de::from_reader, ser::into_writer};
+
+        let node = Node::<TestHasher>::new("quake", BTreeSet::new()).sign(&key(1));
+        let mut buf = Vec::new();
+        into_writer(&node, &mut buf).unwrap();
+        let decoded: Node<TestHasher> = from_reader(buf.as_slice()).unwrap();
+        assert_eq!(decoded.signature(), node.signature());
+        assert_eq!(decoded.verify_signature().unwrap(), key(1).verifying_key());
+        // Unsigned nodes keep the encoding they had before signatures.
+        let mut buf = Vec::new();
+        into_writer(&Node::<TestHasher>::new("quake", BTreeSet::new()), &mut buf).unwrap();
+        assert!(!buf.windows(9).any(|w| w == b"signature"));
+    }
+
+    #[test]
+    fn test_accept_all_policy_is_the_default() {
+        let mut dag = TestDag::new(BTreeMap::new());
+        assert_eq!(dag.signature_policy(), &SignaturePolicy::AcceptAll);
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let forged = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.clone()]))
+            .with_signature(NodeSignature {
+                author: vec![1; 32],
+                signature: vec![2; 64],
+            });
+        dag.add_nodes(vec![forged]).unwrap();
+        let qualm = dag
+            .add_signed_node("quell", BTreeSet::from([quake]), &key(1))
+            .unwrap();
+        let node = dag.get_node_by_id(&qualm).unwrap().unwrap();
+        assert_eq!(node.verify_signature().unwrap(), key(1).verifying_key());
+    }
+
+    #[test]
+    fn test_require_valid_signature_policy() {
+        let mut dag = TestDag::new(BTreeMap::new());
+        dag.set_signature_policy(SignaturePolicy::RequireValidSignature);
+        let err = dag.add_node("quake", BTreeSet::new()).unwrap_err();
+        assert_eq!(err.kind(), StoreErrorKind::InvalidSignature);
+        assert_eq!(dag.node_count().unwrap(), 0);
+        assert!(dag.get_roots().is_empty());
+
+        let quake = dag
+            .add_signed_node("quake", BTreeSet::new(), &key(1))
+            .unwrap();
+        let qualm = dag
+            .add_signed_node("qualm", BTreeSet::from([quake.clone()]), &key(2))
+            .unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm.clone()]));
+
+        let mut tampered = Node::<TestHasher>::new("quell", BTreeSet::from([qualm]))
+            .sign(&key(1))
+            .signature()
+            .unwrap()
+            .clone();
+        tampered.signature[5] ^= 0xff;
+        let node =
+            Node::<TestHasher>::new("quell", BTreeSet::from([quake])).with_signature(tampered);
+        assert_eq!(
+            invalid_reason(dag.add_nodes(vec![node])),
+            "signature doesn't verify"
+        );
+        assert_eq!(dag.node_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_require_known_author_policy() {
+        let (alice, mallory) = (key(1), key(2));
+        let mut dag = TestDag::new(BTreeMap::new());
+        dag.set_signature_policy(SignaturePolicy::known_authors([alice.verifying_key()]));
+        let quake = dag
+            .add_signed_node("quake", BTreeSet::new(), &alice)
+            .unwrap();
+        let result = dag.add_signed_node("qualm", BTreeSet::from([quake.clone()]), &mallory);
+        assert_eq!(
+            invalid_reason(result),
+            format!(
+                "unknown author {}",
+                crate::id::hex(mallory.verifying_key().as_bytes())
+            )
+        );
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quake.clone()]));
+        // Nodes already in the DAG aren't checked again.
+        dag.set_signature_policy(SignaturePolicy::known_authors([mallory.verifying_key()]));
+        assert_eq!(
+            dag.add_signed_node("quake", BTreeSet::new(), &alice)
+                .unwrap(),
+            quake
+        );
+    }
+
+    #[test]
+    fn test_policy_applies_to_transactions() {
+        let mut dag = TestDag::new(BTreeMap::new());
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        dag.set_signature_policy(SignaturePolicy::RequireValidSignature);
+        let err = dag
+            .transaction(|txn| txn.add_node("qualm", BTreeSet::from([quake.clone()])))
+            .unwrap_err();
+        assert_eq!(err.kind(), StoreErrorKind::InvalidSignature);
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quake]));
+        assert_eq!(dag.node_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_policy_applies_to_bulk_load() {
+        let alice = key(1);
+        let quake = Node::<TestHasher>::new("quake", BTreeSet::new()).sign(&alice);
+        let unsigned = Node::<TestHasher>::new("qualm", BTreeSet::from([quake.id().to_vec()]));
+        let dependent =
+            Node::<TestHasher>::new("quell", BTreeSet::from([unsigned.id().to_vec()])).sign(&alice);
+        let mut dag = TestDag::new(BTreeMap::new());
+        dag.set_signature_policy(SignaturePolicy::RequireValidSignature);
+        let failure = dag
+            .bulk_load(
+                vec![quake.clone(), unsigned.clone(), dependent]
+                    .into_iter()
+                    .map(Ok),
+                BulkLoadOpts::default(),
+            )
+            .unwrap_err();
+        // The rejected node isn't loaded so the node depending on it is missing a dependency.
+        assert_eq!(
+            failure
+                .errors
+                .iter()
+                .map(|e| (e.index, e.kind))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, StoreErrorKind::InvalidSignature),
+                (2, StoreErrorKind::NoSuchNode),
+            ]
+        );
+        assert!(dag.check_for_node(quake.id()).unwrap());
+        assert!(!dag.check_for_node(unsigned.id()).unwrap());
+    }
+}
+
+mod siphash_tests {
+    use super::{hex, TestHasher, TestKey};
+    use crate::prelude::*;
+    use std::collections::{BTreeSet, HashSet};
+
+    // The key of the SipHash paper's test vectors followed by zeros so it folds to itself.
+    struct Reference;
+
+    impl HashKey for Reference {
+        fn key() -> [u8; 32] {
+            let mut key = [0; 32];
+            for (idx, byte) in key[..16].iter_mut().enumerate() {
+                *byte = idx as u8;
+            }
+            key
+        }
+    }
+
+    // The same folded key as TestKey from a different 32 byte key.
+    struct FoldsToTestKey;
+
+    impl HashKey for FoldsToTestKey {
+        fn key() -> [u8; 32] {
+            let test_key = TestKey::key();
+            let mut key = [0; 32];
+            for idx in 0..16 {
+                key[idx] = test_key[idx] ^ test_key[idx + 16];
+            }
+            key
+        }
+    }
+
+    struct Elvish;
+
+    impl HashKey for Elvish {
+        fn key() -> [u8; 32] {
+            *b"whats the Elvish word for friend"
+        }
+    }
+
+    #[test]
+    fn test_siphash24_matches_reference_vectors() {
+        let vectors = [
+            (0, "310e0edd47db6f72"),
+            (1, "fd67dc93c539f874"),
+            (7, "37d1018bf50002ab"),
+            (8, "6224939a79f5f593"),
+            (15, "e545be4961ca29a1"),
+            (63, "724506eb4c328a95"),
+        ];
+        for (len, expected) in vectors {
+            let mut hasher = SipHash24::<Reference>::default();
+            hasher.record_bytes(&(0..len).collect::<Vec<u8>>());
+            assert_eq!(hex(&hasher.hash()), expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn test_siphash24_golden_ids() {
+        assert_eq!(hex(&TestHasher::default().hash()), "2efe98fe0c64c184");
+        let quake =
+            Node::<TestHasher>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+        let qualm = Node::<TestHasher>::new_with_id_version(
+            "qualm",
+            BTreeSet::from([quake.id().to_vec()]),
+            NodeIdVersion::V0,
+        );
+        assert_eq!(hex(quake.id()), "6552b4d9743f015f");
+        assert_eq!(hex(qualm.id()), "766e371cb6e4948d");
+        let folded = Node::<SipHash24<FoldsToTestKey>>::new_with_id_version(
+            "quake",
+            BTreeSet::new(),
+            NodeIdVersion::V0,
+        );
+        assert_eq!(folded.id(), quake.id());
+    }
+
+    #[test]
+    fn test_siphash24_keys_assign_different_ids() {
+        let mut ids = HashSet::new();
+        for payload in ["quake", "qualm", "quell"] {
+            assert!(ids.insert(
+                Node::<TestHasher>::new(payload, BTreeSet::new())
+                    .id()
+                    .to_vec()
+            ));
+            assert!(ids.insert(
+                Node::<SipHash24<Elvish>>::new(payload, BTreeSet::new())
+                    .id()
+                    .to_vec()
+            ));
+        }
+    }
+
+    // Every node id and item id of the corpus is distinct. Distinct 8 byte ids for a few
+    // hundred thousand inputs is expected of any hash that mixes its input well.
+    fn check_no_collisions<HW: HashWriter>() {
+        let mut ids = HashSet::new();
+        let mut item_ids = HashSet::new();
+        let mut insert = |node: Node<HW>| {
+            assert!(ids.insert(node.id().to_vec()), "{:?}", node.item());
+            assert!(
+                item_ids.insert(node.item_id().to_vec()),
+                "{:?}",
+                node.item()
+            );
+        };
+        for idx in 0..100_000u32 {
+            insert(Node::<HW>::new(format!("payload {}", idx), BTreeSet::new()));
+        }
+        // Payloads a single bit apart.
+        let base = [0xa5u8; 64];
+        for bit in 0..base.len() * 8 {
+            let mut flipped = base;
+            flipped[bit / 8] ^= 1 << (bit % 8);
+            insert(Node::<HW>::new(flipped.to_vec(), BTreeSet::new()));
+        }
+        // Counters that only differ in a few low bytes.
+        for idx in 0..100_000u64 {
+            insert(Node::<HW>::new(idx.to_le_bytes().to_vec(), BTreeSet::new()));
+        }
+        // The same payload over different dependencies. Only the node ids differ here.
+        let mut ids = HashSet::new();
+        let deps: Vec<Vec<u8>> = (0..1000u32)
+            .map(|idx| {
+                Node::<HW>::new(idx.to_be_bytes().to_vec(), BTreeSet::new())
+                    .id()
+                    .to_vec()
+            })
+            .collect();
+        for (idx, dep) in deps.iter().enumerate() {
+            let single = Node::<HW>::new("merge", BTreeSet::from([dep.clone()]));
+            assert!(ids.insert(single.id().to_vec()));
+            let pair = Node::<HW>::new(
+                "merge",
+                BTreeSet::from([dep.clone(), deps[(idx + 1) % deps.len()].clone()]),
+            );
+            assert!(ids.insert(pair.id().to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_siphash24_has_no_collisions_in_corpus() {
+        check_no_collisions::<TestHasher>();
+        check_no_collisions::<SipHash24<Elvish>>();
+    }
+
+    #[test]
+    fn test_siphash24_dag() {
+        let mut dag = Merkle::<BTreeStore<SipHash24<Elvish>>, SipHash24<Elvish>>::default();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([qualm.clone()]));
+        assert_eq!(dag.compare(&qualm, &quake).unwrap(), NodeCompare::After);
+        let node = dag.get_node_by_id(&qualm).unwrap().unwrap();
+        assert_eq!(node.item(), b"qualm");
+    }
+}
+
+#[cfg(feature = "blake2")]
+mod keyed_blake2_tests {
+    use super::{check_record_paths_agree, hex};
+    use crate::blake2::{Blake2b512, KeyedBlake2b};
+    use crate::prelude::*;
+    use std::collections::BTreeSet;
+
+    struct Elvish;
+
+    impl HashKey for Elvish {
+        fn key() -> [u8; 32] {
+            *b"whats the Elvish word for friend"
+        }
+    }
+
+    struct Dwarvish;
+
+    impl HashKey for Dwarvish {
+        fn key() -> [u8; 32] {
+            *b"whats the Dwarvish word for axe!"
+        }
+    }
+
+    #[test]
+    fn test_keyed_blake2b_golden_ids() {
+        assert_eq!(
+            hex(&KeyedBlake2b::<Elvish>::default().hash()),
+            "624b0344827d3db5558b42b2751b11b13abe2e8a629fd01ecbf0c81679879d76\
+             767bf746a9031a6c4236c0c6aab990cf28f5054bd16db2ea145cf076487ec3e2"
+        );
+        let quake = Node::<KeyedBlake2b<Elvish>>::new_with_id_version(
+            "quake",
+            BTreeSet::new(),
+            NodeIdVersion::V0,
+        );
+        let qualm = Node::<KeyedBlake2b<Elvish>>::new_with_id_version(
+            "qualm",
+            BTreeSet::from([quake.id().to_vec()]),
+            NodeIdVersion::V0,
+        );
+        assert_eq!(
+            hex(quake.id()),
+            "4d6528ed8e574a08ba1ab645df5e7f32594721e22205801e9344ffba2dfeb763\
+             cbb06b33b0ff7206367b2a7f0b011a49c55f09d34b88023d4e3a2701c8502f25"
+        );
+        assert_eq!(
+            hex(qualm.id()),
+            "c7d56a145866588a554f493759e9f589e00b2c70bbb59956acd43f32eb8e0f85\
+             8670a702b09e694d6f544085e4ece02e57997fe4cbabe24be70befd285712491"
+        );
+    }
+
+    #[test]
+    fn test_keyed_blake2b_record_paths_agree() {
+        check_record_paths_agree::<KeyedBlake2b<Elvish>>();
+    }
+
+    fn build<HW: HashWriter>() -> Merkle<BTreeStore<HW>, HW> {
+        let mut dag = Merkle::<BTreeStore<HW>, HW>::default();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        dag.add_node("qualm", BTreeSet::from([quake])).unwrap();
+        dag
+    }
+
+    #[test]
+    fn test_keyed_blake2b_dags_assign_different_ids() {
+        let plain = build::<Blake2b512>();
+        let elvish = build::<KeyedBlake2b<Elvish>>();
+        let dwarvish = build::<KeyedBlake2b<Dwarvish>>();
+        assert_ne!(plain.get_roots(), elvish.get_roots());
+        assert_ne!(plain.get_roots(), dwarvish.get_roots());
+        assert_ne!(elvish.get_roots(), dwarvish.get_roots());
+        // The unkeyed path still hashes the plain item bytes.
+        let quake =
+            Node::<Blake2b512>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+        let mut hasher = Blake2b512::default();
+        hasher.record(b"quake".iter().cloned());
+        assert_eq!(quake.id(), hasher.hash().as_slice());
+        // A keyed DAG finds its own nodes again.
+        let root = elvish.get_roots().iter().next().unwrap();
+        let node = elvish.get_node_by_id(root).unwrap().unwrap();
+        assert_eq!(node.item(), b"qualm");
+        assert_eq!(node.id(), root.as_slice());
+    }
+}
+
+#[cfg(feature = "blake2")]
+mod blake2_var_tests {
+    use super::{check_record_paths_agree, hex};
+    use crate::blake2::{Blake2b512, Blake2bN};
+    use crate::prelude::*;
+    use crate::store::StoreError;
+    use std::collections::BTreeSet;
+
+    fn check_golden_ids<const N: usize>(empty: &str, quake: &str, qualm: &str) {
+        assert_eq!(hex(&Blake2bN::<N>::default().hash()), empty);
+        let quake_node =
+            Node::<Blake2bN<N>>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+        let qualm_node = Node::<Blake2bN<N>>::new_with_id_version(
+            "qualm",
+            BTreeSet::from([quake_node.id().to_vec()]),
+            NodeIdVersion::V0,
+        );
+        assert_eq!(hex(quake_node.id()), quake);
+        assert_eq!(hex(qualm_node.id()), qualm);
+        assert_eq!(qualm_node.id().len(), N);
+        assert_eq!(qualm_node.item_id().len(), N);
+    }
+
+    #[test]
+    fn test_blake2b_n_golden_ids() {
+        check_golden_ids::<20>(
+            "3345524abf6bbe1809449224b5972c41790b6cf2",
+            "3338631fa8402f1d3111d16f83d4cd1a06308eac",
+            "352aa543ce16dd52e028d63ef7ab8f0ad85d3e34",
+        );
+        check_golden_ids::<32>(
+            "0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8",
+            "3298bb6acff0bb4a29ab63de46c081c6224e99dfa31323849cfcd5cde57bc6da",
+            "6edfc1c48a86d78a23340261cccb03ee8f4d73746f5ac54acd6c9fcfc4a3bd73",
+        );
+        check_golden_ids::<48>(
+            "b32811423377f52d7862286ee1a72ee540524380fda1724a\
+             6f25d7978c6fd3244a6caf0498812673c5e05ef583825100",
+            "859a2a9efbe094af44598504aec0577cecdd887cd125d7ab\
+             ab48a506a884d51865164bf3139189af596c2ab62c68a096",
+            "fc171f50bb609b5aaddfe4e95e01d82cadb6a2c23f912cee\
+             00e5598e3089fd44df11d3a0d39868deedaef73df0ca642e",
+        );
+    }
+
+    #[test]
+    fn test_blake2b_n_full_length_matches_blake2b512() {
+        let quake = Node::<Blake2b512>::new("quake", BTreeSet::new());
+        let quake_n = Node::<Blake2bN<64>>::new("quake", BTreeSet::new());
+        assert_eq!(quake.id(), quake_n.id());
+    }
+
+    #[test]
+    fn test_blake2b_n_record_paths_agree() {
+        check_record_paths_agree::<Blake2bN<20>>();
+        check_record_paths_agree::<Blake2bN<32>>();
+        check_record_paths_agree::<Blake2bN<48>>();
+    }
+
+    #[test]
+    fn test_blake2b_32_dag_round_trip() {
+        let mut dag = Merkle::<BTreeStore<Blake2bN<32>>, Blake2bN<32>>::default();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        let qualm = dag
+            .add_node("qualm", BTreeSet::from([quake.clone()]))
+            .unwrap();
+        let quell = dag
+            .add_node("quell", BTreeSet::from([quake.clone(), qualm.clone()]))
+            .unwrap();
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quell.clone()]));
+        for (id, item) in [(&quake, "quake"), (&qualm, "qualm"), (&quell, "quell")] {
+            assert_eq!(id.len(), 32);
+            let node = dag.get_node_by_id(id).unwrap().unwrap();
+            assert_eq!(node.id(), id.as_slice());
+            assert_eq!(node.item(), item.as_bytes());
+        }
+        assert_eq!(dag.compare(&quell, &quake).unwrap(), NodeCompare::After);
+        #[cfg(feature = "cbor")]
+        {
+            let node = dag.get_node_by_id(&quell).unwrap().unwrap();
+            let mut encoded = Vec::new();
+            ciborium::ser::into_writer(&node, &mut encoded).unwrap();
+            let decoded: Node<Blake2bN<32>> =
+                ciborium::de::from_reader(encoded.as_slice()).unwrap();
+            assert_eq!(decoded.id(), quell.as_slice());
+            assert_eq!(decoded.dependency_ids(), node.dependency_ids());
+        }
+    }
+
+    #[test]
+    fn test_blake2b_n_dags_reject_ids_of_other_lengths() {
+        let short = Node::<Blake2bN<20>>::new("quake", BTreeSet::new());
+        let long = Node::<Blake2bN<48>>::new("quake", BTreeSet::new());
+        let mut dag = Merkle::<BTreeStore<Blake2bN<32>>, Blake2bN<32>>::default();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        for foreign in [short.id(), long.id()] {
+            let malformed = |result: crate::store::Result<()>| {
+                matches!(
+                    result,
+                    Err(StoreError::MalformedId { expected: 32, actual })
+                        if actual == foreign.len()
+                )
+            };
+            assert!(malformed(dag.get_node_by_id(foreign).map(|_| ())));
+            assert!(malformed(
+                dag.add_node("qualm", BTreeSet::from([foreign.to_vec()]))
+                    .map(|_| ())
+            ));
+        }
+        assert_eq!(dag.get_roots(), &BTreeSet::from([quake]));
+        #[cfg(feature = "cbor")]
+        {
+            // A node written by a DAG with shorter ids can't be read as one with 32 byte ids.
+            let qualm = Node::<Blake2bN<20>>::new("qualm", BTreeSet::from([short.id().to_vec()]));
+            let mut encoded = Vec::new();
+            ciborium::ser::into_writer(&qualm, &mut encoded).unwrap();
+            let decoded: Result<Node<Blake2bN<32>>, _> =
+                ciborium::de::from_reader(encoded.as_slice());
+            assert!(decoded.is_err());
+        }
+    }
+}
+
+#[cfg(feature = "blake3")]
+mod blake3_tests {
+    use super::hex;
+    use crate::blake3::{Blake3, DerivedBlake3, KeyContext, KeyedBlake3};
+    use crate::prelude::*;
+    use std::collections::BTreeSet;
+
+    struct Elvish;
+
+    impl HashKey for Elvish {
+        fn key() -> [u8; 32] {
+            *b"whats the Elvish word for friend"
+        }
+    }
+
+    struct Dwarvish;
+
+    impl HashKey for Dwarvish {
+        fn key() -> [u8; 32] {
+            *b"whats the Dwarvish word for axe!"
+        }
+    }
+
+    struct TestVectors;
+
+    impl KeyContext for TestVectors {
+        const CONTEXT: &'static str = "BLAKE3 2019-12-27 16:29:52 test vectors context";
+    }
+
+    #[test]
+    fn test_blake3_modes_match_reference_vectors() {
+        // The empty input vectors of the BLAKE3 reference test suite.
+        assert_eq!(
+            hex(&Blake3::default().hash()),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            hex(&KeyedBlake3::<Elvish>::default().hash()),
+            "92b2b75604ed3c761f9d6f62392c8a9227ad0ea3f09573e783f1498a4ed60d26"
+        );
+        assert_eq!(
+            hex(&DerivedBlake3::<TestVectors>::default().hash()),
+            "2cc39783c223154fea8dfb7c1b1660f2ac2dcbd1c1de8277b0b0dd39b7e50d7d"
+        );
+        assert_eq!(
+            hex(blake3::keyed_hash(&Elvish::key(), b"").as_bytes()),
+            hex(&KeyedBlake3::<Elvish>::default().hash())
+        );
+        assert_eq!(
+            hex(&Blake3::keyed(&Elvish::key()).hash()),
+            hex(&KeyedBlake3::<Elvish>::default().hash())
+        );
+        assert_eq!(
+            hex(&Blake3::derive_key(TestVectors::CONTEXT).hash()),
+            hex(&DerivedBlake3::<TestVectors>::default().hash())
+        );
+    }
+
+    #[test]
+    fn test_blake3_golden_ids() {
+        let quake =
+            Node::<Blake3>::new_with_id_version("quake", BTreeSet::new(), NodeIdVersion::V0);
+        let shake =
+            Node::<Blake3>::new_with_id_version("shake", BTreeSet::new(), NodeIdVersion::V0);
+        let qualm = Node::<Blake3>::new_with_id_version(
+            "qualm",
+            BTreeSet::from([quake.id().to_vec()]),
+            NodeIdVersion::V0,
+        );
+        let quell = Node::<Blake3>::new_with_id_version(
+            "quell",
+            BTreeSet::from([shake.id().to_vec(), quake.id().to_vec()]),
+            NodeIdVersion::V0,
+        );
+        assert_eq!(
+            [&quake, &shake, &qualm, &quell].map(|node| hex(node.id())),
+            [
+                "45ac3495362574ac10ba36e694616ca6a28d68d3610c8caef7e8d6568b0f224a",
+                "10e5cf9dc81f56c6073f93c566df315e7f66fdaba705e35282d91bd7fd5743da",
+                "ca4dd71bdd289ddc505f30eec4f51558056dd0f88502407d9ff343d548e16c71",
+                "99f86ed2284a3a0393d920f8785c2602acd4010bb7b888ea8b67c64e84fa8f61",
+            ]
+            .map(str::to_owned)
+        );
+    }
+
+    fn build<HW: HashWriter>() -> Merkle<BTreeStore<HW>, HW> {
+        let mut dag = Merkle::<BTreeStore<HW>, HW>::default();
+        let quake = dag.add_node("quake", BTreeSet::new()).unwrap();
+        dag.add_node("qualm", BTreeSet::from([quake])).unwrap();
+        dag
+    }
+
+    #[test]
+    fn test_keyed_blake3_dags_assign_different_ids() {
+        let plain = build::<Blake3>();
+        let elvish = build::<KeyedBlake3<Elvish>>();
+        let dwarvish = build::<KeyedBlake3<Dwarvish>>();
+        let derived = build::<DerivedBlake3<TestVectors>>();
+        let roots = [
+            plain.get_roots(),
+            elvish.get_roots(),
+            dwarvish.get_roots(),
+            derived.get_roots(),
+        ];
+        for (idx, left) in roots.iter().enumerate() {
+            assert_eq!(left.len(), 1);
+            for right in roots[idx + 1..].iter() {
+                assert_ne!(left, right);
+            }
+        }
+        // A keyed DAG finds its own nodes again.
+        let root = elvish.get_roots().iter().next().unwrap();
+        let node = elvish.get_node_by_id(root).unwrap().unwrap();
+        assert_eq!(node.item(), b"qualm");
+        assert_eq!(node.id(), root.as_slice());
+    }
+}
+
+mod conformance_tests {
+    mod btree_store {
+        crate::store_conformance_tests!(crate::store::BTreeStore::new);
+    }
+
+    mod hash_store {
+        crate::store_conformance_tests!(crate::store::HashStore::new);
+    }
+
+    #[cfg(feature = "dashmap")]
+    mod concurrent_memory_store {
+        crate::store_conformance_tests!(crate::store::ConcurrentMemoryStore::new);
+    }
+
+    mod bloom_store {
+        crate::store_conformance_tests!(|| crate::store::BloomStore::new(
+            crate::store::BTreeStore::new(),
+            1000,
+            0.01
+        )
+        .unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    mod sqlite_store {
+        crate::store_conformance_tests!(|| crate::sqlite::SqliteStore::in_memory().unwrap());
+    }
+
+    #[cfg(feature = "rusty-leveldb")]
+    mod level_store {
+        crate::store_conformance_tests!(crate::leveldb::LevelStore::default);
+    }
+
+    #[cfg(feature = "sled")]
+    mod sled_store {
+        crate::store_conformance_tests!(crate::sled::SledStore::default);
+    }
+
+    #[cfg(feature = "redb")]
+    mod redb_store {
+        crate::store_conformance_tests!(|| crate::redb::RedbStore::in_memory().unwrap());
+    }
+
+    #[cfg(feature = "cbor")]
+    mod fs_store {
+        crate::store_conformance_tests!(crate::test::fs_tests::temporary);
+    }
+
+    #[cfg(feature = "cbor")]
+    mod log_store {
+        crate::store_conformance_tests!(crate::test::log_tests::temporary);
+    }
+
+    #[cfg(feature = "cbor")]
+    mod verifying_store {
+        crate::store_conformance_tests!(|| crate::store::VerifyingStore::new(
+            crate::test::log_tests::temporary()
+        ));
+    }
+
+    #[cfg(feature = "lmdb")]
+    mod lmdb_store {
+        crate::store_conformance_tests!(crate::test::lmdb_tests::temporary);
+    }
+
+    #[cfg(feature = "rocksdb")]
+    mod rocks_store {
+        use crate::rocksdb::SingleThreadedRocksStore;
+        use crate::test::temp_path;
+
+        fn open() -> SingleThreadedRocksStore {
+            let mut opts = ::rocksdb::Options::default();
+            opts.create_if_missing(true);
+            SingleThreadedRocksStore::open_with_opts(temp_path("rocks-conformance").keep(), &opts)
+                .unwrap()
+        }
+
+        crate::store_conformance_tests!(open);
     }
 }
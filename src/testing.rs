@@ -0,0 +1,328 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! [Store] wrappers for testing how applications handle a misbehaving [Store].
+//! Requires the `testing` feature to be enabled.
+//!
+//! A [FlakyStore] fails the calls picked by a [FailureSchedule] and logs every call it gets
+//! so tests can assert on the calls a DAG made before and after a failure.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(feature = "cbor")]
+use crate::store::StoreStats;
+use crate::{
+    dag::{CachedValue, NodeHandle},
+    hash::HashWriter,
+    node::Node,
+    store::{PersistedRoots, Result, Store, StoreError},
+};
+
+/// Which calls of a [FlakyStore] fail.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum FailureSchedule {
+    /// Never fail.
+    Never,
+    /// Fail every nth call. 1 fails every call and 0 none.
+    EveryNth(usize),
+    /// Fail every call involving one of these ids.
+    OnIds(BTreeSet<Vec<u8>>),
+    /// Fail the first call and succeed after that.
+    Once,
+}
+
+/// A call made to a [FlakyStore].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct StoreCall {
+    /// The name of the [Store] method.
+    pub method: &'static str,
+    /// The ids the call was made with. Empty for calls without ids.
+    pub ids: Vec<Vec<u8>>,
+    /// Whether the call was failed by the [FailureSchedule].
+    pub failed: bool,
+}
+
+#[derive(Debug, Default)]
+struct FlakyState {
+    calls: Vec<StoreCall>,
+    scheduled: usize,
+    failed_once: bool,
+}
+
+/// A [Store] wrapper that fails calls on a [FailureSchedule] with a
+/// [StoreError::StoreFailure] before they reach the wrapped [Store]. Only calls to the
+/// [methods](FlakyStore::on_methods) the schedule is limited to count towards it. Every call
+/// is logged whether it failed or not.
+#[derive(Debug)]
+pub struct FlakyStore<S> {
+    inner: S,
+    schedule: FailureSchedule,
+    methods: Option<BTreeSet<&'static str>>,
+    state: Mutex<FlakyState>,
+}
+
+impl<S> FlakyStore<S> {
+    /// Wrap a [Store] that fails nothing until given a [FailureSchedule].
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            schedule: FailureSchedule::Never,
+            methods: None,
+            state: Mutex::new(FlakyState::default()),
+        }
+    }
+
+    /// Fail calls on the `schedule`.
+    pub fn with_schedule(mut self, schedule: FailureSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Limit the [FailureSchedule] to calls to these [Store] methods, like `"store"`.
+    pub fn on_methods<I>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = &'static str>,
+    {
+        self.methods = Some(methods.into_iter().collect());
+        self
+    }
+
+    /// Get a reference to the wrapped [Store].
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The calls made so far in order.
+    pub fn calls(&self) -> Vec<StoreCall> {
+        self.state().calls.clone()
+    }
+
+    /// Return the calls made so far clearing the log.
+    pub fn take_calls(&self) -> Vec<StoreCall> {
+        std::mem::take(&mut self.state().calls)
+    }
+
+    fn state(&self) -> MutexGuard<'_, FlakyState> {
+        self.state.lock().unwrap()
+    }
+
+    // Logs the call failing it if the schedule says so.
+    fn call(&self, method: &'static str, ids: &[&[u8]]) -> Result<()> {
+        let mut state = self.state();
+        let scheduled = self
+            .methods
+            .as_ref()
+            .is_none_or(|methods| methods.contains(method));
+        let failed = scheduled && {
+            state.scheduled += 1;
+            match &self.schedule {
+                FailureSchedule::Never => false,
+                FailureSchedule::EveryNth(n) => state.scheduled.is_multiple_of(*n),
+                FailureSchedule::OnIds(failing) => ids.iter().any(|id| failing.contains(*id)),
+                FailureSchedule::Once => !std::mem::replace(&mut state.failed_once, true),
+            }
+        };
+        state.calls.push(StoreCall {
+            method,
+            ids: ids.iter().map(|id| id.to_vec()).collect(),
+            failed,
+        });
+        if failed {
+            return Err(StoreError::StoreFailure(format!(
+                "Injected failure in {}",
+                method
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<HW, S> Store<HW> for FlakyStore<S>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        self.call("contains", &[id])?;
+        self.inner.contains(id)
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        self.call("get", &[id])?;
+        self.inner.get(id)
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.call("store", &[node.id()])?;
+        self.inner.store(node)
+    }
+
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        self.call("get_many", ids)?;
+        self.inner.get_many(ids)
+    }
+
+    fn contains_many(&self, ids: &[&[u8]]) -> Result<Vec<bool>> {
+        self.call("contains_many", ids)?;
+        self.inner.contains_many(ids)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn get_raw(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.call("get_raw", &[id])?;
+        self.inner.get_raw(id)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        self.call("store_encoded", &[node.id()])?;
+        self.inner.store_encoded(node, encoded)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        let ids: Vec<&[u8]> = records.iter().map(|(node, _)| node.id()).collect();
+        self.call("store_many_encoded", &ids)?;
+        self.inner.store_many_encoded(records, roots)
+    }
+
+    fn get_handle(&self, id: &[u8]) -> Result<Option<NodeHandle<HW>>> {
+        self.call("get_handle", &[id])?;
+        self.inner.get_handle(id)
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let ids: Vec<&[u8]> = nodes.iter().map(Node::id).collect();
+        self.call("store_many", &ids)?;
+        self.inner.store_many(nodes)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.call("store_with_roots", &[node.id()])?;
+        self.inner.store_with_roots(node, roots)
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let ids: Vec<&[u8]> = nodes.iter().map(Node::id).collect();
+        self.call("store_many_with_roots", &ids)?;
+        self.inner.store_many_with_roots(nodes, roots)
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.call("persist_roots", &[])?;
+        self.inner.persist_roots(roots)
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        self.call("persisted_roots", &[])?;
+        self.inner.persisted_roots()
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.call("delete", &[id])?;
+        self.inner.delete(id)
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        self.call("children_of", &[id])?;
+        self.inner.children_of(id)
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        self.call("ids", &[])?;
+        self.inner.ids()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.call("len", &[])?;
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.call("is_empty", &[])?;
+        self.inner.is_empty()
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.call("find_by_prefix", &[])?;
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn key_length_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        self.call("key_length_histogram", &[])?;
+        self.inner.key_length_histogram()
+    }
+
+    #[cfg(feature = "cbor")]
+    fn stats(&self) -> Result<StoreStats> {
+        self.call("stats", &[])?;
+        self.inner.stats()
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        self.call("quarantine_foreign_keys", &[])?;
+        self.inner.quarantine_foreign_keys(expected_len)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.call("get_quarantined", &[id])?;
+        self.inner.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.call("cached_closure_size", &[id])?;
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.call("refresh_closure_sizes", &[])?;
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.call("begin_batch", &[])?;
+        self.inner.begin_batch()
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.call("commit_batch", &[])?;
+        self.inner.commit_batch()
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.call("rollback_batch", &[])?;
+        self.inner.rollback_batch()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.call("flush", &[])?;
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,790 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Record and replay of [Store] interactions for reproducing bugs.
+//! Requires the `cbor` feature to be enabled.
+//!
+//! A [RecordingStore] streams every operation against the [Store] it wraps into a CBOR trace.
+//! Traces can be served back with a [ReplayStore] or used to rebuild the graph structure with
+//! [Merkle::from_trace]. Payloads are scrubbed from the trace unless explicitly requested.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dag::{CachedValue, Merkle},
+    hash::HashWriter,
+    node::{Node, NodeIdVersion, NodeSignature},
+    store::{PersistedRoots, Result, Store, StoreError},
+};
+
+/// The trace format name written into every [TraceHeader].
+pub const TRACE_FORMAT: &str = "merkle-dag-trace";
+/// The current trace format version. Traces of earlier versions can still be read.
+pub const TRACE_VERSION: u32 = 2;
+
+/// Translation from the ids recorded in a trace to the ids of a rebuilt DAG.
+pub type TraceIdMap = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Header written at the start of every trace.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TraceHeader {
+    pub format: String,
+    pub version: u32,
+}
+
+/// The [Store] operation a [TraceEntry] records.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TraceOp {
+    Contains,
+    Get,
+    Store,
+    ChildrenOf,
+    Delete,
+    Ids,
+    PersistRoots,
+    PersistedRoots,
+    BeginBatch,
+    CommitBatch,
+    RollbackBatch,
+}
+
+/// A [Node] as recorded in a trace. The `item` is None when payloads are scrubbed.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TracedNode {
+    pub id: Vec<u8>,
+    pub item_id: Vec<u8>,
+    pub item: Option<Vec<u8>>,
+    pub dependency_ids: BTreeSet<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "NodeIdVersion::is_v0")]
+    pub id_version: NodeIdVersion,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub attributes: BTreeMap<String, Vec<u8>>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub detached: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<NodeSignature>,
+}
+
+/// The result of a recorded [Store] operation.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum TraceResult {
+    Bool(bool),
+    Node(Option<TracedNode>),
+    Ids(BTreeSet<Vec<u8>>),
+    Error(String),
+    Done,
+    IdList(Vec<Vec<u8>>),
+    Roots(Option<PersistedRoots>),
+}
+
+/// A single recorded [Store] operation.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TraceEntry {
+    pub op: TraceOp,
+    pub key: Vec<u8>,
+    pub result: TraceResult,
+}
+
+impl TracedNode {
+    fn from_node<HW: HashWriter>(node: &Node<HW>, record_payloads: bool) -> Self {
+        Self {
+            id: node.id().to_vec(),
+            item_id: node.item_id().to_vec(),
+            item: if record_payloads {
+                Some(node.item().to_vec())
+            } else {
+                None
+            },
+            dependency_ids: node.dependency_ids().clone(),
+            id_version: node.id_version(),
+            attributes: node.attributes().clone(),
+            detached: node.is_detached(),
+            signature: node.signature().cloned(),
+        }
+    }
+}
+
+fn trace_error<E: std::fmt::Debug>(e: E) -> StoreError {
+    StoreError::StoreFailure(format!("Trace failure {:?}", e))
+}
+
+fn traced_result<T, F>(result: &Result<T>, f: F) -> TraceResult
+where
+    F: FnOnce(&T) -> TraceResult,
+{
+    match result {
+        Ok(value) => f(value),
+        Err(e) => TraceResult::Error(format!("{:?}", e)),
+    }
+}
+
+/// A [Store] wrapper that streams every operation into a trace.
+///
+/// Node reads and writes, deletes, id listings, roots and batches are recorded. Writes of
+/// several nodes are recorded as one [TraceOp::Store] per node followed by a
+/// [TraceOp::PersistRoots] when they replace the roots. Maintenance operations like
+/// [Store::quarantine_foreign_keys] and the closure size cache are forwarded without
+/// being recorded.
+///
+/// Entries are written as they happen so memory use while recording is bounded.
+pub struct RecordingStore<S, W: Write> {
+    inner: S,
+    writer: RefCell<W>,
+    record_payloads: bool,
+    recorded: Cell<usize>,
+}
+
+impl<S> RecordingStore<S, BufWriter<File>> {
+    /// Record operations against `inner` into a new trace file at `path`.
+    pub fn create<P: AsRef<Path>>(inner: S, path: P, record_payloads: bool) -> Result<Self> {
+        let file = File::create(path).map_err(trace_error)?;
+        Self::new(inner, BufWriter::new(file), record_payloads)
+    }
+}
+
+impl<S, W: Write> RecordingStore<S, W> {
+    /// Record operations against `inner` into the `writer`. Payloads are only recorded
+    /// when `record_payloads` is true.
+    pub fn new(inner: S, mut writer: W, record_payloads: bool) -> Result<Self> {
+        let header = TraceHeader {
+            format: TRACE_FORMAT.to_owned(),
+            version: TRACE_VERSION,
+        };
+        ciborium::ser::into_writer(&header, &mut writer).map_err(trace_error)?;
+        Ok(Self {
+            inner,
+            writer: RefCell::new(writer),
+            record_payloads,
+            recorded: Cell::new(0),
+        })
+    }
+
+    /// The number of operations recorded so far.
+    pub fn recorded(&self) -> usize {
+        self.recorded.get()
+    }
+
+    /// Flush any buffered trace entries to the underlying writer.
+    pub fn flush(&self) -> Result<()> {
+        self.writer.borrow_mut().flush().map_err(trace_error)
+    }
+
+    fn record(&self, op: TraceOp, key: &[u8], result: TraceResult) -> Result<()> {
+        let entry = TraceEntry {
+            op,
+            key: key.to_vec(),
+            result,
+        };
+        ciborium::ser::into_writer(&entry, &mut *self.writer.borrow_mut()).map_err(trace_error)?;
+        self.recorded.set(self.recorded.get() + 1);
+        Ok(())
+    }
+
+    // Records the outcome of a write of `traced` nodes that replaced the roots when given.
+    fn record_stores(
+        &self,
+        traced: Vec<TracedNode>,
+        roots: Option<&PersistedRoots>,
+        result: &Result<()>,
+    ) -> Result<()> {
+        for node in traced {
+            let id = node.id.clone();
+            self.record(
+                TraceOp::Store,
+                &id,
+                traced_result(result, |_| TraceResult::Node(Some(node))),
+            )?;
+        }
+        if let Some(roots) = roots {
+            self.record(
+                TraceOp::PersistRoots,
+                &[],
+                traced_result(result, |_| TraceResult::Roots(Some(roots.clone()))),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn traced<'a, HW, I>(&self, nodes: I) -> Vec<TracedNode>
+    where
+        HW: HashWriter + 'a,
+        I: IntoIterator<Item = &'a Node<HW>>,
+    {
+        nodes
+            .into_iter()
+            .map(|node| TracedNode::from_node(node, self.record_payloads))
+            .collect()
+    }
+}
+
+impl<HW, S, W> Store<HW> for RecordingStore<S, W>
+where
+    HW: HashWriter,
+    S: Store<HW>,
+    W: Write,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        let result = self.inner.contains(id);
+        self.record(
+            TraceOp::Contains,
+            id,
+            traced_result(&result, |b| TraceResult::Bool(*b)),
+        )?;
+        result
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        let result = self.inner.get(id);
+        self.record(
+            TraceOp::Get,
+            id,
+            traced_result(&result, |n| {
+                TraceResult::Node(
+                    n.as_ref()
+                        .map(|n| TracedNode::from_node(n, self.record_payloads)),
+                )
+            }),
+        )?;
+        result
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        let traced = self.traced([&node]);
+        let result = self.inner.store(node);
+        self.record_stores(traced, None, &result)?;
+        result
+    }
+
+    // Recorded as one TraceOp::Get per id, or a single failed one, like the default
+    // get_many a ReplayStore answers with.
+    fn get_many(&self, ids: &[&[u8]]) -> Result<Vec<Option<Node<HW>>>> {
+        let result = self.inner.get_many(ids);
+        match &result {
+            Ok(nodes) => {
+                for (id, node) in ids.iter().zip(nodes) {
+                    self.record(
+                        TraceOp::Get,
+                        id,
+                        TraceResult::Node(
+                            node.as_ref()
+                                .map(|n| TracedNode::from_node(n, self.record_payloads)),
+                        ),
+                    )?;
+                }
+            }
+            Err(e) => {
+                if let Some(id) = ids.first() {
+                    self.record(TraceOp::Get, id, TraceResult::Error(format!("{:?}", e)))?;
+                }
+            }
+        }
+        result
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, encoded: Vec<u8>) -> Result<()> {
+        let traced = self.traced([&node]);
+        let result = self.inner.store_encoded(node, encoded);
+        self.record_stores(traced, None, &result)?;
+        result
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        let traced = self.traced(records.iter().map(|(node, _)| node));
+        let result = self.inner.store_many_encoded(records, roots);
+        self.record_stores(traced, roots, &result)?;
+        result
+    }
+
+    fn store_many<I>(&mut self, nodes: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let traced = self.traced(&nodes);
+        let result = self.inner.store_many(nodes);
+        self.record_stores(traced, None, &result)?;
+        result
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        let traced = self.traced([&node]);
+        let result = self.inner.store_with_roots(node, roots);
+        self.record_stores(traced, Some(roots), &result)?;
+        result
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        let traced = self.traced(&nodes);
+        let result = self.inner.store_many_with_roots(nodes, roots);
+        self.record_stores(traced, Some(roots), &result)?;
+        result
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        let result = self.inner.persist_roots(roots);
+        self.record_stores(Vec::new(), Some(roots), &result)?;
+        result
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        let result = self.inner.persisted_roots();
+        self.record(
+            TraceOp::PersistedRoots,
+            &[],
+            traced_result(&result, |roots| TraceResult::Roots(roots.clone())),
+        )?;
+        result
+    }
+
+    fn check_hash_algorithm(&self) -> Result<()> {
+        self.inner.check_hash_algorithm()
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        let result = self.inner.delete(id);
+        self.record(
+            TraceOp::Delete,
+            id,
+            traced_result(&result, |_| TraceResult::Done),
+        )?;
+        result
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        let result = self.inner.children_of(id);
+        self.record(
+            TraceOp::ChildrenOf,
+            id,
+            traced_result(&result, |ids| TraceResult::Ids(ids.clone())),
+        )?;
+        result
+    }
+
+    // Lists every id up front so the listing can be recorded in a single entry.
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        let result = self
+            .inner
+            .ids()
+            .and_then(|ids| ids.collect::<Result<Vec<Vec<u8>>>>());
+        self.record(
+            TraceOp::Ids,
+            &[],
+            traced_result(&result, |ids| TraceResult::IdList(ids.clone())),
+        )?;
+        Ok(Box::new(result?.into_iter().map(Ok)))
+    }
+
+    fn find_by_prefix(&self, prefix: &[u8], limit: usize) -> Result<Vec<Vec<u8>>> {
+        self.inner.find_by_prefix(prefix, limit)
+    }
+
+    fn quarantine_foreign_keys(&mut self, expected_len: usize) -> Result<u64> {
+        self.inner.quarantine_foreign_keys(expected_len)
+    }
+
+    fn get_quarantined(&self, id: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_quarantined(id)
+    }
+
+    fn cached_closure_size(&self, id: &[u8]) -> Result<CachedValue<u64>> {
+        self.inner.cached_closure_size(id)
+    }
+
+    fn refresh_closure_sizes(&mut self, batch: usize) -> Result<usize> {
+        self.inner.refresh_closure_sizes(batch)
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        let result = self.inner.begin_batch();
+        self.record(
+            TraceOp::BeginBatch,
+            &[],
+            traced_result(&result, |_| TraceResult::Done),
+        )?;
+        result
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        let result = self.inner.commit_batch();
+        self.record(
+            TraceOp::CommitBatch,
+            &[],
+            traced_result(&result, |_| TraceResult::Done),
+        )?;
+        result
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        let result = self.inner.rollback_batch();
+        self.record(
+            TraceOp::RollbackBatch,
+            &[],
+            traced_result(&result, |_| TraceResult::Done),
+        )?;
+        result
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read all the [TraceEntry] records from a trace.
+pub fn read_trace<R: Read>(reader: R) -> Result<Vec<TraceEntry>> {
+    let mut reader = BufReader::new(reader);
+    let header: TraceHeader = ciborium::de::from_reader(&mut reader).map_err(trace_error)?;
+    if header.format != TRACE_FORMAT || header.version > TRACE_VERSION {
+        return Err(StoreError::StoreFailure(format!(
+            "Unsupported trace {} version {}",
+            header.format, header.version
+        )));
+    }
+    let mut entries = Vec::new();
+    while !reader.fill_buf().map_err(trace_error)?.is_empty() {
+        entries.push(ciborium::de::from_reader(&mut reader).map_err(trace_error)?);
+    }
+    Ok(entries)
+}
+
+/// How strictly a [ReplayStore] matches requests against the recorded trace.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReplayMode {
+    /// Requests must arrive in exactly the recorded order.
+    Strict,
+    /// Requests may arrive in any order and are answered from the last recorded
+    /// result for the same operation and key.
+    Lenient,
+}
+
+/// A [Store] that serves the results of a recorded trace.
+///
+/// Replaying a [TraceOp::Get] requires a trace recorded with payloads since [nodes](Node)
+/// can not be reconstructed from their ids alone. Requests the trace has no answer for
+/// return a [StoreError::StoreFailure].
+pub struct ReplayStore {
+    entries: Vec<TraceEntry>,
+    lookup: BTreeMap<(Vec<u8>, u8), usize>,
+    mode: ReplayMode,
+    position: Cell<usize>,
+    consumed: Cell<usize>,
+}
+
+fn op_tag(op: TraceOp) -> u8 {
+    match op {
+        TraceOp::Contains => 0,
+        TraceOp::Get => 1,
+        TraceOp::Store => 2,
+        TraceOp::ChildrenOf => 3,
+        TraceOp::Delete => 4,
+        TraceOp::Ids => 5,
+        TraceOp::PersistRoots => 6,
+        TraceOp::PersistedRoots => 7,
+        TraceOp::BeginBatch => 8,
+        TraceOp::CommitBatch => 9,
+        TraceOp::RollbackBatch => 10,
+    }
+}
+
+impl ReplayStore {
+    /// Open a trace file for replay.
+    pub fn open<P: AsRef<Path>>(path: P, mode: ReplayMode) -> Result<Self> {
+        let file = File::open(path).map_err(trace_error)?;
+        Self::from_reader(file, mode)
+    }
+
+    /// Read a trace for replay.
+    pub fn from_reader<R: Read>(reader: R, mode: ReplayMode) -> Result<Self> {
+        Ok(Self::new(read_trace(reader)?, mode))
+    }
+
+    /// Construct a [ReplayStore] from already read trace entries.
+    pub fn new(entries: Vec<TraceEntry>, mode: ReplayMode) -> Self {
+        let mut lookup = BTreeMap::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            lookup.insert((entry.key.clone(), op_tag(entry.op)), idx);
+        }
+        Self {
+            entries,
+            lookup,
+            mode,
+            position: Cell::new(0),
+            consumed: Cell::new(0),
+        }
+    }
+
+    /// The number of requests answered from the trace so far.
+    pub fn consumed(&self) -> usize {
+        self.consumed.get()
+    }
+
+    fn next_result(&self, op: TraceOp, key: &[u8]) -> Result<TraceResult> {
+        let entry = match self.mode {
+            ReplayMode::Strict => {
+                let position = self.position.get();
+                let entry = self.entries.get(position).ok_or_else(|| {
+                    StoreError::StoreFailure(format!(
+                        "Trace divergence at {}: unrecorded {:?} request",
+                        position, op
+                    ))
+                })?;
+                if entry.op != op || entry.key != key {
+                    return Err(StoreError::StoreFailure(format!(
+                        "Trace divergence at {}: expected {:?} got {:?}",
+                        position, entry.op, op
+                    )));
+                }
+                self.position.set(position + 1);
+                entry
+            }
+            ReplayMode::Lenient => match self.lookup.get(&(key.to_vec(), op_tag(op))) {
+                Some(idx) => &self.entries[*idx],
+                None => {
+                    return Err(StoreError::StoreFailure(format!(
+                        "Trace has no recorded {:?} request for this key",
+                        op
+                    )))
+                }
+            },
+        };
+        self.consumed.set(self.consumed.get() + 1);
+        match &entry.result {
+            TraceResult::Error(e) => Err(StoreError::StoreFailure(e.clone())),
+            result => Ok(result.clone()),
+        }
+    }
+
+    fn next_done(&self, op: TraceOp, key: &[u8]) -> Result<()> {
+        match self.next_result(op, key)? {
+            TraceResult::Done => Ok(()),
+            result => Err(unexpected_result(op, result)),
+        }
+    }
+
+    // Answers a write recorded by RecordingStore::record_stores.
+    fn next_stores<'a, HW, I>(&self, nodes: I, roots: Option<&PersistedRoots>) -> Result<()>
+    where
+        HW: HashWriter + 'a,
+        I: IntoIterator<Item = &'a Node<HW>>,
+    {
+        for node in nodes {
+            match self.next_result(TraceOp::Store, node.id())? {
+                TraceResult::Node(_) => (),
+                result => return Err(unexpected_result(TraceOp::Store, result)),
+            }
+        }
+        if roots.is_some() {
+            match self.next_result(TraceOp::PersistRoots, &[])? {
+                TraceResult::Roots(_) => (),
+                result => return Err(unexpected_result(TraceOp::PersistRoots, result)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn unexpected_result(op: TraceOp, result: TraceResult) -> StoreError {
+    StoreError::StoreFailure(format!(
+        "Trace recorded {:?} for a {:?} request",
+        result, op
+    ))
+}
+
+impl<HW> Store<HW> for ReplayStore
+where
+    HW: HashWriter,
+{
+    fn contains(&self, id: &[u8]) -> Result<bool> {
+        match self.next_result(TraceOp::Contains, id)? {
+            TraceResult::Bool(b) => Ok(b),
+            result => Err(unexpected_result(TraceOp::Contains, result)),
+        }
+    }
+
+    fn get(&self, id: &[u8]) -> Result<Option<Node<HW>>> {
+        let traced = match self.next_result(TraceOp::Get, id)? {
+            TraceResult::Node(None) => return Ok(None),
+            TraceResult::Node(Some(traced)) => traced,
+            result => return Err(unexpected_result(TraceOp::Get, result)),
+        };
+        let node = match traced {
+            TracedNode {
+                id,
+                item_id,
+                dependency_ids,
+                id_version,
+                attributes,
+                detached: true,
+                ..
+            } => Node::from_detached_parts(id, item_id, dependency_ids, attributes, id_version)
+                .map_err(StoreError::StoreFailure)?,
+            TracedNode {
+                item: Some(item),
+                dependency_ids,
+                id_version,
+                attributes,
+                ..
+            } => Node::new_with_attrs_and_id_version(item, dependency_ids, attributes, id_version),
+            _ => {
+                return Err(StoreError::StoreFailure(
+                    "Trace was recorded without payloads".to_owned(),
+                ))
+            }
+        };
+        Ok(Some(match traced.signature {
+            Some(signature) => node.with_signature(signature),
+            None => node,
+        }))
+    }
+
+    fn store(&mut self, node: Node<HW>) -> Result<()> {
+        self.next_stores([&node], None)
+    }
+
+    fn store_encoded(&mut self, node: Node<HW>, _encoded: Vec<u8>) -> Result<()> {
+        self.next_stores([&node], None)
+    }
+
+    fn store_many_encoded(
+        &mut self,
+        records: Vec<(Node<HW>, Vec<u8>)>,
+        roots: Option<&PersistedRoots>,
+    ) -> Result<()> {
+        self.next_stores(records.iter().map(|(node, _)| node), roots)
+    }
+
+    fn store_with_roots(&mut self, node: Node<HW>, roots: &PersistedRoots) -> Result<()> {
+        self.next_stores([&node], Some(roots))
+    }
+
+    fn store_many_with_roots<I>(&mut self, nodes: I, roots: &PersistedRoots) -> Result<()>
+    where
+        I: IntoIterator<Item = Node<HW>>,
+    {
+        let nodes: Vec<Node<HW>> = nodes.into_iter().collect();
+        self.next_stores(&nodes, Some(roots))
+    }
+
+    fn persist_roots(&mut self, roots: &PersistedRoots) -> Result<()> {
+        self.next_stores::<HW, _>([], Some(roots))
+    }
+
+    fn persisted_roots(&self) -> Result<Option<PersistedRoots>> {
+        match self.next_result(TraceOp::PersistedRoots, &[])? {
+            TraceResult::Roots(roots) => Ok(roots),
+            result => Err(unexpected_result(TraceOp::PersistedRoots, result)),
+        }
+    }
+
+    fn delete(&mut self, id: &[u8]) -> Result<()> {
+        self.next_done(TraceOp::Delete, id)
+    }
+
+    fn children_of(&self, id: &[u8]) -> Result<BTreeSet<Vec<u8>>> {
+        match self.next_result(TraceOp::ChildrenOf, id)? {
+            TraceResult::Ids(ids) => Ok(ids),
+            result => Err(unexpected_result(TraceOp::ChildrenOf, result)),
+        }
+    }
+
+    fn ids(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        match self.next_result(TraceOp::Ids, &[])? {
+            TraceResult::IdList(ids) => Ok(Box::new(ids.into_iter().map(Ok))),
+            result => Err(unexpected_result(TraceOp::Ids, result)),
+        }
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        self.next_done(TraceOp::BeginBatch, &[])
+    }
+
+    fn commit_batch(&mut self) -> Result<()> {
+        self.next_done(TraceOp::CommitBatch, &[])
+    }
+
+    fn rollback_batch(&mut self) -> Result<()> {
+        self.next_done(TraceOp::RollbackBatch, &[])
+    }
+}
+
+impl<S, HW> Merkle<S, HW>
+where
+    HW: HashWriter,
+    S: Store<HW> + Default,
+{
+    /// Rebuild the graph structure recorded in a trace file.
+    ///
+    /// Every recorded node is added again with [Merkle::add_node] from its payload, or from
+    /// its recorded item_id when payloads were scrubbed. Attributes, signatures and id
+    /// versions are not replayed so the rebuilt ids can differ from the recorded ones even
+    /// for nodes recorded with payloads. The returned map translates recorded ids into the
+    /// ids of the rebuilt DAG.
+    pub fn from_trace<P: AsRef<Path>>(path: P) -> Result<(Self, TraceIdMap)> {
+        let file = File::open(path).map_err(trace_error)?;
+        let mut nodes = BTreeMap::new();
+        for entry in read_trace(file)? {
+            if let TraceResult::Node(Some(node)) = entry.result {
+                nodes.insert(node.id.clone(), node);
+            }
+        }
+        // Nodes are added once their last recorded dependency is added.
+        let mut waiting = BTreeMap::new();
+        let mut dependents: BTreeMap<&[u8], Vec<&[u8]>> = BTreeMap::new();
+        let mut ready = VecDeque::new();
+        for (id, node) in nodes.iter() {
+            if node.dependency_ids.is_empty() {
+                ready.push_back(id.as_slice());
+            }
+            waiting.insert(id.as_slice(), node.dependency_ids.len());
+            for dep in node.dependency_ids.iter() {
+                dependents.entry(dep).or_default().push(id);
+            }
+        }
+        let mut dag = Self::new(S::default());
+        let mut id_map = TraceIdMap::new();
+        while let Some(id) = ready.pop_front() {
+            let node = &nodes[id];
+            let deps = node
+                .dependency_ids
+                .iter()
+                .map(|d| id_map[d].clone())
+                .collect();
+            let item = node.item.clone().unwrap_or_else(|| node.item_id.clone());
+            id_map.insert(id.to_vec(), dag.add_node(item, deps)?);
+            for dependent in dependents.remove(id).unwrap_or_default() {
+                let count = waiting.get_mut(dependent).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    ready.push_back(dependent);
+                }
+            }
+        }
+        if id_map.len() < nodes.len() {
+            return Err(StoreError::NoSuchDependents);
+        }
+        Ok((dag, id_map))
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2022 Jeremy Wall (Jeremy@marzhilsltudios.com)
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Implements the [HashWriter] interface for the 64 and 128 bit variants of the XXH3 hash
+//! function. Requires the `xxhash` feature to be enabled.
+//!
+//! XXH3 is not a cryptographic hash function. Anyone can construct payloads with colliding
+//! ids, so these hashers are only meant for tests and benchmarks of DAGs whose content is
+//! trusted.
+
+use std::fmt;
+
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::hash::*;
+
+macro_rules! hash_writer_impl {
+    ($(#[$doc:meta])* $tname:ident, $digest:ident, $len:expr) => {
+        $(#[$doc])*
+        #[derive(Clone, Default)]
+        pub struct $tname(Xxh3);
+
+        impl fmt::Debug for $tname {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_tuple(stringify!($tname)).finish()
+            }
+        }
+
+        impl HashWriter for $tname {
+            const OUTPUT_LEN: usize = $len;
+            const ALGORITHM_ID: &'static str = "xxh3";
+
+            fn record<I: Iterator<Item = u8>>(&mut self, bs: I) {
+                let vec: Vec<u8> = bs.collect();
+                self.0.update(&vec);
+            }
+
+            fn record_bytes(&mut self, bs: &[u8]) {
+                self.0.update(bs);
+            }
+
+            fn hash(&self) -> Vec<u8> {
+                // The canonical big endian form that xxhsum prints.
+                self.0.$digest().to_be_bytes().to_vec()
+            }
+        }
+    };
+}
+
+hash_writer_impl!(
+    /// The 64 bit XXH3 [HashWriter]. Non-cryptographic.
+    Xxh3_64,
+    digest,
+    8
+);
+hash_writer_impl!(
+    /// The 128 bit XXH3 [HashWriter]. Non-cryptographic.
+    Xxh3_128,
+    digest128,
+    16
+);